@@ -0,0 +1,5 @@
+module github.com/fakemyself/pmv/benches/compare/go
+
+go 1.21
+
+require github.com/prometheus/common v0.60.1
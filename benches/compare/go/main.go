@@ -0,0 +1,59 @@
+// Command expfmt-bench parses a text exposition file with the Go client's
+// expfmt.TextParser under testing.Benchmark and prints the result as JSON,
+// for the pmv comparison harness in the parent directory.
+package main
+
+import (
+	"bytes"
+	"encoding/json"
+	"flag"
+	"fmt"
+	"os"
+	"testing"
+	"time"
+
+	"github.com/prometheus/common/expfmt"
+)
+
+func main() {
+	testing.Init()
+	benchtime := flag.Duration("benchtime", time.Second, "how long to run the parser")
+	flag.Parse()
+	if flag.NArg() != 1 {
+		fmt.Fprintln(os.Stderr, "usage: expfmt-bench [-benchtime 1s] FILE")
+		os.Exit(2)
+	}
+	if err := flag.Set("test.benchtime", benchtime.String()); err != nil {
+		fmt.Fprintln(os.Stderr, err)
+		os.Exit(2)
+	}
+
+	data, err := os.ReadFile(flag.Arg(0))
+	if err != nil {
+		fmt.Fprintln(os.Stderr, err)
+		os.Exit(1)
+	}
+	// Fail early rather than inside the benchmark loop.
+	var parser expfmt.TextParser
+	if _, err := parser.TextToMetricFamilies(bytes.NewReader(data)); err != nil {
+		fmt.Fprintln(os.Stderr, err)
+		os.Exit(1)
+	}
+
+	res := testing.Benchmark(func(b *testing.B) {
+		b.ReportAllocs()
+		for i := 0; i < b.N; i++ {
+			var parser expfmt.TextParser
+			if _, err := parser.TextToMetricFamilies(bytes.NewReader(data)); err != nil {
+				b.Fatal(err)
+			}
+		}
+	})
+
+	json.NewEncoder(os.Stdout).Encode(map[string]any{
+		"iterations":    res.N,
+		"ns_per_op":     float64(res.T.Nanoseconds()) / float64(res.N),
+		"allocs_per_op": res.AllocsPerOp(),
+		"bytes_per_op":  res.AllocedBytesPerOp(),
+	})
+}
@@ -0,0 +1,306 @@
+//! Runs the same exposition corpus through pmv, prometheus-parse and the Go
+//! expfmt parser and reports throughput and allocations per parse. See
+//! README.md next to this file for the methodology.
+//!
+//! ```text
+//! cargo bench --bench compare -- [--json] [--no-go] [FILES...]
+//! ```
+//!
+//! Without files a synthetic corpus is generated.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::error::Error;
+use std::fmt::Write as _;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use pmv::text_parse::TextParser;
+use serde_json::{json, Value};
+
+/// Counts every allocation, so the Rust parsers report the same numbers as
+/// Go's `b.ReportAllocs()`. Reallocations count as one allocation of the new
+/// size, as in Go.
+struct CountingAlloc;
+
+static ALLOCS: AtomicU64 = AtomicU64::new(0);
+static ALLOC_BYTES: AtomicU64 = AtomicU64::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCS.fetch_add(1, Ordering::Relaxed);
+        ALLOC_BYTES.fetch_add(layout.size() as u64, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCS.fetch_add(1, Ordering::Relaxed);
+        ALLOC_BYTES.fetch_add(new_size as u64, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+/// How long each parser runs on each input, after calibration.
+const TARGET_TIME: Duration = Duration::from_secs(1);
+
+struct Input {
+    name: String,
+    path: PathBuf,
+    text: String,
+}
+
+struct Measurement {
+    parser: &'static str,
+    input: String,
+    input_bytes: usize,
+    iterations: u64,
+    ns_per_op: f64,
+    allocs_per_op: f64,
+    bytes_per_op: f64,
+}
+
+impl Measurement {
+    fn mb_per_sec(&self) -> f64 {
+        self.input_bytes as f64 / self.ns_per_op * 1e3
+    }
+
+    fn to_json(&self) -> Value {
+        json!({
+            "parser": self.parser,
+            "input": self.input,
+            "input_bytes": self.input_bytes,
+            "iterations": self.iterations,
+            "ns_per_op": self.ns_per_op,
+            "mb_per_sec": self.mb_per_sec(),
+            "allocs_per_op": self.allocs_per_op,
+            "bytes_per_op": self.bytes_per_op,
+        })
+    }
+}
+
+/// Times `parse` on `input` for about `TARGET_TIME`, after one untimed run
+/// to warm caches and estimate the iteration count.
+fn measure(parser: &'static str, input: &Input, parse: impl Fn(&str)) -> Measurement {
+    let start = Instant::now();
+    parse(&input.text);
+    let once = start.elapsed().max(Duration::from_nanos(1));
+    let iterations = (TARGET_TIME.as_nanos() / once.as_nanos()).clamp(1, 1_000_000) as u64;
+
+    let allocs = ALLOCS.load(Ordering::Relaxed);
+    let alloc_bytes = ALLOC_BYTES.load(Ordering::Relaxed);
+    let start = Instant::now();
+    for _ in 0..iterations {
+        parse(&input.text);
+    }
+    let elapsed = start.elapsed();
+    let n = iterations as f64;
+
+    Measurement {
+        parser,
+        input: input.name.clone(),
+        input_bytes: input.text.len(),
+        iterations,
+        ns_per_op: elapsed.as_nanos() as f64 / n,
+        allocs_per_op: (ALLOCS.load(Ordering::Relaxed) - allocs) as f64 / n,
+        bytes_per_op: (ALLOC_BYTES.load(Ordering::Relaxed) - alloc_bytes) as f64 / n,
+    }
+}
+
+fn parse_pmv(text: &str) {
+    let mfs = TextParser::new(text.as_bytes())
+        .text_to_metric_families()
+        .expect("pmv failed to parse the input");
+    std::hint::black_box(mfs);
+}
+
+fn parse_prometheus_parse(text: &str) {
+    // The crate only takes owned lines, so splitting them is part of its cost.
+    let lines = text.lines().map(|l| Ok(l.to_string()));
+    let scrape =
+        prometheus_parse::Scrape::parse(lines).expect("prometheus-parse failed to parse the input");
+    std::hint::black_box(scrape);
+}
+
+/// Runs the helper in `go/`, which benchmarks expfmt with `testing.Benchmark`.
+/// Returns `None` with a note on stderr if Go or its modules are missing.
+fn measure_go(input: &Input) -> Option<Measurement> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("benches/compare/go");
+    let output = match Command::new("go")
+        .args(["run", ".", "-benchtime"])
+        .arg(format!("{}s", TARGET_TIME.as_secs()))
+        .arg(&input.path)
+        .current_dir(&dir)
+        .output()
+    {
+        Ok(output) => output,
+        Err(e) => {
+            eprintln!("skipping Go expfmt: cannot run go: {}", e);
+            return None;
+        }
+    };
+    if !output.status.success() {
+        eprintln!(
+            "skipping Go expfmt on {}: {}",
+            input.name,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+        return None;
+    }
+
+    let result: Value = serde_json::from_slice(&output.stdout).ok()?;
+    Some(Measurement {
+        parser: "go-expfmt",
+        input: input.name.clone(),
+        input_bytes: input.text.len(),
+        iterations: result["iterations"].as_u64()?,
+        ns_per_op: result["ns_per_op"].as_f64()?,
+        allocs_per_op: result["allocs_per_op"].as_f64()?,
+        bytes_per_op: result["bytes_per_op"].as_f64()?,
+    })
+}
+
+/// A deterministic corpus shaped like a node exporter scrape: counters and
+/// gauges with a few labels each, plus labelled histograms.
+fn synthetic(name: &str, families: usize, series_per_family: usize) -> String {
+    let mut text = String::new();
+    for f in 0..families {
+        let (kind, family) = match f % 3 {
+            0 => ("counter", format!("bench_requests_{}_total", f)),
+            1 => ("gauge", format!("bench_temperature_{}", f)),
+            _ => ("histogram", format!("bench_latency_{}_seconds", f)),
+        };
+        writeln!(
+            text,
+            "# HELP {} Synthetic {} {} of {}.",
+            family, kind, f, name
+        )
+        .unwrap();
+        writeln!(text, "# TYPE {} {}", family, kind).unwrap();
+        for s in 0..series_per_family {
+            let labels = format!(
+                "instance=\"host-{}.example.com:9100\",job=\"node\",device=\"sd{}\"",
+                s % 17,
+                s
+            );
+            if kind != "histogram" {
+                writeln!(
+                    text,
+                    "{}{{{}}} {}",
+                    family,
+                    labels,
+                    (f * 31 + s * 7) as f64 * 1.5
+                )
+                .unwrap();
+                continue;
+            }
+            let mut count = 0;
+            for (i, le) in ["0.005", "0.01", "0.1", "1", "10", "+Inf"]
+                .iter()
+                .enumerate()
+            {
+                count += i + s % 5;
+                writeln!(
+                    text,
+                    "{}_bucket{{{},le=\"{}\"}} {}",
+                    family, labels, le, count
+                )
+                .unwrap();
+            }
+            writeln!(text, "{}_sum{{{}}} {}", family, labels, count as f64 * 0.25).unwrap();
+            writeln!(text, "{}_count{{{}}} {}", family, labels, count).unwrap();
+        }
+    }
+    text
+}
+
+fn corpus(files: &[String]) -> Result<Vec<Input>, Box<dyn Error>> {
+    if !files.is_empty() {
+        return files
+            .iter()
+            .map(|f| {
+                Ok(Input {
+                    name: f.clone(),
+                    path: PathBuf::from(f),
+                    text: std::fs::read_to_string(f)?,
+                })
+            })
+            .collect();
+    }
+
+    // The Go helper reads files, so the synthetic inputs are written out.
+    let dir = std::env::temp_dir().join(format!("pmv-bench-{}", std::process::id()));
+    std::fs::create_dir_all(&dir)?;
+    [("small", 10, 10), ("medium", 60, 100), ("large", 150, 1000)]
+        .into_iter()
+        .map(|(name, families, series)| {
+            let text = synthetic(name, families, series);
+            let path = dir.join(format!("{}.prom", name));
+            std::fs::write(&path, &text)?;
+            Ok(Input {
+                name: name.to_string(),
+                path,
+                text,
+            })
+        })
+        .collect()
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let mut json_output = false;
+    let mut go = true;
+    let mut files = Vec::new();
+    for arg in std::env::args().skip(1) {
+        match arg.as_str() {
+            "--json" => json_output = true,
+            "--no-go" => go = false,
+            // Passed by `cargo bench`.
+            "--bench" => {}
+            _ => files.push(arg),
+        }
+    }
+
+    let mut results = Vec::new();
+    for input in corpus(&files)? {
+        eprintln!("{}: {} bytes", input.name, input.text.len());
+        results.push(measure("pmv", &input, parse_pmv));
+        results.push(measure("prometheus-parse", &input, parse_prometheus_parse));
+        if go {
+            match measure_go(&input) {
+                Some(m) => results.push(m),
+                // Don't retry for every input if Go is not usable at all.
+                None => go = false,
+            }
+        }
+    }
+
+    if json_output {
+        let results: Vec<_> = results.iter().map(Measurement::to_json).collect();
+        println!("{}", serde_json::to_string_pretty(&results)?);
+        return Ok(());
+    }
+
+    println!(
+        "{:<12} {:<18} {:>12} {:>10} {:>12} {:>14}",
+        "input", "parser", "ns/op", "MB/s", "allocs/op", "bytes/op"
+    );
+    for m in &results {
+        println!(
+            "{:<12} {:<18} {:>12.0} {:>10.1} {:>12.0} {:>14.0}",
+            m.input,
+            m.parser,
+            m.ns_per_op,
+            m.mb_per_sec(),
+            m.allocs_per_op,
+            m.bytes_per_op
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,303 @@
+use prometheus::proto::{Bucket, Histogram, LabelPair, Metric, MetricFamily, MetricType};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+
+/// Which labels survive an aggregation, as in PromQL's `sum by (...)` and
+/// `sum without (...)`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Grouping {
+    By(Vec<String>),
+    Without(Vec<String>),
+}
+
+impl Grouping {
+    /// Whether series keep `label` when aggregated.
+    pub fn keeps(&self, label: &str) -> bool {
+        match self {
+            Grouping::By(names) => names.iter().any(|n| n == label),
+            Grouping::Without(names) => !names.iter().any(|n| n == label),
+        }
+    }
+}
+
+/// How [`aggregate`] combines the series of a group, as PromQL's
+/// aggregation operators of the same names.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AggOp {
+    #[default]
+    Sum,
+    Avg,
+    Min,
+    Max,
+    Count,
+}
+
+impl AggOp {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            AggOp::Sum => "sum",
+            AggOp::Avg => "avg",
+            AggOp::Min => "min",
+            AggOp::Max => "max",
+            AggOp::Count => "count",
+        }
+    }
+}
+
+/// Combines the series of each family that are equal in the labels
+/// `grouping` keeps, dropping the other labels and timestamps.
+///
+/// Counters, gauges and untyped series combine their values and keep their
+/// type; `min` and `max` ignore `NaN` unless all values are. `count` turns
+/// every family into a gauge of its number of series per group. Histograms
+/// are summed bucket by bucket: where a series has no bucket at a bound
+/// another has, its count at the next lower bound is taken, so the merged
+/// buckets stay cumulative. Summary quantiles cannot be summed and are
+/// dropped, keeping `_sum` and `_count`. Histograms and summaries cannot be
+/// averaged or compared, so `avg`, `min` and `max` leave their families out.
+pub fn aggregate(mfs: &[MetricFamily], op: AggOp, grouping: &Grouping) -> Vec<MetricFamily> {
+    mfs.iter()
+        .filter_map(|mf| {
+            let metric_type = mf.get_field_type();
+            let scalar = !matches!(metric_type, MetricType::HISTOGRAM | MetricType::SUMMARY);
+            if !scalar && matches!(op, AggOp::Avg | AggOp::Min | AggOp::Max) {
+                return None;
+            }
+
+            let mut groups: BTreeMap<Vec<(String, String)>, Vec<&Metric>> = BTreeMap::new();
+            for m in mf.get_metric() {
+                let mut key: Vec<(String, String)> = m
+                    .get_label()
+                    .iter()
+                    .filter(|l| grouping.keeps(l.get_name()))
+                    .map(|l| (l.get_name().to_string(), l.get_value().to_string()))
+                    .collect();
+                key.sort();
+                groups.entry(key).or_default().push(m);
+            }
+
+            let mut mf = mf.clone();
+            if op == AggOp::Count {
+                mf.set_field_type(MetricType::GAUGE);
+            }
+            let metrics = groups
+                .into_iter()
+                .map(|(key, group)| {
+                    let mut out = Metric::default();
+                    out.set_label(
+                        key.into_iter()
+                            .map(|(name, value)| {
+                                let mut pair = LabelPair::new();
+                                pair.set_name(name);
+                                pair.set_value(value);
+                                pair
+                            })
+                            .collect(),
+                    );
+                    match metric_type {
+                        _ if op == AggOp::Count => out.mut_gauge().set_value(group.len() as f64),
+                        MetricType::HISTOGRAM => out.set_histogram(merge_histograms(&group)),
+                        MetricType::SUMMARY => {
+                            let summary = out.mut_summary();
+                            for m in group {
+                                let s = m.get_summary();
+                                summary.set_sample_count(
+                                    summary.get_sample_count() + s.get_sample_count(),
+                                );
+                                summary
+                                    .set_sample_sum(summary.get_sample_sum() + s.get_sample_sum());
+                            }
+                        }
+                        _ => {
+                            let values = group.iter().map(|m| value(metric_type, m));
+                            let v = match op {
+                                AggOp::Sum => values.sum(),
+                                AggOp::Avg => values.sum::<f64>() / group.len() as f64,
+                                AggOp::Min => values.fold(f64::NAN, f64::min),
+                                AggOp::Max => values.fold(f64::NAN, f64::max),
+                                AggOp::Count => unreachable!("counted above"),
+                            };
+                            set_value(metric_type, &mut out, v);
+                        }
+                    }
+                    out
+                })
+                .collect();
+            mf.set_metric(metrics);
+            Some(mf)
+        })
+        .collect()
+}
+
+/// Sums the series of each family that are equal in the labels `grouping`
+/// keeps; [`aggregate`] with [`AggOp::Sum`].
+pub fn sum(mfs: &[MetricFamily], grouping: &Grouping) -> Vec<MetricFamily> {
+    aggregate(mfs, AggOp::Sum, grouping)
+}
+
+fn value(metric_type: MetricType, m: &Metric) -> f64 {
+    match metric_type {
+        MetricType::COUNTER => m.get_counter().get_value(),
+        MetricType::GAUGE => m.get_gauge().get_value(),
+        _ => m.get_untyped().get_value(),
+    }
+}
+
+fn set_value(metric_type: MetricType, m: &mut Metric, v: f64) {
+    match metric_type {
+        MetricType::COUNTER => m.mut_counter().set_value(v),
+        MetricType::GAUGE => m.mut_gauge().set_value(v),
+        _ => m.mut_untyped().set_value(v),
+    }
+}
+
+fn merge_histograms(group: &[&Metric]) -> Histogram {
+    let histograms: Vec<&Histogram> = group.iter().map(|m| m.get_histogram()).collect();
+    let mut bounds: Vec<f64> = histograms
+        .iter()
+        .flat_map(|h| h.get_bucket().iter().map(|b| b.get_upper_bound()))
+        .collect();
+    bounds.sort_by(f64::total_cmp);
+    bounds.dedup();
+
+    let mut merged = Histogram::default();
+    merged.set_sample_count(histograms.iter().map(|h| h.get_sample_count()).sum());
+    merged.set_sample_sum(histograms.iter().map(|h| h.get_sample_sum()).sum());
+    for bound in bounds {
+        let mut bucket = Bucket::default();
+        bucket.set_upper_bound(bound);
+        bucket.set_cumulative_count(histograms.iter().map(|h| count_at(h, bound)).sum());
+        merged.mut_bucket().push(bucket);
+    }
+    merged
+}
+
+/// The cumulative count of `h` at its largest bound not above `bound`. At
+/// `+Inf` that is the sample count, whether or not `h` has the bucket, as
+/// protobuf and remote-write histograms usually do not.
+pub(crate) fn count_at(h: &Histogram, bound: f64) -> u64 {
+    if bound == f64::INFINITY {
+        return h.get_sample_count();
+    }
+    let buckets = h.get_bucket();
+    match buckets.partition_point(|b| b.get_upper_bound() <= bound) {
+        0 => 0,
+        pos => buckets[pos - 1].get_cumulative_count(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::{read_metric_families, Format};
+    use crate::text_encode::metric_families_to_text;
+
+    fn sum_text(text: &str, grouping: Grouping) -> String {
+        let mfs = read_metric_families(Format::Text, text.as_bytes()).unwrap();
+        let mut out = Vec::new();
+        metric_families_to_text(&mut out, &sum(&mfs, &grouping)).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_sum() {
+        let text = r#"# TYPE requests_total counter
+requests_total{instance="a",code="200"} 3
+requests_total{instance="b",code="200"} 4
+requests_total{instance="b",code="500"} 1 1700000000000
+# TYPE latency histogram
+latency_bucket{instance="a",le="1"} 1
+latency_bucket{instance="a",le="+Inf"} 2
+latency_sum{instance="a"} 1.5
+latency_count{instance="a"} 2
+latency_bucket{instance="b",le="0.5"} 1
+latency_bucket{instance="b",le="1"} 3
+latency_bucket{instance="b",le="+Inf"} 3
+latency_sum{instance="b"} 2
+latency_count{instance="b"} 3
+"#;
+        assert_eq!(
+            sum_text(text, Grouping::Without(vec!["instance".to_string()])),
+            r#"# TYPE latency histogram
+latency_bucket{le="0.5"} 1
+latency_bucket{le="1"} 4
+latency_bucket{le="+Inf"} 5
+latency_sum 3.5
+latency_count 5
+# TYPE requests_total counter
+requests_total{code="200"} 7
+requests_total{code="500"} 1
+"#
+        );
+
+        let text = "# TYPE rpc summary\nrpc{quantile=\"0.5\",x=\"1\"} 1\nrpc_sum{x=\"1\"} 2\n\
+                    rpc_count{x=\"1\"} 1\nrpc_sum{x=\"2\"} 3\nrpc_count{x=\"2\"} 2\n";
+        assert_eq!(
+            sum_text(text, Grouping::By(vec![])),
+            "# TYPE rpc summary\nrpc_sum 5\nrpc_count 3\n"
+        );
+    }
+
+    #[test]
+    fn test_aggregate() {
+        let text = r#"# TYPE temperature gauge
+temperature{room="a",floor="1"} 20
+temperature{room="b",floor="1"} 23
+temperature{room="c",floor="2"} NaN
+# TYPE latency histogram
+latency_bucket{path="/a",le="0.1"} 1
+latency_bucket{path="/a",le="1"} 2
+latency_bucket{path="/a",le="+Inf"} 2
+latency_sum{path="/a"} 0.6
+latency_count{path="/a"} 2
+latency_bucket{path="/b",le="0.5"} 1
+latency_bucket{path="/b",le="+Inf"} 4
+latency_sum{path="/b"} 7
+latency_count{path="/b"} 4
+"#;
+        let mfs = read_metric_families(Format::Text, text.as_bytes()).unwrap();
+        let by_floor = Grouping::By(vec!["floor".to_string()]);
+        let run = |op| {
+            let mut out = Vec::new();
+            metric_families_to_text(&mut out, &aggregate(&mfs, op, &by_floor)).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        assert_eq!(
+            run(AggOp::Avg),
+            "# TYPE temperature gauge\ntemperature{floor=\"1\"} 21.5\ntemperature{floor=\"2\"} NaN\n"
+        );
+        assert_eq!(
+            run(AggOp::Max),
+            "# TYPE temperature gauge\ntemperature{floor=\"1\"} 23\ntemperature{floor=\"2\"} NaN\n"
+        );
+        assert!(run(AggOp::Count).contains("# TYPE latency gauge\nlatency 2\n"));
+        // Buckets missing from a series count what its lower bucket holds.
+        assert_eq!(
+            run(AggOp::Sum).split("# TYPE temperature").next().unwrap(),
+            r#"# TYPE latency histogram
+latency_bucket{le="0.1"} 1
+latency_bucket{le="0.5"} 2
+latency_bucket{le="1"} 3
+latency_bucket{le="+Inf"} 6
+latency_sum 7.6
+latency_count 6
+"#
+        );
+
+        // A series without the +Inf bucket still counts all its samples
+        // there when merged with one that has it.
+        let mut mixed = read_metric_families(Format::Text, text.as_bytes()).unwrap();
+        let buckets = mixed[0].mut_metric()[1].mut_histogram().mut_bucket();
+        let inf = buckets.pop().unwrap();
+        assert_eq!(inf.get_upper_bound(), f64::INFINITY);
+        let merged = aggregate(&mixed, AggOp::Sum, &by_floor);
+        let h = merged[0].get_metric()[0].get_histogram();
+        let last = h.get_bucket().last().unwrap();
+        assert_eq!(last.get_upper_bound(), f64::INFINITY);
+        assert_eq!(last.get_cumulative_count(), h.get_sample_count());
+        assert_eq!(h.get_sample_count(), 6);
+    }
+}
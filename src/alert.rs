@@ -0,0 +1,167 @@
+use prometheus::proto::MetricFamily;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::error::Error;
+use std::fmt;
+use std::time::Duration;
+
+use crate::flatten::flatten;
+use crate::grep::{Selector, ValuePredicate};
+
+/// An alert that fires for every sample `selector` matches whose value
+/// satisfies `predicate`, loaded from YAML as
+/// `{name: TooManyErrors, match: 'http_requests_total{code="500"}', when: value > 10}`.
+#[derive(Debug, Clone)]
+pub struct AlertRule {
+    pub name: String,
+    pub selector: Selector,
+    pub predicate: ValuePredicate,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct RawAlertRule {
+    name: String,
+    #[serde(rename = "match")]
+    selector: String,
+    when: String,
+}
+
+impl TryFrom<RawAlertRule> for AlertRule {
+    type Error = String;
+
+    fn try_from(raw: RawAlertRule) -> Result<Self, Self::Error> {
+        let invalid = |e: String| format!("alert {}: {}", raw.name, e);
+        Ok(AlertRule {
+            selector: raw.selector.parse().map_err(invalid)?,
+            predicate: raw.when.parse().map_err(invalid)?,
+            name: raw.name,
+        })
+    }
+}
+
+/// A sample an alert fired for.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Firing {
+    pub alert: String,
+    pub series: String,
+    pub value: f64,
+}
+
+impl fmt::Display for Firing {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {} is {}", self.alert, self.series, self.value)
+    }
+}
+
+#[derive(Debug)]
+pub enum AlertError {
+    Send(String, Box<ureq::Error>),
+}
+
+impl fmt::Display for AlertError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AlertError::Send(url, e) => write!(f, "posting alerts to {}: {}", url, e),
+        }
+    }
+}
+
+impl Error for AlertError {}
+
+/// Every sample of `mfs` that fires one of `rules`, by rule then in sample
+/// order. Histograms and summaries are matched sample by sample, e.g. by
+/// their `_count`.
+pub fn evaluate(rules: &[AlertRule], mfs: &[MetricFamily]) -> Vec<Firing> {
+    let samples = flatten(mfs);
+    rules
+        .iter()
+        .flat_map(|rule| {
+            samples
+                .iter()
+                .filter(|s| {
+                    rule.selector.matches_series(&s.name, &s.labels)
+                        && rule.predicate.matches(s.value)
+                })
+                .map(|s| Firing {
+                    alert: rule.name.clone(),
+                    series: s.series(),
+                    value: s.value,
+                })
+        })
+        .collect()
+}
+
+/// The webhook body: `{"alerts": [{"alert": ..., "series": ..., "value": ...}]}`.
+/// Values that JSON cannot hold, such as `NaN`, are `null`.
+pub fn to_json(firing: &[Firing]) -> Value {
+    json!({
+        "alerts": firing
+            .iter()
+            .map(|f| json!({"alert": f.alert, "series": f.series, "value": f.value}))
+            .collect::<Vec<_>>(),
+    })
+}
+
+/// POSTs `firing` to `url` as [`to_json`] describes.
+pub fn post(url: &str, firing: &[Firing], timeout: Duration) -> Result<(), AlertError> {
+    ureq::post(url)
+        .timeout(timeout)
+        .set("Content-Type", "application/json")
+        .send_string(&to_json(firing).to_string())
+        .map_err(|e| AlertError::Send(url.to_string(), Box::new(e)))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::{read_metric_families, Format};
+
+    #[test]
+    fn test_evaluate() {
+        let mfs = read_metric_families(
+            Format::Text,
+            "# TYPE errors_total counter\nerrors_total{code=\"500\"} 12\nerrors_total{code=\"503\"} 3\n\
+             # TYPE up gauge\nup{job=\"api\"} 0\nup{job=\"db\"} 1\n"
+                .as_bytes(),
+        )
+        .unwrap();
+        let rules: Vec<AlertRule> = [
+            ("TooManyErrors", "errors_total", "value > 10"),
+            ("Down", "up", "value == 0"),
+            ("Never", "missing", "value > 0"),
+        ]
+        .into_iter()
+        .map(|(name, selector, when)| {
+            AlertRule::try_from(RawAlertRule {
+                name: name.to_string(),
+                selector: selector.to_string(),
+                when: when.to_string(),
+            })
+            .unwrap()
+        })
+        .collect();
+
+        let firing = evaluate(&rules, &mfs);
+        let shown: Vec<String> = firing.iter().map(|f| f.to_string()).collect();
+        assert_eq!(
+            shown,
+            vec![
+                "TooManyErrors: errors_total{code=\"500\"} is 12",
+                "Down: up{job=\"api\"} is 0",
+            ]
+        );
+        assert_eq!(
+            to_json(&firing[1..])["alerts"][0],
+            json!({"alert": "Down", "series": "up{job=\"api\"}", "value": 0.0})
+        );
+
+        let bad = RawAlertRule {
+            name: "Bad".to_string(),
+            selector: "up".to_string(),
+            when: "value >> 1".to_string(),
+        };
+        assert!(AlertRule::try_from(bad).is_err());
+    }
+}
@@ -0,0 +1,278 @@
+use prometheus::proto::{LabelPair, Metric, MetricFamily, MetricType};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::aggregate::Grouping;
+use crate::flatten::render_series;
+use crate::grammar::{is_valid_metric_name_continuation, is_valid_metric_name_start};
+
+/// An arithmetic operator between two series.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+impl BinOp {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            BinOp::Add => "+",
+            BinOp::Sub => "-",
+            BinOp::Mul => "*",
+            BinOp::Div => "/",
+        }
+    }
+
+    /// Applies the operator with IEEE 754 semantics, as PromQL does:
+    /// dividing by zero gives an infinity or `NaN`.
+    pub fn apply(self, a: f64, b: f64) -> f64 {
+        match self {
+            BinOp::Add => a + b,
+            BinOp::Sub => a - b,
+            BinOp::Mul => a * b,
+            BinOp::Div => a / b,
+        }
+    }
+}
+
+impl FromStr for BinOp {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "+" => Ok(BinOp::Add),
+            "-" => Ok(BinOp::Sub),
+            "*" => Ok(BinOp::Mul),
+            "/" => Ok(BinOp::Div),
+            _ => Err(format!("unknown operator {:?}, expected +, -, * or /", s)),
+        }
+    }
+}
+
+/// An operation between two families, written `errors_total / requests_total`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Expr {
+    pub left: String,
+    pub op: BinOp,
+    pub right: String,
+}
+
+impl FromStr for Expr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let at = s
+            .find(['+', '-', '*', '/'])
+            .ok_or_else(|| format!("expected `left OP right`, got {:?}", s))?;
+        let (left, right) = (s[..at].trim(), s[at + 1..].trim());
+        for name in [left, right] {
+            if !name.starts_with(is_valid_metric_name_start)
+                || !name.chars().all(is_valid_metric_name_continuation)
+            {
+                return Err(format!("invalid metric name {:?} in {:?}", name, s));
+            }
+        }
+        Ok(Expr {
+            left: left.to_string(),
+            op: s[at..at + 1].parse()?,
+            right: right.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {} {}", self.left, self.op.as_str(), self.right)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BinOpError {
+    MissingFamily(String),
+    /// Histograms and summaries have no single value to operate on.
+    NotScalar {
+        family: String,
+        metric_type: MetricType,
+    },
+    /// Several series of one side match the same labels.
+    ManyToMany {
+        family: String,
+        series: String,
+    },
+}
+
+impl fmt::Display for BinOpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BinOpError::MissingFamily(name) => write!(f, "no family {}", name),
+            BinOpError::NotScalar {
+                family,
+                metric_type,
+            } => write!(f, "family {} is a {:?}, not a scalar", family, metric_type),
+            BinOpError::ManyToMany { family, series } => write!(
+                f,
+                "several series of {} match {}; use --on or --ignoring",
+                family, series
+            ),
+        }
+    }
+}
+
+impl Error for BinOpError {}
+
+/// Evaluates `expr` with its left family from `left` and its right family
+/// from `right`, which may be the same document. See [`binary_op`].
+pub fn evaluate(
+    expr: &Expr,
+    left: &[MetricFamily],
+    right: &[MetricFamily],
+    matching: &Grouping,
+    name: &str,
+) -> Result<MetricFamily, BinOpError> {
+    let find = |mfs: &[MetricFamily], family: &str| {
+        mfs.iter()
+            .find(|mf| mf.get_name() == family)
+            .cloned()
+            .ok_or_else(|| BinOpError::MissingFamily(family.to_string()))
+    };
+    binary_op(
+        &find(left, &expr.left)?,
+        expr.op,
+        &find(right, &expr.right)?,
+        matching,
+        name,
+    )
+}
+
+/// Joins the series of `left` and `right` that are equal in the labels
+/// `matching` keeps and applies `op` to each pair, as PromQL's one-to-one
+/// vector matching with `on (...)` or `ignoring (...)`.
+///
+/// The result is a gauge family `name` with one series per pair, labelled
+/// with the labels matched on, sorted by those labels. Series without a
+/// partner are left out, timestamps are dropped, and counters, gauges and
+/// untyped families can be mixed.
+pub fn binary_op(
+    left: &MetricFamily,
+    op: BinOp,
+    right: &MetricFamily,
+    matching: &Grouping,
+    name: &str,
+) -> Result<MetricFamily, BinOpError> {
+    let right_values = index(right, matching)?;
+
+    let mut out = MetricFamily::default();
+    out.set_name(name.to_string());
+    out.set_field_type(MetricType::GAUGE);
+    for (key, a) in index(left, matching)? {
+        let Some(&b) = right_values.get(&key) else {
+            continue;
+        };
+        let mut m = Metric::default();
+        m.set_label(
+            key.into_iter()
+                .map(|(name, value)| {
+                    let mut pair = LabelPair::new();
+                    pair.set_name(name);
+                    pair.set_value(value);
+                    pair
+                })
+                .collect(),
+        );
+        m.mut_gauge().set_value(op.apply(a, b));
+        out.mut_metric().push(m);
+    }
+    Ok(out)
+}
+
+/// The values of `mf`'s series by the labels `matching` keeps.
+fn index(
+    mf: &MetricFamily,
+    matching: &Grouping,
+) -> Result<BTreeMap<Vec<(String, String)>, f64>, BinOpError> {
+    let metric_type = mf.get_field_type();
+    let mut values = BTreeMap::new();
+    for m in mf.get_metric() {
+        let value = match metric_type {
+            MetricType::COUNTER => m.get_counter().get_value(),
+            MetricType::GAUGE => m.get_gauge().get_value(),
+            MetricType::UNTYPED => m.get_untyped().get_value(),
+            _ => {
+                return Err(BinOpError::NotScalar {
+                    family: mf.get_name().to_string(),
+                    metric_type,
+                })
+            }
+        };
+        let mut key: Vec<(String, String)> = m
+            .get_label()
+            .iter()
+            .filter(|l| matching.keeps(l.get_name()))
+            .map(|l| (l.get_name().to_string(), l.get_value().to_string()))
+            .collect();
+        key.sort();
+        if values.contains_key(&key) {
+            return Err(BinOpError::ManyToMany {
+                family: mf.get_name().to_string(),
+                series: render_series("", &key),
+            });
+        }
+        values.insert(key, value);
+    }
+    Ok(values)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::{read_metric_families, Format};
+    use crate::text_encode::metric_families_to_text;
+
+    #[test]
+    fn test_binary_op() {
+        let errors = read_metric_families(
+            Format::Text,
+            "# TYPE errors_total counter\nerrors_total{job=\"api\",code=\"500\"} 3\n\
+             errors_total{job=\"web\",code=\"500\"} 0\n"
+                .as_bytes(),
+        )
+        .unwrap();
+        let requests = read_metric_families(
+            Format::Text,
+            "# TYPE requests_total counter\nrequests_total{job=\"api\"} 60\n\
+             requests_total{job=\"web\"} 0\nrequests_total{job=\"db\"} 5\n"
+                .as_bytes(),
+        )
+        .unwrap();
+
+        let expr: Expr = "errors_total / requests_total".parse().unwrap();
+        let by_job = Grouping::By(vec!["job".to_string()]);
+        let ratio = evaluate(&expr, &errors, &requests, &by_job, "error_ratio").unwrap();
+        let mut out = Vec::new();
+        metric_families_to_text(&mut out, &[ratio]).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "# TYPE error_ratio gauge\nerror_ratio{job=\"api\"} 0.05\nerror_ratio{job=\"web\"} NaN\n"
+        );
+
+        let all = Grouping::Without(vec![]);
+        assert!(evaluate(&expr, &errors, &requests, &all, "r")
+            .unwrap()
+            .get_metric()
+            .is_empty());
+        let none = Grouping::By(vec![]);
+        assert!(matches!(
+            evaluate(&expr, &errors, &requests, &none, "r"),
+            Err(BinOpError::ManyToMany { .. })
+        ));
+        assert_eq!(
+            evaluate(&"a - b".parse().unwrap(), &errors, &requests, &all, "r"),
+            Err(BinOpError::MissingFamily("a".to_string()))
+        );
+        assert!("errors_total % requests_total".parse::<Expr>().is_err());
+    }
+}
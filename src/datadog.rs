@@ -0,0 +1,308 @@
+//! Conversion of metric families to the series of Datadog's v2 metrics API,
+//! `POST /api/v2/series`, and a client sending them. Built with the
+//! `datadog` feature, for HTTPS.
+//!
+//! Datadog counts hold what happened since the previous point, while
+//! Prometheus counters and the buckets, `_sum` and `_count` of histograms
+//! and summaries only ever grow. [`DatadogConverter`] sends those as their
+//! increase since its previous conversion, and holds back a series until it
+//! has seen it twice. Gauges, untyped series and summary quantiles are sent
+//! as gauges. Labels become `name:value` tags.
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use prometheus::proto::{MetricFamily, MetricType};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Write};
+use std::time::Duration;
+
+use crate::flatten::flatten_family;
+use crate::retry::Retry;
+
+/// Site of Datadog's US1 region; others have their own.
+pub const DEFAULT_SITE: &str = "https://api.datadoghq.com";
+
+/// Type of a Datadog series, numbered as in the API.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Count = 1,
+    Gauge = 3,
+}
+
+/// A point of a Datadog series.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Series {
+    pub metric: String,
+    pub kind: Kind,
+    /// Unix seconds.
+    pub timestamp: i64,
+    pub value: f64,
+    pub tags: Vec<String>,
+}
+
+impl Series {
+    fn to_json(&self) -> Value {
+        json!({
+            "metric": self.metric,
+            "type": self.kind as i32,
+            "points": [{"timestamp": self.timestamp, "value": self.value}],
+            "tags": self.tags,
+        })
+    }
+}
+
+/// Converts metric families to Datadog series, remembering the values of
+/// cumulative series between conversions.
+#[derive(Debug, Default)]
+pub struct DatadogConverter {
+    prefix: String,
+    previous: HashMap<String, f64>,
+}
+
+impl DatadogConverter {
+    pub fn new() -> Self {
+        DatadogConverter::default()
+    }
+
+    /// Prepended to every metric name, e.g. `myapp.`.
+    pub fn prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefix = prefix.into();
+        self
+    }
+
+    /// Continues from the values [`DatadogConverter::state`] returned,
+    /// e.g. in an earlier run.
+    pub fn with_state(mut self, previous: HashMap<String, f64>) -> Self {
+        self.previous = previous;
+        self
+    }
+
+    /// Values of the cumulative series of the last conversion, by series.
+    pub fn state(&self) -> &HashMap<String, f64> {
+        &self.previous
+    }
+
+    /// The points of `mfs`. Samples without a timestamp get
+    /// `default_timestamp_s`. A cumulative series that went down was reset,
+    /// so all of its value is new.
+    pub fn convert(&mut self, mfs: &[MetricFamily], default_timestamp_s: i64) -> Vec<Series> {
+        let mut series = Vec::new();
+        let mut current = HashMap::new();
+        for mf in mfs {
+            for sample in flatten_family(mf) {
+                let cumulative = match mf.get_field_type() {
+                    MetricType::COUNTER | MetricType::HISTOGRAM => true,
+                    MetricType::SUMMARY => sample.name != mf.get_name(),
+                    MetricType::GAUGE | MetricType::UNTYPED => false,
+                };
+                let value = if cumulative {
+                    let key = sample.series_key();
+                    let previous = self.previous.get(&key).copied();
+                    current.insert(key, sample.value);
+                    match previous {
+                        Some(previous) if sample.value >= previous => sample.value - previous,
+                        Some(_) => sample.value,
+                        None => continue,
+                    }
+                } else {
+                    sample.value
+                };
+                if value.is_nan() {
+                    continue;
+                }
+                series.push(Series {
+                    metric: format!("{}{}", self.prefix, sample.name),
+                    kind: if cumulative { Kind::Count } else { Kind::Gauge },
+                    timestamp: sample
+                        .timestamp_ms
+                        .map_or(default_timestamp_s, |ms| ms.div_euclid(1000)),
+                    value,
+                    tags: sample
+                        .labels
+                        .iter()
+                        .map(|(name, value)| format!("{}:{}", name, value))
+                        .collect(),
+                });
+            }
+        }
+        self.previous = current;
+        series
+    }
+}
+
+#[derive(Debug)]
+pub enum DatadogError {
+    Io(io::Error),
+    Send(Box<ureq::Error>),
+}
+
+impl fmt::Display for DatadogError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DatadogError::Io(e) => write!(f, "compressing series failed: {}", e),
+            DatadogError::Send(e) => write!(f, "sending series to Datadog failed: {}", e),
+        }
+    }
+}
+
+impl Error for DatadogError {}
+
+/// Sends series to the Datadog API, gzipped, in requests of at most
+/// [`Client::max_series`] series.
+#[derive(Debug, Clone)]
+pub struct Client {
+    url: String,
+    api_key: String,
+    timeout: Option<Duration>,
+    retry: Retry,
+    max_series: usize,
+}
+
+impl Client {
+    /// A client of the API at `site`, such as [`DEFAULT_SITE`], sending
+    /// 1000 series per request, without timeout or retries.
+    pub fn new(site: &str, api_key: impl Into<String>) -> Self {
+        Client {
+            url: format!("{}/api/v2/series", site.trim_end_matches('/')),
+            api_key: api_key.into(),
+            timeout: None,
+            retry: Retry::default(),
+            max_series: 1000,
+        }
+    }
+
+    /// Limit on every attempt.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Retries of requests that failed to connect, timed out, or got a
+    /// 408, 429 or 5xx answer.
+    pub fn retry(mut self, retry: Retry) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Maximum series per request; at least 1.
+    pub fn max_series(mut self, max_series: usize) -> Self {
+        self.max_series = max_series.max(1);
+        self
+    }
+
+    /// Sends `series` and returns the number of requests sent. Requests are
+    /// sent in order, and the first that fails after its retries ends the
+    /// sending.
+    pub fn send(&self, series: &[Series]) -> Result<usize, DatadogError> {
+        let transient = |e: &DatadogError| match e {
+            DatadogError::Send(e) => match **e {
+                ureq::Error::Status(code, _) => code == 408 || code == 429 || code >= 500,
+                ureq::Error::Transport(_) => true,
+            },
+            DatadogError::Io(_) => false,
+        };
+
+        let mut requests = 0;
+        for batch in series.chunks(self.max_series) {
+            let payload = json!({
+                "series": batch.iter().map(Series::to_json).collect::<Vec<_>>(),
+            });
+            let mut gz = GzEncoder::new(Vec::new(), Compression::default());
+            gz.write_all(payload.to_string().as_bytes())
+                .map_err(DatadogError::Io)?;
+            let body = gz.finish().map_err(DatadogError::Io)?;
+
+            self.retry.run(transient, || {
+                let mut request = ureq::post(&self.url)
+                    .set("Content-Type", "application/json")
+                    .set("Content-Encoding", "gzip")
+                    .set("DD-API-KEY", &self.api_key);
+                if let Some(timeout) = self.timeout {
+                    request = request.timeout(timeout);
+                }
+                request
+                    .send_bytes(&body)
+                    .map_err(|e| DatadogError::Send(Box::new(e)))
+            })?;
+            requests += 1;
+        }
+        Ok(requests)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::{read_metric_families, Format};
+    use flate2::read::GzDecoder;
+
+    fn families(text: &str) -> Vec<MetricFamily> {
+        read_metric_families(Format::Text, text.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn test_datadog() {
+        let text = |requests: u32| {
+            format!(
+                "# TYPE requests_total counter\nrequests_total{{code=\"200\"}} {}\n\
+                 # TYPE temperature gauge\ntemperature 21.5 5000\n",
+                requests
+            )
+        };
+        let mut converter = DatadogConverter::new().prefix("app.");
+        let series = converter.convert(&families(&text(10)), 100);
+        assert_eq!(
+            series,
+            [Series {
+                metric: "app.temperature".to_string(),
+                kind: Kind::Gauge,
+                timestamp: 5,
+                value: 21.5,
+                tags: vec![],
+            }]
+        );
+
+        let mut converter = DatadogConverter::new().with_state(converter.state().clone());
+        let series = converter.convert(&families(&text(25)), 100);
+        assert_eq!(series[0].metric, "requests_total");
+        assert_eq!(series[0].kind, Kind::Count);
+        assert_eq!(series[0].value, 15.0);
+        assert_eq!(series[0].timestamp, 100);
+        assert_eq!(series[0].tags, ["code:200"]);
+        // Restarted, so counted from zero.
+        assert_eq!(converter.convert(&families(&text(4)), 100)[0].value, 4.0);
+
+        let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+        let site = format!("http://{}", server.server_addr());
+        let handle = std::thread::spawn(move || {
+            let mut request = server.recv().unwrap();
+            let url = request.url().to_string();
+            let key = request
+                .headers()
+                .iter()
+                .find(|h| h.field.equiv("DD-API-KEY"))
+                .map(|h| h.value.to_string());
+            let body: Value = serde_json::from_reader(GzDecoder::new(request.as_reader())).unwrap();
+            request.respond(tiny_http::Response::empty(202)).unwrap();
+            (url, key, body)
+        });
+        let client = Client::new(&site, "secret");
+        assert_eq!(client.send(&series).unwrap(), 1);
+
+        let (url, key, body) = handle.join().unwrap();
+        assert_eq!(url, "/api/v2/series");
+        assert_eq!(key.as_deref(), Some("secret"));
+        assert_eq!(
+            body["series"][0],
+            json!({
+                "metric": "requests_total",
+                "type": 1,
+                "points": [{"timestamp": 100, "value": 15.0}],
+                "tags": ["code:200"],
+            })
+        );
+    }
+}
@@ -0,0 +1,223 @@
+use serde_json::{json, Value};
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+use crate::dns_sd::DnsSdError;
+use crate::file_sd::FileSdError;
+use crate::json_format::JsonError;
+#[cfg(feature = "k8s")]
+use crate::k8s::K8sError;
+use crate::label_limit::LabelLimitError;
+use crate::scrape::ScrapeError;
+use crate::text_parse::ParseError;
+use crate::vm_import::VmImportError;
+
+/// What went wrong, coarsely enough for scripts to act on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    /// Reading or writing a file, stream or connection failed.
+    Io,
+    /// An input is not valid in its format.
+    Parse,
+    /// An input parses, but breaks a rule `validate` checks.
+    Validation,
+    Other,
+}
+
+impl Kind {
+    /// The `code` of JSON diagnostics.
+    pub fn code(self) -> &'static str {
+        match self {
+            Kind::Io => "io",
+            Kind::Parse => "parse",
+            Kind::Validation => "validation",
+            Kind::Other => "error",
+        }
+    }
+}
+
+/// One problem, with its position if it is known. Errors classified with
+/// [`Diagnostic::from_error`] keep their kind through further wrapping.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub kind: Kind,
+    /// The input the problem is in.
+    pub file: Option<String>,
+    pub line: Option<u64>,
+    pub column: Option<u64>,
+    pub message: String,
+}
+
+impl Diagnostic {
+    /// A violation `validate` found in `file`.
+    pub fn validation(file: &str, err: &ParseError) -> Self {
+        Diagnostic {
+            kind: Kind::Validation,
+            file: Some(file.to_string()),
+            line: Some(err.line as u64),
+            column: Some(err.column as u64),
+            message: err.msg.clone(),
+        }
+    }
+
+    /// Classifies `err` by the first cause in its source chain pmv knows.
+    /// Anything unrecognized is [`Kind::Other`].
+    pub fn from_error(err: &(dyn Error + 'static)) -> Self {
+        let mut diagnostic = Diagnostic {
+            kind: Kind::Other,
+            file: None,
+            line: None,
+            column: None,
+            message: err.to_string(),
+        };
+
+        let mut cause = Some(err);
+        while let Some(e) = cause {
+            if let Some(d) = e.downcast_ref::<Diagnostic>() {
+                return d.clone();
+            }
+            if let Some(e) = e.downcast_ref::<ParseError>() {
+                diagnostic.kind = Kind::Parse;
+                diagnostic.line = Some(e.line as u64);
+                diagnostic.column = Some(e.column as u64);
+                break;
+            }
+            if let Some(e) = e.downcast_ref::<serde_json::Error>() {
+                if e.is_io() {
+                    diagnostic.kind = Kind::Io;
+                } else {
+                    diagnostic.kind = Kind::Parse;
+                    diagnostic.line = Some(e.line() as u64);
+                    diagnostic.column = Some(e.column() as u64);
+                }
+                break;
+            }
+            if let Some(
+                ScrapeError::Request(_)
+                | ScrapeError::Status(..)
+                | ScrapeError::Credentials(..)
+                | ScrapeError::Socket(..)
+                | ScrapeError::Connection(_),
+            ) = e.downcast_ref::<ScrapeError>()
+            {
+                diagnostic.kind = Kind::Io;
+                break;
+            }
+            if let Some(DnsSdError::Response(..)) = e.downcast_ref::<DnsSdError>() {
+                diagnostic.kind = Kind::Io;
+                break;
+            }
+            #[cfg(feature = "k8s")]
+            if let Some(K8sError::Request(..)) = e.downcast_ref::<K8sError>() {
+                diagnostic.kind = Kind::Io;
+                break;
+            }
+            if let Some(FileSdError::Yaml(..) | FileSdError::Invalid(..)) =
+                e.downcast_ref::<FileSdError>()
+            {
+                diagnostic.kind = Kind::Parse;
+                break;
+            }
+            if e.is::<LabelLimitError>() {
+                diagnostic.kind = Kind::Validation;
+                break;
+            }
+            if e.is::<VmImportError>() {
+                diagnostic.kind = Kind::Parse;
+                break;
+            }
+            if let Some(JsonError::Invalid(_)) = e.downcast_ref::<JsonError>() {
+                diagnostic.kind = Kind::Parse;
+                break;
+            }
+            if let Some(e) = e.downcast_ref::<protobuf::ProtobufError>() {
+                diagnostic.kind = match e {
+                    protobuf::ProtobufError::IoError(_) => Kind::Io,
+                    _ => Kind::Parse,
+                };
+                break;
+            }
+            if e.is::<prost::DecodeError>() {
+                diagnostic.kind = Kind::Parse;
+                break;
+            }
+            if let Some(e) = e.downcast_ref::<io::Error>() {
+                // Decoders report malformed input as invalid data.
+                diagnostic.kind = match e.kind() {
+                    io::ErrorKind::InvalidData => Kind::Parse,
+                    _ => Kind::Io,
+                };
+                break;
+            }
+            cause = e.source();
+        }
+        diagnostic
+    }
+
+    /// Names the input the problem is in, unless it already has one.
+    pub fn in_file(mut self, file: &str) -> Self {
+        self.file.get_or_insert_with(|| file.to_string());
+        self
+    }
+
+    /// `{"file", "line", "column", "code", "message"}`, with `null` for
+    /// unknown fields.
+    pub fn to_json(&self) -> Value {
+        json!({
+            "file": self.file,
+            "line": self.line,
+            "column": self.column,
+            "code": self.kind.code(),
+            "message": self.message,
+        })
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(file) = &self.file {
+            write!(f, "{}: ", file)?;
+        }
+        f.write_str(&self.message)
+    }
+}
+
+impl Error for Diagnostic {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::{read_metric_families, Format};
+
+    #[test]
+    fn test_from_error() {
+        let err = read_metric_families(Format::Text, "a 1\nb{ 2\n".as_bytes()).unwrap_err();
+        let d = Diagnostic::from_error(err.as_ref()).in_file("x.prom");
+        assert_eq!((d.kind, d.line, d.column), (Kind::Parse, Some(2), Some(4)));
+        assert_eq!(
+            d.to_string(),
+            "x.prom: text format parsing error in line 2: invalid label name for metric \"b\""
+        );
+
+        // Classified errors survive being boxed again.
+        let boxed: Box<dyn Error> = Box::new(d.clone());
+        assert_eq!(Diagnostic::from_error(boxed.as_ref()), d);
+
+        let err = read_metric_families(Format::Json, "[{]".as_bytes()).unwrap_err();
+        let d = Diagnostic::from_error(err.as_ref());
+        assert_eq!((d.kind, d.line, d.column), (Kind::Parse, Some(1), Some(3)));
+        let err = read_metric_families(Format::Json, "[{}]".as_bytes()).unwrap_err();
+        assert_eq!(Diagnostic::from_error(err.as_ref()).kind, Kind::Parse);
+
+        let err = io::Error::new(io::ErrorKind::NotFound, "x.prom: not found");
+        let d = Diagnostic::from_error(&err);
+        assert_eq!(
+            d.to_json(),
+            json!({"file": null, "line": null, "column": null, "code": "io", "message": "x.prom: not found"})
+        );
+
+        let err: Box<dyn Error> = "no files match \"*.om\"".into();
+        assert_eq!(Diagnostic::from_error(err.as_ref()).kind, Kind::Other);
+    }
+}
@@ -0,0 +1,300 @@
+use prometheus::proto::{MetricFamily, MetricType};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::flatten::flatten_family;
+use crate::text_encode::{format_float, type_name};
+
+/// One difference between two sets of metric families. Series are identified
+/// by their sample name and labels as written in the text format, e.g.
+/// `latency_bucket{le="0.5"}`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Change {
+    FamilyAdded {
+        family: String,
+    },
+    FamilyRemoved {
+        family: String,
+    },
+    TypeChanged {
+        family: String,
+        old: MetricType,
+        new: MetricType,
+    },
+    HelpChanged {
+        family: String,
+        old: String,
+        new: String,
+    },
+    SeriesAdded {
+        family: String,
+        series: String,
+        value: f64,
+    },
+    SeriesRemoved {
+        family: String,
+        series: String,
+        value: f64,
+    },
+    ValueChanged {
+        family: String,
+        series: String,
+        old: f64,
+        new: f64,
+    },
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Change::FamilyAdded { family } => write!(f, "+ family {}", family),
+            Change::FamilyRemoved { family } => write!(f, "- family {}", family),
+            Change::TypeChanged { family, old, new } => write!(
+                f,
+                "~ type {}: {} -> {}",
+                family,
+                type_name(*old),
+                type_name(*new)
+            ),
+            Change::HelpChanged { family, old, new } => {
+                write!(f, "~ help {}: {:?} -> {:?}", family, old, new)
+            }
+            Change::SeriesAdded { series, value, .. } => {
+                write!(f, "+ {} {}", series, format_float(*value))
+            }
+            Change::SeriesRemoved { series, value, .. } => {
+                write!(f, "- {} {}", series, format_float(*value))
+            }
+            Change::ValueChanged {
+                series, old, new, ..
+            } => write!(
+                f,
+                "~ {} {} -> {} ({:+})",
+                series,
+                format_float(*old),
+                format_float(*new),
+                new - old
+            ),
+        }
+    }
+}
+
+impl Change {
+    pub fn to_json(&self) -> Value {
+        match self {
+            Change::FamilyAdded { family } => json!({"change": "family_added", "family": family}),
+            Change::FamilyRemoved { family } => {
+                json!({"change": "family_removed", "family": family})
+            }
+            Change::TypeChanged { family, old, new } => json!({
+                "change": "type_changed",
+                "family": family,
+                "old": type_name(*old),
+                "new": type_name(*new),
+            }),
+            Change::HelpChanged { family, old, new } => json!({
+                "change": "help_changed",
+                "family": family,
+                "old": old,
+                "new": new,
+            }),
+            Change::SeriesAdded {
+                family,
+                series,
+                value,
+            } => json!({
+                "change": "series_added",
+                "family": family,
+                "series": series,
+                "value": format_float(*value),
+            }),
+            Change::SeriesRemoved {
+                family,
+                series,
+                value,
+            } => json!({
+                "change": "series_removed",
+                "family": family,
+                "series": series,
+                "value": format_float(*value),
+            }),
+            Change::ValueChanged {
+                family,
+                series,
+                old,
+                new,
+            } => json!({
+                "change": "value_changed",
+                "family": family,
+                "series": series,
+                "old": format_float(*old),
+                "new": format_float(*new),
+                "delta": format_float(new - old),
+            }),
+        }
+    }
+}
+
+/// Compares two sets of metric families. Values of series present on both
+/// sides are reported when they differ by more than `tolerance`; `NaN` equals
+/// `NaN`. Series of added or removed families are not listed individually,
+/// nor are those of families whose type changed.
+pub fn diff(old: &[MetricFamily], new: &[MetricFamily], tolerance: f64) -> Vec<Change> {
+    let old_by_name: BTreeMap<&str, &MetricFamily> =
+        old.iter().map(|mf| (mf.get_name(), mf)).collect();
+    let new_by_name: BTreeMap<&str, &MetricFamily> =
+        new.iter().map(|mf| (mf.get_name(), mf)).collect();
+
+    let mut changes = Vec::new();
+
+    let mut names: Vec<&str> = old_by_name
+        .keys()
+        .chain(new_by_name.keys())
+        .copied()
+        .collect();
+    names.sort_unstable();
+    names.dedup();
+
+    for name in names {
+        let family = name.to_string();
+        let (old_mf, new_mf) = match (old_by_name.get(name), new_by_name.get(name)) {
+            (Some(o), Some(n)) => (o, n),
+            (None, _) => {
+                changes.push(Change::FamilyAdded { family });
+                continue;
+            }
+            (_, None) => {
+                changes.push(Change::FamilyRemoved { family });
+                continue;
+            }
+        };
+
+        if old_mf.get_field_type() != new_mf.get_field_type() {
+            changes.push(Change::TypeChanged {
+                family,
+                old: old_mf.get_field_type(),
+                new: new_mf.get_field_type(),
+            });
+            continue;
+        }
+        if old_mf.get_help() != new_mf.get_help() {
+            changes.push(Change::HelpChanged {
+                family: family.clone(),
+                old: old_mf.get_help().to_string(),
+                new: new_mf.get_help().to_string(),
+            });
+        }
+
+        let old_samples = samples(old_mf);
+        let new_samples = samples(new_mf);
+
+        for (series, &old) in &old_samples {
+            match new_samples.get(series) {
+                None => changes.push(Change::SeriesRemoved {
+                    family: family.clone(),
+                    series: series.clone(),
+                    value: old,
+                }),
+                Some(&new) if !values_equal(old, new, tolerance) => {
+                    changes.push(Change::ValueChanged {
+                        family: family.clone(),
+                        series: series.clone(),
+                        old,
+                        new,
+                    })
+                }
+                Some(_) => {}
+            }
+        }
+        for (series, &value) in &new_samples {
+            if !old_samples.contains_key(series) {
+                changes.push(Change::SeriesAdded {
+                    family: family.clone(),
+                    series: series.clone(),
+                    value,
+                });
+            }
+        }
+    }
+
+    changes
+}
+
+fn values_equal(old: f64, new: f64, tolerance: f64) -> bool {
+    if old.is_nan() || new.is_nan() {
+        return old.is_nan() && new.is_nan();
+    }
+    // Also covers equal infinities, whose difference is NaN.
+    old == new || (new - old).abs() <= tolerance
+}
+
+/// The samples of a family keyed by series, labels sorted so that label
+/// order does not count as a difference.
+fn samples(mf: &MetricFamily) -> BTreeMap<String, f64> {
+    flatten_family(mf)
+        .into_iter()
+        .map(|s| (s.series_key(), s.value))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::{read_metric_families, Format};
+
+    fn parse(text: &str) -> Vec<MetricFamily> {
+        read_metric_families(Format::Text, text.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn test_diff() {
+        let old = parse(
+            r#"# HELP requests_total Requests.
+# TYPE requests_total counter
+requests_total{code="200",path="/"} 10
+requests_total{code="500",path="/"} 1
+# TYPE temperature gauge
+temperature 21.5
+# TYPE mode gauge
+mode 1
+# TYPE gone gauge
+gone 1
+"#,
+        );
+        let new = parse(
+            r#"# HELP requests_total Requests served.
+# TYPE requests_total counter
+requests_total{path="/",code="200"} 15
+requests_total{code="404",path="/"} 2
+# TYPE temperature gauge
+temperature 21.50001
+# TYPE mode untyped
+mode 1
+# TYPE latency histogram
+latency_bucket{le="1"} 1
+latency_sum 0.5
+latency_count 1
+"#,
+        );
+
+        let changes = diff(&old, &new, 0.001);
+        let rendered: Vec<String> = changes.iter().map(|c| c.to_string()).collect();
+        assert_eq!(
+            rendered,
+            vec![
+                "- family gone",
+                "+ family latency",
+                "~ type mode: gauge -> untyped",
+                r#"~ help requests_total: "Requests." -> "Requests served.""#,
+                r#"~ requests_total{code="200",path="/"} 10 -> 15 (+5)"#,
+                r#"- requests_total{code="500",path="/"} 1"#,
+                r#"+ requests_total{code="404",path="/"} 2"#,
+            ]
+        );
+
+        assert_eq!(changes[4].to_json()["delta"], "5");
+        assert_eq!(diff(&old, &new, 0.0).len(), 8);
+        assert!(diff(&new, &new, 0.0).is_empty());
+    }
+}
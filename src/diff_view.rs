@@ -0,0 +1,491 @@
+//! `pmv diff --interactive`: the changes side by side, old values left and
+//! new values right, in a full-screen view of the terminal.
+//!
+//! Keys: `j`/`k` or the arrows move, PgUp/PgDn or `b`/space page, `g`/`G`
+//! jump to the first or last change, `a`, `r` and `c` show or hide added,
+//! removed and changed entries, `/` searches, Esc clears the search and `q`
+//! quits.
+
+use std::fmt::Write as _;
+use std::io;
+
+use crate::diff::Change;
+use crate::text_encode::{format_float, type_name};
+
+const BOLD: &str = "\x1b[1m";
+const DIM: &str = "\x1b[2m";
+const REVERSE: &str = "\x1b[7m";
+const RED: &str = "\x1b[31m";
+const GREEN: &str = "\x1b[32m";
+const YELLOW: &str = "\x1b[33m";
+const RESET: &str = "\x1b[0m";
+const CLEAR_LINE: &str = "\x1b[K";
+
+/// The change types the view filters by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeKind {
+    Added,
+    Removed,
+    Changed,
+}
+
+impl ChangeKind {
+    pub fn of(change: &Change) -> Self {
+        match change {
+            Change::FamilyAdded { .. } | Change::SeriesAdded { .. } => ChangeKind::Added,
+            Change::FamilyRemoved { .. } | Change::SeriesRemoved { .. } => ChangeKind::Removed,
+            Change::TypeChanged { .. }
+            | Change::HelpChanged { .. }
+            | Change::ValueChanged { .. } => ChangeKind::Changed,
+        }
+    }
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// A key press, as far as the view cares.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Key {
+    Char(char),
+    Up,
+    Down,
+    PageUp,
+    PageDown,
+    Home,
+    End,
+    Enter,
+    Backspace,
+    Esc,
+}
+
+/// Splits what the terminal sent in raw mode into keys. Unknown escape
+/// sequences are dropped.
+pub fn parse_keys(bytes: &[u8]) -> Vec<Key> {
+    let mut keys = Vec::new();
+    let s = String::from_utf8_lossy(bytes);
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        let key = match c {
+            '\x1b' if chars.peek() == Some(&'[') || chars.peek() == Some(&'O') => {
+                chars.next();
+                let mut seq = String::new();
+                for c in chars.by_ref() {
+                    seq.push(c);
+                    if c.is_ascii_alphabetic() || c == '~' {
+                        break;
+                    }
+                }
+                match seq.as_str() {
+                    "A" => Key::Up,
+                    "B" => Key::Down,
+                    "H" | "1~" => Key::Home,
+                    "F" | "4~" => Key::End,
+                    "5~" => Key::PageUp,
+                    "6~" => Key::PageDown,
+                    _ => continue,
+                }
+            }
+            '\x1b' => Key::Esc,
+            '\r' | '\n' => Key::Enter,
+            '\x7f' | '\x08' => Key::Backspace,
+            c => Key::Char(c),
+        };
+        keys.push(key);
+    }
+    keys
+}
+
+/// What is shown of a diff and where, independent of the terminal.
+#[derive(Debug)]
+pub struct DiffView {
+    changes: Vec<Change>,
+    shown: [bool; 3],
+    search: String,
+    typing: bool,
+    /// Indices into `changes` passing the filters.
+    visible: Vec<usize>,
+    cursor: usize,
+    top: usize,
+}
+
+impl DiffView {
+    pub fn new(changes: Vec<Change>) -> Self {
+        let mut view = DiffView {
+            changes,
+            shown: [true; 3],
+            search: String::new(),
+            typing: false,
+            visible: Vec::new(),
+            cursor: 0,
+            top: 0,
+        };
+        view.refilter();
+        view
+    }
+
+    /// The changes passing the filters, in diff order.
+    pub fn visible(&self) -> impl Iterator<Item = &Change> {
+        self.visible.iter().map(|&i| &self.changes[i])
+    }
+
+    /// Handles a key press with `page` rows on screen. Returns false once
+    /// the view should close.
+    pub fn handle(&mut self, key: Key, page: usize) -> bool {
+        if self.typing {
+            match key {
+                Key::Enter => self.typing = false,
+                Key::Esc => {
+                    self.typing = false;
+                    self.search.clear();
+                }
+                Key::Backspace => {
+                    self.search.pop();
+                }
+                Key::Char(c) if !c.is_control() => self.search.push(c),
+                _ => return true,
+            }
+            self.refilter();
+            return true;
+        }
+
+        let last = self.visible.len().saturating_sub(1);
+        let page = page.max(1);
+        match key {
+            Key::Char('q') => return false,
+            Key::Char('j') | Key::Down => self.cursor = (self.cursor + 1).min(last),
+            Key::Char('k') | Key::Up => self.cursor = self.cursor.saturating_sub(1),
+            Key::Char(' ') | Key::PageDown => self.cursor = (self.cursor + page).min(last),
+            Key::Char('b') | Key::PageUp => self.cursor = self.cursor.saturating_sub(page),
+            Key::Char('g') | Key::Home => self.cursor = 0,
+            Key::Char('G') | Key::End => self.cursor = last,
+            Key::Char('/') => {
+                self.typing = true;
+                self.search.clear();
+                self.refilter();
+            }
+            Key::Esc if !self.search.is_empty() => {
+                self.search.clear();
+                self.refilter();
+            }
+            Key::Esc => return false,
+            Key::Char(c @ ('a' | 'r' | 'c')) => {
+                let kind = match c {
+                    'a' => ChangeKind::Added,
+                    'r' => ChangeKind::Removed,
+                    _ => ChangeKind::Changed,
+                };
+                self.shown[kind.index()] = !self.shown[kind.index()];
+                self.refilter();
+            }
+            _ => {}
+        }
+        true
+    }
+
+    fn refilter(&mut self) {
+        let current = self.visible.get(self.cursor).copied();
+        let search = self.search.to_lowercase();
+        self.visible = (0..self.changes.len())
+            .filter(|&i| {
+                let change = &self.changes[i];
+                self.shown[ChangeKind::of(change).index()]
+                    && (search.is_empty() || change.to_string().to_lowercase().contains(&search))
+            })
+            .collect();
+        // Stay on the same change if it is still shown.
+        self.cursor = current
+            .and_then(|c| self.visible.iter().position(|&i| i >= c))
+            .unwrap_or(0)
+            .min(self.visible.len().saturating_sub(1));
+    }
+
+    /// Renders a `width` by `height` screen: a header, one row per change
+    /// with the old side left and the new side right, and a status line.
+    pub fn render(&mut self, width: usize, height: usize) -> String {
+        let rows = height.saturating_sub(2);
+        if self.cursor < self.top {
+            self.top = self.cursor;
+        } else if rows > 0 && self.cursor >= self.top + rows {
+            self.top = self.cursor + 1 - rows;
+        }
+
+        // Two columns with a marker before and a separator between them.
+        let column = width.saturating_sub(5) / 2;
+        let mut screen = String::new();
+        let _ = write!(
+            screen,
+            "{}  {} │ {}{}{}\r\n",
+            BOLD,
+            fit("OLD", column),
+            fit("NEW", column),
+            RESET,
+            CLEAR_LINE
+        );
+
+        for row in 0..rows {
+            let Some(&i) = self.visible.get(self.top + row) else {
+                let _ = write!(screen, "{}\r\n", CLEAR_LINE);
+                continue;
+            };
+            let change = &self.changes[i];
+            let (marker, color) = match ChangeKind::of(change) {
+                ChangeKind::Added => ('+', GREEN),
+                ChangeKind::Removed => ('-', RED),
+                ChangeKind::Changed => ('~', YELLOW),
+            };
+            let (old, new) = sides(change);
+            let selected = if self.top + row == self.cursor {
+                REVERSE
+            } else {
+                ""
+            };
+            let _ = write!(
+                screen,
+                "{}{}{} {} │ {}{}{}\r\n",
+                selected,
+                color,
+                marker,
+                fit(&old, column),
+                fit(&new, column),
+                RESET,
+                CLEAR_LINE
+            );
+        }
+
+        let status = if self.typing {
+            format!("/{}", self.search)
+        } else {
+            let mut status = format!("{} of {} changes", self.visible.len(), self.changes.len());
+            for (kind, label) in ["[a]dded", "[r]emoved", "[c]hanged"].iter().enumerate() {
+                let mark = if self.shown[kind] { 'x' } else { ' ' };
+                let _ = write!(status, "  {} {}", mark, label);
+            }
+            if !self.search.is_empty() {
+                let _ = write!(status, "  matching {:?}", self.search);
+            }
+            status.push_str("  / search  q quit");
+            status
+        };
+        let _ = write!(
+            screen,
+            "{}{}{}{}",
+            DIM,
+            fit(&status, width),
+            RESET,
+            CLEAR_LINE
+        );
+        screen
+    }
+}
+
+/// The old and new side of a change; the side a change does not have is
+/// empty.
+fn sides(change: &Change) -> (String, String) {
+    match change {
+        Change::FamilyAdded { family } => (String::new(), format!("family {}", family)),
+        Change::FamilyRemoved { family } => (format!("family {}", family), String::new()),
+        Change::TypeChanged { family, old, new } => (
+            format!("{} {}", family, type_name(*old)),
+            format!("{} {}", family, type_name(*new)),
+        ),
+        Change::HelpChanged { family, old, new } => (
+            format!("{} help {:?}", family, old),
+            format!("{} help {:?}", family, new),
+        ),
+        Change::SeriesAdded { series, value, .. } => (
+            String::new(),
+            format!("{} {}", series, format_float(*value)),
+        ),
+        Change::SeriesRemoved { series, value, .. } => (
+            format!("{} {}", series, format_float(*value)),
+            String::new(),
+        ),
+        Change::ValueChanged {
+            series, old, new, ..
+        } => (
+            format!("{} {}", series, format_float(*old)),
+            format!("{} {} ({:+})", series, format_float(*new), new - old),
+        ),
+    }
+}
+
+/// Pads or cuts `s` to exactly `width` characters, marking cuts with `…`.
+fn fit(s: &str, width: usize) -> String {
+    let len = s.chars().count();
+    if len <= width {
+        return format!("{}{}", s, " ".repeat(width - len));
+    }
+    let mut out: String = s.chars().take(width.saturating_sub(1)).collect();
+    if width > 0 {
+        out.push('…');
+    }
+    out
+}
+
+/// Shows `changes` until the user quits, reading keys from and drawing on
+/// the controlling terminal, so that stdin and stdout may be redirected.
+#[cfg(unix)]
+pub fn run(changes: Vec<Change>) -> io::Result<()> {
+    use std::io::{Read, Write};
+    use std::os::fd::AsRawFd;
+
+    let mut tty = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/tty")
+        .map_err(|e| io::Error::new(e.kind(), format!("/dev/tty: {}", e)))?;
+    let fd = tty.as_raw_fd();
+    let _raw = RawMode::enable(fd)?;
+    // Alternate screen, cursor hidden; undone in reverse on the way out.
+    tty.write_all(b"\x1b[?1049h\x1b[?25l")?;
+
+    let mut view = DiffView::new(changes);
+    let result = (|| -> io::Result<()> {
+        let mut buf = [0; 64];
+        loop {
+            let (width, height) = terminal_size(fd);
+            write!(tty, "\x1b[H{}", view.render(width, height))?;
+            tty.flush()?;
+            let n = tty.read(&mut buf)?;
+            if n == 0 {
+                return Ok(());
+            }
+            let page = height.saturating_sub(2);
+            for key in parse_keys(&buf[..n]) {
+                if !view.handle(key, page) {
+                    return Ok(());
+                }
+            }
+        }
+    })();
+
+    tty.write_all(b"\x1b[?25h\x1b[?1049l")?;
+    tty.flush()?;
+    result
+}
+
+#[cfg(not(unix))]
+pub fn run(_changes: Vec<Change>) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "--interactive needs a Unix terminal",
+    ))
+}
+
+/// Puts the terminal in raw mode until dropped.
+#[cfg(unix)]
+struct RawMode {
+    fd: i32,
+    saved: libc::termios,
+}
+
+#[cfg(unix)]
+impl RawMode {
+    fn enable(fd: i32) -> io::Result<Self> {
+        // SAFETY: termios is plain data, filled in by tcgetattr before use.
+        let mut saved: libc::termios = unsafe { std::mem::zeroed() };
+        if unsafe { libc::tcgetattr(fd, &mut saved) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut raw = saved;
+        unsafe { libc::cfmakeraw(&mut raw) };
+        if unsafe { libc::tcsetattr(fd, libc::TCSANOW, &raw) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(RawMode { fd, saved })
+    }
+}
+
+#[cfg(unix)]
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        unsafe { libc::tcsetattr(self.fd, libc::TCSANOW, &self.saved) };
+    }
+}
+
+/// Columns and rows of the terminal, 80 by 24 if it does not say.
+#[cfg(unix)]
+fn terminal_size(fd: i32) -> (usize, usize) {
+    // SAFETY: TIOCGWINSZ fills in the winsize passed.
+    let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+    if unsafe { libc::ioctl(fd, libc::TIOCGWINSZ, &mut size) } != 0 || size.ws_col == 0 {
+        return (80, 24);
+    }
+    (size.ws_col as usize, size.ws_row as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diff::diff;
+    use crate::format::{read_metric_families, Format};
+
+    fn strip_ansi(s: &str) -> String {
+        let mut out = String::new();
+        let mut chars = s.chars();
+        while let Some(c) = chars.next() {
+            if c == '\x1b' {
+                chars.by_ref().find(|c| c.is_ascii_alphabetic());
+            } else if c != '\r' {
+                out.push(c);
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn test_diff_view() {
+        let parse = |text: &str| read_metric_families(Format::Text, text.as_bytes()).unwrap();
+        let old = parse("a{i=\"1\"} 1\na{i=\"2\"} 2\nb 1\n");
+        let new = parse("a{i=\"1\"} 3\na{i=\"3\"} 3\nc 1\n");
+        let mut view = DiffView::new(diff(&old, &new, 0.0));
+
+        let screen = strip_ansi(&view.render(50, 8));
+        let lines: Vec<&str> = screen.lines().collect();
+        assert_eq!(lines[0].trim_end(), "  OLD                    │ NEW");
+        assert_eq!(
+            lines[1].trim_end(),
+            "~ a{i=\"1\"} 1             │ a{i=\"1\"} 3 (+2)"
+        );
+        assert_eq!(lines[2].trim_end(), "- a{i=\"2\"} 2             │");
+        assert!(
+            lines[7].starts_with("5 of 5 changes  x [a]dded"),
+            "{}",
+            lines[7]
+        );
+
+        // Hiding added and changed entries leaves the removed ones.
+        for key in parse_keys(b"ac") {
+            assert!(view.handle(key, 6));
+        }
+        let kinds: Vec<_> = view.visible().map(ChangeKind::of).collect();
+        assert_eq!(kinds, vec![ChangeKind::Removed; 2]);
+
+        for key in parse_keys(b"ac/I=\"3\r") {
+            view.handle(key, 6);
+        }
+        let found: Vec<String> = view.visible().map(|c| c.to_string()).collect();
+        assert_eq!(found, vec!["+ a{i=\"3\"} 3"]);
+        assert!(view.handle(Key::Esc, 6));
+        assert_eq!(view.visible().count(), 5);
+
+        // Scrolling keeps the cursor on screen, a long line is cut.
+        view.handle(Key::End, 6);
+        let screen = strip_ansi(&view.render(20, 4));
+        assert_eq!(screen.lines().nth(2).unwrap(), "+         │ family…");
+        assert!(!view.handle(Key::Char('q'), 6));
+
+        assert_eq!(
+            parse_keys(b"\x1b[A\x1b[6~\x1bx\x7f"),
+            vec![
+                Key::Up,
+                Key::PageDown,
+                Key::Esc,
+                Key::Char('x'),
+                Key::Backspace
+            ]
+        );
+    }
+}
@@ -0,0 +1,380 @@
+//! Scrape targets from DNS names, as Prometheus `dns_sd_configs` finds
+//! them: the addresses of A or AAAA records with a fixed port, or the
+//! hosts and ports of SRV records.
+//!
+//! A and AAAA lookups go through the system resolver. SRV records are
+//! queried from the first `nameserver` of `/etc/resolv.conf` with a small
+//! client of our own, over UDP and, for truncated answers, TCP.
+
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream, ToSocketAddrs, UdpSocket};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use crate::retry::random_fraction;
+use crate::scrape::Target;
+
+const TYPE_SRV: u16 = 33;
+const CLASS_IN: u16 = 1;
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RecordType {
+    A,
+    Aaaa,
+    #[default]
+    Srv,
+}
+
+impl FromStr for RecordType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_uppercase().as_str() {
+            "A" => Ok(RecordType::A),
+            "AAAA" => Ok(RecordType::Aaaa),
+            "SRV" => Ok(RecordType::Srv),
+            _ => Err(format!(
+                "unknown record type {:?}, expected A, AAAA or SRV",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum DnsSdError {
+    /// The lookup could not be sent or its answer not received.
+    Lookup(String, io::Error),
+    /// The server answered with an error or a malformed message.
+    Response(String, String),
+}
+
+impl fmt::Display for DnsSdError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DnsSdError::Lookup(name, e) => write!(f, "DNS lookup of {} failed: {}", name, e),
+            DnsSdError::Response(name, msg) => write!(f, "DNS lookup of {} failed: {}", name, msg),
+        }
+    }
+}
+
+impl Error for DnsSdError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            DnsSdError::Lookup(_, e) => Some(e),
+            DnsSdError::Response(..) => None,
+        }
+    }
+}
+
+/// Looks `name` up and returns the `host:port` addresses it stands for.
+/// `port` is the port of A and AAAA addresses; SRV records carry theirs.
+pub fn lookup(name: &str, record_type: RecordType, port: u16) -> Result<Vec<String>, DnsSdError> {
+    match record_type {
+        RecordType::A | RecordType::Aaaa => {
+            let addrs = (name, port)
+                .to_socket_addrs()
+                .map_err(|e| DnsSdError::Lookup(name.to_string(), e))?;
+            let mut addrs: Vec<String> = addrs
+                .filter(|addr| addr.is_ipv4() == (record_type == RecordType::A))
+                .map(|addr| addr.to_string())
+                .collect();
+            addrs.sort();
+            addrs.dedup();
+            Ok(addrs)
+        }
+        RecordType::Srv => lookup_srv(name, nameserver()),
+    }
+}
+
+/// The first nameserver of `/etc/resolv.conf`, or the local host's.
+fn nameserver() -> SocketAddr {
+    let ip = fs::read_to_string("/etc/resolv.conf")
+        .ok()
+        .and_then(|conf| {
+            conf.lines().find_map(|line| {
+                let mut words = line.split_whitespace();
+                (words.next() == Some("nameserver")).then(|| words.next()?.parse().ok())?
+            })
+        })
+        .unwrap_or(IpAddr::from([127, 0, 0, 1]));
+    SocketAddr::new(ip, 53)
+}
+
+/// Queries `server` for the SRV records of `name` and returns their targets
+/// and ports, sorted.
+fn lookup_srv(name: &str, server: SocketAddr) -> Result<Vec<String>, DnsSdError> {
+    let lookup_error = |e| DnsSdError::Lookup(name.to_string(), e);
+    let response_error = |msg: String| DnsSdError::Response(name.to_string(), msg);
+
+    let id = (random_fraction() * 65536.0) as u16;
+    let query = srv_query(id, name).map_err(response_error)?;
+    let mut response = query_udp(&query, server).map_err(lookup_error)?;
+    if response.len() > 2 && response[2] & 0x02 != 0 {
+        // Truncated; TCP has room for every record.
+        response = query_tcp(&query, server).map_err(lookup_error)?;
+    }
+    let mut addrs = parse_srv_response(id, &response).map_err(response_error)?;
+    addrs.sort();
+    addrs.dedup();
+    Ok(addrs)
+}
+
+fn srv_query(id: u16, name: &str) -> Result<Vec<u8>, String> {
+    let mut query = Vec::with_capacity(name.len() + 18);
+    query.extend_from_slice(&id.to_be_bytes());
+    // Recursion desired, one question.
+    query.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(format!("invalid name {:?}", name));
+        }
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    query.extend_from_slice(&TYPE_SRV.to_be_bytes());
+    query.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(query)
+}
+
+fn query_udp(query: &[u8], server: SocketAddr) -> io::Result<Vec<u8>> {
+    let local: SocketAddr = if server.is_ipv4() {
+        ([0, 0, 0, 0], 0).into()
+    } else {
+        ([0u16; 8], 0).into()
+    };
+    let socket = UdpSocket::bind(local)?;
+    socket.set_read_timeout(Some(TIMEOUT))?;
+    socket.connect(server)?;
+    socket.send(query)?;
+    let mut buf = vec![0; 65535];
+    let len = socket.recv(&mut buf)?;
+    buf.truncate(len);
+    Ok(buf)
+}
+
+fn query_tcp(query: &[u8], server: SocketAddr) -> io::Result<Vec<u8>> {
+    let mut stream = TcpStream::connect_timeout(&server, TIMEOUT)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.write_all(&(query.len() as u16).to_be_bytes())?;
+    stream.write_all(query)?;
+    let mut len = [0; 2];
+    stream.read_exact(&mut len)?;
+    let mut buf = vec![0; u16::from_be_bytes(len) as usize];
+    stream.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+/// The `host:port` of every SRV record in the answer to query `id`.
+fn parse_srv_response(id: u16, msg: &[u8]) -> Result<Vec<String>, String> {
+    let u16_at = |pos: usize| -> Result<u16, String> {
+        msg.get(pos..pos + 2)
+            .map(|b| u16::from_be_bytes([b[0], b[1]]))
+            .ok_or_else(|| "truncated response".to_string())
+    };
+    if u16_at(0)? != id {
+        return Err("response to another query".to_string());
+    }
+    match u16_at(2)? & 0x000f {
+        0 => {}
+        3 => return Err("no such name".to_string()),
+        rcode => return Err(format!("server returned error code {}", rcode)),
+    }
+    let (questions, answers) = (u16_at(4)?, u16_at(6)?);
+
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = read_name(msg, pos)?.1 + 4;
+    }
+    let mut addrs = Vec::new();
+    for _ in 0..answers {
+        pos = read_name(msg, pos)?.1;
+        let (record_type, length) = (u16_at(pos)?, u16_at(pos + 8)? as usize);
+        let data = pos + 10;
+        if record_type == TYPE_SRV {
+            let port = u16_at(data + 4)?;
+            let (host, _) = read_name(msg, data + 6)?;
+            addrs.push(format!("{}:{}", host, port));
+        }
+        pos = data + length;
+    }
+    Ok(addrs)
+}
+
+/// Reads the possibly compressed name at `pos`, without its trailing dot,
+/// and returns it with the position just past it.
+fn read_name(msg: &[u8], mut pos: usize) -> Result<(String, usize), String> {
+    let truncated = || "truncated response".to_string();
+    let mut labels = Vec::new();
+    let mut end = None;
+    // Every pointer must go back, which bounds the jumps.
+    let mut limit = pos;
+    loop {
+        let len = *msg.get(pos).ok_or_else(truncated)? as usize;
+        match len {
+            0 => break,
+            len if len & 0xc0 == 0xc0 => {
+                let low = *msg.get(pos + 1).ok_or_else(truncated)? as usize;
+                let target = (len & 0x3f) << 8 | low;
+                end.get_or_insert(pos + 2);
+                if target >= limit {
+                    return Err("invalid name compression".to_string());
+                }
+                limit = target;
+                pos = target;
+            }
+            len if len <= 63 => {
+                let label = msg.get(pos + 1..pos + 1 + len).ok_or_else(truncated)?;
+                labels.push(String::from_utf8_lossy(label).into_owned());
+                pos += 1 + len;
+            }
+            _ => return Err("invalid label length".to_string()),
+        }
+    }
+    Ok((labels.join("."), end.unwrap_or(pos + 1)))
+}
+
+/// Targets from DNS names, looked up again every refresh interval.
+#[derive(Debug)]
+pub struct DnsSd {
+    names: Vec<String>,
+    record_type: RecordType,
+    port: u16,
+    job: String,
+    refresh: Duration,
+    next: Option<Instant>,
+    addrs: Option<Vec<String>>,
+}
+
+impl DnsSd {
+    /// Targets from `names`, scraped at `/metrics` under `job`. `port` is
+    /// the port of A and AAAA addresses.
+    pub fn new(
+        names: Vec<String>,
+        record_type: RecordType,
+        port: u16,
+        job: impl Into<String>,
+    ) -> Self {
+        DnsSd {
+            names,
+            record_type,
+            port,
+            job: job.into(),
+            refresh: Duration::from_secs(30),
+            next: None,
+            addrs: None,
+        }
+    }
+
+    /// How often names are looked up again; 30s by default, as in
+    /// Prometheus.
+    pub fn refresh_interval(mut self, refresh: Duration) -> Self {
+        self.refresh = refresh;
+        self
+    }
+
+    /// Looks the names up if the refresh interval passed since the last
+    /// lookup, which the first call always makes, and returns the targets
+    /// if they changed. After an error the caller should keep the targets
+    /// it has; the next call tries again.
+    pub fn poll(&mut self) -> Result<Option<Vec<Target>>, DnsSdError> {
+        let now = Instant::now();
+        if self.next.is_some_and(|next| now < next) {
+            return Ok(None);
+        }
+        // Spread out, so that many agents do not query in step.
+        self.next = Some(now + self.refresh.mul_f64(0.9 + random_fraction() * 0.2));
+
+        let mut addrs = Vec::new();
+        for name in &self.names {
+            addrs.extend(lookup(name, self.record_type, self.port)?);
+        }
+        if self.addrs.as_ref() == Some(&addrs) {
+            return Ok(None);
+        }
+        let targets = addrs
+            .iter()
+            .map(|addr| Target {
+                url: format!("http://{}/metrics", addr),
+                job: self.job.clone(),
+                labels: Vec::new(),
+            })
+            .collect();
+        self.addrs = Some(addrs);
+        Ok(Some(targets))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    // An SRV answer for `name`, with its records pointing into the question
+    // so that names are compressed.
+    fn srv_response(query: &[u8], records: &[(u16, &str)]) -> Vec<u8> {
+        let mut msg = query[..2].to_vec();
+        msg.extend_from_slice(&[0x81, 0x80, 0, 1]);
+        msg.extend_from_slice(&(records.len() as u16).to_be_bytes());
+        msg.extend_from_slice(&[0, 0, 0, 0]);
+        msg.extend_from_slice(&query[12..]);
+        for (port, host) in records {
+            let mut data = vec![0, 10, 0, 5];
+            data.extend_from_slice(&port.to_be_bytes());
+            data.push(host.len() as u8);
+            data.extend_from_slice(host.as_bytes());
+            // The name of the service, from offset 12 on.
+            data.extend_from_slice(&[0xc0, 12]);
+            msg.extend_from_slice(&[0xc0, 12, 0, 33, 0, 1, 0, 0, 0, 60]);
+            msg.extend_from_slice(&(data.len() as u16).to_be_bytes());
+            msg.extend_from_slice(&data);
+        }
+        msg
+    }
+
+    #[test]
+    fn test_dns_sd() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            let mut buf = [0; 512];
+            let (len, peer) = server.recv_from(&mut buf).unwrap();
+            let response = srv_response(&buf[..len], &[(9100, "b"), (9100, "a")]);
+            server.send_to(&response, peer).unwrap();
+        });
+        let addrs = lookup_srv("_node._tcp.example.org.", addr).unwrap();
+        handle.join().unwrap();
+        assert_eq!(
+            addrs,
+            [
+                "a._node._tcp.example.org:9100",
+                "b._node._tcp.example.org:9100"
+            ]
+        );
+
+        let query = srv_query(7, "x.org").unwrap();
+        let mut response = srv_response(&query, &[(1, "h")]);
+        assert!(parse_srv_response(8, &response).is_err());
+        response[3] = 0x83;
+        assert_eq!(
+            parse_srv_response(7, &response).unwrap_err(),
+            "no such name"
+        );
+        response[3] = 0x80;
+        response.truncate(response.len() - 1);
+        assert!(parse_srv_response(7, &response).is_err());
+        // A pointer to itself.
+        assert!(read_name(&[0xc0, 0], 0).is_err());
+
+        let mut sd = DnsSd::new(vec!["localhost".to_string()], RecordType::A, 9100, "node");
+        let targets = sd.poll().unwrap().unwrap();
+        assert_eq!(targets[0].url, "http://127.0.0.1:9100/metrics");
+        assert_eq!(sd.poll().unwrap(), None);
+        assert_eq!("aaaa".parse(), Ok(RecordType::Aaaa));
+    }
+}
@@ -0,0 +1,203 @@
+use prometheus::proto::{LabelPair, Metric, MetricFamily};
+use prometheus::{IntCounterVec, Opts, Registry};
+use std::collections::{BTreeMap, HashMap};
+
+use crate::text_parse::labels_to_signature;
+
+/// How many refresh intervals a series may go without being updated before it
+/// is dropped from the merged snapshot.
+#[derive(Debug, Clone, Default)]
+pub struct ExpiryConfig {
+    /// Applies to every family without an entry in `per_family`. `None` keeps
+    /// series forever.
+    pub default_intervals: Option<u64>,
+    pub per_family: HashMap<String, u64>,
+}
+
+impl ExpiryConfig {
+    fn intervals_for(&self, family: &str) -> Option<u64> {
+        self.per_family
+            .get(family)
+            .copied()
+            .or(self.default_intervals)
+    }
+}
+
+#[derive(Debug)]
+struct StoredFamily {
+    // The family without any metrics; HELP and TYPE of the last update win.
+    header: MetricFamily,
+    series: BTreeMap<String, StoredSeries>,
+}
+
+#[derive(Debug)]
+struct StoredSeries {
+    metric: Metric,
+    refreshed_at: u64,
+}
+
+/// Keeps the latest sample of every series seen across updates and expires the
+/// ones that stop being refreshed, e.g. when a pushed source disappears.
+#[derive(Debug)]
+pub struct SeriesStore {
+    config: ExpiryConfig,
+    interval: u64,
+    generation: u64,
+    families: BTreeMap<String, StoredFamily>,
+    expired_series: IntCounterVec,
+}
+
+impl SeriesStore {
+    pub fn new(config: ExpiryConfig) -> Self {
+        let expired_series = IntCounterVec::new(
+            Opts::new(
+                "pmv_expired_series_total",
+                "Series removed from the merged snapshot after not being refreshed.",
+            ),
+            &["family"],
+        )
+        .expect("valid expired series counter");
+
+        SeriesStore {
+            config,
+            interval: 0,
+            generation: 0,
+            families: BTreeMap::new(),
+            expired_series,
+        }
+    }
+
+    /// Registers the store's self-metrics with `registry`.
+    pub fn register(&self, registry: &Registry) -> prometheus::Result<()> {
+        registry.register(Box::new(self.expired_series.clone()))
+    }
+
+    /// Merges `mfs` into the store, refreshing every series they contain.
+    pub fn update(&mut self, mfs: &[MetricFamily]) {
+        self.generation += 1;
+        for mf in mfs {
+            let mut header = mf.clone();
+            header.clear_metric();
+
+            let stored = self
+                .families
+                .entry(mf.get_name().to_string())
+                .or_insert_with(|| StoredFamily {
+                    header: header.clone(),
+                    series: BTreeMap::new(),
+                });
+            stored.header = header;
+
+            for m in mf.get_metric() {
+                stored.series.insert(
+                    series_key(m.get_label()),
+                    StoredSeries {
+                        metric: m.clone(),
+                        refreshed_at: self.interval,
+                    },
+                );
+            }
+        }
+    }
+
+    /// Marks the end of a refresh interval and drops every series that has
+    /// gone unrefreshed for longer than its configured number of intervals.
+    /// Returns the number of series removed.
+    pub fn tick(&mut self) -> usize {
+        self.interval += 1;
+
+        let mut removed = 0;
+        for (name, family) in self.families.iter_mut() {
+            let max_age = match self.config.intervals_for(name) {
+                Some(n) => n,
+                None => continue,
+            };
+
+            let interval = self.interval;
+            let before = family.series.len();
+            family
+                .series
+                .retain(|_, s| interval - s.refreshed_at <= max_age);
+
+            let expired = before - family.series.len();
+            if expired > 0 {
+                self.expired_series
+                    .with_label_values(&[name])
+                    .inc_by(expired as u64);
+                removed += expired;
+            }
+        }
+        self.families.retain(|_, f| !f.series.is_empty());
+        if removed > 0 {
+            self.generation += 1;
+        }
+
+        removed
+    }
+
+    /// A number that changes whenever the contents of the store do, for
+    /// callers caching renderings of the snapshot.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Returns the current contents of the store, families sorted by name.
+    pub fn snapshot(&self) -> Vec<MetricFamily> {
+        self.families
+            .values()
+            .map(|f| {
+                let mut mf = f.header.clone();
+                for s in f.series.values() {
+                    mf.mut_metric().push(s.metric.clone());
+                }
+                mf
+            })
+            .collect()
+    }
+}
+
+fn series_key(labels: &[LabelPair]) -> String {
+    labels_to_signature(labels.iter().map(|l| (l.get_name(), l.get_value())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::text_parse::TextParser;
+    use std::io::{BufReader, Cursor};
+
+    fn parse(text: &str) -> Vec<MetricFamily> {
+        let cursor = Cursor::new(text.to_string().into_bytes());
+        TextParser::new(BufReader::new(cursor))
+            .text_to_metric_families()
+            .unwrap()
+            .into_values()
+            .collect()
+    }
+
+    #[test]
+    fn test_series_expire_after_intervals() {
+        let mut config = ExpiryConfig {
+            default_intervals: Some(2),
+            ..Default::default()
+        };
+        config.per_family.insert("pinned".to_string(), 100);
+
+        let mut store = SeriesStore::new(config);
+        store.update(&parse("a{src=\"x\"} 1\na{src=\"y\"} 2\npinned 1\n"));
+
+        assert_eq!(store.tick(), 0);
+        store.update(&parse("a{src=\"x\"} 3\n"));
+        assert_eq!(store.tick(), 0);
+        assert_eq!(store.tick(), 1);
+
+        let snapshot = store.snapshot();
+        assert_eq!(snapshot.len(), 2);
+        assert_eq!(snapshot[0].get_metric().len(), 1);
+        assert_eq!(snapshot[0].get_metric()[0].get_untyped().get_value(), 3.0);
+        assert_eq!(store.expired_series.with_label_values(&["a"]).get(), 1);
+
+        assert_eq!(store.tick(), 1);
+        assert_eq!(store.snapshot().len(), 1);
+    }
+}
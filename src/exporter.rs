@@ -0,0 +1,498 @@
+//! A metrics endpoint serving series as an exporter would: those of a
+//! file, for mocking exporters in integration tests, or those scraped from
+//! upstream targets, for relaying them through a filtering sidecar.
+//!
+//! The source is read, transformed and encoded again at every request, so
+//! tests can change what the "exporter" exposes by rewriting its file, and
+//! relays pass on fresh samples. Responses are in the format the scraper's
+//! `Accept` header prefers, as far as pmv writes it.
+//!
+//! As in Prometheus, `/-/healthy` answers 200 while pmv runs and `/-/ready`
+//! once the source has been read successfully, for orchestrators' probes.
+
+use base64::Engine;
+use prometheus::proto::MetricFamily;
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{self, Cursor};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tiny_http::{Header, Method, Request, Response};
+
+use crate::format::{self, Format};
+use crate::merge::{self, ConflictPolicy};
+use crate::pipeline::{self, Transform};
+use crate::scrape::{self, ScrapeOptions, Target};
+
+/// Where the served series come from.
+#[derive(Debug, Clone)]
+pub enum Source {
+    /// A file in the given format.
+    File { path: PathBuf, format: Format },
+    /// One upstream endpoint, whose series are served as it exposes them.
+    Url { url: String, options: ScrapeOptions },
+    /// Upstream targets, scraped in parallel and merged, their series
+    /// labeled as by [`scrape::scrape_targets`]. Targets that fail are left
+    /// out, unless all do.
+    Targets {
+        targets: Vec<Target>,
+        options: ScrapeOptions,
+        workers: usize,
+    },
+}
+
+impl Source {
+    fn read(&self) -> Result<Vec<MetricFamily>, Box<dyn Error + Send + Sync>> {
+        match self {
+            Source::File { path, format } => {
+                let file = File::open(path)
+                    .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+                Ok(format::read_metric_families(*format, file)
+                    .map_err(|e| format!("{}: {}", path.display(), e))?)
+            }
+            Source::Url { url, options } => Ok(scrape::scrape_url(url, options)?.families),
+            Source::Targets {
+                targets,
+                options,
+                workers,
+            } => {
+                let mut failed = None;
+                let mut scraped = Vec::new();
+                for result in scrape::scrape_targets(targets, options, *workers) {
+                    match result.result {
+                        Ok(scrape) => {
+                            log::debug!(
+                                "{}: ~{} bytes",
+                                result.target.url,
+                                scrape.approx_byte_size()
+                            );
+                            scraped.push(scrape.families);
+                        }
+                        Err(e) => {
+                            log::error!("{}", e);
+                            failed.get_or_insert(e);
+                        }
+                    }
+                }
+                match failed {
+                    Some(e) if scraped.is_empty() => Err(e.into()),
+                    _ => {
+                        Ok(merge::merge(scraped, ConflictPolicy::Error)
+                            .map_err(|e| e.to_string())?)
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ExporterConfig {
+    pub listen: String,
+    /// Path the series are served at.
+    pub metrics_path: String,
+    pub source: Source,
+    /// Applied in order before every response.
+    pub transforms: Vec<Arc<dyn Transform>>,
+    /// Serve HTTPS with this certificate and key; needs the `tls` feature.
+    pub tls: Option<TlsFiles>,
+    /// Users and passwords allowed in with HTTP basic auth; anyone may
+    /// scrape if empty.
+    pub users: Vec<(String, String)>,
+}
+
+/// PEM files of a certificate chain and its PKCS#8 or RSA private key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TlsFiles {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+/// Reads the users of an `ExporterConfig` from `path`, one `user:password`
+/// per line; blank lines and lines starting with `#` are skipped.
+pub fn read_users(path: &Path) -> io::Result<Vec<(String, String)>> {
+    let text = fs::read_to_string(path)?;
+    let mut users = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim_end_matches('\r');
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match line.split_once(':') {
+            Some((user, password)) if !user.is_empty() => {
+                users.push((user.to_string(), password.to_string()))
+            }
+            _ => {
+                let msg = format!("line {}: expected user:password", i + 1);
+                return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+            }
+        }
+    }
+    Ok(users)
+}
+
+impl Default for ExporterConfig {
+    fn default() -> Self {
+        ExporterConfig {
+            listen: "0.0.0.0:9100".to_string(),
+            metrics_path: "/metrics".to_string(),
+            source: Source::File {
+                path: PathBuf::new(),
+                format: Format::Text,
+            },
+            transforms: Vec::new(),
+            tls: None,
+            users: Vec::new(),
+        }
+    }
+}
+
+impl ExporterConfig {
+    /// Reads and transforms the source.
+    pub fn read(&self) -> Result<Vec<MetricFamily>, Box<dyn Error + Send + Sync>> {
+        let mut mfs = self.source.read()?;
+        pipeline::apply(&self.transforms, &mut mfs)?;
+        Ok(mfs)
+    }
+}
+
+/// The format to answer a request with `Accept` header `accept`: the one
+/// with the highest quality, earlier ones winning ties. Text if none is
+/// acceptable.
+pub fn negotiate(accept: Option<&str>) -> Format {
+    let mut best = (Format::Text, 0.0);
+    for range in accept.unwrap_or("").split(',') {
+        let mut params = range.split(';');
+        let media_type = params.next().unwrap_or("").trim();
+        let format = match media_type {
+            "*/*" | "text/*" => Format::Text,
+            _ => match Format::from_content_type(media_type) {
+                Some(format) => format,
+                None => continue,
+            },
+        };
+        let q = params
+            .filter_map(|p| p.trim().strip_prefix("q="))
+            .find_map(|q| q.parse::<f64>().ok())
+            .unwrap_or(1.0);
+        if q > best.1 {
+            best = (format, q);
+        }
+    }
+    best.0
+}
+
+/// Checks that a file source can be served, then listens on
+/// `config.listen` and handles requests until the process exits. Upstream
+/// targets are not checked, since they may come up later.
+pub fn serve(config: ExporterConfig) -> Result<(), Box<dyn Error + Send + Sync>> {
+    if let Source::File { .. } = config.source {
+        config.read()?;
+    }
+    let server = match &config.tls {
+        Some(tls) => https_server(&config.listen, tls)?,
+        None => tiny_http::Server::http(&config.listen)?,
+    };
+    let scheme = if config.tls.is_some() {
+        "https"
+    } else {
+        "http"
+    };
+    log::info!(
+        "serving on {}://{}{}",
+        scheme,
+        config.listen,
+        config.metrics_path
+    );
+    run(&config, server);
+    Ok(())
+}
+
+#[cfg(feature = "tls")]
+fn https_server(
+    listen: &str,
+    tls: &TlsFiles,
+) -> Result<tiny_http::Server, Box<dyn Error + Send + Sync>> {
+    let read = |path: &Path| {
+        fs::read(path).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))
+    };
+    let ssl = tiny_http::SslConfig {
+        certificate: read(&tls.cert)?,
+        private_key: read(&tls.key)?,
+    };
+    tiny_http::Server::https(listen, ssl)
+        .map_err(|e| format!("cannot serve HTTPS with {}: {}", tls.cert.display(), e).into())
+}
+
+#[cfg(not(feature = "tls"))]
+fn https_server(
+    _listen: &str,
+    _tls: &TlsFiles,
+) -> Result<tiny_http::Server, Box<dyn Error + Send + Sync>> {
+    Err("pmv was built without the tls feature".into())
+}
+
+/// Whether the request's basic auth credentials are those of a user.
+fn authorized(users: &[(String, String)], request: &Request) -> bool {
+    if users.is_empty() {
+        return true;
+    }
+    let credentials = request
+        .headers()
+        .iter()
+        .find(|h| h.field.equiv("Authorization"))
+        .and_then(|h| h.value.as_str().strip_prefix("Basic "))
+        .and_then(|b64| {
+            base64::engine::general_purpose::STANDARD
+                .decode(b64.trim())
+                .ok()
+        })
+        .and_then(|pair| String::from_utf8(pair).ok());
+    let Some((user, password)) = credentials.as_deref().and_then(|c| c.split_once(':')) else {
+        return false;
+    };
+    // Every user is compared, so timing does not tell which exist.
+    users.iter().fold(false, |found, (u, p)| {
+        found | (constant_time_eq(u, user) & constant_time_eq(p, password))
+    })
+}
+
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |diff, (x, y)| diff | (x ^ y))
+            == 0
+}
+
+fn run(config: &ExporterConfig, server: tiny_http::Server) {
+    // Whether a read of the source has succeeded yet.
+    let mut ready = false;
+    for request in server.incoming_requests() {
+        let response = respond(config, &mut ready, &request);
+        if let Err(e) = request.respond(response) {
+            log::warn!("failed to send response: {}", e);
+        }
+    }
+}
+
+fn respond(
+    config: &ExporterConfig,
+    ready: &mut bool,
+    request: &Request,
+) -> Response<Cursor<Vec<u8>>> {
+    let path = request.url().split('?').next().unwrap_or("");
+    // Probes reveal nothing, so orchestrators need no credentials.
+    match path {
+        "/-/healthy" => return Response::from_string("pmv is Healthy.\n"),
+        "/-/ready" => {
+            // Until a scrape succeeds, probes read the source themselves:
+            // orchestrators may hold scrapes back until we are ready.
+            if !*ready {
+                *ready = config.read().is_ok();
+            }
+            return if *ready {
+                Response::from_string("pmv is Ready.\n")
+            } else {
+                Response::from_string("pmv is not ready.\n").with_status_code(503)
+            };
+        }
+        _ => {}
+    }
+    if !authorized(&config.users, request) {
+        return Response::from_string("unauthorized\n")
+            .with_status_code(401)
+            .with_header(
+                Header::from_bytes("WWW-Authenticate", "Basic realm=\"pmv\"")
+                    .expect("valid header"),
+            );
+    }
+    if path != config.metrics_path {
+        return Response::from_string("not found\n").with_status_code(404);
+    }
+    if !matches!(request.method(), Method::Get | Method::Head) {
+        return Response::from_string("method not allowed\n").with_status_code(405);
+    }
+
+    let accept = request
+        .headers()
+        .iter()
+        .find(|h| h.field.equiv("Accept"))
+        .map(|h| h.value.as_str());
+    let format = negotiate(accept);
+    let mut body = Vec::new();
+    let encoded = config.read().and_then(|mfs| {
+        format::write_metric_families(format, &mut body, &mfs).map_err(|e| e.to_string().into())
+    });
+    *ready |= encoded.is_ok();
+    match encoded {
+        Ok(()) => Response::from_data(body).with_header(
+            Header::from_bytes("Content-Type", format.content_type()).expect("valid header"),
+        ),
+        Err(e) => {
+            log::error!("{}", e);
+            let status = match config.source {
+                Source::File { .. } => 500,
+                // The upstream failed, not the relay.
+                Source::Url { .. } | Source::Targets { .. } => 502,
+            };
+            Response::from_string(format!("{}\n", e)).with_status_code(status)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pipeline;
+    use std::fs;
+    use std::io::Read;
+    use std::thread;
+
+    // Serves `config` on a free port and returns its base URL.
+    fn start(config: ExporterConfig) -> String {
+        let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+        let base = format!("http://{}", server.server_addr().to_ip().unwrap());
+        thread::spawn(move || run(&config, server));
+        base
+    }
+
+    fn get(base: &str, path: &str, accept: &str) -> (u16, String, String) {
+        let response = match ureq::get(&format!("{}{}", base, path))
+            .set("Accept", accept)
+            .call()
+        {
+            Ok(response) | Err(ureq::Error::Status(_, response)) => response,
+            Err(e) => panic!("{}", e),
+        };
+        let (status, content_type) = (response.status(), response.content_type().to_string());
+        let mut body = String::new();
+        response.into_reader().read_to_string(&mut body).unwrap();
+        (status, content_type, body)
+    }
+
+    #[test]
+    fn test_exporter() {
+        assert_eq!(negotiate(None), Format::Text);
+        assert_eq!(negotiate(Some(crate::scrape::ACCEPT)), Format::Protobuf);
+        assert_eq!(
+            negotiate(Some(
+                "application/openmetrics-text;version=1.0.0,text/plain;q=0.5"
+            )),
+            Format::OpenMetrics
+        );
+        assert_eq!(negotiate(Some("application/xml")), Format::Text);
+
+        let path = std::env::temp_dir().join(format!("pmv-exporter-{}.prom", std::process::id()));
+        fs::write(&path, "up{job=\"a\"} 1\nup{job=\"b\"} 0\n").unwrap();
+        let config = ExporterConfig {
+            source: Source::File {
+                path: path.clone(),
+                format: Format::Text,
+            },
+            transforms: pipeline::transforms_from_yaml("- filter: {match: ['up{job=\"a\"}']}")
+                .unwrap(),
+            ..ExporterConfig::default()
+        };
+        let base = start(config);
+
+        let (status, content_type, body) = get(&base, "/metrics", "text/plain");
+        assert_eq!((status, content_type.as_str()), (200, "text/plain"));
+        assert_eq!(body, "# TYPE up untyped\nup{job=\"a\"} 1\n");
+
+        // Rewrites show at the next request.
+        fs::write(&path, "up{job=\"a\"} 2\n").unwrap();
+        let (_, content_type, body) = get(&base, "/metrics", "application/openmetrics-text");
+        assert_eq!(content_type, "application/openmetrics-text");
+        assert!(
+            body.contains("up{job=\"a\"} 2") && body.ends_with("# EOF\n"),
+            "{}",
+            body
+        );
+
+        fs::write(&path, "up{").unwrap();
+        assert_eq!(get(&base, "/metrics", "").0, 500);
+        assert_eq!(get(&base, "/", "").0, 404);
+
+        // A relay of the endpoint above labels what it scrapes.
+        fs::write(&path, "up{job=\"a\"} 3\n").unwrap();
+        let target = Target {
+            url: format!("{}/metrics", base),
+            job: "mock".to_string(),
+            ..Target::default()
+        };
+        let relay = start(ExporterConfig {
+            source: Source::Targets {
+                targets: vec![target.clone()],
+                options: ScrapeOptions::default(),
+                workers: 1,
+            },
+            ..ExporterConfig::default()
+        });
+        let (status, _, body) = get(&relay, "/metrics", "text/plain");
+        let instance = target.instance();
+        assert_eq!(status, 200);
+        assert_eq!(
+            body,
+            format!(
+                "# TYPE up untyped\nup{{job=\"mock\",instance=\"{}\"}} 3\n",
+                instance
+            )
+        );
+        // Nothing listens on port 1.
+        let relay = start(ExporterConfig {
+            source: Source::Url {
+                url: "http://127.0.0.1:1/metrics".to_string(),
+                options: ScrapeOptions::default(),
+            },
+            ..ExporterConfig::default()
+        });
+        assert_eq!(get(&relay, "/metrics", "").0, 502);
+        assert_eq!(get(&relay, "/-/healthy", "").0, 200);
+        assert_eq!(get(&relay, "/-/ready", "").0, 503);
+
+        // With users, only they may scrape.
+        let users = path.with_extension("users");
+        fs::write(&users, "# scrapers\nprometheus:s3cr:t\n\n").unwrap();
+        let protected = start(ExporterConfig {
+            source: Source::File {
+                path: path.clone(),
+                format: Format::Text,
+            },
+            users: read_users(&users).unwrap(),
+            ..ExporterConfig::default()
+        });
+        let status = |auth: &str| {
+            let request = ureq::get(&format!("{}/metrics", protected));
+            match request.set("Authorization", auth).call() {
+                Ok(response) => response.status(),
+                Err(ureq::Error::Status(code, _)) => code,
+                Err(e) => panic!("{}", e),
+            }
+        };
+        assert_eq!(status("Basic cHJvbWV0aGV1czpzM2NyOnQ="), 200);
+        assert_eq!(status("Basic cHJvbWV0aGV1czpzM2Ny"), 401);
+        assert_eq!(status("Bearer s3cr:t"), 401);
+        // Probes need no credentials, and read the source if need be.
+        assert_eq!(get(&protected, "/-/ready", "").0, 200);
+        fs::write(&users, "nopassword\n").unwrap();
+        assert!(read_users(&users).is_err());
+        fs::remove_file(&users).unwrap();
+
+        let tls = TlsFiles {
+            cert: path.with_extension("missing"),
+            key: path.with_extension("missing"),
+        };
+        let config = ExporterConfig {
+            listen: "127.0.0.1:0".to_string(),
+            source: Source::Url {
+                url: String::new(),
+                options: ScrapeOptions::default(),
+            },
+            tls: Some(tls),
+            ..ExporterConfig::default()
+        };
+        assert!(serve(config).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
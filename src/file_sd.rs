@@ -0,0 +1,277 @@
+//! Scrape targets from the files of Prometheus file-based service
+//! discovery, `file_sd_configs`:
+//!
+//! ```yaml
+//! - targets: ["node-1:9100", "node-2:9100"]
+//!   labels:
+//!     env: prod
+//!     __metrics_path__: /node/metrics
+//! ```
+//!
+//! Files ending in `.json` are read as JSON, others as YAML. As in
+//! Prometheus, `__metrics_path__`, `__scheme__` and `__param_<name>` labels
+//! shape the URL, and other labels starting with `__` are dropped.
+
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::grammar;
+use crate::scrape::Target;
+use crate::watch::FileWatcher;
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Group {
+    targets: Vec<String>,
+    #[serde(default)]
+    labels: BTreeMap<String, String>,
+}
+
+#[derive(Debug)]
+pub enum FileSdError {
+    Pattern(glob::PatternError),
+    Io(PathBuf, io::Error),
+    Json(PathBuf, serde_json::Error),
+    Yaml(PathBuf, serde_yaml::Error),
+    Invalid(PathBuf, String),
+}
+
+impl fmt::Display for FileSdError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FileSdError::Pattern(e) => write!(f, "invalid file pattern: {}", e),
+            FileSdError::Io(path, e) => write!(f, "{}: {}", path.display(), e),
+            FileSdError::Json(path, e) => {
+                write!(f, "{}: invalid targets file: {}", path.display(), e)
+            }
+            FileSdError::Yaml(path, e) => {
+                write!(f, "{}: invalid targets file: {}", path.display(), e)
+            }
+            FileSdError::Invalid(path, msg) => {
+                write!(f, "{}: invalid targets file: {}", path.display(), msg)
+            }
+        }
+    }
+}
+
+impl Error for FileSdError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            FileSdError::Pattern(e) => Some(e),
+            FileSdError::Io(_, e) => Some(e),
+            FileSdError::Json(_, e) => Some(e),
+            FileSdError::Yaml(_, e) => Some(e),
+            FileSdError::Invalid(..) => None,
+        }
+    }
+}
+
+/// Reads the targets of one file. Their job is the `job` label of their
+/// group, or else `job`.
+pub fn read_targets(path: &Path, job: &str) -> Result<Vec<Target>, FileSdError> {
+    let text = fs::read_to_string(path).map_err(|e| FileSdError::Io(path.to_path_buf(), e))?;
+    let groups: Vec<Group> = if path.extension().is_some_and(|ext| ext == "json") {
+        serde_json::from_str(&text).map_err(|e| FileSdError::Json(path.to_path_buf(), e))?
+    } else {
+        serde_yaml::from_str(&text).map_err(|e| FileSdError::Yaml(path.to_path_buf(), e))?
+    };
+
+    let mut targets = Vec::new();
+    for group in groups {
+        targets.extend(
+            group_targets(group, job)
+                .map_err(|msg| FileSdError::Invalid(path.to_path_buf(), msg))?,
+        );
+    }
+    Ok(targets)
+}
+
+fn group_targets(group: Group, job: &str) -> Result<Vec<Target>, String> {
+    let mut labels = group.labels;
+    let scheme = labels
+        .remove("__scheme__")
+        .unwrap_or_else(|| "http".to_string());
+    if scheme != "http" {
+        return Err(format!("unsupported scheme {:?}", scheme));
+    }
+    let path = labels
+        .remove("__metrics_path__")
+        .unwrap_or_else(|| "/metrics".to_string());
+    let params: Vec<_> = labels
+        .iter()
+        .filter_map(|(name, value)| Some(format!("{}={}", name.strip_prefix("__param_")?, value)))
+        .collect();
+    let job = labels.remove("job").unwrap_or_else(|| job.to_string());
+    labels.retain(|name, _| !name.starts_with("__"));
+    if let Some(name) = labels.keys().find(|name| !is_valid_label_name(name)) {
+        return Err(format!("invalid label name {:?}", name));
+    }
+
+    group
+        .targets
+        .into_iter()
+        .map(|address| {
+            if address.is_empty() || address.contains('/') {
+                return Err(format!("invalid target address {:?}", address));
+            }
+            let mut url = format!("{}://{}{}", scheme, address, path);
+            if !params.is_empty() {
+                url.push(if url.contains('?') { '&' } else { '?' });
+                url.push_str(&params.join("&"));
+            }
+            Ok(Target {
+                url,
+                job: job.clone(),
+                labels: labels.clone().into_iter().collect(),
+            })
+        })
+        .collect()
+}
+
+fn is_valid_label_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(grammar::is_valid_label_name_start)
+        && chars.all(grammar::is_valid_label_name_continuation)
+}
+
+/// Targets from a set of files given by glob patterns, as in the `files`
+/// of a `file_sd_configs` entry, which are read again whenever one is
+/// added, removed or rewritten.
+#[derive(Debug)]
+pub struct FileSd {
+    patterns: Vec<String>,
+    job: String,
+    watchers: Vec<FileWatcher>,
+}
+
+impl FileSd {
+    /// Targets from the files matching `patterns`, with `job` as the job of
+    /// those whose group has no `job` label.
+    pub fn new(patterns: Vec<String>, job: impl Into<String>) -> Self {
+        FileSd {
+            patterns,
+            job: job.into(),
+            watchers: Vec::new(),
+        }
+    }
+
+    /// The targets of all files if any changed since the last call, which
+    /// the first call counts as. Patterns matching no file give no targets.
+    /// After an error the caller should keep the targets it has; the next
+    /// call reads all files again.
+    pub fn poll(&mut self) -> Result<Option<Vec<Target>>, FileSdError> {
+        let result = self.read_if_changed();
+        if result.is_err() {
+            self.watchers.clear();
+        }
+        result
+    }
+
+    fn read_if_changed(&mut self) -> Result<Option<Vec<Target>>, FileSdError> {
+        let mut paths = Vec::new();
+        for pattern in &self.patterns {
+            for path in glob::glob(pattern).map_err(FileSdError::Pattern)? {
+                paths.push(path.map_err(|e| {
+                    let path = e.path().to_path_buf();
+                    FileSdError::Io(path, e.into())
+                })?);
+            }
+        }
+        paths.sort();
+        paths.dedup();
+
+        let mut changed = paths.len() != self.watchers.len()
+            || paths.iter().zip(&self.watchers).any(|(p, w)| p != w.path());
+        if changed {
+            self.watchers = paths.into_iter().map(FileWatcher::new).collect();
+        }
+        for watcher in &mut self.watchers {
+            let path = watcher.path().to_path_buf();
+            changed |= watcher.changed().map_err(|e| FileSdError::Io(path, e))?;
+        }
+        if !changed {
+            return Ok(None);
+        }
+
+        let mut targets = Vec::new();
+        for watcher in &self.watchers {
+            targets.extend(read_targets(watcher.path(), &self.job)?);
+        }
+        Ok(Some(targets))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_sd() {
+        let dir = std::env::temp_dir().join(format!("pmv-file-sd-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("a.yml"),
+            r#"
+- targets: ["node-1:9100", "node-2"]
+  labels:
+    env: prod
+    job: node
+    __metrics_path__: /node/metrics
+    __param_module: cpu
+    __meta_zone: x
+"#,
+        )
+        .unwrap();
+        let mut sd = FileSd::new(vec![format!("{}/*.yml", dir.display())], "files");
+
+        let targets = sd.poll().unwrap().unwrap();
+        let expected = |url: &str| Target {
+            url: url.to_string(),
+            job: "node".to_string(),
+            labels: vec![("env".to_string(), "prod".to_string())],
+        };
+        assert_eq!(
+            targets,
+            [
+                expected("http://node-1:9100/node/metrics?module=cpu"),
+                expected("http://node-2/node/metrics?module=cpu"),
+            ]
+        );
+        assert_eq!(targets[1].instance(), "node-2:80");
+        assert_eq!(sd.poll().unwrap(), None);
+
+        // A new file is picked up; JSON is read by its extension, but only
+        // files matching the pattern count.
+        fs::write(dir.join("b.yml"), r#"[{"targets": ["db:9187"]}]"#).unwrap();
+        fs::write(dir.join("c.json"), "[]").unwrap();
+        let targets = sd.poll().unwrap().unwrap();
+        assert_eq!(targets.len(), 3);
+        assert_eq!(targets[2].job, "files");
+
+        // A broken file keeps failing until it is fixed.
+        fs::write(dir.join("b.yml"), "- targets: [a/b]\n").unwrap();
+        let err = sd.poll().unwrap_err();
+        assert!(
+            err.to_string().ends_with("invalid target address \"a/b\""),
+            "{}",
+            err
+        );
+        assert!(sd.poll().is_err());
+        fs::remove_file(dir.join("b.yml")).unwrap();
+        assert_eq!(sd.poll().unwrap().unwrap().len(), 2);
+
+        assert!(read_targets(&dir.join("c.json"), "").unwrap().is_empty());
+        fs::write(dir.join("c.json"), r#"[{"targets": [], "extra": 1}]"#).unwrap();
+        assert!(matches!(
+            read_targets(&dir.join("c.json"), ""),
+            Err(FileSdError::Json(..))
+        ));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,77 @@
+use xxhash_rust::xxh64::Xxh64;
+
+const SEPARATOR: u8 = 0xff;
+
+/// The hash Prometheus identifies the series `name{labels}` by, as
+/// `labels.Hash()` computes it: the 64-bit xxHash of every label, sorted by
+/// name with `__name__` among them, written as name and value each followed
+/// by a `0xff` byte. Label order does not matter.
+pub fn fingerprint(name: &str, labels: &[(String, String)]) -> u64 {
+    let mut pairs: Vec<(&str, &str)> = labels
+        .iter()
+        .map(|(n, v)| (n.as_str(), v.as_str()))
+        .chain((!name.is_empty()).then_some(("__name__", name)))
+        .collect();
+    pairs.sort_unstable();
+
+    let mut hasher = Xxh64::new(0);
+    for (n, v) in pairs {
+        hasher.update(n.as_bytes());
+        hasher.update(&[SEPARATOR]);
+        hasher.update(v.as_bytes());
+        hasher.update(&[SEPARATOR]);
+    }
+    hasher.digest()
+}
+
+/// The shard of `value` among `modulus`, as Prometheus' `hashmod` relabel
+/// action computes it: the last 8 bytes of its MD5, big-endian, modulo
+/// `modulus`.
+pub fn hashmod(value: &str, modulus: u64) -> u64 {
+    let digest = md5::compute(value.as_bytes());
+    let mut tail = [0; 8];
+    tail.copy_from_slice(&digest.0[8..]);
+    u64::from_be_bytes(tail) % modulus
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fingerprint() {
+        // The xxHash of no input.
+        assert_eq!(fingerprint("", &[]), 0xef46_db37_51d8_e999);
+
+        let labels = |pairs: &[(&str, &str)]| -> Vec<(String, String)> {
+            pairs
+                .iter()
+                .map(|(n, v)| (n.to_string(), v.to_string()))
+                .collect()
+        };
+        let up = fingerprint("up", &labels(&[("job", "a"), ("env", "prod")]));
+        assert_eq!(
+            up,
+            fingerprint("up", &labels(&[("env", "prod"), ("job", "a")]))
+        );
+        assert_eq!(
+            up,
+            fingerprint(
+                "",
+                &labels(&[("__name__", "up"), ("env", "prod"), ("job", "a")])
+            )
+        );
+        assert_ne!(
+            up,
+            fingerprint("up", &labels(&[("job", "b"), ("env", "prod")]))
+        );
+        // Separators keep `a="bc"` apart from `ab="c"`.
+        assert_ne!(
+            fingerprint("x", &labels(&[("a", "bc")])),
+            fingerprint("x", &labels(&[("ab", "c")]))
+        );
+
+        assert_eq!(hashmod("foo", 1000), 696);
+        assert!((0..100).all(|i| hashmod(&i.to_string(), 3) < 3));
+    }
+}
@@ -0,0 +1,228 @@
+use prometheus::proto::{Metric, MetricFamily, MetricType};
+
+use crate::fingerprint;
+use crate::text_encode::{escape_label_value, format_float};
+
+/// A single sample line of a family: summaries and histograms contribute one
+/// per quantile or bucket plus `_sum` and `_count`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FlatSample {
+    pub family: String,
+    pub metric_type: MetricType,
+    /// Sample name including any `_bucket`, `_sum` or `_count` suffix.
+    pub name: String,
+    /// Labels in exposition order, `le` or `quantile` last.
+    pub labels: Vec<(String, String)>,
+    pub value: f64,
+    pub timestamp_ms: Option<i64>,
+}
+
+impl FlatSample {
+    /// Renders name and labels as in the text format, e.g.
+    /// `latency_bucket{path="/",le="0.5"}`.
+    pub fn series(&self) -> String {
+        render_series(&self.name, &self.labels)
+    }
+
+    /// Like `series`, but with labels sorted, for matching the same series
+    /// across inputs that order labels differently.
+    pub fn series_key(&self) -> String {
+        let mut labels = self.labels.clone();
+        labels.sort_unstable();
+        render_series(&self.name, &labels)
+    }
+
+    /// The hash Prometheus identifies the series by; see
+    /// [`fingerprint::fingerprint`].
+    pub fn fingerprint(&self) -> u64 {
+        fingerprint::fingerprint(&self.name, &self.labels)
+    }
+}
+
+pub(crate) fn render_series(name: &str, labels: &[(String, String)]) -> String {
+    let mut out = name.to_string();
+    if !labels.is_empty() {
+        let pairs: Vec<String> = labels
+            .iter()
+            .map(|(n, v)| format!("{}=\"{}\"", n, escape_label_value(v)))
+            .collect();
+        out.push('{');
+        out.push_str(&pairs.join(","));
+        out.push('}');
+    }
+    out
+}
+
+/// Splits a string rendered by `FlatSample::series` or `series_key` back
+/// into the sample name and labels. Returns `None` if it is malformed.
+pub fn parse_series(s: &str) -> Option<(String, Vec<(String, String)>)> {
+    let (name, rest) = match s.find('{') {
+        Some(idx) => (&s[..idx], s[idx + 1..].strip_suffix('}')?),
+        None => (s, ""),
+    };
+    if name.is_empty() {
+        return None;
+    }
+
+    let mut labels = Vec::new();
+    let mut chars = rest.chars();
+    loop {
+        let label: String = chars.by_ref().take_while(|&c| c != '=').collect();
+        if label.is_empty() {
+            break;
+        }
+        if chars.next() != Some('"') {
+            return None;
+        }
+        let mut value = String::new();
+        loop {
+            match chars.next()? {
+                '"' => break,
+                '\\' => match chars.next()? {
+                    'n' => value.push('\n'),
+                    c => value.push(c),
+                },
+                c => value.push(c),
+            }
+        }
+        labels.push((label, value));
+        match chars.next() {
+            Some(',') => {}
+            None => break,
+            Some(_) => return None,
+        }
+    }
+    Some((name.to_string(), labels))
+}
+
+pub fn flatten(mfs: &[MetricFamily]) -> Vec<FlatSample> {
+    mfs.iter().flat_map(flatten_family).collect()
+}
+
+/// Returns the samples of `mf` in the order the text encoder writes them,
+/// including the implicit `+Inf` bucket of histograms.
+pub fn flatten_family(mf: &MetricFamily) -> Vec<FlatSample> {
+    let family = mf.get_name();
+    let metric_type = mf.get_field_type();
+    let mut out = Vec::new();
+
+    let mut push = |m: &Metric, suffix: &str, extra: Option<(&str, String)>, value: f64| {
+        let mut labels: Vec<(String, String)> = m
+            .get_label()
+            .iter()
+            .map(|l| (l.get_name().to_string(), l.get_value().to_string()))
+            .collect();
+        if let Some((name, value)) = extra {
+            labels.push((name.to_string(), value));
+        }
+
+        out.push(FlatSample {
+            family: family.to_string(),
+            metric_type,
+            name: format!("{}{}", family, suffix),
+            labels,
+            value,
+            timestamp_ms: m.has_timestamp_ms().then(|| m.get_timestamp_ms()),
+        });
+    };
+
+    for m in mf.get_metric() {
+        match metric_type {
+            MetricType::COUNTER => push(m, "", None, m.get_counter().get_value()),
+            MetricType::GAUGE => push(m, "", None, m.get_gauge().get_value()),
+            MetricType::UNTYPED => push(m, "", None, m.get_untyped().get_value()),
+            MetricType::SUMMARY => {
+                let s = m.get_summary();
+                for q in s.get_quantile() {
+                    let quantile = format_float(q.get_quantile());
+                    push(m, "", Some(("quantile", quantile)), q.get_value());
+                }
+                push(m, "_sum", None, s.get_sample_sum());
+                push(m, "_count", None, s.get_sample_count() as f64);
+            }
+            MetricType::HISTOGRAM => {
+                let h = m.get_histogram();
+                let mut inf_seen = false;
+                for b in h.get_bucket() {
+                    let upper_bound = format_float(b.get_upper_bound());
+                    push(
+                        m,
+                        "_bucket",
+                        Some(("le", upper_bound)),
+                        b.get_cumulative_count() as f64,
+                    );
+                    if b.get_upper_bound() == f64::INFINITY {
+                        inf_seen = true;
+                    }
+                }
+                if !inf_seen {
+                    push(
+                        m,
+                        "_bucket",
+                        Some(("le", "+Inf".to_string())),
+                        h.get_sample_count() as f64,
+                    );
+                }
+                push(m, "_sum", None, h.get_sample_sum());
+                push(m, "_count", None, h.get_sample_count() as f64);
+            }
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::{read_metric_families, Format};
+
+    #[test]
+    fn test_flatten() {
+        let mfs = read_metric_families(
+            Format::Text,
+            r#"# TYPE latency histogram
+latency_bucket{path="/",le="0.5"} 1
+latency_sum{path="/"} 0.25
+latency_count{path="/"} 2
+up 1 1700000000000
+"#
+            .as_bytes(),
+        )
+        .unwrap();
+
+        let samples = flatten(&mfs);
+        let rendered: Vec<_> = samples
+            .iter()
+            .map(|s| format!("{} {}", s.series(), s.value))
+            .collect();
+        assert_eq!(
+            rendered,
+            vec![
+                r#"latency_bucket{path="/",le="0.5"} 1"#,
+                r#"latency_bucket{path="/",le="+Inf"} 2"#,
+                r#"latency_sum{path="/"} 0.25"#,
+                r#"latency_count{path="/"} 2"#,
+                "up 1",
+            ]
+        );
+        assert_eq!(samples[0].family, "latency");
+        assert_eq!(samples[4].timestamp_ms, Some(1700000000000));
+
+        for s in &samples {
+            let (name, labels) = parse_series(&s.series()).unwrap();
+            assert_eq!((&name, &labels), (&s.name, &s.labels));
+        }
+        assert_eq!(
+            parse_series(r#"x{a="q\"\\\n",b=""}"#),
+            Some((
+                "x".to_string(),
+                vec![
+                    ("a".to_string(), "q\"\\\n".to_string()),
+                    ("b".to_string(), String::new())
+                ]
+            ))
+        );
+        assert_eq!(parse_series(r#"x{a="1""#), None);
+    }
+}
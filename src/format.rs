@@ -0,0 +1,320 @@
+use prometheus::proto::MetricFamily;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::io::{BufReader, Read, Write};
+use std::str::FromStr;
+
+use crate::json_format;
+use crate::model;
+use crate::openmetrics;
+use crate::protobuf_format;
+use crate::text_encode;
+use crate::text_parse::{ParseStats, ParserOptions, TextParser};
+use crate::vm_import;
+
+/// The exposition formats pmv can read and write.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Text,
+    OpenMetrics,
+    Protobuf,
+    Json,
+    /// VictoriaMetrics' `/api/v1/import` JSON lines.
+    VmImport,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "text" | "prometheus" => Ok(Format::Text),
+            "openmetrics" | "om" => Ok(Format::OpenMetrics),
+            "protobuf" | "proto" => Ok(Format::Protobuf),
+            "json" => Ok(Format::Json),
+            "vm-import" | "victoriametrics" => Ok(Format::VmImport),
+            _ => Err(format!(
+                "unknown format {:?}, expected text, openmetrics, protobuf, json or vm-import",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Format::Text => "text",
+            Format::OpenMetrics => "openmetrics",
+            Format::Protobuf => "protobuf",
+            Format::Json => "json",
+            Format::VmImport => "vm-import",
+        };
+        f.write_str(name)
+    }
+}
+
+impl Format {
+    /// Conventional file extension, without the dot.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::Text => "prom",
+            Format::OpenMetrics => "om",
+            Format::Protobuf => "pb",
+            Format::Json => "json",
+            Format::VmImport => "jsonl",
+        }
+    }
+
+    /// The `Content-Type` to serve the format with.
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::Text => "text/plain; version=0.0.4; charset=utf-8",
+            Format::OpenMetrics => openmetrics::OPENMETRICS_FORMAT,
+            Format::Protobuf => protobuf_format::PROTOBUF_FORMAT,
+            Format::Json => "application/json",
+            Format::VmImport => "application/stream+json",
+        }
+    }
+
+    /// The format served with a `Content-Type` such as
+    /// `text/plain; version=0.0.4`, if pmv reads it.
+    pub fn from_content_type(content_type: &str) -> Option<Format> {
+        let media_type = content_type.split(';').next()?.trim().to_ascii_lowercase();
+        match media_type.as_str() {
+            "text/plain" => Some(Format::Text),
+            "application/openmetrics-text" => Some(Format::OpenMetrics),
+            "application/vnd.google.protobuf" => Some(Format::Protobuf),
+            "application/json" => Some(Format::Json),
+            "application/stream+json" => Some(Format::VmImport),
+            _ => None,
+        }
+    }
+}
+
+/// Reads every metric family from `reader`. Families from the text formats
+/// come back sorted by name; the others keep their input order.
+pub fn read_metric_families<R: Read>(
+    format: Format,
+    reader: R,
+) -> Result<Vec<MetricFamily>, Box<dyn Error>> {
+    read_metric_families_with(format, reader, ParserOptions::default()).map(|(mfs, _)| mfs)
+}
+
+/// Like [`read_metric_families`], handling histogram and summary counts
+/// that do not fit a u64 and repeated samples per `options`. Only the text
+/// formats are parsed with them; the stats of the others are always empty.
+pub fn read_metric_families_with<R: Read>(
+    format: Format,
+    reader: R,
+    options: ParserOptions,
+) -> Result<(Vec<MetricFamily>, ParseStats), Box<dyn Error>> {
+    match format {
+        Format::Text | Format::OpenMetrics => {
+            let mut parser = TextParser::new(BufReader::new(reader))
+                .openmetrics(format == Format::OpenMetrics)
+                .options(options);
+            let mfs = parser.text_to_metric_families()?;
+            Ok((sort_by_name(mfs), parser.stats()))
+        }
+        Format::Protobuf => {
+            let mut reader = BufReader::new(reader);
+            let mfs = protobuf_format::read_metric_families(&mut reader)?;
+            Ok((mfs, ParseStats::default()))
+        }
+        Format::Json => {
+            let mfs = json_format::read_json(BufReader::new(reader))?;
+            Ok((mfs, ParseStats::default()))
+        }
+        Format::VmImport => {
+            let mfs = vm_import::read_vm_import(BufReader::new(reader))?;
+            Ok((mfs, ParseStats::default()))
+        }
+    }
+}
+
+pub fn write_metric_families<W: Write>(
+    format: Format,
+    writer: &mut W,
+    mfs: &[MetricFamily],
+) -> Result<(), Box<dyn Error>> {
+    match format {
+        Format::Text => text_encode::metric_families_to_text(writer, mfs)?,
+        Format::OpenMetrics => openmetrics::metric_families_to_openmetrics(writer, mfs)?,
+        Format::Protobuf => protobuf_format::write_metric_families(writer, mfs)?,
+        Format::Json => json_format::write_json(writer, mfs)?,
+        // Every sample needs a timestamp; those without one are taken now.
+        Format::VmImport => {
+            let now_ms = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|d| d.as_millis() as i64)
+                .unwrap_or(0);
+            vm_import::write_vm_import(writer, mfs, now_ms)?
+        }
+    }
+    Ok(())
+}
+
+/// Like [`read_metric_families`], returning pmv's own [`model`] types.
+/// Units, created timestamps and exemplars are not read yet, even from
+/// OpenMetrics.
+pub fn read_families<R: Read>(
+    format: Format,
+    reader: R,
+) -> Result<model::Families, Box<dyn Error>> {
+    let mfs = read_metric_families(format, reader)?;
+    Ok(mfs.iter().map(model::MetricFamily::from).collect())
+}
+
+/// Like [`write_metric_families`], taking pmv's own [`model`] types. Units,
+/// created timestamps and exemplars are not written yet.
+pub fn write_families<W: Write>(
+    format: Format,
+    writer: &mut W,
+    families: &[model::MetricFamily],
+) -> Result<(), Box<dyn Error>> {
+    let mfs: Vec<MetricFamily> = families.iter().map(MetricFamily::from).collect();
+    write_metric_families(format, writer, &mfs)
+}
+
+/// Turns the parser's map into a list ordered by family name.
+pub fn sort_by_name(mfs: HashMap<String, MetricFamily>) -> Vec<MetricFamily> {
+    let mut sorted: Vec<_> = mfs.into_values().collect();
+    sorted.sort_by(|a, b| a.get_name().cmp(b.get_name()));
+    sorted
+}
+
+/// Sorts families by name and, within each family, labels by name and
+/// series by their label sets. Buckets and quantiles keep their order.
+pub fn sort_series(mfs: &mut [MetricFamily]) {
+    mfs.sort_by(|a, b| a.get_name().cmp(b.get_name()));
+
+    for mf in mfs {
+        for m in mf.mut_metric().iter_mut() {
+            m.mut_label().sort_by(|a, b| a.get_name().cmp(b.get_name()));
+        }
+        mf.mut_metric().sort_by(|a, b| {
+            let a = a.get_label().iter().map(|l| (l.get_name(), l.get_value()));
+            let b = b.get_label().iter().map(|l| (l.get_name(), l.get_value()));
+            a.cmp(b)
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_convert_between_all_formats() {
+        let text = "# HELP up Target up.\n# TYPE up gauge\nup{job=\"a\"} 1\n";
+        let mfs = read_metric_families(Format::Text, text.as_bytes()).unwrap();
+
+        for format in [
+            Format::Text,
+            Format::OpenMetrics,
+            Format::Protobuf,
+            Format::Json,
+        ] {
+            let mut buf = Vec::new();
+            write_metric_families(format, &mut buf, &mfs).unwrap();
+            let decoded = read_metric_families(format, buf.as_slice()).unwrap();
+            assert_eq!(decoded, mfs, "{}", format);
+        }
+
+        assert_eq!("OM".parse::<Format>(), Ok(Format::OpenMetrics));
+        assert!("yaml".parse::<Format>().is_err());
+
+        assert_eq!(
+            Format::from_content_type(crate::protobuf_format::PROTOBUF_FORMAT),
+            Some(Format::Protobuf)
+        );
+        assert_eq!(
+            Format::from_content_type("Application/OpenMetrics-Text; version=1.0.0"),
+            Some(Format::OpenMetrics)
+        );
+        assert_eq!(Format::from_content_type("text/html"), None);
+    }
+
+    #[test]
+    fn test_untyped_in_every_format() {
+        use crate::otlp::{self, MetricData, OtlpConverter};
+        use crate::remote_write::{self, MetadataType};
+        use prometheus::proto::MetricType;
+
+        let formats = [
+            Format::Text,
+            Format::OpenMetrics,
+            Format::Protobuf,
+            Format::Json,
+        ];
+        let text = "# HELP u Meaning unknown.\n# TYPE u untyped\nu{job=\"a\"} 1.5\n";
+        let mfs = read_metric_families(Format::Text, text.as_bytes()).unwrap();
+
+        for from in formats {
+            let mut buf = Vec::new();
+            write_metric_families(from, &mut buf, &mfs).unwrap();
+            let read = read_metric_families(from, buf.as_slice()).unwrap();
+            for to in formats {
+                let mut buf = Vec::new();
+                write_metric_families(to, &mut buf, &read).unwrap();
+                let decoded = read_metric_families(to, buf.as_slice()).unwrap();
+                assert_eq!(decoded, mfs, "{} -> {}", from, to);
+            }
+        }
+
+        for (format, type_line) in [
+            (Format::Text, "# TYPE u untyped\n"),
+            (Format::OpenMetrics, "# TYPE u unknown\n"),
+        ] {
+            let mut buf = Vec::new();
+            write_metric_families(format, &mut buf, &mfs).unwrap();
+            let written = String::from_utf8(buf).unwrap();
+            assert!(written.contains(type_line), "{}: {}", format, written);
+        }
+
+        let req = remote_write::metric_families_to_write_request(&mfs, 1000);
+        assert_eq!(req.metadata[0].r#type(), MetadataType::Unknown);
+        let decoded = remote_write::write_request_to_metric_families(&req).unwrap();
+        assert_eq!(decoded[0].get_field_type(), MetricType::UNTYPED);
+        assert_eq!(decoded[0].get_metric()[0].get_untyped().get_value(), 1.5);
+
+        // OTLP has no untyped metrics; gauges come back as gauges.
+        let req = otlp::metric_families_to_export_request(&mfs);
+        let metric = &req.resource_metrics[0].scope_metrics[0].metrics[0];
+        assert!(matches!(metric.data, Some(MetricData::Gauge(_))));
+        let decoded = OtlpConverter::new().convert(&req);
+        assert_eq!(decoded[0].get_field_type(), MetricType::GAUGE);
+        assert_eq!(decoded[0].get_metric()[0].get_gauge().get_value(), 1.5);
+    }
+
+    #[test]
+    fn test_sort_series() {
+        let text = r#"# HELP b B.
+# TYPE b counter
+b{z="1",code="500"} 1
+b{code="200"} 2
+b{z="0",code="500"} 3
+# TYPE a gauge
+a 1
+"#;
+        let mut mfs = read_metric_families(Format::Text, text.as_bytes()).unwrap();
+        mfs.reverse();
+        sort_series(&mut mfs);
+
+        let mut out = Vec::new();
+        write_metric_families(Format::Text, &mut out, &mfs).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            r#"# TYPE a gauge
+a 1
+# HELP b B.
+# TYPE b counter
+b{code="200"} 2
+b{code="500",z="0"} 3
+b{code="500",z="1"} 1
+"#
+        );
+    }
+}
@@ -0,0 +1,444 @@
+//! The line grammar of the Prometheus text format as small composable
+//! parsers, for tools that need the exact grammar without `TextParser`'s
+//! streaming machinery.
+//!
+//! Every parser takes the remaining input and returns it advanced past what
+//! was consumed, together with the parsed value. Names, label values and
+//! comment texts are returned as slices of the input, i.e. spans; label
+//! values and HELP texts keep their escapes until passed to `unescape`.
+//! `recognize` turns any parser into one returning the span it consumed.
+//!
+//! ```
+//! use pmv::grammar::{sample, unescape};
+//!
+//! let (rest, s) = sample("http_requests_total{path=\"/a\\\"b\"} 3 1700000000000\n").unwrap();
+//! assert_eq!(rest, "\n");
+//! assert_eq!(s.name, "http_requests_total");
+//! assert_eq!(unescape(s.labels[0].1).unwrap(), "/a\"b");
+//! assert_eq!((s.value, s.timestamp), (3.0, Some(1700000000000)));
+//! ```
+
+use std::borrow::Cow;
+use std::fmt;
+
+/// A parser's result: the remaining input and the parsed value.
+pub type IResult<'a, T> = Result<(&'a str, T), GrammarError<'a>>;
+
+/// Where a parser failed, and what it expected there.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GrammarError<'a> {
+    /// The input remaining at the point of failure.
+    pub input: &'a str,
+    pub expected: &'static str,
+}
+
+impl GrammarError<'_> {
+    /// Byte offset of the failure within `full`, the input originally
+    /// given to the outermost parser.
+    pub fn offset(&self, full: &str) -> usize {
+        full.len() - self.input.len()
+    }
+}
+
+impl fmt::Display for GrammarError<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let found: String = self.input.chars().take(16).collect();
+        write!(f, "expected {}, found {:?}", self.expected, found)
+    }
+}
+
+impl std::error::Error for GrammarError<'_> {}
+
+fn fail<'a, T>(input: &'a str, expected: &'static str) -> IResult<'a, T> {
+    Err(GrammarError { input, expected })
+}
+
+/// A sample line: name, raw label pairs, value and optional timestamp.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sample<'a> {
+    pub name: &'a str,
+    pub labels: Vec<(&'a str, &'a str)>,
+    pub value: f64,
+    pub timestamp: Option<i64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comment<'a> {
+    /// `# HELP name text`, the text still escaped.
+    Help { name: &'a str, text: &'a str },
+    /// `# TYPE name type`.
+    Type { name: &'a str, kind: &'a str },
+    /// Any other comment, without the leading `#`.
+    Other(&'a str),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Line<'a> {
+    Blank,
+    Comment(Comment<'a>),
+    Sample(Sample<'a>),
+}
+
+pub fn is_blank_or_tab(b: u8) -> bool {
+    b == b' ' || b == b'\t'
+}
+
+pub fn is_valid_label_name_start(c: char) -> bool {
+    c.is_ascii_alphabetic() || c == '_'
+}
+
+pub fn is_valid_label_name_continuation(c: char) -> bool {
+    is_valid_label_name_start(c) || c.is_ascii_digit()
+}
+
+pub fn is_valid_metric_name_start(c: char) -> bool {
+    is_valid_label_name_start(c) || c == ':'
+}
+
+pub fn is_valid_metric_name_continuation(c: char) -> bool {
+    is_valid_label_name_continuation(c) || c == ':'
+}
+
+/// Parses a sample value, accepting the `+Inf`, `-Inf` and `NaN` spellings
+/// of the text format.
+pub fn parse_float(s: &str) -> Option<f64> {
+    match s {
+        "+Inf" => Some(f64::INFINITY),
+        "-Inf" => Some(f64::NEG_INFINITY),
+        "NaN" => Some(f64::NAN),
+        _ => s.parse::<f64>().ok().filter(|v| v.is_finite()),
+    }
+}
+
+/// Runs `parser` and returns the span it consumed instead of its value.
+pub fn recognize<'a, T>(
+    parser: impl Fn(&'a str) -> IResult<'a, T>,
+) -> impl Fn(&'a str) -> IResult<'a, &'a str> {
+    move |input| {
+        let (rest, _) = parser(input)?;
+        Ok((rest, &input[..input.len() - rest.len()]))
+    }
+}
+
+/// Zero or more blanks and tabs.
+pub fn blank(input: &str) -> IResult<'_, &str> {
+    let end = input
+        .bytes()
+        .position(|b| !is_blank_or_tab(b))
+        .unwrap_or(input.len());
+    Ok((&input[end..], &input[..end]))
+}
+
+fn identifier<'a>(
+    input: &'a str,
+    start: fn(char) -> bool,
+    continuation: fn(char) -> bool,
+    expected: &'static str,
+) -> IResult<'a, &'a str> {
+    if !input.starts_with(start) {
+        return fail(input, expected);
+    }
+    let end = input
+        .find(|c: char| !continuation(c))
+        .unwrap_or(input.len());
+    Ok((&input[end..], &input[..end]))
+}
+
+pub fn metric_name(input: &str) -> IResult<'_, &str> {
+    identifier(
+        input,
+        is_valid_metric_name_start,
+        is_valid_metric_name_continuation,
+        "metric name",
+    )
+}
+
+pub fn label_name(input: &str) -> IResult<'_, &str> {
+    identifier(
+        input,
+        is_valid_label_name_start,
+        is_valid_label_name_continuation,
+        "label name",
+    )
+}
+
+/// A double-quoted label value. Returns the text between the quotes with
+/// its escapes, which must be `\\`, `\"` or `\n`.
+pub fn label_value(input: &str) -> IResult<'_, &str> {
+    let body = match input.strip_prefix('"') {
+        Some(body) => body,
+        None => return fail(input, "'\"' at start of label value"),
+    };
+
+    let mut escaped = false;
+    for (i, c) in body.char_indices() {
+        match c {
+            '"' | '\\' | 'n' if escaped => escaped = false,
+            _ if escaped => return fail(&body[i - 1..], "escape sequence \\\\, \\\" or \\n"),
+            '\\' => escaped = true,
+            '"' => return Ok((&body[i + 1..], &body[..i])),
+            '\n' => return fail(&body[i..], "'\"' before end of line"),
+            _ => {}
+        }
+    }
+    fail(&input[input.len()..], "'\"' at end of label value")
+}
+
+/// Resolves the escapes of a label value or HELP text. Fails on an escape
+/// other than `\\`, `\"` or `\n`.
+pub fn unescape(raw: &str) -> Result<Cow<'_, str>, GrammarError<'_>> {
+    if !raw.contains('\\') {
+        return Ok(Cow::Borrowed(raw));
+    }
+
+    let mut out = String::with_capacity(raw.len());
+    let mut chars = raw.char_indices();
+    while let Some((i, c)) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some((_, '\\')) => out.push('\\'),
+            Some((_, '"')) => out.push('"'),
+            Some((_, 'n')) => out.push('\n'),
+            _ => {
+                return Err(GrammarError {
+                    input: &raw[i..],
+                    expected: "escape sequence \\\\, \\\" or \\n",
+                })
+            }
+        }
+    }
+    Ok(Cow::Owned(out))
+}
+
+/// `name="value"`, with blanks allowed around the `=`.
+pub fn label(input: &str) -> IResult<'_, (&str, &str)> {
+    let (rest, name) = label_name(input)?;
+    let (rest, _) = blank(rest)?;
+    let rest = match rest.strip_prefix('=') {
+        Some(rest) => rest,
+        None => return fail(rest, "'=' after label name"),
+    };
+    let (rest, _) = blank(rest)?;
+    let (rest, value) = label_value(rest)?;
+    Ok((rest, (name, value)))
+}
+
+/// `{name="value",...}`, allowing blanks between the pieces and a trailing
+/// comma.
+pub fn label_set(input: &str) -> IResult<'_, Vec<(&str, &str)>> {
+    let mut rest = match input.strip_prefix('{') {
+        Some(rest) => rest,
+        None => return fail(input, "'{'"),
+    };
+
+    let mut labels = Vec::new();
+    loop {
+        rest = blank(rest)?.0;
+        if let Some(after) = rest.strip_prefix('}') {
+            return Ok((after, labels));
+        }
+
+        let (after, pair) = label(rest)?;
+        labels.push(pair);
+        rest = blank(after)?.0;
+
+        if let Some(after) = rest.strip_prefix(',') {
+            rest = after;
+        } else if !rest.starts_with('}') {
+            return fail(rest, "',' or '}' after label value");
+        }
+    }
+}
+
+fn token(input: &str) -> (&str, &str) {
+    let end = input.find([' ', '\t', '\n']).unwrap_or(input.len());
+    (&input[end..], &input[..end])
+}
+
+/// A sample value, including `+Inf`, `-Inf` and `NaN`.
+pub fn value(input: &str) -> IResult<'_, f64> {
+    let (rest, token) = token(input);
+    match parse_float(token) {
+        Some(v) => Ok((rest, v)),
+        None => fail(input, "float as value"),
+    }
+}
+
+/// A timestamp in milliseconds.
+pub fn timestamp(input: &str) -> IResult<'_, i64> {
+    let (rest, token) = token(input);
+    match token.parse() {
+        Ok(ts) => Ok((rest, ts)),
+        Err(_) => fail(input, "integer as timestamp"),
+    }
+}
+
+fn line_end(input: &str) -> (&str, &str) {
+    let end = input.find('\n').unwrap_or(input.len());
+    (&input[end..], &input[..end])
+}
+
+/// A comment, from the `#` up to but excluding the end of line.
+pub fn comment(input: &str) -> IResult<'_, Comment<'_>> {
+    let body = match input.strip_prefix('#') {
+        Some(body) => body,
+        None => return fail(input, "'#'"),
+    };
+    let (rest, _) = blank(body)?;
+    let (after_keyword, keyword) = token(rest);
+
+    let is_help = keyword == "HELP";
+    if !is_help && keyword != "TYPE" {
+        let (rest, text) = line_end(body);
+        return Ok((rest, Comment::Other(text)));
+    }
+
+    let (rest, _) = blank(after_keyword)?;
+    let (rest, name) = metric_name(rest)?;
+    if !rest.is_empty() && !rest.starts_with([' ', '\t', '\n']) {
+        return fail(rest, "blank after metric name in comment");
+    }
+    let (rest, _) = blank(rest)?;
+    let (rest, text) = line_end(rest);
+
+    if is_help {
+        unescape_help(text)?;
+        return Ok((rest, Comment::Help { name, text }));
+    }
+    match text.to_lowercase().as_str() {
+        "counter" | "gauge" | "summary" | "untyped" | "histogram" => {
+            Ok((rest, Comment::Type { name, kind: text }))
+        }
+        _ => fail(text, "metric type"),
+    }
+}
+
+/// HELP texts only know the `\\` and `\n` escapes in the text format.
+fn unescape_help(raw: &str) -> Result<(), GrammarError<'_>> {
+    match raw.find("\\\"") {
+        Some(i) => Err(GrammarError {
+            input: &raw[i..],
+            expected: "escape sequence \\\\ or \\n",
+        }),
+        None => unescape(raw).map(|_| ()),
+    }
+}
+
+/// A sample, up to but excluding the end of line.
+pub fn sample(input: &str) -> IResult<'_, Sample<'_>> {
+    let (rest, name) = metric_name(input)?;
+    let (rest, _) = blank(rest)?;
+    let (rest, labels) = if rest.starts_with('{') {
+        let (rest, labels) = label_set(rest)?;
+        (blank(rest)?.0, labels)
+    } else {
+        (rest, Vec::new())
+    };
+
+    let (rest, value) = value(rest)?;
+    let (rest, _) = blank(rest)?;
+    let (rest, timestamp) = if rest.is_empty() || rest.starts_with('\n') {
+        (rest, None)
+    } else {
+        let (rest, ts) = timestamp(rest)?;
+        (blank(rest)?.0, Some(ts))
+    };
+    if !rest.is_empty() && !rest.starts_with('\n') {
+        return fail(rest, "end of line after timestamp");
+    }
+
+    Ok((
+        rest,
+        Sample {
+            name,
+            labels,
+            value,
+            timestamp,
+        },
+    ))
+}
+
+/// A whole line including its newline, if any.
+pub fn line(input: &str) -> IResult<'_, Line<'_>> {
+    let (rest, _) = blank(input)?;
+    let (rest, line) = if rest.is_empty() || rest.starts_with('\n') {
+        (rest, Line::Blank)
+    } else if rest.starts_with('#') {
+        let (rest, c) = comment(rest)?;
+        (rest, Line::Comment(c))
+    } else {
+        let (rest, s) = sample(rest)?;
+        (rest, Line::Sample(s))
+    };
+    Ok((rest.strip_prefix('\n').unwrap_or(rest), line))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::text_parse::TextParser;
+
+    #[test]
+    fn test_pieces() {
+        assert_eq!(metric_name("up{"), Ok(("{", "up")));
+        assert!(metric_name("1up").is_err());
+        assert_eq!(label_value(r#""a\"b" x"#), Ok((" x", r#"a\"b"#)));
+        assert_eq!(
+            label_set(r#"{ a = "1" ,b="2",} 5"#),
+            Ok((" 5", vec![("a", "1"), ("b", "2")]))
+        );
+        assert_eq!(value("+Inf 12"), Ok((" 12", f64::INFINITY)));
+        assert_eq!(recognize(label)(r#"a="1"}"#), Ok(("}", r#"a="1""#)));
+        assert_eq!(
+            comment("# HELP up Whether\\nup.\n"),
+            Ok((
+                "\n",
+                Comment::Help {
+                    name: "up",
+                    text: "Whether\\nup."
+                }
+            ))
+        );
+        assert_eq!(unescape(r#"a\\\"\n"#).unwrap(), "a\\\"\n");
+
+        let input = "up{a=\"1\" 5\n";
+        let err = sample(input).unwrap_err();
+        assert_eq!(err.offset(input), 9);
+        assert_eq!(err.expected, "',' or '}' after label value");
+    }
+
+    #[test]
+    fn test_agrees_with_text_parser() {
+        for input in [
+            "# HELP a Help\\\\ text.\n# TYPE a counter\na{x=\"1\"} 1 1000\n",
+            "a{x=\"1\",} 1\n\n  b 2\n",
+            "a{x=\"1\"}2\n",
+            "a 1 2 3\n",
+            "a{x=\"\\q\"} 1\n",
+            "a{x=1} 1\n",
+            "# TYPE a flux\n",
+            "a NaNx\n",
+            "a{x=\"1\" y=\"2\"} 1\n",
+        ] {
+            let mut rest = input;
+            let mut grammar_ok = true;
+            while !rest.is_empty() {
+                match line(rest) {
+                    Ok((r, _)) => rest = r,
+                    Err(_) => {
+                        grammar_ok = false;
+                        break;
+                    }
+                }
+            }
+
+            let parser_ok = TextParser::new(input.as_bytes())
+                .text_to_metric_families()
+                .is_ok();
+            assert_eq!(grammar_ok, parser_ok, "{:?}", input);
+        }
+    }
+}
@@ -0,0 +1,667 @@
+use prometheus::proto::{Metric, MetricFamily, MetricType};
+use regex::Regex;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::grammar::{
+    is_valid_label_name_continuation, is_valid_label_name_start, is_valid_metric_name_continuation,
+    is_valid_metric_name_start, parse_float,
+};
+use crate::text_encode::escape_label_value;
+
+/// The label holding the metric name in selectors.
+pub const NAME_LABEL: &str = "__name__";
+
+/// How a [`Matcher`] compares a label value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MatchOp {
+    /// `=`
+    Equal,
+    /// `!=`
+    NotEqual,
+    /// `=~`, a regex matching the whole value.
+    Regex,
+    /// `!~`
+    NotRegex,
+}
+
+impl MatchOp {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            MatchOp::Equal => "=",
+            MatchOp::NotEqual => "!=",
+            MatchOp::Regex => "=~",
+            MatchOp::NotRegex => "!~",
+        }
+    }
+}
+
+/// A label matcher as in PromQL, written `name="value"`, `name!="value"`,
+/// `name=~"regex"` or `name!~"regex"`. Regexes match the whole value, and a
+/// missing label has the empty value. The [`NAME_LABEL`] matches the metric
+/// name.
+#[derive(Debug, Clone)]
+pub struct Matcher {
+    pub name: String,
+    pub op: MatchOp,
+    /// The value, or the regex's source.
+    pub value: String,
+    regex: Option<Regex>,
+}
+
+impl Matcher {
+    pub fn new(
+        name: impl Into<String>,
+        op: MatchOp,
+        value: impl Into<String>,
+    ) -> Result<Self, String> {
+        let value = value.into();
+        let regex = match op {
+            MatchOp::Regex | MatchOp::NotRegex => {
+                Some(Regex::new(&format!("^(?:{})$", value)).map_err(|e| e.to_string())?)
+            }
+            MatchOp::Equal | MatchOp::NotEqual => None,
+        };
+        Ok(Matcher {
+            name: name.into(),
+            op,
+            value,
+            regex,
+        })
+    }
+
+    pub fn matches(&self, value: &str) -> bool {
+        match (self.op, &self.regex) {
+            (MatchOp::Equal, _) => value == self.value,
+            (MatchOp::NotEqual, _) => value != self.value,
+            (MatchOp::Regex, Some(re)) => re.is_match(value),
+            (MatchOp::NotRegex, Some(re)) => !re.is_match(value),
+            (_, None) => unreachable!("regex matchers are built with their regex"),
+        }
+    }
+
+    /// Matches a series of the family `family`.
+    fn matches_metric(&self, family: &str, m: &Metric) -> bool {
+        if self.name == NAME_LABEL {
+            return self.matches(family);
+        }
+        let value = m
+            .get_label()
+            .iter()
+            .find(|l| l.get_name() == self.name)
+            .map(|l| l.get_value())
+            .unwrap_or("");
+        self.matches(value)
+    }
+}
+
+impl FromStr for Matcher {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let end = s
+            .find(['=', '!'])
+            .ok_or_else(|| format!("expected a matcher such as name=\"value\", got {:?}", s))?;
+        let name = s[..end].trim();
+        let rest = &s[end..];
+        let (op, value) = [
+            ("=~", MatchOp::Regex),
+            ("!~", MatchOp::NotRegex),
+            ("!=", MatchOp::NotEqual),
+            ("=", MatchOp::Equal),
+        ]
+        .iter()
+        .find_map(|(token, op)| rest.strip_prefix(token).map(|v| (*op, v)))
+        .ok_or_else(|| format!("unknown operator in {:?}", s))?;
+
+        if !name.starts_with(is_valid_label_name_start)
+            || !name.chars().all(is_valid_label_name_continuation)
+        {
+            return Err(format!("invalid label name {:?}", name));
+        }
+        let value = value.trim();
+        let value = match value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+            Some(quoted) => unescape_value(quoted),
+            None => value.to_string(),
+        };
+        Matcher::new(name, op, value)
+    }
+}
+
+impl fmt::Display for Matcher {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}{}\"{}\"",
+            self.name,
+            self.op.as_str(),
+            escape_label_value(&self.value)
+        )
+    }
+}
+
+/// Resolves `\\`, `\"` and `\n` in a quoted value. Other escapes are
+/// kept, so regexes such as `"\d+"` need no doubled backslashes.
+fn unescape_value(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, chars.peek()) {
+            ('\\', Some('\\')) | ('\\', Some('"')) => out.extend(chars.next()),
+            ('\\', Some('n')) => {
+                chars.next();
+                out.push('\n');
+            }
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+/// A comparison of sample values with a constant, written `value > 0`.
+/// Operators are `==`, `!=`, `>`, `>=`, `<` and `<=`; as in PromQL, `NaN`
+/// only satisfies `!=`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ValuePredicate {
+    pub op: CompareOp,
+    pub value: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompareOp {
+    Eq,
+    Ne,
+    Gt,
+    Ge,
+    Lt,
+    Le,
+}
+
+impl FromStr for ValuePredicate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let rest = s
+            .trim()
+            .strip_prefix("value")
+            .ok_or_else(|| format!("expected `value <op> <number>`, got {:?}", s))?
+            .trim_start();
+
+        // Two-character operators first, so `>=` is not read as `>`.
+        let ops = [
+            ("==", CompareOp::Eq),
+            ("!=", CompareOp::Ne),
+            (">=", CompareOp::Ge),
+            ("<=", CompareOp::Le),
+            (">", CompareOp::Gt),
+            ("<", CompareOp::Lt),
+        ];
+        let (op, number) = ops
+            .iter()
+            .find_map(|(token, op)| rest.strip_prefix(token).map(|n| (*op, n)))
+            .ok_or_else(|| format!("unknown comparison in {:?}", s))?;
+        let value =
+            parse_float(number.trim()).ok_or_else(|| format!("invalid number in {:?}", s))?;
+        Ok(ValuePredicate { op, value })
+    }
+}
+
+impl ValuePredicate {
+    pub fn matches(&self, v: f64) -> bool {
+        match self.op {
+            CompareOp::Eq => v == self.value,
+            CompareOp::Ne => v != self.value,
+            CompareOp::Gt => v > self.value,
+            CompareOp::Ge => v >= self.value,
+            CompareOp::Lt => v < self.value,
+            CompareOp::Le => v <= self.value,
+        }
+    }
+}
+
+/// Keeps the series whose value satisfies every predicate. Histograms and
+/// summaries are judged by their `_count` and kept or dropped whole, so
+/// buckets and quantiles are never stripped from a series. Families left
+/// without series are dropped.
+pub fn filter_values(mfs: &[MetricFamily], predicates: &[ValuePredicate]) -> Vec<MetricFamily> {
+    mfs.iter()
+        .filter_map(|mf| {
+            let metrics: Vec<Metric> = mf
+                .get_metric()
+                .iter()
+                .filter(|m| {
+                    let v = series_value(mf.get_field_type(), m);
+                    predicates.iter().all(|p| p.matches(v))
+                })
+                .cloned()
+                .collect();
+            if metrics.is_empty() {
+                return None;
+            }
+
+            let mut mf = mf.clone();
+            mf.set_metric(metrics.into());
+            Some(mf)
+        })
+        .collect()
+}
+
+fn series_value(metric_type: MetricType, m: &Metric) -> f64 {
+    match metric_type {
+        MetricType::COUNTER => m.get_counter().get_value(),
+        MetricType::GAUGE => m.get_gauge().get_value(),
+        MetricType::UNTYPED => m.get_untyped().get_value(),
+        MetricType::HISTOGRAM => m.get_histogram().get_sample_count() as f64,
+        MetricType::SUMMARY => m.get_summary().get_sample_count() as f64,
+    }
+}
+
+/// A series selector as in PromQL, such as `node_cpu_seconds_total{mode="idle"}`
+/// or `{__name__=~"node_.*",job!="test"}`: an optional metric name, which
+/// stands for a `__name__="name"` matcher, and label matchers, all of which
+/// a series must satisfy.
+#[derive(Debug, Clone)]
+pub struct Selector {
+    pub matchers: Vec<Matcher>,
+}
+
+impl FromStr for Selector {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (name, matchers) = match s.find('{') {
+            Some(idx) => {
+                let matchers = s[idx + 1..]
+                    .strip_suffix('}')
+                    .ok_or_else(|| format!("unterminated selector {:?}", s))?;
+                (s[..idx].trim(), matchers)
+            }
+            None => (s, ""),
+        };
+
+        let mut selector = Selector {
+            matchers: Vec::new(),
+        };
+        if !name.is_empty() {
+            if !name.starts_with(is_valid_metric_name_start)
+                || !name.chars().all(is_valid_metric_name_continuation)
+            {
+                return Err(format!(
+                    "invalid metric name {:?}; match names with {{__name__=~\"regex\"}}",
+                    name
+                ));
+            }
+            selector
+                .matchers
+                .push(Matcher::new(NAME_LABEL, MatchOp::Equal, name)?);
+        }
+        for matcher in split_matchers(matchers) {
+            selector.matchers.push(matcher.parse()?);
+        }
+
+        if selector.matchers.is_empty() {
+            return Err("selector must match a name or at least one label".to_string());
+        }
+        Ok(selector)
+    }
+}
+
+impl fmt::Display for Selector {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let matchers: Vec<String> = self.matchers.iter().map(|m| m.to_string()).collect();
+        write!(f, "{{{}}}", matchers.join(","))
+    }
+}
+
+impl Selector {
+    fn matches(&self, family: &str, m: &Metric) -> bool {
+        self.matchers.iter().all(|p| p.matches_metric(family, m))
+    }
+
+    /// Matches a single sample by its name, including any `_bucket`, `_sum`
+    /// or `_count` suffix, and labels.
+    pub fn matches_series(&self, name: &str, labels: &[(String, String)]) -> bool {
+        self.matchers.iter().all(|p| {
+            if p.name == NAME_LABEL {
+                return p.matches(name);
+            }
+            let value = labels
+                .iter()
+                .find(|(n, _)| *n == p.name)
+                .map(|(_, v)| v.as_str())
+                .unwrap_or("");
+            p.matches(value)
+        })
+    }
+}
+
+/// Splits the inside of a selector's braces on commas outside quotes.
+fn split_matchers(s: &str) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut in_quotes = false;
+    let mut escaped = false;
+
+    for (i, c) in s.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '"' => in_quotes = !in_quotes,
+            ',' if !in_quotes => {
+                parts.push(&s[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push(&s[start..]);
+    parts.into_iter().filter(|p| !p.trim().is_empty()).collect()
+}
+
+/// Whether a [`FilterRule`] keeps or drops the series it matches.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterAction {
+    Keep,
+    Drop,
+}
+
+/// A selector that keeps or drops the series it matches, written as the
+/// selector to keep and with a leading `!` to drop, e.g. `!{job="test"}`.
+#[derive(Debug, Clone)]
+pub struct FilterRule {
+    pub action: FilterAction,
+    pub selector: Selector,
+}
+
+impl FromStr for FilterRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (action, selector) = match s.strip_prefix('!') {
+            Some(selector) => (FilterAction::Drop, selector),
+            None => (FilterAction::Keep, s),
+        };
+        Ok(FilterRule {
+            action,
+            selector: selector.parse()?,
+        })
+    }
+}
+
+/// Keeps the series matching any of `selectors`, as the federation endpoint
+/// does with its `match[]` parameters. Families left without series are
+/// dropped.
+pub fn select(mfs: &[MetricFamily], selectors: &[Selector]) -> Vec<MetricFamily> {
+    mfs.iter()
+        .filter_map(|mf| {
+            let metrics: Vec<Metric> = mf
+                .get_metric()
+                .iter()
+                .filter(|m| selectors.iter().any(|s| s.matches(mf.get_name(), m)))
+                .cloned()
+                .collect();
+            if metrics.is_empty() {
+                return None;
+            }
+
+            let mut mf = mf.clone();
+            mf.set_metric(metrics.into());
+            Some(mf)
+        })
+        .collect()
+}
+
+/// Keeps the series matching any keep rule, or all if there are none,
+/// unless they match a drop rule. Series are kept or dropped whole, so
+/// histogram and summary series keep all their buckets and quantiles.
+/// Families left without series are dropped.
+pub fn filter(mfs: &[MetricFamily], rules: &[FilterRule]) -> Vec<MetricFamily> {
+    let any_keep = rules.iter().any(|r| r.action == FilterAction::Keep);
+    mfs.iter()
+        .filter_map(|mf| {
+            let metrics: Vec<Metric> = mf
+                .get_metric()
+                .iter()
+                .filter(|m| {
+                    let matching = |action| {
+                        rules
+                            .iter()
+                            .filter(|rule| rule.action == action)
+                            .any(|rule| rule.selector.matches(mf.get_name(), m))
+                    };
+                    (!any_keep || matching(FilterAction::Keep)) && !matching(FilterAction::Drop)
+                })
+                .cloned()
+                .collect();
+            if metrics.is_empty() {
+                return None;
+            }
+
+            let mut mf = mf.clone();
+            mf.set_metric(metrics.into());
+            Some(mf)
+        })
+        .collect()
+}
+
+/// Keeps families whose name matches `name` (anywhere in the name, like grep)
+/// and, within them, series whose labels match every pattern in `labels`.
+/// Families left without series are dropped.
+pub fn grep(mfs: &[MetricFamily], name: &Regex, labels: &[Matcher]) -> Vec<MetricFamily> {
+    mfs.iter()
+        .filter(|mf| name.is_match(mf.get_name()))
+        .filter_map(|mf| {
+            let metrics: Vec<Metric> = mf
+                .get_metric()
+                .iter()
+                .filter(|m| labels.iter().all(|p| p.matches_metric(mf.get_name(), m)))
+                .cloned()
+                .collect();
+            if metrics.is_empty() {
+                return None;
+            }
+
+            let mut mf = mf.clone();
+            mf.set_metric(metrics.into());
+            Some(mf)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::{read_metric_families, Format};
+
+    #[test]
+    fn test_grep() {
+        let mfs = read_metric_families(
+            Format::Text,
+            r#"# HELP http_requests_total Requests.
+# TYPE http_requests_total counter
+http_requests_total{method="POST"} 1
+http_requests_total{method="POSTX"} 2
+http_requests_total{method="GET"} 3
+# TYPE process_cpu gauge
+process_cpu 1
+"#
+            .as_bytes(),
+        )
+        .unwrap();
+
+        let name = Regex::new("http_.*").unwrap();
+        let method: Matcher = r#"method="POST""#.parse().unwrap();
+        let matched = grep(&mfs, &name, &[method]);
+
+        assert_eq!(matched.len(), 1);
+        assert_eq!(matched[0].get_help(), "Requests.");
+        assert_eq!(matched[0].get_metric().len(), 1);
+        assert_eq!(matched[0].get_metric()[0].get_counter().get_value(), 1.0);
+
+        let missing: Matcher = r#"method="PUT""#.parse().unwrap();
+        assert!(grep(&mfs, &name, &[missing]).is_empty());
+
+        let cpu = Regex::new("cpu").unwrap();
+        let empty: Matcher = "method=".parse().unwrap();
+        assert_eq!(grep(&mfs, &cpu, &[empty]).len(), 1);
+    }
+
+    #[test]
+    fn test_matcher() {
+        let m: Matcher = r#"path=~"/api/\d+""#.parse().unwrap();
+        assert_eq!((m.name.as_str(), m.op), ("path", MatchOp::Regex));
+        assert!(m.matches("/api/12") && !m.matches("/api/12/x"));
+        let m: Matcher = r#"msg != "say \"hi\"""#.parse().unwrap();
+        assert_eq!(m.value, r#"say "hi""#);
+        assert!(m.matches("") && !m.matches(r#"say "hi""#));
+        assert_eq!(m.to_string(), r#"msg!="say \"hi\"""#);
+        assert!("0x=\"a\"".parse::<Matcher>().is_err());
+        assert!("a~\"b\"".parse::<Matcher>().is_err());
+        assert!("a=~\"(\"".parse::<Matcher>().is_err());
+    }
+
+    #[test]
+    fn test_select() {
+        let mfs = read_metric_families(
+            Format::Text,
+            r#"# TYPE node_cpu_seconds_total counter
+node_cpu_seconds_total{cpu="0",mode="idle"} 10
+node_cpu_seconds_total{cpu="0",mode="user"} 2
+# TYPE node_load1 gauge
+node_load1 0.5
+up{job="api"} 1
+up{job="db"} 0
+"#
+            .as_bytes(),
+        )
+        .unwrap();
+
+        let selectors: Vec<Selector> = vec![
+            r#"{__name__=~"node_cpu.*",mode="idle",cpu=~"0|1"}"#
+                .parse()
+                .unwrap(),
+            r#"{__name__="up",job=~"a.*"}"#.parse().unwrap(),
+        ];
+        let selected = select(&mfs, &selectors);
+
+        assert_eq!(selected.len(), 2);
+        assert_eq!(selected[0].get_name(), "node_cpu_seconds_total");
+        assert_eq!(selected[0].get_metric().len(), 1);
+        assert_eq!(selected[1].get_name(), "up");
+        assert_eq!(
+            selected[1].get_metric()[0].get_label()[0].get_value(),
+            "api"
+        );
+
+        // Names match exactly, unlike grep.
+        let load: Selector = "load1".parse().unwrap();
+        assert!(select(&mfs, &[load]).is_empty());
+        let not_idle: Selector = r#"node_cpu_seconds_total{mode!="idle"}"#.parse().unwrap();
+        assert_eq!(select(&mfs, &[not_idle])[0].get_metric().len(), 1);
+        let others: Selector = r#"{__name__!~"node_.*", job!~"a.*"}"#.parse().unwrap();
+        assert_eq!(
+            select(&mfs, std::slice::from_ref(&others))[0]
+                .get_metric()
+                .len(),
+            1
+        );
+        assert_eq!(others.to_string(), r#"{__name__!~"node_.*",job!~"a.*"}"#);
+        assert!("node_.*".parse::<Selector>().is_err());
+        assert!("{}".parse::<Selector>().is_err());
+        assert!(r#"up{job="a"#.parse::<Selector>().is_err());
+    }
+
+    #[test]
+    fn test_filter() {
+        let mfs = read_metric_families(
+            Format::Text,
+            r#"# TYPE up gauge
+up{job="api",env="prod"} 1
+up{job="api",env="test"} 1
+up{job="db",env="prod"} 0
+# TYPE latency histogram
+latency_bucket{env="test",le="+Inf"} 2
+latency_sum{env="test"} 1
+latency_count{env="test"} 2
+"#
+            .as_bytes(),
+        )
+        .unwrap();
+        let series =
+            |mfs: &[MetricFamily]| -> usize { mfs.iter().map(|mf| mf.get_metric().len()).sum() };
+
+        let rules: Vec<FilterRule> = vec![r#"!{env="test"}"#.parse().unwrap()];
+        let kept = filter(&mfs, &rules);
+        assert_eq!((kept.len(), series(&kept)), (1, 2));
+
+        let rules: Vec<FilterRule> = vec![
+            r#"up{job="api"}"#.parse().unwrap(),
+            "latency".parse().unwrap(),
+            r#" !up{env="test"}"#.parse().unwrap(),
+        ];
+        let kept = filter(&mfs, &rules);
+        // Histogram series are kept whole.
+        assert_eq!(kept[0].get_name(), "latency");
+        assert_eq!(
+            kept[0].get_metric()[0].get_histogram().get_sample_count(),
+            2
+        );
+        assert_eq!(kept[1].get_metric().len(), 1);
+        assert_eq!(kept[1].get_metric()[0].get_label()[1].get_value(), "prod");
+
+        assert_eq!(series(&filter(&mfs, &[])), 4);
+        assert!("!".parse::<FilterRule>().is_err());
+    }
+
+    #[test]
+    fn test_filter_values() {
+        let mfs = read_metric_families(
+            Format::Text,
+            r#"# TYPE errors_total counter
+errors_total{code="500"} 0
+errors_total{code="503"} 4
+# TYPE idle gauge
+idle 0
+# TYPE latency histogram
+latency_bucket{path="/",le="1"} 0
+latency_bucket{path="/",le="+Inf"} 2
+latency_sum{path="/"} 0
+latency_count{path="/"} 2
+latency_bucket{path="/x",le="+Inf"} 0
+latency_sum{path="/x"} 0
+latency_count{path="/x"} 0
+nan NaN
+"#
+            .as_bytes(),
+        )
+        .unwrap();
+
+        let nonzero: ValuePredicate = "value != 0".parse().unwrap();
+        let kept = filter_values(&mfs, &[nonzero]);
+        let names: Vec<_> = kept.iter().map(|mf| mf.get_name()).collect();
+        assert_eq!(names, vec!["errors_total", "latency", "nan"]);
+        // The histogram with a zero bucket and sum is kept whole.
+        let h = kept[1].get_metric()[0].get_histogram();
+        assert_eq!((kept[1].get_metric().len(), h.get_bucket().len()), (1, 2));
+
+        let range: Vec<ValuePredicate> =
+            vec!["value>0".parse().unwrap(), "value <= 2".parse().unwrap()];
+        let kept = filter_values(&mfs, &range);
+        let names: Vec<_> = kept.iter().map(|mf| mf.get_name()).collect();
+        assert_eq!(names, vec!["latency"]);
+
+        assert_eq!(
+            "value >= +Inf".parse(),
+            Ok(ValuePredicate {
+                op: CompareOp::Ge,
+                value: f64::INFINITY
+            })
+        );
+        assert!("value = 0".parse::<ValuePredicate>().is_err());
+        assert!("count > 0".parse::<ValuePredicate>().is_err());
+        assert!("value > x".parse::<ValuePredicate>().is_err());
+    }
+}
@@ -0,0 +1,366 @@
+//! The pmv gRPC service, for non-Rust programs to use pmv's parser over
+//! localhost:
+//!
+//! ```proto
+//! syntax = "proto3";
+//! package pmv.v1;
+//!
+//! service Pmv {
+//!   rpc Parse(ParseRequest) returns (ParseResponse);
+//!   rpc Validate(ValidateRequest) returns (ValidateResponse);
+//!   rpc Forward(stream ForwardRequest) returns (ForwardResponse);
+//! }
+//!
+//! message ParseRequest { bytes body = 1; string format = 2; }
+//! // Every family is an encoded io.prometheus.client.MetricFamily.
+//! message ParseResponse { repeated bytes families = 1; }
+//! message ValidateRequest { bytes body = 1; bool require_type = 2; }
+//! message ValidateResponse { repeated Finding findings = 1; }
+//! message Finding { uint32 line = 1; uint32 column = 2; string message = 3; }
+//! message ForwardRequest { bytes body = 1; string format = 2; }
+//! message ForwardResponse { uint64 families = 1; uint64 series = 2; }
+//! ```
+//!
+//! Formats are named as on the command line; empty means text.
+//!
+//! [`OtlpService`] handles OTLP's
+//! `opentelemetry.proto.collector.metrics.v1.MetricsService/Export`, for
+//! OpenTelemetry SDKs exporting over gRPC.
+//!
+//! [`server::Server`] serves both over HTTP/2: `pmv grpc` the `Pmv`
+//! service, and `pmv receive --otlp-grpc-listen` OTLP's.
+
+use prometheus::proto::MetricFamily;
+use prost::Message;
+use protobuf::Message as _;
+use std::fmt;
+use std::sync::Mutex;
+
+use crate::format::{self, Format};
+use crate::merge::{self, ConflictPolicy};
+use crate::otlp::{ExportMetricsServiceRequest, OtlpConverter};
+use crate::pipeline::Pipeline;
+use crate::text_parse::TextParser;
+
+pub mod server;
+
+#[derive(Clone, PartialEq, Message)]
+pub struct ParseRequest {
+    #[prost(bytes = "vec", tag = "1")]
+    pub body: Vec<u8>,
+    #[prost(string, tag = "2")]
+    pub format: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct ParseResponse {
+    #[prost(bytes = "vec", repeated, tag = "1")]
+    pub families: Vec<Vec<u8>>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct ValidateRequest {
+    #[prost(bytes = "vec", tag = "1")]
+    pub body: Vec<u8>,
+    #[prost(bool, tag = "2")]
+    pub require_type: bool,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct ValidateResponse {
+    #[prost(message, repeated, tag = "1")]
+    pub findings: Vec<Finding>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Finding {
+    #[prost(uint32, tag = "1")]
+    pub line: u32,
+    #[prost(uint32, tag = "2")]
+    pub column: u32,
+    #[prost(string, tag = "3")]
+    pub message: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct ForwardRequest {
+    #[prost(bytes = "vec", tag = "1")]
+    pub body: Vec<u8>,
+    #[prost(string, tag = "2")]
+    pub format: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct ForwardResponse {
+    #[prost(uint64, tag = "1")]
+    pub families: u64,
+    #[prost(uint64, tag = "2")]
+    pub series: u64,
+}
+
+/// The gRPC status codes the handlers fail with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Code {
+    InvalidArgument = 3,
+    ResourceExhausted = 8,
+    Unimplemented = 12,
+    Internal = 13,
+}
+
+/// A failed call, to be sent as the gRPC status.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Status {
+    pub code: Code,
+    pub message: String,
+}
+
+impl Status {
+    fn invalid_argument(message: impl fmt::Display) -> Self {
+        Status {
+            code: Code::InvalidArgument,
+            message: message.to_string(),
+        }
+    }
+
+    fn resource_exhausted(message: impl fmt::Display) -> Self {
+        Status {
+            code: Code::ResourceExhausted,
+            message: message.to_string(),
+        }
+    }
+
+    fn unimplemented(message: impl fmt::Display) -> Self {
+        Status {
+            code: Code::Unimplemented,
+            message: message.to_string(),
+        }
+    }
+
+    fn internal(message: impl fmt::Display) -> Self {
+        Status {
+            code: Code::Internal,
+            message: message.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}: {}", self.code, self.message)
+    }
+}
+
+impl std::error::Error for Status {}
+
+/// Where `Forward` delivers the families it received, after the
+/// pipeline's transforms.
+pub type Sink = Box<dyn Fn(&[MetricFamily]) -> Result<(), String> + Send + Sync>;
+
+/// The handlers of the `Pmv` service.
+pub struct Service {
+    pipeline: Option<Pipeline>,
+    sink: Sink,
+}
+
+impl Service {
+    /// A service forwarding into `sink`, through the transforms of
+    /// `pipeline` if there is one.
+    pub fn new(pipeline: Option<Pipeline>, sink: Sink) -> Self {
+        Service { pipeline, sink }
+    }
+
+    pub fn parse(&self, req: &ParseRequest) -> Result<ParseResponse, Status> {
+        let mfs = read(&req.body, &req.format)?;
+        let families = mfs
+            .iter()
+            .map(|mf| mf.write_to_bytes().map_err(Status::internal))
+            .collect::<Result<_, _>>()?;
+        Ok(ParseResponse { families })
+    }
+
+    /// Reports every violation in the text-format body, as `pmv validate`
+    /// does. A body with violations is not an error.
+    pub fn validate(&self, req: &ValidateRequest) -> ValidateResponse {
+        let findings = TextParser::new(req.body.as_slice())
+            .strict(true)
+            .require_type(req.require_type)
+            .validate()
+            .into_iter()
+            .map(|e| Finding {
+                line: e.line as u32,
+                column: e.column as u32,
+                message: e.msg,
+            })
+            .collect();
+        ValidateResponse { findings }
+    }
+
+    /// Parses every request of the stream, merges them, the request sent
+    /// last winning for duplicate series, and delivers the result once the
+    /// stream ends.
+    pub fn forward<I>(&self, requests: I) -> Result<ForwardResponse, Status>
+    where
+        I: IntoIterator<Item = ForwardRequest>,
+    {
+        let inputs = requests
+            .into_iter()
+            .map(|req| read(&req.body, &req.format))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut mfs =
+            merge::merge(inputs, ConflictPolicy::LastWins).map_err(Status::invalid_argument)?;
+        if let Some(pipeline) = &self.pipeline {
+            mfs = pipeline.apply(mfs).map_err(Status::internal)?;
+        }
+        (self.sink)(&mfs).map_err(Status::internal)?;
+
+        Ok(ForwardResponse {
+            families: mfs.len() as u64,
+            series: mfs.iter().map(|mf| mf.get_metric().len() as u64).sum(),
+        })
+    }
+}
+
+/// OTLP's answer to an export; partial success is not reported.
+#[derive(Clone, PartialEq, Message)]
+pub struct ExportMetricsServiceResponse {}
+
+/// The handler of OTLP's metrics service.
+pub struct OtlpService {
+    converter: Mutex<OtlpConverter>,
+    pipeline: Option<Pipeline>,
+    sink: Sink,
+}
+
+impl OtlpService {
+    /// A service delivering into `sink`, through the transforms of
+    /// `pipeline` if there is one.
+    pub fn new(pipeline: Option<Pipeline>, sink: Sink) -> Self {
+        OtlpService {
+            converter: Mutex::new(OtlpConverter::new()),
+            pipeline,
+            sink,
+        }
+    }
+
+    /// Converts the exported metrics to families, as `pmv receive` does,
+    /// and delivers them. Delta sums and histograms are accumulated over
+    /// the service's life into cumulative ones.
+    pub fn export(
+        &self,
+        req: &ExportMetricsServiceRequest,
+    ) -> Result<ExportMetricsServiceResponse, Status> {
+        let mut mfs = self
+            .converter
+            .lock()
+            .map_err(|_| Status::internal("converter poisoned"))?
+            .convert(req);
+        if let Some(pipeline) = &self.pipeline {
+            mfs = pipeline.apply(mfs).map_err(Status::internal)?;
+        }
+        (self.sink)(&mfs).map_err(Status::internal)?;
+        Ok(ExportMetricsServiceResponse {})
+    }
+}
+
+fn read(body: &[u8], format: &str) -> Result<Vec<MetricFamily>, Status> {
+    let format = match format {
+        "" => Format::Text,
+        name => name.parse().map_err(Status::invalid_argument)?,
+    };
+    format::read_metric_families(format, body).map_err(Status::invalid_argument)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::otlp;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_service() {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let sink_received = Arc::clone(&received);
+        let pipeline = Pipeline::from_yaml(
+            "inputs: [grpc]\noutputs: [-]\ntransforms:\n  - filter:\n      match: [up]\n",
+        )
+        .unwrap();
+        let service = Service::new(
+            Some(pipeline),
+            Box::new(move |mfs| {
+                sink_received.lock().unwrap().extend_from_slice(mfs);
+                Ok(())
+            }),
+        );
+
+        let req = ParseRequest {
+            body: b"# TYPE up gauge\nup 1\n".to_vec(),
+            format: String::new(),
+        };
+        // Requests arrive encoded, as they would from a client.
+        let req = ParseRequest::decode(req.encode_to_vec().as_slice()).unwrap();
+        let resp = service.parse(&req).unwrap();
+        let mf = <MetricFamily as protobuf::Message>::parse_from_bytes(&resp.families[0]).unwrap();
+        assert_eq!(mf.get_name(), "up");
+
+        let err = service
+            .parse(&ParseRequest {
+                body: b"up{ 1\n".to_vec(),
+                format: "text".to_string(),
+            })
+            .unwrap_err();
+        assert_eq!(err.code, Code::InvalidArgument);
+
+        let resp = service.validate(&ValidateRequest {
+            body: b"a 1\nb{ 2\n".to_vec(),
+            require_type: true,
+        });
+        let lines: Vec<u32> = resp.findings.iter().map(|f| f.line).collect();
+        assert_eq!(lines, vec![1, 2]);
+
+        let forward = |body: &str| ForwardRequest {
+            body: body.as_bytes().to_vec(),
+            format: String::new(),
+        };
+        let resp = service
+            .forward(vec![
+                forward("up{i=\"a\"} 1\nother 1\n"),
+                forward("up{i=\"b\"} 0\n"),
+            ])
+            .unwrap();
+        assert_eq!(
+            resp,
+            ForwardResponse {
+                families: 1,
+                series: 2
+            }
+        );
+        assert_eq!(received.lock().unwrap()[0].get_metric().len(), 2);
+    }
+
+    #[test]
+    fn test_otlp_service() {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let sink_received = Arc::clone(&received);
+        let service = OtlpService::new(
+            None,
+            Box::new(move |mfs| {
+                sink_received.lock().unwrap().extend_from_slice(mfs);
+                Ok(())
+            }),
+        );
+
+        let mfs = format::read_metric_families(
+            Format::Text,
+            "# TYPE requests_total counter\nrequests_total{code=\"200\"} 7\n".as_bytes(),
+        )
+        .unwrap();
+        let req = otlp::metric_families_to_export_request(&mfs);
+        // Requests arrive encoded, as they would from an SDK.
+        let req = ExportMetricsServiceRequest::decode(req.encode_to_vec().as_slice()).unwrap();
+        assert_eq!(service.export(&req), Ok(ExportMetricsServiceResponse {}));
+
+        let received = received.lock().unwrap();
+        assert_eq!(received[0].get_name(), "requests_total");
+        assert_eq!(received[0].get_metric()[0].get_counter().get_value(), 7.0);
+    }
+}
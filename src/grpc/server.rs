@@ -0,0 +1,356 @@
+//! Serving the gRPC services over cleartext HTTP/2, with h2 on tokio.
+//!
+//! A call's messages are read whole before its handler runs on tokio's
+//! blocking pool, so handlers may block. Calls may send at most
+//! [`MAX_REQUEST_SIZE`] bytes, and compressed messages are refused.
+
+use bytes::{Buf, Bytes};
+use h2::server::SendResponse;
+use h2::RecvStream;
+use http::{HeaderMap, HeaderValue, Request, Response};
+use prost::Message;
+use std::collections::HashMap;
+use std::io;
+use std::net::TcpListener;
+use std::sync::Arc;
+
+use super::{OtlpService, Service, Status};
+use crate::otlp::export::GRPC_EXPORT_PATH;
+
+pub const PARSE_PATH: &str = "/pmv.v1.Pmv/Parse";
+pub const VALIDATE_PATH: &str = "/pmv.v1.Pmv/Validate";
+pub const FORWARD_PATH: &str = "/pmv.v1.Pmv/Forward";
+
+/// Limit on the length-prefixed messages of one call, together.
+pub const MAX_REQUEST_SIZE: usize = 64 << 20;
+
+/// A method's handler: the call's encoded messages in, the encoded answer
+/// out.
+type Method = Arc<dyn Fn(Vec<Bytes>) -> Result<Vec<u8>, Status> + Send + Sync>;
+
+/// The methods a server answers, by path; others fail as unimplemented.
+#[derive(Default)]
+pub struct Server {
+    methods: HashMap<&'static str, Method>,
+}
+
+impl Server {
+    pub fn new() -> Self {
+        Server::default()
+    }
+
+    /// Answers the methods of the `Pmv` service.
+    pub fn with_service(mut self, service: Service) -> Self {
+        let service = Arc::new(service);
+        let s = Arc::clone(&service);
+        self.methods.insert(
+            PARSE_PATH,
+            Arc::new(move |messages| Ok(s.parse(&unary(&messages)?)?.encode_to_vec())),
+        );
+        let s = Arc::clone(&service);
+        self.methods.insert(
+            VALIDATE_PATH,
+            Arc::new(move |messages| Ok(s.validate(&unary(&messages)?).encode_to_vec())),
+        );
+        self.methods.insert(
+            FORWARD_PATH,
+            Arc::new(move |messages| {
+                let requests = messages
+                    .into_iter()
+                    .map(|m| Message::decode(m).map_err(Status::invalid_argument))
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(service.forward(requests)?.encode_to_vec())
+            }),
+        );
+        self
+    }
+
+    /// Answers OTLP's `MetricsService/Export`.
+    pub fn with_otlp_service(mut self, service: OtlpService) -> Self {
+        self.methods.insert(
+            GRPC_EXPORT_PATH,
+            Arc::new(move |messages| Ok(service.export(&unary(&messages)?)?.encode_to_vec())),
+        );
+        self
+    }
+
+    /// Answers calls on `listener` until the process exits.
+    pub fn serve(self, listener: TcpListener) -> io::Result<()> {
+        listener.set_nonblocking(true)?;
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        let methods = Arc::new(self.methods);
+        runtime.block_on(async move {
+            let listener = tokio::net::TcpListener::from_std(listener)?;
+            loop {
+                let (tcp, peer) = match listener.accept().await {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        log::warn!("gRPC accept failed: {}", e);
+                        continue;
+                    }
+                };
+                let methods = Arc::clone(&methods);
+                tokio::spawn(async move {
+                    if let Err(e) = connection(tcp, methods).await {
+                        log::debug!("gRPC connection from {}: {}", peer, e);
+                    }
+                });
+            }
+        })
+    }
+}
+
+async fn connection(
+    tcp: tokio::net::TcpStream,
+    methods: Arc<HashMap<&'static str, Method>>,
+) -> Result<(), h2::Error> {
+    let mut connection = h2::server::handshake(tcp).await?;
+    while let Some(accepted) = connection.accept().await {
+        let (request, respond) = accepted?;
+        let method = methods.get(request.uri().path()).cloned();
+        tokio::spawn(async move {
+            if let Err(e) = call(request, respond, method).await {
+                log::debug!("gRPC call: {}", e);
+            }
+        });
+    }
+    Ok(())
+}
+
+async fn call(
+    request: Request<RecvStream>,
+    respond: SendResponse<Bytes>,
+    method: Option<Method>,
+) -> Result<(), h2::Error> {
+    let path = request.uri().path().to_string();
+    let result = match method {
+        None => Err(Status::unimplemented(format!("unknown method {}", path))),
+        Some(method) => match read_messages(request.into_body()).await? {
+            Ok(messages) => tokio::task::spawn_blocking(move || method(messages))
+                .await
+                .unwrap_or_else(|_| Err(Status::internal("handler panicked"))),
+            Err(status) => Err(status),
+        },
+    };
+    respond_with(respond, result)
+}
+
+/// Splits the body into its length-prefixed messages.
+async fn read_messages(mut body: RecvStream) -> Result<Result<Vec<Bytes>, Status>, h2::Error> {
+    let mut data = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        let _ = body.flow_control().release_capacity(chunk.len());
+        if data.len() + chunk.len() > MAX_REQUEST_SIZE {
+            return Ok(Err(Status::resource_exhausted(format!(
+                "request over {} bytes",
+                MAX_REQUEST_SIZE
+            ))));
+        }
+        data.extend_from_slice(&chunk);
+    }
+
+    let mut data = Bytes::from(data);
+    let mut messages = Vec::new();
+    while data.has_remaining() {
+        if data.len() < 5 {
+            return Ok(Err(Status::invalid_argument("truncated message")));
+        }
+        let compressed = data.get_u8() != 0;
+        let len = data.get_u32() as usize;
+        if compressed {
+            return Ok(Err(Status::unimplemented(
+                "compressed messages are not supported",
+            )));
+        }
+        if data.len() < len {
+            return Ok(Err(Status::invalid_argument("truncated message")));
+        }
+        messages.push(data.split_to(len));
+    }
+    Ok(Ok(messages))
+}
+
+fn respond_with(
+    mut respond: SendResponse<Bytes>,
+    result: Result<Vec<u8>, Status>,
+) -> Result<(), h2::Error> {
+    let mut response = Response::new(());
+    response
+        .headers_mut()
+        .insert("content-type", HeaderValue::from_static("application/grpc"));
+    match result {
+        Ok(message) => {
+            let mut stream = respond.send_response(response, false)?;
+            let mut data = Vec::with_capacity(5 + message.len());
+            data.push(0);
+            data.extend_from_slice(&(message.len() as u32).to_be_bytes());
+            data.extend_from_slice(&message);
+            stream.send_data(Bytes::from(data), false)?;
+            let mut trailers = HeaderMap::new();
+            trailers.insert("grpc-status", HeaderValue::from(0));
+            stream.send_trailers(trailers)
+        }
+        // Failed calls answer with headers only.
+        Err(status) => {
+            let headers = response.headers_mut();
+            headers.insert("grpc-status", HeaderValue::from(status.code as u32));
+            let message =
+                HeaderValue::from_str(&percent_encode(&status.message)).expect("percent-encoded");
+            headers.insert("grpc-message", message);
+            respond.send_response(response, true).map(drop)
+        }
+    }
+}
+
+fn unary<M: Message + Default>(messages: &[Bytes]) -> Result<M, Status> {
+    match messages {
+        [message] => M::decode(message.clone()).map_err(Status::invalid_argument),
+        _ => Err(Status::invalid_argument(format!(
+            "expected one message, got {}",
+            messages.len()
+        ))),
+    }
+}
+
+/// Encodes `grpc-message` as the gRPC spec asks: bytes outside printable
+/// ASCII, and `%`, as `%XX`.
+fn percent_encode(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for b in s.bytes() {
+        if (b' '..=b'~').contains(&b) && b != b'%' {
+            out.push(b as char);
+        } else {
+            out.push_str(&format!("%{:02X}", b));
+        }
+    }
+    out
+}
+
+/// Calls `path` on the server at `address` with `messages`, returning the
+/// gRPC status and the encoded answer.
+#[cfg(test)]
+pub(crate) fn call_for_test(
+    address: std::net::SocketAddr,
+    path: &str,
+    messages: &[Vec<u8>],
+) -> (u32, Vec<u8>) {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    runtime.block_on(async {
+        let tcp = tokio::net::TcpStream::connect(address).await.unwrap();
+        let (client, connection) = h2::client::handshake(tcp).await.unwrap();
+        tokio::spawn(connection);
+        let mut client = client.ready().await.unwrap();
+        let request = Request::post(format!("http://{}{}", address, path))
+            .header("content-type", "application/grpc")
+            .header("te", "trailers")
+            .body(())
+            .unwrap();
+        let (response, mut stream) = client.send_request(request, false).unwrap();
+        let mut data = Vec::new();
+        for message in messages {
+            data.push(0);
+            data.extend_from_slice(&(message.len() as u32).to_be_bytes());
+            data.extend_from_slice(message);
+        }
+        stream.send_data(Bytes::from(data), true).unwrap();
+
+        let (parts, mut body) = response.await.unwrap().into_parts();
+        let mut answer = Vec::new();
+        while let Some(chunk) = body.data().await {
+            answer.extend_from_slice(&chunk.unwrap());
+        }
+        let trailers = body.trailers().await.unwrap();
+        let status = trailers.as_ref().unwrap_or(&parts.headers)["grpc-status"]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        (status, answer.get(5..).unwrap_or_default().to_vec())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grpc::{
+        ForwardRequest, ForwardResponse, ParseRequest, ParseResponse, ValidateRequest,
+        ValidateResponse,
+    };
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_serve() {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let sink_received = Arc::clone(&received);
+        let service = Service::new(
+            None,
+            Box::new(move |mfs| {
+                sink_received.lock().unwrap().extend_from_slice(mfs);
+                Ok(())
+            }),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        std::thread::spawn(move || Server::new().with_service(service).serve(listener));
+
+        let req = ParseRequest {
+            body: b"# TYPE up gauge\nup 1\n".to_vec(),
+            format: String::new(),
+        };
+        let (status, answer) = call_for_test(address, PARSE_PATH, &[req.encode_to_vec()]);
+        assert_eq!(status, 0);
+        assert_eq!(ParseResponse::decode(&*answer).unwrap().families.len(), 1);
+
+        let req = ValidateRequest {
+            body: b"a 1\n".to_vec(),
+            require_type: true,
+        };
+        let (status, answer) = call_for_test(address, VALIDATE_PATH, &[req.encode_to_vec()]);
+        assert_eq!(status, 0);
+        assert_eq!(
+            ValidateResponse::decode(&*answer).unwrap().findings.len(),
+            1
+        );
+
+        // A client stream of two messages.
+        let forward = |body: &str| {
+            ForwardRequest {
+                body: body.as_bytes().to_vec(),
+                format: String::new(),
+            }
+            .encode_to_vec()
+        };
+        let (status, answer) = call_for_test(
+            address,
+            FORWARD_PATH,
+            &[forward("up 1\n"), forward("other 2\n")],
+        );
+        assert_eq!(status, 0);
+        assert_eq!(
+            ForwardResponse::decode(&*answer).unwrap(),
+            ForwardResponse {
+                families: 2,
+                series: 2
+            }
+        );
+        assert_eq!(received.lock().unwrap().len(), 2);
+
+        let req = ParseRequest {
+            body: b"up{ 1\n".to_vec(),
+            format: String::new(),
+        };
+        let (status, _) = call_for_test(address, PARSE_PATH, &[req.encode_to_vec()]);
+        assert_eq!(status, 3);
+        let (status, _) = call_for_test(address, PARSE_PATH, &[]);
+        assert_eq!(status, 3);
+        let (status, _) = call_for_test(address, GRPC_EXPORT_PATH, &[]);
+        assert_eq!(status, 12);
+        assert_eq!(percent_encode("50% ✓"), "50%25 %E2%9C%93");
+    }
+}
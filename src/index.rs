@@ -0,0 +1,194 @@
+use prometheus::proto::{Metric, MetricFamily};
+use std::collections::HashMap;
+
+use crate::grep::{Matcher, Selector, NAME_LABEL};
+
+/// An inverted index from label name and value to the series of a snapshot,
+/// so selectors are answered from the label values instead of a scan over
+/// every series. Series are numbered in snapshot order.
+///
+/// The index describes the families it was built from; `select` must be
+/// given the same slice.
+#[derive(Debug, Clone, Default)]
+pub struct LabelIndex {
+    family_names: Vec<String>,
+    /// Number of the first series of every family, then the total.
+    family_starts: Vec<u32>,
+    /// Sorted series numbers by label name and value.
+    postings: HashMap<String, HashMap<String, Vec<u32>>>,
+}
+
+impl LabelIndex {
+    pub fn new(mfs: &[MetricFamily]) -> Self {
+        let mut index = LabelIndex::default();
+        let mut id = 0;
+        for mf in mfs {
+            index.family_names.push(mf.get_name().to_string());
+            index.family_starts.push(id);
+            for m in mf.get_metric() {
+                for l in m.get_label() {
+                    index
+                        .postings
+                        .entry(l.get_name().to_string())
+                        .or_default()
+                        .entry(l.get_value().to_string())
+                        .or_default()
+                        .push(id);
+                }
+                id += 1;
+            }
+        }
+        index.family_starts.push(id);
+        index
+    }
+
+    pub fn num_series(&self) -> usize {
+        self.family_starts.last().copied().unwrap_or(0) as usize
+    }
+
+    /// Same as `grep::select(mfs, selectors)`, with `mfs` the families the
+    /// index was built from.
+    pub fn select(&self, mfs: &[MetricFamily], selectors: &[Selector]) -> Vec<MetricFamily> {
+        debug_assert_eq!(mfs.len(), self.family_names.len());
+
+        let mut ids: Vec<u32> = selectors.iter().flat_map(|s| self.matching(s)).collect();
+        ids.sort_unstable();
+        ids.dedup();
+
+        let mut out: Vec<MetricFamily> = Vec::new();
+        let mut current: Option<(usize, Vec<Metric>)> = None;
+        for id in ids {
+            let family = self.family_starts.partition_point(|&start| start <= id) - 1;
+            let m = mfs[family].get_metric()[(id - self.family_starts[family]) as usize].clone();
+            match &mut current {
+                Some((f, metrics)) if *f == family => metrics.push(m),
+                _ => {
+                    out.extend(
+                        current
+                            .take()
+                            .map(|(f, metrics)| with_metrics(&mfs[f], metrics)),
+                    );
+                    current = Some((family, vec![m]));
+                }
+            }
+        }
+        out.extend(current.map(|(f, metrics)| with_metrics(&mfs[f], metrics)));
+        out
+    }
+
+    /// Sorted numbers of the series matching `selector`.
+    fn matching(&self, selector: &Selector) -> Vec<u32> {
+        let (names, labels): (Vec<&Matcher>, Vec<&Matcher>) =
+            selector.matchers.iter().partition(|m| m.name == NAME_LABEL);
+        let mut result: Vec<u32> = self
+            .family_names
+            .iter()
+            .enumerate()
+            .filter(|(_, family)| names.iter().all(|m| m.matches(family)))
+            .flat_map(|(i, _)| self.family_starts[i]..self.family_starts[i + 1])
+            .collect();
+        for pattern in labels {
+            if result.is_empty() {
+                break;
+            }
+            result = intersect(&result, &self.label_matching(pattern));
+        }
+        result
+    }
+
+    fn label_matching(&self, pattern: &Matcher) -> Vec<u32> {
+        let values = self.postings.get(&pattern.name);
+        let mut ids: Vec<u32> = values
+            .into_iter()
+            .flatten()
+            .filter(|(value, _)| pattern.matches(value))
+            .flat_map(|(_, ids)| ids.iter().copied())
+            .collect();
+
+        // A missing label has the empty value, as in PromQL.
+        if pattern.matches("") {
+            let mut labelled: Vec<u32> = values
+                .into_iter()
+                .flatten()
+                .flat_map(|(_, ids)| ids.iter().copied())
+                .collect();
+            labelled.sort_unstable();
+            ids.extend(
+                (0..self.num_series() as u32).filter(|id| labelled.binary_search(id).is_err()),
+            );
+        }
+
+        ids.sort_unstable();
+        ids.dedup();
+        ids
+    }
+}
+
+fn with_metrics(mf: &MetricFamily, metrics: Vec<Metric>) -> MetricFamily {
+    let mut mf = mf.clone();
+    mf.set_metric(metrics.into());
+    mf
+}
+
+fn intersect(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut out = Vec::with_capacity(a.len().min(b.len()));
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        match a[i].cmp(&b[j]) {
+            std::cmp::Ordering::Less => i += 1,
+            std::cmp::Ordering::Greater => j += 1,
+            std::cmp::Ordering::Equal => {
+                out.push(a[i]);
+                i += 1;
+                j += 1;
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::{read_metric_families, Format};
+    use crate::grep::select;
+
+    #[test]
+    fn test_index_matches_scan() {
+        let mfs = read_metric_families(
+            Format::Text,
+            r#"# TYPE http_requests_total counter
+http_requests_total{code="200",job="api"} 1
+http_requests_total{code="500",job="api"} 2
+http_requests_total{code="200",job="web"} 3
+# TYPE up gauge
+up{job="api"} 1
+up{job="web",zone=""} 0
+up{job="db",zone="eu"} 1
+"#
+            .as_bytes(),
+        )
+        .unwrap();
+        let index = LabelIndex::new(&mfs);
+        assert_eq!(index.num_series(), 6);
+
+        for selectors in [
+            vec![r#"{job="api"}"#],
+            vec![r#"{__name__=~"http_.*",code=~"2.."}"#, r#"up{job="db"}"#],
+            vec![r#"up{zone=""}"#],
+            vec![r#"{zone=~"eu|"}"#],
+            vec![r#"{job!="api"}"#, r#"up{zone!~"e.*"}"#],
+            vec![r#"{__name__!="up",code!="500"}"#],
+            vec![r#"{job="none"}"#],
+            vec!["up", r#"{__name__="up",job="api"}"#],
+        ] {
+            let selectors: Vec<Selector> = selectors.iter().map(|s| s.parse().unwrap()).collect();
+            assert_eq!(
+                index.select(&mfs, &selectors),
+                select(&mfs, &selectors),
+                "{:?}",
+                selectors
+            );
+        }
+    }
+}
@@ -0,0 +1,359 @@
+use prometheus::proto::{LabelPair, Metric, MetricFamily, MetricType, Untyped};
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fmt;
+
+/// How InfluxDB measurements and fields are turned into metric names.
+///
+/// A field is named `<prefix><separator><field>`, where the prefix is the
+/// mapped measurement name or the measurement itself. A field called `value`
+/// maps to just the prefix.
+#[derive(Debug, Clone)]
+pub struct InfluxMapping {
+    pub measurements: HashMap<String, String>,
+    pub separator: String,
+}
+
+impl Default for InfluxMapping {
+    fn default() -> Self {
+        InfluxMapping {
+            measurements: HashMap::new(),
+            separator: "_".to_string(),
+        }
+    }
+}
+
+impl InfluxMapping {
+    fn metric_name(&self, measurement: &str, field: &str) -> String {
+        let prefix = self
+            .measurements
+            .get(measurement)
+            .map(String::as_str)
+            .unwrap_or(measurement);
+
+        let name = if field == "value" {
+            prefix.to_string()
+        } else {
+            format!("{}{}{}", prefix, self.separator, field)
+        };
+        sanitize_name(&name)
+    }
+}
+
+/// Timestamp precision of a write request, as given by the `precision` query
+/// parameter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Precision {
+    Nanoseconds,
+    Microseconds,
+    Milliseconds,
+    Seconds,
+}
+
+impl Precision {
+    pub fn from_param(s: &str) -> Option<Self> {
+        match s {
+            "ns" | "n" => Some(Precision::Nanoseconds),
+            "us" | "u" => Some(Precision::Microseconds),
+            "ms" => Some(Precision::Milliseconds),
+            "s" => Some(Precision::Seconds),
+            _ => None,
+        }
+    }
+
+    fn to_millis(self, ts: i64) -> i64 {
+        match self {
+            Precision::Nanoseconds => ts / 1_000_000,
+            Precision::Microseconds => ts / 1_000,
+            Precision::Milliseconds => ts,
+            Precision::Seconds => ts.saturating_mul(1_000),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct InfluxError {
+    pub line: usize,
+    pub msg: String,
+}
+
+impl fmt::Display for InfluxError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line protocol error in line {}: {}", self.line, self.msg)
+    }
+}
+
+impl Error for InfluxError {}
+
+/// One line of line protocol with its numeric fields.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Point {
+    pub measurement: String,
+    pub tags: Vec<(String, String)>,
+    pub fields: Vec<(String, f64)>,
+    pub timestamp: Option<i64>,
+}
+
+/// Parses InfluxDB line protocol. String fields are dropped, booleans become
+/// 1 or 0, and integer fields (`5i`, `5u`) become floats.
+pub fn parse_lines(body: &str) -> Result<Vec<Point>, InfluxError> {
+    let mut points = Vec::new();
+
+    for (idx, line) in body.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let point = parse_line(line).map_err(|msg| InfluxError { line: idx + 1, msg })?;
+        points.push(point);
+    }
+
+    Ok(points)
+}
+
+fn parse_line(line: &str) -> Result<Point, String> {
+    let sections = split_unescaped(line, ' ', true);
+    if sections.len() < 2 || sections.len() > 3 {
+        return Err(format!(
+            "expected 2 or 3 sections, found {}",
+            sections.len()
+        ));
+    }
+
+    let mut series = split_unescaped(sections[0], ',', false).into_iter();
+    let measurement = unescape(series.next().unwrap_or(""));
+    if measurement.is_empty() {
+        return Err("missing measurement".to_string());
+    }
+
+    let mut tags = Vec::new();
+    for tag in series {
+        let (key, value) = split_pair(tag)?;
+        tags.push((unescape(key), unescape(value)));
+    }
+
+    let mut fields = Vec::new();
+    for field in split_unescaped(sections[1], ',', true) {
+        let (key, value) = split_pair(field)?;
+        if let Some(v) = parse_field_value(value)? {
+            fields.push((unescape(key), v));
+        }
+    }
+
+    let timestamp = match sections.get(2) {
+        Some(ts) => Some(
+            ts.parse::<i64>()
+                .map_err(|_| format!("invalid timestamp {:?}", ts))?,
+        ),
+        None => None,
+    };
+
+    Ok(Point {
+        measurement,
+        tags,
+        fields,
+        timestamp,
+    })
+}
+
+fn parse_field_value(value: &str) -> Result<Option<f64>, String> {
+    if value.starts_with('"') {
+        return Ok(None);
+    }
+
+    match value {
+        "t" | "T" | "true" | "True" | "TRUE" => return Ok(Some(1.0)),
+        "f" | "F" | "false" | "False" | "FALSE" => return Ok(Some(0.0)),
+        _ => {}
+    }
+
+    let number = value
+        .strip_suffix('i')
+        .or_else(|| value.strip_suffix('u'))
+        .unwrap_or(value);
+    number
+        .parse::<f64>()
+        .map(Some)
+        .map_err(|_| format!("invalid field value {:?}", value))
+}
+
+fn split_pair(s: &str) -> Result<(&str, &str), String> {
+    let parts = split_unescaped(s, '=', false);
+    match parts.as_slice() {
+        [key, _, ..] if !key.is_empty() => Ok((key, &s[key.len() + 1..])),
+        _ => Err(format!("expected key=value, got {:?}", s)),
+    }
+}
+
+/// Splits on `sep` unless it is backslash-escaped or, with `quotes`, inside a
+/// double-quoted string.
+fn split_unescaped(s: &str, sep: char, quotes: bool) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut escaped = false;
+    let mut in_quotes = false;
+
+    for (i, c) in s.char_indices() {
+        if escaped {
+            escaped = false;
+            continue;
+        }
+        match c {
+            '\\' => escaped = true,
+            '"' if quotes => in_quotes = !in_quotes,
+            c if c == sep && !in_quotes => {
+                parts.push(&s[start..i]);
+                start = i + c.len_utf8();
+            }
+            _ => {}
+        }
+    }
+    parts.push(&s[start..]);
+    parts
+}
+
+fn unescape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some(next @ (',' | ' ' | '=' | '\\' | '"')) => out.push(next),
+                Some(next) => {
+                    out.push('\\');
+                    out.push(next);
+                }
+                None => out.push('\\'),
+            },
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+fn sanitize_name(name: &str) -> String {
+    let mut out = String::with_capacity(name.len());
+    for (i, c) in name.chars().enumerate() {
+        let valid =
+            c.is_ascii_alphabetic() || c == '_' || c == ':' || (i > 0 && c.is_ascii_digit());
+        out.push(if valid { c } else { '_' });
+    }
+    out
+}
+
+/// Converts points into untyped metric families, one per measurement field.
+/// Tags become labels; later points for the same series win.
+pub fn points_to_metric_families(
+    points: &[Point],
+    mapping: &InfluxMapping,
+    precision: Precision,
+) -> Vec<MetricFamily> {
+    let mut mf_by_name: BTreeMap<String, BTreeMap<Vec<(String, String)>, Metric>> = BTreeMap::new();
+
+    for point in points {
+        let mut labels: Vec<(String, String)> = point
+            .tags
+            .iter()
+            .map(|(k, v)| (sanitize_name(k).replace(':', "_"), v.clone()))
+            .collect();
+        labels.sort();
+
+        for (field, value) in &point.fields {
+            let name = mapping.metric_name(&point.measurement, field);
+
+            let mut m = Metric::new();
+            for (k, v) in &labels {
+                let mut label = LabelPair::new();
+                label.set_name(k.clone());
+                label.set_value(v.clone());
+                m.mut_label().push(label);
+            }
+            let mut untyped = Untyped::new();
+            untyped.set_value(*value);
+            m.set_untyped(untyped);
+            if let Some(ts) = point.timestamp {
+                m.set_timestamp_ms(precision.to_millis(ts));
+            }
+
+            mf_by_name
+                .entry(name)
+                .or_default()
+                .insert(labels.clone(), m);
+        }
+    }
+
+    mf_by_name
+        .into_iter()
+        .map(|(name, metrics)| {
+            let mut mf = MetricFamily::new();
+            mf.set_name(name);
+            mf.set_field_type(MetricType::UNTYPED);
+            for m in metrics.into_values() {
+                mf.mut_metric().push(m);
+            }
+            mf
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_lines() {
+        let points = parse_lines(
+            "# comment\n\
+             weather,location=us\\,midwest,season=summer temperature=82,humidity=71i,ok=t,note=\"a, b\" 1465839830100400200\n\
+             cpu\\ load value=0.5\n",
+        )
+        .unwrap();
+
+        assert_eq!(points.len(), 2);
+        assert_eq!(points[0].measurement, "weather");
+        assert_eq!(
+            points[0].tags[0],
+            ("location".to_string(), "us,midwest".to_string())
+        );
+        assert_eq!(
+            points[0].fields,
+            vec![
+                ("temperature".to_string(), 82.0),
+                ("humidity".to_string(), 71.0),
+                ("ok".to_string(), 1.0),
+            ]
+        );
+        assert_eq!(points[0].timestamp, Some(1465839830100400200));
+        assert_eq!(points[1].measurement, "cpu load");
+
+        let err = parse_lines("ok value=1\nbroken\n").unwrap_err();
+        assert_eq!(err.line, 2);
+    }
+
+    #[test]
+    fn test_points_to_metric_families() {
+        let points = parse_lines(
+            "weather,location=us temperature=82 1465839830100400200\n\
+             weather,location=us temperature=83 1465839830200400200\n\
+             cpu\\ load value=0.5\n",
+        )
+        .unwrap();
+
+        let mut mapping = InfluxMapping::default();
+        mapping
+            .measurements
+            .insert("weather".to_string(), "iot_weather".to_string());
+
+        let mfs = points_to_metric_families(&points, &mapping, Precision::Nanoseconds);
+        assert_eq!(mfs.len(), 2);
+        assert_eq!(mfs[0].get_name(), "cpu_load");
+        assert_eq!(mfs[1].get_name(), "iot_weather_temperature");
+
+        let m = &mfs[1].get_metric()[0];
+        assert_eq!(mfs[1].get_metric().len(), 1);
+        assert_eq!(m.get_untyped().get_value(), 83.0);
+        assert_eq!(m.get_timestamp_ms(), 1465839830200);
+        assert_eq!(m.get_label()[0].get_value(), "us");
+    }
+}
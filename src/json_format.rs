@@ -0,0 +1,335 @@
+use prometheus::proto::{
+    Bucket, Counter, Gauge, LabelPair, Metric, MetricFamily, MetricType, Quantile, Summary, Untyped,
+};
+use serde_json::{json, Map, Value};
+use std::error::Error;
+use std::fmt;
+use std::io::{Read, Write};
+
+use crate::text_encode::format_float;
+
+/// Errors from reading the JSON format.
+#[derive(Debug)]
+pub enum JsonError {
+    Syntax(serde_json::Error),
+    Invalid(String),
+}
+
+impl fmt::Display for JsonError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            JsonError::Syntax(e) => write!(f, "invalid JSON: {}", e),
+            JsonError::Invalid(msg) => write!(f, "invalid metrics JSON: {}", msg),
+        }
+    }
+}
+
+impl Error for JsonError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            JsonError::Syntax(e) => Some(e),
+            JsonError::Invalid(_) => None,
+        }
+    }
+}
+
+/// Converts metric families into the JSON layout used by `prom2json`: an array
+/// of families whose sample values are strings, so `NaN` and `+Inf` survive.
+pub fn metric_families_to_json(mfs: &[MetricFamily]) -> Value {
+    Value::Array(mfs.iter().map(family_to_json).collect())
+}
+
+pub fn write_json<W: Write>(writer: &mut W, mfs: &[MetricFamily]) -> serde_json::Result<()> {
+    serde_json::to_writer_pretty(&mut *writer, &metric_families_to_json(mfs))?;
+    writer.write_all(b"\n").map_err(serde_json::Error::io)
+}
+
+pub fn read_json<R: Read>(reader: R) -> Result<Vec<MetricFamily>, JsonError> {
+    let value: Value = serde_json::from_reader(reader).map_err(JsonError::Syntax)?;
+    json_to_metric_families(&value)
+}
+
+fn family_to_json(mf: &MetricFamily) -> Value {
+    let metrics: Vec<Value> = mf
+        .get_metric()
+        .iter()
+        .map(|m| metric_to_json(mf.get_field_type(), m))
+        .collect();
+
+    json!({
+        "name": mf.get_name(),
+        "help": mf.get_help(),
+        "type": format!("{:?}", mf.get_field_type()),
+        "metrics": metrics,
+    })
+}
+
+fn metric_to_json(metric_type: MetricType, m: &Metric) -> Value {
+    let mut obj = Map::new();
+
+    if !m.get_label().is_empty() {
+        let labels: Map<String, Value> = m
+            .get_label()
+            .iter()
+            .map(|l| (l.get_name().to_string(), json!(l.get_value())))
+            .collect();
+        obj.insert("labels".to_string(), Value::Object(labels));
+    }
+
+    if m.has_timestamp_ms() {
+        obj.insert(
+            "timestamp_ms".to_string(),
+            json!(m.get_timestamp_ms().to_string()),
+        );
+    }
+
+    match metric_type {
+        MetricType::COUNTER => {
+            obj.insert("value".to_string(), float(m.get_counter().get_value()));
+        }
+        MetricType::GAUGE => {
+            obj.insert("value".to_string(), float(m.get_gauge().get_value()));
+        }
+        MetricType::UNTYPED => {
+            obj.insert("value".to_string(), float(m.get_untyped().get_value()));
+        }
+        MetricType::SUMMARY => {
+            let s = m.get_summary();
+            let quantiles: Map<String, Value> = s
+                .get_quantile()
+                .iter()
+                .map(|q| (format_float(q.get_quantile()), float(q.get_value())))
+                .collect();
+            obj.insert("quantiles".to_string(), Value::Object(quantiles));
+            obj.insert("count".to_string(), json!(s.get_sample_count().to_string()));
+            obj.insert("sum".to_string(), float(s.get_sample_sum()));
+        }
+        MetricType::HISTOGRAM => {
+            let h = m.get_histogram();
+            let buckets: Map<String, Value> = h
+                .get_bucket()
+                .iter()
+                .map(|b| {
+                    (
+                        format_float(b.get_upper_bound()),
+                        json!(b.get_cumulative_count().to_string()),
+                    )
+                })
+                .collect();
+            obj.insert("buckets".to_string(), Value::Object(buckets));
+            obj.insert("count".to_string(), json!(h.get_sample_count().to_string()));
+            obj.insert("sum".to_string(), float(h.get_sample_sum()));
+        }
+    }
+
+    Value::Object(obj)
+}
+
+fn float(v: f64) -> Value {
+    json!(format_float(v))
+}
+
+/// The inverse of `metric_families_to_json`.
+pub fn json_to_metric_families(value: &Value) -> Result<Vec<MetricFamily>, JsonError> {
+    let families = value
+        .as_array()
+        .ok_or_else(|| invalid("expected an array of metric families"))?;
+
+    families.iter().map(json_to_family).collect()
+}
+
+fn json_to_family(value: &Value) -> Result<MetricFamily, JsonError> {
+    let name = get_str(value, "name")?;
+
+    let metric_type = match get_str(value, "type")? {
+        "COUNTER" => MetricType::COUNTER,
+        "GAUGE" => MetricType::GAUGE,
+        "SUMMARY" => MetricType::SUMMARY,
+        "UNTYPED" => MetricType::UNTYPED,
+        "HISTOGRAM" => MetricType::HISTOGRAM,
+        other => return Err(invalid(&format!("unknown metric type {:?}", other))),
+    };
+
+    let mut mf = MetricFamily::new();
+    mf.set_name(name.to_string());
+    mf.set_field_type(metric_type);
+    if let Some(help) = value.get("help").and_then(Value::as_str) {
+        if !help.is_empty() {
+            mf.set_help(help.to_string());
+        }
+    }
+
+    let metrics = value
+        .get("metrics")
+        .and_then(Value::as_array)
+        .ok_or_else(|| invalid(&format!("family {:?} has no metrics array", name)))?;
+    for m in metrics {
+        mf.mut_metric().push(json_to_metric(metric_type, m)?);
+    }
+
+    Ok(mf)
+}
+
+fn json_to_metric(metric_type: MetricType, value: &Value) -> Result<Metric, JsonError> {
+    let mut m = Metric::new();
+
+    if let Some(labels) = value.get("labels").and_then(Value::as_object) {
+        for (name, v) in labels {
+            let mut label = LabelPair::new();
+            label.set_name(name.clone());
+            label.set_value(
+                v.as_str()
+                    .ok_or_else(|| invalid(&format!("label {:?} is not a string", name)))?
+                    .to_string(),
+            );
+            m.mut_label().push(label);
+        }
+    }
+
+    if value.get("timestamp_ms").is_some() {
+        let ts = get_str(value, "timestamp_ms")?;
+        m.set_timestamp_ms(
+            ts.parse()
+                .map_err(|_| invalid(&format!("invalid timestamp {:?}", ts)))?,
+        );
+    }
+
+    match metric_type {
+        MetricType::COUNTER => {
+            let mut c = Counter::new();
+            c.set_value(get_float(value, "value")?);
+            m.set_counter(c);
+        }
+        MetricType::GAUGE => {
+            let mut g = Gauge::new();
+            g.set_value(get_float(value, "value")?);
+            m.set_gauge(g);
+        }
+        MetricType::UNTYPED => {
+            let mut u = Untyped::new();
+            u.set_value(get_float(value, "value")?);
+            m.set_untyped(u);
+        }
+        MetricType::SUMMARY => {
+            let mut s = Summary::new();
+            for (quantile, v) in get_object(value, "quantiles")? {
+                let mut q = Quantile::new();
+                q.set_quantile(parse_float(quantile)?);
+                q.set_value(parse_float(as_str(v)?)?);
+                s.mut_quantile().push(q);
+            }
+            s.set_sample_count(get_count(value, "count")?);
+            s.set_sample_sum(get_float(value, "sum")?);
+            m.set_summary(s);
+        }
+        MetricType::HISTOGRAM => {
+            let h = m.mut_histogram();
+            for (upper_bound, count) in get_object(value, "buckets")? {
+                let mut b = Bucket::new();
+                b.set_upper_bound(parse_float(upper_bound)?);
+                b.set_cumulative_count(parse_count(as_str(count)?)?);
+                h.mut_bucket().push(b);
+            }
+            h.set_sample_count(get_count(value, "count")?);
+            h.set_sample_sum(get_float(value, "sum")?);
+        }
+    }
+
+    Ok(m)
+}
+
+fn invalid(msg: &str) -> JsonError {
+    JsonError::Invalid(msg.to_string())
+}
+
+fn as_str(value: &Value) -> Result<&str, JsonError> {
+    value
+        .as_str()
+        .ok_or_else(|| invalid(&format!("expected a string, got {}", value)))
+}
+
+fn get_str<'a>(value: &'a Value, key: &str) -> Result<&'a str, JsonError> {
+    value
+        .get(key)
+        .ok_or_else(|| invalid(&format!("missing {:?}", key)))
+        .and_then(as_str)
+}
+
+fn get_object<'a>(value: &'a Value, key: &str) -> Result<&'a Map<String, Value>, JsonError> {
+    value
+        .get(key)
+        .and_then(Value::as_object)
+        .ok_or_else(|| invalid(&format!("missing {:?} object", key)))
+}
+
+fn get_float(value: &Value, key: &str) -> Result<f64, JsonError> {
+    parse_float(get_str(value, key)?)
+}
+
+fn get_count(value: &Value, key: &str) -> Result<u64, JsonError> {
+    parse_count(get_str(value, key)?)
+}
+
+fn parse_float(s: &str) -> Result<f64, JsonError> {
+    match s {
+        "+Inf" => Ok(f64::INFINITY),
+        "-Inf" => Ok(f64::NEG_INFINITY),
+        "NaN" => Ok(f64::NAN),
+        _ => s
+            .parse()
+            .map_err(|_| invalid(&format!("invalid float {:?}", s))),
+    }
+}
+
+fn parse_count(s: &str) -> Result<u64, JsonError> {
+    s.parse()
+        .map_err(|_| invalid(&format!("invalid count {:?}", s)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::text_parse::TextParser;
+    use std::io::{BufReader, Cursor};
+
+    #[test]
+    fn test_json_round_trip() {
+        let cursor = Cursor::new(
+            br#"# HELP h Latency.
+# TYPE h histogram
+h_bucket{path="/",le="0.5"} 1
+h_bucket{path="/",le="+Inf"} 3
+h_sum{path="/"} 2
+h_count{path="/"} 3
+# TYPE s summary
+s{quantile="0.9"} NaN 1000
+s_sum 1
+s_count 2
+g{b="2",a="1"} -Inf
+"#
+            .to_vec(),
+        );
+        let mut mfs: Vec<_> = TextParser::new(BufReader::new(cursor))
+            .text_to_metric_families()
+            .unwrap()
+            .into_values()
+            .collect();
+        mfs.sort_by(|a, b| a.get_name().cmp(b.get_name()));
+
+        let mut out = Vec::new();
+        write_json(&mut out, &mfs).unwrap();
+
+        let value: Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(value[1]["metrics"][0]["buckets"]["+Inf"], "3");
+        assert_eq!(
+            value[0]["metrics"][0]["labels"],
+            json!({"b": "2", "a": "1"})
+        );
+
+        let decoded = read_json(out.as_slice()).unwrap();
+        // NaN never compares equal, so compare the rendered forms.
+        assert_eq!(metric_families_to_json(&decoded), value);
+
+        assert!(read_json(&b"{}"[..]).is_err());
+    }
+}
@@ -0,0 +1,607 @@
+//! Scrape targets from the Kubernetes API, for running pmv inside a
+//! cluster as a small scrape agent. Built with the `k8s` feature.
+//!
+//! Pods and services opt in with the annotations of the usual Prometheus
+//! configurations:
+//!
+//! ```yaml
+//! metadata:
+//!   annotations:
+//!     prometheus.io/scrape: "true"
+//!     prometheus.io/port: "9100"      # default: every declared TCP port
+//!     prometheus.io/path: /metrics    # the default
+//!     prometheus.io/scheme: http      # or https
+//! ```
+//!
+//! In the [`Role::Pods`] role the annotations of pods count; in the
+//! [`Role::Endpoints`] role, those of services, whose endpoints are
+//! scraped. Objects are listed again every refresh interval.
+
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::retry::random_fraction;
+use crate::scrape::Target;
+
+const SERVICE_ACCOUNT: &str = "/var/run/secrets/kubernetes.io/serviceaccount";
+
+/// The objects whose addresses are scraped.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Role {
+    /// Pods, by their IP.
+    #[default]
+    Pods,
+    /// The addresses of the endpoints of services.
+    Endpoints,
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pod" | "pods" => Ok(Role::Pods),
+            "endpoints" => Ok(Role::Endpoints),
+            _ => Err(format!("unknown role {:?}, expected pods or endpoints", s)),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum K8sError {
+    /// The API server or its credentials are not configured.
+    Config(String),
+    /// A token or certificate file could not be read.
+    Io(PathBuf, io::Error),
+    /// The request could not be sent, or the API server refused it.
+    Request(String, Box<ureq::Error>),
+    /// The API server's answer is not a list of the objects asked for.
+    Response(String, serde_json::Error),
+}
+
+impl fmt::Display for K8sError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            K8sError::Config(msg) => write!(f, "invalid Kubernetes configuration: {}", msg),
+            K8sError::Io(path, e) => write!(f, "{}: {}", path.display(), e),
+            K8sError::Request(url, e) => write!(f, "listing {} failed: {}", url, e),
+            K8sError::Response(url, e) => write!(f, "invalid list from {}: {}", url, e),
+        }
+    }
+}
+
+impl Error for K8sError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            K8sError::Config(_) => None,
+            K8sError::Io(_, e) => Some(e),
+            K8sError::Request(_, e) => Some(e),
+            K8sError::Response(_, e) => Some(e),
+        }
+    }
+}
+
+/// Where the API server is and how to log in.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ApiConfig {
+    /// Base URL, e.g. `https://10.0.0.1:443`, or `http://127.0.0.1:8001`
+    /// for `kubectl proxy`.
+    pub url: String,
+    /// File holding a bearer token, read at every request since service
+    /// account tokens are rotated.
+    pub token_file: Option<PathBuf>,
+    /// PEM file of the CA certificates the API server's is checked against,
+    /// instead of the public roots.
+    pub ca_file: Option<PathBuf>,
+}
+
+impl ApiConfig {
+    /// The API server and service account of the pod pmv runs in.
+    pub fn in_cluster() -> Result<Self, K8sError> {
+        let host = std::env::var("KUBERNETES_SERVICE_HOST").map_err(|_| {
+            K8sError::Config("KUBERNETES_SERVICE_HOST is not set; not running in a cluster?".into())
+        })?;
+        let port = std::env::var("KUBERNETES_SERVICE_PORT").unwrap_or_else(|_| "443".into());
+        let host = if host.contains(':') {
+            format!("[{}]", host)
+        } else {
+            host
+        };
+        let account = Path::new(SERVICE_ACCOUNT);
+        Ok(ApiConfig {
+            url: format!("https://{}:{}", host, port),
+            token_file: Some(account.join("token")),
+            ca_file: Some(account.join("ca.crt")),
+        })
+    }
+
+    /// The namespace of the pod pmv runs in.
+    pub fn in_cluster_namespace() -> Result<String, K8sError> {
+        let path = Path::new(SERVICE_ACCOUNT).join("namespace");
+        let namespace = fs::read_to_string(&path).map_err(|e| K8sError::Io(path, e))?;
+        Ok(namespace.trim().to_string())
+    }
+}
+
+/// A client of the few API endpoints discovery needs.
+struct Client {
+    config: ApiConfig,
+    agent: ureq::Agent,
+}
+
+impl Client {
+    fn new(config: ApiConfig) -> Result<Self, K8sError> {
+        let mut agent = ureq::AgentBuilder::new().timeout(Duration::from_secs(30));
+        if let Some(path) = &config.ca_file {
+            agent = agent.tls_config(Arc::new(tls_config(path)?));
+        }
+        Ok(Client {
+            config,
+            agent: agent.build(),
+        })
+    }
+
+    /// The items of the list at `path`, e.g. `/api/v1/pods`.
+    fn list<T: DeserializeOwned>(&self, path: &str) -> Result<Vec<T>, K8sError> {
+        #[derive(Deserialize)]
+        struct List<T> {
+            items: Vec<T>,
+        }
+
+        let url = format!("{}{}", self.config.url.trim_end_matches('/'), path);
+        let mut request = self.agent.get(&url).set("Accept", "application/json");
+        if let Some(path) = &self.config.token_file {
+            let token = fs::read_to_string(path).map_err(|e| K8sError::Io(path.clone(), e))?;
+            request = request.set("Authorization", &format!("Bearer {}", token.trim()));
+        }
+        let response = request
+            .call()
+            .map_err(|e| K8sError::Request(url.clone(), Box::new(e)))?;
+        let list: List<T> = serde_json::from_reader(response.into_reader())
+            .map_err(|e| K8sError::Response(url, e))?;
+        Ok(list.items)
+    }
+}
+
+fn tls_config(ca_file: &Path) -> Result<rustls::ClientConfig, K8sError> {
+    let pem = fs::read(ca_file).map_err(|e| K8sError::Io(ca_file.to_path_buf(), e))?;
+    let mut roots = rustls::RootCertStore::empty();
+    for cert in rustls_pemfile::certs(&mut pem.as_slice()) {
+        let cert = cert.map_err(|e| K8sError::Io(ca_file.to_path_buf(), e))?;
+        roots.add(cert).map_err(|e| {
+            K8sError::Config(format!("{}: invalid certificate: {}", ca_file.display(), e))
+        })?;
+    }
+    if roots.is_empty() {
+        let msg = format!("{}: no certificates", ca_file.display());
+        return Err(K8sError::Config(msg));
+    }
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    Ok(rustls::ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(|e| K8sError::Config(e.to_string()))?
+        .with_root_certificates(roots)
+        .with_no_client_auth())
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Metadata {
+    name: String,
+    namespace: String,
+    annotations: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Pod {
+    metadata: Metadata,
+    #[serde(default)]
+    spec: PodSpec,
+    #[serde(default)]
+    status: PodStatus,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct PodSpec {
+    containers: Vec<Container>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Container {
+    ports: Vec<Port>,
+}
+
+/// A container port or an endpoint port.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Port {
+    #[serde(alias = "port")]
+    container_port: u16,
+    protocol: Option<String>,
+}
+
+impl Port {
+    fn is_tcp(&self) -> bool {
+        self.protocol.as_deref().is_none_or(|p| p == "TCP")
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct PodStatus {
+    phase: String,
+    #[serde(rename = "podIP")]
+    pod_ip: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Service {
+    metadata: Metadata,
+}
+
+#[derive(Debug, Deserialize)]
+struct Endpoints {
+    metadata: Metadata,
+    #[serde(default)]
+    subsets: Vec<Subset>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Subset {
+    addresses: Vec<Address>,
+    ports: Vec<Port>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Address {
+    ip: String,
+    target_ref: Option<ObjectRef>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ObjectRef {
+    kind: String,
+    name: String,
+}
+
+/// What the `prometheus.io/*` annotations ask for.
+struct Annotations {
+    scheme: String,
+    port: Option<u16>,
+    path: String,
+}
+
+impl Annotations {
+    /// None unless `prometheus.io/scrape` is `"true"`.
+    fn parse(object: &Metadata) -> Option<Self> {
+        let get = |key: &str| object.annotations.get(&format!("prometheus.io/{}", key));
+        if get("scrape").is_none_or(|v| v != "true") {
+            return None;
+        }
+        let scheme = get("scheme").map_or("http", |s| s.as_str());
+        if scheme != "http" && scheme != "https" {
+            log::warn!(
+                "{}/{}: unsupported scheme {:?}",
+                object.namespace,
+                object.name,
+                scheme
+            );
+            return None;
+        }
+        let port = match get("port").map(|p| p.parse()) {
+            Some(Ok(port)) => Some(port),
+            Some(Err(_)) => {
+                log::warn!(
+                    "{}/{}: invalid port annotation",
+                    object.namespace,
+                    object.name
+                );
+                return None;
+            }
+            None => None,
+        };
+        Some(Annotations {
+            scheme: scheme.to_string(),
+            port,
+            path: get("path").map_or("/metrics", |p| p.as_str()).to_string(),
+        })
+    }
+
+    /// The annotated port, or else every TCP port given.
+    fn ports<'a>(&self, ports: impl Iterator<Item = &'a Port>) -> Vec<u16> {
+        match self.port {
+            Some(port) => vec![port],
+            None => ports
+                .filter(|p| p.is_tcp())
+                .map(|p| p.container_port)
+                .collect(),
+        }
+    }
+
+    fn target(&self, ip: &str, port: u16, job: &str, labels: Vec<(String, String)>) -> Target {
+        let host = if ip.contains(':') {
+            format!("[{}]", ip)
+        } else {
+            ip.to_string()
+        };
+        Target {
+            url: format!("{}://{}:{}{}", self.scheme, host, port, self.path),
+            job: job.to_string(),
+            labels,
+        }
+    }
+}
+
+fn pod_targets(pods: &[Pod], job: &str) -> Vec<Target> {
+    let mut targets = Vec::new();
+    for pod in pods {
+        let Some(annotations) = Annotations::parse(&pod.metadata) else {
+            continue;
+        };
+        let Some(ip) = pod
+            .status
+            .pod_ip
+            .as_deref()
+            .filter(|_| pod.status.phase == "Running")
+        else {
+            continue;
+        };
+        let ports = pod.spec.containers.iter().flat_map(|c| &c.ports);
+        for port in annotations.ports(ports) {
+            let labels = vec![
+                ("namespace".to_string(), pod.metadata.namespace.clone()),
+                ("pod".to_string(), pod.metadata.name.clone()),
+            ];
+            targets.push(annotations.target(ip, port, job, labels));
+        }
+    }
+    targets
+}
+
+fn endpoints_targets(services: &[Service], endpoints: &[Endpoints], job: &str) -> Vec<Target> {
+    let mut targets = Vec::new();
+    for service in services {
+        let Some(annotations) = Annotations::parse(&service.metadata) else {
+            continue;
+        };
+        let (namespace, name) = (&service.metadata.namespace, &service.metadata.name);
+        let subsets = endpoints
+            .iter()
+            .filter(|e| e.metadata.namespace == *namespace && e.metadata.name == *name)
+            .flat_map(|e| &e.subsets);
+        for subset in subsets {
+            for port in annotations.ports(subset.ports.iter()) {
+                for address in &subset.addresses {
+                    let mut labels = vec![
+                        ("namespace".to_string(), namespace.clone()),
+                        ("service".to_string(), name.clone()),
+                    ];
+                    if let Some(pod) = address.target_ref.as_ref().filter(|r| r.kind == "Pod") {
+                        labels.push(("pod".to_string(), pod.name.clone()));
+                    }
+                    targets.push(annotations.target(&address.ip, port, job, labels));
+                }
+            }
+        }
+    }
+    targets
+}
+
+/// Targets from the Kubernetes API, listed again every refresh interval.
+pub struct K8sSd {
+    client: Client,
+    role: Role,
+    namespaces: Vec<String>,
+    job: String,
+    refresh: Duration,
+    next: Option<Instant>,
+    targets: Option<Vec<Target>>,
+}
+
+impl K8sSd {
+    /// Targets of `role` in `namespaces`, or in all namespaces if empty,
+    /// under `job`.
+    pub fn new(
+        config: ApiConfig,
+        role: Role,
+        namespaces: Vec<String>,
+        job: impl Into<String>,
+    ) -> Result<Self, K8sError> {
+        Ok(K8sSd {
+            client: Client::new(config)?,
+            role,
+            namespaces,
+            job: job.into(),
+            refresh: Duration::from_secs(30),
+            next: None,
+            targets: None,
+        })
+    }
+
+    /// How often objects are listed again; 30s by default.
+    pub fn refresh_interval(mut self, refresh: Duration) -> Self {
+        self.refresh = refresh;
+        self
+    }
+
+    /// Lists the objects if the refresh interval passed since the last
+    /// listing, which the first call always makes, and returns the targets
+    /// if they changed. After an error the caller should keep the targets
+    /// it has; the next call tries again.
+    pub fn poll(&mut self) -> Result<Option<Vec<Target>>, K8sError> {
+        let now = Instant::now();
+        if self.next.is_some_and(|next| now < next) {
+            return Ok(None);
+        }
+        self.next = Some(now + self.refresh.mul_f64(0.9 + random_fraction() * 0.2));
+
+        let mut targets = match self.role {
+            Role::Pods => pod_targets(&self.list("pods")?, &self.job),
+            Role::Endpoints => {
+                let services: Vec<Service> = self.list("services")?;
+                endpoints_targets(&services, &self.list("endpoints")?, &self.job)
+            }
+        };
+        targets.sort_by(|a, b| a.url.cmp(&b.url));
+        if self.targets.as_ref() == Some(&targets) {
+            return Ok(None);
+        }
+        self.targets = Some(targets.clone());
+        Ok(Some(targets))
+    }
+
+    fn list<T: DeserializeOwned>(&self, resource: &str) -> Result<Vec<T>, K8sError> {
+        if self.namespaces.is_empty() {
+            return self.client.list(&format!("/api/v1/{}", resource));
+        }
+        let mut items = Vec::new();
+        for namespace in &self.namespaces {
+            let path = format!("/api/v1/namespaces/{}/{}", namespace, resource);
+            items.extend(self.client.list(&path)?);
+        }
+        Ok(items)
+    }
+}
+
+impl fmt::Debug for K8sSd {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("K8sSd")
+            .field("api", &self.client.config.url)
+            .field("role", &self.role)
+            .field("namespaces", &self.namespaces)
+            .field("job", &self.job)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::thread;
+
+    #[test]
+    fn test_k8s() {
+        let annotated = |port: Option<&str>| {
+            let mut annotations = json!({"prometheus.io/scrape": "true"});
+            if let Some(port) = port {
+                annotations["prometheus.io/port"] = json!(port);
+            }
+            annotations
+        };
+        let pods = json!({"kind": "PodList", "items": [
+            {
+                "metadata": {"name": "node-1", "namespace": "mon", "annotations": annotated(None)},
+                "spec": {"containers": [{"name": "c", "ports": [
+                    {"containerPort": 9100},
+                    {"containerPort": 53, "protocol": "UDP"}
+                ]}]},
+                "status": {"phase": "Running", "podIP": "10.0.0.2"}
+            },
+            {
+                "metadata": {"name": "app", "namespace": "web", "annotations": annotated(Some("8080"))},
+                "status": {"phase": "Running", "podIP": "fd00::1"}
+            },
+            {
+                "metadata": {"name": "pending", "namespace": "web", "annotations": annotated(None)},
+                "status": {"phase": "Pending"}
+            },
+            {
+                "metadata": {"name": "plain", "namespace": "web"},
+                "status": {"phase": "Running", "podIP": "10.0.0.3"}
+            }
+        ]});
+        let services = json!({"items": [
+            {"metadata": {"name": "db", "namespace": "data", "annotations": annotated(None)}},
+            {"metadata": {"name": "cache", "namespace": "data"}}
+        ]});
+        let endpoints = json!({"items": [
+            {"metadata": {"name": "db", "namespace": "data"}, "subsets": [{
+                "addresses": [{"ip": "10.0.1.1", "targetRef": {"kind": "Pod", "name": "db-0"}}],
+                "ports": [{"name": "metrics", "port": 9187, "protocol": "TCP"}]
+            }]},
+            {"metadata": {"name": "cache", "namespace": "data"}, "subsets": [{
+                "addresses": [{"ip": "10.0.1.2"}],
+                "ports": [{"port": 9121}]
+            }]}
+        ]});
+
+        let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", server.server_addr().to_ip().unwrap());
+        let handle = thread::spawn(move || {
+            for _ in 0..3 {
+                let request = server.recv().unwrap();
+                let auth = request
+                    .headers()
+                    .iter()
+                    .find(|h| h.field.equiv("Authorization"))
+                    .map(|h| h.value.to_string());
+                assert_eq!(auth.as_deref(), Some("Bearer secret"));
+                let body = match request.url() {
+                    "/api/v1/pods" => &pods,
+                    "/api/v1/namespaces/data/services" => &services,
+                    "/api/v1/namespaces/data/endpoints" => &endpoints,
+                    url => panic!("unexpected request for {}", url),
+                };
+                let response = tiny_http::Response::from_string(body.to_string());
+                request.respond(response).unwrap();
+            }
+        });
+
+        let token = std::env::temp_dir().join(format!("pmv-k8s-token-{}", std::process::id()));
+        fs::write(&token, "secret\n").unwrap();
+        let config = ApiConfig {
+            url,
+            token_file: Some(token.clone()),
+            ca_file: None,
+        };
+
+        let mut sd = K8sSd::new(config.clone(), Role::Pods, Vec::new(), "k8s").unwrap();
+        let targets = sd.poll().unwrap().unwrap();
+        let urls: Vec<_> = targets.iter().map(|t| t.url.as_str()).collect();
+        assert_eq!(
+            urls,
+            [
+                "http://10.0.0.2:9100/metrics",
+                "http://[fd00::1]:8080/metrics"
+            ]
+        );
+        assert_eq!(
+            targets[0].labels,
+            [
+                ("namespace".to_string(), "mon".to_string()),
+                ("pod".to_string(), "node-1".to_string())
+            ]
+        );
+        // Not listed again before the refresh interval.
+        assert!(sd.poll().unwrap().is_none());
+
+        let namespaces = vec!["data".to_string()];
+        let mut sd = K8sSd::new(config, Role::Endpoints, namespaces, "k8s").unwrap();
+        let targets = sd.poll().unwrap().unwrap();
+        handle.join().unwrap();
+        fs::remove_file(&token).unwrap();
+        assert_eq!(targets.len(), 1);
+        assert_eq!(targets[0].url, "http://10.0.1.1:9187/metrics");
+        assert_eq!(
+            targets[0].labels[2],
+            ("pod".to_string(), "db-0".to_string())
+        );
+
+        assert!(matches!(tls_config(&token), Err(K8sError::Io(..))));
+        assert_eq!("endpoints".parse(), Ok(Role::Endpoints));
+    }
+}
@@ -0,0 +1,270 @@
+use prometheus::proto::{LabelPair, MetricFamily};
+use prometheus::{IntCounterVec, Opts, Registry};
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::error::Error;
+use std::fmt;
+
+use crate::flatten::render_series;
+
+/// What happens to a series with more labels than a sink accepts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LimitAction {
+    /// Leave the series out.
+    DropSample,
+    /// Keep the labels listed in `priority` first, then the others by
+    /// name, up to the limit.
+    DropLabels,
+    /// Fail the write.
+    Error,
+}
+
+impl LimitAction {
+    fn name(self) -> &'static str {
+        match self {
+            LimitAction::DropSample => "drop_sample",
+            LimitAction::DropLabels => "drop_labels",
+            LimitAction::Error => "error",
+        }
+    }
+}
+
+/// The most labels, not counting the metric name, a sink accepts.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LabelLimit {
+    pub max_labels: usize,
+    #[serde(default = "default_action")]
+    pub action: LimitAction,
+    /// Labels to keep first with `drop_labels`, most important first.
+    #[serde(default)]
+    pub priority: Vec<String>,
+}
+
+fn default_action() -> LimitAction {
+    LimitAction::DropSample
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LabelLimitError {
+    pub sink: String,
+    pub series: String,
+    pub labels: usize,
+    pub max_labels: usize,
+}
+
+impl fmt::Display for LabelLimitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}: series {} has {} labels, more than the {} allowed",
+            self.sink, self.series, self.labels, self.max_labels
+        )
+    }
+}
+
+impl Error for LabelLimitError {}
+
+/// Applies [`LabelLimit`]s to what is written to sinks, counting every
+/// series it drops or trims.
+#[derive(Debug)]
+pub struct LabelLimiter {
+    enforced: IntCounterVec,
+}
+
+impl Default for LabelLimiter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LabelLimiter {
+    pub fn new() -> Self {
+        let enforced = IntCounterVec::new(
+            Opts::new(
+                "pmv_label_limit_enforced_total",
+                "Series dropped or trimmed for having more labels than their sink accepts.",
+            ),
+            &["sink", "action"],
+        )
+        .expect("valid label limit counter");
+        LabelLimiter { enforced }
+    }
+
+    /// Registers the limiter's self-metrics with `registry`.
+    pub fn register(&self, registry: &Registry) -> prometheus::Result<()> {
+        registry.register(Box::new(self.enforced.clone()))
+    }
+
+    /// How many series `action` was applied to for `sink` so far.
+    pub fn enforced(&self, sink: &str, action: LimitAction) -> u64 {
+        self.enforced
+            .with_label_values(&[sink, action.name()])
+            .get()
+    }
+
+    /// Returns `mfs` within `limit` for `sink`, in the same order.
+    /// Trimming labels can give a series the labels of another one; the
+    /// series already within the limit, or else the first trimmed one,
+    /// wins and the others count as dropped. Families left without series
+    /// are dropped.
+    pub fn enforce(
+        &self,
+        sink: &str,
+        limit: &LabelLimit,
+        mfs: &[MetricFamily],
+    ) -> Result<Vec<MetricFamily>, LabelLimitError> {
+        let count = |action: LimitAction| {
+            self.enforced
+                .with_label_values(&[sink, action.name()])
+                .inc()
+        };
+
+        let mut out = Vec::with_capacity(mfs.len());
+        for mf in mfs {
+            let mut seen: HashSet<Vec<(String, String)>> = mf
+                .get_metric()
+                .iter()
+                .filter(|m| m.get_label().len() <= limit.max_labels)
+                .map(|m| sorted_labels(m.get_label()))
+                .collect();
+            let mut metrics = Vec::with_capacity(mf.get_metric().len());
+            for m in mf.get_metric() {
+                let labels = m.get_label().len();
+                if labels <= limit.max_labels {
+                    metrics.push(m.clone());
+                    continue;
+                }
+                match limit.action {
+                    LimitAction::Error => {
+                        return Err(LabelLimitError {
+                            sink: sink.to_string(),
+                            series: render_series(mf.get_name(), &sorted_labels(m.get_label())),
+                            labels,
+                            max_labels: limit.max_labels,
+                        })
+                    }
+                    LimitAction::DropSample => count(LimitAction::DropSample),
+                    LimitAction::DropLabels => {
+                        let mut m = m.clone();
+                        m.set_label(trim(m.get_label(), limit).into());
+                        if seen.insert(sorted_labels(m.get_label())) {
+                            count(LimitAction::DropLabels);
+                            metrics.push(m);
+                        } else {
+                            count(LimitAction::DropSample);
+                        }
+                    }
+                }
+            }
+            if metrics.is_empty() {
+                continue;
+            }
+            let mut mf = mf.clone();
+            mf.set_metric(metrics.into());
+            out.push(mf);
+        }
+        Ok(out)
+    }
+}
+
+fn trim(labels: &[LabelPair], limit: &LabelLimit) -> Vec<LabelPair> {
+    let rank = |l: &LabelPair| {
+        let name = l.get_name();
+        let priority = limit.priority.iter().position(|p| p == name);
+        (priority.unwrap_or(usize::MAX), name.to_string())
+    };
+    let mut ranked: Vec<&LabelPair> = labels.iter().collect();
+    ranked.sort_by_key(|l| rank(l));
+    let keep: HashSet<&str> = ranked
+        .iter()
+        .take(limit.max_labels)
+        .map(|l| l.get_name())
+        .collect();
+    // Kept labels stay in their original order.
+    labels
+        .iter()
+        .filter(|l| keep.contains(l.get_name()))
+        .cloned()
+        .collect()
+}
+
+fn sorted_labels(labels: &[LabelPair]) -> Vec<(String, String)> {
+    let mut pairs: Vec<(String, String)> = labels
+        .iter()
+        .map(|l| (l.get_name().to_string(), l.get_value().to_string()))
+        .collect();
+    pairs.sort_unstable();
+    pairs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::{read_metric_families, Format};
+    use crate::text_encode::metric_families_to_text;
+
+    #[test]
+    fn test_enforce() {
+        let mfs = read_metric_families(
+            Format::Text,
+            r#"# TYPE up gauge
+up{job="a",instance="h1"} 1
+up{job="a",instance="h1",zone="z",pod="p"} 2
+up{job="a",instance="h1",zone="y"} 3
+up{job="b",instance="h2",zone="z"} 4
+"#
+            .as_bytes(),
+        )
+        .unwrap();
+        let limiter = LabelLimiter::new();
+        let mut limit = LabelLimit {
+            max_labels: 2,
+            action: LimitAction::DropSample,
+            priority: Vec::new(),
+        };
+
+        let out = limiter.enforce("out.prom", &limit, &mfs).unwrap();
+        assert_eq!(out[0].get_metric().len(), 1);
+        assert_eq!(limiter.enforced("out.prom", LimitAction::DropSample), 3);
+
+        // By priority, then by name: job and instance collide with the
+        // first series, which was within the limit.
+        limit.action = LimitAction::DropLabels;
+        limit.priority = vec!["job".to_string(), "instance".to_string()];
+        let out = limiter.enforce("-", &limit, &mfs).unwrap();
+        let mut text = Vec::new();
+        metric_families_to_text(&mut text, &out).unwrap();
+        assert_eq!(
+            String::from_utf8(text).unwrap(),
+            "# TYPE up gauge\nup{job=\"a\",instance=\"h1\"} 1\nup{job=\"b\",instance=\"h2\"} 4\n"
+        );
+        assert_eq!(limiter.enforced("-", LimitAction::DropLabels), 1);
+        assert_eq!(limiter.enforced("-", LimitAction::DropSample), 2);
+
+        limit.priority.clear();
+        let out = limiter.enforce("-", &limit, &mfs).unwrap();
+        let labels: Vec<_> = out[0].get_metric()[1]
+            .get_label()
+            .iter()
+            .map(|l| l.get_name())
+            .collect();
+        assert_eq!(labels, vec!["job", "instance"]);
+
+        limit.action = LimitAction::Error;
+        let err = limiter.enforce("remote", &limit, &mfs).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "remote: series up{instance=\"h1\",job=\"a\",pod=\"p\",zone=\"z\"} has 4 labels, \
+             more than the 2 allowed"
+        );
+
+        let registry = Registry::new();
+        limiter.register(&registry).unwrap();
+        assert_eq!(
+            registry.gather()[0].get_name(),
+            "pmv_label_limit_enforced_total"
+        );
+    }
+}
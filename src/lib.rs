@@ -0,0 +1,67 @@
+pub mod aggregate;
+pub mod alert;
+pub mod anonymize;
+pub mod binop;
+#[cfg(feature = "datadog")]
+pub mod datadog;
+pub mod diagnostic;
+pub mod diff;
+pub mod diff_view;
+pub mod dns_sd;
+pub mod expiry;
+pub mod exporter;
+pub mod file_sd;
+pub mod fingerprint;
+pub mod flatten;
+pub mod format;
+pub mod grammar;
+pub mod grep;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+pub mod index;
+pub mod influx;
+pub mod json_format;
+#[cfg(feature = "k8s")]
+pub mod k8s;
+pub mod label_limit;
+pub mod lint;
+pub mod merge;
+pub mod migrate;
+pub mod model;
+#[cfg(feature = "mqtt")]
+pub mod mqtt;
+pub mod namespace;
+pub mod openmetrics;
+pub mod otlp;
+pub mod pipeline;
+#[cfg(feature = "plugins")]
+pub mod plugin;
+pub mod protobuf_format;
+pub mod quantile;
+pub mod ratelimit;
+pub mod rebucket;
+pub mod redact;
+pub mod relabel;
+pub mod remote_write;
+pub mod retry;
+pub mod rules;
+pub mod sample;
+pub mod schedule;
+pub mod scrape;
+#[cfg(feature = "script")]
+pub mod script;
+pub mod server;
+pub mod split;
+pub mod table;
+pub mod text_encode;
+pub mod text_parse;
+pub mod timefmt;
+pub mod top;
+#[cfg(feature = "tower")]
+pub mod tower;
+pub mod tsdb;
+pub mod units;
+pub mod vm_import;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+pub mod watch;
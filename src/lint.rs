@@ -0,0 +1,196 @@
+//! The checks of promlint, which `promtool check metrics` runs, with the
+//! same wording so that scripts matching its output keep working.
+
+use prometheus::proto::{MetricFamily, MetricType};
+use std::fmt;
+
+const UNITS: [&str; 10] = [
+    "amperes", "bytes", "celsius", "grams", "joules", "kelvin", "meters", "metres", "seconds",
+    "volts",
+];
+const UNIT_PREFIXES: [&str; 16] = [
+    "pico", "nano", "micro", "milli", "centi", "deci", "deca", "hecto", "kilo", "mega", "giga",
+    "tera", "peta", "exa", "zetta", "yotta",
+];
+const UNIT_ABBREVIATIONS: [&str; 14] = [
+    "s", "ms", "us", "ns", "sec", "b", "kb", "mb", "gb", "tb", "pb", "m", "h", "d",
+];
+
+/// A problem with the naming or documentation of a family.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Problem {
+    pub metric: String,
+    pub text: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.metric, self.text)
+    }
+}
+
+/// Returns the problems of every family, sorted by family name and text.
+pub fn lint(mfs: &[MetricFamily]) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    for mf in mfs {
+        let mut problem = |text: String| {
+            problems.push(Problem {
+                metric: mf.get_name().to_string(),
+                text,
+            })
+        };
+        let name = mf.get_name();
+        let mf_type = mf.get_field_type();
+
+        if !mf.has_help() || mf.get_help().is_empty() {
+            problem("no help text".to_string());
+        }
+
+        if let Some((unit, base)) = name.split('_').find_map(prefixed_unit) {
+            if unit != base {
+                problem(format!("use base unit {:?} instead of {:?}", base, unit));
+            }
+        }
+
+        let lower = name.to_lowercase();
+        for type_name in ["counter", "gauge", "summary", "untyped", "histogram"] {
+            if has_part(&lower, type_name) {
+                problem(format!(
+                    "metric name should not include type '{}'",
+                    type_name
+                ));
+            }
+        }
+
+        if name.contains(':') {
+            problem("metric names should not contain ':'".to_string());
+        }
+
+        if is_camel_case(name) {
+            problem("metric names should be written in 'snake_case' not 'camelCase'".to_string());
+        }
+        let labels = mf.get_metric().iter().flat_map(|m| m.get_label());
+        for label in labels.clone() {
+            if is_camel_case(label.get_name()) {
+                problem(
+                    "label names should be written in 'snake_case' not 'camelCase'".to_string(),
+                );
+            }
+        }
+
+        for abbreviation in UNIT_ABBREVIATIONS {
+            if has_part(&lower, abbreviation) {
+                problem("metric names should not contain abbreviated units".to_string());
+            }
+        }
+
+        let total = name.ends_with("_total");
+        match mf_type {
+            MetricType::COUNTER if !total => {
+                problem("counter metrics should have \"_total\" suffix".to_string())
+            }
+            MetricType::COUNTER => {}
+            MetricType::GAUGE
+            | MetricType::SUMMARY
+            | MetricType::UNTYPED
+            | MetricType::HISTOGRAM => {
+                if total {
+                    problem("non-counter metrics should not have \"_total\" suffix".to_string());
+                }
+            }
+        }
+
+        if mf_type == MetricType::UNTYPED {
+            continue;
+        }
+        let histogram = mf_type == MetricType::HISTOGRAM;
+        let summary = mf_type == MetricType::SUMMARY;
+        if !histogram && name.ends_with("_bucket") {
+            problem("non-histogram metrics should not have \"_bucket\" suffix".to_string());
+        }
+        for suffix in ["_count", "_sum"] {
+            if !histogram && !summary && name.ends_with(suffix) {
+                problem(format!(
+                    "non-histogram and non-summary metrics should not have {:?} suffix",
+                    suffix
+                ));
+            }
+        }
+        for label in labels {
+            if !histogram && label.get_name() == "le" {
+                problem("non-histogram metrics should not have \"le\" label".to_string());
+            }
+            if !summary && label.get_name() == "quantile" {
+                problem("non-summary metrics should not have \"quantile\" label".to_string());
+            }
+        }
+    }
+    problems.sort();
+    problems
+}
+
+/// The unit a name part is in and its base unit, if it is a unit.
+fn prefixed_unit(part: &str) -> Option<(&str, &'static str)> {
+    let base = |unit: &str| UNITS.iter().copied().find(|u| *u == unit);
+    if let Some(unit) = base(part) {
+        return Some((part, unit));
+    }
+    UNIT_PREFIXES
+        .iter()
+        .find_map(|prefix| base(part.strip_prefix(prefix)?))
+        .map(|unit| (part, unit))
+}
+
+/// Whether `name` contains `_<part>_` or ends with `_<part>`.
+fn has_part(name: &str, part: &str) -> bool {
+    name.contains(&format!("_{}_", part)) || name.ends_with(&format!("_{}", part))
+}
+
+fn is_camel_case(name: &str) -> bool {
+    name.as_bytes()
+        .windows(2)
+        .any(|w| w[0].is_ascii_lowercase() && w[1].is_ascii_uppercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::{read_metric_families, Format};
+
+    #[test]
+    fn test_lint() {
+        let text = r#"# HELP requests_total Requests.
+# TYPE requests_total counter
+requests_total{code="200"} 1
+# HELP latency_milliseconds Latency.
+# TYPE latency_milliseconds gauge
+latency_milliseconds{fooBar="x"} 2
+# HELP hits Hits.
+# TYPE hits counter
+hits 3
+# TYPE queue:depth_gauge gauge
+queue:depth_gauge{le="1"} 4
+# HELP bytes_sum Bytes.
+# TYPE bytes_sum gauge
+bytes_sum 5
+# HELP uptime_sec Uptime.
+uptime_sec 6
+"#;
+        let mfs = read_metric_families(Format::Text, text.as_bytes()).unwrap();
+        let problems: Vec<_> = lint(&mfs).iter().map(|p| p.to_string()).collect();
+        assert_eq!(
+            problems,
+            [
+                "bytes_sum non-histogram and non-summary metrics should not have \"_sum\" suffix",
+                "hits counter metrics should have \"_total\" suffix",
+                "latency_milliseconds label names should be written in 'snake_case' not 'camelCase'",
+                "latency_milliseconds use base unit \"seconds\" instead of \"milliseconds\"",
+                "queue:depth_gauge metric name should not include type 'gauge'",
+                "queue:depth_gauge metric names should not contain ':'",
+                "queue:depth_gauge no help text",
+                "queue:depth_gauge non-histogram metrics should not have \"le\" label",
+                "uptime_sec metric names should not contain abbreviated units",
+            ]
+        );
+    }
+}
@@ -1,61 +1,2661 @@
 use std::collections::HashMap;
 use std::error::Error;
+use std::io::{self, BufReader, IsTerminal, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
 
-mod text_parse;
+use clap::{CommandFactory, Parser, Subcommand};
+use clap_complete::Shell;
+use prometheus::proto::{MetricFamily, MetricType};
+use regex::Regex;
+use serde_json::Value;
 
-use text_parse::TextParser;
+use pmv::aggregate::Grouping;
+use pmv::alert::{self, Firing};
+use pmv::anonymize::Anonymize;
+use pmv::binop::{self, Expr};
+#[cfg(feature = "datadog")]
+use pmv::datadog::{self, DatadogConverter};
+use pmv::diagnostic::{Diagnostic, Kind};
+use pmv::diff;
+use pmv::diff_view;
+use pmv::dns_sd::{DnsSd, RecordType};
+use pmv::expiry::ExpiryConfig;
+use pmv::exporter::{self, ExporterConfig, TlsFiles};
+use pmv::file_sd::FileSd;
+use pmv::flatten::{self, FlatSample};
+use pmv::format::{self, Format};
+use pmv::grep::{self, FilterRule, Matcher, Selector, ValuePredicate};
+#[cfg(feature = "grpc")]
+use pmv::grpc;
+use pmv::influx::InfluxMapping;
+#[cfg(feature = "k8s")]
+use pmv::k8s;
+use pmv::label_limit::{LabelLimiter, LimitAction};
+use pmv::lint;
+use pmv::merge::{self, ConflictPolicy};
+use pmv::migrate::{Migration, MigrationReport};
+#[cfg(feature = "mqtt")]
+use pmv::mqtt;
+use pmv::namespace;
+use pmv::otlp::export::{Exporter, Transport};
+use pmv::pipeline::{self, Builtin, Pipeline, Transform};
+#[cfg(feature = "plugins")]
+use pmv::plugin::Plugin;
+use pmv::quantile::histogram_quantile;
+use pmv::ratelimit::RateLimit;
+use pmv::redact::Redact;
+use pmv::remote_write::{self, Protocol, PushOptions};
+use pmv::retry::Retry;
+use pmv::rules::Rules;
+use pmv::sample::{self, SampleSize};
+use pmv::scrape::{self, Auth, ScrapeOptions, Secret, Target, TargetScrape};
+use pmv::server::{self, ServerConfig};
+use pmv::split::{self, SplitBy};
+use pmv::table;
+use pmv::text_encode;
+use pmv::text_parse::{CountPolicy, DuplicatePolicy, ParserOptions, TextParser};
+use pmv::timefmt::TimeStyle;
+use pmv::top;
+use pmv::tsdb::{self, StorageConfig};
+use pmv::watch::{FileWatcher, Summary};
 
-fn main() -> Result<(), Box<dyn Error>> {
-    let r = std::fs::File::open("example.txt").expect("Fail to open file");
-    let _parser = TextParser::new(r);
+#[derive(Parser)]
+#[command(
+    name = "pmv",
+    version,
+    about = "Prometheus metrics toolkit",
+    after_help = "Exit status: 0 on success; 1 if grep matched nothing, diff found changes, \
+                  validate found violations or a pipeline alert fired; 2 for invalid arguments; 65 if an input could not \
+                  be parsed; 74 for I/O errors; 70 for any other error."
+)]
+struct Cli {
+    /// How errors and validation results are reported
+    #[arg(long, global = true, value_enum, default_value = "text")]
+    error_format: ErrorFormat,
+    /// How table and watch outputs show times: utc, local, raw milliseconds, relative or a zone such as Europe/Berlin
+    #[arg(long, global = true, value_name = "STYLE", default_value = "utc")]
+    time: TimeStyle,
+    /// Run the inputs, transforms and outputs of a YAML pipeline file instead of a command
+    #[arg(long, value_name = "PIPELINE")]
+    config: Option<PathBuf>,
+    /// With --config, report what each transform and relabel rule would do instead of writing the outputs
+    #[arg(long, requires = "config")]
+    dry_run: bool,
+    /// With --config, run as an agent: rerun the pipeline at this interval and post firing alerts to its webhook
+    #[arg(long, requires = "config", conflicts_with = "dry_run", value_parser = parse_duration)]
+    interval: Option<Duration>,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Parse exposition text and write it back out, families sorted by name
+    ///
+    /// Several inputs are concatenated into one valid document: families
+    /// split across inputs are merged under a single HELP and TYPE.
+    Cat {
+        /// Collapse whitespace in HELP docstrings
+        #[arg(long)]
+        normalize_help: bool,
+        /// How to handle a series present in several inputs: error, last-wins, sum (counters) or federate (drop instance, sum counters and histograms, average gauges)
+        #[arg(long, default_value = "error")]
+        conflict: ConflictPolicy,
+        /// Output mode
+        #[arg(short, long, value_enum, default_value = "text")]
+        output: Output,
+        #[command(flatten)]
+        inputs: Inputs,
+    },
+    /// Strictly check exposition text and report every violation
+    Validate {
+        /// Also report samples of families without a TYPE line
+        #[arg(long)]
+        require_type: bool,
+        #[command(flatten)]
+        inputs: Inputs,
+    },
+    /// Checks with the output and exit codes of `promtool check`, for scripts written against it
+    Check {
+        #[command(subcommand)]
+        command: CheckCommand,
+    },
+    /// Convert between exposition formats (text, openmetrics, protobuf, json, vm-import)
+    Convert {
+        /// Input format
+        #[arg(long, default_value = "text")]
+        from: Format,
+        /// Output format
+        #[arg(long, default_value = "text")]
+        to: Format,
+        /// What to do with histogram and summary counts that are not integers between 0 and 2^53: error, clamp or skip
+        #[arg(long, default_value = "clamp")]
+        count_policy: CountPolicy,
+        /// What to do with samples repeated in an input: keep, error, first-wins, last-wins or sum
+        #[arg(long, default_value = "keep")]
+        duplicate_policy: DuplicatePolicy,
+        /// Replace the values of this label with salted hashes, e.g. before sharing a dump; repeatable
+        #[arg(long, value_name = "LABEL")]
+        anonymize: Vec<String>,
+        /// Salt for --anonymize; the same salt hashes values alike across runs
+        #[arg(long, default_value = "", requires = "anonymize")]
+        salt: String,
+        /// Replace matches of this regex in label values with REDACTED, e.g. emails or tokens; repeatable
+        #[arg(long, value_name = "REGEX")]
+        redact: Vec<Regex>,
+        #[command(flatten)]
+        inputs: Inputs,
+    },
+    /// Keep only families and series matching a name regex and label patterns
+    Grep {
+        /// Regex matched anywhere in the family name
+        pattern: Regex,
+        /// Label matcher such as method="GET", method!="GET", method=~"GET|POST" or method!~"GET|POST", regexes matching the whole value; repeatable
+        #[arg(short, long = "label", value_name = "MATCHER")]
+        labels: Vec<Matcher>,
+        /// Value constraint such as 'value > 0'; histograms and summaries are judged by their count; repeatable
+        #[arg(long = "where", value_name = "PREDICATE")]
+        predicates: Vec<ValuePredicate>,
+        /// Selector keeping the series it matches, or dropping them with a leading `!`, e.g. '!{env="test"}'; repeatable
+        #[arg(long = "match", value_name = "RULE")]
+        rules: Vec<FilterRule>,
+        /// Output mode
+        #[arg(short, long, value_enum, default_value = "text")]
+        output: Output,
+        #[command(flatten)]
+        inputs: Inputs,
+    },
+    /// Compare two expositions: families and series added or removed, changed values, TYPE and HELP
+    Diff {
+        /// Ignore value changes up to this absolute difference
+        #[arg(long, default_value_t = 0.0)]
+        tolerance: f64,
+        /// Print one JSON array of changes instead of text
+        #[arg(long)]
+        json: bool,
+        /// Browse the changes side by side in the terminal, with search and
+        /// filtering by change type
+        #[arg(long, conflicts_with = "json")]
+        interactive: bool,
+        /// Format of both inputs
+        #[arg(long, default_value = "text")]
+        format: Format,
+        /// Only compare series whose value satisfies this, e.g. 'value != 0'; repeatable
+        #[arg(long = "where", value_name = "PREDICATE")]
+        predicates: Vec<ValuePredicate>,
+        /// Old input file, `-` for stdin
+        old: PathBuf,
+        /// New input file, `-` for stdin
+        new: PathBuf,
+    },
+    /// Combine several inputs into one document
+    Merge {
+        /// Format of the inputs and the output
+        #[arg(long, default_value = "text")]
+        format: Format,
+        /// How to handle a series present in several inputs: error, last-wins, sum (counters) or federate (drop instance, sum counters and histograms, average gauges)
+        #[arg(long, default_value = "error")]
+        conflict: ConflictPolicy,
+        /// Add this label to every series, set to the name of the input file it came from
+        #[arg(long, value_name = "NAME")]
+        label: Option<String>,
+        /// Input files, `-` for stdin
+        #[arg(required = true)]
+        files: Vec<PathBuf>,
+    },
+    /// Print one `name{sorted labels} value [timestamp]` line per sample, without HELP or TYPE
+    Flatten {
+        /// Input format
+        #[arg(long, default_value = "text")]
+        format: Format,
+        /// Start each line with the series' Prometheus fingerprint, in hex
+        #[arg(long)]
+        fingerprint: bool,
+        #[command(flatten)]
+        inputs: Inputs,
+    },
+    /// Estimate a quantile of every histogram series, e.g. the p99 latency of a scrape, as PromQL's histogram_quantile
+    Quantile {
+        /// Quantile between 0 and 1, e.g. 0.99
+        q: f64,
+        /// Only estimate for this family
+        #[arg(long)]
+        family: Option<String>,
+        /// Input format
+        #[arg(long, default_value = "text")]
+        format: Format,
+        #[command(flatten)]
+        inputs: Inputs,
+    },
+    /// Apply +, -, * or / between the series of two families matched by labels, e.g. 'errors_total / requests_total'
+    Calc {
+        /// Expression `LEFT OP RIGHT` naming one family on each side
+        expr: Expr,
+        /// Only match series on these labels; repeatable
+        #[arg(long, value_name = "LABEL", conflicts_with = "ignoring")]
+        on: Vec<String>,
+        /// Match series on all labels but these; repeatable
+        #[arg(long, value_name = "LABEL")]
+        ignoring: Vec<String>,
+        /// Name of the resulting gauge
+        #[arg(long, default_value = "result")]
+        name: String,
+        /// Format of both inputs
+        #[arg(long, default_value = "text")]
+        format: Format,
+        /// Input with the left family, `-` for stdin
+        left: PathBuf,
+        /// Input with the right family if not the left input
+        right: Option<PathBuf>,
+    },
+    /// Reorder families by name and series by label set, keeping HELP and TYPE with their family
+    Sort {
+        /// Input and output format
+        #[arg(long, default_value = "text")]
+        format: Format,
+        /// Input file, stdin if omitted or `-`
+        file: Option<PathBuf>,
+    },
+    /// Write every family, or the series of every label value, to its own `<name>.prom` file, or the extension of `--format`
+    Split {
+        /// Input and output format
+        #[arg(long, default_value = "text")]
+        format: Format,
+        /// Directory for the files, created if missing
+        #[arg(short, long, visible_alias = "out-dir", value_name = "DIR")]
+        out: PathBuf,
+        /// `family`, or `label:NAME` for one file per value of that label
+        #[arg(long, default_value = "family")]
+        by: SplitBy,
+        /// Input file, stdin if omitted or `-`
+        file: Option<PathBuf>,
+    },
+    /// Fetch metrics endpoints over HTTP and write what they expose
+    ///
+    /// Several endpoints are scraped in parallel and their series merged,
+    /// labeled with the `instance` and `job` of their endpoint as Prometheus
+    /// labels them. Endpoints that fail are reported, and the others still
+    /// written.
+    Scrape {
+        #[command(flatten)]
+        scrape: ScrapeArgs,
+        /// Output format
+        #[arg(long, default_value = "text")]
+        format: Format,
+        #[command(flatten)]
+        targets: TargetArgs,
+    },
+    /// Send the series of exposition files to a remote-write endpoint, an OpenTelemetry collector, Datadog or an MQTT broker, e.g. to load them into a TSDB
+    ///
+    /// Pipe `pmv scrape` into it to ship scraped series. Samples without a
+    /// timestamp are sent with the current time.
+    ///
+    /// Datadog takes counters as counts of their increase, so they are only
+    /// sent from the second run with the same --datadog-state on.
+    Push {
+        /// Remote-write endpoint, OTLP endpoint with --otlp, Datadog site with --datadog, or `mqtt://` broker with --mqtt-topic
+        #[arg(long)]
+        url: String,
+        /// Send over OTLP with this transport, http or grpc, instead of remote-write; grpc needs a build with the `otlp-grpc` feature. Exemplars are not sent
+        #[arg(long, value_name = "TRANSPORT", conflicts_with = "datadog")]
+        otlp: Option<Transport>,
+        /// Send to the Datadog series API with the key in $DD_API_KEY; needs a build with the `datadog` feature
+        #[arg(long)]
+        datadog: bool,
+        /// File keeping counter values between runs with --datadog
+        #[arg(long, value_name = "PATH", requires = "datadog")]
+        datadog_state: Option<PathBuf>,
+        #[command(flatten)]
+        mqtt: MqttArgs,
+        /// Input format
+        #[arg(long, default_value = "text")]
+        format: Format,
+        /// Remote-write protocol version, 1.0 or 2.0; 2.0 falls back to 1.0 if the endpoint lacks it
+        #[arg(long, default_value = "1.0")]
+        protocol: Protocol,
+        /// Maximum samples per remote-write request, or series per Datadog one
+        #[arg(long, default_value_t = 2000)]
+        batch_size: usize,
+        /// Give up on a request after this long, e.g. 30s
+        #[arg(long, default_value = "30s", value_parser = parse_duration)]
+        timeout: Duration,
+        #[command(flatten)]
+        retry: RetryArgs,
+        #[command(flatten)]
+        inputs: Inputs,
+    },
+    /// Keep a reproducible subset of the series, e.g. to make a small fixture from a large scrape
+    #[command(group(clap::ArgGroup::new("size").required(true)))]
+    Sample {
+        /// Keep up to this many series of every family
+        #[arg(short = 'k', long, group = "size")]
+        per_family: Option<usize>,
+        /// Keep about this fraction of all series, between 0 and 1
+        #[arg(long, group = "size", value_parser = parse_fraction)]
+        fraction: Option<f64>,
+        /// Different seeds pick different series
+        #[arg(long, default_value_t = 0)]
+        seed: u64,
+        /// Format of the input and the output
+        #[arg(long, default_value = "text")]
+        format: Format,
+        /// Input file, stdin if omitted or `-`
+        file: Option<PathBuf>,
+    },
+    /// Print the samples with the largest values
+    Top {
+        /// Number of samples to print
+        #[arg(short = 'n', long, default_value_t = 10)]
+        count: usize,
+        /// Only consider samples of this family
+        #[arg(long)]
+        family: Option<String>,
+        /// Rank counters by their increase since this earlier exposition
+        #[arg(long, value_name = "OLD")]
+        since: Option<PathBuf>,
+        /// Input file, stdin if omitted or `-`
+        file: Option<PathBuf>,
+    },
+    /// Re-parse a file whenever it changes and print it, or a live summary
+    Watch {
+        /// How often to check the file for changes, e.g. 5s
+        #[arg(long, default_value = "2s", value_parser = parse_duration)]
+        interval: Duration,
+        /// Re-parse on every interval, even if the file did not change
+        #[arg(long)]
+        always: bool,
+        /// Print family, series and change counts instead of the file, redrawn in place on a terminal
+        #[arg(long)]
+        summary: bool,
+        /// Input format
+        #[arg(long, default_value = "text")]
+        format: Format,
+        /// File to watch
+        file: PathBuf,
+    },
+    /// Maintain the sample storage of `receive --storage-dir`
+    Storage {
+        #[command(subcommand)]
+        command: StorageCommand,
+    },
+    /// Rename labels, label values and families in exposition files or storage directories, per a YAML spec
+    ///
+    /// Files are rewritten in place; a directory is taken for the storage of
+    /// `receive --storage-dir`, whose server must be stopped. Every changed
+    /// series is reported as `old -> new`.
+    Migrate {
+        /// Migration spec with `labels`, `values` and `families` renames
+        #[arg(long)]
+        spec: PathBuf,
+        /// Only report what would change
+        #[arg(long)]
+        dry_run: bool,
+        /// Format of the files
+        #[arg(long, default_value = "text")]
+        format: Format,
+        /// Files and storage directories to migrate
+        #[arg(required = true)]
+        paths: Vec<PathBuf>,
+    },
+    /// Evaluate recording rules against a snapshot and print it with the recorded series
+    Rules {
+        /// Rules file with `record` and `expr` entries
+        #[arg(long)]
+        rules: PathBuf,
+        /// Earlier snapshot for `rate` and `increase`
+        #[arg(long)]
+        previous: Option<PathBuf>,
+        /// Time between the snapshots, for series without timestamps; the difference of the file modification times if omitted
+        #[arg(long, value_parser = parse_duration)]
+        interval: Option<Duration>,
+        /// Only print the recorded series
+        #[arg(long)]
+        only_recorded: bool,
+        /// Format of the snapshots and the output
+        #[arg(long, default_value = "text")]
+        format: Format,
+        /// Current snapshot, stdin if omitted or `-`
+        file: Option<PathBuf>,
+    },
+    /// Print stored samples of the series matching any selector
+    Query {
+        /// Start of the range: Unix seconds, `now` or `now-<duration>`; the oldest sample if omitted
+        #[arg(long, value_parser = parse_time)]
+        start: Option<i64>,
+        /// End of the range, inclusive, in the same forms; now if omitted
+        #[arg(long, value_parser = parse_time)]
+        end: Option<i64>,
+        /// Print the query plan and the rows scanned instead of the samples
+        #[arg(long)]
+        explain: bool,
+        /// Write an exposition in this format instead of one line per sample
+        #[arg(long)]
+        format: Option<Format>,
+        /// Storage directory; may be in use by a running server
+        dir: PathBuf,
+        /// Selectors such as `up{job="api"}`; all series if none are given
+        selectors: Vec<Selector>,
+    },
+    /// Accept remote-write and InfluxDB line protocol pushes and expose the merged series on /metrics
+    Receive {
+        #[command(flatten)]
+        server: ServerArgs,
+    },
+    /// Serve pmv's parser over gRPC, for programs in other languages on the same host
+    ///
+    /// Answers the Parse, Validate and Forward methods of the pmv.v1.Pmv
+    /// service, see `pmv::grpc`. The families of every Forward call are
+    /// written to stdout after --transforms. Needs a build with the `grpc`
+    /// feature.
+    Grpc {
+        /// Address to listen on
+        #[arg(long, default_value = "127.0.0.1:50051")]
+        listen: String,
+        /// YAML list of transforms to apply to forwarded families, written as under `transforms:` in a pipeline file
+        #[arg(long, value_name = "PATH")]
+        transforms: Option<PathBuf>,
+        /// Format forwarded families are written in
+        #[arg(long, default_value = "text")]
+        format: Format,
+    },
+    /// Expose a file's series on /metrics like an exporter, e.g. to mock one in integration tests
+    ///
+    /// The file is read again at every request, so rewriting it changes what
+    /// is served. Responses are in the format the scraper prefers.
+    Serve {
+        #[command(flatten)]
+        serve: ServeArgs,
+        /// Input format
+        #[arg(long, default_value = "text")]
+        format: Format,
+        /// File to serve
+        file: PathBuf,
+    },
+    /// Scrape endpoints at every request to /metrics and expose their transformed series, as a filtering sidecar
+    ///
+    /// Endpoints are scraped and labeled as by `pmv scrape`; those that fail
+    /// are left out, and if all do the request fails with 502.
+    Relay {
+        /// Selector keeping the series it matches, or dropping them with a leading `!`, before any --transforms; repeatable
+        #[arg(long = "match", value_name = "RULE")]
+        rules: Vec<FilterRule>,
+        #[command(flatten)]
+        scrape: ScrapeArgs,
+        #[command(flatten)]
+        serve: ServeArgs,
+        #[command(flatten)]
+        targets: TargetArgs,
+    },
+    /// Read exposition data from a source plugin; needs a build with the `plugins` feature
+    Source {
+        /// Configuration string passed to the plugin
+        #[arg(long, default_value = "")]
+        config: String,
+        /// Input format; taken from the plugin's content type if omitted, else text
+        #[arg(long)]
+        format: Option<Format>,
+        /// Output mode
+        #[arg(short, long, value_enum, default_value = "text")]
+        output: Output,
+        /// Plugin shared library
+        plugin: PathBuf,
+    },
+    /// Print a shell completion script, e.g. `pmv completions bash > /etc/bash_completion.d/pmv`
+    Completions {
+        /// Shell to complete for
+        shell: Shell,
+    },
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum ErrorFormat {
+    /// `file: message` lines
+    Text,
+    /// A JSON array of {file, line, column, code, message} objects
+    Json,
+}
+
+/// Exit codes for errors, from sysexits.h.
+fn exit_code(kind: Kind) -> ExitCode {
+    match kind {
+        Kind::Io => ExitCode::from(74),
+        Kind::Parse => ExitCode::from(65),
+        Kind::Validation => ExitCode::FAILURE,
+        Kind::Other => ExitCode::from(70),
+    }
+}
+
+/// How commands that print families write them to stdout.
+#[derive(Clone, Copy, clap::ValueEnum)]
+enum Output {
+    /// The text exposition format
+    Text,
+    /// Aligned NAME, LABELS, VALUE and TYPE columns, colored unless NO_COLOR is set; text when stdout is not a terminal
+    Table,
+}
+
+impl Output {
+    fn write(self, mfs: &[MetricFamily], time: &TimeStyle) -> io::Result<()> {
+        let mut out = io::BufWriter::new(io::stdout().lock());
+        match self {
+            Output::Table if io::stdout().is_terminal() => {
+                let color = std::env::var_os("NO_COLOR").is_none_or(|v| v.is_empty());
+                table::write_table(&mut out, mfs, color, time)?
+            }
+            _ => text_encode::metric_families_to_text(&mut out, mfs)?,
+        }
+        out.flush()
+    }
+}
+
+/// Input files of the commands that accept several.
+#[derive(clap::Args)]
+struct Inputs {
+    /// Input files or glob patterns such as `dumps/*.prom`; stdin if none or `-`
+    files: Vec<String>,
+    /// Handle every input on its own, under a `==> name <==` header, instead of merging them
+    #[arg(long)]
+    per_file: bool,
+    /// Number of inputs to parse in parallel
+    #[arg(short, long, default_value_t = 1)]
+    jobs: usize,
+}
+
+impl Inputs {
+    /// Expands glob patterns, sorted by name. `None` stands for stdin.
+    fn paths(&self) -> Result<Vec<Option<PathBuf>>, Box<dyn Error>> {
+        if self.files.is_empty() {
+            return Ok(vec![None]);
+        }
+
+        let mut paths = Vec::new();
+        for pattern in &self.files {
+            if !pattern.contains(['*', '?', '[']) {
+                paths.push(Some(PathBuf::from(pattern)));
+                continue;
+            }
+            let mut matched = glob::glob(pattern)?.collect::<Result<Vec<_>, _>>()?;
+            if matched.is_empty() {
+                return Err(format!("no files match {:?}", pattern).into());
+            }
+            matched.sort();
+            paths.extend(matched.into_iter().map(Some));
+        }
+        check_stdin_once(paths.iter().map(|p| p.as_deref()))?;
+        Ok(paths)
+    }
+
+    /// Reads every input with `read`, up to `jobs` at a time, keeping the
+    /// input order. Errors name the input they occurred in.
+    fn read_each<T: Send>(
+        &self,
+        read: impl Fn(Option<&Path>) -> Result<T, Box<dyn Error>> + Sync,
+    ) -> Result<Vec<(String, T)>, Box<dyn Error>> {
+        let paths = self.paths()?;
+        let read = |path: &Option<PathBuf>| {
+            let name = input_name(path.as_deref());
+            // Classified here, since the error itself may not be Send.
+            match read(path.as_deref()) {
+                Ok(value) => Ok((name, value)),
+                Err(e) => Err(Diagnostic::from_error(e.as_ref()).in_file(&name)),
+            }
+        };
+
+        let chunk = paths.len().div_ceil(self.jobs.max(1)).max(1);
+        let results: Vec<Result<(String, T), Diagnostic>> = std::thread::scope(|scope| {
+            let handles: Vec<_> = paths
+                .chunks(chunk)
+                .map(|chunk| scope.spawn(|| chunk.iter().map(read).collect::<Vec<_>>()))
+                .collect();
+            handles
+                .into_iter()
+                .flat_map(|h| h.join().expect("input reader panicked"))
+                .collect()
+        });
+        Ok(results.into_iter().collect::<Result<_, _>>()?)
+    }
+
+    /// Reads the inputs and calls `run` with their merge, the input read
+    /// last winning for duplicate series, or with `--per-file` once per
+    /// input. Succeeds if any run succeeds.
+    fn run(
+        &self,
+        read: impl Fn(Option<&Path>) -> Result<Vec<MetricFamily>, Box<dyn Error>> + Sync,
+        run: impl FnMut(&[MetricFamily]) -> Result<ExitCode, Box<dyn Error>>,
+    ) -> Result<ExitCode, Box<dyn Error>> {
+        self.run_merged(ConflictPolicy::LastWins, read, run)
+    }
+
+    /// Like [`Inputs::run`], resolving duplicate series with `conflict`.
+    fn run_merged(
+        &self,
+        conflict: ConflictPolicy,
+        read: impl Fn(Option<&Path>) -> Result<Vec<MetricFamily>, Box<dyn Error>> + Sync,
+        mut run: impl FnMut(&[MetricFamily]) -> Result<ExitCode, Box<dyn Error>>,
+    ) -> Result<ExitCode, Box<dyn Error>> {
+        let inputs = self.read_each(read)?;
+
+        if !self.per_file {
+            if let [(_, mfs)] = inputs.as_slice() {
+                return run(mfs);
+            }
+            let merged = merge::merge(inputs.into_iter().map(|(_, mfs)| mfs), conflict)?;
+            return run(&merged);
+        }
+
+        let mut code = ExitCode::FAILURE;
+        for (i, (name, mfs)) in inputs.iter().enumerate() {
+            if inputs.len() > 1 {
+                if i > 0 {
+                    println!();
+                }
+                println!("==> {} <==", name);
+            }
+            if run(mfs)? == ExitCode::SUCCESS {
+                code = ExitCode::SUCCESS;
+            }
+        }
+        Ok(code)
+    }
+}
+
+#[derive(Subcommand)]
+enum CheckCommand {
+    /// Lint metric names and help as promlint does, reporting `name problem` lines on stderr
+    ///
+    /// Exits with 3 if there are problems and 1 if the input does not parse,
+    /// as `promtool check metrics` does. `pmv validate` reports parse errors
+    /// in more detail.
+    Metrics {
+        /// Input file, stdin if omitted or `-`
+        file: Option<PathBuf>,
+    },
+    /// Check that pipeline files, as run with `--config`, are valid
+    Config {
+        /// Pipeline files
+        #[arg(required = true)]
+        files: Vec<PathBuf>,
+    },
+}
+
+#[derive(Subcommand)]
+enum StorageCommand {
+    /// Truncate torn segment tails and quarantine corrupt blocks after an unclean shutdown
+    Repair {
+        /// Only report what would be done
+        #[arg(long)]
+        dry_run: bool,
+        /// Storage directory; the server using it must be stopped
+        dir: PathBuf,
+    },
+    /// Write stored samples in a time range to stdout or a remote-write endpoint
+    Export {
+        /// Start of the range: Unix seconds, `now` or `now-<duration>`; the oldest sample if omitted
+        #[arg(long, value_parser = parse_time)]
+        start: Option<i64>,
+        /// End of the range, inclusive, in the same forms; now if omitted
+        #[arg(long, value_parser = parse_time)]
+        end: Option<i64>,
+        /// `remote-write` or an output format
+        #[arg(long, default_value = "openmetrics", value_parser = parse_export_target)]
+        to: ExportTarget,
+        /// Remote-write endpoint, required with `--to remote-write`
+        #[arg(long, required_if_eq("to", "remote-write"))]
+        url: Option<String>,
+        /// Maximum samples per remote-write request
+        #[arg(long, default_value_t = 2000)]
+        batch_size: usize,
+        /// Give up on a remote-write request after this long, e.g. 30s
+        #[arg(long, default_value = "30s", value_parser = parse_duration)]
+        timeout: Duration,
+        #[command(flatten)]
+        retry: RetryArgs,
+        /// Storage directory; may be in use by a running server
+        dir: PathBuf,
+    },
+}
+
+#[derive(Clone, Copy)]
+enum ExportTarget {
+    Format(Format),
+    RemoteWrite,
+}
+
+fn parse_export_target(s: &str) -> Result<ExportTarget, String> {
+    match s {
+        "remote-write" => Ok(ExportTarget::RemoteWrite),
+        _ => s.parse().map(ExportTarget::Format),
+    }
+}
+
+#[derive(clap::Args)]
+struct RetryArgs {
+    /// Retry a request that failed to connect, timed out or got a 429 or 5xx this many times
+    #[arg(long, default_value_t = 0)]
+    retries: u32,
+    /// Wait before the first retry, doubled for every further one and jittered
+    #[arg(long, default_value = "500ms", value_parser = parse_duration)]
+    retry_backoff: Duration,
+    /// Longest wait between two attempts
+    #[arg(long, default_value = "30s", value_parser = parse_duration)]
+    max_retry_backoff: Duration,
+}
+
+impl From<RetryArgs> for Retry {
+    fn from(args: RetryArgs) -> Self {
+        Retry {
+            retries: args.retries,
+            backoff: args.retry_backoff,
+            max_backoff: args.max_retry_backoff,
+        }
+    }
+}
+
+#[derive(clap::Args)]
+struct MqttArgs {
+    /// Publish every sample to the broker at --url on this topic, with `{name}` and `{LABEL}` for the sample name and label values; needs a build with the `mqtt` feature
+    #[arg(long, value_name = "TEMPLATE", conflicts_with_all = ["otlp", "datadog"])]
+    mqtt_topic: Option<String>,
+    /// MQTT QoS, 0 or 1
+    #[arg(long, default_value = "0", value_parser = clap::value_parser!(u8).range(0..=1))]
+    mqtt_qos: u8,
+    /// Ask the broker to keep the last message of every topic for new subscribers
+    #[arg(long)]
+    mqtt_retain: bool,
+    /// Log in to the broker; without a password, it is taken from $MQTT_PASSWORD if set
+    #[arg(long, value_name = "USER[:PASSWORD]")]
+    mqtt_auth: Option<String>,
+    /// Only publish samples of series matching one of these selectors, e.g. `temperature{room!=""}`; repeatable
+    #[arg(long, value_name = "SELECTOR")]
+    mqtt_match: Vec<Selector>,
+}
+
+#[derive(clap::Args)]
+struct ServeArgs {
+    /// Address to listen on
+    #[arg(long, default_value = "0.0.0.0:9100")]
+    listen: String,
+    /// Path to serve the series at
+    #[arg(long, default_value = "/metrics")]
+    metrics_path: String,
+    /// YAML list of transforms to apply, written as under `transforms:` in a pipeline file
+    #[arg(long, value_name = "PATH")]
+    transforms: Option<PathBuf>,
+    /// Serve HTTPS with this PEM certificate chain; needs a build with the `tls` feature
+    #[arg(long, value_name = "PATH", requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+    /// PEM private key of --tls-cert
+    #[arg(long, value_name = "PATH", requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+    /// Require HTTP basic auth by one of the users in this file, one `user:password` per line
+    #[arg(long, value_name = "PATH")]
+    basic_auth_file: Option<PathBuf>,
+}
+
+impl ServeArgs {
+    fn into_config(self, source: exporter::Source) -> Result<ExporterConfig, Box<dyn Error>> {
+        let transforms = read_transforms(self.transforms.as_deref())?;
+        let users = match &self.basic_auth_file {
+            Some(path) => exporter::read_users(path)
+                .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?,
+            None => Vec::new(),
+        };
+        Ok(ExporterConfig {
+            listen: self.listen,
+            metrics_path: self.metrics_path,
+            source,
+            transforms,
+            tls: self
+                .tls_cert
+                .zip(self.tls_key)
+                .map(|(cert, key)| TlsFiles { cert, key }),
+            users,
+        })
+    }
+}
+
+#[derive(clap::Args)]
+struct ScrapeArgs {
+    /// Give up after this long, e.g. 30s
+    #[arg(long, default_value = "10s", value_parser = parse_duration)]
+    timeout: Duration,
+    /// Extra request header as `Name: value`; repeatable
+    #[arg(short = 'H', long = "header", value_name = "HEADER", value_parser = parse_header)]
+    headers: Vec<(String, String)>,
+    /// Log in with HTTP basic auth; without a password, it is read from --credentials-file
+    #[arg(long, value_name = "USER[:PASSWORD]", conflicts_with = "bearer_token")]
+    basic_auth: Option<String>,
+    /// Send `Authorization: Bearer TOKEN`
+    #[arg(long, value_name = "TOKEN", conflicts_with = "credentials_file")]
+    bearer_token: Option<String>,
+    /// Read the basic auth password, or else a bearer token, from this file at every scrape
+    #[arg(long, value_name = "PATH")]
+    credentials_file: Option<PathBuf>,
+    #[command(flatten)]
+    retry: RetryArgs,
+}
+
+impl ScrapeArgs {
+    fn into_options(self) -> ScrapeOptions {
+        ScrapeOptions {
+            timeout: self.timeout,
+            headers: self.headers,
+            auth: scrape_auth(self.basic_auth, self.bearer_token, self.credentials_file),
+            retry: self.retry.into(),
+        }
+    }
+}
+
+#[derive(clap::Args)]
+struct TargetArgs {
+    /// `job` label of the series; set, with `instance`, even for a single endpoint if given
+    #[arg(long)]
+    job: Option<String>,
+    /// Number of endpoints to scrape in parallel
+    #[arg(short, long, default_value_t = 4)]
+    jobs: usize,
+    #[command(flatten)]
+    discovery: DiscoveryArgs,
+    /// `http://` URLs of the endpoints, or `unix:///path/to.sock:/metrics` for Unix domain sockets
+    #[arg(required_unless_present_any = ["file_sd", "dns_sd", "k8s"], value_name = "URL")]
+    urls: Vec<String>,
+}
+
+impl TargetArgs {
+    /// The one URL given, if its series are to be taken as they are
+    /// exposed: there is no other target and no job to label them with.
+    fn single_url(&self) -> Option<&str> {
+        match (self.urls.as_slice(), &self.job) {
+            ([url], None) if self.discovery.is_empty() => Some(url),
+            _ => None,
+        }
+    }
+
+    /// Every target given or discovered, under --job or else `pmv`.
+    fn targets(&self) -> Result<Vec<Target>, Box<dyn Error>> {
+        let job = self.job.as_deref().unwrap_or("pmv");
+        let mut targets: Vec<_> = self
+            .urls
+            .iter()
+            .map(|url| Target {
+                url: url.clone(),
+                job: job.to_string(),
+                labels: Vec::new(),
+            })
+            .collect();
+        targets.extend(self.discovery.targets(job)?);
+        if targets.is_empty() {
+            log::warn!("no targets discovered");
+        }
+        Ok(targets)
+    }
+}
+
+#[derive(clap::Args)]
+struct DiscoveryArgs {
+    /// Also scrape the targets of Prometheus file_sd JSON or YAML files matching this glob; repeatable
+    #[arg(long, value_name = "PATTERN")]
+    file_sd: Vec<String>,
+    /// Also scrape the instances this DNS name resolves to; repeatable
+    #[arg(long, value_name = "NAME")]
+    dns_sd: Vec<String>,
+    /// Record type of --dns-sd names: SRV, A or AAAA
+    #[arg(long, default_value = "SRV", value_parser = RecordType::from_str)]
+    dns_type: RecordType,
+    /// Port of A and AAAA addresses; SRV records carry their own
+    #[arg(long)]
+    dns_port: Option<u16>,
+    /// Also scrape the pods, or the endpoints of services, annotated `prometheus.io/scrape: "true"`;
+    /// needs a build with the `k8s` feature
+    #[arg(long, value_name = "pods|endpoints")]
+    k8s: Option<String>,
+    /// Kubernetes API server, e.g. `http://127.0.0.1:8001` for `kubectl proxy`; the cluster pmv runs in if omitted
+    #[arg(long, value_name = "URL", requires = "k8s")]
+    k8s_api: Option<String>,
+    /// Only look in this namespace; repeatable, all namespaces if omitted
+    #[arg(long, value_name = "NAMESPACE", requires = "k8s")]
+    k8s_namespace: Vec<String>,
+}
+
+impl DiscoveryArgs {
+    fn is_empty(&self) -> bool {
+        self.file_sd.is_empty() && self.dns_sd.is_empty() && self.k8s.is_none()
+    }
+
+    /// The targets every discovery mechanism given finds, with `job` as
+    /// their default job.
+    fn targets(&self, job: &str) -> Result<Vec<Target>, Box<dyn Error>> {
+        let mut targets = Vec::new();
+        if !self.file_sd.is_empty() {
+            let mut sd = FileSd::new(self.file_sd.clone(), job);
+            targets.extend(sd.poll()?.unwrap_or_default());
+        }
+        if !self.dns_sd.is_empty() {
+            let port = match (self.dns_type, self.dns_port) {
+                (RecordType::Srv, _) => 0,
+                (_, Some(port)) => port,
+                (_, None) => return Err("--dns-port is required for A and AAAA lookups".into()),
+            };
+            let mut sd = DnsSd::new(self.dns_sd.clone(), self.dns_type, port, job);
+            targets.extend(sd.poll()?.unwrap_or_default());
+        }
+        if let Some(role) = &self.k8s {
+            targets.extend(k8s_targets(
+                role,
+                self.k8s_api.as_deref(),
+                &self.k8s_namespace,
+                job,
+            )?);
+        }
+        Ok(targets)
+    }
+}
+
+#[cfg(feature = "k8s")]
+fn k8s_targets(
+    role: &str,
+    api: Option<&str>,
+    namespaces: &[String],
+    job: &str,
+) -> Result<Vec<Target>, Box<dyn Error>> {
+    let role: k8s::Role = role.parse()?;
+    let config = match api {
+        Some(url) => k8s::ApiConfig {
+            url: url.to_string(),
+            ..k8s::ApiConfig::default()
+        },
+        None => k8s::ApiConfig::in_cluster()?,
+    };
+    let mut sd = k8s::K8sSd::new(config, role, namespaces.to_vec(), job)?;
+    Ok(sd.poll()?.unwrap_or_default())
+}
+
+#[cfg(not(feature = "k8s"))]
+fn k8s_targets(
+    _role: &str,
+    _api: Option<&str>,
+    _namespaces: &[String],
+    _job: &str,
+) -> Result<Vec<Target>, Box<dyn Error>> {
+    Err("pmv was built without the k8s feature".into())
+}
+
+#[derive(clap::Args)]
+struct ServerArgs {
+    /// Address to listen on
+    #[arg(long, default_value = "127.0.0.1:9091")]
+    listen: String,
+    /// Length of a refresh interval in seconds
+    #[arg(long, default_value_t = 15)]
+    interval: u64,
+    /// Drop series not refreshed within this many intervals
+    #[arg(long)]
+    expire_after: Option<u64>,
+    /// Per-family expiry as NAME=INTERVALS, overriding --expire-after
+    #[arg(long, value_parser = parse_family_expiry)]
+    expire_family: Vec<(String, u64)>,
+    /// Metric name prefix for an InfluxDB measurement as MEASUREMENT=PREFIX
+    #[arg(long, value_parser = parse_influx_measurement)]
+    influx_measurement: Vec<(String, String)>,
+    /// Separator between the measurement prefix and the field name
+    #[arg(long, default_value = "_")]
+    influx_separator: String,
+    /// Requests per second allowed per client address
+    #[arg(long, value_parser = parse_positive)]
+    rate_limit: Option<f64>,
+    /// Requests a client may send back to back; defaults to --rate-limit
+    #[arg(long, requires = "rate_limit", value_parser = parse_positive)]
+    rate_burst: Option<f64>,
+    /// Log every request to stderr
+    #[arg(long)]
+    access_log: bool,
+    /// Keep the history of received samples in this directory
+    #[arg(long)]
+    storage_dir: Option<PathBuf>,
+    /// Drop stored samples older than this, e.g. 7d or 12h
+    #[arg(long, default_value = "7d", value_parser = parse_duration)]
+    retention: Duration,
+    /// Drop the oldest stored data beyond this size, e.g. 512M or 2G
+    #[arg(long, value_parser = parse_size)]
+    storage_max_size: Option<u64>,
+    /// How often stored samples are compacted, e.g. 5m
+    #[arg(long, default_value = "5m", value_parser = parse_duration)]
+    compaction_interval: Duration,
+    /// Build a label index for `match[]` selections once this many series are held
+    #[arg(long, default_value_t = 10_000)]
+    index_min_series: usize,
+    /// YAML list of transforms to apply to every push, written as under `transforms:` in a pipeline file
+    #[arg(long, value_name = "PATH")]
+    transforms: Option<PathBuf>,
+    /// Also accept OTLP metrics exports over gRPC on this address, e.g. 127.0.0.1:4317; needs a build with the `grpc` feature
+    #[arg(long, value_name = "ADDRESS")]
+    otlp_grpc_listen: Option<String>,
+}
+
+impl ServerArgs {
+    fn into_config(self) -> Result<ServerConfig, Box<dyn Error>> {
+        Ok(ServerConfig {
+            listen: self.listen,
+            interval: Duration::from_secs(self.interval),
+            expiry: ExpiryConfig {
+                default_intervals: self.expire_after,
+                per_family: self.expire_family.into_iter().collect::<HashMap<_, _>>(),
+            },
+            influx: InfluxMapping {
+                measurements: self.influx_measurement.into_iter().collect(),
+                separator: self.influx_separator,
+            },
+            rate_limit: self.rate_limit.map(|per_second| RateLimit {
+                per_second,
+                burst: self.rate_burst.unwrap_or(per_second).max(1.0),
+            }),
+            storage_dir: self.storage_dir,
+            storage: StorageConfig {
+                retention: Some(self.retention),
+                max_bytes: self.storage_max_size,
+                ..StorageConfig::default()
+            },
+            compaction_interval: self.compaction_interval,
+            index_min_series: self.index_min_series,
+            transforms: read_transforms(self.transforms.as_deref())?,
+            otlp_grpc_listen: self.otlp_grpc_listen,
+        })
+    }
+}
+
+/// The transforms of a `--transforms` file, if given.
+fn read_transforms(path: Option<&Path>) -> Result<Vec<Arc<dyn Transform>>, Box<dyn Error>> {
+    let Some(path) = path else {
+        return Ok(Vec::new());
+    };
+    let yaml = std::fs::read_to_string(path)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+    Ok(pipeline::transforms_from_yaml(&yaml).map_err(|e| format!("{}: {}", path.display(), e))?)
+}
+
+fn parse_influx_measurement(s: &str) -> Result<(String, String), String> {
+    let (measurement, prefix) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected MEASUREMENT=PREFIX, got {:?}", s))?;
+    Ok((measurement.to_string(), prefix.to_string()))
+}
+
+fn parse_family_expiry(s: &str) -> Result<(String, u64), String> {
+    let (name, intervals) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected NAME=INTERVALS, got {:?}", s))?;
+    let intervals = intervals.parse().map_err(|e| format!("{}", e))?;
+    Ok((name.to_string(), intervals))
+}
+
+/// Parses a duration such as `250ms`, `90s`, `5m`, `12h` or `7d`; a bare number is
+/// seconds.
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    let (number, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(idx) => s.split_at(idx),
+        None => (s, "s"),
+    };
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("invalid duration {:?}", s))?;
+    let millis = match unit {
+        "ms" => 1,
+        "s" => 1000,
+        "m" => 60 * 1000,
+        "h" => 3600 * 1000,
+        "d" => 24 * 3600 * 1000,
+        _ => {
+            return Err(format!(
+                "unknown duration unit {:?}, expected ms, s, m, h or d",
+                unit
+            ))
+        }
+    };
+    number
+        .checked_mul(millis)
+        .map(Duration::from_millis)
+        .ok_or_else(|| format!("duration {:?} too large", s))
+}
+
+/// Parses a size such as `4096`, `512K`, `64M` or `2G` (powers of 1024).
+fn parse_size(s: &str) -> Result<u64, String> {
+    let (number, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(idx) => s.split_at(idx),
+        None => (s, ""),
+    };
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("invalid size {:?}", s))?;
+    let shift = match unit.to_ascii_uppercase().as_str() {
+        "" | "B" => 0,
+        "K" | "KB" | "KIB" => 10,
+        "M" | "MB" | "MIB" => 20,
+        "G" | "GB" | "GIB" => 30,
+        "T" | "TB" | "TIB" => 40,
+        _ => return Err(format!("unknown size unit {:?}", unit)),
+    };
+    number
+        .checked_mul(1 << shift)
+        .ok_or_else(|| format!("size {:?} too large", s))
+}
+
+fn parse_header(s: &str) -> Result<(String, String), String> {
+    match s.split_once(':') {
+        Some((name, value)) if !name.trim().is_empty() => {
+            Ok((name.trim().to_string(), value.trim().to_string()))
+        }
+        _ => Err(format!("expected `Name: value`, got {:?}", s)),
+    }
+}
+
+/// Combines the scrape credential flags, exiting with a usage error if
+/// basic auth has no password or two.
+fn scrape_auth(
+    basic_auth: Option<String>,
+    bearer_token: Option<String>,
+    credentials_file: Option<PathBuf>,
+) -> Option<Auth> {
+    let usage = |kind, message: &str| -> ! { Cli::command().error(kind, message).exit() };
+    match (basic_auth, bearer_token, credentials_file) {
+        (Some(basic), _, file) => {
+            let (username, password) = match (basic.split_once(':'), file) {
+                (Some((username, password)), None) => {
+                    (username, Secret::Value(password.to_string()))
+                }
+                (None, Some(file)) => (basic.as_str(), Secret::File(file)),
+                (Some(_), Some(_)) => usage(
+                    clap::error::ErrorKind::ArgumentConflict,
+                    "--credentials-file cannot be used with a password in --basic-auth",
+                ),
+                (None, None) => usage(
+                    clap::error::ErrorKind::MissingRequiredArgument,
+                    "--basic-auth needs `USER:PASSWORD` or --credentials-file",
+                ),
+            };
+            Some(Auth::Basic {
+                username: username.to_string(),
+                password,
+            })
+        }
+        (None, Some(token), _) => Some(Auth::Bearer(Secret::Value(token))),
+        (None, None, Some(file)) => Some(Auth::Bearer(Secret::File(file))),
+        (None, None, None) => None,
+    }
+}
+
+fn parse_fraction(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(f) if (0.0..=1.0).contains(&f) => Ok(f),
+        _ => Err(format!("expected a fraction between 0 and 1, got {:?}", s)),
+    }
+}
+
+fn parse_positive(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(f) if f.is_finite() && f > 0.0 => Ok(f),
+        _ => Err(format!("expected a positive number, got {:?}", s)),
+    }
+}
+
+/// Parses a point in time as Unix seconds, `now` or `now-<duration>`, into
+/// milliseconds.
+fn parse_time(s: &str) -> Result<i64, String> {
+    if let Some(rest) = s.strip_prefix("now") {
+        let ago = match rest.strip_prefix('-') {
+            Some(duration) => parse_duration(duration)?,
+            None if rest.is_empty() => Duration::ZERO,
+            None => return Err(format!("invalid time {:?}", s)),
+        };
+        return Ok(now_ms() - ago.as_millis() as i64);
+    }
+    let seconds: f64 = s.parse().map_err(|_| {
+        format!(
+            "invalid time {:?}, expected Unix seconds or now-<duration>",
+            s
+        )
+    })?;
+    Ok((seconds * 1000.0) as i64)
+}
+
+fn now_ms() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or(0)
+}
 
-    let metric_text = r#"
-     # HELP http_request_total The total number of HTTP requests.
-     # TYPE http_request_total counter
-     http_request_total{path="/api/v1",method="POST"} 1027
-     http_request_total{path="/api/v1",method="GET"} 4711
-    "#;
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let error_format = cli.error_format;
 
-    // parse
-    for line in metric_text.lines() {
-        match parse_metric_line(line) {
-            Ok((name, value, labels)) => {
-                println!("Name: {}, value: {}, Labels: {:?}", name, value, labels);
+    match run(cli) {
+        Ok(code) => code,
+        Err(err) => {
+            let diagnostic = Diagnostic::from_error(err.as_ref());
+            match error_format {
+                ErrorFormat::Text => eprintln!("error: {}", diagnostic),
+                ErrorFormat::Json => eprintln!("{}", Value::from(vec![diagnostic.to_json()])),
             }
-            Err(e) => eprintln!("Error parsing laine: {}", e),
+            exit_code(diagnostic.kind)
         }
     }
+}
+
+fn run(cli: Cli) -> Result<ExitCode, Box<dyn Error>> {
+    let mut logger =
+        env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn"));
+    if let Some(Command::Receive { server }) = &cli.command {
+        if server.access_log {
+            logger.filter_module("pmv::access", log::LevelFilter::Info);
+        }
+    }
+    logger.init();
+
+    let command = match (cli.command, cli.config) {
+        // Checked here: clap's args_conflicts_with_subcommands would also
+        // reject global flags such as --error-format before the command.
+        (Some(_), Some(_)) => {
+            Cli::command()
+                .error(
+                    clap::error::ErrorKind::ArgumentConflict,
+                    "--config cannot be used with a subcommand",
+                )
+                .exit();
+        }
+        (Some(command), None) => command,
+        (None, Some(config)) => return pipeline(&config, cli.dry_run, cli.interval),
+        (None, None) => {
+            eprint!("{}", Cli::command().render_help());
+            return Ok(ExitCode::from(2));
+        }
+    };
+    match command {
+        Command::Cat {
+            normalize_help,
+            conflict,
+            output,
+            inputs,
+        } => cat(&inputs, normalize_help, conflict, output, &cli.time),
+        Command::Validate {
+            require_type,
+            inputs,
+        } => validate(&inputs, require_type, cli.error_format),
+        Command::Convert {
+            from,
+            to,
+            count_policy,
+            duplicate_policy,
+            anonymize,
+            salt,
+            redact,
+            inputs,
+        } => {
+            let options = ParserOptions {
+                count_policy,
+                duplicate_policy,
+            };
+            let anonymize = (!anonymize.is_empty()).then(|| Anonymize::new(anonymize, &salt));
+            let redact = (!redact.is_empty()).then(|| Redact::values(redact));
+            convert(
+                &inputs,
+                from,
+                to,
+                options,
+                anonymize.as_ref(),
+                redact.as_ref(),
+            )
+        }
+        Command::Push {
+            url,
+            otlp,
+            datadog,
+            datadog_state,
+            mqtt,
+            format,
+            protocol,
+            batch_size,
+            timeout,
+            retry,
+            inputs,
+        } => {
+            if mqtt.mqtt_topic.is_some() {
+                return push_mqtt(&inputs, format, &url, mqtt, timeout);
+            }
+            if datadog {
+                return push_datadog(
+                    &inputs,
+                    format,
+                    &url,
+                    datadog_state.as_deref(),
+                    batch_size,
+                    timeout,
+                    retry.into(),
+                );
+            }
+            let target = match otlp {
+                Some(transport) => PushTarget::Otlp(
+                    Exporter::new(url, transport)
+                        .timeout(timeout)
+                        .retry(retry.into()),
+                ),
+                None => PushTarget::RemoteWrite(
+                    remote_write::Client::new(url)
+                        .protocol(protocol)
+                        .max_samples(batch_size)
+                        .timeout(timeout)
+                        .retry(retry.into()),
+                ),
+            };
+            push(&inputs, format, &target)
+        }
+        Command::Grep {
+            pattern,
+            labels,
+            predicates,
+            rules,
+            output,
+            inputs,
+        } => grep(
+            &inputs,
+            &pattern,
+            &labels,
+            &predicates,
+            &rules,
+            output,
+            &cli.time,
+        ),
+        Command::Diff {
+            tolerance,
+            json,
+            interactive,
+            format,
+            predicates,
+            old,
+            new,
+        } => {
+            let output = if interactive {
+                DiffOutput::Interactive
+            } else if json {
+                DiffOutput::Json
+            } else {
+                DiffOutput::Text
+            };
+            diff(&old, &new, format, &predicates, tolerance, output)
+        }
+        Command::Merge {
+            format,
+            conflict,
+            label,
+            files,
+        } => merge(&files, format, conflict, label.as_deref()),
+        Command::Flatten {
+            format,
+            fingerprint,
+            inputs,
+        } => flatten(&inputs, format, fingerprint),
+        Command::Quantile {
+            q,
+            family,
+            format,
+            inputs,
+        } => quantile(&inputs, format, q, family.as_deref()),
+        Command::Calc {
+            expr,
+            on,
+            ignoring,
+            name,
+            format,
+            left,
+            right,
+        } => {
+            let matching = if on.is_empty() {
+                Grouping::Without(ignoring)
+            } else {
+                Grouping::By(on)
+            };
+            calc(&expr, &matching, &name, format, &left, right.as_deref())
+        }
+        Command::Sort { format, file } => sort(file.as_deref(), format),
+        Command::Split {
+            format,
+            out,
+            by,
+            file,
+        } => split(file.as_deref(), format, &out, &by),
+        Command::Scrape {
+            scrape: args,
+            format,
+            targets,
+        } => scrape(&targets, &args.into_options(), format),
+        Command::Sample {
+            per_family,
+            fraction,
+            seed,
+            format,
+            file,
+        } => {
+            let size = match (per_family, fraction) {
+                (Some(k), _) => SampleSize::PerFamily(k),
+                (None, Some(f)) => SampleSize::Fraction(f),
+                (None, None) => unreachable!("clap requires one of them"),
+            };
+            sample(file.as_deref(), format, size, seed)
+        }
+        Command::Top {
+            count,
+            family,
+            since,
+            file,
+        } => top(file.as_deref(), count, family.as_deref(), since.as_deref()),
+        Command::Watch {
+            interval,
+            always,
+            summary,
+            format,
+            file,
+        } => watch(&file, interval, always, summary, format, &cli.time),
+        Command::Check {
+            command: CheckCommand::Metrics { file },
+        } => check_metrics(file.as_deref()),
+        Command::Check {
+            command: CheckCommand::Config { files },
+        } => Ok(check_config(&files)),
+        Command::Storage {
+            command: StorageCommand::Repair { dry_run, dir },
+        } => storage_repair(&dir, dry_run),
+        Command::Storage {
+            command:
+                StorageCommand::Export {
+                    start,
+                    end,
+                    to,
+                    url,
+                    batch_size,
+                    timeout,
+                    retry,
+                    dir,
+                },
+        } => storage_export(
+            &dir,
+            start.unwrap_or(i64::MIN),
+            end.unwrap_or_else(now_ms),
+            to,
+            url.as_deref(),
+            batch_size,
+            &PushOptions {
+                timeout: Some(timeout),
+                retry: retry.into(),
+            },
+        ),
+        Command::Migrate {
+            spec,
+            dry_run,
+            format,
+            paths,
+        } => migrate(&spec, &paths, format, dry_run),
+        Command::Rules {
+            rules,
+            previous,
+            interval,
+            only_recorded,
+            format,
+            file,
+        } => record(
+            &rules,
+            file.as_deref(),
+            previous.as_deref(),
+            interval,
+            only_recorded,
+            format,
+        ),
+        Command::Query {
+            start,
+            end,
+            explain,
+            format,
+            dir,
+            selectors,
+        } => query(
+            &dir,
+            start.unwrap_or(i64::MIN),
+            end.unwrap_or_else(now_ms),
+            &selectors,
+            explain,
+            format,
+        ),
+        Command::Serve {
+            serve: args,
+            format,
+            file,
+        } => serve(args.into_config(exporter::Source::File { path: file, format })?),
+        Command::Relay {
+            rules,
+            scrape,
+            serve: args,
+            targets,
+        } => {
+            let options = scrape.into_options();
+            let source = match targets.single_url() {
+                Some(url) => exporter::Source::Url {
+                    url: url.to_string(),
+                    options,
+                },
+                None => exporter::Source::Targets {
+                    targets: targets.targets()?,
+                    options,
+                    workers: targets.jobs,
+                },
+            };
+            let mut config = args.into_config(source)?;
+            if !rules.is_empty() {
+                let filter = Builtin::Filter {
+                    rules,
+                    predicates: Vec::new(),
+                };
+                config.transforms.insert(0, Arc::new(filter));
+            }
+            serve(config)
+        }
+        Command::Source {
+            config,
+            format,
+            output,
+            plugin,
+        } => source(&plugin, &config, format, output, &cli.time),
+        Command::Completions { shell } => {
+            clap_complete::generate(shell, &mut Cli::command(), "pmv", &mut io::stdout());
+            Ok(ExitCode::SUCCESS)
+        }
+        Command::Receive { server } => {
+            server::serve(server.into_config()?).map_err(|e| e as Box<dyn Error>)?;
+            Ok(ExitCode::SUCCESS)
+        }
+        Command::Grpc {
+            listen,
+            transforms,
+            format,
+        } => serve_grpc(&listen, transforms.as_deref(), format),
+    }
+}
+
+/// Whether `path` means standard input: omitted or `-`.
+fn is_stdin(path: Option<&Path>) -> bool {
+    path.is_none_or(|p| p == Path::new("-"))
+}
+
+fn open_input(path: Option<&Path>) -> io::Result<Box<dyn Read>> {
+    match path {
+        Some(p) if !is_stdin(path) => {
+            let file = std::fs::File::open(p)
+                .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", p.display(), e)))?;
+            Ok(Box::new(file))
+        }
+        _ => Ok(Box::new(io::stdin())),
+    }
+}
 
+/// Standard input can only be read once, so at most one input may use it.
+fn check_stdin_once<'a>(paths: impl IntoIterator<Item = Option<&'a Path>>) -> io::Result<()> {
+    if paths.into_iter().filter(|p| is_stdin(*p)).count() > 1 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "only one input can be read from stdin",
+        ));
+    }
     Ok(())
 }
 
-fn parse_metric_line(line: &str) -> Result<(String, f64, HashMap<String, String>), Box<dyn Error>> {
-    if !line.starts_with("#") && !line.is_empty() {
-        let parts: Vec<&str> = line.split_whitespace().collect();
-        if parts.len() >= 2 {
-            let parts_except_value: Vec<&str> = parts[0].splitn(2, '{').collect();
-            let value = parts[1].parse()?;
+fn input_name(path: Option<&Path>) -> String {
+    match path {
+        Some(p) if !is_stdin(path) => p.display().to_string(),
+        _ => "<stdin>".to_string(),
+    }
+}
 
-            // example: name{labels} value
-            let name = parts_except_value[0].to_string();
-            let labels_str = parts_except_value[1].splitn(2, '}').collect::<Vec<_>>()[0];
+fn cat(
+    inputs: &Inputs,
+    normalize_help: bool,
+    conflict: ConflictPolicy,
+    output: Output,
+    time: &TimeStyle,
+) -> Result<ExitCode, Box<dyn Error>> {
+    let read = |path: Option<&Path>| -> Result<_, Box<dyn Error>> {
+        let reader = open_input(path)?;
+        let mut parser = TextParser::new(BufReader::new(reader)).normalize_help(normalize_help);
+        // Families are written sorted by name so repeated runs diff cleanly.
+        Ok(format::sort_by_name(parser.text_to_metric_families()?))
+    };
 
-            let mut labels = HashMap::new();
+    inputs.run_merged(conflict, read, |mfs| {
+        output.write(mfs, time)?;
+        Ok(ExitCode::SUCCESS)
+    })
+}
 
-            for part in labels_str.split(',').collect::<Vec<_>>().iter() {
-                let label_pair: Vec<&str> = part.splitn(2, '=').collect();
-                if label_pair.len() != 2 {
-                    continue;
+fn validate(
+    inputs: &Inputs,
+    require_type: bool,
+    error_format: ErrorFormat,
+) -> Result<ExitCode, Box<dyn Error>> {
+    let reports = inputs.read_each(|path| {
+        let reader = open_input(path)?;
+        Ok(TextParser::new(BufReader::new(reader))
+            .strict(true)
+            .require_type(require_type)
+            .duplicate_policy(DuplicatePolicy::Error)
+            .validate())
+    })?;
+
+    match error_format {
+        ErrorFormat::Text => {
+            for (name, errors) in &reports {
+                for err in errors {
+                    println!("{}:{}:{}: {}", name, err.line, err.column, err.msg);
                 }
+            }
+        }
+        ErrorFormat::Json => {
+            let diagnostics: Vec<_> = reports
+                .iter()
+                .flat_map(|(name, errors)| errors.iter().map(|e| Diagnostic::validation(name, e)))
+                .map(|d| d.to_json())
+                .collect();
+            println!("{}", serde_json::to_string_pretty(&diagnostics)?);
+        }
+    }
+
+    if reports.iter().all(|(_, errors)| errors.is_empty()) {
+        Ok(ExitCode::SUCCESS)
+    } else {
+        Ok(ExitCode::FAILURE)
+    }
+}
 
-                let label_key = label_pair[0].trim_matches('"').to_string();
-                let label_val = label_pair[1].trim_matches('"').to_string();
-                labels.insert(label_key, label_val);
+fn check_metrics(path: Option<&Path>) -> Result<ExitCode, Box<dyn Error>> {
+    let reader = open_input(path)?;
+    let mfs = match TextParser::new(BufReader::new(reader)).text_to_metric_families() {
+        Ok(mfs) => format::sort_by_name(mfs),
+        Err(e) => {
+            eprintln!("error while linting: {}", e);
+            return Ok(ExitCode::FAILURE);
+        }
+    };
+    let problems = lint::lint(&mfs);
+    for problem in &problems {
+        eprintln!("{}", problem);
+    }
+    if problems.is_empty() {
+        Ok(ExitCode::SUCCESS)
+    } else {
+        Ok(ExitCode::from(3))
+    }
+}
+
+fn check_config(paths: &[PathBuf]) -> ExitCode {
+    let mut failed = false;
+    for path in paths {
+        println!("Checking {}", path.display());
+        let checked = std::fs::read_to_string(path)
+            .map_err(|e| e.to_string())
+            .and_then(|yaml| Pipeline::from_yaml(&yaml).map_err(|e| e.to_string()));
+        match checked {
+            Ok(_) => println!(
+                " SUCCESS: {} is valid pmv config file syntax",
+                path.display()
+            ),
+            Err(e) => {
+                eprintln!("  FAILED: {}", e);
+                failed = true;
             }
+        }
+        println!();
+    }
+    if failed {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    }
+}
+
+fn read_format(
+    format: Format,
+) -> impl Fn(Option<&Path>) -> Result<Vec<MetricFamily>, Box<dyn Error>> + Sync {
+    move |path| format::read_metric_families(format, open_input(path)?)
+}
+
+fn pipeline(
+    config: &Path,
+    dry_run: bool,
+    interval: Option<Duration>,
+) -> Result<ExitCode, Box<dyn Error>> {
+    let yaml = std::fs::read_to_string(config)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", config.display(), e)))?;
+    let pipeline =
+        Pipeline::from_yaml(&yaml).map_err(|e| format!("{}: {}", config.display(), e))?;
+
+    check_stdin_once(pipeline.inputs.iter().map(|i| Some(Path::new(&i.path))))?;
+
+    if dry_run {
+        let mut out = io::BufWriter::new(io::stdout().lock());
+        let reports = pipeline
+            .dry_run(read_pipeline_inputs(&pipeline)?)
+            .map_err(|e| e as Box<dyn Error>)?;
+        for report in reports {
+            write!(out, "{}", report)?;
+        }
+        out.flush()?;
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    let Some(interval) = interval else {
+        let firing = run_pipeline(&pipeline)?;
+        for f in &firing {
+            eprintln!("firing: {}", f);
+        }
+        return Ok(if firing.is_empty() {
+            ExitCode::SUCCESS
+        } else {
+            ExitCode::FAILURE
+        });
+    };
+
+    // Agent mode: a failed run is logged and retried at the next interval.
+    loop {
+        match run_pipeline(&pipeline) {
+            Ok(firing) if firing.is_empty() => {}
+            Ok(firing) => match &pipeline.webhook {
+                Some(url) => {
+                    if let Err(e) = alert::post(url, &firing, Duration::from_secs(10)) {
+                        log::error!("{}", e);
+                    }
+                }
+                None => {
+                    for f in &firing {
+                        log::warn!("firing: {}", f);
+                    }
+                }
+            },
+            Err(e) => log::error!("{}: {}", config.display(), e),
+        }
+        std::thread::sleep(interval);
+    }
+}
+
+/// Reads and merges the inputs of `pipeline`.
+fn read_pipeline_inputs(pipeline: &Pipeline) -> Result<Vec<MetricFamily>, Box<dyn Error>> {
+    let mut inputs = Vec::new();
+    for input in &pipeline.inputs {
+        let files = Inputs {
+            files: vec![input.path.clone()],
+            per_file: false,
+            jobs: 1,
+        };
+        let read = files.read_each(read_format(input.format))?;
+        inputs.extend(read.into_iter().map(|(_, mfs)| match &input.namespace {
+            Some(ns) => namespace::namespace(&mfs, ns),
+            None => mfs,
+        }));
+    }
+    Ok(merge::merge(inputs, ConflictPolicy::LastWins)?)
+}
+
+/// Runs `pipeline` once, returning the alerts that fired.
+fn run_pipeline(pipeline: &Pipeline) -> Result<Vec<Firing>, Box<dyn Error>> {
+    let mfs = pipeline
+        .apply(read_pipeline_inputs(pipeline)?)
+        .map_err(|e| e as Box<dyn Error>)?;
+
+    let limiter = LabelLimiter::new();
+    for output in &pipeline.outputs {
+        let limited;
+        let mfs = match &output.label_limit {
+            Some(limit) => {
+                limited = limiter.enforce(&output.path, limit, &mfs)?;
+                for (action, what) in [
+                    (LimitAction::DropSample, "dropped"),
+                    (LimitAction::DropLabels, "trimmed the labels of"),
+                ] {
+                    let n = limiter.enforced(&output.path, action);
+                    if n > 0 {
+                        log::warn!(
+                            "{}: {} {} series over {} labels",
+                            output.path,
+                            what,
+                            n,
+                            limit.max_labels
+                        );
+                    }
+                }
+                &limited
+            }
+            None => &mfs,
+        };
+        if output.path == "-" {
+            let mut out = io::BufWriter::new(io::stdout().lock());
+            format::write_metric_families(output.format, &mut out, mfs)?;
+            out.flush()?;
+            continue;
+        }
+        let file = std::fs::File::create(&output.path)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", output.path, e)))?;
+        let mut out = io::BufWriter::new(file);
+        format::write_metric_families(output.format, &mut out, mfs)?;
+        out.flush()?;
+    }
+    Ok(alert::evaluate(&pipeline.alerts, &mfs))
+}
+
+#[cfg(feature = "mqtt")]
+fn push_mqtt(
+    inputs: &Inputs,
+    format: Format,
+    url: &str,
+    args: MqttArgs,
+    timeout: Duration,
+) -> Result<ExitCode, Box<dyn Error>> {
+    let topic: mqtt::Topic = args.mqtt_topic.as_deref().unwrap_or_default().parse()?;
+    let qos = if args.mqtt_qos == 1 {
+        mqtt::QoS::AtLeastOnce
+    } else {
+        mqtt::QoS::AtMostOnce
+    };
+    let mut publisher = mqtt::Publisher::new(url, topic)
+        .qos(qos)
+        .retain(args.mqtt_retain)
+        .timeout(timeout);
+    if let Some(auth) = args.mqtt_auth {
+        publisher = match auth.split_once(':') {
+            Some((user, password)) => publisher.credentials(user, Some(password.to_string())),
+            None => publisher.credentials(auth, std::env::var("MQTT_PASSWORD").ok()),
+        };
+    }
+
+    inputs.run(read_format(format), |mfs| {
+        let published = publisher.publish(mfs, &args.mqtt_match)?;
+        eprintln!("published {} samples", published);
+        Ok(ExitCode::SUCCESS)
+    })
+}
+
+#[cfg(not(feature = "mqtt"))]
+fn push_mqtt(
+    _inputs: &Inputs,
+    _format: Format,
+    _url: &str,
+    _args: MqttArgs,
+    _timeout: Duration,
+) -> Result<ExitCode, Box<dyn Error>> {
+    Err("pmv was built without the mqtt feature".into())
+}
+
+#[cfg(feature = "grpc")]
+fn serve_grpc(
+    listen: &str,
+    transforms: Option<&Path>,
+    format: Format,
+) -> Result<ExitCode, Box<dyn Error>> {
+    let transforms = read_transforms(transforms)?;
+    let pipeline = (!transforms.is_empty()).then(|| Pipeline {
+        inputs: Vec::new(),
+        transforms,
+        outputs: Vec::new(),
+        alerts: Vec::new(),
+        webhook: None,
+    });
+    let service = grpc::Service::new(
+        pipeline,
+        Box::new(move |mfs| {
+            let mut out = io::stdout().lock();
+            format::write_metric_families(format, &mut out, mfs)
+                .and_then(|()| Ok(out.flush()?))
+                .map_err(|e| e.to_string())
+        }),
+    );
+    let listener = std::net::TcpListener::bind(listen)?;
+    log::info!("listening on {}", listen);
+    grpc::server::Server::new()
+        .with_service(service)
+        .serve(listener)?;
+    Ok(ExitCode::SUCCESS)
+}
+
+#[cfg(not(feature = "grpc"))]
+fn serve_grpc(
+    _listen: &str,
+    _transforms: Option<&Path>,
+    _format: Format,
+) -> Result<ExitCode, Box<dyn Error>> {
+    Err("pmv was built without the grpc feature".into())
+}
+
+#[cfg(feature = "datadog")]
+fn push_datadog(
+    inputs: &Inputs,
+    format: Format,
+    site: &str,
+    state: Option<&Path>,
+    batch_size: usize,
+    timeout: Duration,
+    retry: Retry,
+) -> Result<ExitCode, Box<dyn Error>> {
+    let api_key = std::env::var("DD_API_KEY").map_err(|_| "--datadog needs $DD_API_KEY")?;
+    let client = datadog::Client::new(site, api_key)
+        .max_series(batch_size)
+        .timeout(timeout)
+        .retry(retry);
+    let previous = match state {
+        Some(path) if path.exists() => serde_json::from_slice(&std::fs::read(path)?)
+            .map_err(|e| format!("{}: {}", path.display(), e))?,
+        _ => HashMap::new(),
+    };
+    let mut converter = DatadogConverter::new().with_state(previous);
+
+    let code = inputs.run(read_format(format), |mfs| {
+        let series = converter.convert(mfs, now_ms() / 1000);
+        let requests = client.send(&series)?;
+        eprintln!("sent {} series in {} requests", series.len(), requests);
+        Ok(ExitCode::SUCCESS)
+    })?;
+    if let Some(path) = state {
+        std::fs::write(path, serde_json::to_vec(converter.state())?)?;
+    }
+    Ok(code)
+}
+
+#[cfg(not(feature = "datadog"))]
+fn push_datadog(
+    _inputs: &Inputs,
+    _format: Format,
+    _site: &str,
+    _state: Option<&Path>,
+    _batch_size: usize,
+    _timeout: Duration,
+    _retry: Retry,
+) -> Result<ExitCode, Box<dyn Error>> {
+    Err("pmv was built without the datadog feature".into())
+}
+
+enum PushTarget {
+    RemoteWrite(remote_write::Client),
+    Otlp(Exporter),
+}
+
+fn push(inputs: &Inputs, format: Format, target: &PushTarget) -> Result<ExitCode, Box<dyn Error>> {
+    inputs.run(read_format(format), |mfs| {
+        let requests = match target {
+            PushTarget::RemoteWrite(client) => client.send(mfs, now_ms())?,
+            PushTarget::Otlp(exporter) => {
+                exporter.export(mfs)?;
+                1
+            }
+        };
+        let samples: usize = mfs.iter().map(|mf| flatten::flatten_family(mf).len()).sum();
+        eprintln!("sent {} samples in {} requests", samples, requests);
+        Ok(ExitCode::SUCCESS)
+    })
+}
+
+fn convert(
+    inputs: &Inputs,
+    from: Format,
+    to: Format,
+    options: ParserOptions,
+    anonymize: Option<&Anonymize>,
+    redact: Option<&Redact>,
+) -> Result<ExitCode, Box<dyn Error>> {
+    let read = |path: Option<&Path>| -> Result<_, Box<dyn Error>> {
+        let (mfs, stats) = format::read_metric_families_with(from, open_input(path)?, options)?;
+        let name = input_name(path);
+        if stats.clamped_counts > 0 {
+            log::warn!(
+                "{}: clamped {} out-of-range counts",
+                name,
+                stats.clamped_counts
+            );
+        }
+        if stats.skipped_counts > 0 {
+            log::warn!(
+                "{}: skipped {} out-of-range counts",
+                name,
+                stats.skipped_counts
+            );
+        }
+        if stats.duplicate_samples > 0 {
+            log::warn!(
+                "{}: resolved {} duplicate samples",
+                name,
+                stats.duplicate_samples
+            );
+        }
+        let mfs = match redact {
+            Some(config) => pmv::redact::redact(&mfs, config),
+            None => mfs,
+        };
+        Ok(match anonymize {
+            Some(config) => pmv::anonymize::anonymize(&mfs, config),
+            None => mfs,
+        })
+    };
+    inputs.run(read, |mfs| {
+        let mut out = io::BufWriter::new(io::stdout().lock());
+        format::write_metric_families(to, &mut out, mfs)?;
+        out.flush()?;
+        Ok(ExitCode::SUCCESS)
+    })
+}
+
+fn grep(
+    inputs: &Inputs,
+    pattern: &Regex,
+    labels: &[Matcher],
+    predicates: &[ValuePredicate],
+    rules: &[FilterRule],
+    output: Output,
+    time: &TimeStyle,
+) -> Result<ExitCode, Box<dyn Error>> {
+    inputs.run(read_format(Format::Text), |mfs| {
+        let mut matched = grep::grep(mfs, pattern, labels);
+        if !rules.is_empty() {
+            matched = grep::filter(&matched, rules);
+        }
+        if !predicates.is_empty() {
+            matched = grep::filter_values(&matched, predicates);
+        }
+
+        output.write(&matched, time)?;
+
+        if matched.is_empty() {
+            Ok(ExitCode::FAILURE)
+        } else {
+            Ok(ExitCode::SUCCESS)
+        }
+    })
+}
+
+/// How `pmv diff` shows the changes.
+#[derive(Clone, Copy)]
+enum DiffOutput {
+    Text,
+    Json,
+    Interactive,
+}
+
+fn calc(
+    expr: &Expr,
+    matching: &Grouping,
+    name: &str,
+    format: Format,
+    left: &Path,
+    right: Option<&Path>,
+) -> Result<ExitCode, Box<dyn Error>> {
+    check_stdin_once([Some(left)].into_iter().chain(right.map(Some)))?;
+    let left_mfs = format::read_metric_families(format, open_input(Some(left))?)?;
+    let right_mfs = match right {
+        Some(right) => format::read_metric_families(format, open_input(Some(right))?)?,
+        None => left_mfs.clone(),
+    };
+    let result = binop::evaluate(expr, &left_mfs, &right_mfs, matching, name)?;
+
+    let mut out = io::BufWriter::new(io::stdout().lock());
+    format::write_metric_families(format, &mut out, &[result])?;
+    out.flush()?;
+
+    Ok(ExitCode::SUCCESS)
+}
+
+fn diff(
+    old: &Path,
+    new: &Path,
+    format: Format,
+    predicates: &[ValuePredicate],
+    tolerance: f64,
+    output: DiffOutput,
+) -> Result<ExitCode, Box<dyn Error>> {
+    check_stdin_once([Some(old), Some(new)])?;
+    let mut old_mfs = format::read_metric_families(format, open_input(Some(old))?)?;
+    let mut new_mfs = format::read_metric_families(format, open_input(Some(new))?)?;
+    if !predicates.is_empty() {
+        old_mfs = grep::filter_values(&old_mfs, predicates);
+        new_mfs = grep::filter_values(&new_mfs, predicates);
+    }
+    let changes = diff::diff(&old_mfs, &new_mfs, tolerance);
+    let differ = !changes.is_empty();
+
+    let mut out = io::BufWriter::new(io::stdout().lock());
+    match output {
+        DiffOutput::Text => {
+            for change in &changes {
+                writeln!(out, "{}", change)?;
+            }
+        }
+        DiffOutput::Json => {
+            let changes: Vec<_> = changes.iter().map(diff::Change::to_json).collect();
+            serde_json::to_writer_pretty(&mut out, &changes)?;
+            writeln!(out)?;
+        }
+        DiffOutput::Interactive if differ => diff_view::run(changes)?,
+        DiffOutput::Interactive => eprintln!("no differences"),
+    }
+    out.flush()?;
+
+    // Like diff(1), exit non-zero when the inputs differ.
+    if !differ {
+        Ok(ExitCode::SUCCESS)
+    } else {
+        Ok(ExitCode::FAILURE)
+    }
+}
+
+fn merge(
+    paths: &[PathBuf],
+    format: Format,
+    conflict: ConflictPolicy,
+    label: Option<&str>,
+) -> Result<ExitCode, Box<dyn Error>> {
+    check_stdin_once(paths.iter().map(|p| Some(p.as_path())))?;
+    let mut inputs = Vec::with_capacity(paths.len());
+    for path in paths {
+        let mut mfs = format::read_metric_families(format, open_input(Some(path))?)?;
+        if let Some(label) = label {
+            let source = match path.file_stem() {
+                Some(stem) if !is_stdin(Some(path)) => stem.to_string_lossy(),
+                _ => "stdin".into(),
+            };
+            merge::add_label(&mut mfs, label, &source);
+        }
+        inputs.push(mfs);
+    }
+    let mfs = merge::merge(inputs, conflict)?;
+
+    let mut out = io::BufWriter::new(io::stdout().lock());
+    format::write_metric_families(format, &mut out, &mfs)?;
+    out.flush()?;
+
+    Ok(ExitCode::SUCCESS)
+}
 
-            return Ok((name, value, labels));
+fn flatten(inputs: &Inputs, format: Format, fingerprint: bool) -> Result<ExitCode, Box<dyn Error>> {
+    inputs.run(read_format(format), |mfs| {
+        let mut out = io::BufWriter::new(io::stdout().lock());
+        for s in flatten::flatten(mfs) {
+            if fingerprint {
+                write!(out, "{:016x} ", s.fingerprint())?;
+            }
+            write!(
+                out,
+                "{} {}",
+                s.series_key(),
+                text_encode::format_float(s.value)
+            )?;
+            if let Some(ts) = s.timestamp_ms {
+                write!(out, " {}", ts)?;
+            }
+            writeln!(out)?;
+        }
+        out.flush()?;
+
+        Ok(ExitCode::SUCCESS)
+    })
+}
+
+fn quantile(
+    inputs: &Inputs,
+    format: Format,
+    q: f64,
+    family: Option<&str>,
+) -> Result<ExitCode, Box<dyn Error>> {
+    inputs.run(read_format(format), |mfs| {
+        let mut out = io::BufWriter::new(io::stdout().lock());
+        let mut found = false;
+        for mf in mfs {
+            if mf.get_field_type() != MetricType::HISTOGRAM
+                || family.is_some_and(|f| f != mf.get_name())
+            {
+                continue;
+            }
+            for m in mf.get_metric() {
+                found = true;
+                let estimate = FlatSample {
+                    family: mf.get_name().to_string(),
+                    metric_type: MetricType::GAUGE,
+                    name: mf.get_name().to_string(),
+                    labels: m
+                        .get_label()
+                        .iter()
+                        .map(|l| (l.get_name().to_string(), l.get_value().to_string()))
+                        .collect(),
+                    value: histogram_quantile(q, m.get_histogram()),
+                    timestamp_ms: None,
+                };
+                writeln!(
+                    out,
+                    "{} {}",
+                    estimate.series_key(),
+                    text_encode::format_float(estimate.value)
+                )?;
+            }
+        }
+        out.flush()?;
+
+        if found {
+            Ok(ExitCode::SUCCESS)
+        } else {
+            Ok(ExitCode::FAILURE)
+        }
+    })
+}
+
+fn watch(
+    path: &Path,
+    interval: Duration,
+    always: bool,
+    summary: bool,
+    format: Format,
+    time: &TimeStyle,
+) -> Result<ExitCode, Box<dyn Error>> {
+    let mut watcher = FileWatcher::new(path);
+    // Summaries are redrawn in place, unless the output goes to a file.
+    let redraw = summary && io::stdout().is_terminal();
+    let mut last: Option<Vec<MetricFamily>> = None;
+
+    loop {
+        let changed = match watcher.changed() {
+            Ok(changed) => changed,
+            // The file may be missing for a moment while it is replaced.
+            Err(e) if e.kind() == io::ErrorKind::NotFound => false,
+            Err(e) => return Err(e.into()),
+        };
+        if changed || always {
+            let mut out = io::BufWriter::new(io::stdout().lock());
+            if redraw {
+                write!(out, "\x1b[2J\x1b[H")?;
+            }
+            // The header shows when the file was read, which is always now.
+            let at = match time {
+                TimeStyle::Relative => TimeStyle::Utc.format(now_ms()),
+                _ => time.format(now_ms()),
+            };
+            writeln!(out, "==> {} at {} <==", path.display(), at)?;
+
+            // A broken file is reported, not fatal: the producer is likely
+            // being debugged and will write a fixed one. So is a missing
+            // one, with --always or if it was replaced since the check.
+            let read = match open_input(Some(path)) {
+                Ok(input) => format::read_metric_families(format, input),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Err(e.into()),
+                Err(e) => return Err(e.into()),
+            };
+            match read {
+                Ok(mfs) if summary => {
+                    writeln!(out, "{}", Summary::new(last.as_deref(), &mfs))?;
+                    // How stale the producer's data is.
+                    let newest = flatten::flatten(&mfs)
+                        .iter()
+                        .filter_map(|s| s.timestamp_ms)
+                        .max();
+                    if let Some(ms) = newest {
+                        writeln!(out, "newest sample {}", TimeStyle::Relative.format(ms))?;
+                    }
+                    last = Some(mfs);
+                }
+                Ok(mfs) => format::write_metric_families(Format::Text, &mut out, &mfs)?,
+                Err(e) => writeln!(out, "error: {}", e)?,
+            }
+            if !redraw {
+                writeln!(out)?;
+            }
+            out.flush()?;
+        }
+        std::thread::sleep(interval);
+    }
+}
+
+fn serve(config: ExporterConfig) -> Result<ExitCode, Box<dyn Error>> {
+    exporter::serve(config).map_err(|e| e as Box<dyn Error>)?;
+    Ok(ExitCode::SUCCESS)
+}
+
+#[cfg(feature = "plugins")]
+fn source(
+    path: &Path,
+    config: &str,
+    format: Option<Format>,
+    output: Output,
+    time: &TimeStyle,
+) -> Result<ExitCode, Box<dyn Error>> {
+    let plugin = Plugin::load(path)?;
+    let source = plugin.open(config)?;
+    let format = format
+        .or_else(|| Format::from_content_type(&source.content_type()?))
+        .unwrap_or(Format::Text);
+    output.write(&format::read_metric_families(format, source)?, time)?;
+    Ok(ExitCode::SUCCESS)
+}
+
+#[cfg(not(feature = "plugins"))]
+fn source(
+    _path: &Path,
+    _config: &str,
+    _format: Option<Format>,
+    _output: Output,
+    _time: &TimeStyle,
+) -> Result<ExitCode, Box<dyn Error>> {
+    Err("pmv was built without the plugins feature".into())
+}
+
+fn sort(path: Option<&Path>, format: Format) -> Result<ExitCode, Box<dyn Error>> {
+    let mut mfs = format::read_metric_families(format, open_input(path)?)?;
+    format::sort_series(&mut mfs);
+
+    let mut out = io::BufWriter::new(io::stdout().lock());
+    format::write_metric_families(format, &mut out, &mfs)?;
+    out.flush()?;
+
+    Ok(ExitCode::SUCCESS)
+}
+
+fn split(
+    path: Option<&Path>,
+    format: Format,
+    out_dir: &Path,
+    by: &SplitBy,
+) -> Result<ExitCode, Box<dyn Error>> {
+    let mfs = format::read_metric_families(format, open_input(path)?)?;
+    let docs = split::split(&mfs, by)?;
+    std::fs::create_dir_all(out_dir)?;
+
+    for (stem, mfs) in &docs {
+        let file_path = out_dir.join(format!("{}.{}", stem, format.extension()));
+        let mut out = io::BufWriter::new(std::fs::File::create(&file_path)?);
+        format::write_metric_families(format, &mut out, mfs)?;
+        out.flush()?;
+    }
+    eprintln!("wrote {} files to {}", docs.len(), out_dir.display());
+
+    Ok(ExitCode::SUCCESS)
+}
+
+fn sample(
+    path: Option<&Path>,
+    format: Format,
+    size: SampleSize,
+    seed: u64,
+) -> Result<ExitCode, Box<dyn Error>> {
+    let mfs = format::read_metric_families(format, open_input(path)?)?;
+    let mut out = io::BufWriter::new(io::stdout().lock());
+    format::write_metric_families(format, &mut out, &sample::sample(&mfs, size, seed))?;
+    out.flush()?;
+    Ok(ExitCode::SUCCESS)
+}
+
+fn top(
+    path: Option<&Path>,
+    count: usize,
+    family: Option<&str>,
+    since: Option<&Path>,
+) -> Result<ExitCode, Box<dyn Error>> {
+    if since.is_some() {
+        check_stdin_once([path, since])?;
+    }
+    let mfs = format::read_metric_families(Format::Text, open_input(path)?)?;
+    let mut samples = match since {
+        Some(old) => {
+            let old_mfs = format::read_metric_families(Format::Text, open_input(Some(old))?)?;
+            top::counter_deltas(&old_mfs, &mfs)
+        }
+        None => flatten::flatten(&mfs),
+    };
+    if let Some(family) = family {
+        samples.retain(|s| s.family == family);
+    }
+
+    let mut out = io::BufWriter::new(io::stdout().lock());
+    for s in top::top(samples, count) {
+        writeln!(out, "{} {}", s.series(), text_encode::format_float(s.value))?;
+    }
+    out.flush()?;
+
+    Ok(ExitCode::SUCCESS)
+}
+
+fn storage_repair(dir: &Path, dry_run: bool) -> Result<ExitCode, Box<dyn Error>> {
+    let report = tsdb::repair(dir, dry_run)?;
+    for entry in &report {
+        println!("{}", entry);
+    }
+    if report.is_empty() {
+        println!("{}: no damage found", dir.display());
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+fn scrape(
+    targets: &TargetArgs,
+    options: &ScrapeOptions,
+    format: Format,
+) -> Result<ExitCode, Box<dyn Error>> {
+    let (mfs, code) = match targets.single_url() {
+        Some(url) => {
+            let scrape = scrape::scrape_url(url, options)?;
+            log::info!(
+                "{}: decoded as {}, ~{} bytes",
+                url,
+                scrape.format,
+                scrape.approx_byte_size()
+            );
+            (scrape.families, ExitCode::SUCCESS)
+        }
+        None => {
+            let workers = targets.jobs;
+            let targets = targets.targets()?;
+            let mut failed = None;
+            let mut scraped = Vec::new();
+            for TargetScrape { target, result } in
+                scrape::scrape_targets(&targets, options, workers)
+            {
+                match result {
+                    Ok(scrape) => {
+                        log::info!(
+                            "{}: decoded as {}, ~{} bytes",
+                            target.url,
+                            scrape.format,
+                            scrape.approx_byte_size()
+                        );
+                        scraped.push(scrape.families);
+                    }
+                    Err(e) => {
+                        log::error!("{}", e);
+                        failed.get_or_insert(e);
+                    }
+                }
+            }
+            // The other targets are still written if some fail.
+            let code = match failed {
+                Some(e) if scraped.is_empty() => return Err(e.into()),
+                Some(e) => exit_code(Diagnostic::from_error(&e).kind),
+                None => ExitCode::SUCCESS,
+            };
+            (merge::merge(scraped, ConflictPolicy::Error)?, code)
+        }
+    };
+
+    let mut out = io::BufWriter::new(io::stdout().lock());
+    format::write_metric_families(format, &mut out, &mfs)?;
+    out.flush()?;
+    Ok(code)
+}
+
+fn record(
+    rules: &Path,
+    current: Option<&Path>,
+    previous: Option<&Path>,
+    interval: Option<Duration>,
+    only_recorded: bool,
+    format: Format,
+) -> Result<ExitCode, Box<dyn Error>> {
+    let yaml = std::fs::read_to_string(rules)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", rules.display(), e)))?;
+    let rules = Rules::from_yaml(&yaml).map_err(|e| format!("{}: {}", rules.display(), e))?;
+
+    check_stdin_once([current].into_iter().chain(previous.map(Some)))?;
+    let mfs = format::read_metric_families(format, open_input(current)?)?;
+    let before = match previous {
+        Some(path) => Some(format::read_metric_families(
+            format,
+            open_input(Some(path))?,
+        )?),
+        None => None,
+    };
+    let interval = interval.or_else(|| {
+        let mtime = |p: Option<&Path>| {
+            std::fs::metadata(p.filter(|p| !is_stdin(Some(p)))?)
+                .ok()?
+                .modified()
+                .ok()
+        };
+        mtime(current)?.duration_since(mtime(previous)?).ok()
+    });
+
+    let recorded = rules.evaluate(&mfs, before.as_deref(), interval)?;
+    let out_mfs = if only_recorded {
+        recorded
+    } else {
+        merge::merge([mfs, recorded], ConflictPolicy::LastWins)?
+    };
+
+    let mut out = io::BufWriter::new(io::stdout().lock());
+    format::write_metric_families(format, &mut out, &out_mfs)?;
+    out.flush()?;
+
+    Ok(ExitCode::SUCCESS)
+}
+
+fn migrate(
+    spec: &Path,
+    paths: &[PathBuf],
+    format: Format,
+    dry_run: bool,
+) -> Result<ExitCode, Box<dyn Error>> {
+    let yaml = std::fs::read_to_string(spec)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", spec.display(), e)))?;
+    let migration =
+        Migration::from_yaml(&yaml).map_err(|e| format!("{}: {}", spec.display(), e))?;
+
+    let mut rewritten = 0;
+    let mut series = 0;
+    for path in paths {
+        let mut report = MigrationReport::default();
+        if path.is_dir() {
+            let files = tsdb::rekey(path, dry_run, |key, ts| {
+                migration.migrate_key(key, ts, &mut report)
+            })?;
+            rewritten += files.len();
+        } else {
+            let mtime = std::fs::metadata(path)?.modified()?;
+            let default_ts = jiff::Timestamp::try_from(mtime)?.as_millisecond();
+            let mfs = format::read_metric_families(format, std::fs::File::open(path)?)
+                .map_err(|e| format!("{}: {}", path.display(), e))?;
+            let mfs = migration
+                .migrate_families(&mfs, default_ts, &mut report)
+                .map_err(|e| format!("{}: {}", path.display(), e))?;
+            if !report.is_empty() {
+                rewritten += 1;
+                if !dry_run {
+                    let tmp = path.with_extension("tmp");
+                    let mut out = io::BufWriter::new(std::fs::File::create(&tmp)?);
+                    format::write_metric_families(format, &mut out, &mfs)?;
+                    out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+                    std::fs::rename(&tmp, path)?;
+                }
+            }
+        }
+        for change in report.to_string().lines() {
+            println!("{}: {}", path.display(), change);
+        }
+        series += report.changes.len();
+    }
+    eprintln!(
+        "{} {} series in {} files",
+        if dry_run { "would migrate" } else { "migrated" },
+        series,
+        rewritten
+    );
+
+    Ok(ExitCode::SUCCESS)
+}
+
+fn storage_export(
+    dir: &Path,
+    start: i64,
+    end: i64,
+    to: ExportTarget,
+    url: Option<&str>,
+    batch_size: usize,
+    push_options: &PushOptions,
+) -> Result<ExitCode, Box<dyn Error>> {
+    let series = tsdb::read_range(dir, start, end)?;
+
+    match to {
+        ExportTarget::Format(format) => {
+            let mfs = tsdb::export::to_metric_families(&series);
+            let mut out = io::BufWriter::new(io::stdout().lock());
+            format::write_metric_families(format, &mut out, &mfs)?;
+            out.flush()?;
+        }
+        ExportTarget::RemoteWrite => {
+            let url = url.ok_or("--url is required with --to remote-write")?;
+            let requests = tsdb::export::to_write_requests(&series, batch_size);
+            let mut samples = 0;
+            for req in &requests {
+                remote_write::push(url, req, push_options)?;
+                samples += req
+                    .timeseries
+                    .iter()
+                    .map(|ts| ts.samples.len())
+                    .sum::<usize>();
+            }
+            eprintln!(
+                "sent {} samples of {} series in {} requests",
+                samples,
+                series.len(),
+                requests.len()
+            );
+        }
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
+
+fn query(
+    dir: &Path,
+    start: i64,
+    end: i64,
+    selectors: &[Selector],
+    explain: bool,
+    format: Option<Format>,
+) -> Result<ExitCode, Box<dyn Error>> {
+    let plan = tsdb::query::plan(dir, start, end, selectors)?;
+    let (series, stats) = plan.execute()?;
+
+    let mut out = io::BufWriter::new(io::stdout().lock());
+    if explain {
+        write!(out, "{}", plan)?;
+        writeln!(out, "{}", stats)?;
+    } else if let Some(format) = format {
+        let mfs = tsdb::export::to_metric_families(&series);
+        format::write_metric_families(format, &mut out, &mfs)?;
+    } else {
+        for (key, samples) in &series {
+            for (ts, value) in samples {
+                writeln!(out, "{} {} {}", key, text_encode::format_float(*value), ts)?;
+            }
         }
     }
+    out.flush()?;
 
-    Err("invalid metric line".into())
+    Ok(ExitCode::SUCCESS)
 }
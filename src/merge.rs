@@ -0,0 +1,319 @@
+use prometheus::proto::{LabelPair, MetricFamily, MetricType};
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::aggregate::{aggregate, AggOp, Grouping};
+use crate::flatten::render_series;
+
+/// The label that tells the targets of a job apart, dropped by
+/// [`ConflictPolicy::Federate`].
+pub const INSTANCE_LABEL: &str = "instance";
+
+/// What `merge` does when two inputs contain the same series.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    /// Fail on the first duplicate series or family type mismatch.
+    Error,
+    /// Keep the series, or for a type mismatch the family, seen last.
+    LastWins,
+    /// Add up duplicate counters; other types keep the value seen last.
+    Sum,
+    /// Combine every input's series regardless of `instance`, as one
+    /// target: see [`federate`].
+    Federate,
+}
+
+impl FromStr for ConflictPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "error" => Ok(ConflictPolicy::Error),
+            "last-wins" => Ok(ConflictPolicy::LastWins),
+            "sum" => Ok(ConflictPolicy::Sum),
+            "federate" => Ok(ConflictPolicy::Federate),
+            _ => Err(format!(
+                "unknown conflict policy {:?}, expected error, last-wins, sum or federate",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MergeError {
+    TypeMismatch {
+        family: String,
+        first: MetricType,
+        second: MetricType,
+    },
+    DuplicateSeries(String),
+}
+
+impl fmt::Display for MergeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MergeError::TypeMismatch {
+                family,
+                first,
+                second,
+            } => write!(
+                f,
+                "family {} is {:?} in one input and {:?} in another",
+                family, first, second
+            ),
+            MergeError::DuplicateSeries(series) => {
+                write!(f, "series {} appears in more than one input", series)
+            }
+        }
+    }
+}
+
+impl Error for MergeError {}
+
+/// Merges the families of several inputs into one document sorted by family
+/// name. Series keep their input order; duplicates, compared by label set
+/// regardless of label order, are resolved by `policy`. A family's first
+/// non-empty HELP is kept, or its last one with `LastWins`.
+pub fn merge<I>(inputs: I, policy: ConflictPolicy) -> Result<Vec<MetricFamily>, MergeError>
+where
+    I: IntoIterator<Item = Vec<MetricFamily>>,
+{
+    if policy == ConflictPolicy::Federate {
+        return federate(inputs);
+    }
+
+    let mut families: BTreeMap<String, MetricFamily> = BTreeMap::new();
+    // Position of every series in its family, by sorted label set.
+    let mut index: HashMap<String, HashMap<Vec<(String, String)>, usize>> = HashMap::new();
+
+    for mfs in inputs {
+        for mut mf in mfs {
+            let name = mf.get_name().to_string();
+            let metrics = mf.take_metric();
+            let series = index.entry(name.clone()).or_default();
+
+            let merged = families.entry(name.clone()).or_insert_with(|| mf.clone());
+            if merged.get_field_type() != mf.get_field_type() {
+                if policy != ConflictPolicy::LastWins {
+                    return Err(MergeError::TypeMismatch {
+                        family: name,
+                        first: merged.get_field_type(),
+                        second: mf.get_field_type(),
+                    });
+                }
+                *merged = mf.clone();
+                series.clear();
+            }
+            if mf.has_help() && (!merged.has_help() || policy == ConflictPolicy::LastWins) {
+                merged.set_help(mf.get_help().to_string());
+            }
+
+            for m in metrics {
+                let mut key: Vec<(String, String)> = m
+                    .get_label()
+                    .iter()
+                    .map(|l| (l.get_name().to_string(), l.get_value().to_string()))
+                    .collect();
+                key.sort_unstable();
+
+                let i = match series.get(&key) {
+                    Some(&i) => i,
+                    None => {
+                        series.insert(key, merged.get_metric().len());
+                        merged.mut_metric().push(m);
+                        continue;
+                    }
+                };
+                let existing = &mut merged.mut_metric()[i];
+                match policy {
+                    ConflictPolicy::Error => {
+                        return Err(MergeError::DuplicateSeries(render_series(&name, &key)))
+                    }
+                    ConflictPolicy::Sum if mf.get_field_type() == MetricType::COUNTER => {
+                        let sum = existing.get_counter().get_value() + m.get_counter().get_value();
+                        existing.mut_counter().set_value(sum);
+                        if m.has_timestamp_ms() {
+                            let ts = existing.get_timestamp_ms().max(m.get_timestamp_ms());
+                            existing.set_timestamp_ms(ts);
+                        }
+                    }
+                    _ => *existing = m,
+                }
+            }
+        }
+    }
+
+    Ok(families.into_values().collect())
+}
+
+/// Merges the families of several targets into one document sorted by
+/// family name, as if one target had exposed them all: the `instance` label
+/// is dropped and the series left equal are combined.
+///
+/// Counters, histogram buckets and the `_sum` and `_count` of histograms
+/// and summaries are summed, with buckets aligned as by
+/// [`aggregate`](crate::aggregate::aggregate); summary quantiles cannot be
+/// and are dropped. Gauges and untyped series are averaged. Timestamps are
+/// dropped.
+pub fn federate<I>(inputs: I) -> Result<Vec<MetricFamily>, MergeError>
+where
+    I: IntoIterator<Item = Vec<MetricFamily>>,
+{
+    let mut families: BTreeMap<String, MetricFamily> = BTreeMap::new();
+    for mfs in inputs {
+        for mut mf in mfs {
+            let metrics = mf.take_metric();
+            let merged = families
+                .entry(mf.get_name().to_string())
+                .or_insert_with(|| mf.clone());
+            if merged.get_field_type() != mf.get_field_type() {
+                return Err(MergeError::TypeMismatch {
+                    family: mf.get_name().to_string(),
+                    first: merged.get_field_type(),
+                    second: mf.get_field_type(),
+                });
+            }
+            if mf.has_help() && !merged.has_help() {
+                merged.set_help(mf.get_help().to_string());
+            }
+            merged.mut_metric().extend(metrics);
+        }
+    }
+
+    let without_instance = Grouping::Without(vec![INSTANCE_LABEL.to_string()]);
+    Ok(families
+        .into_values()
+        .flat_map(|mf| {
+            let op = match mf.get_field_type() {
+                MetricType::GAUGE | MetricType::UNTYPED => AggOp::Avg,
+                _ => AggOp::Sum,
+            };
+            aggregate(&[mf], op, &without_instance)
+        })
+        .collect())
+}
+
+/// Sets `name` to `value` on every series, replacing any existing label of
+/// that name.
+pub fn add_label(mfs: &mut [MetricFamily], name: &str, value: &str) {
+    for mf in mfs {
+        for m in mf.mut_metric().iter_mut() {
+            match m.mut_label().iter_mut().find(|l| l.get_name() == name) {
+                Some(l) => l.set_value(value.to_string()),
+                None => {
+                    let mut l = LabelPair::new();
+                    l.set_name(name.to_string());
+                    l.set_value(value.to_string());
+                    m.mut_label().push(l);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::{read_metric_families, write_metric_families, Format};
+
+    fn parse(text: &str) -> Vec<MetricFamily> {
+        read_metric_families(Format::Text, text.as_bytes()).unwrap()
+    }
+
+    fn encode(mfs: &[MetricFamily]) -> String {
+        let mut out = Vec::new();
+        write_metric_families(Format::Text, &mut out, mfs).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    const A: &str = r#"# TYPE requests_total counter
+requests_total{code="200",job="a"} 3
+# TYPE up gauge
+up 1
+"#;
+    const B: &str = r#"# HELP requests_total Requests.
+# TYPE requests_total counter
+requests_total{job="a",code="200"} 4
+requests_total{code="500",job="a"} 1
+# TYPE up gauge
+up 0
+"#;
+
+    #[test]
+    fn test_merge_policies() {
+        let err = merge([parse(A), parse(B)], ConflictPolicy::Error).unwrap_err();
+        assert_eq!(
+            err,
+            MergeError::DuplicateSeries(r#"requests_total{code="200",job="a"}"#.to_string())
+        );
+
+        let merged = merge([parse(A), parse(B)], ConflictPolicy::Sum).unwrap();
+        assert_eq!(
+            encode(&merged),
+            r#"# HELP requests_total Requests.
+# TYPE requests_total counter
+requests_total{code="200",job="a"} 7
+requests_total{code="500",job="a"} 1
+# TYPE up gauge
+up 0
+"#
+        );
+
+        let merged = merge([parse(A), parse(B)], ConflictPolicy::LastWins).unwrap();
+        assert_eq!(merged[0].get_metric()[0].get_counter().get_value(), 4.0);
+
+        let gauge = parse("# TYPE up counter\nup 1\n");
+        assert!(matches!(
+            merge([parse(A), gauge.clone()], ConflictPolicy::Sum),
+            Err(MergeError::TypeMismatch { .. })
+        ));
+        let merged = merge([parse(A), gauge], ConflictPolicy::LastWins).unwrap();
+        assert_eq!(merged[1].get_field_type(), MetricType::COUNTER);
+    }
+
+    #[test]
+    fn test_federate() {
+        let a = parse(
+            "# TYPE requests_total counter\nrequests_total{code=\"200\",instance=\"a:80\"} 3\n\
+             # TYPE temperature gauge\ntemperature{instance=\"a:80\"} 20\n\
+             # TYPE latency histogram\nlatency_bucket{instance=\"a:80\",le=\"1\"} 1\n\
+             latency_bucket{instance=\"a:80\",le=\"+Inf\"} 2\nlatency_sum 3\nlatency_count 2\n",
+        );
+        let b = parse(
+            "# TYPE requests_total counter\nrequests_total{code=\"200\",instance=\"b:80\"} 4\n\
+             requests_total{code=\"500\",instance=\"b:80\"} 1\n\
+             # TYPE temperature gauge\ntemperature{instance=\"b:80\"} 30\n",
+        );
+        let merged = merge([a, b], ConflictPolicy::Federate).unwrap();
+        assert_eq!(
+            encode(&merged),
+            r#"# TYPE latency histogram
+latency_bucket{le="1"} 1
+latency_bucket{le="+Inf"} 2
+latency_sum 3
+latency_count 2
+# TYPE requests_total counter
+requests_total{code="200"} 7
+requests_total{code="500"} 1
+# TYPE temperature gauge
+temperature 25
+"#
+        );
+    }
+
+    #[test]
+    fn test_merge_with_source_label() {
+        let mut a = parse(A);
+        let mut b = parse(B);
+        add_label(&mut a, "source", "a");
+        add_label(&mut b, "source", "b");
+
+        let merged = merge([a, b], ConflictPolicy::Error).unwrap();
+        assert_eq!(merged[0].get_metric().len(), 3);
+        assert_eq!(merged[1].get_metric().len(), 2);
+        assert_eq!(merged[1].get_metric()[1].get_label()[0].get_value(), "b");
+    }
+}
@@ -0,0 +1,428 @@
+use jiff::civil::Date;
+use jiff::tz::TimeZone;
+use jiff::Timestamp;
+use prometheus::proto::{LabelPair, MetricFamily};
+use regex::Regex;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+
+use crate::flatten::{parse_series, render_series};
+use crate::grammar;
+use crate::relabel::anchored_regex;
+
+/// Suffixes under which stores keep the series of histograms and
+/// summaries, which family renames carry over.
+const SAMPLE_SUFFIXES: [&str; 3] = ["_bucket", "_sum", "_count"];
+
+/// A label schema change, loaded from YAML, for bringing archived data in
+/// line with renamed labels and families:
+///
+/// ```yaml
+/// labels:
+///   - from: instance_name
+///     to: instance
+/// values:
+///   - label: env
+///     from: 'prod(-.*)?'
+///     to: production$1
+/// families:
+///   - from: http_requests
+///     to: http_requests_total
+///     before: 2024-06-01T00:00:00Z
+/// ```
+///
+/// Families are renamed first, then labels, then label values. A family
+/// rename with `after` or `before` applies only to samples at or after,
+/// and before, those times, given in RFC 3339 or as dates, midnight UTC.
+/// A renamed label replaces one already named like its target. Value
+/// regexes are anchored at both ends and `to` may refer to their groups.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Migration {
+    #[serde(default)]
+    pub labels: Vec<LabelRename>,
+    #[serde(default)]
+    pub values: Vec<ValueRewrite>,
+    #[serde(default)]
+    pub families: Vec<FamilyRename>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LabelRename {
+    pub from: String,
+    pub to: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ValueRewrite {
+    pub label: String,
+    #[serde(deserialize_with = "anchored_regex")]
+    pub from: Regex,
+    pub to: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FamilyRename {
+    pub from: String,
+    pub to: String,
+    /// Milliseconds since the epoch.
+    #[serde(default, deserialize_with = "time")]
+    pub after: Option<i64>,
+    #[serde(default, deserialize_with = "time")]
+    pub before: Option<i64>,
+}
+
+impl FamilyRename {
+    fn applies_at(&self, ts: i64) -> bool {
+        self.after.is_none_or(|after| ts >= after) && self.before.is_none_or(|before| ts < before)
+    }
+}
+
+fn time<'de, D: serde::Deserializer<'de>>(d: D) -> Result<Option<i64>, D::Error> {
+    let s = String::deserialize(d)?;
+    let ts = match s.parse::<Timestamp>() {
+        Ok(ts) => ts,
+        Err(_) => s
+            .parse::<Date>()
+            .and_then(|date| date.to_zoned(TimeZone::UTC))
+            .map(|zoned| zoned.timestamp())
+            .map_err(|_| {
+                serde::de::Error::custom(format!(
+                    "expected an RFC 3339 time or a date, got {:?}",
+                    s
+                ))
+            })?,
+    };
+    Ok(Some(ts.as_millisecond()))
+}
+
+#[derive(Debug)]
+pub enum MigrationError {
+    Yaml(serde_yaml::Error),
+    Invalid(String),
+}
+
+impl fmt::Display for MigrationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MigrationError::Yaml(e) => write!(f, "invalid migration file: {}", e),
+            MigrationError::Invalid(msg) => write!(f, "invalid migration: {}", msg),
+        }
+    }
+}
+
+impl Error for MigrationError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            MigrationError::Yaml(e) => Some(e),
+            MigrationError::Invalid(_) => None,
+        }
+    }
+}
+
+/// The series a migration changed, old and new name, with how many samples
+/// of each moved.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MigrationReport {
+    pub changes: BTreeMap<(String, String), u64>,
+}
+
+impl MigrationReport {
+    fn record(&mut self, old: String, new: String) {
+        *self.changes.entry((old, new)).or_default() += 1;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+}
+
+impl fmt::Display for MigrationReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for ((old, new), samples) in &self.changes {
+            write!(f, "{} -> {}", old, new)?;
+            if *samples > 1 {
+                write!(f, " ({} samples)", samples)?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+impl Migration {
+    pub fn from_yaml(s: &str) -> Result<Migration, MigrationError> {
+        let migration: Migration = serde_yaml::from_str(s).map_err(MigrationError::Yaml)?;
+        let invalid = |what: &str, name: &str| {
+            Err(MigrationError::Invalid(format!(
+                "{:?} is not a valid {} name",
+                name, what
+            )))
+        };
+        for rename in &migration.labels {
+            for name in [&rename.from, &rename.to] {
+                if !valid_name(name, true) || name == "__name__" {
+                    return invalid("label", name);
+                }
+            }
+        }
+        for rewrite in &migration.values {
+            if !valid_name(&rewrite.label, true) {
+                return invalid("label", &rewrite.label);
+            }
+        }
+        for rename in &migration.families {
+            for name in [&rename.from, &rename.to] {
+                if !valid_name(name, false) {
+                    return invalid("family", name);
+                }
+            }
+        }
+        Ok(migration)
+    }
+
+    /// The name and labels of a series of `family` at `ts` after the
+    /// migration.
+    fn migrate(
+        &self,
+        family: &str,
+        labels: &[(String, String)],
+        ts: i64,
+    ) -> (String, Vec<(String, String)>) {
+        let family = self
+            .families
+            .iter()
+            .find(|r| r.from == family && r.applies_at(ts))
+            .map_or(family, |r| r.to.as_str())
+            .to_string();
+
+        let mut labels = labels.to_vec();
+        for rename in &self.labels {
+            if let Some(i) = labels.iter().position(|(name, _)| *name == rename.from) {
+                let (_, value) = labels.remove(i);
+                labels.retain(|(name, _)| *name != rename.to);
+                labels.push((rename.to.clone(), value));
+            }
+        }
+        for rewrite in &self.values {
+            for (name, value) in labels.iter_mut() {
+                if *name == rewrite.label && rewrite.from.is_match(value) {
+                    *value = rewrite
+                        .from
+                        .replace(value, rewrite.to.as_str())
+                        .into_owned();
+                }
+            }
+        }
+        labels.sort_unstable();
+        (family, labels)
+    }
+
+    /// Migrates a store series key, as written by `Storage::append`, of a
+    /// sample at `ts`. Family renames also apply to the `_bucket`, `_sum`
+    /// and `_count` series of histograms and summaries. Returns the new key
+    /// if it changed.
+    pub fn migrate_key(&self, key: &str, ts: i64, report: &mut MigrationReport) -> Option<String> {
+        let (name, labels) = parse_series(key)?;
+        let (family, suffix) = SAMPLE_SUFFIXES
+            .iter()
+            .find_map(|suffix| {
+                let family = name.strip_suffix(suffix)?;
+                self.families
+                    .iter()
+                    .any(|r| r.from == family)
+                    .then_some((family, *suffix))
+            })
+            .unwrap_or((&name, ""));
+
+        let (family, labels) = self.migrate(family, &labels, ts);
+        let new_key = render_series(&format!("{}{}", family, suffix), &labels);
+        if new_key == key {
+            return None;
+        }
+        report.record(key.to_string(), new_key.clone());
+        Some(new_key)
+    }
+
+    /// Migrates every series of `mfs`, taking `default_ts` as the time of
+    /// samples without a timestamp. Series moved to a family of another
+    /// type are an error. Families come back sorted by name.
+    pub fn migrate_families(
+        &self,
+        mfs: &[MetricFamily],
+        default_ts: i64,
+        report: &mut MigrationReport,
+    ) -> Result<Vec<MetricFamily>, MigrationError> {
+        let mut out: BTreeMap<String, MetricFamily> = BTreeMap::new();
+        for mf in mfs {
+            for m in mf.get_metric() {
+                let mut labels: Vec<(String, String)> = m
+                    .get_label()
+                    .iter()
+                    .map(|l| (l.get_name().to_string(), l.get_value().to_string()))
+                    .collect();
+                labels.sort_unstable();
+                let ts = if m.has_timestamp_ms() {
+                    m.get_timestamp_ms()
+                } else {
+                    default_ts
+                };
+                let (family, new_labels) = self.migrate(mf.get_name(), &labels, ts);
+                let (old, new) = (
+                    render_series(mf.get_name(), &labels),
+                    render_series(&family, &new_labels),
+                );
+                if old != new {
+                    report.record(old, new);
+                }
+
+                let target = out.entry(family.clone()).or_insert_with(|| {
+                    let mut target = mf.clone();
+                    target.set_name(family.clone());
+                    target.clear_metric();
+                    target
+                });
+                if target.get_field_type() != mf.get_field_type() {
+                    return Err(MigrationError::Invalid(format!(
+                        "renaming {} to {} mixes types",
+                        mf.get_name(),
+                        family
+                    )));
+                }
+                let mut m = m.clone();
+                m.set_label(
+                    new_labels
+                        .into_iter()
+                        .map(|(name, value)| {
+                            let mut label = LabelPair::new();
+                            label.set_name(name);
+                            label.set_value(value);
+                            label
+                        })
+                        .collect(),
+                );
+                target.mut_metric().push(m);
+            }
+        }
+        Ok(out.into_values().collect())
+    }
+}
+
+fn valid_name(name: &str, label: bool) -> bool {
+    let mut chars = name.chars();
+    if label {
+        chars.next().is_some_and(grammar::is_valid_label_name_start)
+            && chars.all(grammar::is_valid_label_name_continuation)
+    } else {
+        chars
+            .next()
+            .is_some_and(grammar::is_valid_metric_name_start)
+            && chars.all(grammar::is_valid_metric_name_continuation)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::{read_metric_families, Format};
+    use crate::text_encode::metric_families_to_text;
+    use crate::tsdb::{self, Storage, StorageConfig};
+
+    #[test]
+    fn test_migration() {
+        let migration = Migration::from_yaml(
+            r#"
+labels:
+  - from: instance_name
+    to: instance
+values:
+  - label: env
+    from: 'prod(-.*)?'
+    to: production$1
+families:
+  - from: http_requests
+    to: http_requests_total
+    before: 2024-06-01
+"#,
+        )
+        .unwrap();
+
+        let mfs = read_metric_families(
+            Format::Text,
+            r#"# TYPE http_requests counter
+http_requests{instance_name="h1",env="prod-eu"} 1 1700000000000
+http_requests{instance_name="h1",env="prod-eu"} 2 1800000000000
+# TYPE http_requests_total counter
+http_requests_total{instance="h2",env="dev"} 3
+"#
+            .as_bytes(),
+        )
+        .unwrap();
+        let mut report = MigrationReport::default();
+        let out = migration.migrate_families(&mfs, 0, &mut report).unwrap();
+        let mut text = Vec::new();
+        metric_families_to_text(&mut text, &out).unwrap();
+        assert_eq!(
+            String::from_utf8(text).unwrap(),
+            "# TYPE http_requests counter\n\
+             http_requests{env=\"production-eu\",instance=\"h1\"} 2 1800000000000\n\
+             # TYPE http_requests_total counter\n\
+             http_requests_total{env=\"production-eu\",instance=\"h1\"} 1 1700000000000\n\
+             http_requests_total{env=\"dev\",instance=\"h2\"} 3\n"
+        );
+        assert_eq!(
+            report.to_string(),
+            "http_requests{env=\"prod-eu\",instance_name=\"h1\"} -> \
+             http_requests_total{env=\"production-eu\",instance=\"h1\"}\n\
+             http_requests{env=\"prod-eu\",instance_name=\"h1\"} -> \
+             http_requests{env=\"production-eu\",instance=\"h1\"}\n"
+        );
+
+        let mut report = MigrationReport::default();
+        assert_eq!(
+            migration.migrate_key("http_requests_count{instance_name=\"h1\"}", 0, &mut report),
+            Some("http_requests_total_count{instance=\"h1\"}".to_string())
+        );
+        assert_eq!(migration.migrate_key("up", 0, &mut report), None);
+
+        // Old samples in a block and new ones in the segment; only the
+        // block is before the rename's cutoff.
+        let dir = std::env::temp_dir().join(format!("pmv-migrate-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let mut storage = Storage::open(&dir, StorageConfig::default()).unwrap();
+        storage.append(&mfs[..1], 1_700_000_000_000).unwrap();
+        storage.compact(1_700_000_000_000).unwrap();
+        storage.append(&mfs[..1], 1_800_000_000_000).unwrap();
+        drop(storage);
+        let mut report = MigrationReport::default();
+        let rewritten = tsdb::rekey(&dir, false, |key, ts| {
+            migration.migrate_key(key, ts, &mut report)
+        })
+        .unwrap();
+        assert_eq!(rewritten.len(), 2);
+        let keys: Vec<_> = tsdb::read_range(&dir, i64::MIN, i64::MAX)
+            .unwrap()
+            .into_keys()
+            .collect();
+        assert_eq!(
+            keys,
+            [
+                "http_requests_total{env=\"production-eu\",instance=\"h1\"}",
+                "http_requests{env=\"production-eu\",instance=\"h1\"}",
+            ]
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let err = Migration::from_yaml("labels:\n  - from: a\n    to: 1b\n").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "invalid migration: \"1b\" is not a valid label name"
+        );
+        assert!(Migration::from_yaml("families:\n  - {from: a, to: b, after: soon}\n").is_err());
+    }
+}
@@ -0,0 +1,1340 @@
+//! pmv's own data model, so that callers need not build
+//! `prometheus::proto` messages and can carry what those cannot: units,
+//! created timestamps and exemplars.
+//!
+//! Families convert to and from the protobuf types, which pmv's readers,
+//! writers and transforms still work on; see [`crate::format::read_families`]
+//! and [`crate::format::write_families`]. Those types have no room for
+//! units, created timestamps or exemplars, so for now only the builders and
+//! serde fill them: documents read from any format leave them unset, and
+//! writing drops them.
+//!
+//! The types implement serde's `Serialize` and `Deserialize`, so parsed
+//! scrapes can be cached or exchanged as JSON, MessagePack or CBOR. `NaN`
+//! and the infinities are written as the strings `"NaN"`, `"+Inf"` and
+//! `"-Inf"`, which JSON has no numbers for.
+
+use prometheus::proto;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Write};
+use std::mem::{size_of, size_of_val};
+use std::ops::Deref;
+use xxhash_rust::xxh64::Xxh64;
+
+use crate::grammar::{
+    is_valid_label_name_continuation, is_valid_label_name_start, is_valid_metric_name_continuation,
+    is_valid_metric_name_start,
+};
+use crate::grep::{Matcher, NAME_LABEL};
+use crate::quantile::histogram_quantile;
+use crate::text_encode::format_float;
+
+/// The type of a [`MetricFamily`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MetricType {
+    Counter,
+    Gauge,
+    #[default]
+    Untyped,
+    Histogram,
+    Summary,
+}
+
+/// A family of series of one type.
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct MetricFamily {
+    pub name: String,
+    #[serde(default)]
+    pub help: Option<String>,
+    /// The OpenMetrics unit, such as `seconds`.
+    #[serde(default)]
+    pub unit: Option<String>,
+    #[serde(rename = "type")]
+    pub metric_type: MetricType,
+    pub samples: Vec<Sample>,
+}
+
+/// One series of a family.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Sample {
+    /// Label names and values, in order.
+    #[serde(default)]
+    pub labels: Vec<(String, String)>,
+    pub value: Value,
+    #[serde(default)]
+    pub timestamp_ms: Option<i64>,
+    /// When a counter, histogram or summary was created or last reset.
+    #[serde(default)]
+    pub created_ms: Option<i64>,
+    /// An exemplar of a counter; histograms keep theirs per bucket.
+    #[serde(default)]
+    pub exemplar: Option<Exemplar>,
+}
+
+/// The value of a [`Sample`], matching its family's type: `Scalar` for
+/// counters, gauges and untyped families.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Value {
+    Scalar(#[serde(with = "float")] f64),
+    Histogram {
+        count: u64,
+        #[serde(with = "float")]
+        sum: f64,
+        /// In increasing order of bound; the `+Inf` bucket is optional.
+        buckets: Vec<Bucket>,
+    },
+    Summary {
+        count: u64,
+        #[serde(with = "float")]
+        sum: f64,
+        quantiles: Vec<Quantile>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Bucket {
+    #[serde(with = "float")]
+    pub upper_bound: f64,
+    pub cumulative_count: u64,
+    #[serde(default)]
+    pub exemplar: Option<Exemplar>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Quantile {
+    #[serde(with = "float")]
+    pub quantile: f64,
+    #[serde(with = "float")]
+    pub value: f64,
+}
+
+/// A traced observation, e.g. `{trace_id="abc"} 0.67 1700000000`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Exemplar {
+    #[serde(default)]
+    pub labels: Vec<(String, String)>,
+    #[serde(with = "float")]
+    pub value: f64,
+    #[serde(default)]
+    pub timestamp_ms: Option<i64>,
+}
+
+/// Floats as numbers, or as the text format spells them if not finite.
+mod float {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    use crate::grammar::parse_float;
+    use crate::text_encode::format_float;
+
+    pub fn serialize<S: Serializer>(v: &f64, s: S) -> Result<S::Ok, S::Error> {
+        if v.is_finite() {
+            s.serialize_f64(*v)
+        } else {
+            s.serialize_str(&format_float(*v))
+        }
+    }
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Repr {
+        Number(f64),
+        Text(String),
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<f64, D::Error> {
+        match Repr::deserialize(d)? {
+            Repr::Number(v) => Ok(v),
+            Repr::Text(s) => parse_float(&s)
+                .ok_or_else(|| serde::de::Error::custom(format!("invalid float {:?}", s))),
+        }
+    }
+}
+
+impl Sample {
+    /// A series of `labels` with `value`, without timestamps or exemplars.
+    pub fn new(labels: Vec<(String, String)>, value: Value) -> Self {
+        Sample {
+            labels,
+            value,
+            timestamp_ms: None,
+            created_ms: None,
+            exemplar: None,
+        }
+    }
+
+    /// The value of a counter, gauge or untyped series.
+    pub fn scalar(&self) -> Option<f64> {
+        match self.value {
+            Value::Scalar(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    pub fn histogram(&self) -> Option<Histogram<'_>> {
+        match &self.value {
+            Value::Histogram {
+                count,
+                sum,
+                buckets,
+            } => Some(Histogram {
+                count: *count,
+                sum: *sum,
+                buckets,
+            }),
+            _ => None,
+        }
+    }
+
+    pub fn summary(&self) -> Option<Summary<'_>> {
+        match &self.value {
+            Value::Summary {
+                count,
+                sum,
+                quantiles,
+            } => Some(Summary {
+                count: *count,
+                sum: *sum,
+                quantiles,
+            }),
+            _ => None,
+        }
+    }
+
+    /// Roughly how many bytes the sample takes in memory: its own size and
+    /// what its labels, buckets, quantiles and exemplars allocate.
+    pub fn approx_byte_size(&self) -> usize {
+        let value = match &self.value {
+            Value::Scalar(_) => 0,
+            Value::Histogram { buckets, .. } => {
+                buckets.capacity() * size_of::<Bucket>()
+                    + buckets
+                        .iter()
+                        .filter_map(|b| b.exemplar.as_ref())
+                        .map(|e| labels_byte_size(&e.labels))
+                        .sum::<usize>()
+            }
+            Value::Summary { quantiles, .. } => quantiles.capacity() * size_of::<Quantile>(),
+        };
+        size_of::<Sample>()
+            + labels_byte_size(&self.labels)
+            + value
+            + self
+                .exemplar
+                .as_ref()
+                .map_or(0, |e| labels_byte_size(&e.labels))
+    }
+}
+
+fn labels_byte_size(labels: &[(String, String)]) -> usize {
+    size_of_val(labels)
+        + labels
+            .iter()
+            .map(|(n, v)| n.capacity() + v.capacity())
+            .sum::<usize>()
+}
+
+impl MetricFamily {
+    /// Roughly how many bytes the family takes in memory, counting the
+    /// spare capacity of its strings and series.
+    pub fn approx_byte_size(&self) -> usize {
+        size_of::<MetricFamily>()
+            + self.name.capacity()
+            + self.help.as_ref().map_or(0, String::capacity)
+            + self.unit.as_ref().map_or(0, String::capacity)
+            + (self.samples.capacity() - self.samples.len()) * size_of::<Sample>()
+            + self
+                .samples
+                .iter()
+                .map(Sample::approx_byte_size)
+                .sum::<usize>()
+    }
+}
+
+/// The value of a histogram series, from [`Sample::histogram`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Histogram<'a> {
+    pub count: u64,
+    pub sum: f64,
+    pub buckets: &'a [Bucket],
+}
+
+impl Histogram<'_> {
+    /// The mean observation, `NaN` without observations.
+    pub fn mean(&self) -> f64 {
+        self.sum / self.count as f64
+    }
+
+    /// Estimates the `q`-quantile as PromQL's `histogram_quantile` does;
+    /// see [`histogram_quantile`].
+    pub fn quantile(&self, q: f64) -> f64 {
+        let mut h = proto::Histogram::default();
+        h.set_sample_count(self.count);
+        for b in self.buckets {
+            let mut bucket = proto::Bucket::default();
+            bucket.set_upper_bound(b.upper_bound);
+            bucket.set_cumulative_count(b.cumulative_count);
+            h.mut_bucket().push(bucket);
+        }
+        histogram_quantile(q, &h)
+    }
+}
+
+/// The value of a summary series, from [`Sample::summary`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Summary<'a> {
+    pub count: u64,
+    pub sum: f64,
+    pub quantiles: &'a [Quantile],
+}
+
+impl Summary<'_> {
+    /// The mean observation, `NaN` without observations.
+    pub fn mean(&self) -> f64 {
+        self.sum / self.count as f64
+    }
+
+    /// The value of the `q`-quantile, if the summary has it.
+    pub fn quantile(&self, q: f64) -> Option<f64> {
+        self.quantiles
+            .iter()
+            .find(|quantile| quantile.quantile == q)
+            .map(|quantile| quantile.value)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum BuildError {
+    InvalidMetricName(String),
+    InvalidLabelName(String),
+    DuplicateLabel(String),
+    /// A sample's value is not of its family's type.
+    WrongValue(MetricType),
+    /// Bucket bounds that do not increase or counts that decrease.
+    InvalidBuckets,
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BuildError::InvalidMetricName(name) => write!(f, "invalid metric name {:?}", name),
+            BuildError::InvalidLabelName(name) => write!(f, "invalid label name {:?}", name),
+            BuildError::DuplicateLabel(name) => write!(f, "duplicate label {:?}", name),
+            BuildError::WrongValue(t) => write!(f, "sample value does not fit a {:?} family", t),
+            BuildError::InvalidBuckets => write!(
+                f,
+                "bucket bounds must increase and their counts must not decrease"
+            ),
+        }
+    }
+}
+
+impl Error for BuildError {}
+
+/// Builds a [`MetricFamily`], checking it as it does:
+///
+/// ```
+/// use pmv::model::{MetricFamilyBuilder, MetricType, SampleBuilder};
+///
+/// let family = MetricFamilyBuilder::new("error_ratio", MetricType::Gauge)
+///     .help("Errors per request.")
+///     .sample(SampleBuilder::scalar(0.05).label("job", "api"))
+///     .build()
+///     .unwrap();
+/// let mf: prometheus::proto::MetricFamily = (&family).into();
+/// ```
+#[derive(Debug, Clone)]
+pub struct MetricFamilyBuilder {
+    family: MetricFamily,
+}
+
+impl MetricFamilyBuilder {
+    pub fn new(name: &str, metric_type: MetricType) -> Self {
+        MetricFamilyBuilder {
+            family: MetricFamily {
+                name: name.to_string(),
+                metric_type,
+                ..Default::default()
+            },
+        }
+    }
+
+    pub fn help(mut self, help: &str) -> Self {
+        self.family.help = Some(help.to_string());
+        self
+    }
+
+    pub fn unit(mut self, unit: &str) -> Self {
+        self.family.unit = Some(unit.to_string());
+        self
+    }
+
+    pub fn sample(mut self, sample: impl Into<Sample>) -> Self {
+        self.family.samples.push(sample.into());
+        self
+    }
+
+    /// The family, if its name and labels are valid and every sample's
+    /// value fits its type.
+    pub fn build(self) -> Result<MetricFamily, BuildError> {
+        let family = self.family;
+        check_name(
+            &family.name,
+            is_valid_metric_name_start,
+            is_valid_metric_name_continuation,
+        )
+        .map_err(BuildError::InvalidMetricName)?;
+        for sample in &family.samples {
+            let mut seen = HashSet::new();
+            let exemplar_labels = sample.exemplar.iter().flat_map(|e| &e.labels);
+            for (name, _) in sample.labels.iter().chain(exemplar_labels) {
+                check_name(
+                    name,
+                    is_valid_label_name_start,
+                    is_valid_label_name_continuation,
+                )
+                .map_err(BuildError::InvalidLabelName)?;
+            }
+            for (name, _) in &sample.labels {
+                if !seen.insert(name) {
+                    return Err(BuildError::DuplicateLabel(name.clone()));
+                }
+            }
+            match (family.metric_type, &sample.value) {
+                (
+                    MetricType::Counter | MetricType::Gauge | MetricType::Untyped,
+                    Value::Scalar(_),
+                )
+                | (MetricType::Summary, Value::Summary { .. }) => {}
+                (MetricType::Histogram, Value::Histogram { buckets, .. }) => {
+                    if buckets.windows(2).any(|w| {
+                        w[0].upper_bound >= w[1].upper_bound
+                            || w[0].cumulative_count > w[1].cumulative_count
+                    }) {
+                        return Err(BuildError::InvalidBuckets);
+                    }
+                }
+                (t, _) => return Err(BuildError::WrongValue(t)),
+            }
+        }
+        Ok(family)
+    }
+}
+
+fn check_name(
+    name: &str,
+    start: fn(char) -> bool,
+    continuation: fn(char) -> bool,
+) -> Result<(), String> {
+    if name.starts_with(start) && name.chars().all(continuation) {
+        Ok(())
+    } else {
+        Err(name.to_string())
+    }
+}
+
+/// Builds a [`Sample`] for [`MetricFamilyBuilder::sample`].
+#[derive(Debug, Clone)]
+pub struct SampleBuilder {
+    sample: Sample,
+}
+
+impl SampleBuilder {
+    pub fn new(value: Value) -> Self {
+        SampleBuilder {
+            sample: Sample::new(Vec::new(), value),
+        }
+    }
+
+    /// A counter, gauge or untyped sample.
+    pub fn scalar(value: f64) -> Self {
+        Self::new(Value::Scalar(value))
+    }
+
+    /// A histogram sample with buckets of upper bounds and cumulative counts.
+    pub fn histogram(count: u64, sum: f64, buckets: &[(f64, u64)]) -> Self {
+        Self::new(Value::Histogram {
+            count,
+            sum,
+            buckets: buckets
+                .iter()
+                .map(|&(upper_bound, cumulative_count)| Bucket {
+                    upper_bound,
+                    cumulative_count,
+                    exemplar: None,
+                })
+                .collect(),
+        })
+    }
+
+    /// A summary sample with quantiles and their values.
+    pub fn summary(count: u64, sum: f64, quantiles: &[(f64, f64)]) -> Self {
+        Self::new(Value::Summary {
+            count,
+            sum,
+            quantiles: quantiles
+                .iter()
+                .map(|&(quantile, value)| Quantile { quantile, value })
+                .collect(),
+        })
+    }
+
+    pub fn label(mut self, name: &str, value: &str) -> Self {
+        self.sample
+            .labels
+            .push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn timestamp_ms(mut self, timestamp_ms: i64) -> Self {
+        self.sample.timestamp_ms = Some(timestamp_ms);
+        self
+    }
+
+    pub fn created_ms(mut self, created_ms: i64) -> Self {
+        self.sample.created_ms = Some(created_ms);
+        self
+    }
+
+    pub fn exemplar(mut self, exemplar: Exemplar) -> Self {
+        self.sample.exemplar = Some(exemplar);
+        self
+    }
+
+    pub fn build(self) -> Sample {
+        self.sample
+    }
+}
+
+impl From<SampleBuilder> for Sample {
+    fn from(builder: SampleBuilder) -> Self {
+        builder.build()
+    }
+}
+
+/// What a [`SampleLine`] is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SampleKind {
+    Counter,
+    Gauge,
+    Untyped,
+    /// A histogram `_bucket`, with an `le` label.
+    Bucket,
+    /// A summary quantile, with a `quantile` label.
+    Quantile,
+    Sum,
+    Count,
+}
+
+/// One sample as the text format writes it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SampleLine<'a> {
+    /// The family name with any `_bucket`, `_sum` or `_count` suffix.
+    pub name: String,
+    pub labels: &'a [(String, String)],
+    /// The `le` or `quantile` label of buckets and quantiles, which follows
+    /// `labels`.
+    pub extra: Option<(&'static str, String)>,
+    pub value: f64,
+    pub timestamp_ms: Option<i64>,
+    pub kind: SampleKind,
+}
+
+/// The families of a document, in order. Dereferences to a slice of them.
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Families(pub Vec<MetricFamily>);
+
+impl Families {
+    /// The family named `name`.
+    pub fn get(&self, name: &str) -> Option<&MetricFamily> {
+        self.iter().find(|family| family.name == name)
+    }
+
+    /// Every series that satisfies all `matchers`, with its family, in
+    /// order. The [`NAME_LABEL`] matches the family name, and a missing
+    /// label has the empty value, as in selectors.
+    pub fn select(&self, matchers: &[Matcher]) -> Vec<(&MetricFamily, &Sample)> {
+        self.iter()
+            .flat_map(|family| family.samples.iter().map(move |s| (family, s)))
+            .filter(|(family, s)| {
+                matchers.iter().all(|m| {
+                    if m.name == NAME_LABEL {
+                        return m.matches(&family.name);
+                    }
+                    let value = s
+                        .labels
+                        .iter()
+                        .find(|(name, _)| *name == m.name)
+                        .map_or("", |(_, v)| v.as_str());
+                    m.matches(value)
+                })
+            })
+            .collect()
+    }
+
+    /// Whether `self` and `other` hold the same families and series, in any
+    /// order and with labels in any order, for golden tests. Floats are
+    /// equal if they differ by at most `tolerance` times the larger of the
+    /// two, so `0.0` compares exactly; `NaN` equals `NaN`. Timestamps and
+    /// counts must be equal.
+    pub fn semantically_equal(&self, other: &Families, tolerance: f64) -> bool {
+        let (mut left, mut right) = (canonical(self), canonical(other));
+        left.sort_by(|a, b| a.name.cmp(&b.name));
+        right.sort_by(|a, b| a.name.cmp(&b.name));
+        left.len() == right.len()
+            && left.iter().zip(&right).all(|(a, b)| {
+                a.name == b.name
+                    && a.help == b.help
+                    && a.unit == b.unit
+                    && a.metric_type == b.metric_type
+                    && a.samples.len() == b.samples.len()
+                    && a.samples
+                        .iter()
+                        .zip(&b.samples)
+                        .all(|(a, b)| samples_close(a, b, tolerance))
+            })
+    }
+
+    /// A hash of the families in the canonical order of
+    /// [`semantically_equal`](Families::semantically_equal), so a relay or
+    /// recorder can skip a scrape identical to the last one. Unlike that
+    /// comparison it is exact: any change to a value, timestamp, help text
+    /// or exemplar changes the digest. It is stable across runs and
+    /// platforms, so it may be stored.
+    pub fn digest(&self) -> u64 {
+        let mut families = canonical(self);
+        families.sort_by(|a, b| a.name.cmp(&b.name));
+        let mut writer = DigestWriter(Xxh64::new(0));
+        serde_json::to_writer(&mut writer, &families).expect("families serialize to JSON");
+        writer.0.digest()
+    }
+
+    /// Roughly how many bytes the families take in memory, to enforce
+    /// memory budgets or report how large a scrape is. Allocator overhead
+    /// is not counted.
+    pub fn approx_byte_size(&self) -> usize {
+        size_of::<Families>()
+            + (self.0.capacity() - self.0.len()) * size_of::<MetricFamily>()
+            + self
+                .iter()
+                .map(MetricFamily::approx_byte_size)
+                .sum::<usize>()
+    }
+
+    /// Every sample in the order the text encoder writes them, histograms
+    /// and summaries decomposed into buckets or quantiles, `_sum` and
+    /// `_count`, with the implicit `+Inf` bucket of histograms.
+    pub fn samples(&self) -> impl Iterator<Item = SampleLine<'_>> {
+        self.iter()
+            .flat_map(|family| family.samples.iter().flat_map(|s| sample_lines(family, s)))
+    }
+}
+
+impl Deref for Families {
+    type Target = [MetricFamily];
+
+    fn deref(&self) -> &[MetricFamily] {
+        &self.0
+    }
+}
+
+impl From<Vec<MetricFamily>> for Families {
+    fn from(families: Vec<MetricFamily>) -> Self {
+        Families(families)
+    }
+}
+
+impl FromIterator<MetricFamily> for Families {
+    fn from_iter<I: IntoIterator<Item = MetricFamily>>(iter: I) -> Self {
+        Families(iter.into_iter().collect())
+    }
+}
+
+impl IntoIterator for Families {
+    type Item = MetricFamily;
+    type IntoIter = std::vec::IntoIter<MetricFamily>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.into_iter()
+    }
+}
+
+/// Hashes what is written to it.
+struct DigestWriter(Xxh64);
+
+impl Write for DigestWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// `families` with labels, series and quantiles sorted.
+fn canonical(families: &[MetricFamily]) -> Vec<MetricFamily> {
+    families
+        .iter()
+        .map(|family| {
+            let mut family = family.clone();
+            for sample in &mut family.samples {
+                sample.labels.sort();
+                if let Some(exemplar) = &mut sample.exemplar {
+                    exemplar.labels.sort();
+                }
+                if let Value::Summary { quantiles, .. } = &mut sample.value {
+                    quantiles.sort_by(|a, b| a.quantile.total_cmp(&b.quantile));
+                }
+            }
+            family
+                .samples
+                .sort_by(|a, b| (&a.labels, a.timestamp_ms).cmp(&(&b.labels, b.timestamp_ms)));
+            family
+        })
+        .collect()
+}
+
+fn samples_close(a: &Sample, b: &Sample, tolerance: f64) -> bool {
+    let close = |x: f64, y: f64| {
+        if x.is_nan() || y.is_nan() {
+            return x.is_nan() && y.is_nan();
+        }
+        x == y || (x - y).abs() <= tolerance * f64::max(x.abs(), y.abs())
+    };
+    let exemplars_close = |x: &Option<Exemplar>, y: &Option<Exemplar>| match (x, y) {
+        (None, None) => true,
+        (Some(x), Some(y)) => {
+            x.labels == y.labels && x.timestamp_ms == y.timestamp_ms && close(x.value, y.value)
+        }
+        _ => false,
+    };
+    let values_close = match (&a.value, &b.value) {
+        (Value::Scalar(x), Value::Scalar(y)) => close(*x, *y),
+        (
+            Value::Histogram {
+                count: c1,
+                sum: s1,
+                buckets: b1,
+            },
+            Value::Histogram {
+                count: c2,
+                sum: s2,
+                buckets: b2,
+            },
+        ) => {
+            c1 == c2
+                && close(*s1, *s2)
+                && b1.len() == b2.len()
+                && b1.iter().zip(b2).all(|(x, y)| {
+                    x.cumulative_count == y.cumulative_count
+                        && close(x.upper_bound, y.upper_bound)
+                        && exemplars_close(&x.exemplar, &y.exemplar)
+                })
+        }
+        (
+            Value::Summary {
+                count: c1,
+                sum: s1,
+                quantiles: q1,
+            },
+            Value::Summary {
+                count: c2,
+                sum: s2,
+                quantiles: q2,
+            },
+        ) => {
+            c1 == c2
+                && close(*s1, *s2)
+                && q1.len() == q2.len()
+                && q1
+                    .iter()
+                    .zip(q2)
+                    .all(|(x, y)| close(x.quantile, y.quantile) && close(x.value, y.value))
+        }
+        _ => false,
+    };
+    values_close
+        && a.labels == b.labels
+        && a.timestamp_ms == b.timestamp_ms
+        && a.created_ms == b.created_ms
+        && exemplars_close(&a.exemplar, &b.exemplar)
+}
+
+fn sample_lines<'a>(family: &MetricFamily, sample: &'a Sample) -> Vec<SampleLine<'a>> {
+    let line = |suffix: &str, kind, extra, value| SampleLine {
+        name: format!("{}{}", family.name, suffix),
+        labels: &sample.labels,
+        extra,
+        value,
+        timestamp_ms: sample.timestamp_ms,
+        kind,
+    };
+    match &sample.value {
+        Value::Scalar(v) => {
+            let kind = match family.metric_type {
+                MetricType::Counter => SampleKind::Counter,
+                MetricType::Gauge => SampleKind::Gauge,
+                _ => SampleKind::Untyped,
+            };
+            vec![line("", kind, None, *v)]
+        }
+        Value::Histogram {
+            count,
+            sum,
+            buckets,
+        } => {
+            let mut lines: Vec<SampleLine> = buckets
+                .iter()
+                .map(|b| {
+                    let le = Some(("le", format_float(b.upper_bound)));
+                    line("_bucket", SampleKind::Bucket, le, b.cumulative_count as f64)
+                })
+                .collect();
+            if buckets
+                .last()
+                .is_none_or(|b| b.upper_bound != f64::INFINITY)
+            {
+                let le = Some(("le", "+Inf".to_string()));
+                lines.push(line("_bucket", SampleKind::Bucket, le, *count as f64));
+            }
+            lines.push(line("_sum", SampleKind::Sum, None, *sum));
+            lines.push(line("_count", SampleKind::Count, None, *count as f64));
+            lines
+        }
+        Value::Summary {
+            count,
+            sum,
+            quantiles,
+        } => {
+            let mut lines: Vec<SampleLine> = quantiles
+                .iter()
+                .map(|q| {
+                    let quantile = Some(("quantile", format_float(q.quantile)));
+                    line("", SampleKind::Quantile, quantile, q.value)
+                })
+                .collect();
+            lines.push(line("_sum", SampleKind::Sum, None, *sum));
+            lines.push(line("_count", SampleKind::Count, None, *count as f64));
+            lines
+        }
+    }
+}
+
+impl From<proto::MetricType> for MetricType {
+    fn from(t: proto::MetricType) -> Self {
+        match t {
+            proto::MetricType::COUNTER => MetricType::Counter,
+            proto::MetricType::GAUGE => MetricType::Gauge,
+            proto::MetricType::UNTYPED => MetricType::Untyped,
+            proto::MetricType::HISTOGRAM => MetricType::Histogram,
+            proto::MetricType::SUMMARY => MetricType::Summary,
+        }
+    }
+}
+
+impl From<MetricType> for proto::MetricType {
+    fn from(t: MetricType) -> Self {
+        match t {
+            MetricType::Counter => proto::MetricType::COUNTER,
+            MetricType::Gauge => proto::MetricType::GAUGE,
+            MetricType::Untyped => proto::MetricType::UNTYPED,
+            MetricType::Histogram => proto::MetricType::HISTOGRAM,
+            MetricType::Summary => proto::MetricType::SUMMARY,
+        }
+    }
+}
+
+impl From<&proto::MetricFamily> for MetricFamily {
+    fn from(mf: &proto::MetricFamily) -> Self {
+        let metric_type = MetricType::from(mf.get_field_type());
+        let samples = mf
+            .get_metric()
+            .iter()
+            .map(|m| {
+                let value = match metric_type {
+                    MetricType::Counter => Value::Scalar(m.get_counter().get_value()),
+                    MetricType::Gauge => Value::Scalar(m.get_gauge().get_value()),
+                    MetricType::Untyped => Value::Scalar(m.get_untyped().get_value()),
+                    MetricType::Histogram => {
+                        let h = m.get_histogram();
+                        Value::Histogram {
+                            count: h.get_sample_count(),
+                            sum: h.get_sample_sum(),
+                            buckets: h
+                                .get_bucket()
+                                .iter()
+                                .map(|b| Bucket {
+                                    upper_bound: b.get_upper_bound(),
+                                    cumulative_count: b.get_cumulative_count(),
+                                    exemplar: None,
+                                })
+                                .collect(),
+                        }
+                    }
+                    MetricType::Summary => {
+                        let s = m.get_summary();
+                        Value::Summary {
+                            count: s.get_sample_count(),
+                            sum: s.get_sample_sum(),
+                            quantiles: s
+                                .get_quantile()
+                                .iter()
+                                .map(|q| Quantile {
+                                    quantile: q.get_quantile(),
+                                    value: q.get_value(),
+                                })
+                                .collect(),
+                        }
+                    }
+                };
+                let mut sample = Sample::new(
+                    m.get_label()
+                        .iter()
+                        .map(|l| (l.get_name().to_string(), l.get_value().to_string()))
+                        .collect(),
+                    value,
+                );
+                sample.timestamp_ms = m.has_timestamp_ms().then(|| m.get_timestamp_ms());
+                sample
+            })
+            .collect();
+        MetricFamily {
+            name: mf.get_name().to_string(),
+            help: mf.has_help().then(|| mf.get_help().to_string()),
+            unit: None,
+            metric_type,
+            samples,
+        }
+    }
+}
+
+/// Drops units, created timestamps and exemplars. Samples whose value does
+/// not match the family's type are written as zero.
+impl From<&MetricFamily> for proto::MetricFamily {
+    fn from(family: &MetricFamily) -> Self {
+        let mut mf = proto::MetricFamily::default();
+        mf.set_name(family.name.clone());
+        if let Some(help) = &family.help {
+            mf.set_help(help.clone());
+        }
+        mf.set_field_type(family.metric_type.into());
+        for sample in &family.samples {
+            let mut m = proto::Metric::default();
+            m.set_label(
+                sample
+                    .labels
+                    .iter()
+                    .map(|(name, value)| {
+                        let mut pair = proto::LabelPair::new();
+                        pair.set_name(name.clone());
+                        pair.set_value(value.clone());
+                        pair
+                    })
+                    .collect(),
+            );
+            if let Some(ts) = sample.timestamp_ms {
+                m.set_timestamp_ms(ts);
+            }
+            match (family.metric_type, &sample.value) {
+                (MetricType::Counter, Value::Scalar(v)) => m.mut_counter().set_value(*v),
+                (MetricType::Counter, _) => m.mut_counter().set_value(0.0),
+                (MetricType::Gauge, Value::Scalar(v)) => m.mut_gauge().set_value(*v),
+                (MetricType::Gauge, _) => m.mut_gauge().set_value(0.0),
+                (MetricType::Untyped, Value::Scalar(v)) => m.mut_untyped().set_value(*v),
+                (MetricType::Untyped, _) => m.mut_untyped().set_value(0.0),
+                (MetricType::Histogram, value) => {
+                    let h = m.mut_histogram();
+                    if let Value::Histogram {
+                        count,
+                        sum,
+                        buckets,
+                    } = value
+                    {
+                        h.set_sample_count(*count);
+                        h.set_sample_sum(*sum);
+                        for b in buckets {
+                            let mut bucket = proto::Bucket::default();
+                            bucket.set_upper_bound(b.upper_bound);
+                            bucket.set_cumulative_count(b.cumulative_count);
+                            h.mut_bucket().push(bucket);
+                        }
+                    }
+                }
+                (MetricType::Summary, value) => {
+                    let s = m.mut_summary();
+                    if let Value::Summary {
+                        count,
+                        sum,
+                        quantiles,
+                    } = value
+                    {
+                        s.set_sample_count(*count);
+                        s.set_sample_sum(*sum);
+                        for quantile in quantiles {
+                            let mut q = proto::Quantile::default();
+                            q.set_quantile(quantile.quantile);
+                            q.set_value(quantile.value);
+                            s.mut_quantile().push(q);
+                        }
+                    }
+                }
+            }
+            mf.mut_metric().push(m);
+        }
+        mf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::{read_metric_families, Format};
+
+    #[test]
+    fn test_proto_round_trip() {
+        let text = r#"# HELP latency Request latency.
+# TYPE latency histogram
+latency_bucket{path="/",le="0.5"} 1
+latency_bucket{path="/",le="+Inf"} 2
+latency_sum{path="/"} 1.5
+latency_count{path="/"} 2
+# TYPE rpc summary
+rpc{quantile="0.9"} 0.2
+rpc_sum 3
+rpc_count 7
+# TYPE up gauge
+up{job="a"} 1 1700000000000
+"#;
+        let mfs = read_metric_families(Format::Text, text.as_bytes()).unwrap();
+        let families: Vec<MetricFamily> = mfs.iter().map(MetricFamily::from).collect();
+
+        assert_eq!(families[0].help.as_deref(), Some("Request latency."));
+        assert_eq!(
+            families[0].samples[0].value,
+            Value::Histogram {
+                count: 2,
+                sum: 1.5,
+                buckets: vec![
+                    Bucket {
+                        upper_bound: 0.5,
+                        cumulative_count: 1,
+                        exemplar: None
+                    },
+                    Bucket {
+                        upper_bound: f64::INFINITY,
+                        cumulative_count: 2,
+                        exemplar: None
+                    },
+                ],
+            }
+        );
+        assert_eq!(families[2].metric_type, MetricType::Gauge);
+        assert_eq!(families[2].samples[0].timestamp_ms, Some(1700000000000));
+        let back: Vec<proto::MetricFamily> = families.iter().map(Into::into).collect();
+        assert_eq!(back, mfs);
+
+        // What the protobuf types cannot hold is dropped.
+        let mut counter = MetricFamily {
+            name: "jobs_total".to_string(),
+            unit: Some("jobs".to_string()),
+            metric_type: MetricType::Counter,
+            samples: vec![Sample::new(vec![], Value::Scalar(3.0))],
+            ..Default::default()
+        };
+        counter.samples[0].created_ms = Some(1600000000000);
+        counter.samples[0].exemplar = Some(Exemplar {
+            labels: vec![("trace_id".to_string(), "abc".to_string())],
+            value: 1.0,
+            timestamp_ms: None,
+        });
+        let mf = proto::MetricFamily::from(&counter);
+        assert_eq!(mf.get_metric()[0].get_counter().get_value(), 3.0);
+        counter.unit = None;
+        counter.samples[0].created_ms = None;
+        counter.samples[0].exemplar = None;
+        assert_eq!(MetricFamily::from(&mf), counter);
+    }
+
+    #[test]
+    fn test_builder() {
+        let family = MetricFamilyBuilder::new("latency_seconds", MetricType::Histogram)
+            .help("Request latency.")
+            .unit("seconds")
+            .sample(
+                SampleBuilder::histogram(3, 1.2, &[(0.5, 2), (f64::INFINITY, 3)])
+                    .label("path", "/")
+                    .created_ms(1600000000000),
+            )
+            .build()
+            .unwrap();
+        let mut out = Vec::new();
+        crate::text_encode::metric_families_to_text(&mut out, &[(&family).into()]).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "# HELP latency_seconds Request latency.\n# TYPE latency_seconds histogram\n\
+             latency_seconds_bucket{path=\"/\",le=\"0.5\"} 2\n\
+             latency_seconds_bucket{path=\"/\",le=\"+Inf\"} 3\n\
+             latency_seconds_sum{path=\"/\"} 1.2\nlatency_seconds_count{path=\"/\"} 3\n"
+        );
+
+        let gauge = |sample: SampleBuilder| {
+            MetricFamilyBuilder::new("up", MetricType::Gauge)
+                .sample(sample)
+                .build()
+        };
+        assert!(gauge(SampleBuilder::scalar(1.0).label("job", "a")).is_ok());
+        assert_eq!(
+            gauge(
+                SampleBuilder::scalar(1.0)
+                    .label("job", "a")
+                    .label("job", "b")
+            ),
+            Err(BuildError::DuplicateLabel("job".to_string()))
+        );
+        assert_eq!(
+            gauge(SampleBuilder::scalar(1.0).label("1job", "a")),
+            Err(BuildError::InvalidLabelName("1job".to_string()))
+        );
+        assert_eq!(
+            gauge(SampleBuilder::summary(1, 1.0, &[])),
+            Err(BuildError::WrongValue(MetricType::Gauge))
+        );
+        assert_eq!(
+            MetricFamilyBuilder::new("h", MetricType::Histogram)
+                .sample(SampleBuilder::histogram(1, 1.0, &[(1.0, 2), (2.0, 1)]))
+                .build(),
+            Err(BuildError::InvalidBuckets)
+        );
+        assert!(MetricFamilyBuilder::new("bad name", MetricType::Gauge)
+            .build()
+            .is_err());
+    }
+
+    #[test]
+    fn test_samples() {
+        let text = "# TYPE latency histogram\nlatency_bucket{path=\"/\",le=\"0.5\"} 1\n\
+                    latency_sum{path=\"/\"} 0.25\nlatency_count{path=\"/\"} 1\n\
+                    # TYPE up gauge\nup{job=\"a\"} 1 1700000000000\n";
+        let families = crate::format::read_families(Format::Text, text.as_bytes()).unwrap();
+        let lines: Vec<SampleLine> = families.samples().collect();
+
+        let shown: Vec<(String, Option<String>, f64, SampleKind)> = lines
+            .iter()
+            .map(|l| {
+                let extra = l.extra.as_ref().map(|(n, v)| format!("{}={}", n, v));
+                (l.name.clone(), extra, l.value, l.kind)
+            })
+            .collect();
+        let line = |name: &str, extra: Option<&str>, value, kind| {
+            (name.to_string(), extra.map(str::to_string), value, kind)
+        };
+        assert_eq!(
+            shown,
+            vec![
+                line("latency_bucket", Some("le=0.5"), 1.0, SampleKind::Bucket),
+                line("latency_bucket", Some("le=+Inf"), 1.0, SampleKind::Bucket),
+                line("latency_sum", None, 0.25, SampleKind::Sum),
+                line("latency_count", None, 1.0, SampleKind::Count),
+                line("up", None, 1.0, SampleKind::Gauge),
+            ]
+        );
+        assert_eq!(lines[0].labels, &[("path".to_string(), "/".to_string())]);
+        assert_eq!(lines[4].timestamp_ms, Some(1700000000000));
+    }
+
+    #[test]
+    fn test_query() {
+        let text = "# TYPE up gauge\nup{job=\"api\",env=\"prod\"} 1\nup{job=\"db\"} 0\n\
+                    # TYPE errors_total counter\nerrors_total{job=\"api\"} 3\n";
+        let families = crate::format::read_families(Format::Text, text.as_bytes()).unwrap();
+        assert_eq!(families.get("up").unwrap().samples.len(), 2);
+        assert!(families.get("down").is_none());
+
+        let api: Matcher = "job=\"api\"".parse().unwrap();
+        let selected: Vec<(&str, f64)> = families
+            .select(&[api])
+            .into_iter()
+            .map(|(family, s)| (family.name.as_str(), s.scalar().unwrap()))
+            .collect();
+        assert_eq!(selected, vec![("errors_total", 3.0), ("up", 1.0)]);
+
+        let up = Matcher::new(NAME_LABEL, crate::grep::MatchOp::Equal, "up").unwrap();
+        let no_env: Matcher = "env=\"\"".parse().unwrap();
+        let selected = families.select(&[up, no_env]);
+        assert_eq!(selected.len(), 1);
+        assert_eq!(
+            selected[0].1.labels,
+            vec![("job".to_string(), "db".to_string())]
+        );
+        assert_eq!(families.select(&[]).len(), 3);
+    }
+
+    #[test]
+    fn test_accessors() {
+        let text =
+            "# TYPE latency histogram\nlatency_bucket{le=\"1\"} 2\nlatency_bucket{le=\"2\"} 4\n\
+                    latency_sum 5\nlatency_count 4\n\
+                    # TYPE rpc summary\nrpc{quantile=\"0.5\"} 0.1\nrpc{quantile=\"0.99\"} 0.8\n\
+                    rpc_sum 3\nrpc_count 10\n# TYPE up gauge\nup 1\n";
+        let families = crate::format::read_families(Format::Text, text.as_bytes()).unwrap();
+        let sample = |name| &families.get(name).unwrap().samples[0];
+
+        let h = sample("latency").histogram().unwrap();
+        assert_eq!((h.count, h.mean()), (4, 1.25));
+        assert_eq!(h.quantile(0.5), 1.0);
+        assert_eq!(h.quantile(0.75), 1.5);
+        assert!(sample("latency").scalar().is_none());
+
+        let s = sample("rpc").summary().unwrap();
+        assert_eq!(s.quantile(0.99), Some(0.8));
+        assert_eq!(s.quantile(0.9), None);
+        assert_eq!(s.mean(), 0.3);
+        assert!(sample("rpc").histogram().is_none());
+
+        assert_eq!(sample("up").scalar(), Some(1.0));
+        assert!(sample("up").summary().is_none());
+    }
+
+    #[test]
+    fn test_semantically_equal() {
+        let read =
+            |text: &str| crate::format::read_families(Format::Text, text.as_bytes()).unwrap();
+        let golden = read(
+            "# TYPE up gauge\nup{job=\"a\",env=\"prod\"} 1\nup{job=\"b\"} NaN\n\
+             # TYPE rpc summary\nrpc{quantile=\"0.5\"} 0.1\nrpc{quantile=\"0.9\"} 1000\n\
+             rpc_sum 3\nrpc_count 10\n",
+        );
+        let reordered = Families(
+            read(
+                "# TYPE up gauge\nup{job=\"b\"} NaN\nup{env=\"prod\",job=\"a\"} 1.0000001\n\
+                 # TYPE rpc summary\nrpc{quantile=\"0.9\"} 1000.0001\nrpc{quantile=\"0.5\"} 0.1\n\
+                 rpc_sum 3\nrpc_count 10\n",
+            )
+            .0
+            .into_iter()
+            .rev()
+            .collect(),
+        );
+
+        assert!(golden.semantically_equal(&golden, 0.0));
+        assert!(golden.semantically_equal(&reordered, 1e-6));
+        assert!(!golden.semantically_equal(&reordered, 1e-9));
+
+        let mut changed = golden.clone();
+        changed.0[1].samples[0].labels[0].1 = "c".to_string();
+        assert!(!golden.semantically_equal(&changed, 1.0));
+        let mut fewer = golden.clone();
+        fewer.0.pop();
+        assert!(!golden.semantically_equal(&fewer, 1.0));
+    }
+
+    #[test]
+    fn test_approx_byte_size() {
+        let text = "# HELP up Whether the target is up.\n# TYPE up gauge\nup{job=\"db\"} 1\n";
+        let families = crate::format::read_families(Format::Text, text.as_bytes()).unwrap();
+        let size = families.approx_byte_size();
+        let strings = "upWhether the target is up.jobdb".len();
+        assert!(size >= size_of::<MetricFamily>() + size_of::<Sample>() + strings);
+        assert!(size < 1024);
+        assert_eq!(
+            Families::default().approx_byte_size(),
+            size_of::<Families>()
+        );
+
+        let mut larger = families.clone();
+        larger.0[0].samples[0]
+            .labels
+            .push(("instance".to_string(), "db-1:9100".to_string()));
+        assert!(larger.approx_byte_size() > size);
+
+        let histogram = SampleBuilder::histogram(2, 1.5, &[(0.5, 1), (1.0, 2)]).build();
+        assert!(histogram.approx_byte_size() >= size_of::<Sample>() + 2 * size_of::<Bucket>());
+    }
+
+    #[test]
+    fn test_digest() {
+        let read =
+            |text: &str| crate::format::read_families(Format::Text, text.as_bytes()).unwrap();
+        let families = read(
+            "# TYPE up gauge\nup{job=\"a\",env=\"prod\"} 1\nup{job=\"b\"} NaN\n\
+             # TYPE rpc summary\nrpc{quantile=\"0.5\"} 0.1\nrpc_sum 3\nrpc_count 10\n",
+        );
+        let reordered = read(
+            "# TYPE rpc summary\nrpc{quantile=\"0.5\"} 0.1\nrpc_sum 3\nrpc_count 10\n\
+             # TYPE up gauge\nup{job=\"b\"} NaN\nup{env=\"prod\",job=\"a\"} 1\n",
+        );
+        assert_eq!(families.digest(), reordered.digest());
+        assert_ne!(families.digest(), Families::default().digest());
+
+        let mut changed = families.clone();
+        changed.0[1].samples[0].value = Value::Scalar(1.0000001);
+        assert_ne!(families.digest(), changed.digest());
+        let mut described = families.clone();
+        described.0[0].help = Some("RPC latency.".to_string());
+        assert_ne!(families.digest(), described.digest());
+    }
+
+    #[test]
+    fn test_serde() {
+        let family = MetricFamily {
+            name: "latency".to_string(),
+            help: Some("Request latency.".to_string()),
+            unit: Some("seconds".to_string()),
+            metric_type: MetricType::Histogram,
+            samples: vec![Sample {
+                created_ms: Some(1600000000000),
+                ..Sample::new(
+                    vec![("path".to_string(), "/".to_string())],
+                    Value::Histogram {
+                        count: 2,
+                        sum: 1.5,
+                        buckets: vec![
+                            Bucket {
+                                upper_bound: 0.5,
+                                cumulative_count: 1,
+                                exemplar: Some(Exemplar {
+                                    labels: vec![("trace_id".to_string(), "abc".to_string())],
+                                    value: 0.25,
+                                    timestamp_ms: None,
+                                }),
+                            },
+                            Bucket {
+                                upper_bound: f64::INFINITY,
+                                cumulative_count: 2,
+                                exemplar: None,
+                            },
+                        ],
+                    },
+                )
+            }],
+        };
+
+        let json = serde_json::to_value(&family).unwrap();
+        assert_eq!(json["type"], "histogram");
+        assert_eq!(
+            json["samples"][0]["value"]["histogram"]["buckets"][1]["upper_bound"],
+            "+Inf"
+        );
+        assert_eq!(
+            serde_json::from_value::<MetricFamily>(json).unwrap(),
+            family
+        );
+
+        let packed = rmp_serde::to_vec(&family).unwrap();
+        assert_eq!(
+            rmp_serde::from_slice::<MetricFamily>(&packed).unwrap(),
+            family
+        );
+
+        let nan: Value = serde_json::from_str(r#"{"scalar": "NaN"}"#).unwrap();
+        assert!(matches!(nan, Value::Scalar(v) if v.is_nan()));
+        assert!(serde_json::from_str::<Value>(r#"{"scalar": "one"}"#).is_err());
+    }
+}
@@ -0,0 +1,423 @@
+//! Publishing samples to an MQTT broker, e.g. to bridge exporters into
+//! home-automation setups. Built with the `mqtt` feature.
+//!
+//! Every sample becomes a message whose payload is its value as the text
+//! format writes it, on a topic rendered from a template such as
+//! `home/{room}/{name}`: `{name}` stands for the sample name and any other
+//! `{label}` for that label's value. The client speaks MQTT 3.1.1 over plain
+//! TCP at QoS 0 or 1, and connects anew for every [`Publisher::publish`].
+
+use prometheus::proto::MetricFamily;
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::flatten::flatten_family;
+use crate::grep::Selector;
+use crate::text_encode::format_float;
+
+const DEFAULT_PORT: u16 = 1883;
+const KEEP_ALIVE_SECS: u16 = 60;
+
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const PUBACK: u8 = 0x40;
+const DISCONNECT: u8 = 0xe0;
+
+#[derive(Debug)]
+pub enum MqttError {
+    Io(io::Error),
+    /// The broker refused the connection with this CONNACK return code.
+    Refused(u8),
+    Protocol(String),
+}
+
+impl fmt::Display for MqttError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MqttError::Io(e) => write!(f, "MQTT connection failed: {}", e),
+            MqttError::Refused(code) => {
+                let reason = match code {
+                    1 => "unacceptable protocol version",
+                    2 => "client identifier rejected",
+                    3 => "server unavailable",
+                    4 => "bad user name or password",
+                    5 => "not authorized",
+                    _ => "unknown reason",
+                };
+                write!(f, "MQTT broker refused connection: {}", reason)
+            }
+            MqttError::Protocol(msg) => write!(f, "MQTT protocol error: {}", msg),
+        }
+    }
+}
+
+impl Error for MqttError {}
+
+impl From<io::Error> for MqttError {
+    fn from(e: io::Error) -> Self {
+        MqttError::Io(e)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum QoS {
+    #[default]
+    AtMostOnce = 0,
+    AtLeastOnce = 1,
+}
+
+impl FromStr for QoS {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "0" => Ok(QoS::AtMostOnce),
+            "1" => Ok(QoS::AtLeastOnce),
+            _ => Err(format!("unsupported QoS {:?}, expected 0 or 1", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    Text(String),
+    Name,
+    Label(String),
+}
+
+/// A topic template such as `pmv/{job}/{name}`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Topic(Vec<Part>);
+
+impl FromStr for Topic {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.contains(['+', '#']) {
+            return Err(format!("topic {:?} has a wildcard", s));
+        }
+        let mut parts = Vec::new();
+        let mut rest = s;
+        while let Some(start) = rest.find('{') {
+            let len = rest[start..]
+                .find('}')
+                .ok_or_else(|| format!("unterminated placeholder in topic {:?}", s))?;
+            if start > 0 {
+                parts.push(Part::Text(rest[..start].to_string()));
+            }
+            parts.push(match &rest[start + 1..start + len] {
+                "name" => Part::Name,
+                label => Part::Label(label.to_string()),
+            });
+            rest = &rest[start + len + 1..];
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Text(rest.to_string()));
+        }
+        if parts.is_empty() {
+            return Err("empty topic".to_string());
+        }
+        Ok(Topic(parts))
+    }
+}
+
+impl Topic {
+    /// The topic of a sample. Characters that would add levels or act as
+    /// wildcards become `_`; missing labels render empty.
+    pub fn render(&self, name: &str, labels: &[(String, String)]) -> String {
+        let clean = |s: &str| s.replace(['/', '+', '#'], "_");
+        self.0
+            .iter()
+            .map(|part| match part {
+                Part::Text(text) => text.clone(),
+                Part::Name => clean(name),
+                Part::Label(label) => labels
+                    .iter()
+                    .find(|(n, _)| n == label)
+                    .map_or(String::new(), |(_, v)| clean(v)),
+            })
+            .collect()
+    }
+}
+
+/// Publishes samples to a broker.
+#[derive(Debug, Clone)]
+pub struct Publisher {
+    address: String,
+    topic: Topic,
+    client_id: String,
+    credentials: Option<(String, Option<String>)>,
+    qos: QoS,
+    retain: bool,
+    timeout: Duration,
+}
+
+impl Publisher {
+    /// A publisher to the broker at `url`, `mqtt://host[:port]` or just
+    /// `host[:port]`, at QoS 0 without retaining, giving up on the broker
+    /// after 10s.
+    pub fn new(url: &str, topic: Topic) -> Self {
+        let address = url.strip_prefix("mqtt://").unwrap_or(url);
+        let address = address.trim_end_matches('/');
+        let address = match address.rsplit_once(':') {
+            Some((_, port)) if port.parse::<u16>().is_ok() => address.to_string(),
+            _ => format!("{}:{}", address, DEFAULT_PORT),
+        };
+        Publisher {
+            address,
+            topic,
+            client_id: format!("pmv-{}", std::process::id()),
+            credentials: None,
+            qos: QoS::AtMostOnce,
+            retain: false,
+            timeout: Duration::from_secs(10),
+        }
+    }
+
+    /// Client identifier; `pmv-<pid>` by default.
+    pub fn client_id(mut self, client_id: impl Into<String>) -> Self {
+        self.client_id = client_id.into();
+        self
+    }
+
+    /// Logs in as `user`, with `password` if given.
+    pub fn credentials(mut self, user: impl Into<String>, password: Option<String>) -> Self {
+        self.credentials = Some((user.into(), password));
+        self
+    }
+
+    pub fn qos(mut self, qos: QoS) -> Self {
+        self.qos = qos;
+        self
+    }
+
+    /// Asks the broker to keep the last message of every topic for new
+    /// subscribers.
+    pub fn retain(mut self, retain: bool) -> Self {
+        self.retain = retain;
+        self
+    }
+
+    /// Limit on connecting and on every read and write.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Publishes the samples of `mfs` matching any of `selectors`, or all
+    /// if there are none, and returns how many were published. At QoS 1,
+    /// every message is acknowledged before the next is sent.
+    pub fn publish(
+        &self,
+        mfs: &[MetricFamily],
+        selectors: &[Selector],
+    ) -> Result<usize, MqttError> {
+        let mut stream = self.connect()?;
+
+        let mut published = 0;
+        for mf in mfs {
+            for sample in flatten_family(mf) {
+                if !selectors.is_empty()
+                    && !selectors
+                        .iter()
+                        .any(|s| s.matches_series(&sample.name, &sample.labels))
+                {
+                    continue;
+                }
+                let topic = self.topic.render(&sample.name, &sample.labels);
+                let packet_id = (published % u16::MAX as usize) as u16 + 1;
+                self.send_publish(&mut stream, &topic, &format_float(sample.value), packet_id)?;
+                published += 1;
+            }
+        }
+
+        stream.write_all(&[DISCONNECT, 0])?;
+        Ok(published)
+    }
+
+    fn connect(&self) -> Result<TcpStream, MqttError> {
+        let addr = self.address.to_socket_addrs()?.next().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{}: no address", self.address),
+            )
+        })?;
+        let mut stream = TcpStream::connect_timeout(&addr, self.timeout)?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+
+        let mut flags = 0x02; // clean session
+        let mut payload = Vec::new();
+        put_string(&mut payload, &self.client_id);
+        if let Some((user, password)) = &self.credentials {
+            flags |= 0x80;
+            put_string(&mut payload, user);
+            if let Some(password) = password {
+                flags |= 0x40;
+                put_string(&mut payload, password);
+            }
+        }
+        let mut body = Vec::new();
+        put_string(&mut body, "MQTT");
+        body.push(4); // protocol level 3.1.1
+        body.push(flags);
+        body.extend_from_slice(&KEEP_ALIVE_SECS.to_be_bytes());
+        body.extend_from_slice(&payload);
+        write_packet(&mut stream, CONNECT, &body)?;
+
+        match read_packet(&mut stream)? {
+            (CONNACK, body) if body.len() == 2 => match body[1] {
+                0 => Ok(stream),
+                code => Err(MqttError::Refused(code)),
+            },
+            (kind, _) => Err(MqttError::Protocol(format!(
+                "expected CONNACK, got packet type {}",
+                kind >> 4
+            ))),
+        }
+    }
+
+    fn send_publish(
+        &self,
+        stream: &mut TcpStream,
+        topic: &str,
+        payload: &str,
+        packet_id: u16,
+    ) -> Result<(), MqttError> {
+        let mut body = Vec::new();
+        put_string(&mut body, topic);
+        if self.qos == QoS::AtLeastOnce {
+            body.extend_from_slice(&packet_id.to_be_bytes());
+        }
+        body.extend_from_slice(payload.as_bytes());
+        let flags = (self.qos as u8) << 1 | u8::from(self.retain);
+        write_packet(stream, PUBLISH | flags, &body)?;
+
+        if self.qos == QoS::AtLeastOnce {
+            match read_packet(stream)? {
+                (PUBACK, body) if body == packet_id.to_be_bytes() => {}
+                (kind, _) => {
+                    return Err(MqttError::Protocol(format!(
+                        "expected PUBACK {}, got packet type {}",
+                        packet_id,
+                        kind >> 4
+                    )))
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn put_string(buf: &mut Vec<u8>, s: &str) {
+    buf.extend_from_slice(&(s.len() as u16).to_be_bytes());
+    buf.extend_from_slice(s.as_bytes());
+}
+
+fn write_packet<W: Write>(w: &mut W, header: u8, body: &[u8]) -> io::Result<()> {
+    let mut packet = vec![header];
+    // The remaining length, 7 bits per byte, least significant first.
+    let mut len = body.len();
+    loop {
+        let mut byte = (len % 128) as u8;
+        len /= 128;
+        if len > 0 {
+            byte |= 0x80;
+        }
+        packet.push(byte);
+        if len == 0 {
+            break;
+        }
+    }
+    packet.extend_from_slice(body);
+    w.write_all(&packet)
+}
+
+/// Reads a packet, returning its first byte and its body.
+fn read_packet<R: Read>(r: &mut R) -> Result<(u8, Vec<u8>), MqttError> {
+    let mut byte = [0u8];
+    r.read_exact(&mut byte)?;
+    let header = byte[0] & 0xf0;
+    let mut len = 0usize;
+    for shift in (0..28).step_by(7) {
+        r.read_exact(&mut byte)?;
+        len |= ((byte[0] & 0x7f) as usize) << shift;
+        if byte[0] & 0x80 == 0 {
+            let mut body = vec![0; len];
+            r.read_exact(&mut body)?;
+            return Ok((header, body));
+        }
+    }
+    Err(MqttError::Protocol(
+        "malformed remaining length".to_string(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::{read_metric_families, Format};
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_publish() {
+        let topic: Topic = "home/{room}/{name}".parse().unwrap();
+        assert_eq!(
+            topic.render("temp", &[("room".to_string(), "a/b".to_string())]),
+            "home/a_b/temp"
+        );
+        assert!("home/#".parse::<Topic>().is_err());
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("mqtt://{}", listener.local_addr().unwrap());
+        let broker = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let (kind, connect) = read_packet(&mut stream).unwrap();
+            assert_eq!(kind, CONNECT);
+            write_packet(&mut stream, CONNACK, &[0, 0]).unwrap();
+
+            let mut messages = Vec::new();
+            loop {
+                let (kind, body) = read_packet(&mut stream).unwrap();
+                if kind == DISCONNECT {
+                    break;
+                }
+                let len = u16::from_be_bytes([body[0], body[1]]) as usize;
+                let topic = String::from_utf8(body[2..2 + len].to_vec()).unwrap();
+                let packet_id = &body[2 + len..4 + len];
+                let payload = String::from_utf8(body[4 + len..].to_vec()).unwrap();
+                write_packet(&mut stream, PUBACK, packet_id).unwrap();
+                messages.push((topic, payload));
+            }
+            (connect, messages)
+        });
+
+        let mfs = read_metric_families(
+            Format::Text,
+            "temperature{room=\"kitchen\"} 21.5\ntemperature{room=\"attic\"} 30\nhumidity 40\n"
+                .as_bytes(),
+        )
+        .unwrap();
+        let publisher = Publisher::new(&url, topic)
+            .credentials("pmv", Some("secret".to_string()))
+            .qos(QoS::AtLeastOnce);
+        let selectors = ["temperature".parse().unwrap()];
+        assert_eq!(publisher.publish(&mfs, &selectors).unwrap(), 2);
+
+        let (connect, messages) = broker.join().unwrap();
+        assert_eq!(&connect[..7], b"\x00\x04MQTT\x04");
+        assert_eq!(connect[7], 0xc2);
+        assert_eq!(
+            messages,
+            [
+                ("home/kitchen/temperature".to_string(), "21.5".to_string()),
+                ("home/attic/temperature".to_string(), "30".to_string()),
+            ]
+        );
+    }
+}
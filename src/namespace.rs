@@ -0,0 +1,94 @@
+use prometheus::proto::MetricFamily;
+use serde::Deserialize;
+
+use crate::grammar::{is_valid_metric_name_continuation, is_valid_metric_name_start};
+
+/// A rewrite of family names so the families of several sources can be
+/// merged without colliding, e.g. `node_load1` to `edge_node_load1`. A
+/// family keeps its HELP, TYPE and series, which are stored with it, so its
+/// `_bucket`, `_sum` and `_count` samples follow the new name.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Namespace {
+    /// Removed from the names that start with it, before `prefix` is added.
+    /// Names that would be left empty or invalid keep it.
+    #[serde(default)]
+    pub strip: Option<String>,
+    /// Prepended to every name.
+    #[serde(default)]
+    pub prefix: Option<String>,
+}
+
+impl Namespace {
+    /// Fails if `prefix` cannot start a metric name.
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(prefix) = &self.prefix {
+            if !prefix.starts_with(is_valid_metric_name_start)
+                || !prefix.chars().all(is_valid_metric_name_continuation)
+            {
+                return Err(format!("invalid name prefix {:?}", prefix));
+            }
+        }
+        Ok(())
+    }
+
+    /// The new name of the family `name`.
+    pub fn rename(&self, name: &str) -> String {
+        let stripped = self
+            .strip
+            .as_deref()
+            .and_then(|strip| name.strip_prefix(strip))
+            .filter(|rest| {
+                rest.starts_with(is_valid_metric_name_start)
+                    && rest.chars().all(is_valid_metric_name_continuation)
+            })
+            .unwrap_or(name);
+        format!("{}{}", self.prefix.as_deref().unwrap_or(""), stripped)
+    }
+}
+
+/// Renames every family per `namespace`.
+pub fn namespace(mfs: &[MetricFamily], namespace: &Namespace) -> Vec<MetricFamily> {
+    mfs.iter()
+        .map(|mf| {
+            let mut mf = mf.clone();
+            mf.set_name(namespace.rename(mf.get_name()));
+            mf
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::{read_metric_families, Format};
+    use crate::text_encode::metric_families_to_text;
+
+    #[test]
+    fn test_namespace() {
+        let text = "# HELP legacy_requests_total Requests.\n# TYPE legacy_requests_total counter\n\
+                    legacy_requests_total 3\n# TYPE legacy_ gauge\nlegacy_ 1\n\
+                    # TYPE latency histogram\nlatency_bucket{le=\"+Inf\"} 1\nlatency_sum 2\nlatency_count 1\n";
+        let mfs = read_metric_families(Format::Text, text.as_bytes()).unwrap();
+        let ns = Namespace {
+            strip: Some("legacy_".to_string()),
+            prefix: Some("edge_".to_string()),
+        };
+        let mut out = Vec::new();
+        metric_families_to_text(&mut out, &namespace(&mfs, &ns)).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "# TYPE edge_latency histogram\nedge_latency_bucket{le=\"+Inf\"} 1\n\
+             edge_latency_sum 2\nedge_latency_count 1\n# TYPE edge_legacy_ gauge\nedge_legacy_ 1\n\
+             # HELP edge_requests_total Requests.\n# TYPE edge_requests_total counter\n\
+             edge_requests_total 3\n"
+        );
+
+        assert!(ns.validate().is_ok());
+        let bad = Namespace {
+            prefix: Some("1st_".to_string()),
+            ..Namespace::default()
+        };
+        assert!(bad.validate().is_err());
+    }
+}
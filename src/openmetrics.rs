@@ -0,0 +1,259 @@
+use prometheus::proto::{LabelPair, Metric, MetricFamily, MetricType};
+use std::io::{self, Write};
+
+use crate::text_encode::{escape_label_value, format_float};
+
+const QUANTILE_LABEL: &str = "quantile";
+const BUCKET_LABEL: &str = "le";
+
+/// The OpenMetrics text content type.
+pub const OPENMETRICS_FORMAT: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// Writes metric families in the OpenMetrics text format, followed by the
+/// mandatory `# EOF` line.
+///
+/// Counter families are announced without their `_total` suffix, untyped
+/// families become `unknown`, and timestamps are written in seconds.
+pub fn metric_families_to_openmetrics<W: Write>(
+    out: &mut W,
+    mfs: &[MetricFamily],
+) -> io::Result<()> {
+    for mf in mfs {
+        metric_family_to_openmetrics(out, mf)?;
+    }
+    writeln!(out, "# EOF")
+}
+
+fn metric_family_to_openmetrics<W: Write>(out: &mut W, mf: &MetricFamily) -> io::Result<()> {
+    let metric_type = mf.get_field_type();
+    let name = match metric_type {
+        MetricType::COUNTER => mf.get_name().trim_end_matches("_total"),
+        _ => mf.get_name(),
+    };
+
+    writeln!(out, "# TYPE {} {}", name, type_name(metric_type))?;
+    if mf.has_help() {
+        writeln!(out, "# HELP {} {}", name, escape_label_value(mf.get_help()))?;
+    }
+
+    for m in mf.get_metric() {
+        match metric_type {
+            MetricType::COUNTER => {
+                write_sample(out, name, "_total", m, None, m.get_counter().get_value())?;
+            }
+            MetricType::GAUGE => {
+                write_sample(out, name, "", m, None, m.get_gauge().get_value())?;
+            }
+            MetricType::UNTYPED => {
+                write_sample(out, name, "", m, None, m.get_untyped().get_value())?;
+            }
+            MetricType::SUMMARY => {
+                let s = m.get_summary();
+                for q in s.get_quantile() {
+                    let quantile = format_openmetrics_float(q.get_quantile());
+                    write_sample(
+                        out,
+                        name,
+                        "",
+                        m,
+                        Some((QUANTILE_LABEL, &quantile)),
+                        q.get_value(),
+                    )?;
+                }
+                write_sample(out, name, "_sum", m, None, s.get_sample_sum())?;
+                write_sample(out, name, "_count", m, None, s.get_sample_count() as f64)?;
+            }
+            MetricType::HISTOGRAM => {
+                let h = m.get_histogram();
+                let mut inf_seen = false;
+                for b in h.get_bucket() {
+                    let upper_bound = format_openmetrics_float(b.get_upper_bound());
+                    write_sample(
+                        out,
+                        name,
+                        "_bucket",
+                        m,
+                        Some((BUCKET_LABEL, &upper_bound)),
+                        b.get_cumulative_count() as f64,
+                    )?;
+                    if b.get_upper_bound() == f64::INFINITY {
+                        inf_seen = true;
+                    }
+                }
+                if !inf_seen {
+                    write_sample(
+                        out,
+                        name,
+                        "_bucket",
+                        m,
+                        Some((BUCKET_LABEL, "+Inf")),
+                        h.get_sample_count() as f64,
+                    )?;
+                }
+                write_sample(out, name, "_sum", m, None, h.get_sample_sum())?;
+                write_sample(out, name, "_count", m, None, h.get_sample_count() as f64)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn write_sample<W: Write>(
+    out: &mut W,
+    name: &str,
+    suffix: &str,
+    m: &Metric,
+    additional_label: Option<(&str, &str)>,
+    value: f64,
+) -> io::Result<()> {
+    write!(out, "{}{}", name, suffix)?;
+    write_labels(out, m.get_label(), additional_label)?;
+    write!(out, " {}", format_float(value))?;
+
+    if m.has_timestamp_ms() {
+        write!(
+            out,
+            " {}",
+            format_float(m.get_timestamp_ms() as f64 / 1000.0)
+        )?;
+    }
+
+    writeln!(out)
+}
+
+fn write_labels<W: Write>(
+    out: &mut W,
+    labels: &[LabelPair],
+    additional_label: Option<(&str, &str)>,
+) -> io::Result<()> {
+    if labels.is_empty() && additional_label.is_none() {
+        return Ok(());
+    }
+
+    let pairs = labels
+        .iter()
+        .map(|l| (l.get_name(), l.get_value()))
+        .chain(additional_label);
+
+    let mut separator = "{";
+    for (name, value) in pairs {
+        write!(
+            out,
+            "{}{}=\"{}\"",
+            separator,
+            name,
+            escape_label_value(value)
+        )?;
+        separator = ",";
+    }
+
+    write!(out, "}}")
+}
+
+fn type_name(t: MetricType) -> &'static str {
+    match t {
+        MetricType::COUNTER => "counter",
+        MetricType::GAUGE => "gauge",
+        MetricType::SUMMARY => "summary",
+        MetricType::UNTYPED => "unknown",
+        MetricType::HISTOGRAM => "histogram",
+    }
+}
+
+/// Formats `le` and `quantile` values in the canonical OpenMetrics form,
+/// which always carries a decimal point or exponent (`1.0`, not `1`).
+fn format_openmetrics_float(v: f64) -> String {
+    let s = format_float(v);
+    if v.is_finite() && !s.contains(['.', 'e']) {
+        format!("{}.0", s)
+    } else {
+        s
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::text_parse::TextParser;
+    use std::io::{BufReader, Cursor};
+
+    fn parse(text: &str, openmetrics: bool) -> Vec<MetricFamily> {
+        let cursor = Cursor::new(text.to_string().into_bytes());
+        let mfs = TextParser::new(BufReader::new(cursor))
+            .openmetrics(openmetrics)
+            .text_to_metric_families()
+            .unwrap();
+
+        let mut names: Vec<_> = mfs.keys().cloned().collect();
+        names.sort();
+        names.iter().map(|n| mfs[n].clone()).collect()
+    }
+
+    fn encode(mfs: &[MetricFamily]) -> String {
+        let mut out = Vec::new();
+        metric_families_to_openmetrics(&mut out, mfs).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_text_to_openmetrics() {
+        let mfs = parse(
+            r#"# HELP http_requests_total Requests "served".
+# TYPE http_requests_total counter
+http_requests_total{code="200"} 10 1700000000500
+# TYPE latency histogram
+latency_bucket{le="1"} 2
+latency_sum 0.5
+latency_count 2
+other 1
+"#,
+            false,
+        );
+
+        assert_eq!(
+            encode(&mfs),
+            r#"# TYPE http_requests counter
+# HELP http_requests Requests \"served\".
+http_requests_total{code="200"} 10 1700000000.5
+# TYPE latency histogram
+latency_bucket{le="1.0"} 2
+latency_bucket{le="+Inf"} 2
+latency_sum 0.5
+latency_count 2
+# TYPE other unknown
+other 1
+# EOF
+"#
+        );
+    }
+
+    #[test]
+    fn test_openmetrics_round_trip() {
+        let text = r#"# TYPE build info
+build_info{version="1.2"} 1
+# TYPE jobs counter
+# HELP jobs Jobs run.
+jobs_total 3 1700000000.25
+jobs_created 1600000000
+# TYPE rpc summary
+rpc{quantile="0.5"} 0.1
+rpc_sum 1.0
+rpc_count 4
+rpc_created 1600000000
+# EOF
+"#;
+        let mfs = parse(text, true);
+        assert_eq!(mfs.len(), 3);
+        assert_eq!(mfs[0].get_name(), "build_info");
+        assert_eq!(mfs[1].get_name(), "jobs_total");
+        assert_eq!(mfs[1].get_help(), "Jobs run.");
+        assert_eq!(mfs[1].get_metric()[0].get_timestamp_ms(), 1700000000250);
+        assert_eq!(mfs[2].get_metric()[0].get_summary().get_sample_count(), 4);
+
+        let encoded = encode(&mfs);
+        assert!(encoded
+            .contains("# TYPE jobs counter\n# HELP jobs Jobs run.\njobs_total 3 1700000000.25\n"));
+        assert_eq!(parse(&encoded, true), mfs);
+    }
+}
@@ -0,0 +1,799 @@
+use prometheus::proto::{
+    Bucket, Counter, Gauge, LabelPair, Metric as PromMetric, MetricFamily, MetricType, Quantile,
+};
+use prost::Message;
+use std::collections::{BTreeMap, HashMap};
+use std::iter;
+
+use crate::grammar::{
+    is_valid_label_name_continuation, is_valid_label_name_start, is_valid_metric_name_continuation,
+    is_valid_metric_name_start,
+};
+use crate::text_parse::labels_to_signature;
+
+pub mod export;
+
+/// `ExportMetricsServiceRequest` from OTLP, reduced to what pmv converts.
+/// Exponential histograms, exemplars and scope metadata are not decoded.
+#[derive(Clone, PartialEq, Message)]
+pub struct ExportMetricsServiceRequest {
+    #[prost(message, repeated, tag = "1")]
+    pub resource_metrics: Vec<ResourceMetrics>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct ResourceMetrics {
+    #[prost(message, optional, tag = "1")]
+    pub resource: Option<Resource>,
+    #[prost(message, repeated, tag = "2")]
+    pub scope_metrics: Vec<ScopeMetrics>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Resource {
+    #[prost(message, repeated, tag = "1")]
+    pub attributes: Vec<KeyValue>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct ScopeMetrics {
+    #[prost(message, repeated, tag = "2")]
+    pub metrics: Vec<Metric>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Metric {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(string, tag = "2")]
+    pub description: String,
+    #[prost(string, tag = "3")]
+    pub unit: String,
+    #[prost(oneof = "MetricData", tags = "5, 7, 9, 11")]
+    pub data: Option<MetricData>,
+}
+
+#[derive(Clone, PartialEq, prost::Oneof)]
+pub enum MetricData {
+    #[prost(message, tag = "5")]
+    Gauge(GaugeData),
+    #[prost(message, tag = "7")]
+    Sum(SumData),
+    #[prost(message, tag = "9")]
+    Histogram(HistogramData),
+    #[prost(message, tag = "11")]
+    Summary(SummaryData),
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct GaugeData {
+    #[prost(message, repeated, tag = "1")]
+    pub data_points: Vec<NumberDataPoint>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct SumData {
+    #[prost(message, repeated, tag = "1")]
+    pub data_points: Vec<NumberDataPoint>,
+    #[prost(enumeration = "AggregationTemporality", tag = "2")]
+    pub aggregation_temporality: i32,
+    #[prost(bool, tag = "3")]
+    pub is_monotonic: bool,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct HistogramData {
+    #[prost(message, repeated, tag = "1")]
+    pub data_points: Vec<HistogramDataPoint>,
+    #[prost(enumeration = "AggregationTemporality", tag = "2")]
+    pub aggregation_temporality: i32,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct SummaryData {
+    #[prost(message, repeated, tag = "1")]
+    pub data_points: Vec<SummaryDataPoint>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+pub enum AggregationTemporality {
+    Unspecified = 0,
+    Delta = 1,
+    Cumulative = 2,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct NumberDataPoint {
+    #[prost(message, repeated, tag = "7")]
+    pub attributes: Vec<KeyValue>,
+    #[prost(fixed64, tag = "3")]
+    pub time_unix_nano: u64,
+    #[prost(oneof = "NumberValue", tags = "4, 6")]
+    pub value: Option<NumberValue>,
+}
+
+#[derive(Clone, PartialEq, prost::Oneof)]
+pub enum NumberValue {
+    #[prost(double, tag = "4")]
+    AsDouble(f64),
+    #[prost(sfixed64, tag = "6")]
+    AsInt(i64),
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct HistogramDataPoint {
+    #[prost(message, repeated, tag = "9")]
+    pub attributes: Vec<KeyValue>,
+    #[prost(fixed64, tag = "3")]
+    pub time_unix_nano: u64,
+    #[prost(fixed64, tag = "4")]
+    pub count: u64,
+    #[prost(double, optional, tag = "5")]
+    pub sum: Option<f64>,
+    #[prost(fixed64, repeated, tag = "6")]
+    pub bucket_counts: Vec<u64>,
+    #[prost(double, repeated, tag = "7")]
+    pub explicit_bounds: Vec<f64>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct SummaryDataPoint {
+    #[prost(message, repeated, tag = "7")]
+    pub attributes: Vec<KeyValue>,
+    #[prost(fixed64, tag = "3")]
+    pub time_unix_nano: u64,
+    #[prost(fixed64, tag = "4")]
+    pub count: u64,
+    #[prost(double, tag = "5")]
+    pub sum: f64,
+    #[prost(message, repeated, tag = "6")]
+    pub quantile_values: Vec<ValueAtQuantile>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct ValueAtQuantile {
+    #[prost(double, tag = "1")]
+    pub quantile: f64,
+    #[prost(double, tag = "2")]
+    pub value: f64,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct KeyValue {
+    #[prost(string, tag = "1")]
+    pub key: String,
+    #[prost(message, optional, tag = "2")]
+    pub value: Option<AnyValue>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct AnyValue {
+    #[prost(oneof = "AnyValueKind", tags = "1, 2, 3, 4")]
+    pub value: Option<AnyValueKind>,
+}
+
+#[derive(Clone, PartialEq, prost::Oneof)]
+pub enum AnyValueKind {
+    #[prost(string, tag = "1")]
+    StringValue(String),
+    #[prost(bool, tag = "2")]
+    BoolValue(bool),
+    #[prost(int64, tag = "3")]
+    IntValue(i64),
+    #[prost(double, tag = "4")]
+    DoubleValue(f64),
+}
+
+impl AnyValue {
+    fn to_label_value(&self) -> String {
+        match &self.value {
+            Some(AnyValueKind::StringValue(s)) => s.clone(),
+            Some(AnyValueKind::BoolValue(b)) => b.to_string(),
+            Some(AnyValueKind::IntValue(i)) => i.to_string(),
+            Some(AnyValueKind::DoubleValue(d)) => d.to_string(),
+            None => String::new(),
+        }
+    }
+}
+
+pub fn decode_export_request(
+    body: &[u8],
+) -> Result<ExportMetricsServiceRequest, prost::DecodeError> {
+    ExportMetricsServiceRequest::decode(body)
+}
+
+/// Converts metric families into a request, the reverse of
+/// [`OtlpConverter`]. Counters become monotonic cumulative sums and
+/// histograms cumulative histograms. Untyped families become gauges, the
+/// OTLP type that makes no claim about how values change. Labels become
+/// data point attributes; samples without a timestamp have no time.
+pub fn metric_families_to_export_request(mfs: &[MetricFamily]) -> ExportMetricsServiceRequest {
+    let metrics = mfs
+        .iter()
+        .map(|mf| {
+            let data = match mf.get_field_type() {
+                MetricType::COUNTER => MetricData::Sum(SumData {
+                    data_points: number_points(mf, |m| m.get_counter().get_value()),
+                    aggregation_temporality: AggregationTemporality::Cumulative as i32,
+                    is_monotonic: true,
+                }),
+                MetricType::GAUGE => MetricData::Gauge(GaugeData {
+                    data_points: number_points(mf, |m| m.get_gauge().get_value()),
+                }),
+                MetricType::UNTYPED => MetricData::Gauge(GaugeData {
+                    data_points: number_points(mf, |m| m.get_untyped().get_value()),
+                }),
+                MetricType::HISTOGRAM => MetricData::Histogram(HistogramData {
+                    data_points: mf.get_metric().iter().map(histogram_point).collect(),
+                    aggregation_temporality: AggregationTemporality::Cumulative as i32,
+                }),
+                MetricType::SUMMARY => MetricData::Summary(SummaryData {
+                    data_points: mf.get_metric().iter().map(summary_point).collect(),
+                }),
+            };
+            Metric {
+                name: mf.get_name().to_string(),
+                description: mf.get_help().to_string(),
+                unit: String::new(),
+                data: Some(data),
+            }
+        })
+        .collect();
+
+    ExportMetricsServiceRequest {
+        resource_metrics: vec![ResourceMetrics {
+            resource: None,
+            scope_metrics: vec![ScopeMetrics { metrics }],
+        }],
+    }
+}
+
+fn number_points(mf: &MetricFamily, value: impl Fn(&PromMetric) -> f64) -> Vec<NumberDataPoint> {
+    mf.get_metric()
+        .iter()
+        .map(|m| NumberDataPoint {
+            attributes: attributes(m),
+            time_unix_nano: time_unix_nano(m),
+            value: Some(NumberValue::AsDouble(value(m))),
+        })
+        .collect()
+}
+
+fn histogram_point(m: &PromMetric) -> HistogramDataPoint {
+    let h = m.get_histogram();
+    let mut explicit_bounds = Vec::new();
+    let mut bucket_counts = Vec::new();
+    let mut below = 0;
+    for b in h.get_bucket() {
+        if b.get_upper_bound().is_infinite() {
+            continue;
+        }
+        explicit_bounds.push(b.get_upper_bound());
+        bucket_counts.push(b.get_cumulative_count().saturating_sub(below));
+        below = b.get_cumulative_count();
+    }
+    // The implicit `+Inf` bucket.
+    bucket_counts.push(h.get_sample_count().saturating_sub(below));
+
+    HistogramDataPoint {
+        attributes: attributes(m),
+        time_unix_nano: time_unix_nano(m),
+        count: h.get_sample_count(),
+        sum: Some(h.get_sample_sum()),
+        bucket_counts,
+        explicit_bounds,
+    }
+}
+
+fn summary_point(m: &PromMetric) -> SummaryDataPoint {
+    let s = m.get_summary();
+    SummaryDataPoint {
+        attributes: attributes(m),
+        time_unix_nano: time_unix_nano(m),
+        count: s.get_sample_count(),
+        sum: s.get_sample_sum(),
+        quantile_values: s
+            .get_quantile()
+            .iter()
+            .map(|q| ValueAtQuantile {
+                quantile: q.get_quantile(),
+                value: q.get_value(),
+            })
+            .collect(),
+    }
+}
+
+fn attributes(m: &PromMetric) -> Vec<KeyValue> {
+    m.get_label()
+        .iter()
+        .map(|l| KeyValue {
+            key: l.get_name().to_string(),
+            value: Some(AnyValue {
+                value: Some(AnyValueKind::StringValue(l.get_value().to_string())),
+            }),
+        })
+        .collect()
+}
+
+fn time_unix_nano(m: &PromMetric) -> u64 {
+    if m.has_timestamp_ms() {
+        (m.get_timestamp_ms().max(0) as u64).saturating_mul(1_000_000)
+    } else {
+        0
+    }
+}
+
+#[derive(Debug)]
+enum DeltaState {
+    Sum(f64),
+    Histogram {
+        count: u64,
+        sum: f64,
+        buckets: Vec<u64>,
+    },
+}
+
+/// Converts OTLP metrics into metric families.
+///
+/// Delta sums and histograms are accumulated per series across requests so
+/// they can be exposed as cumulative Prometheus counters and histograms.
+/// Monotonic sums become counters with a `_total` suffix, non-monotonic sums
+/// become gauges. `service.name` and `service.instance.id` resource
+/// attributes become the `job` and `instance` labels.
+#[derive(Debug, Default)]
+pub struct OtlpConverter {
+    deltas: HashMap<String, DeltaState>,
+}
+
+impl OtlpConverter {
+    pub fn new() -> Self {
+        OtlpConverter::default()
+    }
+
+    pub fn convert(&mut self, req: &ExportMetricsServiceRequest) -> Vec<MetricFamily> {
+        let mut mf_by_name: BTreeMap<String, MetricFamily> = BTreeMap::new();
+
+        for rm in &req.resource_metrics {
+            let resource_labels = resource_labels(rm.resource.as_ref());
+
+            for metric in rm.scope_metrics.iter().flat_map(|sm| &sm.metrics) {
+                self.convert_metric(metric, &resource_labels, &mut mf_by_name);
+            }
+        }
+
+        mf_by_name.into_values().collect()
+    }
+
+    fn convert_metric(
+        &mut self,
+        metric: &Metric,
+        resource_labels: &[(String, String)],
+        mf_by_name: &mut BTreeMap<String, MetricFamily>,
+    ) {
+        let base_name = sanitize_metric_name(&metric.name);
+
+        let (name, metric_type) = match &metric.data {
+            Some(MetricData::Gauge(_)) => (base_name, MetricType::GAUGE),
+            Some(MetricData::Sum(sum)) if sum.is_monotonic => {
+                let name = if base_name.ends_with("_total") {
+                    base_name
+                } else {
+                    format!("{}_total", base_name)
+                };
+                (name, MetricType::COUNTER)
+            }
+            Some(MetricData::Sum(_)) => (base_name, MetricType::GAUGE),
+            Some(MetricData::Histogram(_)) => (base_name, MetricType::HISTOGRAM),
+            Some(MetricData::Summary(_)) => (base_name, MetricType::SUMMARY),
+            None => {
+                log::debug!("skipping OTLP metric {} with unsupported data", metric.name);
+                return;
+            }
+        };
+
+        let mf = mf_by_name.entry(name.clone()).or_insert_with(|| {
+            let mut mf = MetricFamily::new();
+            mf.set_name(name.clone());
+            mf.set_field_type(metric_type);
+            if !metric.description.is_empty() {
+                mf.set_help(metric.description.clone());
+            }
+            mf
+        });
+        if mf.get_field_type() != metric_type {
+            log::warn!("OTLP metric {} changes type, skipping", name);
+            return;
+        }
+
+        match &metric.data {
+            Some(MetricData::Gauge(gauge)) => {
+                for dp in &gauge.data_points {
+                    let mut m = new_metric(resource_labels, &dp.attributes, dp.time_unix_nano);
+                    let mut g = Gauge::new();
+                    g.set_value(number_value(dp));
+                    m.set_gauge(g);
+                    mf.mut_metric().push(m);
+                }
+            }
+            Some(MetricData::Sum(sum)) => {
+                let delta = sum.aggregation_temporality == AggregationTemporality::Delta as i32;
+                for dp in &sum.data_points {
+                    let mut m = new_metric(resource_labels, &dp.attributes, dp.time_unix_nano);
+                    let mut value = number_value(dp);
+                    if delta {
+                        value = self.accumulate_sum(&name, &m, value);
+                    }
+
+                    if sum.is_monotonic {
+                        let mut c = Counter::new();
+                        c.set_value(value);
+                        m.set_counter(c);
+                    } else {
+                        let mut g = Gauge::new();
+                        g.set_value(value);
+                        m.set_gauge(g);
+                    }
+                    mf.mut_metric().push(m);
+                }
+            }
+            Some(MetricData::Histogram(histogram)) => {
+                let delta =
+                    histogram.aggregation_temporality == AggregationTemporality::Delta as i32;
+                for dp in &histogram.data_points {
+                    let mut m = new_metric(resource_labels, &dp.attributes, dp.time_unix_nano);
+
+                    let mut count = dp.count;
+                    let mut sum = dp.sum.unwrap_or(0.0);
+                    let mut bucket_counts = dp.bucket_counts.clone();
+                    if delta {
+                        (count, sum, bucket_counts) =
+                            self.accumulate_histogram(&name, &m, count, sum, &bucket_counts);
+                    }
+
+                    let h = m.mut_histogram();
+                    h.set_sample_count(count);
+                    h.set_sample_sum(sum);
+                    let mut cumulative: u64 = 0;
+                    for (bound, bucket_count) in dp.explicit_bounds.iter().zip(&bucket_counts) {
+                        cumulative = cumulative.saturating_add(*bucket_count);
+                        let mut b = Bucket::new();
+                        b.set_upper_bound(*bound);
+                        b.set_cumulative_count(cumulative);
+                        h.mut_bucket().push(b);
+                    }
+                    mf.mut_metric().push(m);
+                }
+            }
+            Some(MetricData::Summary(summary)) => {
+                for dp in &summary.data_points {
+                    let mut m = new_metric(resource_labels, &dp.attributes, dp.time_unix_nano);
+                    let s = m.mut_summary();
+                    s.set_sample_count(dp.count);
+                    s.set_sample_sum(dp.sum);
+                    for qv in &dp.quantile_values {
+                        let mut q = Quantile::new();
+                        q.set_quantile(qv.quantile);
+                        q.set_value(qv.value);
+                        s.mut_quantile().push(q);
+                    }
+                    mf.mut_metric().push(m);
+                }
+            }
+            None => {}
+        }
+    }
+
+    fn accumulate_sum(&mut self, name: &str, m: &PromMetric, value: f64) -> f64 {
+        let state = self
+            .deltas
+            .entry(series_key(name, m))
+            .or_insert(DeltaState::Sum(0.0));
+        match state {
+            DeltaState::Sum(total) => {
+                *total += value;
+                *total
+            }
+            _ => value,
+        }
+    }
+
+    fn accumulate_histogram(
+        &mut self,
+        name: &str,
+        m: &PromMetric,
+        count: u64,
+        sum: f64,
+        bucket_counts: &[u64],
+    ) -> (u64, f64, Vec<u64>) {
+        let state =
+            self.deltas
+                .entry(series_key(name, m))
+                .or_insert_with(|| DeltaState::Histogram {
+                    count: 0,
+                    sum: 0.0,
+                    buckets: vec![0; bucket_counts.len()],
+                });
+
+        match state {
+            // A changed bucket layout cannot be added up; start over.
+            DeltaState::Histogram {
+                count: total_count,
+                sum: total_sum,
+                buckets,
+            } if buckets.len() == bucket_counts.len() => {
+                // Counts off the wire may be hostile, or wrap around.
+                *total_count = total_count.saturating_add(count);
+                *total_sum += sum;
+                for (total, delta) in buckets.iter_mut().zip(bucket_counts) {
+                    *total = total.saturating_add(*delta);
+                }
+                (*total_count, *total_sum, buckets.clone())
+            }
+            _ => {
+                *state = DeltaState::Histogram {
+                    count,
+                    sum,
+                    buckets: bucket_counts.to_vec(),
+                };
+                (count, sum, bucket_counts.to_vec())
+            }
+        }
+    }
+}
+
+fn resource_labels(resource: Option<&Resource>) -> Vec<(String, String)> {
+    let mut labels = Vec::new();
+    for kv in resource
+        .map(|r| r.attributes.as_slice())
+        .unwrap_or_default()
+    {
+        let name = match kv.key.as_str() {
+            "service.name" => "job",
+            "service.instance.id" => "instance",
+            _ => continue,
+        };
+        let value = kv.value.as_ref().map(AnyValue::to_label_value);
+        labels.push((name.to_string(), value.unwrap_or_default()));
+    }
+    labels
+}
+
+fn new_metric(
+    resource_labels: &[(String, String)],
+    attributes: &[KeyValue],
+    time_unix_nano: u64,
+) -> PromMetric {
+    let mut labels: Vec<(String, String)> = attributes
+        .iter()
+        .map(|kv| {
+            let value = kv.value.as_ref().map(AnyValue::to_label_value);
+            (sanitize_label_name(&kv.key), value.unwrap_or_default())
+        })
+        .collect();
+    for (name, value) in resource_labels {
+        if !labels.iter().any(|(n, _)| n == name) {
+            labels.push((name.clone(), value.clone()));
+        }
+    }
+    labels.sort();
+
+    let mut m = PromMetric::new();
+    for (name, value) in labels {
+        let mut label = LabelPair::new();
+        label.set_name(name);
+        label.set_value(value);
+        m.mut_label().push(label);
+    }
+    if time_unix_nano > 0 {
+        m.set_timestamp_ms((time_unix_nano / 1_000_000) as i64);
+    }
+    m
+}
+
+fn number_value(dp: &NumberDataPoint) -> f64 {
+    match dp.value {
+        Some(NumberValue::AsDouble(v)) => v,
+        Some(NumberValue::AsInt(v)) => v as f64,
+        None => 0.0,
+    }
+}
+
+fn series_key(name: &str, m: &PromMetric) -> String {
+    let labels = m.get_label().iter().map(|l| (l.get_name(), l.get_value()));
+    labels_to_signature(iter::once(("__name__", name)).chain(labels))
+}
+
+/// Replaces every character not valid in a metric name with `_`.
+fn sanitize_metric_name(name: &str) -> String {
+    sanitize(
+        name,
+        is_valid_metric_name_start,
+        is_valid_metric_name_continuation,
+    )
+}
+
+/// Replaces every character not valid in a label name, such as the `.` of
+/// attribute keys or a `:`, with `_`.
+fn sanitize_label_name(name: &str) -> String {
+    sanitize(
+        name,
+        is_valid_label_name_start,
+        is_valid_label_name_continuation,
+    )
+}
+
+fn sanitize(name: &str, start: fn(char) -> bool, continuation: fn(char) -> bool) -> String {
+    let mut out: String = name
+        .chars()
+        .map(|c| if continuation(c) { c } else { '_' })
+        .collect();
+    if out.chars().next().is_some_and(|c| !start(c)) {
+        out.insert(0, '_');
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kv(key: &str, value: &str) -> KeyValue {
+        KeyValue {
+            key: key.to_string(),
+            value: Some(AnyValue {
+                value: Some(AnyValueKind::StringValue(value.to_string())),
+            }),
+        }
+    }
+
+    fn request(metrics: Vec<Metric>) -> ExportMetricsServiceRequest {
+        ExportMetricsServiceRequest {
+            resource_metrics: vec![ResourceMetrics {
+                resource: Some(Resource {
+                    attributes: vec![kv("service.name", "checkout"), kv("host.arch", "arm64")],
+                }),
+                scope_metrics: vec![ScopeMetrics { metrics }],
+            }],
+        }
+    }
+
+    fn delta_sum(value: i64) -> Metric {
+        Metric {
+            name: "http.server.requests".to_string(),
+            description: "Requests.".to_string(),
+            unit: String::new(),
+            data: Some(MetricData::Sum(SumData {
+                data_points: vec![NumberDataPoint {
+                    attributes: vec![kv("http.method", "GET"), kv("net:peer", "db")],
+                    time_unix_nano: 1_700_000_000_000_000_000,
+                    value: Some(NumberValue::AsInt(value)),
+                }],
+                aggregation_temporality: AggregationTemporality::Delta as i32,
+                is_monotonic: true,
+            })),
+        }
+    }
+
+    fn delta_histogram(bucket_counts: Vec<u64>) -> Metric {
+        Metric {
+            name: "latency".to_string(),
+            description: String::new(),
+            unit: String::new(),
+            data: Some(MetricData::Histogram(HistogramData {
+                data_points: vec![HistogramDataPoint {
+                    attributes: vec![],
+                    time_unix_nano: 0,
+                    count: bucket_counts.iter().sum(),
+                    sum: Some(1.0),
+                    bucket_counts,
+                    explicit_bounds: vec![0.1, 1.0],
+                }],
+                aggregation_temporality: AggregationTemporality::Delta as i32,
+            })),
+        }
+    }
+
+    #[test]
+    fn test_delta_sum_becomes_cumulative_counter() {
+        let mut converter = OtlpConverter::new();
+
+        let req = request(vec![delta_sum(3)]);
+        let decoded = decode_export_request(&req.encode_to_vec()).unwrap();
+        assert_eq!(decoded, req);
+
+        converter.convert(&decoded);
+        let mfs = converter.convert(&request(vec![delta_sum(4)]));
+
+        assert_eq!(mfs.len(), 1);
+        let mf = &mfs[0];
+        assert_eq!(mf.get_name(), "http_server_requests_total");
+        assert_eq!(mf.get_field_type(), MetricType::COUNTER);
+        assert_eq!(mf.get_help(), "Requests.");
+
+        let m = &mf.get_metric()[0];
+        assert_eq!(m.get_counter().get_value(), 7.0);
+        assert_eq!(m.get_timestamp_ms(), 1_700_000_000_000);
+        let labels: Vec<_> = m
+            .get_label()
+            .iter()
+            .map(|l| (l.get_name(), l.get_value()))
+            .collect();
+        assert_eq!(
+            labels,
+            vec![
+                ("http_method", "GET"),
+                ("job", "checkout"),
+                ("net_peer", "db")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_delta_histogram_accumulates_buckets() {
+        let mut converter = OtlpConverter::new();
+        converter.convert(&request(vec![delta_histogram(vec![1, 2, 0])]));
+        let mfs = converter.convert(&request(vec![delta_histogram(vec![0, 1, 3])]));
+
+        let h = mfs[0].get_metric()[0].get_histogram();
+        assert_eq!(h.get_sample_count(), 7);
+        assert_eq!(h.get_sample_sum(), 2.0);
+        let counts: Vec<_> = h
+            .get_bucket()
+            .iter()
+            .map(|b| b.get_cumulative_count())
+            .collect();
+        assert_eq!(counts, vec![1, 4]);
+
+        // Counts saturate instead of overflowing.
+        let mut converter = OtlpConverter::new();
+        converter.convert(&request(vec![delta_histogram(vec![u64::MAX, 0, 0])]));
+        let mfs = converter.convert(&request(vec![delta_histogram(vec![1, 1, 0])]));
+        let h = mfs[0].get_metric()[0].get_histogram();
+        assert_eq!(h.get_sample_count(), u64::MAX);
+        assert!(h
+            .get_bucket()
+            .iter()
+            .all(|b| b.get_cumulative_count() == u64::MAX));
+    }
+
+    #[test]
+    fn test_export_request() {
+        let text = r#"# HELP requests_total Requests.
+# TYPE requests_total counter
+requests_total{code="200"} 3 1700000000000
+# TYPE latency histogram
+latency_bucket{le="0.1"} 1
+latency_bucket{le="1"} 3
+latency_bucket{le="+Inf"} 4
+latency_sum 2.5
+latency_count 4
+# TYPE rpc summary
+rpc{quantile="0.5"} 0.2
+rpc_sum 1
+rpc_count 5
+"#;
+        let mfs = crate::format::read_metric_families(crate::format::Format::Text, text.as_bytes())
+            .unwrap();
+        let req = metric_families_to_export_request(&mfs);
+        let decoded = decode_export_request(&req.encode_to_vec()).unwrap();
+        assert_eq!(decoded, req);
+
+        let metrics = &req.resource_metrics[0].scope_metrics[0].metrics;
+        match &metrics[0].data {
+            Some(MetricData::Histogram(h)) => {
+                assert_eq!(h.data_points[0].explicit_bounds, vec![0.1, 1.0]);
+                assert_eq!(h.data_points[0].bucket_counts, vec![1, 2, 1]);
+            }
+            data => panic!("{:?}", data),
+        }
+
+        // Converting back gives the families read, but for the `+Inf`
+        // bucket, which is implicit in the sample count.
+        let mut expected = mfs.clone();
+        expected[0].mut_metric()[0]
+            .mut_histogram()
+            .mut_bucket()
+            .pop();
+        assert_eq!(OtlpConverter::new().convert(&req), expected);
+    }
+}
@@ -0,0 +1,345 @@
+//! Sending metric families to an OpenTelemetry collector, converted by
+//! [`metric_families_to_export_request`].
+//!
+//! OTLP/HTTP posts the protobuf request to the collector's `/v1/metrics`.
+//! OTLP/gRPC calls `MetricsService/Export` over cleartext HTTP/2 and needs a
+//! build with the `otlp-grpc` feature.
+//!
+//! Exemplars are not supported: the families pmv reads and converts carry
+//! none, so the data points sent never have any.
+
+use prometheus::proto::MetricFamily;
+use prost::Message;
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+use super::metric_families_to_export_request;
+use crate::retry::Retry;
+
+/// Path of the gRPC method exporting metrics.
+pub const GRPC_EXPORT_PATH: &str =
+    "/opentelemetry.proto.collector.metrics.v1.MetricsService/Export";
+
+// gRPC codes the OTLP spec marks as retriable.
+const RETRIABLE_GRPC_CODES: [u32; 7] = [1, 4, 8, 10, 11, 14, 15];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Transport {
+    /// OTLP/HTTP with protobuf bodies.
+    #[default]
+    Http,
+    /// OTLP/gRPC.
+    Grpc,
+}
+
+impl FromStr for Transport {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "http" | "http/protobuf" => Ok(Transport::Http),
+            "grpc" => Ok(Transport::Grpc),
+            _ => Err(format!(
+                "unknown OTLP transport {:?}, expected http or grpc",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ExportError {
+    Http(Box<ureq::Error>),
+    /// The call failed before the collector answered.
+    Grpc(String),
+    /// The collector answered with a gRPC status other than OK.
+    GrpcStatus(u32, String),
+    /// gRPC export in a build without the `otlp-grpc` feature.
+    GrpcUnsupported,
+}
+
+impl fmt::Display for ExportError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExportError::Http(e) => write!(f, "exporting metrics failed: {}", e),
+            ExportError::Grpc(msg) => write!(f, "exporting metrics failed: {}", msg),
+            ExportError::GrpcStatus(code, msg) => {
+                write!(
+                    f,
+                    "collector refused metrics: gRPC status {}: {}",
+                    code, msg
+                )
+            }
+            ExportError::GrpcUnsupported => {
+                write!(f, "pmv was built without the otlp-grpc feature")
+            }
+        }
+    }
+}
+
+impl Error for ExportError {}
+
+/// Sends metric families to a collector.
+#[derive(Debug, Clone)]
+pub struct Exporter {
+    endpoint: String,
+    transport: Transport,
+    headers: Vec<(String, String)>,
+    timeout: Option<Duration>,
+    retry: Retry,
+}
+
+impl Exporter {
+    /// An exporter to `endpoint`: the URL to post to for HTTP, such as
+    /// `http://localhost:4318/v1/metrics`, or the collector's `http://`
+    /// address for gRPC, such as `http://localhost:4317`.
+    pub fn new(endpoint: impl Into<String>, transport: Transport) -> Self {
+        Exporter {
+            endpoint: endpoint.into(),
+            transport,
+            headers: Vec::new(),
+            timeout: None,
+            retry: Retry::default(),
+        }
+    }
+
+    /// Adds a request header, or gRPC metadata, e.g. for authentication.
+    pub fn header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    /// Limit on every attempt.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Retries of exports that failed to connect, timed out, or were
+    /// refused with a code the OTLP spec marks as retriable.
+    pub fn retry(mut self, retry: Retry) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Sends `mfs` in one export request.
+    pub fn export(&self, mfs: &[MetricFamily]) -> Result<(), ExportError> {
+        let body = metric_families_to_export_request(mfs).encode_to_vec();
+        let transient = |e: &ExportError| match e {
+            ExportError::Http(e) => match **e {
+                ureq::Error::Status(code, _) => matches!(code, 429 | 502 | 503 | 504),
+                ureq::Error::Transport(_) => true,
+            },
+            ExportError::Grpc(_) => true,
+            ExportError::GrpcStatus(code, _) => RETRIABLE_GRPC_CODES.contains(code),
+            ExportError::GrpcUnsupported => false,
+        };
+        self.retry.run(transient, || match self.transport {
+            Transport::Http => self.export_http(&body),
+            Transport::Grpc => self.export_grpc(&body),
+        })
+    }
+
+    fn export_http(&self, body: &[u8]) -> Result<(), ExportError> {
+        let mut request = ureq::post(&self.endpoint).set("Content-Type", "application/x-protobuf");
+        for (name, value) in &self.headers {
+            request = request.set(name, value);
+        }
+        if let Some(timeout) = self.timeout {
+            request = request.timeout(timeout);
+        }
+        request
+            .send_bytes(body)
+            .map_err(|e| ExportError::Http(Box::new(e)))?;
+        Ok(())
+    }
+
+    #[cfg(not(feature = "otlp-grpc"))]
+    fn export_grpc(&self, _body: &[u8]) -> Result<(), ExportError> {
+        Err(ExportError::GrpcUnsupported)
+    }
+
+    #[cfg(feature = "otlp-grpc")]
+    fn export_grpc(&self, body: &[u8]) -> Result<(), ExportError> {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .map_err(|e| ExportError::Grpc(e.to_string()))?;
+        runtime.block_on(async {
+            match self.timeout {
+                Some(timeout) => tokio::time::timeout(timeout, self.call_grpc(body))
+                    .await
+                    .unwrap_or_else(|_| Err(ExportError::Grpc("timed out".to_string()))),
+                None => self.call_grpc(body).await,
+            }
+        })
+    }
+
+    #[cfg(feature = "otlp-grpc")]
+    async fn call_grpc(&self, body: &[u8]) -> Result<(), ExportError> {
+        let err = |e: &dyn fmt::Display| ExportError::Grpc(e.to_string());
+
+        let uri: http::Uri = self.endpoint.parse().map_err(|e| err(&e))?;
+        if uri.scheme_str() != Some("http") {
+            return Err(ExportError::Grpc(format!(
+                "{}: only http:// collectors are supported",
+                self.endpoint
+            )));
+        }
+        let authority = uri
+            .authority()
+            .ok_or_else(|| ExportError::Grpc(format!("{}: no host", self.endpoint)))?;
+        let address = format!(
+            "{}:{}",
+            authority.host(),
+            authority.port_u16().unwrap_or(4317)
+        );
+
+        let tcp = tokio::net::TcpStream::connect(&address)
+            .await
+            .map_err(|e| ExportError::Grpc(format!("{}: {}", address, e)))?;
+        let (client, connection) = h2::client::handshake(tcp).await.map_err(|e| err(&e))?;
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                log::debug!("gRPC connection: {}", e);
+            }
+        });
+        let mut client = client.ready().await.map_err(|e| err(&e))?;
+
+        let mut request = http::Request::post(format!("http://{}{}", authority, GRPC_EXPORT_PATH))
+            .header("content-type", "application/grpc")
+            .header("te", "trailers");
+        for (name, value) in &self.headers {
+            request = request.header(name.to_ascii_lowercase(), value);
+        }
+        let request = request.body(()).map_err(|e| err(&e))?;
+        let (response, mut stream) = client.send_request(request, false).map_err(|e| err(&e))?;
+        // A length-prefixed message, uncompressed.
+        let mut message = Vec::with_capacity(5 + body.len());
+        message.push(0);
+        message.extend_from_slice(&(body.len() as u32).to_be_bytes());
+        message.extend_from_slice(body);
+        stream
+            .send_data(bytes::Bytes::from(message), true)
+            .map_err(|e| err(&e))?;
+
+        let (parts, mut body) = response.await.map_err(|e| err(&e))?.into_parts();
+        if parts.status != http::StatusCode::OK {
+            return Err(ExportError::Grpc(format!("HTTP status {}", parts.status)));
+        }
+        // The ExportMetricsServiceResponse is not interpreted.
+        while let Some(chunk) = body.data().await {
+            let chunk = chunk.map_err(|e| err(&e))?;
+            let _ = body.flow_control().release_capacity(chunk.len());
+        }
+        let trailers = body.trailers().await.map_err(|e| err(&e))?;
+        // A call failing at once may carry its status in the headers.
+        let metadata = trailers.as_ref().unwrap_or(&parts.headers);
+        let field = |name: &str| metadata.get(name).and_then(|v| v.to_str().ok());
+        match field("grpc-status").map(str::parse::<u32>) {
+            Some(Ok(0)) => Ok(()),
+            Some(Ok(code)) => Err(ExportError::GrpcStatus(
+                code,
+                field("grpc-message").unwrap_or_default().to_string(),
+            )),
+            _ => Err(ExportError::Grpc("answer without grpc-status".to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::{read_metric_families, Format};
+    use crate::otlp::{decode_export_request, MetricData};
+
+    #[test]
+    fn test_export_http() {
+        let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/v1/metrics", server.server_addr());
+        let handle = std::thread::spawn(move || {
+            let mut request = server.recv().unwrap();
+            let mut body = Vec::new();
+            request.as_reader().read_to_end(&mut body).unwrap();
+            let auth = request
+                .headers()
+                .iter()
+                .find(|h| h.field.equiv("Authorization"))
+                .map(|h| h.value.to_string());
+            request.respond(tiny_http::Response::empty(200)).unwrap();
+            (decode_export_request(&body).unwrap(), auth)
+        });
+
+        let mfs = read_metric_families(
+            Format::Text,
+            "# TYPE requests_total counter\nrequests_total 7\n".as_bytes(),
+        )
+        .unwrap();
+        Exporter::new(url, Transport::Http)
+            .header("Authorization", "Bearer t")
+            .export(&mfs)
+            .unwrap();
+
+        let (req, auth) = handle.join().unwrap();
+        assert_eq!(auth.as_deref(), Some("Bearer t"));
+        let metric = &req.resource_metrics[0].scope_metrics[0].metrics[0];
+        assert_eq!(metric.name, "requests_total");
+        assert!(matches!(metric.data, Some(MetricData::Sum(_))));
+
+        let refused = Exporter::new("http://127.0.0.1:1/v1/metrics", Transport::Http).export(&mfs);
+        assert!(matches!(refused, Err(ExportError::Http(_))));
+    }
+
+    #[cfg(feature = "otlp-grpc")]
+    #[test]
+    fn test_export_grpc() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap();
+            runtime.block_on(async move {
+                let listener = tokio::net::TcpListener::from_std(listener).unwrap();
+                let (tcp, _) = listener.accept().await.unwrap();
+                let mut connection = h2::server::handshake(tcp).await.unwrap();
+                let (request, mut respond) = connection.accept().await.unwrap().unwrap();
+                // Driven until the client hangs up, so the answer is flushed.
+                let driver =
+                    tokio::spawn(async move { while connection.accept().await.is_some() {} });
+                let path = request.uri().path().to_string();
+                let mut body = request.into_body();
+                let mut message = Vec::new();
+                while let Some(chunk) = body.data().await {
+                    message.extend_from_slice(&chunk.unwrap());
+                }
+                let response = http::Response::builder()
+                    .header("content-type", "application/grpc")
+                    .body(())
+                    .unwrap();
+                let mut stream = respond.send_response(response, false).unwrap();
+                let mut trailers = http::HeaderMap::new();
+                trailers.insert("grpc-status", "0".parse().unwrap());
+                stream.send_trailers(trailers).unwrap();
+                driver.await.unwrap();
+                (path, decode_export_request(&message[5..]).unwrap())
+            })
+        });
+
+        let mfs = read_metric_families(Format::Text, "up 1\n".as_bytes()).unwrap();
+        Exporter::new(endpoint, Transport::Grpc)
+            .export(&mfs)
+            .unwrap();
+
+        let (path, req) = server.join().unwrap();
+        assert_eq!(path, GRPC_EXPORT_PATH);
+        assert_eq!(
+            req.resource_metrics[0].scope_metrics[0].metrics[0].name,
+            "up"
+        );
+    }
+}
@@ -0,0 +1,1046 @@
+use prometheus::proto::{Metric, MetricFamily};
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::error::Error;
+use std::fmt;
+use std::sync::Arc;
+
+use crate::aggregate::{self, AggOp, Grouping};
+use crate::alert::{AlertRule, RawAlertRule};
+use crate::anonymize::{self, Anonymize};
+use crate::flatten::render_series;
+use crate::format::Format;
+use crate::grep::{self, FilterAction, FilterRule, Selector, ValuePredicate};
+use crate::label_limit::LabelLimit;
+use crate::namespace::{self, Namespace};
+use crate::rebucket::{self, Rebucket};
+use crate::redact::{self, Redact};
+use crate::relabel::{self, Action, RelabelRule};
+use crate::sample::{self, Budget, SAMPLED_LABEL};
+use crate::units::{self, Conversion};
+
+/// How many affected series a dry run shows per step.
+pub const DRY_RUN_EXAMPLES: usize = 5;
+
+/// A read-transform-write job, loaded from YAML:
+///
+/// ```yaml
+/// inputs:
+///   - dumps/*.prom
+///   - path: extra.json
+///     format: json
+///     namespace:
+///       prefix: extra_
+/// transforms:
+///   - filter:
+///       match: ['{job="node"}']
+///       drop: ['{mode="idle"}']
+///       where: ['value != 0']
+///   - relabel:
+///       - source_labels: [instance]
+///         regex: '(.*):\d+'
+///         target_label: host
+///       - regex: instance
+///         action: labeldrop
+///   - aggregate:
+///       without: [cpu]
+///   - aggregate:
+///       op: max
+///       by: [job]
+///   - namespace:
+///       strip: legacy_
+///   - convert:
+///       from: milliseconds
+///       to: seconds
+///   - rebucket:
+///       bounds: [0.1, 1, 10]
+///   - budget:
+///       max_series: 1000
+///       families: {node_cpu_seconds_total: 5000}
+///   - anonymize:
+///       labels: [instance, customer_id]
+///       salt: s3cret
+///   - redact:
+///       labels: ['.*token']
+///       values: ['[\w.+-]+@[\w-]+\.[\w.-]+']
+///   - wasm:
+///       path: plugins/drop_debug.wasm
+///   - script:
+///       source: 'if labels.env == "dev" { return false; }'
+/// outputs:
+///   - '-'
+///   - path: node.om
+///     format: openmetrics
+///     label_limit:
+///       max_labels: 30
+///       action: drop_labels
+///       priority: [job, instance]
+/// alerts:
+///   webhook: http://localhost:9000/alerts
+///   rules:
+///     - name: TooManyErrors
+///       match: 'http_requests_total{code="500"}'
+///       when: value > 10
+/// ```
+///
+/// Inputs are merged, the input listed last winning for duplicate series.
+/// Transforms run in order, then the result is written to every output;
+/// `-` is stdin or stdout. Formats default to text. Inputs may rename their
+/// families before the merge, see [`Namespace`], and outputs may cap the
+/// labels of every series, see [`LabelLimit`]. `wasm` stages run modules
+/// compiled to WebAssembly when pmv is built with the `wasm` feature, see
+/// `pmv::wasm`, and `script` stages run rhai scripts, from a `path` or an
+/// inline `source`, with the `script` feature, see `pmv::script`. Alerts
+/// are checked against the transformed series; run once, pmv fails if any
+/// fire, and run as an agent, it posts them to the webhook.
+#[derive(Debug, Clone)]
+pub struct Pipeline {
+    pub inputs: Vec<Endpoint>,
+    pub transforms: Vec<Arc<dyn Transform>>,
+    pub outputs: Vec<Endpoint>,
+    pub alerts: Vec<AlertRule>,
+    pub webhook: Option<String>,
+}
+
+/// An input or output file, or a glob pattern for inputs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Endpoint {
+    pub path: String,
+    pub format: Format,
+    /// Only for inputs.
+    pub namespace: Option<Namespace>,
+    /// Only for outputs.
+    pub label_limit: Option<LabelLimit>,
+}
+
+/// The families a [`Transform`] rewrites.
+pub type Families = Vec<MetricFamily>;
+
+/// Why a [`Transform`] failed.
+pub type TransformError = Box<dyn Error + Send + Sync>;
+
+/// A pipeline stage. The stages pmv ships with are [`Builtin`]s, loaded
+/// from YAML; other crates can implement this trait and add their own
+/// stages to [`Pipeline::transforms`] or the transforms of a server or
+/// exporter.
+pub trait Transform: fmt::Debug + Send + Sync {
+    /// What the stage is, as shown in dry runs, e.g. `filter`.
+    fn name(&self) -> String;
+
+    /// Rewrites `families`. On error the pipeline run stops, and whatever
+    /// `families` holds is discarded.
+    fn apply(&self, families: &mut Families) -> Result<(), TransformError>;
+
+    /// Applies the stage as numbered `step`, reporting what it did. By
+    /// default every series counts as matched and the series that were
+    /// dropped or added as affected.
+    fn dry_run(
+        &self,
+        step: usize,
+        families: &mut Families,
+    ) -> Result<Vec<StepReport>, TransformError> {
+        let before = families.clone();
+        self.apply(families)?;
+        let mut report = StepReport::new(format!("{}. {}", step, self.name()), &before, families);
+        report.matched = report.series_in;
+        let old: HashSet<String> = series(&before).collect();
+        let new: HashSet<String> = series(families).collect();
+        for s in series(&before).filter(|s| !new.contains(s)) {
+            report.example(format!("{} dropped", s));
+        }
+        for s in series(families).filter(|s| !old.contains(s)) {
+            report.example(format!("{} added", s));
+        }
+        Ok(vec![report])
+    }
+}
+
+/// A transform configured under `transforms:` in a pipeline file.
+#[derive(Debug, Clone)]
+pub enum Builtin {
+    /// Keeps series as [`grep::filter`] does and that satisfy every value
+    /// predicate.
+    Filter {
+        rules: Vec<FilterRule>,
+        predicates: Vec<ValuePredicate>,
+    },
+    Relabel(Vec<RelabelRule>),
+    /// Combines series within each family; see [`aggregate::aggregate`].
+    Aggregate {
+        op: AggOp,
+        grouping: Grouping,
+    },
+    /// Renames families.
+    Namespace(Namespace),
+    /// Converts families to another unit.
+    Convert(Conversion),
+    /// Merges histogram buckets into coarser ones.
+    Rebucket(Rebucket),
+    /// Samples families with more series than their budget.
+    Budget(Budget),
+    /// Replaces label values with salted hashes.
+    Anonymize(Anonymize),
+    /// Replaces label values matching patterns.
+    Redact(Redact),
+}
+
+impl Builtin {
+    fn transformed(&self, mfs: Vec<MetricFamily>) -> Vec<MetricFamily> {
+        match self {
+            Builtin::Filter { rules, predicates } => {
+                let mut mfs = mfs;
+                if !rules.is_empty() {
+                    mfs = grep::filter(&mfs, rules);
+                }
+                if !predicates.is_empty() {
+                    mfs = grep::filter_values(&mfs, predicates);
+                }
+                mfs
+            }
+            Builtin::Relabel(rules) => relabel::relabel(&mfs, rules),
+            Builtin::Aggregate { op, grouping } => aggregate::aggregate(&mfs, *op, grouping),
+            Builtin::Namespace(ns) => namespace::namespace(&mfs, ns),
+            Builtin::Convert(conversion) => units::convert(&mfs, conversion),
+            Builtin::Rebucket(rb) => rebucket::rebucket(&mfs, rb),
+            Builtin::Budget(budget) => sample::budget(&mfs, budget),
+            Builtin::Anonymize(config) => anonymize::anonymize(&mfs, config),
+            Builtin::Redact(config) => redact::redact(&mfs, config),
+        }
+    }
+}
+
+impl Transform for Builtin {
+    fn name(&self) -> String {
+        match self {
+            Builtin::Filter { .. } => "filter",
+            Builtin::Relabel(_) => "relabel",
+            Builtin::Aggregate { .. } => "aggregate",
+            Builtin::Namespace(_) => "namespace",
+            Builtin::Convert(_) => "convert",
+            Builtin::Rebucket(_) => "rebucket",
+            Builtin::Budget(_) => "budget",
+            Builtin::Anonymize(_) => "anonymize",
+            Builtin::Redact(_) => "redact",
+        }
+        .to_string()
+    }
+
+    fn apply(&self, families: &mut Families) -> Result<(), TransformError> {
+        *families = self.transformed(std::mem::take(families));
+        Ok(())
+    }
+
+    /// Reports filters and sampling by the series they drop, relabeling
+    /// rule by rule, and renames and aggregations by the series they
+    /// change.
+    fn dry_run(
+        &self,
+        step: usize,
+        families: &mut Families,
+    ) -> Result<Vec<StepReport>, TransformError> {
+        let mut mfs = std::mem::take(families);
+        let mut reports = Vec::new();
+        match self {
+            Builtin::Filter { .. } => {
+                let out = self.transformed(mfs.clone());
+                let kept: HashSet<String> = series(&out).collect();
+                let mut report = StepReport::new(format!("{}. filter", step), &mfs, &out);
+                for s in series(&mfs) {
+                    if kept.contains(&s) {
+                        report.matched += 1;
+                    } else {
+                        report.example(format!("{} dropped", s));
+                    }
+                }
+                reports.push(report);
+                mfs = out;
+            }
+            Builtin::Relabel(rules) => {
+                for (j, rule) in rules.iter().enumerate() {
+                    let rules = std::slice::from_ref(rule);
+                    let out = relabel::relabel(&mfs, rules);
+                    let name = format!("{}.{}. relabel {}", step, j + 1, describe(rule));
+                    let mut report = StepReport::new(name, &mfs, &out);
+                    for mf in &mfs {
+                        for m in mf.get_metric() {
+                            if rule.matches(mf.get_name(), m) {
+                                report.matched += 1;
+                            }
+                            let before = series_name(mf.get_name(), m);
+                            match relabel::relabel_series(mf.get_name(), m, rules) {
+                                None => report.example(format!("{} dropped", before)),
+                                Some(m) => {
+                                    let after = series_name(mf.get_name(), &m);
+                                    if after != before {
+                                        report.example(format!("{} -> {}", before, after));
+                                    }
+                                }
+                            }
+                        }
+                    }
+                    reports.push(report);
+                    mfs = out;
+                }
+            }
+            Builtin::Aggregate { op, grouping } => {
+                let out = self.transformed(mfs.clone());
+                let (how, labels) = match grouping {
+                    Grouping::By(labels) => ("by", labels),
+                    Grouping::Without(labels) => ("without", labels),
+                };
+                let name = format!("{}. {} {} ({})", step, op.as_str(), how, labels.join(", "));
+                let mut report = StepReport::new(name, &mfs, &out);
+                for mf in &mfs {
+                    for m in mf.get_metric() {
+                        report.matched += 1;
+                        let mut grouped = m.clone();
+                        let kept = m
+                            .get_label()
+                            .iter()
+                            .filter(|l| grouping.keeps(l.get_name()))
+                            .cloned()
+                            .collect();
+                        grouped.set_label(kept);
+                        let (before, after) = (
+                            series_name(mf.get_name(), m),
+                            series_name(mf.get_name(), &grouped),
+                        );
+                        if after != before {
+                            report.example(format!("{} -> {}", before, after));
+                        }
+                    }
+                }
+                reports.push(report);
+                mfs = out;
+            }
+            Builtin::Namespace(ns) => {
+                let out = self.transformed(mfs.clone());
+                let name = format!("{}. namespace", step);
+                let mut report = StepReport::new(name, &mfs, &out);
+                for mf in &mfs {
+                    let renamed = ns.rename(mf.get_name());
+                    if renamed == mf.get_name() {
+                        continue;
+                    }
+                    for m in mf.get_metric() {
+                        report.matched += 1;
+                        report.example(format!(
+                            "{} -> {}",
+                            series_name(mf.get_name(), m),
+                            series_name(&renamed, m)
+                        ));
+                    }
+                }
+                reports.push(report);
+                mfs = out;
+            }
+            Builtin::Convert(conversion) => {
+                let out = self.transformed(mfs.clone());
+                let name = format!(
+                    "{}. convert {} to {}",
+                    step,
+                    conversion.from.name(),
+                    conversion.to.name()
+                );
+                let mut report = StepReport::new(name, &mfs, &out);
+                for mf in &mfs {
+                    let Some(renamed) = conversion.rename(mf.get_name(), mf.get_field_type())
+                    else {
+                        continue;
+                    };
+                    for m in mf.get_metric() {
+                        report.matched += 1;
+                        report.example(format!(
+                            "{} -> {}",
+                            series_name(mf.get_name(), m),
+                            series_name(&renamed, m)
+                        ));
+                    }
+                }
+                reports.push(report);
+                mfs = out;
+            }
+            Builtin::Rebucket(rb) => {
+                let out = self.transformed(mfs.clone());
+                let bounds: Vec<String> = rb.bounds.iter().map(|b| b.to_string()).collect();
+                let name = format!("{}. rebucket [{}]", step, bounds.join(", "));
+                let mut report = StepReport::new(name, &mfs, &out);
+                for (before, after) in mfs.iter().zip(&out) {
+                    for (m, rebucketed) in before.get_metric().iter().zip(after.get_metric()) {
+                        if m == rebucketed {
+                            continue;
+                        }
+                        report.matched += 1;
+                        report.example(format!(
+                            "{}: {} -> {} buckets",
+                            series_name(before.get_name(), m),
+                            m.get_histogram().get_bucket().len(),
+                            rebucketed.get_histogram().get_bucket().len()
+                        ));
+                    }
+                }
+                reports.push(report);
+                mfs = out;
+            }
+            Builtin::Budget(budget) => {
+                let out = self.transformed(mfs.clone());
+                let name = format!("{}. budget {} series", step, budget.max_series);
+                let mut report = StepReport::new(name, &mfs, &out);
+                let kept: HashSet<String> = out
+                    .iter()
+                    .flat_map(|mf| {
+                        mf.get_metric()
+                            .iter()
+                            .map(move |m| unsampled_name(mf.get_name(), m))
+                    })
+                    .collect();
+                for mf in &mfs {
+                    if mf.get_metric().len() <= budget.max_series(mf.get_name()) {
+                        continue;
+                    }
+                    for m in mf.get_metric() {
+                        report.matched += 1;
+                        let s = unsampled_name(mf.get_name(), m);
+                        if !kept.contains(&s) {
+                            report.example(format!("{} dropped", s));
+                        }
+                    }
+                }
+                reports.push(report);
+                mfs = out;
+            }
+            Builtin::Anonymize(_) | Builtin::Redact(_) => {
+                let out = self.transformed(mfs.clone());
+                let name = match self {
+                    Builtin::Anonymize(config) => {
+                        format!("{}. anonymize ({})", step, config.labels.join(", "))
+                    }
+                    _ => format!("{}. redact", step),
+                };
+                let mut report = StepReport::new(name, &mfs, &out);
+                for (before, after) in mfs.iter().zip(&out) {
+                    for (m, hashed) in before.get_metric().iter().zip(after.get_metric()) {
+                        if m == hashed {
+                            continue;
+                        }
+                        report.matched += 1;
+                        report.example(format!(
+                            "{} -> {}",
+                            series_name(before.get_name(), m),
+                            series_name(after.get_name(), hashed)
+                        ));
+                    }
+                }
+                reports.push(report);
+                mfs = out;
+            }
+        }
+        *families = mfs;
+        Ok(reports)
+    }
+}
+
+#[derive(Debug)]
+pub enum PipelineError {
+    Yaml(serde_yaml::Error),
+    Invalid(String),
+}
+
+impl fmt::Display for PipelineError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PipelineError::Yaml(e) => write!(f, "invalid pipeline file: {}", e),
+            PipelineError::Invalid(msg) => write!(f, "invalid pipeline: {}", msg),
+        }
+    }
+}
+
+impl Error for PipelineError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            PipelineError::Yaml(e) => Some(e),
+            PipelineError::Invalid(_) => None,
+        }
+    }
+}
+
+// The file as written; selectors, predicates and formats are parsed into
+// `Pipeline` afterwards so their errors name the transform.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawPipeline {
+    inputs: Vec<RawEndpoint>,
+    // `- filter: {...}` rather than serde_yaml's `- !filter {...}`.
+    #[serde(default, with = "serde_yaml::with::singleton_map_recursive")]
+    transforms: Vec<RawTransform>,
+    outputs: Vec<RawEndpoint>,
+    #[serde(default)]
+    alerts: RawAlerts,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawAlerts {
+    #[serde(default)]
+    webhook: Option<String>,
+    #[serde(default)]
+    rules: Vec<RawAlertRule>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawEndpoint {
+    Path(String),
+    Full {
+        path: String,
+        #[serde(default)]
+        format: Option<String>,
+        #[serde(default)]
+        namespace: Option<Namespace>,
+        #[serde(default)]
+        label_limit: Option<LabelLimit>,
+    },
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum RawTransform {
+    Filter {
+        #[serde(default, rename = "match")]
+        selectors: Vec<String>,
+        #[serde(default)]
+        drop: Vec<String>,
+        #[serde(default, rename = "where")]
+        predicates: Vec<String>,
+    },
+    Relabel(Vec<RelabelRule>),
+    Aggregate {
+        #[serde(default)]
+        op: AggOp,
+        #[serde(flatten)]
+        grouping: Grouping,
+    },
+    Namespace(Namespace),
+    Convert(Conversion),
+    Rebucket(Rebucket),
+    Budget(Budget),
+    Anonymize(Anonymize),
+    Redact(Redact),
+    Wasm {
+        path: String,
+    },
+    Script {
+        #[serde(default)]
+        path: Option<String>,
+        #[serde(default)]
+        source: Option<String>,
+    },
+}
+
+impl Pipeline {
+    pub fn from_yaml(s: &str) -> Result<Pipeline, PipelineError> {
+        let raw: RawPipeline = serde_yaml::from_str(s).map_err(PipelineError::Yaml)?;
+        if raw.inputs.is_empty() || raw.outputs.is_empty() {
+            return Err(PipelineError::Invalid(
+                "at least one input and one output are required".to_string(),
+            ));
+        }
+
+        let transforms = transforms(raw.transforms)?;
+        let alerts = raw
+            .alerts
+            .rules
+            .into_iter()
+            .map(AlertRule::try_from)
+            .collect::<Result<_, _>>()
+            .map_err(PipelineError::Invalid)?;
+
+        Ok(Pipeline {
+            inputs: endpoints(raw.inputs, false)?,
+            transforms,
+            outputs: endpoints(raw.outputs, true)?,
+            alerts,
+            webhook: raw.alerts.webhook,
+        })
+    }
+
+    /// Appends a stage after the configured transforms.
+    pub fn with_transform(mut self, transform: impl Transform + 'static) -> Self {
+        self.transforms.push(Arc::new(transform));
+        self
+    }
+
+    /// Runs the transforms in order.
+    pub fn apply(&self, mut mfs: Vec<MetricFamily>) -> Result<Vec<MetricFamily>, TransformError> {
+        apply(&self.transforms, &mut mfs)?;
+        Ok(mfs)
+    }
+
+    /// Runs the transforms in order, reporting what each step did instead
+    /// of returning the result. Relabeling reports every rule on its own.
+    pub fn dry_run(&self, mut mfs: Vec<MetricFamily>) -> Result<Vec<StepReport>, TransformError> {
+        let mut reports = Vec::new();
+        for (i, transform) in self.transforms.iter().enumerate() {
+            reports.extend(transform.dry_run(i + 1, &mut mfs)?);
+        }
+        Ok(reports)
+    }
+}
+
+/// Runs `transforms` in order on `families`.
+pub fn apply(
+    transforms: &[Arc<dyn Transform>],
+    families: &mut Families,
+) -> Result<(), TransformError> {
+    transforms.iter().try_for_each(|t| t.apply(families))
+}
+
+/// Parses a list of transforms, written as under `transforms:` in a
+/// pipeline file, for commands that transform without a whole pipeline.
+pub fn transforms_from_yaml(s: &str) -> Result<Vec<Arc<dyn Transform>>, PipelineError> {
+    let de = serde_yaml::Deserializer::from_str(s);
+    let raw: Vec<RawTransform> =
+        serde_yaml::with::singleton_map_recursive::deserialize(de).map_err(PipelineError::Yaml)?;
+    transforms(raw)
+}
+
+fn transforms(raw: Vec<RawTransform>) -> Result<Vec<Arc<dyn Transform>>, PipelineError> {
+    raw.into_iter()
+        .enumerate()
+        .map(|(i, t)| -> Result<Arc<dyn Transform>, _> {
+            let invalid = |e: String| PipelineError::Invalid(format!("transform {}: {}", i + 1, e));
+            let builtin = match t {
+                RawTransform::Filter {
+                    selectors,
+                    drop,
+                    predicates,
+                } => {
+                    let keep = parse_all::<Selector>(&selectors).map_err(invalid)?;
+                    let drop = parse_all::<Selector>(&drop).map_err(invalid)?;
+                    let rules = keep
+                        .into_iter()
+                        .map(|selector| FilterRule {
+                            action: FilterAction::Keep,
+                            selector,
+                        })
+                        .chain(drop.into_iter().map(|selector| FilterRule {
+                            action: FilterAction::Drop,
+                            selector,
+                        }))
+                        .collect();
+                    Builtin::Filter {
+                        rules,
+                        predicates: parse_all(&predicates).map_err(invalid)?,
+                    }
+                }
+                RawTransform::Relabel(rules) => {
+                    for rule in &rules {
+                        rule.validate().map_err(invalid)?;
+                    }
+                    Builtin::Relabel(rules)
+                }
+                RawTransform::Aggregate { op, grouping } => Builtin::Aggregate { op, grouping },
+                RawTransform::Namespace(ns) => {
+                    ns.validate().map_err(invalid)?;
+                    Builtin::Namespace(ns)
+                }
+                RawTransform::Convert(conversion) => Builtin::Convert(conversion),
+                RawTransform::Rebucket(rb) => {
+                    rb.validate().map_err(invalid)?;
+                    Builtin::Rebucket(rb)
+                }
+                RawTransform::Budget(budget) => Builtin::Budget(budget),
+                RawTransform::Anonymize(config) => {
+                    config.validate().map_err(invalid)?;
+                    Builtin::Anonymize(config)
+                }
+                RawTransform::Redact(config) => {
+                    config.validate().map_err(invalid)?;
+                    Builtin::Redact(config)
+                }
+                RawTransform::Wasm { path } => return wasm(&path).map_err(invalid),
+                RawTransform::Script { path, source } => {
+                    return script(path, source).map_err(invalid)
+                }
+            };
+            Ok(Arc::new(builtin))
+        })
+        .collect()
+}
+
+#[cfg(feature = "wasm")]
+fn wasm(path: &str) -> Result<Arc<dyn Transform>, String> {
+    crate::wasm::WasmTransform::load(path.as_ref())
+        .map(|t| Arc::new(t) as Arc<dyn Transform>)
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(not(feature = "wasm"))]
+fn wasm(_path: &str) -> Result<Arc<dyn Transform>, String> {
+    Err("pmv was built without the wasm feature".to_string())
+}
+
+#[cfg(feature = "script")]
+fn script(path: Option<String>, source: Option<String>) -> Result<Arc<dyn Transform>, String> {
+    let (name, source) = match (path, source) {
+        (Some(path), None) => {
+            let source = std::fs::read_to_string(&path).map_err(|e| format!("{}: {}", path, e))?;
+            (path, source)
+        }
+        (None, Some(source)) => ("inline".to_string(), source),
+        _ => return Err("a script needs either a path or a source".to_string()),
+    };
+    crate::script::Script::new(&name, &source)
+        .map(|t| Arc::new(t) as Arc<dyn Transform>)
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(not(feature = "script"))]
+fn script(_path: Option<String>, _source: Option<String>) -> Result<Arc<dyn Transform>, String> {
+    Err("pmv was built without the script feature".to_string())
+}
+
+/// What one step of [`Pipeline::dry_run`] did.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StepReport {
+    /// The step number and transform, e.g. `2.1. relabel replace [instance] -> host`.
+    pub step: String,
+    pub series_in: usize,
+    pub series_out: usize,
+    /// Series the step's selectors, predicates or regex matched; every
+    /// series for aggregation.
+    pub matched: usize,
+    /// Series the step changed or dropped.
+    pub affected: usize,
+    /// The first [`DRY_RUN_EXAMPLES`] affected series, as `before -> after`
+    /// or `before dropped`.
+    pub examples: Vec<String>,
+}
+
+impl StepReport {
+    fn new(step: String, before: &[MetricFamily], after: &[MetricFamily]) -> Self {
+        StepReport {
+            step,
+            series_in: series(before).count(),
+            series_out: series(after).count(),
+            matched: 0,
+            affected: 0,
+            examples: Vec::new(),
+        }
+    }
+
+    fn example(&mut self, example: String) {
+        self.affected += 1;
+        if self.examples.len() < DRY_RUN_EXAMPLES {
+            self.examples.push(example);
+        }
+    }
+}
+
+impl fmt::Display for StepReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{}: {} of {} series matched, {} affected, {} out",
+            self.step, self.matched, self.series_in, self.affected, self.series_out
+        )?;
+        for example in &self.examples {
+            writeln!(f, "    {}", example)?;
+        }
+        if self.affected > self.examples.len() {
+            writeln!(
+                f,
+                "    ... and {} more",
+                self.affected - self.examples.len()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+fn describe(rule: &RelabelRule) -> String {
+    let action = match rule.action {
+        Action::Replace => "replace",
+        Action::Keep => "keep",
+        Action::Drop => "drop",
+        Action::LabelDrop => "labeldrop",
+        Action::LabelKeep => "labelkeep",
+        Action::HashMod => "hashmod",
+    };
+    let mut s = action.to_string();
+    if !rule.source_labels.is_empty() {
+        s.push_str(&format!(" [{}]", rule.source_labels.join(", ")));
+    }
+    if let Some(target) = &rule.target_label {
+        s.push_str(&format!(" -> {}", target));
+    }
+    s
+}
+
+fn series_name(family: &str, m: &Metric) -> String {
+    let mut labels: Vec<(String, String)> = m
+        .get_label()
+        .iter()
+        .map(|l| (l.get_name().to_string(), l.get_value().to_string()))
+        .collect();
+    labels.sort_unstable();
+    render_series(family, &labels)
+}
+
+/// Like [`series_name`], without the label [`sample::budget`] adds.
+fn unsampled_name(family: &str, m: &Metric) -> String {
+    let mut labels: Vec<(String, String)> = m
+        .get_label()
+        .iter()
+        .filter(|l| l.get_name() != SAMPLED_LABEL)
+        .map(|l| (l.get_name().to_string(), l.get_value().to_string()))
+        .collect();
+    labels.sort_unstable();
+    render_series(family, &labels)
+}
+
+/// Every series of `mfs`, by name.
+fn series(mfs: &[MetricFamily]) -> impl Iterator<Item = String> + '_ {
+    mfs.iter().flat_map(|mf| {
+        mf.get_metric()
+            .iter()
+            .map(move |m| series_name(mf.get_name(), m))
+    })
+}
+
+fn parse_all<T: std::str::FromStr<Err = String>>(values: &[String]) -> Result<Vec<T>, String> {
+    values.iter().map(|v| v.parse()).collect()
+}
+
+fn endpoints(raw: Vec<RawEndpoint>, outputs: bool) -> Result<Vec<Endpoint>, PipelineError> {
+    raw.into_iter()
+        .map(|e| {
+            let (path, format, namespace, label_limit) = match e {
+                RawEndpoint::Path(path) => (path, None, None, None),
+                RawEndpoint::Full {
+                    path,
+                    format,
+                    namespace,
+                    label_limit,
+                } => (path, format, namespace, label_limit),
+            };
+            if namespace.is_some() && outputs {
+                return Err(PipelineError::Invalid(format!(
+                    "{}: namespace only applies to inputs",
+                    path
+                )));
+            }
+            if let Some(ns) = &namespace {
+                ns.validate()
+                    .map_err(|e| PipelineError::Invalid(format!("{}: {}", path, e)))?;
+            }
+            if label_limit.is_some() && !outputs {
+                return Err(PipelineError::Invalid(format!(
+                    "{}: label_limit only applies to outputs",
+                    path
+                )));
+            }
+            let format = match format {
+                Some(f) => f.parse().map_err(PipelineError::Invalid)?,
+                None => Format::Text,
+            };
+            Ok(Endpoint {
+                path,
+                format,
+                namespace,
+                label_limit,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::read_metric_families;
+    use crate::label_limit::LimitAction;
+    use crate::text_encode::metric_families_to_text;
+
+    #[test]
+    fn test_pipeline() {
+        let pipeline = Pipeline::from_yaml(
+            r#"
+inputs:
+  - a.prom
+  - path: b.json
+    format: json
+    namespace:
+      prefix: b_
+transforms:
+  - filter:
+      match: ['{job="node"}']
+      drop: ['{cpu="1"}']
+      where: ['value != 0']
+  - relabel:
+      - source_labels: [instance]
+        regex: '(.*):\d+'
+        target_label: host
+  - aggregate:
+      without: [instance, cpu]
+  - aggregate:
+      op: max
+      by: [host]
+outputs:
+  - '-'
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            pipeline.inputs[1],
+            Endpoint {
+                path: "b.json".to_string(),
+                format: Format::Json,
+                namespace: Some(Namespace {
+                    strip: None,
+                    prefix: Some("b_".to_string()),
+                }),
+                label_limit: None,
+            }
+        );
+        assert_eq!(pipeline.outputs[0].format, Format::Text);
+
+        let mfs = read_metric_families(
+            Format::Text,
+            r#"# TYPE cpu_seconds_total counter
+cpu_seconds_total{job="node",instance="h1:9100",cpu="0"} 2
+cpu_seconds_total{job="node",instance="h1:9100",cpu="1"} 3
+cpu_seconds_total{job="node",instance="h2:9100",cpu="0"} 0
+cpu_seconds_total{job="api",instance="h3:9100",cpu="0"} 9
+"#
+            .as_bytes(),
+        )
+        .unwrap();
+        let mut out = Vec::new();
+        metric_families_to_text(&mut out, &pipeline.apply(mfs).unwrap()).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "# TYPE cpu_seconds_total counter\ncpu_seconds_total{host=\"h1\"} 2\n"
+        );
+
+        let err = Pipeline::from_yaml(
+            "inputs: [a]\noutputs: [b]\ntransforms:\n  - filter:\n      where: ['value ~ 1']\n",
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "invalid pipeline: transform 1: unknown comparison in \"value ~ 1\""
+        );
+        assert!(Pipeline::from_yaml("inputs: [a]\noutputs: []\n").is_err());
+        assert!(Pipeline::from_yaml("inputs: [a]\noutputs: [b]\nextra: 1\n").is_err());
+
+        let limited = "outputs:\n  - path: b\n    label_limit: {max_labels: 30, action: error}\n";
+        let pipeline = Pipeline::from_yaml(&format!("inputs: [a]\n{}", limited)).unwrap();
+        let limit = pipeline.outputs[0].label_limit.as_ref().unwrap();
+        assert_eq!((limit.max_labels, limit.action), (30, LimitAction::Error));
+        let err = Pipeline::from_yaml(&format!(
+            "inputs:\n  - path: a\n    label_limit: {{max_labels: 1}}\n{}",
+            limited
+        ))
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "invalid pipeline: a: label_limit only applies to outputs"
+        );
+
+        let err = Pipeline::from_yaml(
+            "inputs: [a]\noutputs: [b]\nalerts:\n  rules:\n    - {name: Down, match: up, when: value = 0}\n",
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "invalid pipeline: alert Down: unknown comparison in \"value = 0\""
+        );
+    }
+
+    #[test]
+    fn test_dry_run() {
+        let pipeline = Pipeline::from_yaml(
+            r#"
+inputs: [a]
+transforms:
+  - filter:
+      match: ['{job="node"}']
+  - relabel:
+      - source_labels: [instance]
+        regex: '(.*):\d+'
+        target_label: host
+      - regex: instance
+        action: labeldrop
+  - budget:
+      max_series: 1
+outputs: [b]
+"#,
+        )
+        .unwrap();
+        let mfs = read_metric_families(
+            Format::Text,
+            "# TYPE up gauge\nup{job=\"node\",instance=\"h1:9100\"} 1\n\
+             up{job=\"node\",instance=\"h2\"} 1\nup{job=\"api\",instance=\"h3:80\"} 1\n"
+                .as_bytes(),
+        )
+        .unwrap();
+        let reports: Vec<String> = pipeline
+            .dry_run(mfs)
+            .unwrap()
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(
+            reports,
+            vec![
+                "1. filter: 2 of 3 series matched, 1 affected, 2 out\n    \
+                 up{instance=\"h3:80\",job=\"api\"} dropped\n",
+                "2.1. relabel replace [instance] -> host: 1 of 2 series matched, 1 affected, 2 out\n    \
+                 up{instance=\"h1:9100\",job=\"node\"} -> up{host=\"h1\",instance=\"h1:9100\",job=\"node\"}\n",
+                "2.2. relabel labeldrop: 2 of 2 series matched, 2 affected, 2 out\n    \
+                 up{host=\"h1\",instance=\"h1:9100\",job=\"node\"} -> up{host=\"h1\",job=\"node\"}\n    \
+                 up{instance=\"h2\",job=\"node\"} -> up{job=\"node\"}\n",
+                "3. budget 1 series: 2 of 2 series matched, 1 affected, 1 out\n    \
+                 up{host=\"h1\",job=\"node\"} dropped\n",
+            ]
+        );
+    }
+
+    #[derive(Debug)]
+    struct Rename(&'static str);
+
+    impl Transform for Rename {
+        fn name(&self) -> String {
+            format!("rename to {}", self.0)
+        }
+
+        fn apply(&self, families: &mut Families) -> Result<(), TransformError> {
+            for mf in families.iter_mut() {
+                if mf.get_name() == self.0 {
+                    return Err(format!("{} exists", self.0).into());
+                }
+                mf.set_name(self.0.to_string());
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_custom_transform() {
+        let pipeline = Pipeline::from_yaml("inputs: [a]\noutputs: [b]\n")
+            .unwrap()
+            .with_transform(Rename("alive"));
+        let mfs = read_metric_families(Format::Text, "up 1\n".as_bytes()).unwrap();
+        let reports = pipeline.dry_run(mfs.clone()).unwrap();
+        assert_eq!(
+            reports[0].to_string(),
+            "1. rename to alive: 1 of 1 series matched, 2 affected, 1 out\n    \
+             up dropped\n    alive added\n"
+        );
+
+        let renamed = pipeline.apply(mfs).unwrap();
+        assert_eq!(renamed[0].get_name(), "alive");
+        let err = pipeline.apply(renamed).unwrap_err();
+        assert_eq!(err.to_string(), "alive exists");
+    }
+}
@@ -0,0 +1,305 @@
+//! Source plugins: shared libraries that hand pmv exposition bytes over a
+//! small versioned C ABI, for transports pmv does not ship itself. Built
+//! with the `plugins` feature.
+//!
+//! A plugin exports `pmv_source_plugin`, returning a pointer to a static
+//! vtable:
+//!
+//! ```c
+//! #define PMV_SOURCE_ABI_VERSION 1
+//!
+//! struct pmv_source_vtable {
+//!     uint32_t abi_version;
+//!     const char *name;
+//!     void *(*open)(const char *config);
+//!     intptr_t (*read)(void *handle, uint8_t *buf, size_t len);
+//!     const char *(*metadata)(void *handle, const char *key);
+//!     const char *(*last_error)(void *handle);
+//!     void (*close)(void *handle);
+//! };
+//!
+//! const struct pmv_source_vtable *pmv_source_plugin(void);
+//! ```
+//!
+//! The fields are documented on [`SourceVTable`]. New fields are only ever
+//! appended, together with a new ABI version.
+
+use libloading::{Library, Symbol};
+use std::error::Error;
+use std::ffi::{c_char, c_void, CStr, CString};
+use std::fmt;
+use std::io::{self, Read};
+use std::path::Path;
+
+/// The vtable version this build of pmv understands.
+pub const ABI_VERSION: u32 = 1;
+
+/// The function a plugin exports, NUL-terminated for `dlsym`.
+pub const ENTRY_SYMBOL: &[u8] = b"pmv_source_plugin\0";
+
+type EntryFn = unsafe extern "C" fn() -> *const SourceVTable;
+
+/// The functions of a source plugin. All strings are NUL-terminated UTF-8.
+#[repr(C)]
+pub struct SourceVTable {
+    /// Must be [`ABI_VERSION`].
+    pub abi_version: u32,
+    /// Static name of the plugin, for messages.
+    pub name: *const c_char,
+    /// Opens a stream with a plugin-specific configuration string, never
+    /// null. Returns null on failure, with the reason in `last_error(NULL)`.
+    pub open: unsafe extern "C" fn(config: *const c_char) -> *mut c_void,
+    /// Reads up to `len` bytes into `buf`. Returns the number of bytes read,
+    /// 0 at the end of the stream or -1 on error.
+    pub read: unsafe extern "C" fn(handle: *mut c_void, buf: *mut u8, len: usize) -> isize,
+    /// Returns stream metadata such as `content-type`, or null if unknown.
+    /// The string must stay valid until the next call with the same handle.
+    pub metadata: unsafe extern "C" fn(handle: *mut c_void, key: *const c_char) -> *const c_char,
+    /// Describes the last error of `read` on `handle`, or of `open` if
+    /// `handle` is null. May return null.
+    pub last_error: unsafe extern "C" fn(handle: *mut c_void) -> *const c_char,
+    /// Releases the stream; the handle is not used afterwards.
+    pub close: unsafe extern "C" fn(handle: *mut c_void),
+}
+
+#[derive(Debug)]
+pub enum PluginError {
+    Load(libloading::Error),
+    NullVTable,
+    AbiVersion(u32),
+    Config(std::ffi::NulError),
+    Open(String),
+}
+
+impl fmt::Display for PluginError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PluginError::Load(e) => write!(f, "loading plugin failed: {}", e),
+            PluginError::NullVTable => write!(f, "plugin returned no vtable"),
+            PluginError::AbiVersion(v) => write!(
+                f,
+                "plugin uses ABI version {}, pmv supports {}",
+                v, ABI_VERSION
+            ),
+            PluginError::Config(e) => write!(f, "invalid plugin configuration: {}", e),
+            PluginError::Open(msg) => write!(f, "plugin could not open a stream: {}", msg),
+        }
+    }
+}
+
+impl Error for PluginError {}
+
+/// A loaded source plugin.
+pub struct Plugin {
+    vtable: *const SourceVTable,
+    // Keeps the vtable's code mapped, so must outlive every use of it.
+    _library: Option<Library>,
+}
+
+impl Plugin {
+    /// Loads the shared library at `path` and checks its ABI version.
+    /// Loading runs the library's initializers, so plugins are as trusted
+    /// as pmv itself.
+    pub fn load(path: &Path) -> Result<Plugin, PluginError> {
+        // SAFETY: see above; the entry point is called with the signature
+        // the ABI defines.
+        unsafe {
+            let library = Library::new(path).map_err(PluginError::Load)?;
+            let entry: Symbol<EntryFn> = library.get(ENTRY_SYMBOL).map_err(PluginError::Load)?;
+            let vtable = entry();
+            Plugin::from_vtable(vtable, Some(library))
+        }
+    }
+
+    /// # Safety
+    ///
+    /// `vtable` must be null or valid for as long as the plugin is used.
+    unsafe fn from_vtable(
+        vtable: *const SourceVTable,
+        library: Option<Library>,
+    ) -> Result<Plugin, PluginError> {
+        let version = vtable.as_ref().ok_or(PluginError::NullVTable)?.abi_version;
+        if version != ABI_VERSION {
+            return Err(PluginError::AbiVersion(version));
+        }
+        Ok(Plugin {
+            vtable,
+            _library: library,
+        })
+    }
+
+    fn vtable(&self) -> &SourceVTable {
+        // SAFETY: checked non-null in from_vtable, and the library holding
+        // it lives as long as self.
+        unsafe { &*self.vtable }
+    }
+
+    pub fn name(&self) -> String {
+        // SAFETY: the ABI requires a static string or null.
+        unsafe { string_from(self.vtable().name) }.unwrap_or_default()
+    }
+
+    /// Opens a stream with the plugin-specific `config`.
+    pub fn open(&self, config: &str) -> Result<PluginSource<'_>, PluginError> {
+        let config = CString::new(config).map_err(PluginError::Config)?;
+        let vtable = self.vtable();
+        // SAFETY: config is a valid C string for the duration of the call.
+        let handle = unsafe { (vtable.open)(config.as_ptr()) };
+        if handle.is_null() {
+            // SAFETY: a null handle asks for the error of open.
+            let msg = unsafe { string_from((vtable.last_error)(handle)) };
+            return Err(PluginError::Open(
+                msg.unwrap_or_else(|| "unknown error".to_string()),
+            ));
+        }
+        Ok(PluginSource {
+            plugin: self,
+            handle,
+        })
+    }
+}
+
+/// An open plugin stream. Reading it yields the exposition bytes; the
+/// stream is closed on drop.
+pub struct PluginSource<'a> {
+    plugin: &'a Plugin,
+    handle: *mut c_void,
+}
+
+impl PluginSource<'_> {
+    pub fn metadata(&self, key: &str) -> Option<String> {
+        let key = CString::new(key).ok()?;
+        // SAFETY: handle is open and key a valid C string; the result is
+        // copied before the next call.
+        unsafe { string_from((self.plugin.vtable().metadata)(self.handle, key.as_ptr())) }
+    }
+
+    /// The `content-type` metadata, e.g. `text/plain; version=0.0.4`.
+    pub fn content_type(&self) -> Option<String> {
+        self.metadata("content-type")
+    }
+}
+
+impl Read for PluginSource<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let vtable = self.plugin.vtable();
+        // SAFETY: handle is open and buf valid for buf.len() bytes.
+        let n = unsafe { (vtable.read)(self.handle, buf.as_mut_ptr(), buf.len()) };
+        if n < 0 {
+            // SAFETY: handle is open.
+            let msg = unsafe { string_from((vtable.last_error)(self.handle)) };
+            return Err(io::Error::other(format!(
+                "plugin {}: {}",
+                self.plugin.name(),
+                msg.unwrap_or_else(|| "read failed".to_string())
+            )));
+        }
+        // Guard against plugins claiming more than fits.
+        Ok((n as usize).min(buf.len()))
+    }
+}
+
+impl Drop for PluginSource<'_> {
+    fn drop(&mut self) {
+        // SAFETY: handle is open and not used after this.
+        unsafe { (self.plugin.vtable().close)(self.handle) }
+    }
+}
+
+/// Copies a C string owned by the plugin, `None` if it is null.
+///
+/// # Safety
+///
+/// `s` must be null or point to a NUL-terminated string.
+unsafe fn string_from(s: *const c_char) -> Option<String> {
+    if s.is_null() {
+        return None;
+    }
+    Some(CStr::from_ptr(s).to_string_lossy().into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A source that yields its configuration string, in chunks of at most
+    // four bytes.
+    struct Stream {
+        data: Vec<u8>,
+        pos: usize,
+    }
+
+    unsafe extern "C" fn open(config: *const c_char) -> *mut c_void {
+        let data = CStr::from_ptr(config).to_bytes().to_vec();
+        if data.is_empty() {
+            return std::ptr::null_mut();
+        }
+        Box::into_raw(Box::new(Stream { data, pos: 0 })).cast()
+    }
+
+    unsafe extern "C" fn read(handle: *mut c_void, buf: *mut u8, len: usize) -> isize {
+        let stream = &mut *handle.cast::<Stream>();
+        let n = len.min(4).min(stream.data.len() - stream.pos);
+        std::ptr::copy_nonoverlapping(stream.data[stream.pos..].as_ptr(), buf, n);
+        stream.pos += n;
+        n as isize
+    }
+
+    unsafe extern "C" fn metadata(_: *mut c_void, key: *const c_char) -> *const c_char {
+        match CStr::from_ptr(key).to_bytes() {
+            b"content-type" => c"text/plain; version=0.0.4".as_ptr(),
+            _ => std::ptr::null(),
+        }
+    }
+
+    unsafe extern "C" fn last_error(_: *mut c_void) -> *const c_char {
+        c"empty configuration".as_ptr()
+    }
+
+    unsafe extern "C" fn close(handle: *mut c_void) {
+        drop(Box::from_raw(handle.cast::<Stream>()));
+    }
+
+    fn vtable(abi_version: u32) -> SourceVTable {
+        SourceVTable {
+            abi_version,
+            name: c"test".as_ptr(),
+            open,
+            read,
+            metadata,
+            last_error,
+            close,
+        }
+    }
+
+    #[test]
+    fn test_plugin_source() {
+        let vtable = vtable(ABI_VERSION);
+        let plugin = unsafe { Plugin::from_vtable(&vtable, None) }.unwrap();
+        assert_eq!(plugin.name(), "test");
+
+        let mut source = plugin.open("up 1\nother 2\n").unwrap();
+        assert_eq!(
+            source.content_type().as_deref(),
+            Some("text/plain; version=0.0.4")
+        );
+        assert_eq!(source.metadata("encoding"), None);
+        let mut text = String::new();
+        source.read_to_string(&mut text).unwrap();
+        assert_eq!(text, "up 1\nother 2\n");
+
+        let err = plugin.open("").err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "plugin could not open a stream: empty configuration"
+        );
+
+        let vtable = self::vtable(ABI_VERSION + 1);
+        let err = unsafe { Plugin::from_vtable(&vtable, None) }.err().unwrap();
+        assert!(matches!(err, PluginError::AbiVersion(2)));
+        let err = unsafe { Plugin::from_vtable(std::ptr::null(), None) }
+            .err()
+            .unwrap();
+        assert!(matches!(err, PluginError::NullVTable));
+    }
+}
@@ -0,0 +1,51 @@
+use prometheus::proto::MetricFamily;
+use protobuf::{CodedInputStream, Message, ProtobufResult};
+use std::io::{Read, Write};
+
+/// The delimited protobuf content type Prometheus negotiates for scrapes.
+pub const PROTOBUF_FORMAT: &str =
+    "application/vnd.google.protobuf; proto=io.prometheus.client.MetricFamily; encoding=delimited";
+
+/// Reads varint length-delimited `MetricFamily` messages until end of input.
+pub fn read_metric_families(reader: &mut dyn Read) -> ProtobufResult<Vec<MetricFamily>> {
+    let mut is = CodedInputStream::new(reader);
+
+    let mut mfs = Vec::new();
+    while !is.eof()? {
+        mfs.push(is.read_message::<MetricFamily>()?);
+    }
+    Ok(mfs)
+}
+
+/// Writes each family as a varint length-delimited `MetricFamily` message.
+pub fn write_metric_families(writer: &mut dyn Write, mfs: &[MetricFamily]) -> ProtobufResult<()> {
+    for mf in mfs {
+        mf.write_length_delimited_to_writer(writer)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::text_parse::TextParser;
+    use std::io::{BufReader, Cursor};
+
+    #[test]
+    fn test_delimited_round_trip() {
+        let cursor = Cursor::new(b"# TYPE a counter\na{x=\"1\"} 2\nb 3 1000\n".to_vec());
+        let mut mfs: Vec<_> = TextParser::new(BufReader::new(cursor))
+            .text_to_metric_families()
+            .unwrap()
+            .into_values()
+            .collect();
+        mfs.sort_by(|a, b| a.get_name().cmp(b.get_name()));
+
+        let mut buf = Vec::new();
+        write_metric_families(&mut buf, &mfs).unwrap();
+        let decoded = read_metric_families(&mut buf.as_slice()).unwrap();
+        assert_eq!(decoded, mfs);
+
+        assert!(read_metric_families(&mut &buf[..buf.len() - 1]).is_err());
+    }
+}
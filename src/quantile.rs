@@ -0,0 +1,96 @@
+use prometheus::proto::Histogram;
+
+/// Estimates the `q`-quantile of the observations of `h` as PromQL's
+/// `histogram_quantile` does: the bucket holding the quantile is found by
+/// its cumulative count, and the quantile placed within it by linear
+/// interpolation, from 0 for the first bucket if its bound is positive.
+///
+/// Quantiles falling in the `+Inf` bucket are its lower bound; `h`'s count
+/// stands for that bucket if it has none. The result is `NaN` without
+/// observations or finite buckets, `-Inf` for `q < 0` and `+Inf` for
+/// `q > 1`. Counts that decrease from one bucket to the next, as when
+/// buckets were scraped apart, are taken as the largest count below them.
+pub fn histogram_quantile(q: f64, h: &Histogram) -> f64 {
+    if q.is_nan() {
+        return f64::NAN;
+    }
+    if q < 0.0 {
+        return f64::NEG_INFINITY;
+    }
+    if q > 1.0 {
+        return f64::INFINITY;
+    }
+
+    let mut buckets: Vec<(f64, f64)> = h
+        .get_bucket()
+        .iter()
+        .map(|b| (b.get_upper_bound(), b.get_cumulative_count() as f64))
+        .collect();
+    buckets.sort_by(|a, b| a.0.total_cmp(&b.0));
+    let mut max = 0.0;
+    for b in &mut buckets {
+        max = f64::max(max, b.1);
+        b.1 = max;
+    }
+    if buckets.last().is_none_or(|b| b.0 != f64::INFINITY) {
+        buckets.push((f64::INFINITY, f64::max(max, h.get_sample_count() as f64)));
+    }
+    if buckets.len() < 2 {
+        return f64::NAN;
+    }
+
+    let observations = buckets[buckets.len() - 1].1;
+    if observations == 0.0 {
+        return f64::NAN;
+    }
+    let mut rank = q * observations;
+    let b = buckets.partition_point(|&(_, count)| count < rank);
+
+    if b == buckets.len() - 1 {
+        return buckets[b - 1].0;
+    }
+    if b == 0 && buckets[0].0 <= 0.0 {
+        return buckets[0].0;
+    }
+    let (mut start, end, mut count) = (0.0, buckets[b].0, buckets[b].1);
+    if b > 0 {
+        start = buckets[b - 1].0;
+        count -= buckets[b - 1].1;
+        rank -= buckets[b - 1].1;
+    }
+    start + (end - start) * (rank / count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::{read_metric_families, Format};
+
+    #[test]
+    fn test_histogram_quantile() {
+        let text = r#"# TYPE latency histogram
+latency_bucket{le="0.1"} 50
+latency_bucket{le="0.5"} 90
+latency_bucket{le="1"} 100
+latency_bucket{le="+Inf"} 100
+latency_sum 20
+latency_count 100
+"#;
+        let mfs = read_metric_families(Format::Text, text.as_bytes()).unwrap();
+        let h = mfs[0].get_metric()[0].get_histogram();
+        assert_eq!(histogram_quantile(0.5, h), 0.1);
+        assert!((histogram_quantile(0.7, h) - 0.3).abs() < 1e-12);
+        assert_eq!(histogram_quantile(0.95, h), 0.75);
+        assert_eq!(histogram_quantile(0.25, h), 0.05);
+        assert_eq!(histogram_quantile(1.0, h), 1.0);
+        assert_eq!(histogram_quantile(2.0, h), f64::INFINITY);
+        assert!(histogram_quantile(f64::NAN, h).is_nan());
+
+        // Observations beyond the last finite bucket.
+        let text = "# TYPE h histogram\nh_bucket{le=\"1\"} 1\nh_bucket{le=\"+Inf\"} 4\nh_count 4\n";
+        let mfs = read_metric_families(Format::Text, text.as_bytes()).unwrap();
+        let h = mfs[0].get_metric()[0].get_histogram();
+        assert_eq!(histogram_quantile(0.99, h), 1.0);
+        assert!(histogram_quantile(0.5, &Histogram::default()).is_nan());
+    }
+}
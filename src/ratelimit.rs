@@ -0,0 +1,110 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+/// Clients tracked before buckets that have refilled completely are dropped.
+const MAX_TRACKED_CLIENTS: usize = 4096;
+
+/// Token bucket parameters applied to every client address.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    /// Tokens added per second.
+    pub per_second: f64,
+    /// Bucket size, i.e. how many requests may arrive back to back.
+    pub burst: f64,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Per-client token buckets.
+#[derive(Debug)]
+pub struct RateLimiter {
+    limit: RateLimit,
+    buckets: HashMap<IpAddr, Bucket>,
+}
+
+impl RateLimiter {
+    pub fn new(limit: RateLimit) -> Self {
+        RateLimiter {
+            limit,
+            buckets: HashMap::new(),
+        }
+    }
+
+    /// Takes a token for `client` at time `now`. On refusal, returns how long
+    /// until the next token is available, or [`Duration::MAX`] if none ever
+    /// will be because `per_second` is not positive.
+    pub fn check(&mut self, client: IpAddr, now: Instant) -> Result<(), Duration> {
+        if self.buckets.len() >= MAX_TRACKED_CLIENTS && !self.buckets.contains_key(&client) {
+            self.prune(now);
+        }
+
+        let limit = self.limit;
+        let bucket = self.buckets.entry(client).or_insert(Bucket {
+            tokens: limit.burst,
+            updated: now,
+        });
+
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * limit.per_second).min(limit.burst);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            let missing = 1.0 - bucket.tokens;
+            Err(Duration::try_from_secs_f64(missing / limit.per_second).unwrap_or(Duration::MAX))
+        }
+    }
+
+    /// Forgets clients whose buckets would be full by now; they are
+    /// indistinguishable from new clients.
+    fn prune(&mut self, now: Instant) {
+        let limit = self.limit;
+        self.buckets.retain(|_, b| {
+            let elapsed = now.saturating_duration_since(b.updated).as_secs_f64();
+            b.tokens + elapsed * limit.per_second < limit.burst
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_bucket_per_client() {
+        let mut limiter = RateLimiter::new(RateLimit {
+            per_second: 2.0,
+            burst: 2.0,
+        });
+        let a: IpAddr = "10.0.0.1".parse().unwrap();
+        let b: IpAddr = "10.0.0.2".parse().unwrap();
+        let start = Instant::now();
+
+        assert!(limiter.check(a, start).is_ok());
+        assert!(limiter.check(a, start).is_ok());
+        assert_eq!(limiter.check(a, start), Err(Duration::from_millis(500)));
+        assert!(limiter.check(b, start).is_ok());
+
+        assert!(limiter.check(a, start + Duration::from_millis(500)).is_ok());
+        assert!(limiter
+            .check(a, start + Duration::from_millis(500))
+            .is_err());
+
+        let mut stopped = RateLimiter::new(RateLimit {
+            per_second: 0.0,
+            burst: 1.0,
+        });
+        assert!(stopped.check(a, start).is_ok());
+        assert_eq!(
+            stopped.check(a, start + Duration::from_secs(60)),
+            Err(Duration::MAX)
+        );
+    }
+}
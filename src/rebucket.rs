@@ -0,0 +1,116 @@
+use prometheus::proto::{Bucket, MetricFamily, MetricType};
+use serde::Deserialize;
+
+use crate::aggregate::count_at;
+
+/// Coarser `le` bounds for histograms, loaded from YAML as
+/// `{bounds: [0.1, 1, 10], families: [http_request_duration_seconds]}`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rebucket {
+    /// Finite upper bounds, in increasing order; `+Inf` is always kept.
+    pub bounds: Vec<f64>,
+    /// Histograms to rebucket; all if empty.
+    #[serde(default)]
+    pub families: Vec<String>,
+}
+
+impl Rebucket {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.bounds.is_empty() {
+            return Err("no bucket bounds".to_string());
+        }
+        if self.bounds.iter().any(|b| !b.is_finite()) {
+            return Err("bucket bounds must be finite".to_string());
+        }
+        if self.bounds.windows(2).any(|w| w[0] >= w[1]) {
+            return Err("bucket bounds must increase".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Replaces the buckets of histograms with ones at `rebucket.bounds`, so
+/// their series are fewer and the same for every source. Each new bucket
+/// counts what the old bucket with the largest bound not above it did, so
+/// counts stay cumulative and never overstate; bounds that fall between old
+/// ones count only up to the old bound below. The `+Inf` bucket, `_sum`
+/// and `_count` are kept.
+pub fn rebucket(mfs: &[MetricFamily], rebucket: &Rebucket) -> Vec<MetricFamily> {
+    mfs.iter()
+        .map(|mf| {
+            let mut mf = mf.clone();
+            if mf.get_field_type() != MetricType::HISTOGRAM
+                || !(rebucket.families.is_empty()
+                    || rebucket.families.iter().any(|f| f == mf.get_name()))
+            {
+                return mf;
+            }
+            for m in mf.mut_metric().iter_mut() {
+                let h = m.mut_histogram();
+                let mut buckets: Vec<Bucket> = rebucket
+                    .bounds
+                    .iter()
+                    .map(|&bound| {
+                        let mut bucket = Bucket::default();
+                        bucket.set_upper_bound(bound);
+                        bucket.set_cumulative_count(count_at(h, bound));
+                        bucket
+                    })
+                    .collect();
+                buckets.extend(
+                    h.get_bucket()
+                        .iter()
+                        .filter(|b| b.get_upper_bound() == f64::INFINITY)
+                        .cloned(),
+                );
+                h.set_bucket(buckets.into());
+            }
+            mf
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::{read_metric_families, Format};
+    use crate::text_encode::metric_families_to_text;
+
+    #[test]
+    fn test_rebucket() {
+        let text = r#"# TYPE latency histogram
+latency_bucket{le="0.05"} 2
+latency_bucket{le="0.1"} 5
+latency_bucket{le="0.25"} 7
+latency_bucket{le="0.5"} 9
+latency_bucket{le="1"} 9
+latency_bucket{le="+Inf"} 10
+latency_sum 4
+latency_count 10
+# TYPE up gauge
+up 1
+"#;
+        let mfs = read_metric_families(Format::Text, text.as_bytes()).unwrap();
+        let rb: Rebucket = serde_yaml::from_str("{bounds: [0.1, 0.3, 1]}").unwrap();
+        rb.validate().unwrap();
+        let mut out = Vec::new();
+        metric_families_to_text(&mut out, &rebucket(&mfs, &rb)).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            r#"# TYPE latency histogram
+latency_bucket{le="0.1"} 5
+latency_bucket{le="0.3"} 7
+latency_bucket{le="1"} 9
+latency_bucket{le="+Inf"} 10
+latency_sum 4
+latency_count 10
+# TYPE up gauge
+up 1
+"#
+        );
+
+        let unsorted: Rebucket = serde_yaml::from_str("{bounds: [1, 0.5]}").unwrap();
+        assert!(unsorted.validate().is_err());
+    }
+}
@@ -0,0 +1,132 @@
+use prometheus::proto::MetricFamily;
+use regex::{NoExpand, Regex};
+use serde::{Deserialize, Deserializer};
+use std::borrow::Cow;
+
+/// What [`redact`] replaces by default.
+pub const REDACTED: &str = "REDACTED";
+
+/// Label values to hide, loaded from YAML as
+/// `{labels: [api_key, '.*token'], values: ['[\w.+-]+@[\w-]+\.[\w.-]+']}`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Redact {
+    /// Regexes over label names, anchored as in relabeling; the whole
+    /// value of matching labels is replaced.
+    #[serde(default, deserialize_with = "anchored_regexes")]
+    pub labels: Vec<Regex>,
+    /// Regexes over label values; every match, in any label, is replaced.
+    #[serde(default, deserialize_with = "regexes")]
+    pub values: Vec<Regex>,
+    #[serde(default = "default_replacement")]
+    pub replacement: String,
+}
+
+fn default_replacement() -> String {
+    REDACTED.to_string()
+}
+
+fn regexes<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<Regex>, D::Error> {
+    Vec::<String>::deserialize(d)?
+        .iter()
+        .map(|s| Regex::new(s).map_err(serde::de::Error::custom))
+        .collect()
+}
+
+fn anchored_regexes<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<Regex>, D::Error> {
+    Vec::<String>::deserialize(d)?
+        .iter()
+        .map(|s| Regex::new(&format!("^(?:{})$", s)).map_err(serde::de::Error::custom))
+        .collect()
+}
+
+impl Redact {
+    /// Redacts matches of `values` with [`REDACTED`].
+    pub fn values(values: Vec<Regex>) -> Self {
+        Redact {
+            labels: Vec::new(),
+            values,
+            replacement: default_replacement(),
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.labels.is_empty() && self.values.is_empty() {
+            return Err("nothing to redact; set labels or values".to_string());
+        }
+        Ok(())
+    }
+
+    /// `value` of label `name`, redacted.
+    pub fn redact_value(&self, name: &str, value: &str) -> String {
+        if self.labels.iter().any(|re| re.is_match(name)) {
+            return self.replacement.clone();
+        }
+        let mut value = value.to_string();
+        for re in &self.values {
+            if let Cow::Owned(replaced) = re.replace_all(&value, NoExpand(&self.replacement)) {
+                value = replaced;
+            }
+        }
+        value
+    }
+}
+
+/// Replaces label values as `redact` says, so that secrets such as emails
+/// or tokens that leaked into labels never reach an output. Empty values
+/// stay empty.
+pub fn redact(mfs: &[MetricFamily], redact: &Redact) -> Vec<MetricFamily> {
+    mfs.iter()
+        .map(|mf| {
+            let mut mf = mf.clone();
+            for m in mf.mut_metric().iter_mut() {
+                for l in m.mut_label().iter_mut() {
+                    if !l.get_value().is_empty() {
+                        let value = redact.redact_value(l.get_name(), l.get_value());
+                        l.set_value(value);
+                    }
+                }
+            }
+            mf
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::{read_metric_families, Format};
+    use crate::text_encode::metric_families_to_text;
+
+    #[test]
+    fn test_redact() {
+        let mfs = read_metric_families(
+            Format::Text,
+            "# TYPE logins_total counter\n\
+             logins_total{user=\"ann@example.com\",session_token=\"abc123\",path=\"/a\"} 3\n\
+             logins_total{user=\"bob (bob@example.org)\",session_token=\"def456\",path=\"/b\"} 1\n"
+                .as_bytes(),
+        )
+        .unwrap();
+        let config: Redact =
+            serde_yaml::from_str("labels: ['.*token']\nvalues: ['[\\w.+-]+@[\\w-]+\\.[\\w.-]+']\n")
+                .unwrap();
+        config.validate().unwrap();
+
+        let mut out = Vec::new();
+        metric_families_to_text(&mut out, &redact(&mfs, &config)).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "# TYPE logins_total counter\n\
+             logins_total{user=\"REDACTED\",session_token=\"REDACTED\",path=\"/a\"} 3\n\
+             logins_total{user=\"bob (REDACTED)\",session_token=\"REDACTED\",path=\"/b\"} 1\n"
+        );
+
+        // Anchored: `token` alone does not match `session_token`.
+        let config: Redact = serde_yaml::from_str("labels: [token]\nreplacement: $x").unwrap();
+        assert_eq!(config.redact_value("session_token", "abc"), "abc");
+        assert_eq!(config.redact_value("token", "abc"), "$x");
+        assert!(serde_yaml::from_str::<Redact>("values: ['(']").is_err());
+        assert!(Redact::values(vec![]).validate().is_err());
+    }
+}
@@ -0,0 +1,270 @@
+use prometheus::proto::{LabelPair, Metric, MetricFamily};
+use regex::Regex;
+use serde::{Deserialize, Deserializer};
+use std::collections::BTreeMap;
+
+use crate::fingerprint;
+
+const METRIC_NAME_LABEL: &str = "__name__";
+
+/// What a relabel rule does, as in Prometheus' `relabel_configs`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    /// Sets `target_label` to `replacement`, expanded with the groups of
+    /// `regex`, if the joined source labels match. An empty result removes
+    /// the label.
+    Replace,
+    /// Drops series whose joined source labels do not match.
+    Keep,
+    /// Drops series whose joined source labels match.
+    Drop,
+    /// Removes labels whose name matches.
+    LabelDrop,
+    /// Removes labels whose name does not match.
+    LabelKeep,
+    /// Sets `target_label` to the shard of the joined source labels among
+    /// `modulus`, as Prometheus does, e.g. to split series between relays.
+    HashMod,
+}
+
+/// One rule of Prometheus' `relabel_configs`. Source labels may include
+/// `__name__`, but families cannot be renamed.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RelabelRule {
+    #[serde(default)]
+    pub source_labels: Vec<String>,
+    #[serde(default = "default_separator")]
+    pub separator: String,
+    /// Anchored at both ends, as in Prometheus.
+    #[serde(default = "default_regex", deserialize_with = "anchored_regex")]
+    pub regex: Regex,
+    pub target_label: Option<String>,
+    #[serde(default = "default_replacement")]
+    pub replacement: String,
+    #[serde(default = "default_action")]
+    pub action: Action,
+    /// Only for `hashmod`.
+    pub modulus: Option<u64>,
+}
+
+fn default_separator() -> String {
+    ";".to_string()
+}
+
+fn default_regex() -> Regex {
+    Regex::new("^(?:(.*))$").expect("valid regex")
+}
+
+fn default_replacement() -> String {
+    "$1".to_string()
+}
+
+fn default_action() -> Action {
+    Action::Replace
+}
+
+pub(crate) fn anchored_regex<'de, D: Deserializer<'de>>(d: D) -> Result<Regex, D::Error> {
+    let s = String::deserialize(d)?;
+    Regex::new(&format!("^(?:{})$", s)).map_err(serde::de::Error::custom)
+}
+
+impl RelabelRule {
+    /// Checks what deserialization cannot: `replace` and `hashmod` need a
+    /// target label, which must not be `__name__`, and `hashmod` a modulus.
+    pub fn validate(&self) -> Result<(), String> {
+        match (self.action, self.target_label.as_deref()) {
+            (Action::Replace, None) => Err("replace rule without target_label".to_string()),
+            (Action::HashMod, None) => Err("hashmod rule without target_label".to_string()),
+            (Action::Replace | Action::HashMod, Some(METRIC_NAME_LABEL)) => {
+                Err("relabeling cannot rename families".to_string())
+            }
+            (Action::HashMod, _) if self.modulus.unwrap_or(0) == 0 => {
+                Err("hashmod rule without a positive modulus".to_string())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether the regex matches a series: its joined source labels, or for
+    /// `labeldrop` and `labelkeep` any label name.
+    pub fn matches(&self, family: &str, m: &Metric) -> bool {
+        let labels = series_labels(family, m);
+        match self.action {
+            Action::LabelDrop | Action::LabelKeep => labels
+                .keys()
+                .any(|name| name != METRIC_NAME_LABEL && self.regex.is_match(name)),
+            _ => self.regex.is_match(&self.join(&labels)),
+        }
+    }
+
+    fn join(&self, labels: &BTreeMap<String, String>) -> String {
+        self.source_labels
+            .iter()
+            .map(|name| labels.get(name).map(String::as_str).unwrap_or(""))
+            .collect::<Vec<_>>()
+            .join(&self.separator)
+    }
+
+    /// Applies the rule to a series' labels, `__name__` included. Returns
+    /// false if the series is dropped.
+    fn apply(&self, labels: &mut BTreeMap<String, String>) -> bool {
+        match self.action {
+            Action::Keep => self.regex.is_match(&self.join(labels)),
+            Action::Drop => !self.regex.is_match(&self.join(labels)),
+            Action::LabelDrop => {
+                labels.retain(|name, _| name == METRIC_NAME_LABEL || !self.regex.is_match(name));
+                true
+            }
+            Action::LabelKeep => {
+                labels.retain(|name, _| name == METRIC_NAME_LABEL || self.regex.is_match(name));
+                true
+            }
+            Action::Replace => {
+                let source = self.join(labels);
+                if let Some(caps) = self.regex.captures(&source) {
+                    let mut value = String::new();
+                    caps.expand(&self.replacement, &mut value);
+                    let target = self.target_label.clone().unwrap_or_default();
+                    if value.is_empty() {
+                        labels.remove(&target);
+                    } else {
+                        labels.insert(target, value);
+                    }
+                }
+                true
+            }
+            Action::HashMod => {
+                let shard = fingerprint::hashmod(&self.join(labels), self.modulus.unwrap_or(1));
+                let target = self.target_label.clone().unwrap_or_default();
+                labels.insert(target, shard.to_string());
+                true
+            }
+        }
+    }
+}
+
+/// Applies `rules` in order to every series. Dropped series are removed,
+/// and families left without series with them.
+pub fn relabel(mfs: &[MetricFamily], rules: &[RelabelRule]) -> Vec<MetricFamily> {
+    mfs.iter()
+        .filter_map(|mf| {
+            let metrics: Vec<Metric> = mf
+                .get_metric()
+                .iter()
+                .filter_map(|m| relabel_series(mf.get_name(), m, rules))
+                .collect();
+            if metrics.is_empty() {
+                return None;
+            }
+
+            let mut mf = mf.clone();
+            mf.set_metric(metrics.into());
+            Some(mf)
+        })
+        .collect()
+}
+
+/// Applies `rules` in order to one series of `family`, or returns `None` if
+/// a rule drops it.
+pub fn relabel_series(family: &str, m: &Metric, rules: &[RelabelRule]) -> Option<Metric> {
+    let mut labels = series_labels(family, m);
+
+    for rule in rules {
+        if !rule.apply(&mut labels) {
+            return None;
+        }
+    }
+
+    let mut m = m.clone();
+    let pairs: Vec<LabelPair> = labels
+        .into_iter()
+        .filter(|(name, _)| name != METRIC_NAME_LABEL)
+        .map(|(name, value)| {
+            let mut pair = LabelPair::new();
+            pair.set_name(name);
+            pair.set_value(value);
+            pair
+        })
+        .collect();
+    m.set_label(pairs.into());
+    Some(m)
+}
+
+fn series_labels(family: &str, m: &Metric) -> BTreeMap<String, String> {
+    let mut labels: BTreeMap<String, String> = m
+        .get_label()
+        .iter()
+        .map(|l| (l.get_name().to_string(), l.get_value().to_string()))
+        .collect();
+    labels.insert(METRIC_NAME_LABEL.to_string(), family.to_string());
+    labels
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::{read_metric_families, Format};
+    use crate::text_encode::metric_families_to_text;
+
+    #[test]
+    fn test_relabel() {
+        let mfs = read_metric_families(
+            Format::Text,
+            r#"# TYPE up gauge
+up{instance="web-1:9100",job="node",env="dev"} 1
+up{instance="db-1:9100",job="node",env="prod"} 1
+# TYPE build_info gauge
+build_info{version="1.2"} 1
+"#
+            .as_bytes(),
+        )
+        .unwrap();
+
+        let rules: Vec<RelabelRule> = serde_yaml::from_str(
+            r#"
+- source_labels: [__name__, env]
+  regex: "up;dev"
+  action: drop
+- source_labels: [instance]
+  regex: "(.*):\\d+"
+  target_label: host
+- regex: instance
+  action: labeldrop
+"#,
+        )
+        .unwrap();
+        for rule in &rules {
+            rule.validate().unwrap();
+        }
+
+        let mut out = Vec::new();
+        metric_families_to_text(&mut out, &relabel(&mfs, &rules)).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "# TYPE build_info gauge\nbuild_info{version=\"1.2\"} 1\n\
+             # TYPE up gauge\nup{env=\"prod\",host=\"db-1\",job=\"node\"} 1\n"
+        );
+
+        let rule: RelabelRule = serde_yaml::from_str(
+            "source_labels: [version]\ntarget_label: shard\nmodulus: 1000\naction: hashmod",
+        )
+        .unwrap();
+        rule.validate().unwrap();
+        let sharded = relabel_series("build_info", &mfs[0].get_metric()[0], &[rule]);
+        let shard = fingerprint::hashmod("1.2", 1000).to_string();
+        assert!(sharded
+            .unwrap()
+            .get_label()
+            .iter()
+            .any(|l| l.get_name() == "shard" && l.get_value() == shard));
+
+        let rule: RelabelRule = serde_yaml::from_str("target_label: __name__").unwrap();
+        assert!(rule.validate().is_err());
+        let rule: RelabelRule =
+            serde_yaml::from_str("target_label: shard\naction: hashmod").unwrap();
+        assert!(rule.validate().is_err());
+        assert!(serde_yaml::from_str::<RelabelRule>("regex: '('").is_err());
+    }
+}
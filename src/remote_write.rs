@@ -0,0 +1,629 @@
+use prometheus::proto::{Counter, Gauge, LabelPair, Metric, MetricFamily, MetricType, Untyped};
+use prost::Message;
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::flatten::flatten_family;
+use crate::retry::Retry;
+
+pub mod v2;
+
+const METRIC_NAME_LABEL: &str = "__name__";
+
+/// Headers in which receivers report what a request added.
+pub const SAMPLES_WRITTEN_HEADER: &str = "X-Prometheus-Remote-Write-Samples-Written";
+pub const HISTOGRAMS_WRITTEN_HEADER: &str = "X-Prometheus-Remote-Write-Histograms-Written";
+pub const EXEMPLARS_WRITTEN_HEADER: &str = "X-Prometheus-Remote-Write-Exemplars-Written";
+
+/// Version of the remote-write protocol, named by its message in the
+/// `proto` parameter of the `Content-Type`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Protocol {
+    /// `prometheus.WriteRequest`
+    #[default]
+    V1,
+    /// `io.prometheus.write.v2.Request`
+    V2,
+}
+
+impl Protocol {
+    /// The protocol of a request with this `Content-Type`. 1.0 senders may
+    /// leave out the `proto` parameter or the whole header.
+    pub fn from_content_type(value: Option<&str>) -> Result<Protocol, RemoteWriteError> {
+        let params = value.into_iter().flat_map(|v| v.split(';').skip(1));
+        for param in params {
+            let Some((key, proto)) = param.split_once('=') else {
+                continue;
+            };
+            if key.trim().eq_ignore_ascii_case("proto") {
+                return match proto.trim().trim_matches('"') {
+                    "prometheus.WriteRequest" => Ok(Protocol::V1),
+                    "io.prometheus.write.v2.Request" => Ok(Protocol::V2),
+                    other => Err(RemoteWriteError::UnsupportedProto(other.to_string())),
+                };
+            }
+        }
+        Ok(Protocol::V1)
+    }
+
+    /// The `Content-Type` of requests.
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Protocol::V1 => "application/x-protobuf",
+            Protocol::V2 => "application/x-protobuf;proto=io.prometheus.write.v2.Request",
+        }
+    }
+
+    /// The `X-Prometheus-Remote-Write-Version` of requests.
+    pub fn version(self) -> &'static str {
+        match self {
+            Protocol::V1 => "0.1.0",
+            Protocol::V2 => "2.0.0",
+        }
+    }
+}
+
+impl FromStr for Protocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "1" | "1.0" => Ok(Protocol::V1),
+            "2" | "2.0" => Ok(Protocol::V2),
+            _ => Err(format!(
+                "unknown remote-write protocol {:?}, expected 1.0 or 2.0",
+                s
+            )),
+        }
+    }
+}
+
+/// What a request added to the receiver, as reported in the `*-Written`
+/// headers.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Written {
+    pub samples: usize,
+    pub histograms: usize,
+    pub exemplars: usize,
+}
+
+/// `prometheus.WriteRequest` from the remote-write 1.0 protocol. Exemplars and
+/// native histograms are not decoded.
+#[derive(Clone, PartialEq, Message)]
+pub struct WriteRequest {
+    #[prost(message, repeated, tag = "1")]
+    pub timeseries: Vec<TimeSeries>,
+    #[prost(message, repeated, tag = "3")]
+    pub metadata: Vec<MetricMetadata>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct TimeSeries {
+    #[prost(message, repeated, tag = "1")]
+    pub labels: Vec<Label>,
+    #[prost(message, repeated, tag = "2")]
+    pub samples: Vec<Sample>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Label {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(string, tag = "2")]
+    pub value: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Sample {
+    #[prost(double, tag = "1")]
+    pub value: f64,
+    #[prost(int64, tag = "2")]
+    pub timestamp: i64,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct MetricMetadata {
+    #[prost(enumeration = "MetadataType", tag = "1")]
+    pub r#type: i32,
+    #[prost(string, tag = "2")]
+    pub metric_family_name: String,
+    #[prost(string, tag = "4")]
+    pub help: String,
+    #[prost(string, tag = "5")]
+    pub unit: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+pub enum MetadataType {
+    Unknown = 0,
+    Counter = 1,
+    Gauge = 2,
+    Histogram = 3,
+    Gaugehistogram = 4,
+    Summary = 5,
+    Info = 6,
+    Stateset = 7,
+}
+
+#[derive(Debug)]
+pub enum RemoteWriteError {
+    Snappy(snap::Error),
+    Decode(prost::DecodeError),
+    MissingName,
+    Invalid(String),
+    UnsupportedProto(String),
+    Send(Box<ureq::Error>),
+}
+
+impl fmt::Display for RemoteWriteError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RemoteWriteError::Snappy(e) => write!(f, "invalid snappy payload: {}", e),
+            RemoteWriteError::Decode(e) => write!(f, "invalid write request: {}", e),
+            RemoteWriteError::MissingName => write!(f, "time series without __name__ label"),
+            RemoteWriteError::Invalid(msg) => write!(f, "invalid write request: {}", msg),
+            RemoteWriteError::UnsupportedProto(proto) => {
+                write!(f, "unsupported remote-write message {:?}", proto)
+            }
+            RemoteWriteError::Send(e) => write!(f, "sending write request failed: {}", e),
+        }
+    }
+}
+
+impl Error for RemoteWriteError {}
+
+/// Decodes a snappy block-compressed, protobuf encoded remote-write body.
+pub fn decode_write_request(body: &[u8]) -> Result<WriteRequest, RemoteWriteError> {
+    let raw = snap::raw::Decoder::new()
+        .decompress_vec(body)
+        .map_err(RemoteWriteError::Snappy)?;
+    WriteRequest::decode(raw.as_slice()).map_err(RemoteWriteError::Decode)
+}
+
+/// Encodes a write request as a snappy block-compressed protobuf body.
+pub fn encode_write_request(req: &WriteRequest) -> Result<Vec<u8>, RemoteWriteError> {
+    snap::raw::Encoder::new()
+        .compress_vec(&req.encode_to_vec())
+        .map_err(RemoteWriteError::Snappy)
+}
+
+/// How [`push`] talks to the endpoint.
+#[derive(Debug, Clone, Default)]
+pub struct PushOptions {
+    /// Limit on every attempt; none if unset.
+    pub timeout: Option<Duration>,
+    /// Retries of requests that failed to connect, timed out, or got a 429
+    /// or 5xx answer, which the protocol marks as retriable.
+    pub retry: Retry,
+}
+
+/// Posts a write request to the remote-write endpoint at `url`.
+pub fn push(url: &str, req: &WriteRequest, options: &PushOptions) -> Result<(), RemoteWriteError> {
+    post(url, &encode_write_request(req)?, Protocol::V1, options)?;
+    Ok(())
+}
+
+fn post(
+    url: &str,
+    body: &[u8],
+    protocol: Protocol,
+    options: &PushOptions,
+) -> Result<ureq::Response, RemoteWriteError> {
+    let transient = |e: &RemoteWriteError| match e {
+        RemoteWriteError::Send(e) => match **e {
+            ureq::Error::Status(code, _) => code == 429 || code >= 500,
+            ureq::Error::Transport(_) => true,
+        },
+        _ => false,
+    };
+    options.retry.run(transient, || {
+        let mut request = ureq::post(url)
+            .set("Content-Type", protocol.content_type())
+            .set("Content-Encoding", "snappy")
+            .set("X-Prometheus-Remote-Write-Version", protocol.version());
+        if let Some(timeout) = options.timeout {
+            request = request.timeout(timeout);
+        }
+        request
+            .send_bytes(body)
+            .map_err(|e| RemoteWriteError::Send(Box::new(e)))
+    })
+}
+
+/// Ships metric families to a remote-write endpoint, in requests of at
+/// most [`Client::max_samples`] samples.
+#[derive(Debug, Clone)]
+pub struct Client {
+    url: String,
+    options: PushOptions,
+    max_samples: usize,
+    protocol: Protocol,
+    // Set once the endpoint turned out not to take 2.0; shared by clones.
+    v1_only: Arc<AtomicBool>,
+}
+
+impl Client {
+    /// A client of the endpoint at `url`, sending up to 2000 samples per
+    /// request as Prometheus does, without timeout or retries.
+    pub fn new(url: impl Into<String>) -> Self {
+        Client {
+            url: url.into(),
+            options: PushOptions::default(),
+            max_samples: 2000,
+            protocol: Protocol::V1,
+            v1_only: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Protocol to send; an endpoint answering a 2.0 request with 415, or
+    /// without the written counts 2.0 receivers report, is sent 1.0 from
+    /// then on.
+    pub fn protocol(mut self, protocol: Protocol) -> Self {
+        self.protocol = protocol;
+        self
+    }
+
+    /// Limit on every attempt.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.options.timeout = Some(timeout);
+        self
+    }
+
+    /// Retries of requests failing transiently.
+    pub fn retry(mut self, retry: Retry) -> Self {
+        self.options.retry = retry;
+        self
+    }
+
+    /// Maximum samples per request; at least 1.
+    pub fn max_samples(mut self, max_samples: usize) -> Self {
+        self.max_samples = max_samples.max(1);
+        self
+    }
+
+    /// Sends the samples of `mfs`, giving those without a timestamp
+    /// `default_timestamp_ms`, and returns the number of requests sent.
+    /// Requests are sent in order, and the first that fails after its
+    /// retries ends the sending.
+    pub fn send(
+        &self,
+        mfs: &[MetricFamily],
+        default_timestamp_ms: i64,
+    ) -> Result<usize, RemoteWriteError> {
+        if self.protocol == Protocol::V1 {
+            let req = metric_families_to_write_request(mfs, default_timestamp_ms);
+            let requests = split_write_request(req, self.max_samples);
+            for req in &requests {
+                push(&self.url, req, &self.options)?;
+            }
+            return Ok(requests.len());
+        }
+
+        let requests = v2::metric_families_to_requests(mfs, default_timestamp_ms, self.max_samples);
+        for req in &requests {
+            if !self.v1_only.load(Ordering::Relaxed) {
+                let body = v2::encode_request(req)?;
+                match post(&self.url, &body, Protocol::V2, &self.options) {
+                    Ok(response) if response.header(SAMPLES_WRITTEN_HEADER).is_some() => continue,
+                    Ok(_) => log::warn!(
+                        "{}: no written counts in the answer to a remote-write 2.0 request, sending 1.0",
+                        self.url
+                    ),
+                    Err(RemoteWriteError::Send(e)) if matches!(*e, ureq::Error::Status(415, _)) => {
+                        log::warn!(
+                            "{}: remote-write 2.0 not supported, sending 1.0",
+                            self.url
+                        )
+                    }
+                    Err(e) => return Err(e),
+                }
+                self.v1_only.store(true, Ordering::Relaxed);
+            }
+            push(&self.url, &v2::to_write_request(req)?, &self.options)?;
+        }
+        Ok(requests.len())
+    }
+}
+
+/// Splits a write request into requests of at most `max_samples` samples,
+/// keeping series whole unless they alone have more. All metadata goes with
+/// the first request.
+pub fn split_write_request(req: WriteRequest, max_samples: usize) -> Vec<WriteRequest> {
+    let max_samples = max_samples.max(1);
+    let mut requests = Vec::new();
+    let mut current = WriteRequest {
+        timeseries: Vec::new(),
+        metadata: req.metadata,
+    };
+    let mut current_samples = 0;
+
+    for ts in req.timeseries {
+        let mut rest = ts.samples.as_slice();
+        while !rest.is_empty() {
+            let room = max_samples - current_samples;
+            if room < rest.len().min(max_samples) {
+                requests.push(std::mem::take(&mut current));
+                current_samples = 0;
+                continue;
+            }
+            let (chunk, tail) = rest.split_at(rest.len().min(room));
+            current.timeseries.push(TimeSeries {
+                labels: ts.labels.clone(),
+                samples: chunk.to_vec(),
+            });
+            current_samples += chunk.len();
+            rest = tail;
+        }
+    }
+    if !current.timeseries.is_empty() || !current.metadata.is_empty() || requests.is_empty() {
+        requests.push(current);
+    }
+    requests
+}
+
+/// Converts metric families into a write request with one series per
+/// sample, as the text format lists them, and metadata with the type and
+/// help of every family. Untyped families are sent as `UNKNOWN`. Samples
+/// without a timestamp get `default_timestamp_ms`.
+pub fn metric_families_to_write_request(
+    mfs: &[MetricFamily],
+    default_timestamp_ms: i64,
+) -> WriteRequest {
+    let mut req = WriteRequest::default();
+    for mf in mfs {
+        for sample in flatten_family(mf) {
+            let mut labels: Vec<Label> = sample
+                .labels
+                .into_iter()
+                .map(|(name, value)| Label { name, value })
+                .collect();
+            labels.push(Label {
+                name: METRIC_NAME_LABEL.to_string(),
+                value: sample.name,
+            });
+            // Receivers expect labels sorted by name.
+            labels.sort_by(|a, b| a.name.cmp(&b.name));
+            req.timeseries.push(TimeSeries {
+                labels,
+                samples: vec![Sample {
+                    value: sample.value,
+                    timestamp: sample.timestamp_ms.unwrap_or(default_timestamp_ms),
+                }],
+            });
+        }
+
+        req.metadata.push(MetricMetadata {
+            r#type: metadata_type(mf.get_field_type()) as i32,
+            metric_family_name: mf.get_name().to_string(),
+            help: mf.get_help().to_string(),
+            unit: String::new(),
+        });
+    }
+    req
+}
+
+fn metadata_type(t: MetricType) -> MetadataType {
+    match t {
+        MetricType::COUNTER => MetadataType::Counter,
+        MetricType::GAUGE => MetadataType::Gauge,
+        MetricType::HISTOGRAM => MetadataType::Histogram,
+        MetricType::SUMMARY => MetadataType::Summary,
+        MetricType::UNTYPED => MetadataType::Unknown,
+    }
+}
+
+/// Converts a write request into metric families keeping the latest sample of
+/// every series. Counter and gauge metadata set the family type; everything
+/// else, including the `_bucket`/`_sum`/`_count` series of histograms and
+/// summaries, becomes untyped.
+pub fn write_request_to_metric_families(
+    req: &WriteRequest,
+) -> Result<Vec<MetricFamily>, RemoteWriteError> {
+    let metadata: HashMap<&str, &MetricMetadata> = req
+        .metadata
+        .iter()
+        .map(|m| (m.metric_family_name.as_str(), m))
+        .collect();
+
+    let mut mf_by_name: BTreeMap<String, MetricFamily> = BTreeMap::new();
+
+    for ts in &req.timeseries {
+        let name = ts
+            .labels
+            .iter()
+            .find(|l| l.name == METRIC_NAME_LABEL)
+            .map(|l| l.value.clone())
+            .ok_or(RemoteWriteError::MissingName)?;
+
+        let sample = match ts.samples.iter().max_by_key(|s| s.timestamp) {
+            Some(s) => s,
+            None => continue,
+        };
+
+        let mf = mf_by_name.entry(name.clone()).or_insert_with(|| {
+            let mut mf = MetricFamily::new();
+            mf.set_name(name.clone());
+            mf.set_field_type(MetricType::UNTYPED);
+            if let Some(meta) = metadata.get(name.as_str()) {
+                if !meta.help.is_empty() {
+                    mf.set_help(meta.help.clone());
+                }
+                match meta.r#type() {
+                    MetadataType::Counter => mf.set_field_type(MetricType::COUNTER),
+                    MetadataType::Gauge => mf.set_field_type(MetricType::GAUGE),
+                    _ => {}
+                }
+            }
+            mf
+        });
+
+        let mut m = Metric::new();
+        for l in ts.labels.iter().filter(|l| l.name != METRIC_NAME_LABEL) {
+            let mut label = LabelPair::new();
+            label.set_name(l.name.clone());
+            label.set_value(l.value.clone());
+            m.mut_label().push(label);
+        }
+        m.set_timestamp_ms(sample.timestamp);
+
+        match mf.get_field_type() {
+            MetricType::COUNTER => {
+                let mut c = Counter::new();
+                c.set_value(sample.value);
+                m.set_counter(c);
+            }
+            MetricType::GAUGE => {
+                let mut g = Gauge::new();
+                g.set_value(sample.value);
+                m.set_gauge(g);
+            }
+            _ => {
+                let mut u = Untyped::new();
+                u.set_value(sample.value);
+                m.set_untyped(u);
+            }
+        }
+        mf.mut_metric().push(m);
+    }
+
+    Ok(mf_by_name.into_values().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn label(name: &str, value: &str) -> Label {
+        Label {
+            name: name.to_string(),
+            value: value.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_decode_and_convert() {
+        let req = WriteRequest {
+            timeseries: vec![
+                TimeSeries {
+                    labels: vec![label("__name__", "up"), label("job", "node")],
+                    samples: vec![
+                        Sample {
+                            value: 0.0,
+                            timestamp: 1000,
+                        },
+                        Sample {
+                            value: 1.0,
+                            timestamp: 2000,
+                        },
+                    ],
+                },
+                TimeSeries {
+                    labels: vec![label("__name__", "requests_total")],
+                    samples: vec![Sample {
+                        value: 42.0,
+                        timestamp: 2000,
+                    }],
+                },
+            ],
+            metadata: vec![MetricMetadata {
+                r#type: MetadataType::Counter as i32,
+                metric_family_name: "requests_total".to_string(),
+                help: "Requests.".to_string(),
+                unit: String::new(),
+            }],
+        };
+
+        let body = encode_write_request(&req).unwrap();
+        let decoded = decode_write_request(&body).unwrap();
+        assert_eq!(decoded, req);
+
+        let mfs = write_request_to_metric_families(&decoded).unwrap();
+        assert_eq!(mfs.len(), 2);
+        assert_eq!(mfs[0].get_name(), "requests_total");
+        assert_eq!(mfs[0].get_field_type(), MetricType::COUNTER);
+        assert_eq!(mfs[0].get_help(), "Requests.");
+
+        let up = &mfs[1].get_metric()[0];
+        assert_eq!(up.get_untyped().get_value(), 1.0);
+        assert_eq!(up.get_timestamp_ms(), 2000);
+        assert_eq!(up.get_label()[0].get_value(), "node");
+
+        assert!(decode_write_request(b"garbage").is_err());
+
+        let requests = split_write_request(req.clone(), 2);
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].timeseries, req.timeseries[..1]);
+        assert_eq!(requests[0].metadata, req.metadata);
+        assert_eq!(requests[1].timeseries, req.timeseries[1..]);
+        assert!(requests[1].metadata.is_empty());
+
+        let requests = split_write_request(req.clone(), 1);
+        assert_eq!(requests.len(), 3);
+        assert_eq!(
+            requests[1].timeseries[0].samples,
+            req.timeseries[0].samples[1..]
+        );
+    }
+
+    #[test]
+    fn test_client() {
+        let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/api/v1/write", server.server_addr());
+        let handle = std::thread::spawn(move || {
+            let mut bodies = Vec::new();
+            for _ in 0..2 {
+                let mut request = server.recv().unwrap();
+                let mut body = Vec::new();
+                request.as_reader().read_to_end(&mut body).unwrap();
+                bodies.push(decode_write_request(&body).unwrap());
+                request.respond(tiny_http::Response::empty(204)).unwrap();
+            }
+            bodies
+        });
+
+        let mfs = crate::format::read_metric_families(
+            crate::format::Format::Text,
+            "# TYPE up gauge\nup{job=\"a\"} 1\nup{job=\"b\"} 0 5000\n".as_bytes(),
+        )
+        .unwrap();
+        let client = Client::new(url).max_samples(1);
+        assert_eq!(client.send(&mfs, 1000).unwrap(), 2);
+
+        let bodies = handle.join().unwrap();
+        assert_eq!(bodies[0].timeseries[0].samples[0].timestamp, 1000);
+        assert_eq!(bodies[0].metadata[0].metric_family_name, "up");
+        assert_eq!(bodies[1].timeseries[0].samples[0].value, 0.0);
+        assert_eq!(bodies[1].timeseries[0].samples[0].timestamp, 5000);
+
+        // A 1.0 receiver refusing 2.0 gets 1.0 from then on.
+        let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/api/v1/write", server.server_addr());
+        let handle = std::thread::spawn(move || {
+            let mut protocols = Vec::new();
+            for _ in 0..3 {
+                let request = server.recv().unwrap();
+                let content_type = request
+                    .headers()
+                    .iter()
+                    .find(|h| h.field.equiv("Content-Type"))
+                    .map(|h| h.value.to_string());
+                let protocol = Protocol::from_content_type(content_type.as_deref()).unwrap();
+                let status = if protocol == Protocol::V2 { 415 } else { 204 };
+                request.respond(tiny_http::Response::empty(status)).unwrap();
+                protocols.push(protocol);
+            }
+            protocols
+        });
+        let client = Client::new(url).protocol(Protocol::V2).max_samples(1);
+        assert_eq!(client.send(&mfs, 1000).unwrap(), 2);
+        assert_eq!(
+            handle.join().unwrap(),
+            [Protocol::V2, Protocol::V1, Protocol::V1]
+        );
+    }
+}
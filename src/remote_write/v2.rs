@@ -0,0 +1,509 @@
+//! `io.prometheus.write.v2.Request` from the remote-write 2.0 protocol, whose
+//! series refer to their labels, help and unit through a table of interned
+//! strings and carry their own metadata.
+//!
+//! Metric families have neither exemplars nor native histograms, so requests
+//! built from them have none either. Received exemplars are dropped, and
+//! native histograms become the `_bucket`, `_sum` and `_count` series of a
+//! classic histogram.
+
+use prometheus::proto::MetricFamily;
+use prost::{Message, Oneof};
+use std::collections::{HashMap, HashSet};
+
+use super::{
+    metadata_type, Label, MetadataType, MetricMetadata, RemoteWriteError, Sample, WriteRequest,
+    Written, METRIC_NAME_LABEL,
+};
+use crate::flatten::flatten_family;
+use crate::text_encode::format_float;
+
+/// Schema of native histograms with custom bucket bounds.
+const CUSTOM_BUCKETS_SCHEMA: i32 = -53;
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Request {
+    /// Strings referred to by index; the first is always empty.
+    #[prost(string, repeated, tag = "4")]
+    pub symbols: Vec<String>,
+    #[prost(message, repeated, tag = "5")]
+    pub timeseries: Vec<TimeSeries>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct TimeSeries {
+    /// Symbols of label names and values, alternately.
+    #[prost(uint32, repeated, tag = "1")]
+    pub labels_refs: Vec<u32>,
+    #[prost(message, repeated, tag = "2")]
+    pub samples: Vec<Sample>,
+    #[prost(message, repeated, tag = "3")]
+    pub histograms: Vec<Histogram>,
+    #[prost(message, repeated, tag = "4")]
+    pub exemplars: Vec<Exemplar>,
+    #[prost(message, optional, tag = "5")]
+    pub metadata: Option<Metadata>,
+    #[prost(int64, tag = "6")]
+    pub created_timestamp: i64,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Exemplar {
+    #[prost(uint32, repeated, tag = "1")]
+    pub labels_refs: Vec<u32>,
+    #[prost(double, tag = "2")]
+    pub value: f64,
+    #[prost(int64, tag = "3")]
+    pub timestamp: i64,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Metadata {
+    #[prost(enumeration = "MetadataType", tag = "1")]
+    pub r#type: i32,
+    #[prost(uint32, tag = "3")]
+    pub help_ref: u32,
+    #[prost(uint32, tag = "4")]
+    pub unit_ref: u32,
+}
+
+/// A native histogram. Buckets are given by spans of consecutive indexes
+/// and by their counts, delta-encoded for integer histograms.
+#[derive(Clone, PartialEq, Message)]
+pub struct Histogram {
+    #[prost(oneof = "Count", tags = "1, 2")]
+    pub count: Option<Count>,
+    #[prost(double, tag = "3")]
+    pub sum: f64,
+    #[prost(sint32, tag = "4")]
+    pub schema: i32,
+    #[prost(double, tag = "5")]
+    pub zero_threshold: f64,
+    #[prost(oneof = "Count", tags = "6, 7")]
+    pub zero_count: Option<Count>,
+    #[prost(message, repeated, tag = "8")]
+    pub negative_spans: Vec<BucketSpan>,
+    #[prost(sint64, repeated, tag = "9")]
+    pub negative_deltas: Vec<i64>,
+    #[prost(double, repeated, tag = "10")]
+    pub negative_counts: Vec<f64>,
+    #[prost(message, repeated, tag = "11")]
+    pub positive_spans: Vec<BucketSpan>,
+    #[prost(sint64, repeated, tag = "12")]
+    pub positive_deltas: Vec<i64>,
+    #[prost(double, repeated, tag = "13")]
+    pub positive_counts: Vec<f64>,
+    /// `ResetHint`, which is not interpreted.
+    #[prost(int32, tag = "14")]
+    pub reset_hint: i32,
+    #[prost(int64, tag = "15")]
+    pub timestamp: i64,
+    #[prost(double, repeated, tag = "16")]
+    pub custom_values: Vec<f64>,
+}
+
+/// A count of a [`Histogram`], an integer or a float one.
+#[derive(Clone, PartialEq, Oneof)]
+pub enum Count {
+    #[prost(uint64, tag = "1")]
+    Int(u64),
+    #[prost(double, tag = "2")]
+    Float(f64),
+}
+
+impl Count {
+    fn value(count: &Option<Count>) -> f64 {
+        match count {
+            Some(Count::Int(n)) => *n as f64,
+            Some(Count::Float(v)) => *v,
+            None => 0.0,
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct BucketSpan {
+    /// Gap to the end of the previous span, or the first index.
+    #[prost(sint32, tag = "1")]
+    pub offset: i32,
+    #[prost(uint32, tag = "2")]
+    pub length: u32,
+}
+
+/// Decodes a snappy block-compressed, protobuf encoded 2.0 body.
+pub fn decode_request(body: &[u8]) -> Result<Request, RemoteWriteError> {
+    let raw = snap::raw::Decoder::new()
+        .decompress_vec(body)
+        .map_err(RemoteWriteError::Snappy)?;
+    Request::decode(raw.as_slice()).map_err(RemoteWriteError::Decode)
+}
+
+/// Encodes a 2.0 request as a snappy block-compressed protobuf body.
+pub fn encode_request(req: &Request) -> Result<Vec<u8>, RemoteWriteError> {
+    snap::raw::Encoder::new()
+        .compress_vec(&req.encode_to_vec())
+        .map_err(RemoteWriteError::Snappy)
+}
+
+/// Interns the strings of a request.
+struct Symbols {
+    symbols: Vec<String>,
+    refs: HashMap<String, u32>,
+}
+
+impl Symbols {
+    fn new() -> Self {
+        let mut symbols = Symbols {
+            symbols: Vec::new(),
+            refs: HashMap::new(),
+        };
+        symbols.intern("");
+        symbols
+    }
+
+    fn intern(&mut self, s: &str) -> u32 {
+        if let Some(&r) = self.refs.get(s) {
+            return r;
+        }
+        let r = self.symbols.len() as u32;
+        self.symbols.push(s.to_string());
+        self.refs.insert(s.to_string(), r);
+        r
+    }
+}
+
+/// Converts metric families into requests of at most `max_samples` samples,
+/// one series per sample as in [`super::metric_families_to_write_request`].
+/// Every series carries the type and help of its family. Samples without a
+/// timestamp get `default_timestamp_ms`.
+pub fn metric_families_to_requests(
+    mfs: &[MetricFamily],
+    default_timestamp_ms: i64,
+    max_samples: usize,
+) -> Vec<Request> {
+    let max_samples = max_samples.max(1);
+    let mut requests = Vec::new();
+    let mut symbols = Symbols::new();
+    let mut timeseries = Vec::new();
+
+    for mf in mfs {
+        for sample in flatten_family(mf) {
+            if timeseries.len() == max_samples {
+                let full = std::mem::replace(&mut symbols, Symbols::new());
+                requests.push(Request {
+                    symbols: full.symbols,
+                    timeseries: std::mem::take(&mut timeseries),
+                });
+            }
+            let mut labels = sample.labels;
+            labels.push((METRIC_NAME_LABEL.to_string(), sample.name));
+            // Receivers expect labels sorted by name.
+            labels.sort();
+            timeseries.push(TimeSeries {
+                labels_refs: labels
+                    .iter()
+                    .flat_map(|(name, value)| [symbols.intern(name), symbols.intern(value)])
+                    .collect(),
+                samples: vec![Sample {
+                    value: sample.value,
+                    timestamp: sample.timestamp_ms.unwrap_or(default_timestamp_ms),
+                }],
+                metadata: Some(Metadata {
+                    r#type: metadata_type(mf.get_field_type()) as i32,
+                    help_ref: symbols.intern(mf.get_help()),
+                    unit_ref: 0,
+                }),
+                ..Default::default()
+            });
+        }
+    }
+    if !timeseries.is_empty() || requests.is_empty() {
+        requests.push(Request {
+            symbols: symbols.symbols,
+            timeseries,
+        });
+    }
+    requests
+}
+
+/// What accepting `req` adds; exemplars are dropped, so none.
+pub fn written(req: &Request) -> Written {
+    Written {
+        samples: req.timeseries.iter().map(|ts| ts.samples.len()).sum(),
+        histograms: req.timeseries.iter().map(|ts| ts.histograms.len()).sum(),
+        exemplars: 0,
+    }
+}
+
+/// Converts a 2.0 request into a 1.0 one, with native histograms as classic
+/// ones and metadata for every family that has any.
+pub fn to_write_request(req: &Request) -> Result<WriteRequest, RemoteWriteError> {
+    let symbol = |r: u32| {
+        req.symbols.get(r as usize).ok_or_else(|| {
+            RemoteWriteError::Invalid(format!("symbol {} out of {}", r, req.symbols.len()))
+        })
+    };
+
+    let mut out = WriteRequest::default();
+    let mut families = HashSet::new();
+    for ts in &req.timeseries {
+        if ts.labels_refs.len() % 2 != 0 {
+            return Err(RemoteWriteError::Invalid(
+                "odd number of label references".to_string(),
+            ));
+        }
+        let labels = ts
+            .labels_refs
+            .chunks(2)
+            .map(|pair| {
+                Ok(Label {
+                    name: symbol(pair[0])?.clone(),
+                    value: symbol(pair[1])?.clone(),
+                })
+            })
+            .collect::<Result<Vec<_>, RemoteWriteError>>()?;
+        let name = labels
+            .iter()
+            .find(|l| l.name == METRIC_NAME_LABEL)
+            .map(|l| l.value.clone())
+            .ok_or(RemoteWriteError::MissingName)?;
+
+        if !ts.samples.is_empty() {
+            out.timeseries.push(super::TimeSeries {
+                labels: labels.clone(),
+                samples: ts.samples.clone(),
+            });
+        }
+        for h in &ts.histograms {
+            classic_series(&name, &labels, h, &mut out.timeseries)?;
+        }
+
+        if let Some(metadata) = &ts.metadata {
+            let family = family_name(&name, metadata.r#type);
+            if families.insert(family.to_string()) {
+                out.metadata.push(MetricMetadata {
+                    r#type: metadata.r#type,
+                    metric_family_name: family.to_string(),
+                    help: symbol(metadata.help_ref)?.clone(),
+                    unit: symbol(metadata.unit_ref)?.clone(),
+                });
+            }
+        }
+    }
+    Ok(out)
+}
+
+/// The family of a series with 1.0 metadata of type `t`.
+fn family_name(name: &str, t: i32) -> &str {
+    let suffixes: &[&str] = match MetadataType::try_from(t) {
+        Ok(MetadataType::Histogram | MetadataType::Gaugehistogram) => {
+            &["_bucket", "_sum", "_count"]
+        }
+        Ok(MetadataType::Summary) => &["_sum", "_count"],
+        _ => &[],
+    };
+    suffixes
+        .iter()
+        .find_map(|suffix| name.strip_suffix(suffix))
+        .unwrap_or(name)
+}
+
+/// Appends the `_bucket`, `_sum` and `_count` series of the classic
+/// histogram with the buckets of `h`.
+fn classic_series(
+    name: &str,
+    labels: &[Label],
+    h: &Histogram,
+    out: &mut Vec<super::TimeSeries>,
+) -> Result<(), RemoteWriteError> {
+    let series = |suffix: &str, le: Option<f64>, value: f64| {
+        let mut labels: Vec<Label> = labels
+            .iter()
+            .map(|l| match l.name.as_str() {
+                METRIC_NAME_LABEL => Label {
+                    name: l.name.clone(),
+                    value: format!("{}{}", name, suffix),
+                },
+                _ => l.clone(),
+            })
+            .collect();
+        if let Some(le) = le {
+            labels.push(Label {
+                name: "le".to_string(),
+                value: format_float(le),
+            });
+            labels.sort_by(|a, b| a.name.cmp(&b.name));
+        }
+        super::TimeSeries {
+            labels,
+            samples: vec![Sample {
+                value,
+                timestamp: h.timestamp,
+            }],
+        }
+    };
+
+    let count = Count::value(&h.count);
+    let mut cumulative = 0.0;
+    for (upper, n) in buckets(h)? {
+        cumulative += n;
+        // The +Inf bucket is written from the count below.
+        if upper < f64::INFINITY {
+            out.push(series("_bucket", Some(upper), cumulative));
+        }
+    }
+    out.push(series("_bucket", Some(f64::INFINITY), count));
+    out.push(series("_sum", None, h.sum));
+    out.push(series("_count", None, count));
+    Ok(())
+}
+
+/// The buckets of `h` as upper bounds and counts, by increasing bound.
+fn buckets(h: &Histogram) -> Result<Vec<(f64, f64)>, RemoteWriteError> {
+    let positive = indexed_counts(&h.positive_spans, &h.positive_deltas, &h.positive_counts);
+    if h.schema == CUSTOM_BUCKETS_SCHEMA {
+        return positive
+            .into_iter()
+            .map(|(i, n)| match usize::try_from(i) {
+                Ok(i) if i < h.custom_values.len() => Ok((h.custom_values[i], n)),
+                Ok(i) if i == h.custom_values.len() => Ok((f64::INFINITY, n)),
+                _ => Err(RemoteWriteError::Invalid(format!(
+                    "custom bucket {} out of {}",
+                    i,
+                    h.custom_values.len()
+                ))),
+            })
+            .collect();
+    }
+    if !(-4..=8).contains(&h.schema) {
+        return Err(RemoteWriteError::Invalid(format!(
+            "unsupported histogram schema {}",
+            h.schema
+        )));
+    }
+
+    // Bucket i of an exponential schema holds (base^(i-1), base^i], and its
+    // negative counterpart the same range negated.
+    let base = 2f64.powf(2f64.powi(-h.schema));
+    let negative = indexed_counts(&h.negative_spans, &h.negative_deltas, &h.negative_counts);
+    let mut buckets: Vec<(f64, f64)> = negative
+        .into_iter()
+        .rev()
+        .map(|(i, n)| (-base.powi(i - 1), n))
+        .collect();
+    buckets.push((h.zero_threshold, Count::value(&h.zero_count)));
+    buckets.extend(positive.into_iter().map(|(i, n)| (base.powi(i), n)));
+    Ok(buckets)
+}
+
+/// Bucket indexes with their counts, from spans and either absolute counts
+/// or deltas.
+fn indexed_counts(spans: &[BucketSpan], deltas: &[i64], counts: &[f64]) -> Vec<(i32, f64)> {
+    let mut indexes = Vec::new();
+    let mut index = 0;
+    for span in spans {
+        index += span.offset;
+        for _ in 0..span.length {
+            indexes.push(index);
+            index += 1;
+        }
+    }
+
+    let counts: Vec<f64> = if counts.is_empty() {
+        deltas
+            .iter()
+            .scan(0i64, |count, delta| {
+                *count += delta;
+                Some(*count as f64)
+            })
+            .collect()
+    } else {
+        counts.to_vec()
+    };
+    indexes.into_iter().zip(counts).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::{read_metric_families, Format};
+
+    #[test]
+    fn test_v2() {
+        let mfs = read_metric_families(
+            Format::Text,
+            "# HELP up Up.\n# TYPE up gauge\nup{job=\"a\"} 1\nup{job=\"b\"} 0 5000\n".as_bytes(),
+        )
+        .unwrap();
+        let requests = metric_families_to_requests(&mfs, 1000, 1);
+        assert_eq!(requests.len(), 2);
+        let req = decode_request(&encode_request(&requests[1]).unwrap()).unwrap();
+        assert_eq!(req.symbols, ["", "__name__", "up", "job", "b", "Up."]);
+        assert_eq!(req.timeseries[0].labels_refs, [1, 2, 3, 4]);
+        assert_eq!(req.timeseries[0].metadata.as_ref().unwrap().help_ref, 5);
+
+        let v1 = to_write_request(&req).unwrap();
+        assert_eq!(v1.timeseries[0].samples[0].timestamp, 5000);
+        assert_eq!(v1.metadata[0].metric_family_name, "up");
+        assert_eq!(v1.metadata[0].help, "Up.");
+
+        // Schema 0 buckets [-4, -2), [-1, 1], (1, 2] and (2, 4].
+        let req = Request {
+            symbols: vec!["".into(), "__name__".into(), "latency".into()],
+            timeseries: vec![TimeSeries {
+                labels_refs: vec![1, 2],
+                histograms: vec![Histogram {
+                    count: Some(Count::Int(10)),
+                    sum: 12.5,
+                    zero_threshold: 1.0,
+                    zero_count: Some(Count::Int(2)),
+                    negative_spans: vec![BucketSpan {
+                        offset: 2,
+                        length: 1,
+                    }],
+                    negative_deltas: vec![1],
+                    positive_spans: vec![BucketSpan {
+                        offset: 1,
+                        length: 2,
+                    }],
+                    positive_deltas: vec![4, -1],
+                    timestamp: 2000,
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }],
+        };
+        assert_eq!(written(&req).histograms, 1);
+        let v1 = to_write_request(&req).unwrap();
+        let buckets: Vec<(String, String, f64)> = v1
+            .timeseries
+            .iter()
+            .map(|ts| {
+                let le = ts.labels.iter().find(|l| l.name == "le");
+                (
+                    ts.labels[0].value.clone(),
+                    le.map_or(String::new(), |l| l.value.clone()),
+                    ts.samples[0].value,
+                )
+            })
+            .collect();
+        let expected = [
+            ("latency_bucket", "-2", 1.0),
+            ("latency_bucket", "1", 3.0),
+            ("latency_bucket", "2", 7.0),
+            ("latency_bucket", "4", 10.0),
+            ("latency_bucket", "+Inf", 10.0),
+            ("latency_sum", "", 12.5),
+            ("latency_count", "", 10.0),
+        ];
+        let expected: Vec<(String, String, f64)> = expected
+            .iter()
+            .map(|&(name, le, v)| (name.to_string(), le.to_string(), v))
+            .collect();
+        assert_eq!(buckets, expected);
+
+        let mut bad = req.clone();
+        bad.timeseries[0].labels_refs = vec![1, 7];
+        assert!(to_write_request(&bad).is_err());
+    }
+}
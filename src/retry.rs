@@ -0,0 +1,125 @@
+//! Retries with exponential backoff for the network clients: scrapes and
+//! remote-write pushes.
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::thread;
+use std::time::Duration;
+
+/// How often, and after how long, to retry a failed request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Retry {
+    /// Retries after the first attempt; 0 gives up at once.
+    pub retries: u32,
+    /// Wait before the first retry, doubled for every further one.
+    pub backoff: Duration,
+    /// Cap on the wait between two attempts.
+    pub max_backoff: Duration,
+}
+
+impl Default for Retry {
+    fn default() -> Self {
+        Retry {
+            retries: 0,
+            backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+        }
+    }
+}
+
+impl Retry {
+    /// The wait before retry `n`, counting from 0, without jitter.
+    pub fn backoff(&self, n: u32) -> Duration {
+        self.backoff
+            .checked_mul(1 << n.min(31))
+            .map_or(self.max_backoff, |d| d.min(self.max_backoff))
+    }
+
+    /// Calls `f` until it succeeds, fails with an error `transient` rejects,
+    /// or the retries run out, and returns its last result. Waits are drawn
+    /// at random from the upper half of [`Retry::backoff`], so that clients
+    /// failing together do not retry in lockstep.
+    pub fn run<T, E, F, P>(&self, transient: P, mut f: F) -> Result<T, E>
+    where
+        F: FnMut() -> Result<T, E>,
+        P: Fn(&E) -> bool,
+    {
+        let mut n = 0;
+        loop {
+            match f() {
+                Err(e) if n < self.retries && transient(&e) => {
+                    thread::sleep(jitter(self.backoff(n)));
+                    n += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+pub(crate) fn jitter(d: Duration) -> Duration {
+    d / 2 + d.mul_f64(random_fraction() / 2.0)
+}
+
+/// A random number in [0, 1).
+pub(crate) fn random_fraction() -> f64 {
+    // Hashers of a fresh RandomState are randomly keyed.
+    let random = RandomState::new().build_hasher().finish();
+    (random >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_retry() {
+        let retry = Retry {
+            retries: 3,
+            backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(3),
+        };
+        let waits: Vec<_> = (0..4).map(|n| retry.backoff(n).as_millis()).collect();
+        assert_eq!(waits, [1, 2, 3, 3]);
+        assert_eq!(retry.backoff(100), retry.max_backoff);
+        for _ in 0..100 {
+            let d = jitter(Duration::from_millis(10));
+            assert!(d >= Duration::from_millis(5) && d <= Duration::from_millis(10));
+        }
+
+        // Transient errors are retried until the retries run out.
+        let mut calls = 0;
+        let result: Result<(), u32> = retry.run(
+            |e| *e < 500,
+            || {
+                calls += 1;
+                Err(calls)
+            },
+        );
+        assert_eq!((result, calls), (Err(4), 4));
+
+        // Others end the attempts at once, as does success.
+        let mut calls = 0;
+        let result: Result<(), u32> = retry.run(
+            |_| false,
+            || {
+                calls += 1;
+                Err(calls)
+            },
+        );
+        assert_eq!(result, Err(1));
+        let mut calls = 0;
+        let result = retry.run(
+            |_: &u32| true,
+            || {
+                calls += 1;
+                if calls < 3 {
+                    Err(calls)
+                } else {
+                    Ok(calls)
+                }
+            },
+        );
+        assert_eq!(result, Ok(3));
+    }
+}
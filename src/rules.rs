@@ -0,0 +1,664 @@
+//! Recording rules evaluated offline against one or two snapshots, for
+//! reports. Expressions are a small part of PromQL:
+//!
+//! * series selectors, such as `http_requests_total{code="500"}`, and numbers;
+//! * `rate(selector)` and `increase(selector)` between the previous snapshot
+//!   and the current one; a range such as `[5m]` is accepted and ignored;
+//! * `sum`, `avg`, `min`, `max` and `count`, with `by (...)` or
+//!   `without (...)` before or after their argument;
+//! * `+`, `-`, `*` and `/`, between two vectors matched on all their labels
+//!   or a vector and a number.
+//!
+//! Selectors read the current snapshot, including the series recorded by
+//! the rules before them.
+
+use prometheus::proto::{LabelPair, Metric, MetricFamily, MetricType};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fmt;
+use std::time::Duration;
+
+use crate::aggregate::{aggregate, AggOp, Grouping};
+use crate::binop::{binary_op, BinOp};
+use crate::flatten::flatten;
+use crate::grammar::{is_valid_metric_name_continuation, is_valid_metric_name_start};
+use crate::grep::{Selector, NAME_LABEL};
+
+#[derive(Debug)]
+pub enum RuleError {
+    Yaml(serde_yaml::Error),
+    Invalid(String),
+    Eval { record: String, msg: String },
+}
+
+impl fmt::Display for RuleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RuleError::Yaml(e) => write!(f, "invalid rules file: {}", e),
+            RuleError::Invalid(msg) => write!(f, "invalid rule: {}", msg),
+            RuleError::Eval { record, msg } => write!(f, "cannot record {}: {}", record, msg),
+        }
+    }
+}
+
+impl Error for RuleError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            RuleError::Yaml(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawRules {
+    rules: Vec<RawRule>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawRule {
+    record: String,
+    expr: String,
+}
+
+/// A rule recording the result of `expr` as the gauge family `record`.
+#[derive(Debug, Clone)]
+pub struct Rule {
+    pub record: String,
+    pub expr: Node,
+}
+
+/// Rules loaded from YAML as
+/// `{rules: [{record: path:requests:rate, expr: "sum by (path) (rate(requests_total))"}]}`,
+/// evaluated in order.
+#[derive(Debug, Clone)]
+pub struct Rules {
+    pub rules: Vec<Rule>,
+}
+
+impl Rules {
+    pub fn from_yaml(s: &str) -> Result<Rules, RuleError> {
+        let raw: RawRules = serde_yaml::from_str(s).map_err(RuleError::Yaml)?;
+        let rules = raw
+            .rules
+            .into_iter()
+            .map(|rule| {
+                if !rule.record.starts_with(is_valid_metric_name_start)
+                    || !rule.record.chars().all(is_valid_metric_name_continuation)
+                {
+                    return Err(RuleError::Invalid(format!(
+                        "{:?} is not a valid metric name",
+                        rule.record
+                    )));
+                }
+                let expr = Node::parse(&rule.expr)
+                    .map_err(|msg| RuleError::Invalid(format!("{}: {}", rule.record, msg)))?;
+                Ok(Rule {
+                    record: rule.record,
+                    expr,
+                })
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Rules { rules })
+    }
+
+    /// Evaluates every rule against `current`, returning the recorded
+    /// families in rule order.
+    ///
+    /// `rate` and `increase` need the `previous` snapshot. Counter resets,
+    /// where a value decreased, count from zero. `rate` divides by the time
+    /// between the timestamps of a series, or `interval` where a series has
+    /// none.
+    pub fn evaluate(
+        &self,
+        current: &[MetricFamily],
+        previous: Option<&[MetricFamily]>,
+        interval: Option<Duration>,
+    ) -> Result<Vec<MetricFamily>, RuleError> {
+        let mut ctx = Context {
+            current: samples(current),
+            previous: previous.map(samples),
+            interval,
+        };
+        let mut recorded = Vec::new();
+        for rule in &self.rules {
+            let eval_error = |msg| RuleError::Eval {
+                record: rule.record.clone(),
+                msg,
+            };
+            let vector = match ctx.eval(&rule.expr).map_err(eval_error)? {
+                Value::Vector(v) => without_name(v),
+                Value::Scalar(v) => Vector::from([(Vec::new(), v)]),
+            };
+            let mut mf = to_family(&vector);
+            mf.set_name(rule.record.clone());
+
+            for (labels, value) in vector {
+                let mut key = labels;
+                key.push((NAME_LABEL.to_string(), rule.record.clone()));
+                key.sort();
+                ctx.current.insert(key, (value, None));
+            }
+            recorded.push(mf);
+        }
+        Ok(recorded)
+    }
+}
+
+/// A parsed rule expression.
+#[derive(Debug, Clone)]
+pub enum Node {
+    Number(f64),
+    Select(Selector),
+    Rate(Selector),
+    Increase(Selector),
+    Aggregate {
+        op: AggOp,
+        grouping: Grouping,
+        expr: Box<Node>,
+    },
+    Binary {
+        op: BinOp,
+        left: Box<Node>,
+        right: Box<Node>,
+    },
+}
+
+impl Node {
+    pub fn parse(s: &str) -> Result<Node, String> {
+        let mut parser = Parser {
+            tokens: tokenize(s)?,
+            pos: 0,
+        };
+        let node = parser.expr()?;
+        match parser.next() {
+            None => Ok(node),
+            Some(token) => Err(format!("unexpected {} in {:?}", token, s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(f64),
+    Ident(String),
+    /// Label matchers with their braces.
+    Braces(String),
+    /// A range such as `[5m]`, which is ignored.
+    Range,
+    LParen,
+    RParen,
+    Comma,
+    Op(BinOp),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Token::Number(v) => write!(f, "{}", v),
+            Token::Ident(s) | Token::Braces(s) => write!(f, "{:?}", s),
+            Token::Range => write!(f, "range"),
+            Token::LParen => write!(f, "\"(\""),
+            Token::RParen => write!(f, "\")\""),
+            Token::Comma => write!(f, "\",\""),
+            Token::Op(op) => write!(f, "{:?}", op.as_str()),
+        }
+    }
+}
+
+fn tokenize(s: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = s.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let start = i;
+        i += 1;
+        match c {
+            _ if c.is_whitespace() => {}
+            '(' => tokens.push(Token::LParen),
+            ')' => tokens.push(Token::RParen),
+            ',' => tokens.push(Token::Comma),
+            '+' | '-' | '*' | '/' => tokens.push(Token::Op(c.to_string().parse()?)),
+            '{' | '[' => {
+                let close = if c == '{' { '}' } else { ']' };
+                let mut quoted = false;
+                while i < chars.len() && (quoted || chars[i] != close) {
+                    match chars[i] {
+                        '\\' if quoted => i += 1,
+                        '"' => quoted = !quoted,
+                        _ => {}
+                    }
+                    i += 1;
+                }
+                if i == chars.len() {
+                    return Err(format!("missing {:?} in {:?}", close, s));
+                }
+                i += 1;
+                tokens.push(if c == '{' {
+                    Token::Braces(chars[start..i].iter().collect())
+                } else {
+                    Token::Range
+                });
+            }
+            _ if c.is_ascii_digit() || c == '.' => {
+                while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '.') {
+                    i += 1;
+                }
+                let number: String = chars[start..i].iter().collect();
+                let v = number
+                    .parse()
+                    .map_err(|_| format!("invalid number {:?}", number))?;
+                tokens.push(Token::Number(v));
+            }
+            _ if is_valid_metric_name_start(c) => {
+                while i < chars.len() && is_valid_metric_name_continuation(chars[i]) {
+                    i += 1;
+                }
+                tokens.push(Token::Ident(chars[start..i].iter().collect()));
+            }
+            _ => return Err(format!("unexpected {:?} in {:?}", c, s)),
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn expect(&mut self, expected: Token) -> Result<(), String> {
+        match self.next() {
+            Some(token) if token == expected => Ok(()),
+            Some(token) => Err(format!("expected {}, got {}", expected, token)),
+            None => Err(format!("expected {}, got the end", expected)),
+        }
+    }
+
+    /// Sums and differences of products and quotients, left to right.
+    fn expr(&mut self) -> Result<Node, String> {
+        let mut node = self.term()?;
+        while let Some(&Token::Op(op @ (BinOp::Add | BinOp::Sub))) = self.peek() {
+            self.pos += 1;
+            node = Node::Binary {
+                op,
+                left: Box::new(node),
+                right: Box::new(self.term()?),
+            };
+        }
+        Ok(node)
+    }
+
+    fn term(&mut self) -> Result<Node, String> {
+        let mut node = self.operand()?;
+        while let Some(&Token::Op(op @ (BinOp::Mul | BinOp::Div))) = self.peek() {
+            self.pos += 1;
+            node = Node::Binary {
+                op,
+                left: Box::new(node),
+                right: Box::new(self.operand()?),
+            };
+        }
+        Ok(node)
+    }
+
+    fn operand(&mut self) -> Result<Node, String> {
+        match self.next() {
+            Some(Token::Number(v)) => Ok(Node::Number(v)),
+            Some(Token::Op(BinOp::Sub)) => match self.next() {
+                Some(Token::Number(v)) => Ok(Node::Number(-v)),
+                _ => Err("expected a number after \"-\"".to_string()),
+            },
+            Some(Token::LParen) => {
+                let node = self.expr()?;
+                self.expect(Token::RParen)?;
+                Ok(node)
+            }
+            Some(Token::Braces(matchers)) => Ok(Node::Select(matchers.parse()?)),
+            Some(Token::Ident(name)) => match name.as_str() {
+                "sum" | "avg" | "min" | "max" | "count" => self.aggregation(&name),
+                "rate" | "increase" if self.peek() == Some(&Token::LParen) => {
+                    self.pos += 1;
+                    let selector = self.selector()?;
+                    if self.peek() == Some(&Token::Range) {
+                        self.pos += 1;
+                    }
+                    self.expect(Token::RParen)?;
+                    Ok(if name == "rate" {
+                        Node::Rate(selector)
+                    } else {
+                        Node::Increase(selector)
+                    })
+                }
+                _ => {
+                    self.pos -= 1;
+                    Ok(Node::Select(self.selector()?))
+                }
+            },
+            Some(token) => Err(format!("unexpected {}", token)),
+            None => Err("unexpected end of expression".to_string()),
+        }
+    }
+
+    fn selector(&mut self) -> Result<Selector, String> {
+        let selector = match self.next() {
+            Some(Token::Ident(name)) => match self.peek() {
+                Some(Token::Braces(matchers)) => {
+                    let s = format!("{}{}", name, matchers);
+                    self.pos += 1;
+                    s
+                }
+                _ => name,
+            },
+            Some(Token::Braces(matchers)) => matchers,
+            Some(token) => return Err(format!("expected a series selector, got {}", token)),
+            None => return Err("expected a series selector".to_string()),
+        };
+        selector.parse()
+    }
+
+    fn aggregation(&mut self, name: &str) -> Result<Node, String> {
+        let op = match name {
+            "sum" => AggOp::Sum,
+            "avg" => AggOp::Avg,
+            "min" => AggOp::Min,
+            "max" => AggOp::Max,
+            _ => AggOp::Count,
+        };
+        let mut grouping = self.grouping()?;
+        self.expect(Token::LParen)?;
+        let expr = self.expr()?;
+        self.expect(Token::RParen)?;
+        if grouping.is_none() {
+            grouping = self.grouping()?;
+        }
+        Ok(Node::Aggregate {
+            op,
+            grouping: grouping.unwrap_or(Grouping::By(Vec::new())),
+            expr: Box::new(expr),
+        })
+    }
+
+    /// An optional `by (...)` or `without (...)`.
+    fn grouping(&mut self) -> Result<Option<Grouping>, String> {
+        let by = match self.peek() {
+            Some(Token::Ident(word)) if word == "by" => true,
+            Some(Token::Ident(word)) if word == "without" => false,
+            _ => return Ok(None),
+        };
+        self.pos += 1;
+        self.expect(Token::LParen)?;
+        let mut labels = Vec::new();
+        loop {
+            match self.next() {
+                Some(Token::RParen) if labels.is_empty() => break,
+                Some(Token::Ident(label)) => labels.push(label),
+                _ => return Err("expected a label name".to_string()),
+            }
+            match self.next() {
+                Some(Token::Comma) => {}
+                Some(Token::RParen) => break,
+                _ => return Err("expected \",\" or \")\"".to_string()),
+            }
+        }
+        Ok(Some(if by {
+            Grouping::By(labels)
+        } else {
+            Grouping::Without(labels)
+        }))
+    }
+}
+
+/// Sample values by sorted labels, `__name__` included for selected series.
+type Vector = BTreeMap<Vec<(String, String)>, f64>;
+
+enum Value {
+    Scalar(f64),
+    Vector(Vector),
+}
+
+/// Every sample of a snapshot, with its timestamp, by sorted labels with
+/// `__name__`.
+type Samples = HashMap<Vec<(String, String)>, (f64, Option<i64>)>;
+
+fn samples(mfs: &[MetricFamily]) -> Samples {
+    flatten(mfs)
+        .into_iter()
+        .map(|s| {
+            let mut key = s.labels;
+            key.push((NAME_LABEL.to_string(), s.name));
+            key.sort();
+            (key, (s.value, s.timestamp_ms))
+        })
+        .collect()
+}
+
+struct Context {
+    current: Samples,
+    previous: Option<Samples>,
+    interval: Option<Duration>,
+}
+
+impl Context {
+    fn eval(&self, node: &Node) -> Result<Value, String> {
+        match node {
+            Node::Number(v) => Ok(Value::Scalar(*v)),
+            Node::Select(selector) => Ok(Value::Vector(
+                self.current
+                    .iter()
+                    .filter(|(key, _)| matches(selector, key))
+                    .map(|(key, &(value, _))| (key.clone(), value))
+                    .collect(),
+            )),
+            Node::Rate(selector) => self.increase(selector, true),
+            Node::Increase(selector) => self.increase(selector, false),
+            Node::Aggregate { op, grouping, expr } => {
+                let v = match self.eval(expr)? {
+                    Value::Vector(v) => without_name(v),
+                    Value::Scalar(_) => return Err(format!("{} of a number", op.as_str())),
+                };
+                Ok(Value::Vector(from_family(
+                    &aggregate(&[to_family(&v)], *op, grouping)[0],
+                )))
+            }
+            Node::Binary { op, left, right } => match (self.eval(left)?, self.eval(right)?) {
+                (Value::Scalar(a), Value::Scalar(b)) => Ok(Value::Scalar(op.apply(a, b))),
+                (Value::Vector(a), Value::Scalar(b)) => Ok(Value::Vector(
+                    without_name(a)
+                        .into_iter()
+                        .map(|(k, a)| (k, op.apply(a, b)))
+                        .collect(),
+                )),
+                (Value::Scalar(a), Value::Vector(b)) => Ok(Value::Vector(
+                    without_name(b)
+                        .into_iter()
+                        .map(|(k, b)| (k, op.apply(a, b)))
+                        .collect(),
+                )),
+                (Value::Vector(a), Value::Vector(b)) => {
+                    let all = Grouping::Without(Vec::new());
+                    let mf = binary_op(
+                        &to_family(&without_name(a)),
+                        *op,
+                        &to_family(&without_name(b)),
+                        &all,
+                        "",
+                    )
+                    .map_err(|e| e.to_string())?;
+                    Ok(Value::Vector(from_family(&mf)))
+                }
+            },
+        }
+    }
+
+    /// The increase of the series `selector` matches since the previous
+    /// snapshot, per second if `per_second`.
+    fn increase(&self, selector: &Selector, per_second: bool) -> Result<Value, String> {
+        let previous = self
+            .previous
+            .as_ref()
+            .ok_or("rate and increase need a previous snapshot")?;
+        let mut out = Vector::new();
+        for (key, &(value, ts)) in &self.current {
+            let Some(&(before, before_ts)) = previous.get(key) else {
+                continue;
+            };
+            if !matches(selector, key) {
+                continue;
+            }
+            let increase = if value < before {
+                value
+            } else {
+                value - before
+            };
+            if !per_second {
+                out.insert(key.clone(), increase);
+                continue;
+            }
+            let seconds = match (ts, before_ts, self.interval) {
+                (Some(ts), Some(before_ts), _) => (ts - before_ts) as f64 / 1000.0,
+                (_, _, Some(interval)) => interval.as_secs_f64(),
+                _ => return Err("rate needs timestamps or the interval between snapshots".into()),
+            };
+            if seconds > 0.0 {
+                out.insert(key.clone(), increase / seconds);
+            }
+        }
+        Ok(Value::Vector(out))
+    }
+}
+
+fn matches(selector: &Selector, key: &[(String, String)]) -> bool {
+    let name = key
+        .iter()
+        .find(|(n, _)| n == NAME_LABEL)
+        .map_or("", |(_, v)| v.as_str());
+    selector.matches_series(name, key)
+}
+
+/// Drops `__name__`, as PromQL does for computed series.
+fn without_name(v: Vector) -> Vector {
+    v.into_iter()
+        .map(|(key, value)| {
+            let key = key.into_iter().filter(|(n, _)| n != NAME_LABEL).collect();
+            (key, value)
+        })
+        .collect()
+}
+
+fn to_family(v: &Vector) -> MetricFamily {
+    let mut mf = MetricFamily::default();
+    mf.set_field_type(MetricType::GAUGE);
+    for (key, &value) in v {
+        let mut m = Metric::default();
+        m.set_label(
+            key.iter()
+                .map(|(name, value)| {
+                    let mut pair = LabelPair::new();
+                    pair.set_name(name.clone());
+                    pair.set_value(value.clone());
+                    pair
+                })
+                .collect(),
+        );
+        m.mut_gauge().set_value(value);
+        mf.mut_metric().push(m);
+    }
+    mf
+}
+
+fn from_family(mf: &MetricFamily) -> Vector {
+    mf.get_metric()
+        .iter()
+        .map(|m| {
+            let mut key: Vec<(String, String)> = m
+                .get_label()
+                .iter()
+                .map(|l| (l.get_name().to_string(), l.get_value().to_string()))
+                .collect();
+            key.sort();
+            (key, m.get_gauge().get_value())
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::{read_metric_families, Format};
+    use crate::text_encode::metric_families_to_text;
+
+    #[test]
+    fn test_rules() {
+        let before = read_metric_families(
+            Format::Text,
+            "# TYPE http_requests_total counter\n\
+             http_requests_total{path=\"/\",code=\"200\"} 100\n\
+             http_requests_total{path=\"/\",code=\"500\"} 10\n\
+             http_requests_total{path=\"/api\",code=\"200\"} 50\n"
+                .as_bytes(),
+        )
+        .unwrap();
+        let after = read_metric_families(
+            Format::Text,
+            "# TYPE http_requests_total counter\n\
+             http_requests_total{path=\"/\",code=\"200\"} 160\n\
+             http_requests_total{path=\"/\",code=\"500\"} 13\n\
+             http_requests_total{path=\"/api\",code=\"200\"} 20\n"
+                .as_bytes(),
+        )
+        .unwrap();
+        let rules = Rules::from_yaml(
+            r#"
+rules:
+  - record: path:http_requests:rate
+    expr: sum by (path) (rate(http_requests_total[5m]))
+  - record: path:http_errors:ratio
+    expr: sum(increase(http_requests_total{code=~"5.."})) without (code) / sum without (code) (increase(http_requests_total))
+  - record: requests:total
+    expr: sum(http_requests_total) * 2 - 1
+"#,
+        )
+        .unwrap();
+
+        let recorded = rules
+            .evaluate(&after, Some(&before), Some(Duration::from_secs(60)))
+            .unwrap();
+        let mut out = Vec::new();
+        metric_families_to_text(&mut out, &recorded).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            r#"# TYPE path:http_requests:rate gauge
+path:http_requests:rate{path="/"} 1.05
+path:http_requests:rate{path="/api"} 0.3333333333333333
+# TYPE path:http_errors:ratio gauge
+path:http_errors:ratio{path="/"} 0.047619047619047616
+# TYPE requests:total gauge
+requests:total 385
+"#
+        );
+
+        assert!(matches!(
+            rules.evaluate(&after, None, None),
+            Err(RuleError::Eval { .. })
+        ));
+        assert!(Node::parse("sum by (path (x)").is_err());
+        assert!(Node::parse("rate(x) +").is_err());
+        assert!(Rules::from_yaml("rules: [{record: '1x', expr: x}]").is_err());
+    }
+}
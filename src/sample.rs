@@ -0,0 +1,277 @@
+use prometheus::proto::{LabelPair, Metric, MetricFamily};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+
+/// The label [`budget`] marks the series of sampled families with, set to
+/// the sampling rate, e.g. `1/16`.
+pub const SAMPLED_LABEL: &str = "pmv_sampled";
+
+/// How many series `sample` keeps.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SampleSize {
+    /// Up to this many series of every family.
+    PerFamily(usize),
+    /// About this fraction, between 0 and 1, of all series.
+    Fraction(f64),
+}
+
+/// Picks a subset of the series of `mfs`. Each series is ranked by a hash
+/// of `seed`, its family and its sorted labels, so the same seed picks the
+/// same series regardless of input order. With a fraction, a series picked
+/// from one scrape is also picked from later scrapes of the same target.
+/// Families left without series are dropped; picked series keep their
+/// input order.
+pub fn sample(mfs: &[MetricFamily], size: SampleSize, seed: u64) -> Vec<MetricFamily> {
+    mfs.iter()
+        .filter_map(|mf| {
+            let ranks: Vec<u64> = mf
+                .get_metric()
+                .iter()
+                .map(|m| rank(seed, mf.get_name(), m))
+                .collect();
+            let keep: Vec<bool> = match size {
+                SampleSize::PerFamily(k) => {
+                    let mut sorted = ranks.clone();
+                    sorted.sort_unstable();
+                    match k.checked_sub(1).and_then(|i| sorted.get(i)) {
+                        Some(&max) => ranks.iter().map(|&r| r <= max).collect(),
+                        None if k == 0 => vec![false; ranks.len()],
+                        None => vec![true; ranks.len()],
+                    }
+                }
+                SampleSize::Fraction(f) => {
+                    let cutoff = (f.clamp(0.0, 1.0) * u64::MAX as f64) as u64;
+                    ranks.iter().map(|&r| r < cutoff || f >= 1.0).collect()
+                }
+            };
+
+            let metrics: Vec<Metric> = mf
+                .get_metric()
+                .iter()
+                .zip(keep)
+                .filter(|(_, keep)| *keep)
+                .map(|(m, _)| m.clone())
+                .collect();
+            if metrics.is_empty() {
+                return None;
+            }
+            let mut mf = mf.clone();
+            mf.set_metric(metrics.into());
+            Some(mf)
+        })
+        .collect()
+}
+
+/// How many series every family may keep.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Budget {
+    pub max_series: usize,
+    /// Budgets of particular families, instead of `max_series`.
+    #[serde(default)]
+    pub families: BTreeMap<String, usize>,
+    /// Different seeds pick different series.
+    #[serde(default)]
+    pub seed: u64,
+}
+
+impl Budget {
+    /// The budget of `family`.
+    pub fn max_series(&self, family: &str) -> usize {
+        self.families
+            .get(family)
+            .copied()
+            .unwrap_or(self.max_series)
+    }
+}
+
+/// Keeps families within their budget as they are, and samples those
+/// over it at a rate of 1 in N: the series whose hash, as in [`sample`],
+/// falls in the lowest Nth of the range, N the smallest power of two that
+/// brings the family within budget. A series kept from one scrape is kept
+/// from the next as long as N stays the same. Kept series of sampled
+/// families are labeled `pmv_sampled="1/N"`, so that consumers can scale
+/// sums and counts by N; rates of families sampled before multiply.
+pub fn budget(mfs: &[MetricFamily], budget: &Budget) -> Vec<MetricFamily> {
+    mfs.iter()
+        .filter_map(|mf| {
+            let max = budget.max_series(mf.get_name());
+            let len = mf.get_metric().len();
+            if len <= max {
+                return Some(mf.clone());
+            }
+            if max == 0 {
+                return None;
+            }
+
+            let ranks: Vec<u64> = mf
+                .get_metric()
+                .iter()
+                .map(|m| rank(budget.seed, mf.get_name(), m))
+                .collect();
+            let mut rate = len.div_ceil(max).next_power_of_two() as u64;
+            let kept = |rate: u64| ranks.iter().filter(|&&r| r < u64::MAX / rate).count();
+            // Hashes are only about uniform; sample sparser until within budget.
+            while kept(rate) > max {
+                rate *= 2;
+            }
+
+            let metrics: Vec<Metric> = mf
+                .get_metric()
+                .iter()
+                .zip(&ranks)
+                .filter(|(_, &r)| r < u64::MAX / rate)
+                .map(|(m, _)| mark_sampled(m, rate))
+                .collect();
+            if metrics.is_empty() {
+                return None;
+            }
+            let mut mf = mf.clone();
+            mf.set_metric(metrics.into());
+            Some(mf)
+        })
+        .collect()
+}
+
+fn mark_sampled(m: &Metric, rate: u64) -> Metric {
+    let mut m = m.clone();
+    let labels = m.mut_label();
+    let before = labels
+        .iter()
+        .position(|l| l.get_name() == SAMPLED_LABEL)
+        .map(|i| labels.remove(i));
+    let before = before
+        .and_then(|l| l.get_value().strip_prefix("1/")?.parse::<u64>().ok())
+        .unwrap_or(1);
+
+    let mut label = LabelPair::new();
+    label.set_name(SAMPLED_LABEL.to_string());
+    label.set_value(format!("1/{}", before.saturating_mul(rate)));
+    labels.push(label);
+    m
+}
+
+fn rank(seed: u64, family: &str, m: &Metric) -> u64 {
+    let mut labels: Vec<(&str, &str)> = m
+        .get_label()
+        .iter()
+        .map(|l| (l.get_name(), l.get_value()))
+        .collect();
+    labels.sort_unstable();
+
+    // FNV-1a, with a zero byte after every string so that label names and
+    // values cannot run into each other, then mixed so that nearby hashes
+    // spread over the whole range.
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325 ^ seed;
+    let mut write = |s: &str| {
+        for b in s.bytes().chain([0]) {
+            hash ^= b as u64;
+            hash = hash.wrapping_mul(0x100_0000_01b3);
+        }
+    };
+    write(family);
+    for (name, value) in labels {
+        write(name);
+        write(value);
+    }
+    mix(hash)
+}
+
+// The splitmix64 finalizer.
+fn mix(mut x: u64) -> u64 {
+    x ^= x >> 30;
+    x = x.wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x ^= x >> 27;
+    x = x.wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::{read_metric_families, Format};
+
+    fn series_count(mfs: &[MetricFamily]) -> usize {
+        mfs.iter().map(|mf| mf.get_metric().len()).sum()
+    }
+
+    #[test]
+    fn test_sample() {
+        let mut text = String::from("# TYPE a counter\n");
+        for i in 0..1000 {
+            text.push_str(&format!("a{{i=\"{}\"}} 1\n", i));
+        }
+        text.push_str("# TYPE b gauge\nb{i=\"0\"} 1\nb{i=\"1\"} 2\n");
+        let mfs = read_metric_families(Format::Text, text.as_bytes()).unwrap();
+
+        let picked = sample(&mfs, SampleSize::PerFamily(3), 7);
+        assert_eq!(picked[0].get_metric().len(), 3);
+        assert_eq!(picked[1].get_metric().len(), 2);
+        // The same seed picks the same series from reordered input.
+        let mut reversed = mfs.clone();
+        reversed[0].mut_metric().reverse();
+        let mut again = sample(&reversed, SampleSize::PerFamily(3), 7);
+        again[0].mut_metric().reverse();
+        assert_eq!(again, picked);
+        assert_ne!(sample(&mfs, SampleSize::PerFamily(3), 8), picked);
+        assert!(sample(&mfs, SampleSize::PerFamily(0), 7).is_empty());
+
+        let tenth = series_count(&sample(&mfs, SampleSize::Fraction(0.1), 7));
+        assert!((70..=130).contains(&tenth), "{}", tenth);
+        assert_eq!(
+            series_count(&sample(&mfs, SampleSize::Fraction(1.0), 7)),
+            1002
+        );
+        assert!(sample(&mfs, SampleSize::Fraction(0.0), 7).is_empty());
+    }
+
+    #[test]
+    fn test_budget() {
+        let mut text = String::from("# TYPE a counter\n");
+        for i in 0..1000 {
+            text.push_str(&format!("a{{i=\"{}\"}} 1\n", i));
+        }
+        text.push_str("# TYPE b gauge\nb{i=\"0\"} 1\nb{i=\"1\",pmv_sampled=\"1/4\"} 2\n");
+        let mfs = read_metric_families(Format::Text, text.as_bytes()).unwrap();
+
+        let mut limits = Budget {
+            max_series: 100,
+            families: BTreeMap::new(),
+            seed: 0,
+        };
+        let out = budget(&mfs, &limits);
+        let kept = out[0].get_metric().len();
+        assert!((30..=100).contains(&kept), "{}", kept);
+        let rates: Vec<_> = out[0]
+            .get_metric()
+            .iter()
+            .map(|m| m.get_label().last().unwrap().get_value())
+            .collect();
+        assert!(rates.iter().all(|&r| r == rates[0]) && rates[0].starts_with("1/"));
+        assert_eq!(out[1], mfs[1]);
+
+        // The same series are kept from a later scrape with other values.
+        let mut later = mfs.clone();
+        later[0].mut_metric()[0].mut_counter().set_value(2.0);
+        let again = budget(&later, &limits);
+        assert_eq!(series_count(&again), series_count(&out));
+
+        // Sampling sampled series multiplies the rates.
+        let rate = |mf: &MetricFamily| -> u64 {
+            let label = mf.get_metric()[0].get_label().last().unwrap();
+            assert_eq!(label.get_name(), SAMPLED_LABEL);
+            label.get_value()[2..].parse().unwrap()
+        };
+        limits.max_series = 10;
+        let twice = budget(&out, &limits);
+        assert!(rate(&twice[0]) > rate(&out[0]));
+        assert_eq!(rate(&twice[0]) % rate(&out[0]), 0);
+
+        limits.families.insert("b".to_string(), 1);
+        let out = budget(&mfs, &limits);
+        assert_eq!(out[1].get_metric().len(), 1);
+        assert_eq!(rate(&out[1]), 4);
+        limits.families.insert("b".to_string(), 0);
+        assert_eq!(budget(&mfs, &limits).len(), 1);
+    }
+}
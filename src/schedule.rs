@@ -0,0 +1,230 @@
+//! Recurring scrapes of a set of targets, each at its own interval, for
+//! running pmv as a small agent.
+
+use std::fmt;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::retry::random_fraction;
+use crate::scrape::{self, ScrapeOptions, Target, TargetScrape};
+
+/// Targets to scrape and how often.
+#[derive(Debug, Clone, Default)]
+pub struct Scheduler {
+    options: ScrapeOptions,
+    targets: Vec<(Target, Duration)>,
+}
+
+impl Scheduler {
+    pub fn new(options: ScrapeOptions) -> Self {
+        Scheduler {
+            options,
+            targets: Vec::new(),
+        }
+    }
+
+    /// Scrapes `target` every `interval`.
+    pub fn target(mut self, target: Target, interval: Duration) -> Self {
+        self.targets.push((target, interval));
+        self
+    }
+
+    /// Starts scraping every target on a thread of its own, and calls
+    /// `deliver` with every result, labeled as by
+    /// [`scrape::scrape_targets`]. A target's first scrape comes at a random
+    /// point within its first interval, so that targets added together are
+    /// not scraped in bursts; from then on, scrapes keep to the interval,
+    /// and skip the ticks a slow scrape overran. To receive the results on
+    /// a channel, pass a closure that sends them.
+    pub fn start<F>(self, deliver: F) -> Schedule
+    where
+        F: Fn(TargetScrape) + Send + Sync + 'static,
+    {
+        let mut schedule = Schedule {
+            options: Arc::new(self.options),
+            deliver: Arc::new(deliver),
+            running: Vec::new(),
+        };
+        schedule.update(self.targets);
+        schedule
+    }
+}
+
+/// Sleeps until `deadline`; returns false, early, if the target stopped.
+fn wait_until(stop: &(Mutex<bool>, Condvar), deadline: Instant) -> bool {
+    let (stopped, wake) = stop;
+    let mut stopped = stopped.lock().expect("schedule state poisoned");
+    loop {
+        if *stopped {
+            return false;
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return true;
+        }
+        stopped = wake
+            .wait_timeout(stopped, deadline - now)
+            .expect("schedule state poisoned")
+            .0;
+    }
+}
+
+type Deliver = dyn Fn(TargetScrape) + Send + Sync;
+
+/// Running scrapes, started by [`Scheduler::start`]. Dropping it stops
+/// them, waiting for scrapes in flight to end.
+pub struct Schedule {
+    options: Arc<ScrapeOptions>,
+    deliver: Arc<Deliver>,
+    running: Vec<Running>,
+}
+
+/// The thread scraping one target.
+struct Running {
+    target: Target,
+    interval: Duration,
+    stop: Arc<(Mutex<bool>, Condvar)>,
+    thread: JoinHandle<()>,
+}
+
+impl Running {
+    fn start(
+        target: Target,
+        interval: Duration,
+        options: &Arc<ScrapeOptions>,
+        deliver: &Arc<Deliver>,
+    ) -> Self {
+        let stop = Arc::new((Mutex::new(false), Condvar::new()));
+        let (scraped, options, deliver) =
+            (target.clone(), Arc::clone(options), Arc::clone(deliver));
+        let thread_stop = Arc::clone(&stop);
+        let thread = thread::spawn(move || {
+            let interval = interval.max(Duration::from_millis(1));
+            let mut next = Instant::now() + interval.mul_f64(random_fraction());
+            let targets = [scraped];
+            while wait_until(&thread_stop, next) {
+                for scrape in scrape::scrape_targets(&targets, &options, 1) {
+                    deliver(scrape);
+                }
+                while next <= Instant::now() {
+                    next += interval;
+                }
+            }
+        });
+        Running {
+            target,
+            interval,
+            stop,
+            thread,
+        }
+    }
+
+    fn signal_stop(&self) {
+        let (stopped, wake) = &*self.stop;
+        *stopped.lock().expect("schedule state poisoned") = true;
+        wake.notify_all();
+    }
+}
+
+impl Schedule {
+    /// Scrapes `targets` from now on, as after reloading a targets file.
+    /// Targets kept with the same interval carry on undisturbed; the others
+    /// start as in [`Scheduler::start`], and dropped ones stop, which this
+    /// waits for.
+    pub fn update(&mut self, targets: Vec<(Target, Duration)>) {
+        let (kept, dropped): (Vec<_>, Vec<_>) = self.running.drain(..).partition(|r| {
+            targets
+                .iter()
+                .any(|(t, i)| *t == r.target && *i == r.interval)
+        });
+        self.running = kept;
+        stop_all(dropped);
+        for (target, interval) in targets {
+            let running = self
+                .running
+                .iter()
+                .any(|r| r.target == target && r.interval == interval);
+            if !running {
+                let started = Running::start(target, interval, &self.options, &self.deliver);
+                self.running.push(started);
+            }
+        }
+    }
+
+    /// The targets being scraped and their intervals.
+    pub fn targets(&self) -> impl Iterator<Item = (&Target, Duration)> {
+        self.running.iter().map(|r| (&r.target, r.interval))
+    }
+
+    /// Stops scraping; no result is delivered after this returns.
+    pub fn stop(self) {}
+}
+
+fn stop_all(running: Vec<Running>) {
+    // Signalled first, so that slow scrapes end together.
+    for r in &running {
+        r.signal_stop();
+    }
+    for r in running {
+        // A panic in `deliver` has already been reported.
+        let _ = r.thread.join();
+    }
+}
+
+impl Drop for Schedule {
+    fn drop(&mut self) {
+        stop_all(std::mem::take(&mut self.running));
+    }
+}
+
+impl fmt::Debug for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Schedule")
+            .field("targets", &self.targets().collect::<Vec<_>>())
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::sync::mpsc;
+
+    #[test]
+    fn test_schedule() {
+        // Nothing listens on port 1, so every scrape fails at once.
+        let target = |job: &str| Target {
+            url: "http://127.0.0.1:1/metrics".to_string(),
+            job: job.to_string(),
+            ..Target::default()
+        };
+        let (tx, rx) = mpsc::channel();
+        let mut schedule = Scheduler::new(ScrapeOptions::default())
+            .target(target("fast"), Duration::from_millis(10))
+            .target(target("slow"), Duration::from_secs(3600))
+            .start(move |scrape| tx.send(scrape).unwrap());
+
+        let mut jobs: HashMap<String, usize> = HashMap::new();
+        for scrape in rx.iter().take(3) {
+            assert!(scrape.result.is_err());
+            *jobs.entry(scrape.target.job).or_default() += 1;
+        }
+        // The slow target may have had its first scrape.
+        assert!(jobs["fast"] >= 2);
+
+        // Dropping the fast target leaves the slow one running.
+        schedule.update(vec![(target("slow"), Duration::from_secs(3600))]);
+        let jobs: Vec<_> = schedule.targets().map(|(t, _)| t.job.as_str()).collect();
+        assert_eq!(jobs, ["slow"]);
+
+        // Stopping does not wait out the slow target's interval, and drops
+        // the channel's sender once the scrapes in flight have ended.
+        let started = Instant::now();
+        schedule.stop();
+        assert!(started.elapsed() < Duration::from_secs(60));
+        rx.try_iter().for_each(drop);
+        assert!(rx.recv().is_err());
+    }
+}
@@ -0,0 +1,731 @@
+use base64::Engine;
+use flate2::read::GzDecoder;
+use prometheus::proto::MetricFamily;
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use crate::diagnostic::Diagnostic;
+use crate::format::{self, Format};
+use crate::merge::add_label;
+use crate::model;
+use crate::retry::Retry;
+
+#[cfg(feature = "async-scrape")]
+mod hyper_client;
+#[cfg(unix)]
+mod unix;
+
+#[cfg(feature = "async-scrape")]
+pub use hyper_client::scrape_url_async;
+
+/// The `Accept` header scrapes send: every format pmv decodes, preferring
+/// protobuf and OpenMetrics as Prometheus does.
+pub const ACCEPT: &str = "application/vnd.google.protobuf;\
+                          proto=io.prometheus.client.MetricFamily;encoding=delimited;q=0.7,\
+                          application/openmetrics-text;version=1.0.0;q=0.6,\
+                          text/plain;version=0.0.4;q=0.5,*/*;q=0.1";
+
+/// How [`scrape_url`] talks to the target.
+#[derive(Debug, Clone)]
+pub struct ScrapeOptions {
+    /// Limit on connecting and on reading the whole response.
+    pub timeout: Duration,
+    /// Extra request headers, sent after, and so replacing, any
+    /// `Authorization` header from `auth`.
+    pub headers: Vec<(String, String)>,
+    pub auth: Option<Auth>,
+    /// Retries of requests that failed to connect, timed out, or got a
+    /// 429 or 5xx answer; `timeout` applies to every attempt.
+    pub retry: Retry,
+}
+
+impl Default for ScrapeOptions {
+    fn default() -> Self {
+        ScrapeOptions {
+            timeout: Duration::from_secs(10),
+            headers: Vec::new(),
+            auth: None,
+            retry: Retry::default(),
+        }
+    }
+}
+
+/// Credentials for targets behind authentication.
+#[derive(Debug, Clone)]
+pub enum Auth {
+    /// HTTP basic authentication.
+    Basic { username: String, password: Secret },
+    /// `Authorization: Bearer <token>`.
+    Bearer(Secret),
+}
+
+impl Auth {
+    fn header(&self) -> Result<String, ScrapeError> {
+        Ok(match self {
+            Auth::Basic { username, password } => {
+                let pair = format!("{}:{}", username, password.read()?);
+                format!(
+                    "Basic {}",
+                    base64::engine::general_purpose::STANDARD.encode(pair)
+                )
+            }
+            Auth::Bearer(token) => format!("Bearer {}", token.read()?),
+        })
+    }
+}
+
+/// A password or token. Files are read at every scrape, so that rotated
+/// credentials are picked up, and trailing line breaks are ignored.
+#[derive(Clone)]
+pub enum Secret {
+    Value(String),
+    File(PathBuf),
+}
+
+impl Secret {
+    fn read(&self) -> Result<String, ScrapeError> {
+        match self {
+            Secret::Value(value) => Ok(value.clone()),
+            Secret::File(path) => std::fs::read_to_string(path)
+                .map(|s| s.trim_end_matches(['\r', '\n']).to_string())
+                .map_err(|e| ScrapeError::Credentials(path.clone(), e)),
+        }
+    }
+}
+
+// Keeps credentials out of logs.
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Secret::Value(_) => f.write_str("Value(<redacted>)"),
+            Secret::File(path) => f.debug_tuple("File").field(path).finish(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ScrapeError {
+    /// The request could not be sent or the response not read.
+    Request(Box<ureq::Error>),
+    /// The target answered with a status other than 2xx.
+    Status(u16, String),
+    /// The body is not valid in the format of its `Content-Type`.
+    Parse(Diagnostic),
+    /// A credentials file could not be read.
+    Credentials(PathBuf, io::Error),
+    /// Talking to a target on a Unix domain socket failed.
+    Socket(PathBuf, io::Error),
+    /// [`scrape_url_async`] could not send the request or read the
+    /// response.
+    Connection(io::Error),
+}
+
+impl ScrapeError {
+    /// Whether trying again later might succeed: the target was not
+    /// reached, was too slow, or answered 429 or 5xx.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            ScrapeError::Request(e) => matches!(**e, ureq::Error::Transport(_)),
+            ScrapeError::Socket(..) | ScrapeError::Connection(_) => true,
+            ScrapeError::Status(code, _) => *code == 429 || *code >= 500,
+            ScrapeError::Parse(_) | ScrapeError::Credentials(..) => false,
+        }
+    }
+}
+
+impl fmt::Display for ScrapeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ScrapeError::Request(e) => write!(f, "scrape failed: {}", e),
+            ScrapeError::Status(code, text) => {
+                write!(f, "scrape failed: server returned {} {}", code, text)
+            }
+            ScrapeError::Parse(e) => write!(f, "invalid scrape response: {}", e),
+            ScrapeError::Credentials(path, e) => {
+                write!(f, "cannot read credentials from {}: {}", path.display(), e)
+            }
+            ScrapeError::Socket(path, e) => write!(f, "scrape failed: {}: {}", path.display(), e),
+            ScrapeError::Connection(e) => write!(f, "scrape failed: {}", e),
+        }
+    }
+}
+
+impl Error for ScrapeError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ScrapeError::Request(e) => Some(e.as_ref()),
+            ScrapeError::Status(..) => None,
+            ScrapeError::Parse(e) => Some(e),
+            ScrapeError::Credentials(_, e) | ScrapeError::Socket(_, e) => Some(e),
+            ScrapeError::Connection(e) => Some(e),
+        }
+    }
+}
+
+/// What a scrape returned.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Scrape {
+    /// The format the body was decoded as.
+    pub format: Format,
+    pub families: Vec<MetricFamily>,
+}
+
+impl Scrape {
+    /// Roughly how many bytes the families take in memory, as
+    /// [`model::Families::approx_byte_size`] counts them.
+    pub fn approx_byte_size(&self) -> usize {
+        self.families
+            .iter()
+            .map(|mf| model::MetricFamily::from(mf).approx_byte_size())
+            .sum()
+    }
+}
+
+/// Fetches `url` with a blocking GET and decodes the body, as it streams
+/// in, in the format its `Content-Type` names. Bodies without one, or with
+/// one pmv does not know, are read as the text format, as Prometheus does.
+/// Gzip-compressed responses are accepted and decompressed.
+/// Families from the text formats come back sorted by name. Only `http://`
+/// URLs are supported, and `unix:///path/to.sock:/metrics` for targets on
+/// a Unix domain socket, whose HTTP path defaults to `/metrics`.
+pub fn scrape_url(url: &str, options: &ScrapeOptions) -> Result<Scrape, ScrapeError> {
+    options
+        .retry
+        .run(ScrapeError::is_transient, || scrape_once(url, options))
+}
+
+fn scrape_once(url: &str, options: &ScrapeOptions) -> Result<Scrape, ScrapeError> {
+    let headers = request_headers(options)?;
+    let headers: Vec<(&str, &str)> = headers.iter().map(|(n, v)| (*n, v.as_str())).collect();
+    let (content_type, content_encoding, body): Body = match parse_unix_url(url) {
+        Some((socket, path)) => get_unix(socket, path, &headers, options.timeout)?,
+        None => {
+            let mut request = ureq::get(url).timeout(options.timeout);
+            for (name, value) in &headers {
+                request = request.set(name, value);
+            }
+            let response = match request.call() {
+                Ok(response) => response,
+                Err(ureq::Error::Status(code, response)) => {
+                    return Err(ScrapeError::Status(
+                        code,
+                        response.status_text().to_string(),
+                    ))
+                }
+                Err(e) => return Err(ScrapeError::Request(Box::new(e))),
+            };
+            let header = |name| response.header(name).map(str::to_string);
+            (
+                header("Content-Type"),
+                header("Content-Encoding"),
+                response.into_reader(),
+            )
+        }
+    };
+    decode(content_type.as_deref(), content_encoding.as_deref(), body)
+}
+
+/// The headers every scrape request sends.
+fn request_headers(options: &ScrapeOptions) -> Result<Vec<(&str, String)>, ScrapeError> {
+    let mut headers = vec![
+        ("Accept", ACCEPT.to_string()),
+        ("Accept-Encoding", "gzip".to_string()),
+        (
+            "X-Prometheus-Scrape-Timeout-Seconds",
+            options.timeout.as_secs_f64().to_string(),
+        ),
+    ];
+    if let Some(auth) = &options.auth {
+        headers.push(("Authorization", auth.header()?));
+    }
+    for (name, value) in &options.headers {
+        headers.push((name, value.clone()));
+    }
+    Ok(headers)
+}
+
+/// Decodes `body`, as it is read, in the format `content_type` names.
+fn decode(
+    content_type: Option<&str>,
+    content_encoding: Option<&str>,
+    body: Box<dyn Read + Send>,
+) -> Result<Scrape, ScrapeError> {
+    let format = content_type
+        .and_then(Format::from_content_type)
+        .unwrap_or(Format::Text);
+    let gzip =
+        content_encoding.is_some_and(|encoding| encoding.trim().eq_ignore_ascii_case("gzip"));
+    let body: Box<dyn Read + Send> = if gzip {
+        Box::new(GzDecoder::new(body))
+    } else {
+        body
+    };
+    let families = format::read_metric_families(format, body)
+        .map_err(|e| ScrapeError::Parse(Diagnostic::from_error(e.as_ref())))?;
+    Ok(Scrape { format, families })
+}
+
+/// Splits `unix:///path/to.sock:/metrics` into the socket path and the
+/// HTTP path, `/metrics` if none is given.
+fn parse_unix_url(url: &str) -> Option<(&Path, &str)> {
+    let rest = url.strip_prefix("unix://")?;
+    Some(match rest.split_once(":/") {
+        Some((socket, _)) => (Path::new(socket), &rest[socket.len() + 1..]),
+        None => (Path::new(rest), "/metrics"),
+    })
+}
+
+/// The content type, content encoding and body of a response.
+type Body = (Option<String>, Option<String>, Box<dyn Read + Send>);
+
+#[cfg(unix)]
+fn get_unix(
+    socket: &Path,
+    path: &str,
+    headers: &[(&str, &str)],
+    timeout: Duration,
+) -> Result<Body, ScrapeError> {
+    let response = unix::get(socket, path, headers, timeout)
+        .map_err(|e| ScrapeError::Socket(socket.to_path_buf(), e))?;
+    if !(200..300).contains(&response.status) {
+        return Err(ScrapeError::Status(response.status, response.reason));
+    }
+    let header = |name| response.header(name).map(str::to_string);
+    Ok((
+        header("Content-Type"),
+        header("Content-Encoding"),
+        response.body,
+    ))
+}
+
+#[cfg(not(unix))]
+fn get_unix(
+    socket: &Path,
+    _path: &str,
+    _headers: &[(&str, &str)],
+    _timeout: Duration,
+) -> Result<Body, ScrapeError> {
+    Err(ScrapeError::Socket(
+        socket.to_path_buf(),
+        io::Error::new(
+            io::ErrorKind::Unsupported,
+            "Unix domain sockets are not supported on this platform",
+        ),
+    ))
+}
+
+/// A target of [`scrape_targets`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Target {
+    pub url: String,
+    /// The `job` label of its series.
+    pub job: String,
+    /// Further labels of its series, set after `instance` and `job`, and
+    /// so able to replace them.
+    pub labels: Vec<(String, String)>,
+}
+
+impl Target {
+    /// The `instance` label of its series: the host and port of the URL,
+    /// port 80 if it names none, as Prometheus sets it, or the path of a
+    /// Unix domain socket.
+    pub fn instance(&self) -> String {
+        if let Some((socket, _)) = parse_unix_url(&self.url) {
+            return socket.display().to_string();
+        }
+        let rest = self
+            .url
+            .split_once("://")
+            .map_or(&*self.url, |(_, rest)| rest);
+        let host = rest.split(['/', '?', '#']).next().unwrap_or("");
+        let host = host.rsplit_once('@').map_or(host, |(_, host)| host);
+        // The colons of an IPv6 address are inside its brackets.
+        if host
+            .rsplit_once(']')
+            .map_or(host, |(_, port)| port)
+            .contains(':')
+        {
+            host.to_string()
+        } else {
+            format!("{}:80", host)
+        }
+    }
+}
+
+/// What scraping one of the targets of [`scrape_targets`] gave.
+#[derive(Debug)]
+pub struct TargetScrape {
+    pub target: Target,
+    pub result: Result<Scrape, ScrapeError>,
+}
+
+/// Scrapes every target with [`scrape_url`], up to `workers` at a time,
+/// and returns their results in the order of `targets`. The series of
+/// every target get its `instance`, `job` and further labels, replacing
+/// any they expose.
+pub fn scrape_targets(
+    targets: &[Target],
+    options: &ScrapeOptions,
+    workers: usize,
+) -> Vec<TargetScrape> {
+    let next = AtomicUsize::new(0);
+    let scrape = || {
+        let mut results = Vec::new();
+        loop {
+            let i = next.fetch_add(1, Ordering::Relaxed);
+            let Some(target) = targets.get(i) else {
+                return results;
+            };
+            let result = scrape_url(&target.url, options).map(|mut scrape| {
+                add_label(&mut scrape.families, "instance", &target.instance());
+                add_label(&mut scrape.families, "job", &target.job);
+                for (name, value) in &target.labels {
+                    add_label(&mut scrape.families, name, value);
+                }
+                scrape
+            });
+            results.push((i, result));
+        }
+    };
+
+    let mut results: Vec<_> = thread::scope(|scope| {
+        let handles: Vec<_> = (0..workers.clamp(1, targets.len().max(1)))
+            .map(|_| scope.spawn(scrape))
+            .collect();
+        handles
+            .into_iter()
+            .flat_map(|h| h.join().expect("scrape worker panicked"))
+            .collect()
+    });
+    results.sort_by_key(|(i, _)| *i);
+    results
+        .into_iter()
+        .map(|(i, result)| TargetScrape {
+            target: targets[i].clone(),
+            result,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    // Answers one request with `status`, `headers` whose values are not
+    // empty and `body`, returning the URL and the request's Accept,
+    // Authorization and Accept-Encoding headers.
+    fn serve_once(
+        status: u16,
+        headers: &[(&str, &str)],
+        body: impl Into<Vec<u8>>,
+    ) -> (String, thread::JoinHandle<Vec<String>>) {
+        let body = body.into();
+        let headers: Vec<tiny_http::Header> = headers
+            .iter()
+            .filter(|(_, value)| !value.is_empty())
+            .map(|(name, value)| tiny_http::Header::from_bytes(*name, *value).unwrap())
+            .collect();
+        let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/metrics", server.server_addr().to_ip().unwrap());
+        let handle = thread::spawn(move || {
+            let request = server.recv().unwrap();
+            let received = ["Accept", "Authorization", "Accept-Encoding"]
+                .iter()
+                .map(|name| {
+                    request
+                        .headers()
+                        .iter()
+                        .find(|h| h.field.equiv(name))
+                        .map(|h| h.value.to_string())
+                        .unwrap_or_default()
+                })
+                .collect();
+            let mut response = tiny_http::Response::from_data(body).with_status_code(status);
+            for header in headers {
+                response.add_header(header);
+            }
+            request.respond(response).unwrap();
+            received
+        });
+        (url, handle)
+    }
+
+    #[test]
+    fn test_scrape_url() {
+        let text = "# TYPE up gauge\nup 1\n# TYPE a counter\na 2\n";
+        let (url, handle) = serve_once(200, &[("Content-Type", "text/plain; version=0.0.4")], text);
+        let options = ScrapeOptions {
+            headers: vec![("Authorization".to_string(), "Bearer t".to_string())],
+            ..ScrapeOptions::default()
+        };
+        let scrape = scrape_url(&url, &options).unwrap();
+        assert_eq!(scrape.format, Format::Text);
+        let names: Vec<_> = scrape.families.iter().map(|mf| mf.get_name()).collect();
+        assert_eq!(names, vec!["a", "up"]);
+        assert_eq!(handle.join().unwrap(), vec![ACCEPT, "Bearer t", "gzip"]);
+
+        let (url, handle) = serve_once(503, &[], "");
+        let err = scrape_url(&url, &ScrapeOptions::default()).unwrap_err();
+        assert!(matches!(err, ScrapeError::Status(503, _)), "{}", err);
+        handle.join().unwrap();
+
+        // A 503 is retried, then the scrape succeeds.
+        let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/metrics", server.server_addr().to_ip().unwrap());
+        let handle = thread::spawn(move || {
+            for status in [503, 200] {
+                let response = tiny_http::Response::from_string("up 1\n").with_status_code(status);
+                server.recv().unwrap().respond(response).unwrap();
+            }
+        });
+        let options = ScrapeOptions {
+            retry: Retry {
+                retries: 2,
+                backoff: Duration::from_millis(1),
+                ..Retry::default()
+            },
+            ..ScrapeOptions::default()
+        };
+        assert_eq!(scrape_url(&url, &options).unwrap().families.len(), 1);
+        handle.join().unwrap();
+
+        let (url, handle) = serve_once(200, &[], "up{ 1\n");
+        match scrape_url(&url, &ScrapeOptions::default()).unwrap_err() {
+            ScrapeError::Parse(d) => assert_eq!((d.line, d.column), (Some(1), Some(5))),
+            err => panic!("{}", err),
+        }
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_scrape_targets() {
+        let (a, handle_a) = serve_once(200, &[], "up 1\n");
+        let (b, handle_b) = serve_once(200, &[], "up{job=\"exposed\"} 0\n");
+        let targets: Vec<_> = [a.as_str(), "http://127.0.0.1:1/metrics", b.as_str()]
+            .iter()
+            .map(|url| Target {
+                url: url.to_string(),
+                job: "node".to_string(),
+                ..Target::default()
+            })
+            .collect();
+        let results = scrape_targets(&targets, &ScrapeOptions::default(), 2);
+        handle_a.join().unwrap();
+        handle_b.join().unwrap();
+
+        let urls: Vec<_> = results.iter().map(|r| r.target.url.as_str()).collect();
+        assert_eq!(urls, [a.as_str(), "http://127.0.0.1:1/metrics", b.as_str()]);
+        assert!(results[1].result.as_ref().unwrap_err().is_transient());
+        for (r, value) in [(&results[0], 1.0), (&results[2], 0.0)] {
+            let mf = &r.result.as_ref().unwrap().families[0];
+            let m = &mf.get_metric()[0];
+            let mut labels: Vec<_> = m
+                .get_label()
+                .iter()
+                .map(|l| (l.get_name(), l.get_value()))
+                .collect();
+            labels.sort_unstable();
+            let instance = r
+                .target
+                .url
+                .trim_start_matches("http://")
+                .trim_end_matches("/metrics");
+            assert_eq!(labels, [("instance", instance), ("job", "node")]);
+            assert_eq!(m.get_untyped().get_value(), value);
+        }
+
+        let instance = |url: &str| {
+            Target {
+                url: url.to_string(),
+                ..Target::default()
+            }
+            .instance()
+        };
+        assert_eq!(instance("http://host/metrics"), "host:80");
+        assert_eq!(instance("http://u:p@[::1]:9100/m?x=1"), "[::1]:9100");
+        assert_eq!(instance("http://[::1]"), "[::1]:80");
+    }
+
+    #[test]
+    fn test_content_type() {
+        let mfs = format::read_metric_families(Format::Text, "# TYPE up gauge\nup 1\n".as_bytes())
+            .unwrap();
+        let cases = [
+            (
+                "application/vnd.google.protobuf; \
+                 proto=io.prometheus.client.MetricFamily; encoding=delimited",
+                Format::Protobuf,
+            ),
+            (
+                "application/openmetrics-text; version=1.0.0; charset=utf-8",
+                Format::OpenMetrics,
+            ),
+            ("text/plain; version=0.0.4; charset=utf-8", Format::Text),
+            ("", Format::Text),
+            ("application/octet-stream", Format::Text),
+        ];
+        for (content_type, expected) in cases {
+            let mut body = Vec::new();
+            format::write_metric_families(expected, &mut body, &mfs).unwrap();
+            let (url, handle) = serve_once(200, &[("Content-Type", content_type)], body);
+            let scrape = scrape_url(&url, &ScrapeOptions::default()).unwrap();
+            assert_eq!(scrape.format, expected, "{}", content_type);
+            assert_eq!(scrape.families, mfs, "{}", content_type);
+            handle.join().unwrap();
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unix_socket() {
+        use std::io::{BufRead, BufReader, Write};
+        use std::os::unix::net::UnixListener;
+
+        let socket = std::env::temp_dir().join(format!("pmv-scrape-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&socket);
+        let listener = UnixListener::bind(&socket).unwrap();
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            for line in BufReader::new(&stream).lines() {
+                let line = line.unwrap();
+                if line.is_empty() {
+                    break;
+                }
+                request.push(line);
+            }
+            (&stream)
+                .write_all(
+                    b"HTTP/1.1 200 OK\r\n\
+                      Content-Type: text/plain; version=0.0.4\r\n\
+                      Transfer-Encoding: chunked\r\n\r\n\
+                      5\r\nup 1\n\r\n5\r\nab 2\n\r\n0\r\n\r\n",
+                )
+                .unwrap();
+            request
+        });
+
+        let url = format!("unix://{}:/custom/path", socket.display());
+        let scrape = scrape_url(&url, &ScrapeOptions::default()).unwrap();
+        let names: Vec<_> = scrape.families.iter().map(|mf| mf.get_name()).collect();
+        assert_eq!(names, ["ab", "up"]);
+        let request = handle.join().unwrap();
+        assert_eq!(request[0], "GET /custom/path HTTP/1.1");
+        assert!(request.contains(&"Accept-Encoding: gzip".to_string()));
+
+        let target = Target {
+            url,
+            ..Target::default()
+        };
+        assert_eq!(target.instance(), socket.display().to_string());
+        std::fs::remove_file(&socket).unwrap();
+        let err = scrape_url(&target.url, &ScrapeOptions::default()).unwrap_err();
+        assert!(matches!(err, ScrapeError::Socket(..)) && err.is_transient());
+        assert_eq!(
+            parse_unix_url("unix:///run/a.sock"),
+            Some((Path::new("/run/a.sock"), "/metrics"))
+        );
+    }
+
+    #[test]
+    fn test_gzip() {
+        use flate2::write::GzEncoder;
+        use std::io::Write;
+
+        let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(b"# TYPE up gauge\nup 1\n").unwrap();
+        let body = encoder.finish().unwrap();
+        let (url, handle) = serve_once(200, &[("Content-Encoding", "gzip")], body);
+        let scrape = scrape_url(&url, &ScrapeOptions::default()).unwrap();
+        assert_eq!(
+            scrape.families[0].get_metric()[0].get_gauge().get_value(),
+            1.0
+        );
+        handle.join().unwrap();
+
+        // Uncompressed responses are still read as they are.
+        let (url, handle) = serve_once(200, &[("Content-Encoding", "identity")], "up 1\n");
+        let scrape = scrape_url(&url, &ScrapeOptions::default()).unwrap();
+        assert_eq!(scrape.families[0].get_name(), "up");
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_auth() {
+        let authorization = |auth: Auth| {
+            let (url, handle) = serve_once(200, &[], "up 1\n");
+            let options = ScrapeOptions {
+                auth: Some(auth),
+                ..ScrapeOptions::default()
+            };
+            let result = scrape_url(&url, &options).map(|_| handle.join().unwrap()[1].clone());
+            (result, url)
+        };
+
+        let basic = Auth::Basic {
+            username: "prometheus".to_string(),
+            password: Secret::Value("s3cret".to_string()),
+        };
+        assert_eq!(
+            authorization(basic).0.unwrap(),
+            "Basic cHJvbWV0aGV1czpzM2NyZXQ="
+        );
+
+        let path = std::env::temp_dir().join(format!("pmv-token-{}", std::process::id()));
+        std::fs::write(&path, "t0ken\n").unwrap();
+        let bearer = Auth::Bearer(Secret::File(path.clone()));
+        assert_eq!(authorization(bearer.clone()).0.unwrap(), "Bearer t0ken");
+        std::fs::remove_file(&path).unwrap();
+
+        // Without the file there is no request; release the server.
+        let (result, url) = authorization(bearer);
+        assert!(matches!(result, Err(ScrapeError::Credentials(..))));
+        let _ = ureq::get(&url).call();
+
+        assert_eq!(
+            format!("{:?}", Secret::Value("s3cret".to_string())),
+            "Value(<redacted>)"
+        );
+    }
+
+    #[cfg(feature = "async-scrape")]
+    #[test]
+    fn test_scrape_url_async() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+
+        // Large enough to arrive in several frames.
+        let text = format!("# TYPE up gauge\nup 1\n{}", "a 2\n".repeat(10_000));
+        let (url, handle) = serve_once(200, &[("Content-Type", "text/plain")], text);
+        let options = ScrapeOptions::default();
+        let scrape = runtime.block_on(scrape_url_async(&url, &options)).unwrap();
+        assert_eq!(scrape.format, Format::Text);
+        let names: Vec<_> = scrape.families.iter().map(|mf| mf.get_name()).collect();
+        assert_eq!(names, vec!["a", "up"]);
+        assert_eq!(handle.join().unwrap()[0], ACCEPT);
+
+        let (url, handle) = serve_once(404, &[], "");
+        let err = runtime
+            .block_on(scrape_url_async(&url, &options))
+            .unwrap_err();
+        assert!(matches!(err, ScrapeError::Status(404, _)), "{}", err);
+        handle.join().unwrap();
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/metrics", listener.local_addr().unwrap());
+        drop(listener);
+        let err = runtime
+            .block_on(scrape_url_async(&url, &options))
+            .unwrap_err();
+        assert!(matches!(err, ScrapeError::Connection(_)), "{}", err);
+        assert!(err.is_transient());
+    }
+}
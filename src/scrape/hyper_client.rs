@@ -0,0 +1,202 @@
+//! [`scrape_url_async`], on tokio and hyper, for applications that already
+//! run a tokio runtime. Built with the `async-scrape` feature.
+
+use bytes::{Buf, Bytes};
+use http::header::{CONTENT_ENCODING, CONTENT_TYPE, HOST};
+use http::{Request, Response, Uri};
+use http_body_util::{BodyExt, Empty};
+use hyper::body::Incoming;
+use hyper_util::rt::TokioIo;
+use std::io::{self, Read};
+use std::path::Path;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio::runtime::Handle;
+use tokio::time::{self, Instant};
+
+use super::{decode, parse_unix_url, request_headers, Scrape, ScrapeError, ScrapeOptions};
+use crate::retry::jitter;
+
+/// Like [`scrape_url`](super::scrape_url), but async: it must be awaited
+/// within a tokio runtime with IO and time enabled, and blocks none of its
+/// worker threads. The body is parsed as it streams in, on the runtime's
+/// blocking pool. Retries wait with `tokio::time::sleep`.
+pub async fn scrape_url_async(url: &str, options: &ScrapeOptions) -> Result<Scrape, ScrapeError> {
+    let mut n = 0;
+    loop {
+        match scrape_once(url, options).await {
+            Err(e) if n < options.retry.retries && e.is_transient() => {
+                time::sleep(jitter(options.retry.backoff(n))).await;
+                n += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+async fn scrape_once(url: &str, options: &ScrapeOptions) -> Result<Scrape, ScrapeError> {
+    let headers = request_headers(options)?;
+    let deadline = Instant::now() + options.timeout;
+
+    let response = match parse_unix_url(url) {
+        Some((socket, path)) => get_unix(socket, path, &headers, deadline)
+            .await
+            .map_err(|e| ScrapeError::Socket(socket.to_path_buf(), e))?,
+        None => get_tcp(url, &headers, deadline)
+            .await
+            .map_err(ScrapeError::Connection)?,
+    };
+    let status = response.status();
+    if !status.is_success() {
+        return Err(ScrapeError::Status(
+            status.as_u16(),
+            status.canonical_reason().unwrap_or("").to_string(),
+        ));
+    }
+
+    let header = |name| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string)
+    };
+    let (content_type, content_encoding) = (header(CONTENT_TYPE), header(CONTENT_ENCODING));
+    let body = BodyReader {
+        body: response.into_body(),
+        chunk: Bytes::new(),
+        runtime: Handle::current(),
+        deadline,
+    };
+    tokio::task::spawn_blocking(move || {
+        decode(
+            content_type.as_deref(),
+            content_encoding.as_deref(),
+            Box::new(body),
+        )
+    })
+    .await
+    .expect("scrape parser panicked")
+}
+
+async fn get_tcp(
+    url: &str,
+    headers: &[(&str, String)],
+    deadline: Instant,
+) -> io::Result<Response<Incoming>> {
+    let uri: Uri = url
+        .parse()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let authority = match (uri.scheme_str(), uri.authority()) {
+        (Some("http"), Some(authority)) => authority.clone(),
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("unsupported URL {}, only http:// is", url),
+            ))
+        }
+    };
+    let address = format!(
+        "{}:{}",
+        authority.host(),
+        authority.port_u16().unwrap_or(80)
+    );
+    let path = uri.path_and_query().map_or("/", |p| p.as_str());
+    let stream = time::timeout_at(deadline, TcpStream::connect(address))
+        .await
+        .map_err(|_| timed_out())??;
+    get(stream, authority.as_str(), path, headers, deadline).await
+}
+
+#[cfg(unix)]
+async fn get_unix(
+    socket: &Path,
+    path: &str,
+    headers: &[(&str, String)],
+    deadline: Instant,
+) -> io::Result<Response<Incoming>> {
+    let stream = time::timeout_at(deadline, tokio::net::UnixStream::connect(socket))
+        .await
+        .map_err(|_| timed_out())??;
+    get(stream, "localhost", path, headers, deadline).await
+}
+
+#[cfg(not(unix))]
+async fn get_unix(
+    _socket: &Path,
+    _path: &str,
+    _headers: &[(&str, String)],
+    _deadline: Instant,
+) -> io::Result<Response<Incoming>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Unix domain sockets are not supported on this platform",
+    ))
+}
+
+/// Sends `GET path` over `stream` and waits for the response head.
+async fn get<S>(
+    stream: S,
+    host: &str,
+    path: &str,
+    headers: &[(&str, String)],
+    deadline: Instant,
+) -> io::Result<Response<Incoming>>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let mut request = Request::get(path).header(HOST, host);
+    for (name, value) in headers {
+        request = request.header(*name, value);
+    }
+    let request = request
+        .body(Empty::<Bytes>::new())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+    let exchange = async {
+        let (mut sender, connection) = hyper::client::conn::http1::handshake(TokioIo::new(stream))
+            .await
+            .map_err(io::Error::other)?;
+        // Ends once the body is read or dropped.
+        tokio::spawn(connection);
+        sender.send_request(request).await.map_err(io::Error::other)
+    };
+    time::timeout_at(deadline, exchange)
+        .await
+        .map_err(|_| timed_out())?
+}
+
+fn timed_out() -> io::Error {
+    io::Error::new(io::ErrorKind::TimedOut, "scrape timed out")
+}
+
+/// Reads a response body from the blocking pool, waiting for every frame on
+/// the runtime, until the scrape's deadline.
+struct BodyReader {
+    body: Incoming,
+    chunk: Bytes,
+    runtime: Handle,
+    deadline: Instant,
+}
+
+impl Read for BodyReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while !self.chunk.has_remaining() {
+            let frame = self
+                .runtime
+                .block_on(time::timeout_at(self.deadline, self.body.frame()))
+                .map_err(|_| timed_out())?;
+            match frame {
+                None => return Ok(0),
+                Some(frame) => {
+                    if let Ok(data) = frame.map_err(io::Error::other)?.into_data() {
+                        self.chunk = data;
+                    }
+                }
+            }
+        }
+        let n = buf.len().min(self.chunk.len());
+        self.chunk.copy_to_slice(&mut buf[..n]);
+        Ok(n)
+    }
+}
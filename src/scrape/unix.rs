@@ -0,0 +1,105 @@
+//! A minimal HTTP/1.1 client for targets that only listen on a Unix domain
+//! socket, which ureq cannot connect to.
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::time::Duration;
+
+use chunked_transfer::Decoder;
+
+/// What the target answered.
+pub(super) struct Response {
+    pub status: u16,
+    pub reason: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Box<dyn Read + Send>,
+}
+
+impl Response {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Sends `GET path` with `headers` to the server listening on `socket`.
+/// `timeout` limits every read and write rather than the whole exchange.
+pub(super) fn get(
+    socket: &Path,
+    path: &str,
+    headers: &[(&str, &str)],
+    timeout: Duration,
+) -> io::Result<Response> {
+    let stream = UnixStream::connect(socket)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+
+    let mut request = format!(
+        "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n",
+        path
+    );
+    for (name, value) in headers {
+        request.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    request.push_str("\r\n");
+    (&stream).write_all(request.as_bytes())?;
+
+    let mut reader = BufReader::new(stream);
+    let status_line = read_line(&mut reader)?;
+    let mut parts = status_line.splitn(3, ' ');
+    let status = match (parts.next(), parts.next()) {
+        (Some(version), Some(code)) if version.starts_with("HTTP/1.") => code.parse().ok(),
+        _ => None,
+    }
+    .ok_or_else(|| invalid(format!("invalid status line {:?}", status_line)))?;
+    let reason = parts.next().unwrap_or("").to_string();
+
+    let mut headers = Vec::new();
+    loop {
+        let line = read_line(&mut reader)?;
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| invalid(format!("invalid header line {:?}", line)))?;
+        headers.push((name.trim().to_string(), value.trim().to_string()));
+    }
+
+    let mut response = Response {
+        status,
+        reason,
+        headers,
+        body: Box::new(io::empty()),
+    };
+    let chunked = response
+        .header("Transfer-Encoding")
+        .is_some_and(|encoding| encoding.eq_ignore_ascii_case("chunked"));
+    let length = response.header("Content-Length").map(str::parse::<u64>);
+    response.body = match (chunked, length) {
+        (true, _) => Box::new(Decoder::new(reader)),
+        (false, Some(Ok(length))) => Box::new(reader.take(length)),
+        (false, Some(Err(_))) => return Err(invalid("invalid Content-Length".to_string())),
+        // The server closes the connection after the body.
+        (false, None) => Box::new(reader),
+    };
+    Ok(response)
+}
+
+fn read_line<R: BufRead>(reader: &mut R) -> io::Result<String> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "connection closed before the response headers ended",
+        ));
+    }
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
@@ -0,0 +1,287 @@
+//! Per-series transforms written in [rhai](https://rhai.rs), for one-off
+//! munging that does not warrant a compiled plugin. Built with the
+//! `script` feature.
+//!
+//! The script runs once per series with these variables in scope:
+//!
+//! - `name`, the family name;
+//! - `labels`, a map of label names to values;
+//! - `value`, the value of counters, gauges and untyped series, or the
+//!   sample count of histograms and summaries;
+//! - `type`, the family type, such as `"counter"`.
+//!
+//! Changes to `name`, `labels` and `value` are kept, except to the value of
+//! histograms and summaries. Labels set to `""` are removed. A script
+//! evaluating to `false` drops the series:
+//!
+//! ```rhai
+//! if labels.env == "dev" { return false; }
+//! name.replace("legacy_", "");
+//! labels.remove("pod");
+//! if name.ends_with("_milliseconds") { value /= 1000.0; }
+//! ```
+//!
+//! `print` writes to stderr, leaving stdout to the metrics.
+
+use prometheus::proto::{LabelPair, Metric, MetricFamily, MetricType};
+use rhai::{Dynamic, Engine, Map, Scope, AST};
+use std::error::Error;
+use std::fmt;
+
+use crate::flatten::render_series;
+use crate::grammar::{
+    is_valid_label_name_continuation, is_valid_label_name_start, is_valid_metric_name_continuation,
+    is_valid_metric_name_start,
+};
+use crate::pipeline::{Families, Transform, TransformError};
+
+/// How many operations a script may take per series before it is stopped,
+/// so a runaway loop fails the run instead of hanging it.
+pub const MAX_OPERATIONS: u64 = 1_000_000;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ScriptError {
+    Compile(String),
+    /// Running the script on `series` failed, or left it invalid.
+    Run {
+        series: String,
+        msg: String,
+    },
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ScriptError::Compile(msg) => write!(f, "invalid script: {}", msg),
+            ScriptError::Run { series, msg } => write!(f, "script failed on {}: {}", series, msg),
+        }
+    }
+}
+
+impl Error for ScriptError {}
+
+/// A compiled script.
+pub struct Script {
+    name: String,
+    engine: Engine,
+    ast: AST,
+}
+
+impl fmt::Debug for Script {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Script").field("name", &self.name).finish()
+    }
+}
+
+impl Script {
+    /// Compiles `source`; `name` identifies the script in dry runs.
+    pub fn new(name: &str, source: &str) -> Result<Self, ScriptError> {
+        let mut engine = Engine::new();
+        engine.set_max_operations(MAX_OPERATIONS);
+        engine.on_print(|s| eprintln!("{}", s));
+        let ast = engine
+            .compile(source)
+            .map_err(|e| ScriptError::Compile(e.to_string()))?;
+        Ok(Script {
+            name: name.to_string(),
+            engine,
+            ast,
+        })
+    }
+
+    /// Runs the script on every series of `mfs`. Renamed series join the
+    /// family of their new name, which must be of the same type, and
+    /// families left without series are removed.
+    pub fn run(&self, mfs: &[MetricFamily]) -> Result<Vec<MetricFamily>, ScriptError> {
+        let mut out: Vec<MetricFamily> = Vec::new();
+        for mf in mfs {
+            for m in mf.get_metric() {
+                let Some((name, m)) = self.run_series(mf, m)? else {
+                    continue;
+                };
+                match out.iter_mut().find(|o| o.get_name() == name) {
+                    Some(o) if o.get_field_type() != mf.get_field_type() => {
+                        return Err(ScriptError::Run {
+                            series: series(mf.get_name(), &m),
+                            msg: format!(
+                                "renamed into {} of type {:?}, not {:?}",
+                                name,
+                                o.get_field_type(),
+                                mf.get_field_type()
+                            ),
+                        });
+                    }
+                    Some(o) => o.mut_metric().push(m),
+                    None => {
+                        let mut o = mf.clone();
+                        o.set_name(name);
+                        o.set_metric(vec![m].into());
+                        out.push(o);
+                    }
+                }
+            }
+        }
+        Ok(out)
+    }
+
+    /// The new family name and series, or `None` to drop it.
+    fn run_series(
+        &self,
+        mf: &MetricFamily,
+        m: &Metric,
+    ) -> Result<Option<(String, Metric)>, ScriptError> {
+        let metric_type = mf.get_field_type();
+        let failed = |msg: String| ScriptError::Run {
+            series: series(mf.get_name(), m),
+            msg,
+        };
+
+        let labels: Map = m
+            .get_label()
+            .iter()
+            .map(|l| (l.get_name().into(), l.get_value().to_string().into()))
+            .collect();
+        let value = match metric_type {
+            MetricType::COUNTER => m.get_counter().get_value(),
+            MetricType::GAUGE => m.get_gauge().get_value(),
+            MetricType::UNTYPED => m.get_untyped().get_value(),
+            MetricType::HISTOGRAM => m.get_histogram().get_sample_count() as f64,
+            MetricType::SUMMARY => m.get_summary().get_sample_count() as f64,
+        };
+        let mut scope = Scope::new();
+        scope.push("name", mf.get_name().to_string());
+        scope.push("labels", labels);
+        scope.push("value", value);
+        scope.push_constant("type", format!("{:?}", metric_type).to_lowercase());
+
+        let result = self
+            .engine
+            .eval_ast_with_scope::<Dynamic>(&mut scope, &self.ast)
+            .map_err(|e| failed(e.to_string()))?;
+        if result.as_bool() == Ok(false) {
+            return Ok(None);
+        }
+
+        let name = scope
+            .get_value::<Dynamic>("name")
+            .and_then(|d| d.into_string().ok())
+            .ok_or_else(|| failed("name is not a string".to_string()))?;
+        if !name.starts_with(is_valid_metric_name_start)
+            || !name.chars().all(is_valid_metric_name_continuation)
+        {
+            return Err(failed(format!("invalid metric name {:?}", name)));
+        }
+
+        let labels = scope
+            .get_value::<Map>("labels")
+            .ok_or_else(|| failed("labels is not a map".to_string()))?;
+        let mut pairs = Vec::new();
+        for (label, value) in labels {
+            if !label.starts_with(is_valid_label_name_start)
+                || !label.chars().all(is_valid_label_name_continuation)
+            {
+                return Err(failed(format!("invalid label name {:?}", label.as_str())));
+            }
+            let value = value.to_string();
+            if value.is_empty() {
+                continue;
+            }
+            let mut pair = LabelPair::new();
+            pair.set_name(label.to_string());
+            pair.set_value(value);
+            pairs.push(pair);
+        }
+
+        let value = scope
+            .get_value::<Dynamic>("value")
+            .and_then(|d| {
+                d.as_float()
+                    .ok()
+                    .or_else(|| d.as_int().ok().map(|i| i as f64))
+            })
+            .ok_or_else(|| failed("value is not a number".to_string()))?;
+
+        let mut m = m.clone();
+        m.set_label(pairs.into());
+        match metric_type {
+            MetricType::COUNTER => m.mut_counter().set_value(value),
+            MetricType::GAUGE => m.mut_gauge().set_value(value),
+            MetricType::UNTYPED => m.mut_untyped().set_value(value),
+            MetricType::HISTOGRAM | MetricType::SUMMARY => {}
+        }
+        Ok(Some((name, m)))
+    }
+}
+
+fn series(name: &str, m: &Metric) -> String {
+    let labels: Vec<(String, String)> = m
+        .get_label()
+        .iter()
+        .map(|l| (l.get_name().to_string(), l.get_value().to_string()))
+        .collect();
+    render_series(name, &labels)
+}
+
+impl Transform for Script {
+    fn name(&self) -> String {
+        format!("script {}", self.name)
+    }
+
+    fn apply(&self, families: &mut Families) -> Result<(), TransformError> {
+        *families = self.run(families)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::{read_metric_families, Format};
+    use crate::text_encode::metric_families_to_text;
+
+    #[test]
+    fn test_script() {
+        let mfs = read_metric_families(
+            Format::Text,
+            "# TYPE legacy_latency_milliseconds gauge\n\
+             legacy_latency_milliseconds{env=\"prod\",pod=\"a\"} 1500\n\
+             legacy_latency_milliseconds{env=\"dev\",pod=\"b\"} 20\n\
+             # TYPE latency_seconds gauge\nlatency_seconds{env=\"qa\"} 3\n\
+             # TYPE up untyped\nup 1\n"
+                .as_bytes(),
+        )
+        .unwrap();
+        let script = Script::new(
+            "inline",
+            r#"
+            if labels.env == "dev" { return false; }
+            labels.pod = "";
+            if name.starts_with("legacy_") {
+                name = "latency_seconds";
+                value /= 1000;
+            }
+            if type == "untyped" { value = 2; }
+            "#,
+        )
+        .unwrap();
+
+        let mut out = Vec::new();
+        metric_families_to_text(&mut out, &script.run(&mfs).unwrap()).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "# TYPE latency_seconds gauge\nlatency_seconds{env=\"qa\"} 3\n\
+             latency_seconds{env=\"prod\"} 1.5\n# TYPE up untyped\nup 2\n"
+        );
+
+        let bad = Script::new("bad", r#"name = "up""#).unwrap();
+        assert!(matches!(bad.run(&mfs), Err(ScriptError::Run { .. })));
+        let invalid = Script::new("invalid", r#"name = "1x""#).unwrap();
+        assert_eq!(
+            invalid.run(&mfs[2..]).unwrap_err().to_string(),
+            "script failed on up: invalid metric name \"1x\""
+        );
+        let runaway = Script::new("runaway", "loop {}").unwrap();
+        assert!(runaway.run(&mfs[2..]).is_err());
+        assert!(Script::new("broken", "if {").is_err());
+    }
+}
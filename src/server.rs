@@ -0,0 +1,798 @@
+use flate2::read::GzDecoder;
+use prometheus::proto::MetricFamily;
+use prometheus::{IntCounter, Registry};
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::Cursor;
+use std::io::{self, Read};
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tiny_http::{Header, Method, Request, Response};
+
+use crate::expiry::{ExpiryConfig, SeriesStore};
+use crate::grep::{self, Selector};
+#[cfg(feature = "grpc")]
+use crate::grpc::{self, OtlpService};
+use crate::index::LabelIndex;
+use crate::influx::{self, InfluxMapping, Precision};
+use crate::otlp::{self, OtlpConverter};
+use crate::pipeline::{self, Transform};
+use crate::ratelimit::{RateLimit, RateLimiter};
+use crate::remote_write::{self, Protocol, Written};
+use crate::text_encode;
+use crate::tsdb::{Storage, StorageConfig};
+
+const TEXT_CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+const PROTOBUF_CONTENT_TYPE: &str = "application/x-protobuf";
+
+/// Upper bound on the number of distinct `match[]` combinations whose
+/// rendering is kept between requests.
+const MAX_CACHED_SELECTIONS: usize = 64;
+
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    pub listen: String,
+    /// Length of one refresh interval for series expiry.
+    pub interval: Duration,
+    pub expiry: ExpiryConfig,
+    pub influx: InfluxMapping,
+    /// Token bucket applied per client address; `None` disables limiting.
+    pub rate_limit: Option<RateLimit>,
+    /// Directory to keep the history of received samples in; `None` keeps
+    /// only the latest values in memory.
+    pub storage_dir: Option<PathBuf>,
+    pub storage: StorageConfig,
+    /// How often stored samples are compacted.
+    pub compaction_interval: Duration,
+    /// Snapshots with at least this many series get a label index for
+    /// answering `match[]` selections.
+    pub index_min_series: usize,
+    /// Applied in order to every push before it is merged and stored.
+    pub transforms: Vec<Arc<dyn Transform>>,
+    /// Address to also accept OTLP metrics exports over gRPC on, with the
+    /// `grpc` feature; `None` takes OTLP over HTTP only.
+    pub otlp_grpc_listen: Option<String>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            listen: "127.0.0.1:9091".to_string(),
+            interval: Duration::from_secs(15),
+            expiry: ExpiryConfig::default(),
+            influx: InfluxMapping::default(),
+            rate_limit: None,
+            storage_dir: None,
+            storage: StorageConfig::default(),
+            compaction_interval: Duration::from_secs(300),
+            index_min_series: 10_000,
+            transforms: Vec::new(),
+            otlp_grpc_listen: None,
+        }
+    }
+}
+
+/// A snapshot of the store with its label index, reused for `match[]`
+/// selections until the store changes.
+struct IndexedSnapshot {
+    generation: u64,
+    mfs: Vec<MetricFamily>,
+    index: LabelIndex,
+}
+
+/// State shared by the request handlers: the merged series and pmv's own
+/// metrics.
+pub struct ServerState {
+    store: Mutex<SeriesStore>,
+    registry: Registry,
+    influx_mapping: InfluxMapping,
+    otlp: Mutex<OtlpConverter>,
+    // Rendered `match[]` selections by selector list, with the store
+    // generation they were rendered from.
+    selections: Mutex<HashMap<String, (u64, Vec<u8>)>>,
+    indexed: Mutex<Option<IndexedSnapshot>>,
+    index_min_series: usize,
+    limiter: Option<Mutex<RateLimiter>>,
+    storage: Option<Mutex<Storage>>,
+    transforms: Vec<Arc<dyn Transform>>,
+    remote_write_samples: IntCounter,
+    influx_points: IntCounter,
+    otlp_series: IntCounter,
+    rate_limited_requests: IntCounter,
+}
+
+impl ServerState {
+    pub fn new(config: &ServerConfig) -> Self {
+        let registry = Registry::new();
+
+        let store = SeriesStore::new(config.expiry.clone());
+        store
+            .register(&registry)
+            .expect("register series store metrics");
+
+        let remote_write_samples = IntCounter::new(
+            "pmv_remote_write_samples_total",
+            "Samples received through the remote-write endpoint.",
+        )
+        .expect("valid remote write counter");
+        registry
+            .register(Box::new(remote_write_samples.clone()))
+            .expect("register remote write counter");
+
+        let influx_points = IntCounter::new(
+            "pmv_influx_points_total",
+            "Points received through the InfluxDB line protocol endpoint.",
+        )
+        .expect("valid influx counter");
+        registry
+            .register(Box::new(influx_points.clone()))
+            .expect("register influx counter");
+
+        let otlp_series = IntCounter::new(
+            "pmv_otlp_series_total",
+            "Series received through the OTLP/HTTP endpoint.",
+        )
+        .expect("valid otlp counter");
+        registry
+            .register(Box::new(otlp_series.clone()))
+            .expect("register otlp counter");
+
+        let rate_limited_requests = IntCounter::new(
+            "pmv_rate_limited_requests_total",
+            "Requests refused because the client exceeded its rate limit.",
+        )
+        .expect("valid rate limit counter");
+        registry
+            .register(Box::new(rate_limited_requests.clone()))
+            .expect("register rate limit counter");
+
+        ServerState {
+            store: Mutex::new(store),
+            registry,
+            influx_mapping: config.influx.clone(),
+            otlp: Mutex::new(OtlpConverter::new()),
+            selections: Mutex::new(HashMap::new()),
+            indexed: Mutex::new(None),
+            index_min_series: config.index_min_series,
+            limiter: config.rate_limit.map(|l| Mutex::new(RateLimiter::new(l))),
+            storage: None,
+            transforms: config.transforms.clone(),
+            remote_write_samples,
+            influx_points,
+            otlp_series,
+            rate_limited_requests,
+        }
+    }
+
+    /// Also appends every received sample to `storage`.
+    pub fn with_storage(mut self, storage: Storage) -> Self {
+        self.storage = Some(Mutex::new(storage));
+        self
+    }
+
+    fn ingest(&self, mut mfs: Vec<MetricFamily>) {
+        if let Err(e) = pipeline::apply(&self.transforms, &mut mfs) {
+            log::error!("dropping pushed samples: {}", e);
+            return;
+        }
+        self.store.lock().unwrap().update(&mfs);
+
+        if let Some(storage) = &self.storage {
+            if let Err(e) = storage.lock().unwrap().append(&mfs, now_ms()) {
+                log::error!("failed to store samples: {}", e);
+            }
+        }
+    }
+
+    fn compact(&self) {
+        if let Some(storage) = &self.storage {
+            match storage.lock().unwrap().compact(now_ms()) {
+                Ok(stats) => log::debug!("compaction: {:?}", stats),
+                Err(e) => log::error!("compaction failed: {}", e),
+            }
+        }
+    }
+
+    /// Decodes a remote-write body sent with `protocol` and merges it into
+    /// the store.
+    pub fn remote_write(
+        &self,
+        body: &[u8],
+        protocol: Protocol,
+    ) -> Result<Written, remote_write::RemoteWriteError> {
+        let (req, written) = match protocol {
+            Protocol::V1 => {
+                let req = remote_write::decode_write_request(body)?;
+                let samples = req.timeseries.iter().map(|ts| ts.samples.len()).sum();
+                let written = Written {
+                    samples,
+                    ..Written::default()
+                };
+                (req, written)
+            }
+            Protocol::V2 => {
+                let req = remote_write::v2::decode_request(body)?;
+                (
+                    remote_write::v2::to_write_request(&req)?,
+                    remote_write::v2::written(&req),
+                )
+            }
+        };
+        let mfs = remote_write::write_request_to_metric_families(&req)?;
+
+        self.remote_write_samples.inc_by(written.samples as u64);
+
+        self.ingest(mfs);
+        Ok(written)
+    }
+
+    /// Parses a line protocol body and merges it into the store.
+    pub fn influx_write(
+        &self,
+        body: &str,
+        precision: Precision,
+    ) -> Result<(), influx::InfluxError> {
+        let points = influx::parse_lines(body)?;
+        let mfs = influx::points_to_metric_families(&points, &self.influx_mapping, precision);
+
+        self.influx_points.inc_by(points.len() as u64);
+
+        self.ingest(mfs);
+        Ok(())
+    }
+
+    /// Decodes an OTLP metrics export body and merges it into the store.
+    /// Delta temporality series are accumulated into cumulative ones.
+    pub fn otlp_write(&self, body: &[u8]) -> Result<(), prost::DecodeError> {
+        let req = otlp::decode_export_request(body)?;
+        let mfs = self.otlp.lock().unwrap().convert(&req);
+        self.otlp_ingest(mfs);
+        Ok(())
+    }
+
+    /// Merges families converted from OTLP into the store.
+    fn otlp_ingest(&self, mfs: Vec<MetricFamily>) {
+        let series: usize = mfs.iter().map(|mf| mf.get_metric().len()).sum();
+        self.otlp_series.inc_by(series as u64);
+
+        self.ingest(mfs);
+    }
+
+    /// Renders the merged series followed by pmv's own metrics.
+    pub fn metrics_text(&self) -> Vec<u8> {
+        let mut mfs = self.store.lock().unwrap().snapshot();
+        mfs.extend(self.registry.gather());
+
+        let mut out = Vec::new();
+        text_encode::metric_families_to_text(&mut out, &mfs).expect("write to Vec");
+        out
+    }
+
+    /// Renders the merged series matching any of the federation-style
+    /// `selectors`. pmv's own metrics are not included. Renderings are cached
+    /// until the store changes.
+    pub fn selected_metrics_text(&self, selectors: &[String]) -> Result<Vec<u8>, String> {
+        let mut sorted = selectors.to_vec();
+        sorted.sort();
+        sorted.dedup();
+        let key = sorted.join("\n");
+
+        let store = self.store.lock().unwrap();
+        let generation = store.generation();
+
+        let mut cache = self.selections.lock().unwrap();
+        if let Some((cached_generation, text)) = cache.get(&key) {
+            if *cached_generation == generation {
+                return Ok(text.clone());
+            }
+        }
+
+        let parsed = sorted
+            .iter()
+            .map(|s| s.parse::<Selector>())
+            .collect::<Result<Vec<_>, _>>()?;
+        let mfs = self.select(&store, generation, &parsed);
+        drop(store);
+
+        let mut out = Vec::new();
+        text_encode::metric_families_to_text(&mut out, &mfs).expect("write to Vec");
+
+        if cache.len() >= MAX_CACHED_SELECTIONS {
+            cache.retain(|_, (g, _)| *g == generation);
+            if cache.len() >= MAX_CACHED_SELECTIONS {
+                cache.clear();
+            }
+        }
+        cache.insert(key, (generation, out.clone()));
+
+        Ok(out)
+    }
+
+    /// Selects from the store's snapshot, through the label index once the
+    /// snapshot is large enough.
+    fn select(
+        &self,
+        store: &SeriesStore,
+        generation: u64,
+        selectors: &[Selector],
+    ) -> Vec<MetricFamily> {
+        let mut indexed = self.indexed.lock().unwrap();
+        if let Some(snapshot) = indexed.as_ref().filter(|s| s.generation == generation) {
+            return snapshot.index.select(&snapshot.mfs, selectors);
+        }
+
+        let mfs = store.snapshot();
+        let series: usize = mfs.iter().map(|mf| mf.get_metric().len()).sum();
+        if series < self.index_min_series {
+            *indexed = None;
+            return grep::select(&mfs, selectors);
+        }
+
+        let index = LabelIndex::new(&mfs);
+        let selected = index.select(&mfs, selectors);
+        *indexed = Some(IndexedSnapshot {
+            generation,
+            mfs,
+            index,
+        });
+        selected
+    }
+
+    /// Takes a rate limit token for `client`. Returns the time until the
+    /// next token when the client is over its limit.
+    fn check_rate_limit(&self, client: Option<IpAddr>) -> Result<(), Duration> {
+        let (limiter, client) = match (&self.limiter, client) {
+            (Some(limiter), Some(client)) => (limiter, client),
+            _ => return Ok(()),
+        };
+
+        let result = limiter.lock().unwrap().check(client, Instant::now());
+        if result.is_err() {
+            self.rate_limited_requests.inc();
+        }
+        result
+    }
+
+    fn tick(&self) -> usize {
+        self.store.lock().unwrap().tick()
+    }
+}
+
+/// Listens on `config.listen` and handles requests until the process exits.
+pub fn serve(config: ServerConfig) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut state = ServerState::new(&config);
+    if let Some(dir) = &config.storage_dir {
+        state = state.with_storage(Storage::open(dir, config.storage.clone())?);
+    }
+    let state = Arc::new(state);
+    let server = tiny_http::Server::http(&config.listen)?;
+    log::info!("listening on {}", config.listen);
+    if let Some(listen) = &config.otlp_grpc_listen {
+        let listener = std::net::TcpListener::bind(listen)?;
+        log::info!("accepting OTLP over gRPC on {}", listen);
+        serve_otlp_grpc(listener, Arc::clone(&state))?;
+    }
+
+    let ticker = Arc::clone(&state);
+    thread::spawn(move || loop {
+        thread::sleep(config.interval);
+        let removed = ticker.tick();
+        if removed > 0 {
+            log::debug!("expired {} series", removed);
+        }
+    });
+
+    if config.storage_dir.is_some() {
+        let compactor = Arc::clone(&state);
+        thread::spawn(move || loop {
+            thread::sleep(config.compaction_interval);
+            compactor.compact();
+        });
+    }
+
+    for request in server.incoming_requests() {
+        handle(&state, request);
+    }
+
+    Ok(())
+}
+
+/// Serves OTLP's `MetricsService/Export` on `listener` from a thread of its
+/// own, merging the exports into `state` as OTLP/HTTP pushes are.
+#[cfg(feature = "grpc")]
+fn serve_otlp_grpc(
+    listener: std::net::TcpListener,
+    state: Arc<ServerState>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let service = OtlpService::new(
+        None,
+        Box::new(move |mfs| {
+            state.otlp_ingest(mfs.to_vec());
+            Ok(())
+        }),
+    );
+    thread::spawn(move || {
+        if let Err(e) = grpc::server::Server::new()
+            .with_otlp_service(service)
+            .serve(listener)
+        {
+            log::error!("gRPC server failed: {}", e);
+        }
+    });
+    Ok(())
+}
+
+#[cfg(not(feature = "grpc"))]
+fn serve_otlp_grpc(
+    _listener: std::net::TcpListener,
+    _state: Arc<ServerState>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    Err("pmv was built without the grpc feature".into())
+}
+
+fn handle(state: &ServerState, mut request: Request) {
+    let start = Instant::now();
+    let client = request.remote_addr().map(|addr| addr.ip());
+    let url = request.url().to_string();
+    let (path, query) = url.split_once('?').unwrap_or((&url, ""));
+
+    let response = match state.check_rate_limit(client) {
+        Ok(()) => route(state, &mut request, path, query),
+        Err(retry_after) => {
+            let retry_after = retry_after.as_secs_f64().ceil().to_string();
+            Response::from_string("rate limit exceeded\n")
+                .with_status_code(429)
+                .with_header(Header::from_bytes("Retry-After", retry_after).expect("valid header"))
+        }
+    };
+
+    let method = request.method().clone();
+    let status = response.status_code().0;
+    let bytes = response.data_length().unwrap_or(0);
+    if let Err(e) = request.respond(response) {
+        log::warn!("failed to send response: {}", e);
+    }
+
+    log::info!(
+        target: "pmv::access",
+        "client={} method={} path={} match={:?} status={} bytes={} duration_ms={:.3}",
+        client.map_or_else(|| "-".to_string(), |ip| ip.to_string()),
+        method,
+        path,
+        query_params(query, "match[]").join(","),
+        status,
+        bytes,
+        start.elapsed().as_secs_f64() * 1000.0,
+    );
+}
+
+fn route(
+    state: &ServerState,
+    request: &mut Request,
+    path: &str,
+    query: &str,
+) -> Response<Cursor<Vec<u8>>> {
+    match (request.method(), path) {
+        (Method::Get, "/metrics") => {
+            let selectors = query_params(query, "match[]");
+            let text = if selectors.is_empty() {
+                Ok(state.metrics_text())
+            } else {
+                state.selected_metrics_text(&selectors)
+            };
+            match text {
+                Ok(text) => Response::from_data(text).with_header(content_type(TEXT_CONTENT_TYPE)),
+                Err(e) => Response::from_string(e + "\n").with_status_code(400),
+            }
+        }
+        (Method::Post, "/api/v1/write") => {
+            let content_type = request
+                .headers()
+                .iter()
+                .find(|h| h.field.equiv("Content-Type"))
+                .map(|h| h.value.as_str());
+            let protocol = match Protocol::from_content_type(content_type) {
+                Ok(protocol) => protocol,
+                Err(e) => return Response::from_string(e.to_string()).with_status_code(415),
+            };
+            match read_body(request, false) {
+                Ok(body) => match state.remote_write(&body, protocol) {
+                    Ok(written) => Response::from_data(Vec::new())
+                        .with_status_code(204)
+                        .with_header(written_header(
+                            remote_write::SAMPLES_WRITTEN_HEADER,
+                            written.samples,
+                        ))
+                        .with_header(written_header(
+                            remote_write::HISTOGRAMS_WRITTEN_HEADER,
+                            written.histograms,
+                        ))
+                        .with_header(written_header(
+                            remote_write::EXEMPLARS_WRITTEN_HEADER,
+                            written.exemplars,
+                        )),
+                    Err(e) => Response::from_string(e.to_string()).with_status_code(400),
+                },
+                Err(e) => Response::from_string(e.to_string()).with_status_code(400),
+            }
+        }
+        (Method::Post, "/write") | (Method::Post, "/api/v2/write") => {
+            let precision = match query_param(query, "precision") {
+                None => Some(Precision::Nanoseconds),
+                Some(p) => Precision::from_param(p),
+            };
+
+            let mut body = String::new();
+            match (precision, request.as_reader().read_to_string(&mut body)) {
+                (None, _) => Response::from_string("invalid precision\n").with_status_code(400),
+                (_, Err(e)) => Response::from_string(e.to_string()).with_status_code(400),
+                (Some(precision), Ok(_)) => match state.influx_write(&body, precision) {
+                    Ok(()) => Response::from_data(Vec::new()).with_status_code(204),
+                    Err(e) => Response::from_string(e.to_string()).with_status_code(400),
+                },
+            }
+        }
+        (Method::Post, "/v1/metrics") => {
+            let gzip = request.headers().iter().any(|h| {
+                h.field.equiv("Content-Encoding") && h.value.as_str().eq_ignore_ascii_case("gzip")
+            });
+            match read_body(request, gzip) {
+                Ok(body) => match state.otlp_write(&body) {
+                    // An empty ExportMetricsServiceResponse.
+                    Ok(()) => Response::from_data(Vec::new())
+                        .with_header(content_type(PROTOBUF_CONTENT_TYPE)),
+                    Err(e) => Response::from_string(e.to_string()).with_status_code(400),
+                },
+                Err(e) => Response::from_string(e.to_string()).with_status_code(400),
+            }
+        }
+        // Probes for orchestrators; pmv is ready to receive once listening.
+        (Method::Get, "/-/healthy") => Response::from_string("pmv is Healthy.\n"),
+        (Method::Get, "/-/ready") => Response::from_string("pmv is Ready.\n"),
+        _ => Response::from_string("not found\n").with_status_code(404),
+    }
+}
+
+fn read_body(request: &mut Request, gzip: bool) -> io::Result<Vec<u8>> {
+    let mut body = Vec::new();
+    if gzip {
+        GzDecoder::new(request.as_reader()).read_to_end(&mut body)?;
+    } else {
+        request.as_reader().read_to_end(&mut body)?;
+    }
+    Ok(body)
+}
+
+fn now_ms() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or(0)
+}
+
+fn query_param<'a>(query: &'a str, name: &str) -> Option<&'a str> {
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(k, _)| *k == name)
+        .map(|(_, v)| v)
+}
+
+/// Returns every percent-decoded value of the query parameter `name`.
+fn query_params(query: &str, name: &str) -> Vec<String> {
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .filter(|(k, _)| percent_decode(k) == name)
+        .map(|(_, v)| percent_decode(v))
+        .collect()
+}
+
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => out.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).ok();
+                match hex.and_then(|h| u8::from_str_radix(h, 16).ok()) {
+                    Some(b) => {
+                        out.push(b);
+                        i += 2;
+                    }
+                    None => out.push(b'%'),
+                }
+            }
+            b => out.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+fn content_type(value: &str) -> Header {
+    Header::from_bytes("Content-Type", value).expect("valid header")
+}
+
+fn written_header(name: &str, count: usize) -> Header {
+    Header::from_bytes(name, count.to_string()).expect("valid header")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::remote_write::{Label, Sample, TimeSeries, WriteRequest};
+    use prost::Message;
+
+    #[test]
+    fn test_remote_write_feeds_metrics() {
+        let state = ServerState::new(&ServerConfig::default());
+
+        let req = WriteRequest {
+            timeseries: vec![TimeSeries {
+                labels: vec![Label {
+                    name: "__name__".to_string(),
+                    value: "temperature".to_string(),
+                }],
+                samples: vec![Sample {
+                    value: 21.5,
+                    timestamp: 1000,
+                }],
+            }],
+            metadata: vec![],
+        };
+        let body = snap::raw::Encoder::new()
+            .compress_vec(&req.encode_to_vec())
+            .unwrap();
+        let written = state.remote_write(&body, Protocol::V1).unwrap();
+        assert_eq!(written.samples, 1);
+
+        let text = String::from_utf8(state.metrics_text()).unwrap();
+        assert!(text.contains("temperature 21.5 1000\n"));
+        assert!(text.contains("pmv_remote_write_samples_total 1\n"));
+    }
+
+    #[test]
+    fn test_transforms_apply_to_pushes() {
+        let transforms = crate::pipeline::transforms_from_yaml(
+            "- filter: {match: ['{floor=\"1\"}']}\n\
+             - relabel: [{target_label: site, replacement: hq}]\n",
+        )
+        .unwrap();
+        let state = ServerState::new(&ServerConfig {
+            transforms,
+            ..ServerConfig::default()
+        });
+
+        state
+            .influx_write(
+                "room,floor=1 temp=21.5 1\nroom,floor=2 temp=19 1\n",
+                Precision::Seconds,
+            )
+            .unwrap();
+        let text = String::from_utf8(state.metrics_text()).unwrap();
+        assert!(
+            text.contains("room_temp{floor=\"1\",site=\"hq\"} 21.5 1000\n"),
+            "{}",
+            text
+        );
+        assert!(!text.contains("floor=\"2\""));
+    }
+
+    #[test]
+    fn test_influx_write_feeds_metrics() {
+        let state = ServerState::new(&ServerConfig::default());
+
+        state
+            .influx_write("room,floor=1 temp=21.5 1700000000\n", Precision::Seconds)
+            .unwrap();
+        assert!(state.influx_write("bad", Precision::Seconds).is_err());
+
+        let text = String::from_utf8(state.metrics_text()).unwrap();
+        assert!(text.contains("room_temp{floor=\"1\"} 21.5 1700000000000\n"));
+        assert!(text.contains("pmv_influx_points_total 1\n"));
+    }
+
+    #[test]
+    fn test_otlp_write_feeds_metrics() {
+        use crate::otlp::{
+            ExportMetricsServiceRequest, GaugeData, Metric, MetricData, NumberDataPoint,
+            NumberValue, ResourceMetrics, ScopeMetrics,
+        };
+
+        let state = ServerState::new(&ServerConfig::default());
+
+        let req = ExportMetricsServiceRequest {
+            resource_metrics: vec![ResourceMetrics {
+                resource: None,
+                scope_metrics: vec![ScopeMetrics {
+                    metrics: vec![Metric {
+                        name: "queue.depth".to_string(),
+                        description: String::new(),
+                        unit: String::new(),
+                        data: Some(MetricData::Gauge(GaugeData {
+                            data_points: vec![NumberDataPoint {
+                                attributes: vec![],
+                                time_unix_nano: 0,
+                                value: Some(NumberValue::AsInt(5)),
+                            }],
+                        })),
+                    }],
+                }],
+            }],
+        };
+        state.otlp_write(&req.encode_to_vec()).unwrap();
+        assert!(state.otlp_write(b"\xff\xff").is_err());
+
+        let text = String::from_utf8(state.metrics_text()).unwrap();
+        assert!(text.contains("# TYPE queue_depth gauge\nqueue_depth 5\n"));
+        assert!(text.contains("pmv_otlp_series_total 1\n"));
+    }
+
+    #[cfg(feature = "grpc")]
+    #[test]
+    fn test_otlp_grpc_feeds_metrics() {
+        use crate::format::{read_metric_families, Format};
+        use crate::otlp::export::GRPC_EXPORT_PATH;
+
+        let state = Arc::new(ServerState::new(&ServerConfig::default()));
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        serve_otlp_grpc(listener, Arc::clone(&state)).unwrap();
+
+        let mfs = read_metric_families(Format::Text, "# TYPE up gauge\nup 1\n".as_bytes()).unwrap();
+        let req = otlp::metric_families_to_export_request(&mfs);
+        let (status, _) =
+            grpc::server::call_for_test(address, GRPC_EXPORT_PATH, &[req.encode_to_vec()]);
+        assert_eq!(status, 0);
+        let (status, _) =
+            grpc::server::call_for_test(address, GRPC_EXPORT_PATH, &[b"\xff".to_vec()]);
+        assert_eq!(status, 3);
+
+        let text = String::from_utf8(state.metrics_text()).unwrap();
+        assert!(text.contains("# TYPE up gauge\nup 1\n"), "{}", text);
+        assert!(text.contains("pmv_otlp_series_total 1\n"));
+    }
+
+    #[test]
+    fn test_selected_metrics_text() {
+        // Without and with the label index.
+        for index_min_series in [usize::MAX, 0] {
+            let config = ServerConfig {
+                index_min_series,
+                ..ServerConfig::default()
+            };
+            let state = ServerState::new(&config);
+            state
+                .influx_write(
+                    "node_cpu,mode=idle value=10\nnode_cpu,mode=user value=2\nload value=1\n",
+                    Precision::Seconds,
+                )
+                .unwrap();
+
+            let selectors =
+                query_params("match%5B%5D=node_cpu%7Bmode%3D%22idle%22%7D&x=1", "match[]");
+            assert_eq!(selectors, vec![r#"node_cpu{mode="idle"}"#.to_string()]);
+
+            let text = String::from_utf8(state.selected_metrics_text(&selectors).unwrap()).unwrap();
+            assert_eq!(
+                text,
+                "# TYPE node_cpu untyped\nnode_cpu{mode=\"idle\"} 10\n"
+            );
+
+            // A later write invalidates the cached rendering.
+            state
+                .influx_write("node_cpu,mode=idle value=11\n", Precision::Seconds)
+                .unwrap();
+            let text = String::from_utf8(state.selected_metrics_text(&selectors).unwrap()).unwrap();
+            assert!(text.contains("node_cpu{mode=\"idle\"} 11\n"));
+
+            assert!(state.selected_metrics_text(&["(".to_string()]).is_err());
+        }
+    }
+}
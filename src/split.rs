@@ -0,0 +1,131 @@
+use prometheus::proto::{Metric, MetricFamily};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::str::FromStr;
+
+/// How `split` groups series into files.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SplitBy {
+    /// One file per family.
+    Family,
+    /// One file per value of a label; series without it share one file.
+    Label(String),
+}
+
+impl FromStr for SplitBy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "family" => Ok(SplitBy::Family),
+            Some(("label", name)) if !name.is_empty() => Ok(SplitBy::Label(name.to_string())),
+            _ => Err(format!("expected family or label:NAME, got {:?}", s)),
+        }
+    }
+}
+
+/// The file stem for series missing the label `split` groups by. It cannot
+/// clash with an encoded value, which never has `%` without two hex digits.
+pub const MISSING_LABEL_STEM: &str = "%empty";
+
+/// Groups the series of `mfs` into documents keyed by file stem, sorted by
+/// stem. Families keep their HELP and TYPE in every document they appear in.
+pub fn split(
+    mfs: &[MetricFamily],
+    by: &SplitBy,
+) -> Result<Vec<(String, Vec<MetricFamily>)>, String> {
+    let name = match by {
+        SplitBy::Family => {
+            let mut docs = Vec::new();
+            for mf in mfs {
+                let name = mf.get_name();
+                // Valid metric names are safe file names, but JSON and
+                // protobuf inputs are not checked.
+                if name.is_empty() || name.starts_with('.') || name.contains(['/', '\\']) {
+                    return Err(format!("family name {:?} is not a safe file name", name));
+                }
+                docs.push((name.to_string(), vec![mf.clone()]));
+            }
+            docs.sort_by(|a, b| a.0.cmp(&b.0));
+            return Ok(docs);
+        }
+        SplitBy::Label(name) => name,
+    };
+
+    let mut docs: BTreeMap<String, Vec<MetricFamily>> = BTreeMap::new();
+    for mf in mfs {
+        let mut groups: BTreeMap<String, Vec<Metric>> = BTreeMap::new();
+        for m in mf.get_metric() {
+            let value = m
+                .get_label()
+                .iter()
+                .find(|l| l.get_name() == name)
+                .map(|l| l.get_value())
+                .unwrap_or("");
+            groups.entry(file_stem(value)).or_default().push(m.clone());
+        }
+        for (stem, metrics) in groups {
+            let mut part = mf.clone();
+            part.set_metric(metrics.into());
+            docs.entry(stem).or_default().push(part);
+        }
+    }
+    Ok(docs.into_iter().collect())
+}
+
+/// Percent-encodes a label value into a portable file name: bytes other
+/// than ASCII letters, digits, `_`, `-`, `:` and non-leading `.` are
+/// written as `%XX`.
+fn file_stem(value: &str) -> String {
+    if value.is_empty() {
+        return MISSING_LABEL_STEM.to_string();
+    }
+    let mut stem = String::new();
+    for (i, b) in value.bytes().enumerate() {
+        match b {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'_' | b'-' | b':' => stem.push(b as char),
+            b'.' if i > 0 => stem.push('.'),
+            _ => write!(stem, "%{:02X}", b).unwrap(),
+        }
+    }
+    stem
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::{read_metric_families, Format};
+
+    #[test]
+    fn test_split() {
+        let text = r#"# HELP up Target up.
+# TYPE up gauge
+up{job="api"} 1
+up{job="db/main"} 0
+up 1
+# TYPE requests_total counter
+requests_total{job="api",code="200"} 7
+requests_total{job="api",code="500"} 1
+"#;
+        let mfs = read_metric_families(Format::Text, text.as_bytes()).unwrap();
+
+        let docs = split(&mfs, &"family".parse().unwrap()).unwrap();
+        let stems: Vec<_> = docs.iter().map(|(stem, _)| stem.as_str()).collect();
+        assert_eq!(stems, vec!["requests_total", "up"]);
+
+        let docs = split(&mfs, &"label:job".parse().unwrap()).unwrap();
+        let stems: Vec<_> = docs.iter().map(|(stem, _)| stem.as_str()).collect();
+        assert_eq!(stems, vec![MISSING_LABEL_STEM, "api", "db%2Fmain"]);
+
+        let api = &docs[1].1;
+        assert_eq!(api.len(), 2);
+        assert_eq!(api[0].get_name(), "requests_total");
+        assert_eq!(api[0].get_metric().len(), 2);
+        assert_eq!(api[1].get_help(), "Target up.");
+        assert_eq!(api[1].get_metric().len(), 1);
+
+        assert!("label:".parse::<SplitBy>().is_err());
+        assert!("job".parse::<SplitBy>().is_err());
+        assert_eq!(file_stem(".hidden"), "%2Ehidden");
+    }
+}
@@ -0,0 +1,138 @@
+use prometheus::proto::MetricFamily;
+use std::io::{self, Write};
+
+use crate::flatten::flatten;
+use crate::text_encode::{escape_label_value, format_float, type_name};
+use crate::timefmt::TimeStyle;
+
+const BOLD: &str = "\x1b[1m";
+const CYAN: &str = "\x1b[36m";
+const DIM: &str = "\x1b[2m";
+const RESET: &str = "\x1b[0m";
+
+const VALUE_COLUMN: usize = 2;
+const TYPE_COLUMN: usize = 3;
+
+/// Writes one row per sample with aligned NAME, LABELS, VALUE and TYPE
+/// columns, for reading in a terminal, and a TIME column rendered with
+/// `time` if any sample has a timestamp. With `color` the header is bold,
+/// names are cyan and types dimmed, using ANSI escapes.
+pub fn write_table<W: Write>(
+    out: &mut W,
+    mfs: &[MetricFamily],
+    color: bool,
+    time: &TimeStyle,
+) -> io::Result<()> {
+    let samples = flatten(mfs);
+    let with_time = samples.iter().any(|s| s.timestamp_ms.is_some());
+
+    let mut header = vec!["NAME", "LABELS", "VALUE", "TYPE"];
+    if with_time {
+        header.push("TIME");
+    }
+    let header: Vec<String> = header.into_iter().map(String::from).collect();
+    let rows: Vec<Vec<String>> = samples
+        .into_iter()
+        .map(|s| {
+            let labels: Vec<_> = s
+                .labels
+                .iter()
+                .map(|(n, v)| format!("{}=\"{}\"", n, escape_label_value(v)))
+                .collect();
+            let mut row = vec![
+                s.name,
+                labels.join(", "),
+                format_float(s.value),
+                type_name(s.metric_type).to_string(),
+            ];
+            if with_time {
+                row.push(s.timestamp_ms.map(|ms| time.format(ms)).unwrap_or_default());
+            }
+            row
+        })
+        .collect();
+
+    let mut widths = vec![0; header.len()];
+    for row in std::iter::once(&header).chain(&rows) {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let paint = |style: &str, text: String| {
+        if color {
+            format!("{}{}{}", style, text, RESET)
+        } else {
+            text
+        }
+    };
+    let pad = |text: &str, width: usize| " ".repeat(width - text.chars().count());
+
+    let cells = |row: &[String]| -> Vec<String> {
+        row.iter()
+            .zip(&widths)
+            .enumerate()
+            .map(|(i, (cell, &width))| match i {
+                // Values are right-aligned so their magnitudes line up.
+                VALUE_COLUMN => format!("{}{}", pad(cell, width), cell),
+                // The last column is not padded.
+                _ if i == row.len() - 1 => cell.clone(),
+                _ => format!("{}{}", cell, pad(cell, width)),
+            })
+            .collect()
+    };
+
+    writeln!(out, "{}", paint(BOLD, cells(&header).join("  ")))?;
+    for row in &rows {
+        let mut cells = cells(row);
+        cells[0] = paint(CYAN, std::mem::take(&mut cells[0]));
+        cells[TYPE_COLUMN] = paint(DIM, std::mem::take(&mut cells[TYPE_COLUMN]));
+        // Rows without a timestamp end in padding.
+        writeln!(out, "{}", cells.join("  ").trim_end())?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::{read_metric_families, Format};
+
+    #[test]
+    fn test_write_table() {
+        let text = "# TYPE requests_total counter\n\
+                    requests_total{code=\"200\",path=\"/\"} 1027\n\
+                    requests_total{code=\"500\",path=\"/api\"} 3\n\
+                    # TYPE up gauge\nup 1\n";
+        let mfs = read_metric_families(Format::Text, text.as_bytes()).unwrap();
+
+        let mut out = Vec::new();
+        write_table(&mut out, &mfs, false, &TimeStyle::Utc).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "NAME            LABELS                   VALUE  TYPE\n\
+             requests_total  code=\"200\", path=\"/\"      1027  counter\n\
+             requests_total  code=\"500\", path=\"/api\"      3  counter\n\
+             up                                           1  gauge\n"
+        );
+
+        let mut out = Vec::new();
+        write_table(&mut out, &mfs[1..], true, &TimeStyle::Utc).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "\x1b[1mNAME  LABELS  VALUE  TYPE\x1b[0m\n\
+             \x1b[36mup  \x1b[0m              1  \x1b[2mgauge\x1b[0m\n"
+        );
+
+        let text = "# TYPE up gauge\nup{job=\"a\"} 1 1700000000000\nup{job=\"b\"} 0\n";
+        let mfs = read_metric_families(Format::Text, text.as_bytes()).unwrap();
+        let mut out = Vec::new();
+        write_table(&mut out, &mfs, false, &TimeStyle::Utc).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "NAME  LABELS   VALUE  TYPE   TIME\n\
+             up    job=\"a\"      1  gauge  2023-11-14T22:13:20Z\n\
+             up    job=\"b\"      0  gauge\n"
+        );
+    }
+}
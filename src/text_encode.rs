@@ -0,0 +1,235 @@
+use prometheus::proto::{LabelPair, Metric, MetricFamily, MetricType};
+use std::io::{self, Write};
+
+const QUANTILE_LABEL: &str = "quantile";
+const BUCKET_LABEL: &str = "le";
+
+/// Writes metric families in the text exposition format, in the given order.
+///
+/// Unlike `prometheus::TextEncoder`, untyped families and empty HELP lines are
+/// supported, so anything `TextParser` produces can be written back out.
+pub fn metric_families_to_text<W: Write>(out: &mut W, mfs: &[MetricFamily]) -> io::Result<()> {
+    for mf in mfs {
+        metric_family_to_text(out, mf)?;
+    }
+    Ok(())
+}
+
+pub fn metric_family_to_text<W: Write>(out: &mut W, mf: &MetricFamily) -> io::Result<()> {
+    let name = mf.get_name();
+
+    if mf.has_help() {
+        writeln!(out, "# HELP {} {}", name, escape_help(mf.get_help()))?;
+    }
+
+    writeln!(out, "# TYPE {} {}", name, type_name(mf.get_field_type()))?;
+
+    for m in mf.get_metric() {
+        match mf.get_field_type() {
+            MetricType::COUNTER => {
+                write_sample(out, name, "", m, None, m.get_counter().get_value())?;
+            }
+            MetricType::GAUGE => {
+                write_sample(out, name, "", m, None, m.get_gauge().get_value())?;
+            }
+            MetricType::UNTYPED => {
+                write_sample(out, name, "", m, None, m.get_untyped().get_value())?;
+            }
+            MetricType::SUMMARY => {
+                let s = m.get_summary();
+                for q in s.get_quantile() {
+                    let quantile = format_float(q.get_quantile());
+                    write_sample(
+                        out,
+                        name,
+                        "",
+                        m,
+                        Some((QUANTILE_LABEL, &quantile)),
+                        q.get_value(),
+                    )?;
+                }
+                write_sample(out, name, "_sum", m, None, s.get_sample_sum())?;
+                write_sample(out, name, "_count", m, None, s.get_sample_count() as f64)?;
+            }
+            MetricType::HISTOGRAM => {
+                let h = m.get_histogram();
+                let mut inf_seen = false;
+                for b in h.get_bucket() {
+                    let upper_bound = format_float(b.get_upper_bound());
+                    write_sample(
+                        out,
+                        name,
+                        "_bucket",
+                        m,
+                        Some((BUCKET_LABEL, &upper_bound)),
+                        b.get_cumulative_count() as f64,
+                    )?;
+                    if b.get_upper_bound() == f64::INFINITY {
+                        inf_seen = true;
+                    }
+                }
+                if !inf_seen {
+                    write_sample(
+                        out,
+                        name,
+                        "_bucket",
+                        m,
+                        Some((BUCKET_LABEL, "+Inf")),
+                        h.get_sample_count() as f64,
+                    )?;
+                }
+                write_sample(out, name, "_sum", m, None, h.get_sample_sum())?;
+                write_sample(out, name, "_count", m, None, h.get_sample_count() as f64)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn write_sample<W: Write>(
+    out: &mut W,
+    name: &str,
+    suffix: &str,
+    m: &Metric,
+    additional_label: Option<(&str, &str)>,
+    value: f64,
+) -> io::Result<()> {
+    write!(out, "{}{}", name, suffix)?;
+    write_labels(out, m.get_label(), additional_label)?;
+    write!(out, " {}", format_float(value))?;
+
+    if m.has_timestamp_ms() {
+        write!(out, " {}", m.get_timestamp_ms())?;
+    }
+
+    writeln!(out)
+}
+
+fn write_labels<W: Write>(
+    out: &mut W,
+    labels: &[LabelPair],
+    additional_label: Option<(&str, &str)>,
+) -> io::Result<()> {
+    if labels.is_empty() && additional_label.is_none() {
+        return Ok(());
+    }
+
+    let pairs = labels
+        .iter()
+        .map(|l| (l.get_name(), l.get_value()))
+        .chain(additional_label);
+
+    let mut separator = "{";
+    for (name, value) in pairs {
+        write!(
+            out,
+            "{}{}=\"{}\"",
+            separator,
+            name,
+            escape_label_value(value)
+        )?;
+        separator = ",";
+    }
+
+    write!(out, "}}")
+}
+
+pub fn type_name(t: MetricType) -> &'static str {
+    match t {
+        MetricType::COUNTER => "counter",
+        MetricType::GAUGE => "gauge",
+        MetricType::SUMMARY => "summary",
+        MetricType::UNTYPED => "untyped",
+        MetricType::HISTOGRAM => "histogram",
+    }
+}
+
+/// Formats a value the way the text format spells it, using `+Inf`, `-Inf`
+/// and `NaN` for the special values.
+pub fn format_float(v: f64) -> String {
+    if v.is_nan() {
+        "NaN".to_string()
+    } else if v == f64::INFINITY {
+        "+Inf".to_string()
+    } else if v == f64::NEG_INFINITY {
+        "-Inf".to_string()
+    } else {
+        v.to_string()
+    }
+}
+
+/// Escapes `\` and new-line, the inverse of the HELP unescaping in
+/// `TextParser`.
+pub fn escape_help(s: &str) -> String {
+    escape(s, false)
+}
+
+/// Escapes `\`, new-line and `"` for use inside a quoted label value.
+pub fn escape_label_value(s: &str) -> String {
+    escape(s, true)
+}
+
+fn escape(s: &str, include_double_quote: bool) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '"' if include_double_quote => escaped.push_str("\\\""),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::text_parse::TextParser;
+    use std::io::{BufReader, Cursor};
+
+    fn round_trip(text: &str) -> String {
+        let cursor = Cursor::new(text.to_string().into_bytes());
+        let mfs = TextParser::new(BufReader::new(cursor))
+            .text_to_metric_families()
+            .unwrap();
+
+        let mut names: Vec<_> = mfs.keys().cloned().collect();
+        names.sort();
+        let sorted: Vec<_> = names.iter().map(|n| mfs[n].clone()).collect();
+
+        let mut out = Vec::new();
+        metric_families_to_text(&mut out, &sorted).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_help_round_trip() {
+        let text = r#"# HELP a_total Paths like C:\\temp\\x and\nmultiple lines.
+# TYPE a_total counter
+a_total{path="C:\\temp",q="say \"hi\"\n"} 3
+# HELP b Trailing backslash \\
+# TYPE b gauge
+b 1.5 1700000000000
+"#;
+        assert_eq!(round_trip(text), text);
+    }
+
+    #[test]
+    fn test_summary_and_histogram_round_trip() {
+        let text = r#"# TYPE h histogram
+h_bucket{code="200",le="0.5"} 1
+h_bucket{code="200",le="+Inf"} 4
+h_sum{code="200"} 2.5
+h_count{code="200"} 4
+# TYPE s summary
+s{quantile="0.9"} NaN
+s_sum 0
+s_count 0
+# TYPE u untyped
+u -Inf
+"#;
+        assert_eq!(round_trip(text), text);
+    }
+}
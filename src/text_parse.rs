@@ -1,47 +1,202 @@
-use prometheus::proto::{Metric, MetricFamily, MetricType};
-use std::collections::HashMap;
+use prometheus::proto::{
+    Bucket, Counter, Gauge, LabelPair, Metric, MetricFamily, MetricType, Quantile, Untyped,
+};
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
 use std::fmt;
-use std::io::{self, BufReader, Cursor, Read};
-//use std::rc::Rc;
+use std::io::{self, Read};
+use std::ops::Range;
 use std::str;
 
-#[derive(Debug)]
-struct ParseError {
-    msg: String,
+use crate::flatten::render_series;
+use crate::grammar::{
+    is_blank_or_tab, is_valid_label_name_continuation, is_valid_label_name_start,
+    is_valid_metric_name_continuation, is_valid_metric_name_start, parse_float,
+};
+use crate::text_encode::format_float;
+
+const METRIC_NAME_LABEL: &str = "__name__";
+const QUANTILE_LABEL: &str = "quantile";
+const BUCKET_LABEL: &str = "le";
+// Counts are written as floats; above 2^53 they may already have been
+// rounded, so the u64 would not be the count the input had.
+const MAX_EXACT_COUNT: f64 = 9_007_199_254_740_992.0;
+
+/// What the parser does with a histogram or summary count, `_count` or
+/// bucket, that is not an integer between 0 and 2^53.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CountPolicy {
+    /// Fail with a parse error.
+    Error,
+    /// Clamp it into range, truncating fractions; NaN becomes 0.
+    #[default]
+    Clamp,
+    /// Drop the sample.
+    Skip,
 }
 
-impl fmt::Display for ParseError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "parse error: {}", self.msg)
+impl str::FromStr for CountPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "error" => Ok(CountPolicy::Error),
+            "clamp" => Ok(CountPolicy::Clamp),
+            "skip" => Ok(CountPolicy::Skip),
+            _ => Err(format!(
+                "unknown count policy {:?}, expected error, clamp or skip",
+                s
+            )),
+        }
     }
 }
 
-impl Error for ParseError {
-    fn description(&self) -> &str {
-        &self.msg
+/// What the parser does with a sample whose name and labels, `le` and
+/// `quantile` included, an earlier line of the same input already had.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DuplicatePolicy {
+    /// Keep both, as separate series or buckets.
+    #[default]
+    Keep,
+    /// Fail with a parse error.
+    Error,
+    /// Keep the sample seen first.
+    FirstWins,
+    /// Keep the sample seen last, in the place of the first.
+    LastWins,
+    /// Add the values up; quantiles keep the value seen last.
+    Sum,
+}
+
+impl str::FromStr for DuplicatePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "keep" => Ok(DuplicatePolicy::Keep),
+            "error" => Ok(DuplicatePolicy::Error),
+            "first-wins" => Ok(DuplicatePolicy::FirstWins),
+            "last-wins" => Ok(DuplicatePolicy::LastWins),
+            "sum" => Ok(DuplicatePolicy::Sum),
+            _ => Err(format!(
+                "unknown duplicate policy {:?}, expected keep, error, first-wins, last-wins or sum",
+                s
+            )),
+        }
     }
 }
 
+/// How inputs are parsed, for callers that do not build a [`TextParser`]
+/// themselves.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ParserOptions {
+    pub count_policy: CountPolicy,
+    pub duplicate_policy: DuplicatePolicy,
+}
+
+/// What the parser had to work around in its input.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ParseStats {
+    /// Counts clamped under [`CountPolicy::Clamp`].
+    pub clamped_counts: usize,
+    /// Count samples dropped under [`CountPolicy::Skip`].
+    pub skipped_counts: usize,
+    /// Duplicate samples resolved under [`DuplicatePolicy::FirstWins`],
+    /// `LastWins` or `Sum`. Not counted under `Keep`.
+    pub duplicate_samples: usize,
+}
+
+/// The input line of a sample, kept with [`TextParser::capture_raw_lines`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RawLine {
+    /// The sample's name and labels as written, `quantile` and `le`
+    /// included, with labels sorted by name.
+    pub series: String,
+    pub line: i32,
+    /// Byte offsets of the line in the input, without its newline.
+    pub span: Range<usize>,
+    /// The line, without its newline; invalid UTF-8 is replaced.
+    pub text: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    pub line: i32,
+    /// Byte position in the line, starting at 1, at which the error was
+    /// detected; for a bad token that is just past its end.
+    pub column: i32,
+    pub msg: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "text format parsing error in line {}: {}",
+            self.line, self.msg
+        )
+    }
+}
+
+impl Error for ParseError {}
+
 #[derive(Debug)]
 pub struct TextParser<R: Read> {
     current_byte: u8,
 
-    //current_labels: HashMap<String, String>,
     mf_by_name: HashMap<String, MetricFamily>,
     cur_mf_name: String,
+    // Index of the metric the current sample line belongs to, once it has
+    // been attached to the current metric family.
+    cur_metric_idx: usize,
+    cur_metric: Metric,
+    cur_label_name: String,
+
+    // Summaries and histograms spread one metric over several lines, so the
+    // labels (minus quantile/le) are collected to find the metric again.
+    current_labels: HashMap<String, String>,
+    summaries: HashMap<String, usize>,
+    histograms: HashMap<String, usize>,
+    current_quantile: f64,
+    current_bucket: f64,
 
     current_token: Vec<u8>,
-    //current_bucket: f64,
     current_is_summary_count: bool,
     current_is_summary_sum: bool,
     current_is_histogram_count: bool,
     current_is_histogram_sum: bool,
     line_count: i32,
     reading_bytes: i32,
+    // Value of reading_bytes before the first byte of the current line.
+    line_start: i32,
     reader: R,
 
-    //cur_metric: Option<Rc<Metric>>,
+    normalize_help: bool,
+    strict: bool,
+    require_type: bool,
+    openmetrics: bool,
+    count_policy: CountPolicy,
+    duplicate_policy: DuplicatePolicy,
+    // Rendered series of the samples read, unless duplicates are kept, and
+    // the metric each went to.
+    seen_samples: HashMap<String, usize>,
+    // Family, first and duplicate metric of counter, gauge and untyped
+    // samples seen twice, resolved once the input is read.
+    duplicates: Vec<(String, usize, usize)>,
+    stats: ParseStats,
+    // Families typed untyped because samples came before any TYPE line.
+    implicit_types: HashSet<String>,
+
+    capture_raw_lines: bool,
+    raw_lines: Vec<RawLine>,
+    // Bytes of the current line, and the name and labels of its sample as
+    // written, while capturing raw lines.
+    raw_line: Vec<u8>,
+    raw_name: String,
+    raw_labels: Vec<(String, String)>,
+    // Series of the sample on the current line, once it has been parsed.
+    raw_series: Option<String>,
+
     error: Option<Box<dyn Error>>,
     state_fn: StateFn<R>,
 }
@@ -49,82 +204,253 @@ pub struct TextParser<R: Read> {
 type StateFn<R> = fn(&mut TextParser<R>) -> ParserState<R>;
 
 enum ParserState<R: Read> {
-    _Any(StateFn<R>),
+    Next(StateFn<R>),
     End,
 }
 
 impl<R: Read> TextParser<R> {
     pub fn new(reader: R) -> Self {
         TextParser {
-            //current_labels: HashMap::new(),
             mf_by_name: HashMap::new(),
             cur_mf_name: String::new(),
+            cur_metric_idx: 0,
+            cur_metric: Metric::new(),
+            cur_label_name: String::new(),
+
+            current_labels: HashMap::new(),
+            summaries: HashMap::new(),
+            histograms: HashMap::new(),
+            current_quantile: f64::NAN,
+            current_bucket: f64::NAN,
 
             current_token: Vec::new(),
-            current_byte: 0 as u8,
-            //current_bucket: 0.0,
+            current_byte: 0,
             current_is_summary_count: false,
             current_is_summary_sum: false,
             current_is_histogram_count: false,
             current_is_histogram_sum: false,
             line_count: 0,
             reading_bytes: 0,
-            reader: reader,
+            line_start: 0,
+            reader,
+            normalize_help: false,
+            strict: false,
+            require_type: false,
+            openmetrics: false,
+            count_policy: CountPolicy::default(),
+            duplicate_policy: DuplicatePolicy::default(),
+            seen_samples: HashMap::new(),
+            duplicates: Vec::new(),
+            stats: ParseStats::default(),
+            implicit_types: HashSet::new(),
+            capture_raw_lines: false,
+            raw_lines: Vec::new(),
+            raw_line: Vec::new(),
+            raw_name: String::new(),
+            raw_labels: Vec::new(),
+            raw_series: None,
             error: None,
             state_fn: TextParser::start_of_line,
-            //cur_metric: None,
         }
     }
 
-    pub fn text_to_metric_families(&mut self) -> Result<HashMap<String, MetricFamily>, io::Error> {
+    /// Collapses runs of whitespace (including unescaped `\n`) in HELP
+    /// docstrings into single spaces and trims both ends.
+    pub fn normalize_help(mut self, normalize: bool) -> Self {
+        self.normalize_help = normalize;
+        self
+    }
+
+    /// Enables checks beyond the syntax the Go parser enforces: histogram
+    /// buckets must be sorted with non-decreasing counts and quantiles must
+    /// lie within [0, 1].
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Rejects samples of families without a `# TYPE` line before them, as
+    /// left by exporters that emit everything untyped.
+    pub fn require_type(mut self, require: bool) -> Self {
+        self.require_type = require;
+        self
+    }
+
+    /// Accepts OpenMetrics input: `# EOF`, `# UNIT`, the `unknown`, `info`
+    /// and `stateset` types, `_total`/`_created` counter samples, exemplars
+    /// and timestamps in seconds. Counter and info families are keyed by
+    /// their `_total`/`_info` sample names, as in the text format.
+    pub fn openmetrics(mut self, openmetrics: bool) -> Self {
+        self.openmetrics = openmetrics;
+        self
+    }
+
+    /// Sets what happens to counts that do not fit a u64 exactly; they
+    /// are clamped by default.
+    pub fn count_policy(mut self, policy: CountPolicy) -> Self {
+        self.count_policy = policy;
+        self
+    }
+
+    /// Sets what happens to samples repeated within the input; they are
+    /// kept by default.
+    pub fn duplicate_policy(mut self, policy: DuplicatePolicy) -> Self {
+        self.duplicate_policy = policy;
+        self
+    }
+
+    /// Sets the count and duplicate policies.
+    pub fn options(self, options: ParserOptions) -> Self {
+        self.count_policy(options.count_policy)
+            .duplicate_policy(options.duplicate_policy)
+    }
+
+    /// Keeps the input line of every sample, for audits of how it was
+    /// read. Off by default, as it holds a copy of the sample lines.
+    pub fn capture_raw_lines(mut self, capture: bool) -> Self {
+        self.capture_raw_lines = capture;
+        self
+    }
+
+    /// Takes the lines of the samples parsed so far, in input order.
+    /// Samples dropped under [`CountPolicy::Skip`] have none.
+    pub fn take_raw_lines(&mut self) -> Vec<RawLine> {
+        std::mem::take(&mut self.raw_lines)
+    }
+
+    /// What was clamped, skipped or deduplicated so far.
+    pub fn stats(&self) -> ParseStats {
+        self.stats
+    }
+
+    pub fn text_to_metric_families(&mut self) -> Result<HashMap<String, MetricFamily>, ParseError> {
+        self.run();
+        self.resolve_duplicates();
+
+        // Get rid of empty metric families.
+        self.mf_by_name.retain(|_, mf| !mf.get_metric().is_empty());
+
+        match self.take_error() {
+            None => Ok(std::mem::take(&mut self.mf_by_name)),
+            Some(err) => Err(err),
+        }
+    }
+
+    /// Parses the whole input, but instead of stopping at the first error
+    /// skips to the next line and carries on. Returns every error found, in
+    /// input order.
+    pub fn validate(&mut self) -> Vec<ParseError> {
+        let mut errors = Vec::new();
+
         loop {
-            match (self.state_fn)(self) {
-                ParserState::_Any(next) => {
-                    self.state_fn = next;
-                }
-                ParserState::End => {
-                    break;
+            self.run();
+
+            let at_eof = match &self.error {
+                None => break,
+                Some(err) => is_eof(err.as_ref()),
+            };
+            errors.extend(self.take_error());
+            if at_eof {
+                break;
+            }
+
+            while self.current_byte != b'\n' {
+                self.read_byte();
+                if self.error.is_some() {
+                    return errors;
                 }
             }
+            self.state_fn = TextParser::start_of_line;
+        }
+
+        errors
+    }
+
+    fn run(&mut self) {
+        while let ParserState::Next(next) = (self.state_fn)(self) {
+            self.state_fn = next;
+        }
+    }
+
+    fn take_error(&mut self) -> Option<ParseError> {
+        let err = self.error.take()?;
+
+        if let Some(err) = err.downcast_ref::<ParseError>() {
+            return Some(err.clone());
         }
 
-        Ok(HashMap::new()) // TODO: return empty
+        // Running into EOF anywhere but the start of a line means the input
+        // stream ended prematurely.
+        let msg = if is_eof(err.as_ref()) {
+            "unexpected end of input stream".to_string()
+        } else {
+            err.to_string()
+        };
+        Some(ParseError {
+            line: self.line_count,
+            column: self.column(),
+            msg,
+        })
     }
 
     fn start_of_line(&mut self) -> ParserState<R> {
-        println!("in start_of_line");
+        if let Some(series) = self.raw_series.take() {
+            let text = self.raw_line.strip_suffix(b"\n").unwrap_or(&self.raw_line);
+            let start = self.line_start as usize;
+            self.raw_lines.push(RawLine {
+                series,
+                line: self.line_count,
+                span: start..start + text.len(),
+                text: String::from_utf8_lossy(text).into_owned(),
+            });
+        }
+        self.raw_line.clear();
 
         self.line_count += 1;
+        self.line_start = self.reading_bytes;
         self.skip_blank_tab();
 
-        match self.current_byte as char {
-            '#' => self.start_comment(),
+        if let Some(err) = &self.error {
+            // This is the only place where EOF is expected and means we are
+            // done.
+            if is_eof(err.as_ref()) {
+                self.error = None;
+            }
+            return ParserState::End;
+        }
 
-            '\n' => self.start_of_line(),
+        match self.current_byte {
+            b'#' => ParserState::Next(TextParser::start_comment),
 
-            _ => self.reading_metric_name(),
+            b'\n' => ParserState::Next(TextParser::start_of_line),
+
+            _ => ParserState::Next(TextParser::reading_metric_name),
         }
     }
 
     fn start_comment(&mut self) -> ParserState<R> {
-        println!("in start_comment");
-
         self.skip_blank_tab();
-        if let Some(_err) = &self.error {
+        if self.error.is_some() {
             return ParserState::End;
         }
 
-        if self.current_byte == '\n' as u8 {
-            return self.start_of_line();
+        if self.current_byte == b'\n' {
+            return ParserState::Next(TextParser::start_of_line);
         }
 
         self.read_token_until_white_space();
-        if let Some(_err) = &self.error {
+        if self.openmetrics && self.current_token == b"EOF" {
+            // Anything after `# EOF` is ignored.
+            self.error = None;
+            return ParserState::End;
+        }
+        if self.error.is_some() {
             return ParserState::End; // unexpected end of input.
         }
 
-        if self.current_byte == '\n' as u8 {
-            return self.start_of_line();
+        // Hitting the end of line already is not considered a syntax error.
+        if self.current_byte == b'\n' {
+            return ParserState::Next(TextParser::start_of_line);
         }
 
         let mut on_help = false;
@@ -137,103 +463,265 @@ impl<R: Read> TextParser<R> {
             Ok("TYPE") => {
                 on_type = true;
             }
-            Ok(_) => {
-                loop {
-                    if self.current_byte == '\n' as u8 {
-                        break;
-                    }
-
-                    if let Some(_) = &self.error {
-                        return ParserState::End;
-                    }
-
-                    self.read_byte()
-                }
-                return self.start_of_line();
-            }
-            Err(e) => {
-                todo!("invalid UTF8 token: {}", e);
+            _ => {
+                // Generic comment, fast forward to the end of line.
+                return self.skip_to_next_line();
             }
         }
 
         // there is something. Next has to be a metric name.
         self.skip_blank_tab();
-        if let Some(_) = &self.error {
+        if self.error.is_some() {
             return ParserState::End;
         }
 
         self.read_token_as_metric_name();
-        if let Some(_) = &self.error {
+        if self.error.is_some() {
             return ParserState::End;
         }
 
-        if self.current_byte == '\n' as u8 {
-            return self.start_of_line();
+        if self.current_byte == b'\n' {
+            return ParserState::Next(TextParser::start_of_line);
         }
 
         if !is_blank_or_tab(self.current_byte) {
+            self.parse_error("invalid metric name in comment".to_string());
             return ParserState::End;
         }
 
         self.set_or_create_current_mf();
 
         self.skip_blank_tab();
-        if let Some(_) = &self.error {
+        if self.error.is_some() {
             return ParserState::End;
         }
-        if self.current_byte == '\n' as u8 {
-            return self.start_of_line();
+        if self.current_byte == b'\n' {
+            return ParserState::Next(TextParser::start_of_line);
         }
 
         if on_help {
-            return self.reading_help();
+            return ParserState::Next(TextParser::reading_help);
         }
 
         if on_type {
-            return self.reading_type();
+            return ParserState::Next(TextParser::reading_type);
         }
 
-        ParserState::End // TODO
+        unreachable!("unexpected comment keyword")
     }
 
     fn reading_help(&mut self) -> ParserState<R> {
-        println!("in reading_help");
+        if self.current_mf().has_help() {
+            let msg = format!("second HELP line for metric name {:?}", self.cur_mf_name);
+            self.parse_error(msg);
+            return ParserState::End;
+        }
 
+        // Rest of line is the docstring.
         self.read_token_until_newline(true);
-        if let Some(_) = &self.error {
+        if self.error.is_some() {
+            return ParserState::End;
+        }
+
+        match String::from_utf8(self.current_token.clone()) {
+            Ok(s) => {
+                let help = if self.normalize_help {
+                    normalize_whitespace(&s)
+                } else {
+                    s
+                };
+                self.current_mf_mut().set_help(help);
+            }
+            Err(e) => {
+                self.error = Some(Box::new(e));
+                return ParserState::End;
+            }
+        };
+
+        ParserState::Next(TextParser::start_of_line)
+    }
+
+    fn reading_type(&mut self) -> ParserState<R> {
+        // Rest of line is the type.
+        self.read_token_until_newline(false);
+        if self.error.is_some() {
             return ParserState::End;
         }
 
-        if let Some(mf) = self.mf_by_name.get_mut(&self.cur_mf_name) {
-            println!("get mf for {}", self.cur_mf_name);
+        let token = String::from_utf8_lossy(&self.current_token).to_string();
+        let (metric_type, om_suffix) = match token.to_lowercase().as_str() {
+            "counter" => (MetricType::COUNTER, "_total"),
+            "gauge" => (MetricType::GAUGE, ""),
+            "summary" => (MetricType::SUMMARY, ""),
+            "untyped" => (MetricType::UNTYPED, ""),
+            "histogram" => (MetricType::HISTOGRAM, ""),
+            "unknown" if self.openmetrics => (MetricType::UNTYPED, ""),
+            "stateset" if self.openmetrics => (MetricType::GAUGE, ""),
+            "info" if self.openmetrics => (MetricType::GAUGE, "_info"),
+            _ => {
+                self.parse_error(format!("unknown metric type {:?}", token));
+                return ParserState::End;
+            }
+        };
+
+        // Samples of a histogram or summary may come before its TYPE line;
+        // they were read as untyped and are moved over below.
+        let reattach = matches!(metric_type, MetricType::HISTOGRAM | MetricType::SUMMARY);
+        let implicit = self.implicit_types.contains(&self.cur_mf_name);
+        if self.current_mf().has_field_type() && !(implicit && reattach) {
+            let msg = format!(
+                "second TYPE line for metric name {:?}, or TYPE reported after samples",
+                self.cur_mf_name
+            );
+            self.parse_error(msg);
+            return ParserState::End;
+        }
 
-            if mf.get_help().len() > 0 {
-                self.error = Some(Box::new(ParseError {
-                    msg: format!("second HELP line for metric name {}", mf.get_name()),
-                }));
+        // OpenMetrics names counter and info families without the suffix
+        // their samples carry.
+        if self.openmetrics && !om_suffix.is_empty() && !self.cur_mf_name.ends_with(om_suffix) {
+            let name = format!("{}{}", self.cur_mf_name, om_suffix);
+            if self.mf_by_name.contains_key(&name) {
+                self.parse_error(format!("TYPE for {:?} reported after samples", name));
                 return ParserState::End;
             }
 
-            match String::from_utf8(self.current_token.clone()) {
-                Ok(s) => {
-                    mf.set_help(s);
-                }
-                Err(e) => {
-                    self.error = Some(Box::new(e));
-                }
-            };
-        } else {
-            println!("mf {} not found", self.cur_mf_name);
+            let mut mf = self
+                .mf_by_name
+                .remove(&self.cur_mf_name)
+                .unwrap_or_default();
+            mf.set_name(name.clone());
+            self.mf_by_name.insert(name.clone(), mf);
+            self.cur_mf_name = name;
         }
 
-        println!("mf_by_name(after set HELP): {:?}", self.mf_by_name);
+        self.current_mf_mut().set_field_type(metric_type);
+        if reattach {
+            self.reattach_children();
+            if self.error.is_some() {
+                return ParserState::End;
+            }
+        }
 
-        self.start_of_line()
+        ParserState::Next(TextParser::start_of_line)
     }
 
-    fn reading_type(&mut self) -> ParserState<R> {
-        println!("in reading_type");
-        self.start_of_line()
+    /// Moves samples read before the current family's TYPE line into it.
+    /// Without a type they became untyped families of their own: `x_bucket`,
+    /// `x_sum` and `x_count`, and `x` itself for summary quantiles.
+    fn reattach_children(&mut self) {
+        let parent = self.cur_mf_name.clone();
+        let suffixes: &[&str] = if self.current_mf().get_field_type() == MetricType::HISTOGRAM {
+            &["", "_bucket", "_sum", "_count"]
+        } else {
+            &["", "_sum", "_count"]
+        };
+
+        for suffix in suffixes {
+            let name = format!("{}{}", parent, suffix);
+            if !self.implicit_types.remove(&name) {
+                continue;
+            }
+            // Its duplicates are resolved as the parent's samples, if at all.
+            self.duplicates.retain(|(family, _, _)| *family != name);
+            let metrics = if suffix.is_empty() {
+                self.current_mf_mut().take_metric()
+            } else {
+                match self.mf_by_name.remove(&name) {
+                    Some(mut mf) => mf.take_metric(),
+                    None => continue,
+                }
+            };
+            for m in metrics {
+                self.reattach_sample(&parent, suffix, m);
+            }
+        }
+    }
+
+    fn reattach_sample(&mut self, parent: &str, suffix: &str, sample: Metric) {
+        let mf_type = self.current_mf().get_field_type();
+        let special = if mf_type == MetricType::HISTOGRAM {
+            BUCKET_LABEL
+        } else {
+            QUANTILE_LABEL
+        };
+
+        let mut signature_labels = HashMap::new();
+        signature_labels.insert(METRIC_NAME_LABEL.to_string(), parent.to_string());
+        let mut bound = None;
+        let mut labels = Vec::new();
+        for l in sample.get_label() {
+            if l.get_name() == special {
+                bound = parse_float(l.get_value());
+            } else {
+                signature_labels.insert(l.get_name().to_string(), l.get_value().to_string());
+                labels.push(l.clone());
+            }
+        }
+        let signature = labels_to_signature(&signature_labels);
+
+        let index = if mf_type == MetricType::HISTOGRAM {
+            &self.histograms
+        } else {
+            &self.summaries
+        };
+        let idx = match index.get(&signature) {
+            Some(&idx) => idx,
+            None => {
+                let mut m = Metric::new();
+                m.set_label(labels.into());
+                let idx = self.push_current_metric(m);
+                if mf_type == MetricType::HISTOGRAM {
+                    self.histograms.insert(signature, idx);
+                } else {
+                    self.summaries.insert(signature, idx);
+                }
+                idx
+            }
+        };
+
+        let value = sample.get_untyped().get_value();
+        let count = match (mf_type, suffix, bound) {
+            (MetricType::HISTOGRAM, "_bucket", Some(_)) | (_, "_count", _) => {
+                match self.check_count(value) {
+                    Some(count) => count,
+                    None => return,
+                }
+            }
+            _ => 0,
+        };
+        let metric = &mut self.current_mf_mut().mut_metric()[idx];
+        if sample.has_timestamp_ms() {
+            metric.set_timestamp_ms(sample.get_timestamp_ms());
+        }
+        match (mf_type, suffix, bound) {
+            (MetricType::HISTOGRAM, "_bucket", Some(bound)) => {
+                let buckets = metric.mut_histogram().mut_bucket();
+                let pos = buckets.partition_point(|b| b.get_upper_bound() < bound);
+                let mut b = Bucket::new();
+                b.set_upper_bound(bound);
+                b.set_cumulative_count(count);
+                buckets.insert(pos, b);
+            }
+            (MetricType::HISTOGRAM, "_sum", _) => metric.mut_histogram().set_sample_sum(value),
+            (MetricType::HISTOGRAM, "_count", _) => metric.mut_histogram().set_sample_count(count),
+            (MetricType::SUMMARY, "", Some(quantile)) => {
+                let quantiles = metric.mut_summary().mut_quantile();
+                let pos = quantiles.partition_point(|q| q.get_quantile() < quantile);
+                let mut q = Quantile::new();
+                q.set_quantile(quantile);
+                q.set_value(value);
+                quantiles.insert(pos, q);
+            }
+            (MetricType::SUMMARY, "_sum", _) => metric.mut_summary().set_sample_sum(value),
+            (MetricType::SUMMARY, "_count", _) => metric.mut_summary().set_sample_count(count),
+            _ => log::debug!(
+                "dropping {}{} sample without {} label",
+                parent,
+                suffix,
+                special
+            ),
+        }
     }
 
     fn set_or_create_current_mf(&mut self) {
@@ -242,74 +730,93 @@ impl<R: Read> TextParser<R> {
         self.current_is_histogram_count = false;
         self.current_is_histogram_sum = false;
 
-        let name;
+        let name = match String::from_utf8(self.current_token.clone()) {
+            Ok(s) => s,
+            Err(err) => {
+                self.error = Some(Box::new(err));
+                return;
+            }
+        };
 
-        match String::from_utf8(self.current_token.clone()) {
-            Ok(s) => {
-                name = s;
-                println!("got name: {}", name);
+        if self.mf_by_name.contains_key(&name) {
+            self.cur_mf_name = name;
+            return;
+        }
 
-                if self.mf_by_name.contains_key(&name) {
-                    // key exist
+        if self.openmetrics {
+            for suffix in ["_total", "_info"] {
+                let full_name = format!("{}{}", name, suffix);
+                if self.mf_by_name.contains_key(&full_name) {
+                    self.cur_mf_name = full_name;
                     return;
                 }
+            }
+        }
+
+        // Try out if this is a _sum or _count for a summary/histogram.
+        let sum_name = summary_metric_name(&name);
+        if let Some(mf) = self.mf_by_name.get(sum_name) {
+            if mf.get_field_type() == MetricType::SUMMARY {
+                self.cur_mf_name = sum_name.to_string();
+                self.current_is_summary_count = is_count(&name);
+                self.current_is_summary_sum = is_sum(&name);
+                return;
+            }
+        }
 
-                let sum_name = summary_metric_name(&name);
-                match self.mf_by_name.get(sum_name) {
-                    Some(mf) => {
-                        self.cur_mf_name = sum_name.to_string();
+        let histogram_name = histogram_metric_name(&name);
+        if let Some(mf) = self.mf_by_name.get(histogram_name) {
+            if mf.get_field_type() == MetricType::HISTOGRAM {
+                self.cur_mf_name = histogram_name.to_string();
+                self.current_is_histogram_count = is_count(&name);
+                self.current_is_histogram_sum = is_sum(&name);
+                return;
+            }
+        }
 
-                        if mf.get_field_type() == MetricType::SUMMARY {
-                            if is_count(&name) {
-                                self.current_is_summary_count = true;
-                            }
+        log::debug!("add metric family {}", name);
+        self.cur_mf_name = name.clone();
 
-                            if is_sum(&name) {
-                                self.current_is_summary_sum = true;
-                            }
-                            return;
-                        }
-                    }
-                    _ => {}
-                }
+        let mut mf = MetricFamily::new();
+        mf.set_name(name.clone());
+        self.mf_by_name.insert(name, mf);
+    }
 
-                let histogram_name = histogram_metric_name(&name);
-                match self.mf_by_name.get(histogram_name) {
-                    Some(mf) => {
-                        self.cur_mf_name = histogram_name.to_string();
-                        if mf.get_field_type() == MetricType::HISTOGRAM {
-                            if is_count(&name) {
-                                self.current_is_histogram_count = true
-                            }
-
-                            if is_sum(&name) {
-                                self.current_is_histogram_sum = true
-                            }
-                            return;
-                        }
-                    }
-                    _ => {}
-                }
+    fn current_mf(&self) -> &MetricFamily {
+        &self.mf_by_name[&self.cur_mf_name]
+    }
 
-                println!("add metric {}", name);
-                self.cur_mf_name = name.clone();
+    fn current_mf_mut(&mut self) -> &mut MetricFamily {
+        self.mf_by_name
+            .get_mut(&self.cur_mf_name)
+            .expect("current metric family must exist")
+    }
 
-                let mut mf = MetricFamily::new();
-                mf.set_name(name.clone());
-                self.mf_by_name.insert(name, mf);
+    fn read_token_as_metric_name(&mut self) {
+        self.current_token.clear();
 
-                println!("mf_by_name: {:?}", self.mf_by_name);
+        if !is_valid_metric_name_start(self.current_byte as char) {
+            return;
+        }
+
+        loop {
+            self.current_token.push(self.current_byte);
+            self.read_byte();
+
+            if self.error.is_some() {
+                break;
             }
-            Err(err) => {
-                self.error = Some(Box::new(err));
+
+            if !is_valid_metric_name_continuation(self.current_byte as char) {
+                break;
             }
         }
     }
 
-    fn read_token_as_metric_name(&mut self) {
+    fn read_token_as_label_name(&mut self) {
         self.current_token.clear();
 
-        if !is_valid_metric_name_start(self.current_byte as char) {
+        if !is_valid_label_name_start(self.current_byte as char) {
             return;
         }
 
@@ -317,86 +824,755 @@ impl<R: Read> TextParser<R> {
             self.current_token.push(self.current_byte);
             self.read_byte();
 
-            if let Some(_err) = &self.error {
-                println!("got error: {:?}", self.error);
+            if self.error.is_some() {
                 break;
             }
 
             if !is_valid_label_name_continuation(self.current_byte as char) {
-                println!("got char: {}", self.current_byte as char);
                 break;
             }
         }
+    }
 
-        println!(
-            "in read_token_as_metric_name: {}",
-            str::from_utf8(&self.current_token).unwrap()
-        );
+    fn read_token_as_label_value(&mut self) {
+        self.current_token.clear();
+
+        let mut escaped = false;
+        loop {
+            self.read_byte();
+            if self.error.is_some() {
+                return;
+            }
+
+            if escaped {
+                match self.current_byte {
+                    b'"' | b'\\' => {
+                        self.current_token.push(self.current_byte);
+                    }
+                    b'n' => {
+                        self.current_token.push(b'\n');
+                    }
+                    _ => {
+                        let msg =
+                            format!("invalid escape sequence '\\{}'", self.current_byte as char);
+                        self.parse_error(msg);
+                        return;
+                    }
+                }
+                escaped = false;
+                continue;
+            }
+
+            match self.current_byte {
+                b'"' => {
+                    return;
+                }
+                b'\n' => {
+                    let msg = format!(
+                        "label value {:?} contains unescaped new-line",
+                        String::from_utf8_lossy(&self.current_token)
+                    );
+                    self.parse_error(msg);
+                    return;
+                }
+                b'\\' => {
+                    escaped = true;
+                }
+                _ => {
+                    self.current_token.push(self.current_byte);
+                }
+            }
+        }
     }
 
     fn reading_metric_name(&mut self) -> ParserState<R> {
-        println!("in reading_metric_name");
         self.read_token_as_metric_name();
 
-        if let Some(_) = &self.error {
+        if self.error.is_some() {
             return ParserState::End;
         }
 
-        if self.current_token.len() == 0 {
-            self.error = Some(Box::new(ParseError {
-                msg: "invalid metric name".to_string(),
-            }));
+        if self.current_token.is_empty() {
+            self.parse_error("invalid metric name".to_string());
+            return ParserState::End;
+        }
+
+        if self.openmetrics && self.is_created_sample() {
+            // Creation timestamps have no place in the data model.
+            return self.skip_to_next_line();
+        }
+
+        if self.capture_raw_lines {
+            self.raw_name = String::from_utf8_lossy(&self.current_token).into_owned();
+            self.raw_labels.clear();
         }
 
         self.set_or_create_current_mf();
+        if self.error.is_some() {
+            return ParserState::End;
+        }
+
+        // Now is the time to fix the type if it hasn't happened yet.
+        if !self.current_mf().has_field_type() {
+            if self.require_type {
+                let msg = format!("sample of {:?} before any TYPE line", self.cur_mf_name);
+                self.parse_error(msg);
+                return ParserState::End;
+            }
+            self.current_mf_mut().set_field_type(MetricType::UNTYPED);
+            self.implicit_types.insert(self.cur_mf_name.clone());
+        }
+
+        // The metric is not attached to the family yet. For summaries and
+        // histograms it may already exist, which is only known after all
+        // labels have been read.
+        self.cur_metric = Metric::new();
 
-        if let Some(_mf) = self.mf_by_name.get_mut(&self.cur_mf_name) {
-            // TODO: fix metric type here?
-            let _metric = Metric::new();
+        self.skip_blank_tab_if_current_blank_tab();
+        if self.error.is_some() {
+            return ParserState::End;
         }
 
-        ParserState::End
+        ParserState::Next(TextParser::reading_labels)
     }
 
     fn reading_labels(&mut self) -> ParserState<R> {
-        self.start_label_name()
+        // Summaries and histograms need the label set to be reset before
+        // reading labels.
+        let mf_type = self.current_mf().get_field_type();
+        if mf_type == MetricType::SUMMARY || mf_type == MetricType::HISTOGRAM {
+            self.current_labels.clear();
+            self.current_labels
+                .insert(METRIC_NAME_LABEL.to_string(), self.cur_mf_name.clone());
+            self.current_quantile = f64::NAN;
+            self.current_bucket = f64::NAN;
+        }
+
+        if self.current_byte != b'{' {
+            return ParserState::Next(TextParser::reading_value);
+        }
+
+        ParserState::Next(TextParser::start_label_name)
     }
 
     fn start_label_name(&mut self) -> ParserState<R> {
-        self.start_label_value()
+        self.skip_blank_tab();
+        if self.error.is_some() {
+            return ParserState::End;
+        }
+
+        if self.current_byte == b'}' {
+            self.skip_blank_tab();
+            if self.error.is_some() {
+                return ParserState::End;
+            }
+            return ParserState::Next(TextParser::reading_value);
+        }
+
+        self.read_token_as_label_name();
+        if self.error.is_some() {
+            return ParserState::End;
+        }
+
+        if self.current_token.is_empty() {
+            let msg = format!("invalid label name for metric {:?}", self.cur_mf_name);
+            self.parse_error(msg);
+            return ParserState::End;
+        }
+
+        self.cur_label_name = String::from_utf8_lossy(&self.current_token).to_string();
+        if self.cur_label_name == METRIC_NAME_LABEL {
+            self.parse_error(format!("label name {:?} is reserved", METRIC_NAME_LABEL));
+            return ParserState::End;
+        }
+
+        // Special summary/histogram treatment. Don't add 'quantile' and 'le'
+        // labels to 'real' labels.
+        let mf_type = self.current_mf().get_field_type();
+        let is_special = (mf_type == MetricType::SUMMARY && self.cur_label_name == QUANTILE_LABEL)
+            || (mf_type == MetricType::HISTOGRAM && self.cur_label_name == BUCKET_LABEL);
+        if !is_special {
+            if self
+                .cur_metric
+                .get_label()
+                .iter()
+                .any(|l| l.get_name() == self.cur_label_name)
+            {
+                let msg = format!("duplicate label names for metric {:?}", self.cur_mf_name);
+                self.parse_error(msg);
+                return ParserState::End;
+            }
+
+            let mut label = LabelPair::new();
+            label.set_name(self.cur_label_name.clone());
+            self.cur_metric.mut_label().push(label);
+        }
+
+        self.skip_blank_tab_if_current_blank_tab();
+        if self.error.is_some() {
+            return ParserState::End;
+        }
+
+        if self.current_byte != b'=' {
+            let msg = format!(
+                "expected '=' after label name, found {:?}",
+                self.current_byte as char
+            );
+            self.parse_error(msg);
+            return ParserState::End;
+        }
+
+        ParserState::Next(TextParser::start_label_value)
     }
 
     fn start_label_value(&mut self) -> ParserState<R> {
-        todo!()
+        self.skip_blank_tab();
+        if self.error.is_some() {
+            return ParserState::End;
+        }
+
+        if self.current_byte != b'"' {
+            let msg = format!(
+                "expected '\"' at start of label value, found {:?}",
+                self.current_byte as char
+            );
+            self.parse_error(msg);
+            return ParserState::End;
+        }
+
+        self.read_token_as_label_value();
+        if self.error.is_some() {
+            return ParserState::End;
+        }
+
+        let value = match String::from_utf8(self.current_token.clone()) {
+            Ok(s) => s,
+            Err(_) => {
+                let msg = format!(
+                    "invalid label value {:?}",
+                    String::from_utf8_lossy(&self.current_token)
+                );
+                self.parse_error(msg);
+                return ParserState::End;
+            }
+        };
+
+        if self.capture_raw_lines {
+            self.raw_labels
+                .push((self.cur_label_name.clone(), value.clone()));
+        }
+
+        let mf_type = self.current_mf().get_field_type();
+        let is_special = (mf_type == MetricType::SUMMARY && self.cur_label_name == QUANTILE_LABEL)
+            || (mf_type == MetricType::HISTOGRAM && self.cur_label_name == BUCKET_LABEL);
+
+        if is_special {
+            // Quantile and le labels become part of the summary/histogram
+            // value rather than regular labels.
+            match parse_float(&value) {
+                Some(v) if mf_type == MetricType::SUMMARY => {
+                    if self.strict && !(0.0..=1.0).contains(&v) {
+                        self.parse_error(format!("quantile {} is outside [0, 1]", value));
+                        return ParserState::End;
+                    }
+                    self.current_quantile = v
+                }
+                Some(v) => self.current_bucket = v,
+                None => {
+                    let msg = format!(
+                        "expected float as value for '{}' label, got {:?}",
+                        self.cur_label_name, value
+                    );
+                    self.parse_error(msg);
+                    return ParserState::End;
+                }
+            }
+        } else {
+            if mf_type == MetricType::SUMMARY || mf_type == MetricType::HISTOGRAM {
+                self.current_labels
+                    .insert(self.cur_label_name.clone(), value.clone());
+            }
+
+            if let Some(label) = self.cur_metric.mut_label().last_mut() {
+                label.set_value(value.clone());
+            }
+        }
+
+        self.skip_blank_tab();
+        if self.error.is_some() {
+            return ParserState::End;
+        }
+
+        match self.current_byte {
+            b',' => ParserState::Next(TextParser::start_label_name),
+
+            b'}' => {
+                self.skip_blank_tab();
+                if self.error.is_some() {
+                    return ParserState::End;
+                }
+                ParserState::Next(TextParser::reading_value)
+            }
+
+            _ => {
+                self.parse_error(format!("unexpected end of label value {:?}", value));
+                ParserState::End
+            }
+        }
+    }
+
+    fn reading_value(&mut self) -> ParserState<R> {
+        // All labels have been read, so for summaries and histograms we can
+        // finally find out if the metric already exists.
+        let mf_type = self.current_mf().get_field_type();
+        let metric = std::mem::take(&mut self.cur_metric);
+
+        self.cur_metric_idx = match mf_type {
+            MetricType::SUMMARY | MetricType::HISTOGRAM => {
+                let signature = labels_to_signature(&self.current_labels);
+                let existing = if mf_type == MetricType::SUMMARY {
+                    self.summaries.get(&signature)
+                } else {
+                    self.histograms.get(&signature)
+                };
+
+                match existing {
+                    Some(idx) => *idx,
+                    None => {
+                        let idx = self.push_current_metric(metric);
+                        if mf_type == MetricType::SUMMARY {
+                            self.summaries.insert(signature, idx);
+                        } else {
+                            self.histograms.insert(signature, idx);
+                        }
+                        idx
+                    }
+                }
+            }
+            _ => self.push_current_metric(metric),
+        };
+
+        self.read_token_until_white_space();
+        if self.error.is_some() {
+            return ParserState::End;
+        }
+
+        let token = String::from_utf8_lossy(&self.current_token).to_string();
+        let value = match parse_float(&token) {
+            Some(v) => v,
+            None => {
+                self.parse_error(format!("expected float as value, got {:?}", token));
+                return ParserState::End;
+            }
+        };
+
+        let idx = self.cur_metric_idx;
+        let is_summary_count = self.current_is_summary_count;
+        let is_summary_sum = self.current_is_summary_sum;
+        let is_histogram_count = self.current_is_histogram_count;
+        let is_histogram_sum = self.current_is_histogram_sum;
+        let quantile = self.current_quantile;
+        let bucket = self.current_bucket;
+
+        let strict = self.strict;
+
+        let is_count = match mf_type {
+            MetricType::SUMMARY => is_summary_count,
+            MetricType::HISTOGRAM => is_histogram_count || (!is_histogram_sum && !bucket.is_nan()),
+            _ => false,
+        };
+        let count = if is_count {
+            match self.check_count(value) {
+                Some(count) => count,
+                None if self.error.is_some() => return ParserState::End,
+                None => return self.end_of_value(),
+            }
+        } else {
+            0
+        };
+
+        let duplicate = match self.duplicate_policy {
+            DuplicatePolicy::Keep => None,
+            policy => match self.first_sample_of(idx) {
+                Ok(_) => None,
+                Err(series) if policy == DuplicatePolicy::Error => {
+                    self.parse_error(format!("duplicate sample {}", series));
+                    return ParserState::End;
+                }
+                Err(_) => {
+                    self.stats.duplicate_samples += 1;
+                    Some(policy)
+                }
+            },
+        };
+
+        let mut violation = None;
+        let metric = &mut self.current_mf_mut().mut_metric()[idx];
+        match mf_type {
+            MetricType::COUNTER => {
+                let mut counter = Counter::new();
+                counter.set_value(value);
+                metric.set_counter(counter);
+            }
+            MetricType::GAUGE => {
+                let mut gauge = Gauge::new();
+                gauge.set_value(value);
+                metric.set_gauge(gauge);
+            }
+            MetricType::UNTYPED => {
+                let mut untyped = Untyped::new();
+                untyped.set_value(value);
+                metric.set_untyped(untyped);
+            }
+            MetricType::SUMMARY => {
+                let summary = metric.mut_summary();
+                if is_summary_count {
+                    let count = resolve_count(duplicate, summary.get_sample_count(), count);
+                    summary.set_sample_count(count);
+                } else if is_summary_sum {
+                    summary.set_sample_sum(resolve(duplicate, summary.get_sample_sum(), value));
+                } else if !quantile.is_nan() {
+                    let existing = summary
+                        .mut_quantile()
+                        .iter_mut()
+                        .find(|q| duplicate.is_some() && q.get_quantile() == quantile);
+                    match existing {
+                        Some(_) if duplicate == Some(DuplicatePolicy::FirstWins) => {}
+                        Some(q) => q.set_value(value),
+                        None => {
+                            let mut q = Quantile::new();
+                            q.set_quantile(quantile);
+                            q.set_value(value);
+                            summary.mut_quantile().push(q);
+                        }
+                    }
+                }
+            }
+            MetricType::HISTOGRAM => {
+                let histogram = metric.mut_histogram();
+                let existing = histogram
+                    .mut_bucket()
+                    .iter_mut()
+                    .find(|b| duplicate.is_some() && b.get_upper_bound() == bucket);
+                if is_histogram_count {
+                    let count = resolve_count(duplicate, histogram.get_sample_count(), count);
+                    histogram.set_sample_count(count);
+                } else if is_histogram_sum {
+                    let sum = resolve(duplicate, histogram.get_sample_sum(), value);
+                    histogram.set_sample_sum(sum);
+                } else if let Some(b) = existing {
+                    let count = resolve_count(duplicate, b.get_cumulative_count(), count);
+                    b.set_cumulative_count(count);
+                } else if !bucket.is_nan() {
+                    if let Some(prev) = histogram.get_bucket().last().filter(|_| strict) {
+                        if bucket <= prev.get_upper_bound() {
+                            violation = Some(format!(
+                                "bucket le=\"{}\" is not greater than the previous bucket",
+                                bucket
+                            ));
+                        } else if count < prev.get_cumulative_count() {
+                            violation = Some(format!(
+                                "non-monotonic bucket counts: le=\"{}\" has {} after {}",
+                                bucket,
+                                value,
+                                prev.get_cumulative_count()
+                            ));
+                        }
+                    }
+
+                    let mut b = Bucket::new();
+                    b.set_upper_bound(bucket);
+                    b.set_cumulative_count(count);
+                    histogram.mut_bucket().push(b);
+                }
+            }
+        }
+
+        if let Some(msg) = violation {
+            self.parse_error(msg);
+            return ParserState::End;
+        }
+
+        if self.capture_raw_lines {
+            self.raw_labels.sort_unstable();
+            self.raw_series = Some(render_series(&self.raw_name, &self.raw_labels));
+        }
+
+        self.end_of_value()
+    }
+
+    fn end_of_value(&mut self) -> ParserState<R> {
+        if self.current_byte == b'\n' {
+            return ParserState::Next(TextParser::start_of_line);
+        }
+
+        ParserState::Next(TextParser::start_timestamp)
+    }
+
+    fn start_timestamp(&mut self) -> ParserState<R> {
+        self.skip_blank_tab();
+        if self.error.is_some() {
+            return ParserState::End;
+        }
+
+        self.read_token_until_white_space();
+        if self.error.is_some() {
+            return ParserState::End;
+        }
+
+        if self.openmetrics && self.current_token.starts_with(b"#") {
+            // An exemplar, which is dropped.
+            return self.skip_to_next_line();
+        }
+
+        let token = String::from_utf8_lossy(&self.current_token).to_string();
+        let timestamp = if self.openmetrics {
+            // OpenMetrics timestamps are seconds, possibly fractional.
+            token
+                .parse::<f64>()
+                .ok()
+                .filter(|ts| ts.is_finite())
+                .map(|ts| (ts * 1000.0).round() as i64)
+        } else {
+            token.parse::<i64>().ok()
+        };
+        let timestamp = match timestamp {
+            Some(ts) => ts,
+            None => {
+                self.parse_error(format!("expected integer as timestamp, got {:?}", token));
+                return ParserState::End;
+            }
+        };
+
+        let idx = self.cur_metric_idx;
+        self.current_mf_mut().mut_metric()[idx].set_timestamp_ms(timestamp);
+
+        self.read_token_until_newline(false);
+        if self.error.is_some() {
+            return ParserState::End;
+        }
+
+        if self.openmetrics && self.current_token.trim_ascii_start().starts_with(b"#") {
+            self.current_token.clear();
+        }
+
+        if !self.current_token.is_empty() {
+            let msg = format!(
+                "spurious string after timestamp: {:?}",
+                String::from_utf8_lossy(&self.current_token)
+            );
+            self.parse_error(msg);
+            return ParserState::End;
+        }
+
+        ParserState::Next(TextParser::start_of_line)
+    }
+
+    /// Whether the metric name just read is the `_created` sample of an
+    /// OpenMetrics counter, summary or histogram.
+    fn is_created_sample(&self) -> bool {
+        let name = match str::from_utf8(&self.current_token) {
+            Ok(name) => name,
+            Err(_) => return false,
+        };
+        let base = match name.strip_suffix("_created") {
+            Some(base) => base,
+            None => return false,
+        };
+
+        let counter = self.mf_by_name.get(&format!("{}_total", base));
+        if counter.map(|mf| mf.get_field_type()) == Some(MetricType::COUNTER) {
+            return true;
+        }
+
+        matches!(
+            self.mf_by_name.get(base).map(|mf| mf.get_field_type()),
+            Some(MetricType::SUMMARY) | Some(MetricType::HISTOGRAM)
+        )
+    }
+
+    fn skip_to_next_line(&mut self) -> ParserState<R> {
+        while self.current_byte != b'\n' {
+            self.read_byte();
+            if self.error.is_some() {
+                return ParserState::End;
+            }
+        }
+        ParserState::Next(TextParser::start_of_line)
+    }
+
+    /// Records the sample of the current line, which went to the metric at
+    /// `idx`, and returns it, or if an earlier line had the same sample,
+    /// its rendered series. Duplicate counter, gauge and untyped samples
+    /// are left for [`TextParser::resolve_duplicates`].
+    fn first_sample_of(&mut self, idx: usize) -> Result<usize, String> {
+        let mf_type = self.current_mf().get_field_type();
+        let (name, mut labels): (String, Vec<(String, String)>) = match mf_type {
+            MetricType::SUMMARY | MetricType::HISTOGRAM => {
+                let mut labels: Vec<(String, String)> = self
+                    .current_labels
+                    .iter()
+                    .filter(|(name, _)| *name != METRIC_NAME_LABEL)
+                    .map(|(name, value)| (name.clone(), value.clone()))
+                    .collect();
+                let suffix = if self.current_is_summary_count || self.current_is_histogram_count {
+                    "_count"
+                } else if self.current_is_summary_sum || self.current_is_histogram_sum {
+                    "_sum"
+                } else if mf_type == MetricType::HISTOGRAM {
+                    labels.push((BUCKET_LABEL.to_string(), format_float(self.current_bucket)));
+                    "_bucket"
+                } else {
+                    labels.push((
+                        QUANTILE_LABEL.to_string(),
+                        format_float(self.current_quantile),
+                    ));
+                    ""
+                };
+                (format!("{}{}", self.cur_mf_name, suffix), labels)
+            }
+            _ => (
+                self.cur_mf_name.clone(),
+                self.current_mf().get_metric()[idx]
+                    .get_label()
+                    .iter()
+                    .map(|l| (l.get_name().to_string(), l.get_value().to_string()))
+                    .collect(),
+            ),
+        };
+        labels.sort_unstable();
+        let series = render_series(&name, &labels);
+
+        match self.seen_samples.get(&series) {
+            None => {
+                self.seen_samples.insert(series, idx);
+                Ok(idx)
+            }
+            Some(&first) => {
+                if !matches!(mf_type, MetricType::SUMMARY | MetricType::HISTOGRAM) {
+                    self.duplicates.push((self.cur_mf_name.clone(), first, idx));
+                }
+                Err(series)
+            }
+        }
+    }
+
+    /// Folds duplicate counter, gauge and untyped metrics into the first of
+    /// their series under the duplicate policy and removes them.
+    fn resolve_duplicates(&mut self) {
+        let mut dropped: HashMap<String, HashSet<usize>> = HashMap::new();
+        for (family, first, dup) in std::mem::take(&mut self.duplicates) {
+            let Some(mf) = self.mf_by_name.get_mut(&family) else {
+                continue;
+            };
+            let mf_type = mf.get_field_type();
+            let metrics = mf.mut_metric();
+            if dup >= metrics.len() {
+                continue;
+            }
+            match self.duplicate_policy {
+                DuplicatePolicy::LastWins => metrics[first] = metrics[dup].clone(),
+                DuplicatePolicy::Sum => {
+                    let (a, b) = (&metrics[first], &metrics[dup]);
+                    let sum = match mf_type {
+                        MetricType::COUNTER => {
+                            a.get_counter().get_value() + b.get_counter().get_value()
+                        }
+                        MetricType::GAUGE => a.get_gauge().get_value() + b.get_gauge().get_value(),
+                        _ => a.get_untyped().get_value() + b.get_untyped().get_value(),
+                    };
+                    match mf_type {
+                        MetricType::COUNTER => metrics[first].mut_counter().set_value(sum),
+                        MetricType::GAUGE => metrics[first].mut_gauge().set_value(sum),
+                        _ => metrics[first].mut_untyped().set_value(sum),
+                    }
+                }
+                _ => {}
+            }
+            dropped.entry(family).or_default().insert(dup);
+        }
+
+        for (family, dropped) in dropped {
+            if let Some(mf) = self.mf_by_name.get_mut(&family) {
+                let metrics: Vec<Metric> = mf
+                    .take_metric()
+                    .into_iter()
+                    .enumerate()
+                    .filter(|(i, _)| !dropped.contains(i))
+                    .map(|(_, m)| m)
+                    .collect();
+                mf.set_metric(metrics.into());
+            }
+        }
+    }
+
+    fn push_current_metric(&mut self, metric: Metric) -> usize {
+        let metrics = self.current_mf_mut().mut_metric();
+        metrics.push(metric);
+        metrics.len() - 1
+    }
+
+    /// Converts a histogram or summary count under the count policy.
+    /// Returns `None` if the sample is skipped or, with an error set, if
+    /// parsing must stop.
+    fn check_count(&mut self, value: f64) -> Option<u64> {
+        if (0.0..=MAX_EXACT_COUNT).contains(&value) && value.fract() == 0.0 {
+            return Some(value as u64);
+        }
+        match self.count_policy {
+            CountPolicy::Error => {
+                self.parse_error(format!(
+                    "count {} is not an integer between 0 and 2^53",
+                    value
+                ));
+                None
+            }
+            CountPolicy::Clamp => {
+                self.stats.clamped_counts += 1;
+                // `as` maps NaN to 0 and truncates fractions.
+                Some(value.clamp(0.0, MAX_EXACT_COUNT) as u64)
+            }
+            CountPolicy::Skip => {
+                self.stats.skipped_counts += 1;
+                None
+            }
+        }
+    }
+
+    fn parse_error(&mut self, msg: String) {
+        self.raw_series = None;
+        self.error = Some(Box::new(ParseError {
+            line: self.line_count,
+            column: self.column(),
+            msg,
+        }));
+    }
+
+    fn column(&self) -> i32 {
+        (self.reading_bytes - self.line_start).max(1)
     }
 
     fn read_token_until_white_space(&mut self) {
-        println!("in read_token_until_white_space");
         self.current_token.clear();
         loop {
-            if let Some(_err) = &self.error {
+            if self.error.is_some() {
                 break;
             }
 
-            if is_blank_or_tab(self.current_byte) || self.current_byte == '\n' as u8 {
+            if is_blank_or_tab(self.current_byte) || self.current_byte == b'\n' {
                 break;
             }
 
             self.current_token.push(self.current_byte);
             self.read_byte();
         }
-
-        println!(
-            "current token {}",
-            str::from_utf8(&self.current_token).unwrap()
-        );
     }
 
     fn skip_blank_tab(&mut self) {
         loop {
             self.read_byte();
 
-            if let Some(_err) = &self.error {
+            if self.error.is_some() {
                 return;
             }
 
@@ -406,6 +1582,12 @@ impl<R: Read> TextParser<R> {
         }
     }
 
+    fn skip_blank_tab_if_current_blank_tab(&mut self) {
+        if is_blank_or_tab(self.current_byte) {
+            self.skip_blank_tab();
+        }
+    }
+
     fn read_byte(&mut self) {
         let mut buf = [0; 1];
         match self.reader.read_exact(&mut buf) {
@@ -413,6 +1595,9 @@ impl<R: Read> TextParser<R> {
                 self.reading_bytes += 1;
                 self.error = None; // clear error
                 self.current_byte = buf[0];
+                if self.capture_raw_lines {
+                    self.raw_line.push(buf[0]);
+                }
             }
             Err(err) => {
                 self.error = Some(Box::new(err));
@@ -425,30 +1610,35 @@ impl<R: Read> TextParser<R> {
 
         let mut escaped = false;
         loop {
-            if let Some(_err) = &self.error {
+            if self.error.is_some() {
                 return;
             }
 
             if recognize_escape_seq && escaped {
-                match self.current_byte as char {
-                    '\\' => {
+                match self.current_byte {
+                    b'"' if self.openmetrics => {
+                        self.current_token.push(self.current_byte);
+                    }
+                    b'\\' => {
                         self.current_token.push(self.current_byte);
                     }
-                    'n' => {
-                        self.current_token.push('\n' as u8);
+                    b'n' => {
+                        self.current_token.push(b'\n');
                     }
                     _ => {
-                        self.error = Some(Box::new(ParseError {
-                            msg: format!("invalid escape sequence '{}'", self.current_byte),
-                        }))
+                        let msg =
+                            format!("invalid escape sequence '\\{}'", self.current_byte as char);
+                        self.parse_error(msg);
+                        return;
                     }
                 }
+                escaped = false;
             } else {
-                match self.current_byte as char {
-                    '\n' => {
+                match self.current_byte {
+                    b'\n' => {
                         return;
                     }
-                    '\\' => {
+                    b'\\' if recognize_escape_seq => {
                         escaped = true;
                     }
                     _ => {
@@ -461,24 +1651,8 @@ impl<R: Read> TextParser<R> {
     }
 }
 
-fn is_blank_or_tab(b: u8) -> bool {
-    return b == (' ' as u8) || b == ('\t' as u8);
-}
-
-fn is_valid_label_name_start(b: char) -> bool {
-    return (b >= 'a' && b <= 'z') || (b >= 'A' && b <= 'Z') || b == '_';
-}
-
-fn is_valid_label_name_continuation(b: char) -> bool {
-    return is_valid_label_name_start(b) || (b >= '0' && b <= '9');
-}
-
-fn is_valid_metric_name_start(b: char) -> bool {
-    return is_valid_label_name_start(b) || b == ':';
-}
-
-fn _is_valid_metric_name_continuation(b: char) -> bool {
-    return is_valid_label_name_continuation(b) || b == ':';
+fn is_eof(err: &(dyn Error + 'static)) -> bool {
+    matches!(err.downcast_ref::<io::Error>(), Some(e) if e.kind() == io::ErrorKind::UnexpectedEof)
 }
 
 fn summary_metric_name(name: &str) -> &str {
@@ -486,8 +1660,6 @@ fn summary_metric_name(name: &str) -> &str {
         &name[0..name.len() - 6]
     } else if is_sum(name) {
         &name[0..name.len() - 4]
-    } else if is_bucket(name) {
-        &name[0..name.len() - 7]
     } else {
         name
     }
@@ -506,21 +1678,66 @@ fn histogram_metric_name(name: &str) -> &str {
 }
 
 fn is_count(name: &str) -> bool {
-    return name.ends_with("_count");
+    name.ends_with("_count")
 }
 
 fn is_sum(name: &str) -> bool {
-    return name.ends_with("_sum");
+    name.ends_with("_sum")
 }
 
 fn is_bucket(name: &str) -> bool {
-    return name.ends_with("_bucket");
+    name.ends_with("_bucket")
 }
 
-#[cfg(test)]
+/// The value of a duplicate sample `new` for one seen as `old`.
+fn resolve(duplicate: Option<DuplicatePolicy>, old: f64, new: f64) -> f64 {
+    match duplicate {
+        Some(DuplicatePolicy::FirstWins) => old,
+        Some(DuplicatePolicy::Sum) => old + new,
+        _ => new,
+    }
+}
+
+fn resolve_count(duplicate: Option<DuplicatePolicy>, old: u64, new: u64) -> u64 {
+    match duplicate {
+        Some(DuplicatePolicy::FirstWins) => old,
+        Some(DuplicatePolicy::Sum) => old.saturating_add(new),
+        _ => new,
+    }
+}
+
+/// Builds a stable key from a label set by joining its sorted pairs.
+pub(crate) fn labels_to_signature<K, V>(labels: impl IntoIterator<Item = (K, V)>) -> String
+where
+    K: AsRef<str>,
+    V: AsRef<str>,
+{
+    let mut pairs: Vec<(K, V)> = labels.into_iter().collect();
+    pairs.sort_by(|(a, x), (b, y)| (a.as_ref(), x.as_ref()).cmp(&(b.as_ref(), y.as_ref())));
+
+    let mut signature = String::new();
+    for (name, value) in pairs {
+        signature.push_str(name.as_ref());
+        signature.push('\u{ff}');
+        signature.push_str(value.as_ref());
+        signature.push('\u{ff}');
+    }
+    signature
+}
+
+fn normalize_whitespace(s: &str) -> String {
+    s.split_whitespace().collect::<Vec<_>>().join(" ")
+}
 
+#[cfg(test)]
 mod tests {
     use super::*;
+    use std::io::{BufReader, Cursor};
+
+    fn parse(text: &str) -> Result<HashMap<String, MetricFamily>, ParseError> {
+        let cursor = Cursor::new(text.to_string().into_bytes());
+        TextParser::new(BufReader::new(cursor)).text_to_metric_families()
+    }
 
     #[test]
     fn test_basic_parse() {
@@ -545,10 +1762,319 @@ http_request_total{path="/api/v1",method="GET"} 4711
 
         let mut parser = TextParser::new(BufReader::new(cursor));
 
-        let _ = parser.text_to_metric_families();
-        println!(
-            "reading bytes: {}, lines: {}",
-            parser.reading_bytes, parser.line_count
+        let mfs = parser.text_to_metric_families().unwrap();
+        assert_eq!(parser.line_count, 13);
+        assert_eq!(mfs.len(), 2);
+
+        let summary = &mfs["http_request_duration_seconds"];
+        assert_eq!(summary.get_field_type(), MetricType::SUMMARY);
+        assert_eq!(summary.get_metric().len(), 1);
+        let s = summary.get_metric()[0].get_summary();
+        assert_eq!(s.get_quantile().len(), 3);
+        assert_eq!(s.get_sample_count(), 1000);
+        assert_eq!(s.get_sample_sum(), 15.678);
+
+        let counter = &mfs["http_request_total"];
+        assert_eq!(counter.get_help(), "The total number of HTTP requests.");
+        assert_eq!(counter.get_metric().len(), 2);
+        assert_eq!(counter.get_metric()[1].get_counter().get_value(), 4711.0);
+        assert_eq!(counter.get_metric()[1].get_label()[1].get_value(), "GET");
+    }
+
+    #[test]
+    fn test_histogram_and_timestamp() {
+        let mfs = parse(
+            r#"# TYPE rpc_duration histogram
+rpc_duration_bucket{le="0.1",code="200"} 3
+rpc_duration_bucket{le="+Inf",code="200"} 5
+rpc_duration_sum{code="200"} 1.5
+rpc_duration_count{code="200"} 5
+untyped_metric{a="x\"y\\z"} -1.5 1700000000000
+"#,
+        )
+        .unwrap();
+
+        let h = mfs["rpc_duration"].get_metric()[0].get_histogram();
+        assert_eq!(h.get_bucket().len(), 2);
+        assert!(h.get_bucket()[1].get_upper_bound().is_infinite());
+        assert_eq!(h.get_sample_count(), 5);
+
+        let m = &mfs["untyped_metric"].get_metric()[0];
+        assert_eq!(mfs["untyped_metric"].get_field_type(), MetricType::UNTYPED);
+        assert_eq!(m.get_label()[0].get_value(), "x\"y\\z");
+        assert_eq!(m.get_untyped().get_value(), -1.5);
+        assert_eq!(m.get_timestamp_ms(), 1700000000000);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let err = parse("metric{a=\"b\"} x\n").unwrap_err();
+        assert_eq!((err.line, err.column), (1, 16));
+
+        let err = parse("# TYPE a counter\n# TYPE a gauge\n").unwrap_err();
+        assert_eq!((err.line, err.column), (2, 15));
+
+        let err = parse("a 1\n  b{1=\"x\"} 2\n").unwrap_err();
+        assert_eq!((err.line, err.column), (2, 5));
+
+        let err = parse("# HELP a bad \\t escape\n").unwrap_err();
+        assert!(err.msg.contains("invalid escape sequence"));
+
+        let err = parse("metric 1").unwrap_err();
+        assert_eq!(err.msg, "unexpected end of input stream");
+    }
+
+    #[test]
+    fn test_require_type() {
+        let text = "# TYPE a histogram\na_bucket{le=\"+Inf\"} 1\na_count 1\n\
+                    # HELP b Untyped.\nb 1\nc 2\n";
+        assert!(parse(text).is_ok());
+
+        let errors = TextParser::new(text.as_bytes())
+            .require_type(true)
+            .validate();
+        let msgs: Vec<_> = errors.iter().map(|e| (e.line, e.msg.as_str())).collect();
+        assert_eq!(
+            msgs,
+            vec![
+                (5, "sample of \"b\" before any TYPE line"),
+                (6, "sample of \"c\" before any TYPE line"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_children_out_of_order() {
+        let histogram = "# TYPE h histogram\n\
+                         h_bucket{x=\"a\",le=\"1\"} 1\nh_bucket{x=\"a\",le=\"+Inf\"} 2\n\
+                         h_sum{x=\"a\"} 3\nh_count{x=\"a\"} 2\n";
+        let summary = "# TYPE s summary\n\
+                       s{quantile=\"0.5\"} 1 10\ns{quantile=\"0.9\"} 4 10\n\
+                       s_sum 7 10\ns_count 3 10\n";
+        let cases = [
+            // _sum and _count before the buckets.
+            (
+                histogram,
+                "# TYPE h histogram\nh_sum{x=\"a\"} 3\nh_count{x=\"a\"} 2\n\
+                 h_bucket{x=\"a\",le=\"1\"} 1\nh_bucket{x=\"a\",le=\"+Inf\"} 2\n",
+            ),
+            // All children before TYPE.
+            (
+                histogram,
+                "h_count{x=\"a\"} 2\nh_bucket{x=\"a\",le=\"+Inf\"} 2\n\
+                 h_sum{x=\"a\"} 3\nh_bucket{x=\"a\",le=\"1\"} 1\n# TYPE h histogram\n",
+            ),
+            // Some children before TYPE, the rest after.
+            (
+                histogram,
+                "h_bucket{x=\"a\",le=\"1\"} 1\nh_sum{x=\"a\"} 3\n# TYPE h histogram\n\
+                 h_bucket{x=\"a\",le=\"+Inf\"} 2\nh_count{x=\"a\"} 2\n",
+            ),
+            // Summary quantiles and _count before TYPE.
+            (
+                summary,
+                "s_count 3 10\ns{quantile=\"0.9\"} 4 10\ns{quantile=\"0.5\"} 1 10\n\
+                 # TYPE s summary\ns_sum 7 10\n",
+            ),
+        ];
+        for (canonical, shuffled) in cases {
+            assert_eq!(
+                parse(shuffled).unwrap(),
+                parse(canonical).unwrap(),
+                "{}",
+                shuffled
+            );
+        }
+
+        // Interleaved families.
+        let canonical = format!("{}{}# TYPE c counter\nc 1\n", histogram, summary);
+        let shuffled = "s_sum 7 10\nh_sum{x=\"a\"} 3\n# TYPE c counter\n\
+                        h_bucket{x=\"a\",le=\"1\"} 1\ns{quantile=\"0.5\"} 1 10\nc 1\n\
+                        # TYPE s summary\nh_count{x=\"a\"} 2\ns_count 3 10\n\
+                        s{quantile=\"0.9\"} 4 10\nh_bucket{x=\"a\",le=\"+Inf\"} 2\n\
+                        # TYPE h histogram\n";
+        assert_eq!(parse(shuffled).unwrap(), parse(&canonical).unwrap());
+
+        // A counter cannot claim samples after the fact.
+        assert!(parse("c 1\n# TYPE c counter\n").is_err());
+        assert!(parse("# TYPE h gauge\nh 1\n# TYPE h histogram\n").is_err());
+    }
+
+    #[test]
+    fn test_count_policy() {
+        let text = "# TYPE h histogram\nh_bucket{le=\"1\"} -1\nh_bucket{le=\"+Inf\"} 1e20\n\
+                    h_sum 2\nh_count 2.5\n# TYPE s summary\ns_count 3\n";
+        let parse_with = |policy| {
+            let mut parser = TextParser::new(text.as_bytes()).count_policy(policy);
+            parser
+                .text_to_metric_families()
+                .map(|mfs| (mfs, parser.stats()))
+        };
+
+        let (mfs, stats) = parse_with(CountPolicy::Clamp).unwrap();
+        let h = mfs["h"].get_metric()[0].get_histogram();
+        let counts: Vec<u64> = h
+            .get_bucket()
+            .iter()
+            .map(|b| b.get_cumulative_count())
+            .collect();
+        assert_eq!(counts, vec![0, 1 << 53]);
+        assert_eq!(h.get_sample_count(), 2);
+        assert_eq!(stats.clamped_counts, 3);
+
+        let (mfs, stats) = parse_with(CountPolicy::Skip).unwrap();
+        let h = mfs["h"].get_metric()[0].get_histogram();
+        assert!(h.get_bucket().is_empty());
+        assert!(!h.has_sample_count());
+        assert_eq!(h.get_sample_sum(), 2.0);
+        assert_eq!(mfs["s"].get_metric()[0].get_summary().get_sample_count(), 3);
+        assert_eq!(stats.skipped_counts, 3);
+
+        let err = parse_with(CountPolicy::Error).unwrap_err();
+        assert_eq!(
+            (err.line, err.msg.as_str()),
+            (2, "count -1 is not an integer between 0 and 2^53")
+        );
+
+        // Counts of samples read before their TYPE line are checked too.
+        let mut parser = TextParser::new("h_count -1\n# TYPE h histogram\n".as_bytes())
+            .count_policy(CountPolicy::Error);
+        assert!(parser.text_to_metric_families().is_err());
+    }
+
+    #[test]
+    fn test_duplicate_policy() {
+        let text = "# TYPE c counter\nc{a=\"1\"} 1\nc{a=\"2\"} 5\nc{a=\"1\"} 2\n\
+                    # TYPE h histogram\nh_bucket{le=\"1\"} 1\nh_bucket{le=\"+Inf\"} 2\n\
+                    h_bucket{le=\"1\"} 3\nh_sum 4\nh_sum 6\nh_count 2\n";
+        let parse_with = |policy| {
+            let mut parser = TextParser::new(text.as_bytes()).duplicate_policy(policy);
+            parser
+                .text_to_metric_families()
+                .map(|mfs| (mfs, parser.stats()))
+        };
+        let values = |mfs: &HashMap<String, MetricFamily>| {
+            let c: Vec<f64> = mfs["c"]
+                .get_metric()
+                .iter()
+                .map(|m| m.get_counter().get_value())
+                .collect();
+            let h = mfs["h"].get_metric()[0].get_histogram();
+            let buckets: Vec<u64> = h
+                .get_bucket()
+                .iter()
+                .map(|b| b.get_cumulative_count())
+                .collect();
+            (c, buckets, h.get_sample_sum())
+        };
+
+        let (mfs, stats) = parse_with(DuplicatePolicy::Keep).unwrap();
+        assert_eq!(values(&mfs), (vec![1.0, 5.0, 2.0], vec![1, 2, 3], 6.0));
+        assert_eq!(stats.duplicate_samples, 0);
+
+        let (mfs, stats) = parse_with(DuplicatePolicy::FirstWins).unwrap();
+        assert_eq!(values(&mfs), (vec![1.0, 5.0], vec![1, 2], 4.0));
+        assert_eq!(stats.duplicate_samples, 3);
+
+        let (mfs, _) = parse_with(DuplicatePolicy::LastWins).unwrap();
+        assert_eq!(values(&mfs), (vec![2.0, 5.0], vec![3, 2], 6.0));
+
+        let (mfs, _) = parse_with(DuplicatePolicy::Sum).unwrap();
+        assert_eq!(values(&mfs), (vec![3.0, 5.0], vec![4, 2], 10.0));
+
+        let err = parse_with(DuplicatePolicy::Error).unwrap_err();
+        assert_eq!(
+            (err.line, err.msg.as_str()),
+            (4, "duplicate sample c{a=\"1\"}")
+        );
+        assert!("newest".parse::<DuplicatePolicy>().is_err());
+    }
+
+    #[test]
+    fn test_raw_lines() {
+        let text = "# TYPE h histogram\n\
+                    h_bucket{le=\"1.0\", a=\"x\"} 1\n\
+                    h_bucket{a=\"x\",le=\"+Inf\"} 2.5 17\n\
+                    \n\
+                    up   1\n";
+        let mut parser = TextParser::new(text.as_bytes()).count_policy(CountPolicy::Skip);
+        assert!(parser.text_to_metric_families().is_ok());
+        assert!(parser.take_raw_lines().is_empty());
+
+        let mut parser = TextParser::new(text.as_bytes())
+            .count_policy(CountPolicy::Skip)
+            .capture_raw_lines(true);
+        parser.text_to_metric_families().unwrap();
+        let lines: Vec<_> = parser
+            .take_raw_lines()
+            .into_iter()
+            .map(|raw| {
+                assert_eq!(&text[raw.span.clone()], raw.text);
+                (raw.line, raw.series, raw.text)
+            })
+            .collect();
+        // The fractional count was skipped.
+        assert_eq!(
+            lines,
+            [
+                (
+                    2,
+                    "h_bucket{a=\"x\",le=\"1.0\"}".to_string(),
+                    "h_bucket{le=\"1.0\", a=\"x\"} 1".to_string()
+                ),
+                (5, "up".to_string(), "up   1".to_string()),
+            ]
         );
+
+        // Lines of samples that failed are left out.
+        let mut parser = TextParser::new("a 1\nb 2 x\nc 3\n".as_bytes()).capture_raw_lines(true);
+        assert_eq!(parser.validate().len(), 1);
+        let series: Vec<_> = parser
+            .take_raw_lines()
+            .into_iter()
+            .map(|raw| raw.series)
+            .collect();
+        assert_eq!(series, ["a", "c"]);
+    }
+
+    #[test]
+    fn test_help_escapes() {
+        let mfs = parse("# HELP m a\\\\b\\nc\nm 1\n").unwrap();
+        assert_eq!(mfs["m"].get_help(), "a\\b\nc");
+
+        let cursor = Cursor::new(b"# HELP m  spread \\n over\tlines \nm 1\n".to_vec());
+        let mfs = TextParser::new(BufReader::new(cursor))
+            .normalize_help(true)
+            .text_to_metric_families()
+            .unwrap();
+        assert_eq!(mfs["m"].get_help(), "spread over lines");
+    }
+
+    #[test]
+    fn test_validate_reports_every_error() {
+        let text = r#"# TYPE h histogram
+h_bucket{le="1"} 5
+h_bucket{le="2"} 3
+h_bucket{le="0.5"} 6
+# TYPE s summary
+s{quantile="1.5"} 1
+# TYPE s gauge
+ok 1
+bad{a="\x"} 1
+"#;
+        let cursor = Cursor::new(text.to_string().into_bytes());
+        let errors = TextParser::new(BufReader::new(cursor))
+            .strict(true)
+            .validate();
+
+        let lines: Vec<_> = errors.iter().map(|e| e.line).collect();
+        assert_eq!(lines, vec![3, 4, 6, 7, 9]);
+        assert!(errors[1].msg.contains("not greater than"));
+        assert!(errors[3].msg.contains("second TYPE line"));
+
+        let cursor = Cursor::new(b"# TYPE s summary\ns{quantile=\"1.5\"} 1\n".to_vec());
+        assert!(TextParser::new(BufReader::new(cursor))
+            .validate()
+            .is_empty());
     }
 }
@@ -0,0 +1,110 @@
+use jiff::tz::TimeZone;
+use jiff::Timestamp;
+use std::str::FromStr;
+
+/// How outputs meant for reading show sample timestamps. Machine formats
+/// always keep the raw milliseconds.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TimeStyle {
+    /// Milliseconds since the Unix epoch, as in the exposition formats.
+    Raw,
+    /// RFC 3339 in UTC.
+    Utc,
+    /// RFC 3339 with the offset of a time zone.
+    Zone(TimeZone),
+    /// Distance from now, such as `5m ago`.
+    Relative,
+}
+
+impl FromStr for TimeStyle {
+    type Err = String;
+
+    /// Parses `raw`, `utc`, `local`, `relative` or an IANA time zone name
+    /// such as `Europe/Berlin`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "raw" => Ok(TimeStyle::Raw),
+            "utc" | "UTC" => Ok(TimeStyle::Utc),
+            "local" => Ok(TimeStyle::Zone(TimeZone::system())),
+            "relative" => Ok(TimeStyle::Relative),
+            _ => TimeZone::get(s).map(TimeStyle::Zone).map_err(|_| {
+                format!(
+                    "expected raw, utc, local, relative or a time zone name, got {:?}",
+                    s
+                )
+            }),
+        }
+    }
+}
+
+impl TimeStyle {
+    /// Renders `ms` milliseconds since the epoch, relative to the current
+    /// time if the style is.
+    pub fn format(&self, ms: i64) -> String {
+        self.format_at(ms, Timestamp::now().as_millisecond())
+    }
+
+    /// Like [`TimeStyle::format`], with `now_ms` as the current time.
+    pub fn format_at(&self, ms: i64, now_ms: i64) -> String {
+        let ts = match self {
+            TimeStyle::Raw => return ms.to_string(),
+            TimeStyle::Relative => return relative(ms, now_ms),
+            TimeStyle::Utc | TimeStyle::Zone(_) => match Timestamp::from_millisecond(ms) {
+                Ok(ts) => ts,
+                // Beyond what RFC 3339 can express.
+                Err(_) => return ms.to_string(),
+            },
+        };
+        match self {
+            TimeStyle::Zone(tz) => ts.display_with_offset(tz.to_offset(ts)).to_string(),
+            _ => ts.to_string(),
+        }
+    }
+}
+
+/// `now`, or the distance in the largest whole unit: `42s ago`, `5m ago`,
+/// `in 3h`, `2d ago`.
+fn relative(ms: i64, now_ms: i64) -> String {
+    let delta = now_ms.saturating_sub(ms);
+    let secs = delta.unsigned_abs() / 1000;
+    if secs == 0 {
+        return "now".to_string();
+    }
+    let amount = match secs {
+        0..=59 => format!("{}s", secs),
+        60..=3599 => format!("{}m", secs / 60),
+        3600..=86399 => format!("{}h", secs / 3600),
+        _ => format!("{}d", secs / 86400),
+    };
+    if delta > 0 {
+        format!("{} ago", amount)
+    } else {
+        format!("in {}", amount)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format() {
+        let ms = 1_700_000_000_123;
+        assert_eq!(TimeStyle::Raw.format_at(ms, 0), "1700000000123");
+        assert_eq!(TimeStyle::Utc.format_at(ms, 0), "2023-11-14T22:13:20.123Z");
+        assert_eq!(
+            TimeStyle::Utc.format_at(1_700_000_000_000, 0),
+            "2023-11-14T22:13:20Z"
+        );
+
+        let cet = TimeStyle::Zone(TimeZone::fixed(jiff::tz::offset(1)));
+        assert_eq!(cet.format_at(ms, 0), "2023-11-14T23:13:20.123+01:00");
+        assert!("Mars/Olympus".parse::<TimeStyle>().is_err());
+
+        let now = ms + 5 * 60_000 + 30_000;
+        assert_eq!(TimeStyle::Relative.format_at(ms, now), "5m ago");
+        assert_eq!(TimeStyle::Relative.format_at(ms, ms + 999), "now");
+        assert_eq!(TimeStyle::Relative.format_at(ms, ms - 7_200_000), "in 2h");
+        assert_eq!(TimeStyle::Relative.format_at(0, ms), "19675d ago");
+    }
+}
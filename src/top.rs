@@ -0,0 +1,100 @@
+use prometheus::proto::{MetricFamily, MetricType};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+use crate::flatten::{flatten, FlatSample};
+
+/// Returns the `n` samples with the largest values, largest first. `NaN`
+/// samples are ignored; ties keep input order.
+pub fn top(mut samples: Vec<FlatSample>, n: usize) -> Vec<FlatSample> {
+    samples.retain(|s| !s.value.is_nan());
+    samples.sort_by(|a, b| b.value.partial_cmp(&a.value).unwrap_or(Ordering::Equal));
+    samples.truncate(n);
+    samples
+}
+
+/// Flattens the cumulative samples of `new` (counters, summary and histogram
+/// counts and sums) and replaces each value by its increase since `old`.
+///
+/// A decrease is taken as a counter reset, so the increase is the new value,
+/// as is the case for series missing from `old`. Gauges and untyped samples
+/// are dropped, since they have no meaningful delta.
+pub fn counter_deltas(old: &[MetricFamily], new: &[MetricFamily]) -> Vec<FlatSample> {
+    let old_values: HashMap<String, f64> = flatten(old)
+        .into_iter()
+        .map(|s| (s.series_key(), s.value))
+        .collect();
+
+    flatten(new)
+        .into_iter()
+        .filter(is_cumulative)
+        .map(|mut s| {
+            match old_values.get(&s.series_key()) {
+                Some(&old) if s.value >= old => s.value -= old,
+                _ => {}
+            }
+            s
+        })
+        .collect()
+}
+
+fn is_cumulative(s: &FlatSample) -> bool {
+    match s.metric_type {
+        MetricType::COUNTER => true,
+        // Quantiles are not cumulative, bucket counts and sums are.
+        MetricType::SUMMARY => s.name != s.family,
+        MetricType::HISTOGRAM => true,
+        MetricType::GAUGE | MetricType::UNTYPED => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::{read_metric_families, Format};
+
+    fn parse(text: &str) -> Vec<MetricFamily> {
+        read_metric_families(Format::Text, text.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn test_top() {
+        let mfs = parse("a 3\nb NaN\nc 10\nd -1\n");
+        let names: Vec<_> = top(flatten(&mfs), 2).into_iter().map(|s| s.name).collect();
+        assert_eq!(names, vec!["c", "a"]);
+    }
+
+    #[test]
+    fn test_counter_deltas() {
+        let old = parse(
+            r#"# TYPE requests_total counter
+requests_total{code="200",path="/"} 100
+requests_total{code="500",path="/"} 50
+# TYPE queue gauge
+queue 7
+"#,
+        );
+        let new = parse(
+            r#"# TYPE requests_total counter
+requests_total{path="/",code="200"} 130
+requests_total{code="500",path="/"} 5
+requests_total{code="404",path="/"} 2
+# TYPE queue gauge
+queue 9
+"#,
+        );
+
+        let deltas: Vec<_> = top(counter_deltas(&old, &new), 10)
+            .into_iter()
+            .map(|s| (s.series(), s.value))
+            .collect();
+        assert_eq!(
+            deltas,
+            vec![
+                (r#"requests_total{path="/",code="200"}"#.to_string(), 30.0),
+                (r#"requests_total{code="500",path="/"}"#.to_string(), 5.0),
+                (r#"requests_total{code="404",path="/"}"#.to_string(), 2.0),
+            ]
+        );
+    }
+}
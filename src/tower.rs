@@ -0,0 +1,160 @@
+//! A [`tower_service::Service`] serving metric families over HTTP, for
+//! applications embedding pmv in an axum, hyper or other tower-based
+//! server. Built with the `tower` feature.
+//!
+//! With axum, for instance:
+//!
+//! ```ignore
+//! let metrics = MetricsService::new();
+//! let app = axum::Router::new().route_service("/metrics", metrics.clone());
+//! // Later, from wherever the families are parsed:
+//! metrics.update(families);
+//! ```
+//!
+//! Responses are in the format the request's `Accept` header prefers, as
+//! [`exporter::negotiate`] picks it.
+
+use bytes::Bytes;
+use http::header::{ACCEPT, CONTENT_TYPE};
+use http::{Method, Request, Response, StatusCode};
+use http_body_util::Full;
+use prometheus::proto::MetricFamily;
+use std::convert::Infallible;
+use std::future::{self, Ready};
+use std::sync::{Arc, RwLock};
+use std::task::{Context, Poll};
+
+use crate::exporter;
+use crate::format;
+
+/// Serves the families last passed to [`MetricsService::update`]. Clones
+/// share them, so one clone can be handed to the router and another kept
+/// for updates.
+#[derive(Debug, Clone, Default)]
+pub struct MetricsService {
+    families: Arc<RwLock<Arc<Vec<MetricFamily>>>>,
+}
+
+impl MetricsService {
+    /// A service serving no families until updated.
+    pub fn new() -> Self {
+        MetricsService::default()
+    }
+
+    /// Serves `mfs` from now on.
+    pub fn update(&self, mfs: Vec<MetricFamily>) {
+        *self.families.write().expect("families lock poisoned") = Arc::new(mfs);
+    }
+
+    /// The families being served.
+    pub fn families(&self) -> Arc<Vec<MetricFamily>> {
+        Arc::clone(&self.families.read().expect("families lock poisoned"))
+    }
+
+    /// The response to `request`, whatever its path.
+    pub fn respond<B>(&self, request: &Request<B>) -> Response<Full<Bytes>> {
+        if request.method() != Method::GET && request.method() != Method::HEAD {
+            return plain(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "method not allowed\n".into(),
+            );
+        }
+        let accept = request.headers().get(ACCEPT).and_then(|v| v.to_str().ok());
+        let format = exporter::negotiate(accept);
+        // Encoded outside the lock, so updates do not wait for slow clients.
+        let mfs = self.families();
+        let mut body = Vec::new();
+        match format::write_metric_families(format, &mut body, &mfs) {
+            Ok(()) => Response::builder()
+                .header(CONTENT_TYPE, format.content_type())
+                .body(Full::new(Bytes::from(body)))
+                .expect("valid response"),
+            Err(e) => {
+                log::error!("encoding metrics failed: {}", e);
+                plain(StatusCode::INTERNAL_SERVER_ERROR, format!("{}\n", e))
+            }
+        }
+    }
+}
+
+fn plain(status: StatusCode, text: String) -> Response<Full<Bytes>> {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "text/plain; charset=utf-8")
+        .body(Full::new(Bytes::from(text)))
+        .expect("valid response")
+}
+
+impl<B> tower_service::Service<Request<B>> for MetricsService {
+    type Response = Response<Full<Bytes>>;
+    type Error = Infallible;
+    type Future = Ready<Result<Self::Response, Infallible>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: Request<B>) -> Self::Future {
+        future::ready(Ok(self.respond(&request)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::{read_metric_families, Format};
+    use http_body_util::BodyExt;
+    use std::future::Future;
+    use std::pin::pin;
+    use std::task::Waker;
+    use tower_service::Service;
+
+    // The service's futures are ready at once, so no runtime is needed.
+    fn block_on<F: Future>(f: F) -> F::Output {
+        match pin!(f).poll(&mut Context::from_waker(Waker::noop())) {
+            Poll::Ready(output) => output,
+            Poll::Pending => panic!("future not ready"),
+        }
+    }
+
+    fn get(service: &mut MetricsService, accept: Option<&str>) -> (StatusCode, String, String) {
+        let mut request = Request::get("/metrics");
+        if let Some(accept) = accept {
+            request = request.header(ACCEPT, accept);
+        }
+        let response = block_on(service.call(request.body(()).unwrap())).unwrap();
+        let content_type = response.headers()[CONTENT_TYPE]
+            .to_str()
+            .unwrap()
+            .to_string();
+        let status = response.status();
+        let body = block_on(response.into_body().collect()).unwrap().to_bytes();
+        (
+            status,
+            content_type,
+            String::from_utf8(body.to_vec()).unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_metrics_service() {
+        let mut service = MetricsService::new();
+        let updater = service.clone();
+        assert_eq!(get(&mut service, None).2, "");
+
+        let mfs = read_metric_families(Format::Text, "up 1\n".as_bytes()).unwrap();
+        updater.update(mfs);
+        let (status, content_type, body) = get(&mut service, None);
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(content_type, Format::Text.content_type());
+        assert_eq!(body, "# TYPE up untyped\nup 1\n");
+
+        let (_, content_type, body) = get(&mut service, Some("application/openmetrics-text"));
+        assert_eq!(content_type, Format::OpenMetrics.content_type());
+        assert!(body.ends_with("# EOF\n"), "{}", body);
+
+        let request = Request::post("/metrics").body(()).unwrap();
+        let response = block_on(service.call(request)).unwrap();
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+    }
+}
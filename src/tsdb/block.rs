@@ -0,0 +1,401 @@
+//! Immutable files of compressed series covering a time range.
+//!
+//! Layout, little endian: magic `PMVB`, version byte, `i64 min time`,
+//! `i64 max time`, `u32 series count`, `u32 CRC-32 of the header`, then per
+//! series `u32 key length, key, u32 sample count, u32 chunk length, chunk,
+//! u32 CRC-32 of the entry`.
+//!
+//! Since version 3 the entries are followed by a series index, `u32 key
+//! length, key, u64 entry offset` per series and a `u32 CRC-32` of the
+//! index, and the file ends with the `u64` offset of the index. Queries use
+//! it to read only the entries of matching series. Version 2 blocks, which
+//! lack the index, are still read.
+//!
+//! Since version 4 the index is followed by the label [`Postings`] of the
+//! series, numbered in index order, and their `u32 CRC-32`; the file ends
+//! with the `u64` offset of the postings, then that of the index.
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use super::chunk;
+use super::postings::Postings;
+
+const MAGIC: &[u8; 4] = b"PMVB";
+const VERSION: u8 = 4;
+const MIN_VERSION: u8 = 2;
+const HEADER_LEN: u64 = 29;
+
+/// Samples by series key, sorted by timestamp.
+pub type SeriesMap = BTreeMap<String, Vec<(i64, f64)>>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockMeta {
+    pub min_time: i64,
+    pub max_time: i64,
+    pub num_series: u32,
+}
+
+/// Writes `series` to `path` atomically via a temporary file. Empty series
+/// are skipped. Returns `None` without writing anything if no samples remain.
+pub fn write_block(path: &Path, series: &SeriesMap) -> io::Result<Option<BlockMeta>> {
+    let series: Vec<_> = series.iter().filter(|(_, s)| !s.is_empty()).collect();
+    let (min_time, max_time) = match time_range(&series) {
+        Some(range) => range,
+        None => return Ok(None),
+    };
+    let meta = BlockMeta {
+        min_time,
+        max_time,
+        num_series: series.len() as u32,
+    };
+
+    let tmp = path.with_extension("tmp");
+    {
+        let mut w = BufWriter::new(File::create(&tmp)?);
+
+        let mut header = Vec::with_capacity(25);
+        header.extend_from_slice(MAGIC);
+        header.push(VERSION);
+        header.extend_from_slice(&meta.min_time.to_le_bytes());
+        header.extend_from_slice(&meta.max_time.to_le_bytes());
+        header.extend_from_slice(&meta.num_series.to_le_bytes());
+        w.write_all(&header)?;
+        w.write_all(&crc32fast::hash(&header).to_le_bytes())?;
+
+        let postings = Postings::build(series.iter().map(|(key, _)| key.as_str())).encode();
+        let mut index = Vec::new();
+        let mut offset = HEADER_LEN;
+        for (key, samples) in series {
+            index.extend_from_slice(&(key.len() as u32).to_le_bytes());
+            index.extend_from_slice(key.as_bytes());
+            index.extend_from_slice(&offset.to_le_bytes());
+
+            let data = chunk::encode(samples);
+
+            let mut entry = Vec::with_capacity(12 + key.len() + data.len());
+            entry.extend_from_slice(&(key.len() as u32).to_le_bytes());
+            entry.extend_from_slice(key.as_bytes());
+            entry.extend_from_slice(&(samples.len() as u32).to_le_bytes());
+            entry.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            entry.extend_from_slice(&data);
+            w.write_all(&entry)?;
+            w.write_all(&crc32fast::hash(&entry).to_le_bytes())?;
+            offset += entry.len() as u64 + 4;
+        }
+
+        w.write_all(&index)?;
+        w.write_all(&crc32fast::hash(&index).to_le_bytes())?;
+        w.write_all(&postings)?;
+        w.write_all(&crc32fast::hash(&postings).to_le_bytes())?;
+        let postings_offset = offset + index.len() as u64 + 4;
+        w.write_all(&postings_offset.to_le_bytes())?;
+        w.write_all(&offset.to_le_bytes())?;
+
+        w.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    }
+    fs::rename(&tmp, path)?;
+
+    Ok(Some(meta))
+}
+
+fn time_range(series: &[(&String, &Vec<(i64, f64)>)]) -> Option<(i64, i64)> {
+    let min = series
+        .iter()
+        .filter_map(|(_, s)| s.first())
+        .map(|s| s.0)
+        .min()?;
+    let max = series
+        .iter()
+        .filter_map(|(_, s)| s.last())
+        .map(|s| s.0)
+        .max()?;
+    Some((min, max))
+}
+
+fn read_header(r: &mut impl Read) -> io::Result<(BlockMeta, u8)> {
+    let mut header = [0u8; 25];
+    r.read_exact(&mut header)?;
+    if &header[..4] != MAGIC || !(MIN_VERSION..=VERSION).contains(&header[4]) {
+        return Err(invalid("not a pmv block"));
+    }
+    let crc = u32::from_le_bytes(read_array(r)?);
+    if crc != crc32fast::hash(&header) {
+        return Err(invalid("block header checksum mismatch"));
+    }
+
+    let meta = BlockMeta {
+        min_time: i64::from_le_bytes(header[5..13].try_into().unwrap()),
+        max_time: i64::from_le_bytes(header[13..21].try_into().unwrap()),
+        num_series: u32::from_le_bytes(header[21..25].try_into().unwrap()),
+    };
+    Ok((meta, header[4]))
+}
+
+/// Reads only the header of a block.
+pub fn read_meta(path: &Path) -> io::Result<BlockMeta> {
+    Ok(read_header(&mut BufReader::new(File::open(path)?))?.0)
+}
+
+/// Series keys of a block with the offsets of their entries, or `None` for a
+/// version 2 block without an index.
+pub fn read_index(path: &Path) -> io::Result<Option<Vec<(String, u64)>>> {
+    let mut r = BufReader::new(File::open(path)?);
+    let (meta, version) = read_header(&mut r)?;
+    let Some(footer) = read_footer(&mut r, version)? else {
+        return Ok(None);
+    };
+    let index = read_section(&mut r, footer.index, "index")?;
+
+    let mut entries = Vec::with_capacity(meta.num_series.min(1 << 20) as usize);
+    let mut rest = index.as_slice();
+    while !rest.is_empty() {
+        let entry = parse_index_entry(rest).ok_or_else(|| invalid("truncated index"))?;
+        rest = &rest[12 + entry.0.len()..];
+        entries.push(entry);
+    }
+    Ok(Some(entries))
+}
+
+/// The label postings of a block, numbering series as `read_index` lists
+/// them, or `None` for a block older than version 4.
+pub fn read_postings(path: &Path) -> io::Result<Option<Postings>> {
+    let mut r = BufReader::new(File::open(path)?);
+    let (meta, version) = read_header(&mut r)?;
+    let Some(range) = read_footer(&mut r, version)?.and_then(|f| f.postings) else {
+        return Ok(None);
+    };
+    let data = read_section(&mut r, range, "postings")?;
+    match Postings::decode(&data) {
+        Some(postings) if postings.num_series() == meta.num_series as usize => Ok(Some(postings)),
+        _ => Err(invalid("malformed postings")),
+    }
+}
+
+/// Where the sections after the entries lie, as `(offset, length)` without
+/// their checksums.
+struct Footer {
+    index: (u64, u64),
+    postings: Option<(u64, u64)>,
+}
+
+fn read_footer<R: Read + Seek>(r: &mut R, version: u8) -> io::Result<Option<Footer>> {
+    if version < 3 {
+        return Ok(None);
+    }
+    let footer_len = if version < 4 { 8 } else { 16 };
+    let file_len = r.seek(SeekFrom::End(0))?;
+    if file_len < HEADER_LEN + 4 + footer_len {
+        return Err(invalid("missing index"));
+    }
+    r.seek(SeekFrom::Start(file_len - footer_len))?;
+    let postings_offset = match version {
+        3 => None,
+        _ => Some(u64::from_le_bytes(read_array(r)?)),
+    };
+    let index_offset = u64::from_le_bytes(read_array(r)?);
+
+    // Each section is followed by its checksum.
+    let section = |start: u64, end: u64| {
+        end.checked_sub(4)
+            .and_then(|end| end.checked_sub(start))
+            .filter(|_| start >= HEADER_LEN)
+            .map(|len| (start, len))
+            .ok_or_else(|| invalid("index offset out of range"))
+    };
+    let footer = match postings_offset {
+        None => Footer {
+            index: section(index_offset, file_len - footer_len)?,
+            postings: None,
+        },
+        Some(postings_offset) => Footer {
+            index: section(index_offset, postings_offset)?,
+            postings: Some(section(postings_offset, file_len - footer_len)?),
+        },
+    };
+    Ok(Some(footer))
+}
+
+fn read_section<R: Read + Seek>(
+    r: &mut R,
+    (offset, len): (u64, u64),
+    what: &str,
+) -> io::Result<Vec<u8>> {
+    let len = u32::try_from(len).map_err(|_| invalid(&format!("{} too large", what)))?;
+    r.seek(SeekFrom::Start(offset))?;
+    let data = read_vec(r, len)?;
+    if u32::from_le_bytes(read_array(r)?) != crc32fast::hash(&data) {
+        return Err(invalid(&format!("{} checksum mismatch", what)));
+    }
+    Ok(data)
+}
+
+fn parse_index_entry(data: &[u8]) -> Option<(String, u64)> {
+    let key_len = u32::from_le_bytes(data.get(..4)?.try_into().ok()?) as usize;
+    let key = std::str::from_utf8(data.get(4..4 + key_len)?).ok()?;
+    let offset = u64::from_le_bytes(data.get(4 + key_len..12 + key_len)?.try_into().ok()?);
+    Some((key.to_string(), offset))
+}
+
+/// An entry whose checksum has been verified but whose chunk is not yet
+/// decoded.
+pub struct RawEntry {
+    pub key: String,
+    count: u32,
+    data: Vec<u8>,
+}
+
+impl RawEntry {
+    pub fn decode(&self) -> io::Result<Vec<(i64, f64)>> {
+        chunk::decode(&self.data, self.count as usize).ok_or_else(|| invalid("truncated chunk"))
+    }
+}
+
+/// Reads the entries at `offsets`, as listed by `read_index`.
+pub fn read_entries_at(path: &Path, offsets: &[u64]) -> io::Result<Vec<RawEntry>> {
+    let mut r = BufReader::new(File::open(path)?);
+    let mut entries = Vec::with_capacity(offsets.len());
+    for &offset in offsets {
+        r.seek(SeekFrom::Start(offset))?;
+        entries.push(read_raw_entry(&mut r)?);
+    }
+    Ok(entries)
+}
+
+/// Reads every entry in file order without decoding the chunks.
+pub fn read_entries(path: &Path) -> io::Result<Vec<RawEntry>> {
+    let mut r = BufReader::new(File::open(path)?);
+    let (meta, _) = read_header(&mut r)?;
+    (0..meta.num_series)
+        .map(|_| read_raw_entry(&mut r))
+        .collect()
+}
+
+pub fn read_block(path: &Path) -> io::Result<(BlockMeta, SeriesMap)> {
+    let scan = scan_block(path)?;
+    match (scan.meta, scan.error) {
+        (Some(meta), None) => Ok((meta, scan.series)),
+        (_, Some(error)) => Err(invalid(&error)),
+        (None, None) => unreachable!("scan without header or error"),
+    }
+}
+
+/// The readable part of a block, as found by `scan_block`.
+#[derive(Debug)]
+pub struct BlockScan {
+    /// `None` if the header itself is unreadable.
+    pub meta: Option<BlockMeta>,
+    /// Series before the first corrupt entry.
+    pub series: SeriesMap,
+    /// What made the rest of the block unreadable.
+    pub error: Option<String>,
+}
+
+impl BlockScan {
+    /// Series announced by the header but not recovered.
+    pub fn lost_series(&self) -> Option<u32> {
+        self.meta
+            .map(|m| m.num_series.saturating_sub(self.series.len() as u32))
+    }
+}
+
+/// Reads a block up to its first corrupt or truncated entry. I/O errors
+/// other than running out of data are returned as errors.
+pub fn scan_block(path: &Path) -> io::Result<BlockScan> {
+    let mut r = BufReader::new(File::open(path)?);
+    let mut scan = BlockScan {
+        meta: None,
+        series: SeriesMap::new(),
+        error: None,
+    };
+
+    let (meta, version) = match read_header(&mut r) {
+        Ok(header) => header,
+        Err(e) if is_corruption(&e) => {
+            scan.error = Some(e.to_string());
+            return Ok(scan);
+        }
+        Err(e) => return Err(e),
+    };
+    scan.meta = Some(meta);
+
+    for i in 0..meta.num_series {
+        match read_entry(&mut r) {
+            Ok((key, samples)) => {
+                scan.series.insert(key, samples);
+            }
+            Err(e) if is_corruption(&e) => {
+                scan.error = Some(format!("series entry {}: {}", i, e));
+                break;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    if scan.error.is_none() && version >= 3 {
+        match read_index(path).and_then(|_| read_postings(path)) {
+            Ok(_) => {}
+            Err(e) if is_corruption(&e) => scan.error = Some(format!("series index: {}", e)),
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(scan)
+}
+
+fn read_entry(r: &mut impl Read) -> io::Result<(String, Vec<(i64, f64)>)> {
+    let entry = read_raw_entry(r)?;
+    let samples = entry.decode()?;
+    Ok((entry.key, samples))
+}
+
+fn read_raw_entry(r: &mut impl Read) -> io::Result<RawEntry> {
+    let key_len: [u8; 4] = read_array(r)?;
+    let key = read_vec(r, u32::from_le_bytes(key_len))?;
+    let count: [u8; 4] = read_array(r)?;
+    let data_len: [u8; 4] = read_array(r)?;
+    let data = read_vec(r, u32::from_le_bytes(data_len))?;
+    let crc = u32::from_le_bytes(read_array(r)?);
+
+    let mut hasher = crc32fast::Hasher::new();
+    for part in [&key_len[..], &key, &count, &data_len, &data] {
+        hasher.update(part);
+    }
+    if hasher.finalize() != crc {
+        return Err(invalid("checksum mismatch"));
+    }
+
+    let key = String::from_utf8(key).map_err(|_| invalid("series key is not UTF-8"))?;
+    Ok(RawEntry {
+        key,
+        count: u32::from_le_bytes(count),
+        data,
+    })
+}
+
+fn is_corruption(e: &io::Error) -> bool {
+    matches!(e.kind(), ErrorKind::InvalidData | ErrorKind::UnexpectedEof)
+}
+
+pub(super) fn read_array<const N: usize>(r: &mut impl Read) -> io::Result<[u8; N]> {
+    let mut buf = [0u8; N];
+    r.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+/// Reads `len` bytes without trusting `len` for the allocation, since a
+/// corrupt length can be arbitrarily large.
+pub(super) fn read_vec(r: &mut impl Read, len: u32) -> io::Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    r.take(len as u64).read_to_end(&mut buf)?;
+    if buf.len() < len as usize {
+        return Err(ErrorKind::UnexpectedEof.into());
+    }
+    Ok(buf)
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, msg.to_string())
+}
@@ -0,0 +1,246 @@
+//! Gorilla-style compression of one series' samples: delta-of-delta encoded
+//! timestamps and XOR encoded values, interleaved in a single bit stream.
+
+/// Appends bits to a byte buffer, most significant bit first.
+#[derive(Debug, Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    // Bits used in the last byte; 8 means it is full.
+    used: u8,
+}
+
+impl BitWriter {
+    fn write_bit(&mut self, bit: bool) {
+        if self.used == 8 || self.bytes.is_empty() {
+            self.bytes.push(0);
+            self.used = 0;
+        }
+        if bit {
+            let last = self.bytes.len() - 1;
+            self.bytes[last] |= 0x80 >> self.used;
+        }
+        self.used += 1;
+    }
+
+    /// Writes the low `n` bits of `value`.
+    fn write_bits(&mut self, value: u64, n: u8) {
+        for i in (0..n).rev() {
+            self.write_bit((value >> i) & 1 == 1);
+        }
+    }
+}
+
+struct BitReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        BitReader { bytes, pos: 0 }
+    }
+
+    fn read_bit(&mut self) -> Option<bool> {
+        let byte = *self.bytes.get(self.pos / 8)?;
+        let bit = byte & (0x80 >> (self.pos % 8)) != 0;
+        self.pos += 1;
+        Some(bit)
+    }
+
+    fn read_bits(&mut self, n: u8) -> Option<u64> {
+        let mut value = 0;
+        for _ in 0..n {
+            value = (value << 1) | self.read_bit()? as u64;
+        }
+        Some(value)
+    }
+}
+
+/// Encodes samples, which must be sorted by timestamp.
+pub fn encode(samples: &[(i64, f64)]) -> Vec<u8> {
+    let mut w = BitWriter::default();
+
+    let mut prev_ts = 0i64;
+    let mut prev_delta = 0i64;
+    let mut prev_value = 0u64;
+    let mut prev_leading = u8::MAX;
+    let mut prev_trailing = 0u8;
+
+    for (i, &(ts, value)) in samples.iter().enumerate() {
+        let value = value.to_bits();
+        match i {
+            0 => {
+                w.write_bits(ts as u64, 64);
+                w.write_bits(value, 64);
+            }
+            _ => {
+                let delta = ts.wrapping_sub(prev_ts);
+                if i == 1 {
+                    w.write_bits(delta as u64, 64);
+                } else {
+                    write_delta_of_delta(&mut w, delta.wrapping_sub(prev_delta));
+                }
+                prev_delta = delta;
+
+                let xor = value ^ prev_value;
+                if xor == 0 {
+                    w.write_bit(false);
+                } else {
+                    w.write_bit(true);
+                    let leading = (xor.leading_zeros() as u8).min(31);
+                    let trailing = xor.trailing_zeros() as u8;
+
+                    if prev_leading != u8::MAX
+                        && leading >= prev_leading
+                        && trailing >= prev_trailing
+                    {
+                        // Fits in the previous meaningful window.
+                        w.write_bit(false);
+                        w.write_bits(xor >> prev_trailing, 64 - prev_leading - prev_trailing);
+                    } else {
+                        let meaningful = 64 - leading - trailing;
+                        w.write_bit(true);
+                        w.write_bits(leading as u64, 5);
+                        // 64 meaningful bits do not fit in 6 bits and are written as 0.
+                        w.write_bits(meaningful as u64 & 0x3f, 6);
+                        w.write_bits(xor >> trailing, meaningful);
+                        prev_leading = leading;
+                        prev_trailing = trailing;
+                    }
+                }
+            }
+        }
+        prev_ts = ts;
+        prev_value = value;
+    }
+
+    w.bytes
+}
+
+fn write_delta_of_delta(w: &mut BitWriter, dod: i64) {
+    match dod {
+        0 => w.write_bit(false),
+        -63..=64 => {
+            w.write_bits(0b10, 2);
+            w.write_bits(dod as u64, 7);
+        }
+        -255..=256 => {
+            w.write_bits(0b110, 3);
+            w.write_bits(dod as u64, 9);
+        }
+        -2047..=2048 => {
+            w.write_bits(0b1110, 4);
+            w.write_bits(dod as u64, 12);
+        }
+        _ => {
+            w.write_bits(0b1111, 4);
+            w.write_bits(dod as u64, 64);
+        }
+    }
+}
+
+/// Decodes `count` samples written by `encode`. Returns `None` if the data
+/// ends early.
+pub fn decode(data: &[u8], count: usize) -> Option<Vec<(i64, f64)>> {
+    let mut r = BitReader::new(data);
+    // Every sample takes at least two bits, so a bogus count cannot over-allocate.
+    let mut samples = Vec::with_capacity(count.min(data.len() * 4));
+
+    let mut ts = 0i64;
+    let mut delta = 0i64;
+    let mut value = 0u64;
+    let mut leading = 0u8;
+    let mut trailing = 0u8;
+
+    for i in 0..count {
+        match i {
+            0 => {
+                ts = r.read_bits(64)? as i64;
+                value = r.read_bits(64)?;
+            }
+            _ => {
+                if i == 1 {
+                    delta = r.read_bits(64)? as i64;
+                } else {
+                    delta = delta.wrapping_add(read_delta_of_delta(&mut r)?);
+                }
+                ts = ts.wrapping_add(delta);
+
+                if r.read_bit()? {
+                    if r.read_bit()? {
+                        leading = r.read_bits(5)? as u8;
+                        let meaningful = match r.read_bits(6)? as u8 {
+                            0 => 64,
+                            n => n,
+                        };
+                        // Corrupt data can claim more than 64 bits.
+                        trailing = 64u8.checked_sub(leading + meaningful)?;
+                    }
+                    let meaningful = 64 - leading - trailing;
+                    value ^= r.read_bits(meaningful)? << trailing;
+                }
+            }
+        }
+        samples.push((ts, f64::from_bits(value)));
+    }
+
+    Some(samples)
+}
+
+fn read_delta_of_delta(r: &mut BitReader) -> Option<i64> {
+    let mut prefix = 0;
+    while prefix < 4 && r.read_bit()? {
+        prefix += 1;
+    }
+    let bits = match prefix {
+        0 => return Some(0),
+        1 => 7,
+        2 => 9,
+        3 => 12,
+        _ => 64,
+    };
+    let raw = r.read_bits(bits)?;
+    // Sign-extend from `bits` bits.
+    let shift = 64 - bits as u32;
+    Some(((raw << shift) as i64) >> shift)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let mut samples = vec![(1_700_000_000_000, 1.0)];
+        let mut ts = 1_700_000_000_000;
+        for i in 1..500i64 {
+            // Mostly regular intervals with some jitter and large gaps.
+            ts += 15_000 + (i % 7) * 3 - if i % 50 == 0 { 100_000 } else { 0 } + (i % 97) * 1000;
+            let value = match i % 5 {
+                0 => f64::NAN,
+                1 => i as f64,
+                2 => -0.001 * i as f64,
+                3 => f64::INFINITY,
+                _ => 1e300,
+            };
+            samples.push((ts, value));
+        }
+
+        let data = encode(&samples);
+        let decoded = decode(&data, samples.len()).unwrap();
+        assert_eq!(decoded.len(), samples.len());
+        for (a, b) in samples.iter().zip(&decoded) {
+            assert_eq!(a.0, b.0);
+            assert_eq!(a.1.to_bits(), b.1.to_bits());
+        }
+
+        assert!(decode(&data[..data.len() / 2], samples.len()).is_none());
+    }
+
+    #[test]
+    fn test_regular_series_compress() {
+        let samples: Vec<_> = (0..1000).map(|i| (i * 15_000, 42.0)).collect();
+        // 16 bytes raw per sample; a constant regular series needs two bits.
+        assert!(encode(&samples).len() < 300);
+    }
+}
@@ -0,0 +1,161 @@
+//! Turning stored samples back into expositions or remote-write requests,
+//! for backfilling history into another system.
+//!
+//! The store keeps only series keys and samples, so types and help texts
+//! are gone: every sample name becomes its own untyped family, and
+//! histogram and summary series come out as their `_bucket`, `_sum` and
+//! `_count` parts.
+
+use prometheus::proto::{LabelPair, Metric, MetricFamily, MetricType, Untyped};
+use std::collections::BTreeMap;
+
+use super::SeriesMap;
+use crate::flatten::parse_series;
+use crate::remote_write::{Label, Sample, TimeSeries, WriteRequest};
+
+const METRIC_NAME_LABEL: &str = "__name__";
+
+/// Returns one untyped family per sample name, with a timestamped metric
+/// per stored sample, oldest first within each series.
+pub fn to_metric_families(series: &SeriesMap) -> Vec<MetricFamily> {
+    let mut mf_by_name: BTreeMap<String, MetricFamily> = BTreeMap::new();
+
+    for (key, samples) in series {
+        let (name, labels) = match parse_key(key) {
+            Some(parsed) => parsed,
+            None => continue,
+        };
+
+        let mf = mf_by_name.entry(name.clone()).or_insert_with(|| {
+            let mut mf = MetricFamily::new();
+            mf.set_name(name);
+            mf.set_field_type(MetricType::UNTYPED);
+            mf
+        });
+        for &(ts, value) in samples {
+            let mut m = Metric::new();
+            for (name, value) in &labels {
+                let mut label = LabelPair::new();
+                label.set_name(name.clone());
+                label.set_value(value.clone());
+                m.mut_label().push(label);
+            }
+            let mut u = Untyped::new();
+            u.set_value(value);
+            m.set_untyped(u);
+            m.set_timestamp_ms(ts);
+            mf.mut_metric().push(m);
+        }
+    }
+
+    mf_by_name.into_values().collect()
+}
+
+/// Packs the series into write requests of at most `max_samples` samples
+/// each. A series larger than that is split across consecutive requests.
+pub fn to_write_requests(series: &SeriesMap, max_samples: usize) -> Vec<WriteRequest> {
+    let max_samples = max_samples.max(1);
+    let mut requests = Vec::new();
+    let mut current = WriteRequest::default();
+    let mut current_samples = 0;
+
+    for (key, samples) in series {
+        let (name, labels) = match parse_key(key) {
+            Some(parsed) => parsed,
+            None => continue,
+        };
+        let mut labels: Vec<Label> = labels
+            .into_iter()
+            .map(|(name, value)| Label { name, value })
+            .collect();
+        labels.push(Label {
+            name: METRIC_NAME_LABEL.to_string(),
+            value: name,
+        });
+        // Receivers expect labels sorted by name.
+        labels.sort_by(|a, b| a.name.cmp(&b.name));
+
+        let mut rest = samples.as_slice();
+        while !rest.is_empty() {
+            if current_samples == max_samples {
+                requests.push(std::mem::take(&mut current));
+                current_samples = 0;
+            }
+            let (chunk, tail) = rest.split_at(rest.len().min(max_samples - current_samples));
+            current.timeseries.push(TimeSeries {
+                labels: labels.clone(),
+                samples: chunk
+                    .iter()
+                    .map(|&(timestamp, value)| Sample { value, timestamp })
+                    .collect(),
+            });
+            current_samples += chunk.len();
+            rest = tail;
+        }
+    }
+
+    if !current.timeseries.is_empty() {
+        requests.push(current);
+    }
+    requests
+}
+
+fn parse_key(key: &str) -> Option<(String, Vec<(String, String)>)> {
+    let parsed = parse_series(key);
+    if parsed.is_none() {
+        log::warn!("skipping series with malformed key {:?}", key);
+    }
+    parsed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::{write_metric_families, Format};
+
+    fn series() -> SeriesMap {
+        let mut series = SeriesMap::new();
+        series.insert(
+            r#"requests_total{code="200",job="api"}"#.to_string(),
+            vec![(1000, 1.0), (2000, 3.0), (3000, 4.0)],
+        );
+        series.insert("up".to_string(), vec![(1000, 1.0)]);
+        series
+    }
+
+    #[test]
+    fn test_to_openmetrics() {
+        let mfs = to_metric_families(&series());
+        let mut out = Vec::new();
+        write_metric_families(Format::OpenMetrics, &mut out, &mfs).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            r#"# TYPE requests_total unknown
+requests_total{code="200",job="api"} 1 1
+requests_total{code="200",job="api"} 3 2
+requests_total{code="200",job="api"} 4 3
+# TYPE up unknown
+up 1 1
+# EOF
+"#
+        );
+    }
+
+    #[test]
+    fn test_to_write_requests_splits_batches() {
+        let requests = to_write_requests(&series(), 2);
+        let sizes: Vec<Vec<usize>> = requests
+            .iter()
+            .map(|r| r.timeseries.iter().map(|ts| ts.samples.len()).collect())
+            .collect();
+        assert_eq!(sizes, vec![vec![2], vec![1, 1]]);
+
+        let labels: Vec<&str> = requests[0].timeseries[0]
+            .labels
+            .iter()
+            .map(|l| l.name.as_str())
+            .collect();
+        assert_eq!(labels, vec!["__name__", "code", "job"]);
+        assert_eq!(requests[1].timeseries[0].samples[0].timestamp, 3000);
+    }
+}
@@ -0,0 +1,525 @@
+//! A small on-disk time series store for keeping local history of the
+//! merged series.
+//!
+//! Samples are appended to write-ahead segments (`wal-N.seg`), which are
+//! indexed (`wal-N.idx`) when sealed. Compaction turns sealed segments into
+//! compressed blocks (`block-N.blk`), merges adjacent blocks up to
+//! `max_block_duration`, and enforces the retention duration and disk-usage
+//! cap by dropping the oldest data.
+//!
+//! Segment batches, block headers and block entries carry CRC-32 checksums.
+//! A torn segment tail is skipped when reading; `repair` truncates it and
+//! quarantines damaged blocks. `rekey` rewrites series keys in place.
+
+mod block;
+mod chunk;
+pub mod export;
+mod postings;
+pub mod query;
+mod rekey;
+mod repair;
+mod wal;
+
+use prometheus::proto::MetricFamily;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::flatten::flatten;
+pub use block::{BlockMeta, SeriesMap};
+pub use rekey::rekey;
+pub use repair::{repair, RepairAction, RepairEntry};
+use wal::SegmentWriter;
+
+const SEGMENT_PREFIX: &str = "wal-";
+const SEGMENT_SUFFIX: &str = ".seg";
+const BLOCK_PREFIX: &str = "block-";
+const BLOCK_SUFFIX: &str = ".blk";
+
+#[derive(Debug, Clone)]
+pub struct StorageConfig {
+    /// Samples older than this are dropped during compaction; `None` keeps
+    /// them until the disk cap is hit.
+    pub retention: Option<Duration>,
+    /// Oldest blocks are deleted while the store is larger than this.
+    pub max_bytes: Option<u64>,
+    /// Adjacent blocks are merged while the result spans at most this long.
+    pub max_block_duration: Duration,
+    /// The active segment is sealed once it grows past this size.
+    pub segment_bytes: u64,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        StorageConfig {
+            retention: Some(Duration::from_secs(7 * 24 * 3600)),
+            max_bytes: None,
+            max_block_duration: Duration::from_secs(24 * 3600),
+            segment_bytes: 16 << 20,
+        }
+    }
+}
+
+/// What one compaction run did.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CompactionStats {
+    pub segments_compacted: usize,
+    pub blocks_merged: usize,
+    pub blocks_deleted: usize,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct BlockEntry {
+    seq: u64,
+    meta: BlockMeta,
+}
+
+pub struct Storage {
+    dir: PathBuf,
+    config: StorageConfig,
+    next_seq: u64,
+    head_seq: u64,
+    head: SegmentWriter,
+    // Sorted by minimum time.
+    blocks: Vec<BlockEntry>,
+}
+
+impl Storage {
+    /// Opens or creates a store in `dir`. Segments left by a previous run
+    /// are kept for the next compaction; writes go to a fresh segment.
+    pub fn open(dir: &Path, config: StorageConfig) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+
+        let mut max_seq = 0;
+        let mut blocks = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+
+            if name.ends_with(".tmp") {
+                // Left by a compaction that did not finish.
+                fs::remove_file(&path)?;
+            } else if let Some(seq) = parse_seq(name, SEGMENT_PREFIX, SEGMENT_SUFFIX) {
+                max_seq = max_seq.max(seq);
+            } else if let Some(seq) = parse_seq(name, BLOCK_PREFIX, BLOCK_SUFFIX) {
+                max_seq = max_seq.max(seq);
+                let meta = block::read_meta(&path).map_err(|e| {
+                    io::Error::new(
+                        e.kind(),
+                        format!("{}: {}; run `pmv storage repair`", path.display(), e),
+                    )
+                })?;
+                blocks.push(BlockEntry { seq, meta });
+            }
+        }
+        blocks.sort_by_key(|b| (b.meta.min_time, b.seq));
+
+        let head_seq = max_seq + 1;
+        let head = SegmentWriter::open(&segment_path(dir, head_seq))?;
+
+        Ok(Storage {
+            dir: dir.to_path_buf(),
+            config,
+            next_seq: head_seq + 1,
+            head_seq,
+            head,
+            blocks,
+        })
+    }
+
+    /// Appends every sample of `mfs`. Samples without a timestamp are stored
+    /// at `now_ms`.
+    pub fn append(&mut self, mfs: &[MetricFamily], now_ms: i64) -> io::Result<()> {
+        for s in flatten(mfs) {
+            self.head
+                .append(&s.series_key(), s.timestamp_ms.unwrap_or(now_ms), s.value);
+        }
+        self.head.flush()?;
+
+        if self.head.len() >= self.config.segment_bytes {
+            self.rotate()?;
+        }
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.head.seal()?;
+        self.head_seq = self.take_seq();
+        self.head = SegmentWriter::open(&segment_path(&self.dir, self.head_seq))?;
+        Ok(())
+    }
+
+    fn take_seq(&mut self) -> u64 {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        seq
+    }
+
+    /// Compacts sealed segments into a block, merges small adjacent blocks
+    /// and applies retention and the disk cap, relative to `now_ms`.
+    pub fn compact(&mut self, now_ms: i64) -> io::Result<CompactionStats> {
+        let mut stats = CompactionStats::default();
+        let cutoff = self.retention_cutoff(now_ms);
+
+        if !self.head.is_empty() {
+            self.rotate()?;
+        }
+
+        let segments = self.sealed_segments()?;
+        if !segments.is_empty() {
+            let mut series = SeriesMap::new();
+            for path in &segments {
+                for (key, ts, value) in wal::read_segment(path)? {
+                    series.entry(key).or_default().push((ts, value));
+                }
+            }
+            normalize(&mut series, cutoff);
+
+            self.add_block(&series)?;
+            for path in &segments {
+                wal::remove_index(path)?;
+                fs::remove_file(path)?;
+            }
+            stats.segments_compacted = segments.len();
+        }
+
+        // Blocks entirely past retention go without being read.
+        let before = self.blocks.len();
+        let expired: Vec<_> = self
+            .blocks
+            .iter()
+            .filter(|b| b.meta.max_time < cutoff)
+            .map(|b| b.seq)
+            .collect();
+        for seq in expired {
+            self.remove_block(seq)?;
+        }
+        stats.blocks_deleted += before - self.blocks.len();
+
+        stats.blocks_merged = self.merge_blocks(cutoff)?;
+
+        if let Some(max_bytes) = self.config.max_bytes {
+            while self.disk_usage()? > max_bytes && !self.blocks.is_empty() {
+                let oldest = self.blocks[0].seq;
+                log::warn!("storage exceeds {} bytes, dropping oldest block", max_bytes);
+                self.remove_block(oldest)?;
+                stats.blocks_deleted += 1;
+            }
+        }
+
+        Ok(stats)
+    }
+
+    fn retention_cutoff(&self, now_ms: i64) -> i64 {
+        match self.config.retention {
+            Some(retention) => now_ms.saturating_sub(retention.as_millis() as i64),
+            None => i64::MIN,
+        }
+    }
+
+    /// Merges runs of adjacent blocks whose combined span fits within
+    /// `max_block_duration`. Returns the number of input blocks merged.
+    fn merge_blocks(&mut self, cutoff: i64) -> io::Result<usize> {
+        let max_span = self.config.max_block_duration.as_millis() as i64;
+
+        let mut groups: Vec<Vec<BlockEntry>> = Vec::new();
+        for b in &self.blocks {
+            match groups.last_mut() {
+                Some(group)
+                    if b.meta.max_time.max(group_max(group)) - group[0].meta.min_time
+                        <= max_span =>
+                {
+                    group.push(*b)
+                }
+                _ => groups.push(vec![*b]),
+            }
+        }
+
+        let mut merged = 0;
+        for group in groups.into_iter().filter(|g| g.len() > 1) {
+            let mut series = SeriesMap::new();
+            for b in &group {
+                let (_, block_series) = block::read_block(&self.block_path(b.seq))?;
+                for (key, samples) in block_series {
+                    series.entry(key).or_default().extend(samples);
+                }
+            }
+            normalize(&mut series, cutoff);
+
+            self.add_block(&series)?;
+            for b in &group {
+                self.remove_block(b.seq)?;
+            }
+            merged += group.len();
+        }
+
+        Ok(merged)
+    }
+
+    fn add_block(&mut self, series: &SeriesMap) -> io::Result<()> {
+        let seq = self.take_seq();
+        if let Some(meta) = block::write_block(&self.block_path(seq), series)? {
+            self.blocks.push(BlockEntry { seq, meta });
+            self.blocks.sort_by_key(|b| (b.meta.min_time, b.seq));
+        }
+        Ok(())
+    }
+
+    fn remove_block(&mut self, seq: u64) -> io::Result<()> {
+        fs::remove_file(self.block_path(seq))?;
+        self.blocks.retain(|b| b.seq != seq);
+        Ok(())
+    }
+
+    /// Returns every stored sample with a timestamp in `[min_time, max_time]`.
+    pub fn query(&mut self, min_time: i64, max_time: i64) -> io::Result<SeriesMap> {
+        self.head.flush()?;
+        read_range(&self.dir, min_time, max_time)
+    }
+
+    /// Metadata of the current blocks, oldest first.
+    pub fn blocks(&self) -> Vec<BlockMeta> {
+        self.blocks.iter().map(|b| b.meta).collect()
+    }
+
+    /// Total size of the store's files in bytes.
+    pub fn disk_usage(&self) -> io::Result<u64> {
+        let mut total = 0;
+        for entry in fs::read_dir(&self.dir)? {
+            let metadata = entry?.metadata()?;
+            if metadata.is_file() {
+                total += metadata.len();
+            }
+        }
+        Ok(total)
+    }
+
+    /// All segments, oldest first, including the active one.
+    fn segments(&self) -> io::Result<Vec<PathBuf>> {
+        let mut segments = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+            if let Some(seq) = parse_seq(name, SEGMENT_PREFIX, SEGMENT_SUFFIX) {
+                segments.push((seq, path));
+            }
+        }
+        segments.sort();
+        Ok(segments.into_iter().map(|(_, p)| p).collect())
+    }
+
+    fn sealed_segments(&self) -> io::Result<Vec<PathBuf>> {
+        let head = segment_path(&self.dir, self.head_seq);
+        let mut segments = self.segments()?;
+        segments.retain(|p| *p != head);
+        Ok(segments)
+    }
+
+    fn block_path(&self, seq: u64) -> PathBuf {
+        self.dir
+            .join(format!("{}{:08}{}", BLOCK_PREFIX, seq, BLOCK_SUFFIX))
+    }
+}
+
+/// Returns every sample with a timestamp in `[min_time, max_time]` from the
+/// store in `dir`. Unlike `Storage::open`, nothing is written, so this can
+/// read a store while a server is appending to it.
+pub fn read_range(dir: &Path, min_time: i64, max_time: i64) -> io::Result<SeriesMap> {
+    Ok(query::plan(dir, min_time, max_time, &[])?.execute()?.0)
+}
+
+fn segment_path(dir: &Path, seq: u64) -> PathBuf {
+    dir.join(format!("{}{:08}{}", SEGMENT_PREFIX, seq, SEGMENT_SUFFIX))
+}
+
+fn parse_seq(name: &str, prefix: &str, suffix: &str) -> Option<u64> {
+    name.strip_prefix(prefix)?
+        .strip_suffix(suffix)?
+        .parse()
+        .ok()
+}
+
+fn group_max(group: &[BlockEntry]) -> i64 {
+    group
+        .iter()
+        .map(|b| b.meta.max_time)
+        .max()
+        .unwrap_or(i64::MIN)
+}
+
+/// Sorts samples by timestamp, keeps the last one written for duplicate
+/// timestamps and drops those before `cutoff` along with emptied series.
+fn normalize(series: &mut SeriesMap, cutoff: i64) {
+    for samples in series.values_mut() {
+        samples.retain(|(ts, _)| *ts >= cutoff);
+        // Stable, so equal timestamps stay in write order.
+        samples.sort_by_key(|(ts, _)| *ts);
+
+        let mut deduped: Vec<(i64, f64)> = Vec::with_capacity(samples.len());
+        for &(ts, value) in samples.iter() {
+            match deduped.last_mut() {
+                Some(last) if last.0 == ts => last.1 = value,
+                _ => deduped.push((ts, value)),
+            }
+        }
+        *samples = deduped;
+    }
+    series.retain(|_, samples| !samples.is_empty());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::{read_metric_families, Format};
+
+    const HOUR_MS: i64 = 3_600_000;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("pmv-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn scrape(value: f64) -> Vec<MetricFamily> {
+        let text = format!("# TYPE up gauge\nup{{job=\"a\"}} {}\n", value);
+        read_metric_families(Format::Text, text.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn test_compaction_merges_and_retains() {
+        let dir = temp_dir("compact");
+        let config = StorageConfig {
+            retention: Some(Duration::from_secs(48 * 3600)),
+            max_block_duration: Duration::from_secs(24 * 3600),
+            ..StorageConfig::default()
+        };
+        let mut storage = Storage::open(&dir, config.clone()).unwrap();
+
+        // One compaction per hour over three days.
+        for hour in 0..72 {
+            for minute in 0..4 {
+                let now = hour * HOUR_MS + minute * 15 * 60_000;
+                storage.append(&scrape(now as f64), now).unwrap();
+            }
+            storage.compact(hour * HOUR_MS + 45 * 60_000).unwrap();
+        }
+
+        let blocks = storage.blocks();
+        assert!(blocks.len() <= 3, "{:?}", blocks);
+        for b in &blocks {
+            assert!(b.max_time - b.min_time <= 24 * HOUR_MS);
+        }
+
+        let now = 71 * HOUR_MS + 45 * 60_000;
+        let series = storage.query(i64::MIN, i64::MAX).unwrap();
+        let samples = &series[r#"up{job="a"}"#];
+        assert!(samples.first().unwrap().0 >= now - 48 * HOUR_MS - 24 * HOUR_MS);
+        assert_eq!(samples.last().unwrap(), &(now, now as f64));
+
+        let recent = storage.query(now - HOUR_MS, now).unwrap();
+        assert_eq!(recent[r#"up{job="a"}"#].len(), 5);
+
+        // Reopening finds the blocks again.
+        drop(storage);
+        let mut storage = Storage::open(&dir, config).unwrap();
+        assert_eq!(storage.blocks(), blocks);
+        assert_eq!(storage.query(i64::MIN, i64::MAX).unwrap(), series);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_repair_after_crash() {
+        let dir = temp_dir("repair");
+        let mut storage = Storage::open(&dir, StorageConfig::default()).unwrap();
+        storage.append(&scrape(1.0), 1000).unwrap();
+        storage.compact(1000).unwrap();
+        storage.append(&scrape(2.0), 2000).unwrap();
+        storage.append(&scrape(3.0), 3000).unwrap();
+        drop(storage);
+
+        let mut names: Vec<_> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .collect();
+        names.sort();
+        let block_path = names
+            .iter()
+            .find(|p| p.extension().unwrap() == "blk")
+            .unwrap();
+        let segment_path = names.last().unwrap();
+
+        // A half-written batch at the end of the segment, and a flipped bit
+        // in the block's only series.
+        let mut segment = fs::read(segment_path).unwrap();
+        let intact = segment.len() as u64;
+        segment.extend_from_slice(&[40, 0, 0, 0, 1, 2]);
+        fs::write(segment_path, &segment).unwrap();
+        let mut block = fs::read(block_path).unwrap();
+        // Inside the key of the first entry, right after the header.
+        block[29 + 6] ^= 1;
+        fs::write(block_path, &block).unwrap();
+
+        // Reads skip the torn tail but refuse the corrupt block.
+        let mut storage = Storage::open(&dir, StorageConfig::default()).unwrap();
+        assert!(storage.query(i64::MIN, i64::MAX).is_err());
+        drop(storage);
+
+        let report = repair(&dir, true).unwrap();
+        assert_eq!(report.len(), 2);
+        assert_eq!(fs::read(block_path).unwrap(), block);
+
+        let report = repair(&dir, false).unwrap();
+        assert_eq!(
+            report[0].action,
+            RepairAction::Quarantined {
+                to: dir.join("quarantine").join(block_path.file_name().unwrap()),
+                salvaged_series: 0,
+                lost_series: Some(1),
+            }
+        );
+        assert_eq!(
+            report[1].action,
+            RepairAction::Truncated {
+                kept_bytes: intact,
+                lost_bytes: 6,
+            }
+        );
+        assert!(repair(&dir, false).unwrap().is_empty());
+
+        let mut storage = Storage::open(&dir, StorageConfig::default()).unwrap();
+        let series = storage.query(i64::MIN, i64::MAX).unwrap();
+        assert_eq!(series[r#"up{job="a"}"#], vec![(2000, 2.0), (3000, 3.0)]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_disk_cap_drops_oldest_blocks() {
+        let dir = temp_dir("cap");
+        let mut storage = Storage::open(
+            &dir,
+            StorageConfig {
+                retention: None,
+                max_bytes: Some(200),
+                max_block_duration: Duration::from_millis(1),
+                ..StorageConfig::default()
+            },
+        )
+        .unwrap();
+
+        for hour in 0..20 {
+            storage
+                .append(&scrape(hour as f64), hour * HOUR_MS)
+                .unwrap();
+            storage.compact(hour * HOUR_MS).unwrap();
+        }
+
+        assert!(storage.disk_usage().unwrap() <= 200);
+        let series = storage.query(i64::MIN, i64::MAX).unwrap();
+        assert_eq!(series[r#"up{job="a"}"#].last().unwrap().1, 19.0);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
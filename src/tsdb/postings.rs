@@ -0,0 +1,190 @@
+//! Label postings: for every label pair of a block or segment, the series
+//! that carry it, so queries find the series matching a selector without
+//! reading every key.
+//!
+//! Series are numbered by their position in the file's series index. The
+//! metric name is posted under the `__name__` label. Encoded, little
+//! endian: `u32 series count, u32 list count`, then per list `u32 name
+//! length, name, u32 value length, value, u32 series count, u32 series...`.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::flatten::parse_series;
+use crate::grep::{Matcher, Selector, NAME_LABEL};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Postings {
+    num_series: u32,
+    /// Series by label name and value, in increasing order.
+    lists: BTreeMap<(String, String), Vec<u32>>,
+}
+
+impl Postings {
+    /// Posts the series `keys`, numbered in order. Keys that do not parse
+    /// are numbered but posted under no label.
+    pub fn build<'a>(keys: impl IntoIterator<Item = &'a str>) -> Self {
+        let mut postings = Postings::default();
+        for (series, key) in keys.into_iter().enumerate() {
+            postings.num_series += 1;
+            let Some((name, labels)) = parse_series(key) else {
+                continue;
+            };
+            for pair in labels.into_iter().chain([(NAME_LABEL.to_string(), name)]) {
+                postings.lists.entry(pair).or_default().push(series as u32);
+            }
+        }
+        postings
+    }
+
+    pub fn num_series(&self) -> usize {
+        self.num_series as usize
+    }
+
+    /// The series matching any of `selectors`, as
+    /// [`Selector::matches_series`] would find by parsing every key, or all
+    /// of them if there are none; in increasing order.
+    pub fn select(&self, selectors: &[Selector]) -> Vec<u32> {
+        if selectors.is_empty() {
+            return (0..self.num_series).collect();
+        }
+        let mut selected = BTreeSet::new();
+        for selector in selectors {
+            let mut series: Option<BTreeSet<u32>> = None;
+            for matcher in &selector.matchers {
+                let matching = self.matching(matcher);
+                series = Some(match series {
+                    Some(series) => series.intersection(&matching).copied().collect(),
+                    None => matching,
+                });
+            }
+            selected.extend(series.unwrap_or_default());
+        }
+        selected.into_iter().collect()
+    }
+
+    /// The parsed series whose value of the matcher's label it accepts; a
+    /// missing label has the empty value.
+    fn matching(&self, matcher: &Matcher) -> BTreeSet<u32> {
+        let mut with_label: BTreeSet<u32> = BTreeSet::new();
+        let mut matching = BTreeSet::new();
+        for ((_, value), series) in self.label(&matcher.name) {
+            with_label.extend(series);
+            if matcher.matches(value) {
+                matching.extend(series);
+            }
+        }
+        if matcher.matches("") {
+            // Every parsed series has a name.
+            for (_, series) in self.label(NAME_LABEL) {
+                matching.extend(series.iter().filter(|s| !with_label.contains(s)));
+            }
+        }
+        matching
+    }
+
+    fn label<'a>(
+        &'a self,
+        name: &'a str,
+    ) -> impl Iterator<Item = (&'a (String, String), &'a Vec<u32>)> + 'a {
+        self.lists
+            .range((name.to_string(), String::new())..)
+            .take_while(move |((n, _), _)| n == name)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(&self.num_series.to_le_bytes());
+        out.extend_from_slice(&(self.lists.len() as u32).to_le_bytes());
+        for ((name, value), series) in &self.lists {
+            for s in [name, value] {
+                out.extend_from_slice(&(s.len() as u32).to_le_bytes());
+                out.extend_from_slice(s.as_bytes());
+            }
+            out.extend_from_slice(&(series.len() as u32).to_le_bytes());
+            for s in series {
+                out.extend_from_slice(&s.to_le_bytes());
+            }
+        }
+        out
+    }
+
+    /// Decodes what `encode` wrote, or `None` if it is malformed.
+    pub fn decode(mut data: &[u8]) -> Option<Self> {
+        let num_series = take_u32(&mut data)?;
+        let num_lists = take_u32(&mut data)?;
+        let mut lists = BTreeMap::new();
+        for _ in 0..num_lists {
+            let name = take_str(&mut data)?;
+            let value = take_str(&mut data)?;
+            let len = take_u32(&mut data)? as usize;
+            let bytes = data.get(..len.checked_mul(4)?)?;
+            data = &data[bytes.len()..];
+            let series: Vec<u32> = bytes
+                .chunks_exact(4)
+                .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+                .collect();
+            if series.iter().any(|&s| s >= num_series) {
+                return None;
+            }
+            lists.insert((name, value), series);
+        }
+        if !data.is_empty() {
+            return None;
+        }
+        Some(Postings { num_series, lists })
+    }
+}
+
+fn take_u32(data: &mut &[u8]) -> Option<u32> {
+    let value = u32::from_le_bytes(data.get(..4)?.try_into().ok()?);
+    *data = &data[4..];
+    Some(value)
+}
+
+fn take_str(data: &mut &[u8]) -> Option<String> {
+    let len = take_u32(data)? as usize;
+    let s = std::str::from_utf8(data.get(..len)?).ok()?.to_string();
+    *data = &data[len..];
+    Some(s)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_select_matches_selectors() {
+        let keys = [
+            r#"requests_total{code="200",job="api"}"#,
+            r#"requests_total{code="500",job="api"}"#,
+            r#"up{job="api"}"#,
+            r#"up{job="db",zone=""}"#,
+            "up",
+            "{not a key",
+        ];
+        let postings = Postings::build(keys);
+        assert_eq!(Postings::decode(&postings.encode()), Some(postings.clone()));
+        assert_eq!(postings.select(&[]), vec![0, 1, 2, 3, 4, 5]);
+
+        for selectors in [
+            vec![r#"up{job="api"}"#],
+            vec![r#"{job=~"a.*",code!="500"}"#],
+            vec![r#"up{zone=""}"#],
+            vec![r#"{job!="api"}"#],
+            vec![r#"{__name__=~"req.*"}"#, r#"up{job="db"}"#],
+            vec![r#"up{job="none"}"#],
+        ] {
+            let selectors: Vec<Selector> = selectors.iter().map(|s| s.parse().unwrap()).collect();
+            let expected: Vec<u32> = (0..keys.len() as u32)
+                .filter(|&i| {
+                    parse_series(keys[i as usize]).is_some_and(|(name, labels)| {
+                        selectors.iter().any(|s| s.matches_series(&name, &labels))
+                    })
+                })
+                .collect();
+            assert_eq!(postings.select(&selectors), expected, "{:?}", selectors);
+        }
+
+        assert_eq!(Postings::decode(&[1, 0, 0, 0]), None);
+    }
+}
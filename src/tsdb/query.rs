@@ -0,0 +1,399 @@
+//! Planning and running selector queries over a store.
+//!
+//! Time ranges and label matchers are pushed down into the readers: blocks
+//! outside the range are not opened, the label postings of a block select
+//! the entries to read, and chunks of non-matching series are never
+//! decoded. The postings of a sealed segment select the batches to read;
+//! the active segment has none and is scanned record by record. Blocks
+//! written before postings existed have their series index filtered key by
+//! key instead.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use super::{block, normalize, parse_seq, wal, SeriesMap};
+use super::{BLOCK_PREFIX, BLOCK_SUFFIX, SEGMENT_PREFIX, SEGMENT_SUFFIX};
+use crate::flatten::parse_series;
+use crate::grep::Selector;
+
+/// How a file is read by a query.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Access {
+    /// The block lies outside the query's time range and is not read.
+    SkipTime { min_time: i64, max_time: i64 },
+    /// Only the entries at these offsets, of the series the block's label
+    /// postings select, are read.
+    Postings {
+        offsets: Vec<u64>,
+        num_series: usize,
+    },
+    /// The block has a series index but no postings; every key of the index
+    /// is matched, and only the entries of matching ones are read.
+    Index {
+        offsets: Vec<u64>,
+        num_series: usize,
+    },
+    /// The block has no index; every entry is read but only matching ones
+    /// are decoded.
+    ScanBlock { num_series: u32 },
+    /// Only the batches at these offsets, holding the series the segment's
+    /// label postings select, are read.
+    SegmentPostings {
+        offsets: Vec<u64>,
+        num_batches: u32,
+        matching_series: usize,
+        num_series: usize,
+    },
+    /// The segment is not indexed, as the active one; every record is read.
+    ScanSegment,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Step {
+    pub path: PathBuf,
+    pub access: Access,
+}
+
+/// The files a query reads and how, in the order they are read: blocks by
+/// time, then segments.
+#[derive(Debug, Clone)]
+pub struct QueryPlan {
+    pub min_time: i64,
+    pub max_time: i64,
+    pub steps: Vec<Step>,
+    selectors: Vec<Selector>,
+}
+
+/// What running a plan read.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QueryStats {
+    /// Block entries and segment records read from disk.
+    pub rows_scanned: usize,
+    pub samples_decoded: usize,
+    pub samples_returned: usize,
+}
+
+/// Plans a query for the series of the store in `dir` matching any of
+/// `selectors`, or all series if there are none, with samples in
+/// `[min_time, max_time]`. Reads block headers, indexes and postings, and
+/// segment indexes, only.
+pub fn plan(
+    dir: &Path,
+    min_time: i64,
+    max_time: i64,
+    selectors: &[Selector],
+) -> io::Result<QueryPlan> {
+    let mut blocks = Vec::new();
+    let mut segments = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+        if let Some(seq) = parse_seq(name, SEGMENT_PREFIX, SEGMENT_SUFFIX) {
+            segments.push((seq, path));
+        } else if let Some(seq) = parse_seq(name, BLOCK_PREFIX, BLOCK_SUFFIX) {
+            blocks.push((block::read_meta(&path)?, seq, path));
+        }
+    }
+    // Older data first, so `normalize` keeps the latest write of a timestamp.
+    blocks.sort_by_key(|(meta, seq, _)| (meta.min_time, *seq));
+    segments.sort();
+
+    let mut filter = KeyFilter::new(selectors);
+    let mut steps = Vec::new();
+    for (meta, _, path) in blocks {
+        let access = if meta.max_time < min_time || meta.min_time > max_time {
+            Access::SkipTime {
+                min_time: meta.min_time,
+                max_time: meta.max_time,
+            }
+        } else {
+            match (block::read_index(&path)?, block::read_postings(&path)?) {
+                (Some(index), Some(postings)) => Access::Postings {
+                    num_series: index.len(),
+                    offsets: postings
+                        .select(selectors)
+                        .into_iter()
+                        .filter_map(|series| index.get(series as usize))
+                        .map(|(_, offset)| *offset)
+                        .collect(),
+                },
+                (Some(index), None) => Access::Index {
+                    num_series: index.len(),
+                    offsets: index
+                        .into_iter()
+                        .filter(|(key, _)| filter.matches(key))
+                        .map(|(_, offset)| offset)
+                        .collect(),
+                },
+                (None, _) => Access::ScanBlock {
+                    num_series: meta.num_series,
+                },
+            }
+        };
+        steps.push(Step { path, access });
+    }
+    for (_, path) in segments {
+        let access = match wal::read_index(&path)? {
+            Some(index) => {
+                let selected = index.postings.select(selectors);
+                let mut offsets: Vec<u64> = selected
+                    .iter()
+                    .filter_map(|&series| index.series.get(series as usize))
+                    .flat_map(|(_, offsets)| offsets.iter().copied())
+                    .collect();
+                offsets.sort_unstable();
+                offsets.dedup();
+                Access::SegmentPostings {
+                    offsets,
+                    num_batches: index.num_batches,
+                    matching_series: selected.len(),
+                    num_series: index.series.len(),
+                }
+            }
+            None => Access::ScanSegment,
+        };
+        steps.push(Step { path, access });
+    }
+
+    Ok(QueryPlan {
+        min_time,
+        max_time,
+        steps,
+        selectors: selectors.to_vec(),
+    })
+}
+
+impl QueryPlan {
+    pub fn execute(&self) -> io::Result<(SeriesMap, QueryStats)> {
+        let mut filter = KeyFilter::new(&self.selectors);
+        let mut stats = QueryStats::default();
+        let mut series = SeriesMap::new();
+
+        for step in &self.steps {
+            match &step.access {
+                Access::SkipTime { .. } => {}
+                Access::Postings { offsets, .. } | Access::Index { offsets, .. } => {
+                    if offsets.is_empty() {
+                        continue;
+                    }
+                    for entry in block::read_entries_at(&step.path, offsets)? {
+                        stats.rows_scanned += 1;
+                        let samples = entry.decode()?;
+                        stats.samples_decoded += samples.len();
+                        series.entry(entry.key).or_default().extend(samples);
+                    }
+                }
+                Access::ScanBlock { .. } => {
+                    for entry in block::read_entries(&step.path)? {
+                        stats.rows_scanned += 1;
+                        if filter.matches(&entry.key) {
+                            let samples = entry.decode()?;
+                            stats.samples_decoded += samples.len();
+                            series.entry(entry.key).or_default().extend(samples);
+                        }
+                    }
+                }
+                Access::SegmentPostings { offsets, .. } => {
+                    // Batches also hold series that do not match.
+                    for (key, ts, value) in wal::read_batches_at(&step.path, offsets)? {
+                        stats.rows_scanned += 1;
+                        if filter.matches(&key) {
+                            stats.samples_decoded += 1;
+                            series.entry(key).or_default().push((ts, value));
+                        }
+                    }
+                }
+                Access::ScanSegment => {
+                    for (key, ts, value) in wal::read_segment(&step.path)? {
+                        stats.rows_scanned += 1;
+                        if filter.matches(&key) {
+                            stats.samples_decoded += 1;
+                            series.entry(key).or_default().push((ts, value));
+                        }
+                    }
+                }
+            }
+        }
+
+        for samples in series.values_mut() {
+            samples.retain(|(ts, _)| (self.min_time..=self.max_time).contains(ts));
+        }
+        normalize(&mut series, i64::MIN);
+        stats.samples_returned = series.values().map(Vec::len).sum();
+        Ok((series, stats))
+    }
+}
+
+impl fmt::Display for QueryPlan {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "time range {}..={}, {} selectors",
+            self.min_time,
+            self.max_time,
+            self.selectors.len()
+        )?;
+        for step in &self.steps {
+            let name = step.path.file_name().unwrap_or_default().to_string_lossy();
+            write!(f, "{}: ", name)?;
+            match &step.access {
+                Access::SkipTime { min_time, max_time } => writeln!(
+                    f,
+                    "skip, covers {}..={} outside the range",
+                    min_time, max_time
+                )?,
+                Access::Postings {
+                    offsets,
+                    num_series,
+                } => writeln!(
+                    f,
+                    "postings lookup, {} of {} series match",
+                    offsets.len(),
+                    num_series
+                )?,
+                Access::Index {
+                    offsets,
+                    num_series,
+                } => writeln!(
+                    f,
+                    "index scan, {} of {} series match, no postings",
+                    offsets.len(),
+                    num_series
+                )?,
+                Access::ScanBlock { num_series } => {
+                    writeln!(f, "scan {} series, no index", num_series)?
+                }
+                Access::SegmentPostings {
+                    offsets,
+                    num_batches,
+                    matching_series,
+                    num_series,
+                } => writeln!(
+                    f,
+                    "postings lookup, {} of {} series match, read {} of {} batches",
+                    matching_series,
+                    num_series,
+                    offsets.len(),
+                    num_batches
+                )?,
+                Access::ScanSegment => writeln!(f, "scan all records, no index")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for QueryStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "rows scanned: {}, samples decoded: {}, samples returned: {}",
+            self.rows_scanned, self.samples_decoded, self.samples_returned
+        )
+    }
+}
+
+/// Filters series keys with [`Selector::matches_series`], and so with the
+/// selectors' [`grep::Matcher`](crate::grep::Matcher)s, remembering the
+/// result per key since segments repeat keys for every sample.
+struct KeyFilter<'a> {
+    selectors: &'a [Selector],
+    cache: HashMap<String, bool>,
+}
+
+impl<'a> KeyFilter<'a> {
+    fn new(selectors: &'a [Selector]) -> Self {
+        KeyFilter {
+            selectors,
+            cache: HashMap::new(),
+        }
+    }
+
+    fn matches(&mut self, key: &str) -> bool {
+        if self.selectors.is_empty() {
+            return true;
+        }
+        if let Some(&matched) = self.cache.get(key) {
+            return matched;
+        }
+        let matched = match parse_series(key) {
+            Some((name, labels)) => self
+                .selectors
+                .iter()
+                .any(|s| s.matches_series(&name, &labels)),
+            None => false,
+        };
+        self.cache.insert(key.to_string(), matched);
+        matched
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::{read_metric_families, Format};
+    use crate::tsdb::{Storage, StorageConfig};
+
+    #[test]
+    fn test_plan_pushes_down_matchers() {
+        let dir = std::env::temp_dir().join(format!("pmv-query-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        // Every append seals its segment, so it gets indexed.
+        let config = StorageConfig {
+            segment_bytes: 1,
+            ..StorageConfig::default()
+        };
+        let mut storage = Storage::open(&dir, config).unwrap();
+
+        let text = "up{job=\"a\"} 1\nup{job=\"b\"} 0\nrequests_total{job=\"a\"} 5\n";
+        let mfs = read_metric_families(Format::Text, text.as_bytes()).unwrap();
+        storage.append(&mfs, 1000).unwrap();
+        storage.compact(1500).unwrap();
+        storage.append(&mfs, 2000).unwrap();
+        storage.query(0, 0).unwrap();
+
+        let selectors = vec![r#"up{job="a"}"#.parse().unwrap()];
+        let query = plan(&dir, 0, 5000, &selectors).unwrap();
+        let accesses: Vec<_> = query.steps.iter().map(|s| &s.access).collect();
+        assert!(matches!(
+            accesses[0],
+            Access::Postings { offsets, num_series: 3 } if offsets.len() == 1
+        ));
+        assert!(matches!(
+            accesses[1],
+            Access::SegmentPostings {
+                offsets,
+                num_batches: 1,
+                matching_series: 1,
+                num_series: 3,
+            } if offsets.len() == 1
+        ));
+        assert_eq!(*accesses[2], Access::ScanSegment);
+        let explain = query.to_string();
+        assert!(explain.contains("postings lookup, 1 of 3 series match\n"));
+        assert!(explain.contains("postings lookup, 1 of 3 series match, read 1 of 1 batches\n"));
+        assert!(explain.contains("scan all records, no index\n"));
+
+        let (series, stats) = query.execute().unwrap();
+        assert_eq!(
+            series.into_iter().collect::<Vec<_>>(),
+            vec![(r#"up{job="a"}"#.to_string(), vec![(1000, 1.0), (2000, 1.0)])]
+        );
+        // One block entry, and the three records of the segment's batch.
+        assert_eq!(stats.rows_scanned, 1 + 3);
+        assert_eq!(stats.samples_returned, 2);
+
+        let none = vec![r#"up{job="c"}"#.parse().unwrap()];
+        let (series, stats) = plan(&dir, 0, 5000, &none).unwrap().execute().unwrap();
+        assert!(series.is_empty());
+        assert_eq!(stats.rows_scanned, 0);
+
+        let query = plan(&dir, 1600, 5000, &selectors).unwrap();
+        assert!(matches!(query.steps[0].access, Access::SkipTime { .. }));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,101 @@
+//! Offline rewriting of the series keys of a store, for label and family
+//! renames that should also apply to history.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use super::{
+    block, normalize, parse_seq, wal, SeriesMap, BLOCK_PREFIX, BLOCK_SUFFIX, SEGMENT_PREFIX,
+    SEGMENT_SUFFIX,
+};
+
+/// Calls `rekey` with the key and timestamp of every sample in `dir`, and
+/// moves the sample to the key it returns, if any. Samples that end up
+/// with the same key and timestamp keep the value read last. Every file
+/// with a changed key is rewritten through a temporary file; with
+/// `dry_run`, nothing is written. Returns the files that were, or would
+/// be, rewritten.
+///
+/// The store must not be open while this runs.
+pub fn rekey<F>(dir: &Path, dry_run: bool, mut rekey: F) -> io::Result<Vec<PathBuf>>
+where
+    F: FnMut(&str, i64) -> Option<String>,
+{
+    let mut paths: Vec<PathBuf> = fs::read_dir(dir)?
+        .map(|e| e.map(|e| e.path()))
+        .collect::<io::Result<_>>()?;
+    paths.sort();
+
+    let mut rewritten = Vec::new();
+    for path in paths {
+        let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+        let changed = if parse_seq(name, BLOCK_PREFIX, BLOCK_SUFFIX).is_some() {
+            rekey_block(&path, dry_run, &mut rekey)?
+        } else if parse_seq(name, SEGMENT_PREFIX, SEGMENT_SUFFIX).is_some() {
+            rekey_segment(&path, dry_run, &mut rekey)?
+        } else {
+            false
+        };
+        if changed {
+            rewritten.push(path);
+        }
+    }
+    Ok(rewritten)
+}
+
+fn rekey_block<F>(path: &Path, dry_run: bool, rekey: &mut F) -> io::Result<bool>
+where
+    F: FnMut(&str, i64) -> Option<String>,
+{
+    let (_, series) = block::read_block(path)?;
+    let mut changed = false;
+    let mut out = SeriesMap::new();
+    for (key, samples) in series {
+        for (ts, value) in samples {
+            let new_key = rekey(&key, ts);
+            changed |= new_key.is_some();
+            let key = new_key.unwrap_or_else(|| key.clone());
+            out.entry(key).or_default().push((ts, value));
+        }
+    }
+    if changed && !dry_run {
+        normalize(&mut out, i64::MIN);
+        block::write_block(path, &out)?;
+    }
+    Ok(changed)
+}
+
+fn rekey_segment<F>(path: &Path, dry_run: bool, rekey: &mut F) -> io::Result<bool>
+where
+    F: FnMut(&str, i64) -> Option<String>,
+{
+    let records = wal::read_segment(path)?;
+    let mut changed = false;
+    let records: Vec<_> = records
+        .into_iter()
+        .map(|(key, ts, value)| match rekey(&key, ts) {
+            Some(new_key) => {
+                changed = true;
+                (new_key, ts, value)
+            }
+            None => (key, ts, value),
+        })
+        .collect();
+    if changed && !dry_run {
+        let tmp = path.with_extension("tmp");
+        // A leftover would be appended to.
+        if tmp.exists() {
+            fs::remove_file(&tmp)?;
+        }
+        let mut writer = wal::SegmentWriter::open(&tmp)?;
+        for (key, ts, value) in &records {
+            writer.append(key, *ts, *value);
+        }
+        writer.flush()?;
+        drop(writer);
+        wal::remove_index(path)?;
+        fs::rename(&tmp, path)?;
+    }
+    Ok(changed)
+}
@@ -0,0 +1,163 @@
+//! Offline recovery of a store after an unclean shutdown or disk corruption.
+
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use super::{block, parse_seq, wal, BLOCK_PREFIX, BLOCK_SUFFIX, SEGMENT_PREFIX, SEGMENT_SUFFIX};
+
+const QUARANTINE_DIR: &str = "quarantine";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RepairAction {
+    /// The segment was cut back to its intact prefix.
+    Truncated { kept_bytes: u64, lost_bytes: u64 },
+    /// The file was moved aside; readable series were written back to the
+    /// original path.
+    Quarantined {
+        to: PathBuf,
+        salvaged_series: usize,
+        /// `None` when the header was unreadable, so the total is unknown.
+        lost_series: Option<u32>,
+    },
+}
+
+/// A damaged file and what was done, or would be done, about it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RepairEntry {
+    pub file: PathBuf,
+    pub problem: String,
+    pub action: RepairAction,
+}
+
+impl fmt::Display for RepairEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}: ", self.file.display(), self.problem)?;
+        match &self.action {
+            RepairAction::Truncated {
+                kept_bytes,
+                lost_bytes,
+            } => write!(
+                f,
+                "truncated to {} bytes, {} bytes of samples lost",
+                kept_bytes, lost_bytes
+            ),
+            RepairAction::Quarantined {
+                to,
+                salvaged_series,
+                lost_series,
+            } => {
+                write!(
+                    f,
+                    "moved to {}, {} series salvaged, ",
+                    to.display(),
+                    salvaged_series
+                )?;
+                match lost_series {
+                    Some(n) => write!(f, "{} series lost", n),
+                    None => write!(f, "unknown number of series lost"),
+                }
+            }
+        }
+    }
+}
+
+/// Checks every segment and block in `dir`. Segments with a torn or corrupt
+/// tail are truncated to their intact prefix; unreadable segments and
+/// corrupt blocks are moved to `quarantine/`, keeping what can still be read.
+/// With `dry_run`, nothing is changed and the report says what would be done.
+///
+/// The store must not be open while this runs.
+pub fn repair(dir: &Path, dry_run: bool) -> io::Result<Vec<RepairEntry>> {
+    let mut paths: Vec<PathBuf> = fs::read_dir(dir)?
+        .map(|e| e.map(|e| e.path()))
+        .collect::<io::Result<_>>()?;
+    paths.sort();
+
+    let mut report = Vec::new();
+    for path in paths {
+        let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+        let entry = if parse_seq(name, SEGMENT_PREFIX, SEGMENT_SUFFIX).is_some() {
+            repair_segment(dir, &path, dry_run)?
+        } else if parse_seq(name, BLOCK_PREFIX, BLOCK_SUFFIX).is_some() {
+            repair_block(dir, &path, dry_run)?
+        } else {
+            None
+        };
+        report.extend(entry);
+    }
+
+    Ok(report)
+}
+
+fn repair_segment(dir: &Path, path: &Path, dry_run: bool) -> io::Result<Option<RepairEntry>> {
+    let scan = wal::scan_segment(path)?;
+    let problem = match scan.error {
+        Some(problem) => problem,
+        None => return Ok(None),
+    };
+
+    let action = if scan.valid_len < wal::HEADER_LEN {
+        let to = quarantine(dir, path, dry_run)?;
+        RepairAction::Quarantined {
+            to,
+            salvaged_series: 0,
+            lost_series: None,
+        }
+    } else {
+        if !dry_run {
+            let file = OpenOptions::new().write(true).open(path)?;
+            file.set_len(scan.valid_len)?;
+            file.sync_all()?;
+        }
+        RepairAction::Truncated {
+            kept_bytes: scan.valid_len,
+            lost_bytes: scan.file_len - scan.valid_len,
+        }
+    };
+    // The index lists batches of the segment as it was.
+    if !dry_run {
+        wal::remove_index(path)?;
+    }
+
+    Ok(Some(RepairEntry {
+        file: path.to_path_buf(),
+        problem,
+        action,
+    }))
+}
+
+fn repair_block(dir: &Path, path: &Path, dry_run: bool) -> io::Result<Option<RepairEntry>> {
+    let scan = block::scan_block(path)?;
+    let problem = match &scan.error {
+        Some(problem) => problem.clone(),
+        None => return Ok(None),
+    };
+
+    let to = quarantine(dir, path, dry_run)?;
+    if !dry_run && !scan.series.is_empty() {
+        block::write_block(path, &scan.series)?;
+    }
+
+    Ok(Some(RepairEntry {
+        file: path.to_path_buf(),
+        problem,
+        action: RepairAction::Quarantined {
+            to,
+            salvaged_series: scan.series.len(),
+            lost_series: scan.lost_series(),
+        },
+    }))
+}
+
+/// Moves `path` into the quarantine directory, returning its new location.
+fn quarantine(dir: &Path, path: &Path, dry_run: bool) -> io::Result<PathBuf> {
+    let quarantine_dir = dir.join(QUARANTINE_DIR);
+    let to = quarantine_dir.join(path.file_name().unwrap_or_default());
+    if !dry_run {
+        fs::create_dir_all(&quarantine_dir)?;
+        fs::rename(path, &to)?;
+    }
+    Ok(to)
+}
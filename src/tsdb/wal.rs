@@ -0,0 +1,357 @@
+//! Append-only segments holding raw samples until they are compacted into a
+//! block.
+//!
+//! A segment starts with the magic `PMVW` and a version byte, followed by
+//! batches, one per append: `u32 payload length, u32 CRC-32 of the payload,
+//! payload`. The payload is a run of records, little endian:
+//! `u32 key length, key, i64 timestamp, f64 value`. A crash can only tear
+//! the last batch, which the checksum detects.
+//!
+//! When a segment is sealed, the batch offsets of each series and their
+//! label [`Postings`] are written next to it, as `wal-N.idx`: magic `PMVI`,
+//! a version byte, `u64 segment length, u32 batch count, u32 series count`,
+//! per series in key order `u32 key length, key, u32 batch count, u64 batch
+//! offset...`, then the postings and a `u32 CRC-32` of all that. Queries
+//! use it to read only the batches holding matching series. An index that
+//! does not cover the segment's current length is ignored.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use super::block::{read_array, read_vec};
+use super::postings::Postings;
+
+const MAGIC: &[u8; 4] = b"PMVW";
+const VERSION: u8 = 1;
+pub const HEADER_LEN: u64 = 5;
+
+const INDEX_MAGIC: &[u8; 4] = b"PMVI";
+const INDEX_VERSION: u8 = 1;
+
+pub type Record = (String, i64, f64);
+
+pub struct SegmentWriter {
+    path: PathBuf,
+    file: File,
+    batch: Vec<u8>,
+    bytes: u64,
+    /// Offsets of the batches holding each series, or `None` if the file
+    /// had batches before it was opened, so no index can be written.
+    batches: Option<BTreeMap<String, Vec<u64>>>,
+    batch_keys: BTreeSet<String>,
+    num_batches: u32,
+}
+
+impl SegmentWriter {
+    /// Opens `path` for appending, writing the header if the file is new.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        let mut bytes = file.metadata()?.len();
+        if bytes == 0 {
+            file.write_all(MAGIC)?;
+            file.write_all(&[VERSION])?;
+            bytes = HEADER_LEN;
+        }
+        Ok(SegmentWriter {
+            path: path.to_path_buf(),
+            file,
+            batch: Vec::new(),
+            bytes,
+            batches: (bytes == HEADER_LEN).then(BTreeMap::new),
+            batch_keys: BTreeSet::new(),
+            num_batches: 0,
+        })
+    }
+
+    /// Buffers a record; it reaches the file with the next `flush`.
+    pub fn append(&mut self, key: &str, ts: i64, value: f64) {
+        if self.batches.is_some() && !self.batch_keys.contains(key) {
+            self.batch_keys.insert(key.to_string());
+        }
+        self.batch
+            .extend_from_slice(&(key.len() as u32).to_le_bytes());
+        self.batch.extend_from_slice(key.as_bytes());
+        self.batch.extend_from_slice(&ts.to_le_bytes());
+        self.batch.extend_from_slice(&value.to_le_bytes());
+    }
+
+    /// Writes the buffered records as one checksummed batch.
+    pub fn flush(&mut self) -> io::Result<()> {
+        if self.batch.is_empty() {
+            return Ok(());
+        }
+
+        let mut out = Vec::with_capacity(8 + self.batch.len());
+        out.extend_from_slice(&(self.batch.len() as u32).to_le_bytes());
+        out.extend_from_slice(&crc32fast::hash(&self.batch).to_le_bytes());
+        out.extend_from_slice(&self.batch);
+        self.file.write_all(&out)?;
+
+        if let Some(batches) = &mut self.batches {
+            for key in std::mem::take(&mut self.batch_keys) {
+                batches.entry(key).or_default().push(self.bytes);
+            }
+            self.num_batches += 1;
+        }
+        self.bytes += out.len() as u64;
+        self.batch.clear();
+        Ok(())
+    }
+
+    /// Flushes the segment and writes its index, atomically via a
+    /// temporary file. Nothing more should be appended.
+    pub fn seal(&mut self) -> io::Result<()> {
+        self.flush()?;
+        let Some(batches) = &self.batches else {
+            return Ok(());
+        };
+
+        let mut index = Vec::new();
+        index.extend_from_slice(INDEX_MAGIC);
+        index.push(INDEX_VERSION);
+        index.extend_from_slice(&self.bytes.to_le_bytes());
+        index.extend_from_slice(&self.num_batches.to_le_bytes());
+        index.extend_from_slice(&(batches.len() as u32).to_le_bytes());
+        for (key, offsets) in batches {
+            index.extend_from_slice(&(key.len() as u32).to_le_bytes());
+            index.extend_from_slice(key.as_bytes());
+            index.extend_from_slice(&(offsets.len() as u32).to_le_bytes());
+            for offset in offsets {
+                index.extend_from_slice(&offset.to_le_bytes());
+            }
+        }
+        index.extend_from_slice(&Postings::build(batches.keys().map(String::as_str)).encode());
+        let crc = crc32fast::hash(&index);
+        index.extend_from_slice(&crc.to_le_bytes());
+
+        let path = index_path(&self.path);
+        let tmp = path.with_extension("idx.tmp");
+        let mut file = File::create(&tmp)?;
+        file.write_all(&index)?;
+        file.sync_all()?;
+        fs::rename(&tmp, &path)
+    }
+
+    /// Size of the segment including buffered records.
+    pub fn len(&self) -> u64 {
+        self.bytes + self.batch.len() as u64
+    }
+
+    /// Whether no records were written or buffered.
+    pub fn is_empty(&self) -> bool {
+        self.len() <= HEADER_LEN
+    }
+}
+
+/// The readable part of a segment, as found by `scan_segment`.
+#[derive(Debug)]
+pub struct SegmentScan {
+    pub records: Vec<Record>,
+    /// Length of the valid prefix: the header and every intact batch.
+    pub valid_len: u64,
+    pub file_len: u64,
+    /// Why reading stopped before the end of the file.
+    pub error: Option<String>,
+}
+
+/// Reads a segment up to its first torn or corrupt batch.
+pub fn scan_segment(path: &Path) -> io::Result<SegmentScan> {
+    let file = File::open(path)?;
+    let file_len = file.metadata()?.len();
+    let mut r = BufReader::new(file);
+
+    let mut scan = SegmentScan {
+        records: Vec::new(),
+        valid_len: 0,
+        file_len,
+        error: None,
+    };
+
+    // Created, but the process died before the header was written.
+    if file_len == 0 {
+        return Ok(scan);
+    }
+
+    let mut header = [0u8; HEADER_LEN as usize];
+    match r.read_exact(&mut header) {
+        Ok(()) if &header[..4] == MAGIC && header[4] == VERSION => {}
+        Ok(()) => {
+            scan.error = Some("not a pmv segment".to_string());
+            return Ok(scan);
+        }
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+            scan.error = Some("truncated header".to_string());
+            return Ok(scan);
+        }
+        Err(e) => return Err(e),
+    }
+    scan.valid_len = HEADER_LEN;
+
+    while scan.valid_len < file_len {
+        let batch = read_array::<4>(&mut r)
+            .and_then(|len| Ok((len, read_array::<4>(&mut r)?)))
+            .and_then(|(len, crc)| Ok((read_vec(&mut r, u32::from_le_bytes(len))?, crc)));
+        let (payload, crc) = match batch {
+            Ok(batch) => batch,
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+                scan.error = Some(format!("torn batch at offset {}", scan.valid_len));
+                break;
+            }
+            Err(e) => return Err(e),
+        };
+
+        let records = if u32::from_le_bytes(crc) == crc32fast::hash(&payload) {
+            parse_records(&payload)
+        } else {
+            None
+        };
+        match records {
+            Some(records) => scan.records.extend(records),
+            None => {
+                scan.error = Some(format!("corrupt batch at offset {}", scan.valid_len));
+                break;
+            }
+        }
+        scan.valid_len += 8 + payload.len() as u64;
+    }
+
+    Ok(scan)
+}
+
+fn parse_records(mut payload: &[u8]) -> Option<Vec<Record>> {
+    let mut records = Vec::new();
+    while !payload.is_empty() {
+        let key_len = u32::from_le_bytes(payload.get(..4)?.try_into().ok()?) as usize;
+        let key = std::str::from_utf8(payload.get(4..4 + key_len)?).ok()?;
+        let rest = payload.get(4 + key_len..4 + key_len + 16)?;
+        let ts = i64::from_le_bytes(rest[..8].try_into().ok()?);
+        let value = f64::from_le_bytes(rest[8..].try_into().ok()?);
+        records.push((key.to_string(), ts, value));
+        payload = &payload[4 + key_len + 16..];
+    }
+    Some(records)
+}
+
+/// The index of a sealed segment, from `read_index`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SegmentIndex {
+    pub num_batches: u32,
+    /// Series keys with the offsets of the batches holding them, numbered
+    /// as in `postings`.
+    pub series: Vec<(String, Vec<u64>)>,
+    pub postings: Postings,
+}
+
+/// Where the index of the segment at `path` is kept.
+pub fn index_path(path: &Path) -> PathBuf {
+    path.with_extension("idx")
+}
+
+/// The index of the segment at `path`, or `None` if it has none, as for
+/// the active segment, or the index no longer matches the segment. A
+/// damaged index is ignored with a warning, since the segment can still
+/// be scanned.
+pub fn read_index(path: &Path) -> io::Result<Option<SegmentIndex>> {
+    let data = match fs::read(index_path(path)) {
+        Ok(data) => data,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let Some((index, segment_len)) = parse_index(&data) else {
+        log::warn!("ignoring damaged index of {}", path.display());
+        return Ok(None);
+    };
+    Ok((fs::metadata(path)?.len() == segment_len).then_some(index))
+}
+
+fn parse_index(data: &[u8]) -> Option<(SegmentIndex, u64)> {
+    let (body, crc) = data.split_at_checked(data.len().checked_sub(4)?)?;
+    if u32::from_le_bytes(crc.try_into().ok()?) != crc32fast::hash(body)
+        || body.get(..5)? != [&INDEX_MAGIC[..], &[INDEX_VERSION]].concat()
+    {
+        return None;
+    }
+    let mut rest = &body[5..];
+    let mut take = |n: usize| {
+        let (head, tail) = rest.split_at_checked(n)?;
+        rest = tail;
+        Some(head)
+    };
+    let segment_len = u64::from_le_bytes(take(8)?.try_into().ok()?);
+    let num_batches = u32::from_le_bytes(take(4)?.try_into().ok()?);
+    let num_series = u32::from_le_bytes(take(4)?.try_into().ok()?);
+
+    let mut series = Vec::new();
+    for _ in 0..num_series {
+        let key_len = u32::from_le_bytes(take(4)?.try_into().ok()?) as usize;
+        let key = std::str::from_utf8(take(key_len)?).ok()?.to_string();
+        let count = u32::from_le_bytes(take(4)?.try_into().ok()?) as usize;
+        let offsets = take(count.checked_mul(8)?)?
+            .chunks_exact(8)
+            .map(|b| u64::from_le_bytes(b.try_into().unwrap()))
+            .collect();
+        series.push((key, offsets));
+    }
+    let postings = Postings::decode(rest)?;
+    if postings.num_series() != series.len() {
+        return None;
+    }
+    let index = SegmentIndex {
+        num_batches,
+        series,
+        postings,
+    };
+    Some((index, segment_len))
+}
+
+/// Removes the index of the segment at `path`, if any, before the segment
+/// is deleted or rewritten.
+pub fn remove_index(path: &Path) -> io::Result<()> {
+    match fs::remove_file(index_path(path)) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Reads the records of the batches at `offsets`, as listed by
+/// `read_index`.
+pub fn read_batches_at(path: &Path, offsets: &[u64]) -> io::Result<Vec<Record>> {
+    let mut r = BufReader::new(File::open(path)?);
+    let mut records = Vec::new();
+    for &offset in offsets {
+        r.seek(SeekFrom::Start(offset))?;
+        let len = u32::from_le_bytes(read_array(&mut r)?);
+        let crc = u32::from_le_bytes(read_array(&mut r)?);
+        let payload = read_vec(&mut r, len)?;
+        let batch = (crc == crc32fast::hash(&payload))
+            .then(|| parse_records(&payload))
+            .flatten();
+        match batch {
+            Some(batch) => records.extend(batch),
+            None => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("corrupt batch at offset {}", offset),
+                ))
+            }
+        }
+    }
+    Ok(records)
+}
+
+/// Reads every intact record of a segment. Anything after a torn or corrupt
+/// batch, as left by a crash mid-write, is ignored with a warning.
+pub fn read_segment(path: &Path) -> io::Result<Vec<Record>> {
+    let scan = scan_segment(path)?;
+    if let Some(error) = &scan.error {
+        log::warn!(
+            "ignoring {} bytes of {}: {}",
+            scan.file_len - scan.valid_len,
+            path.display(),
+            error
+        );
+    }
+    Ok(scan.records)
+}
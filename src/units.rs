@@ -0,0 +1,287 @@
+use prometheus::proto::{MetricFamily, MetricType};
+use serde::Deserialize;
+use std::str::FromStr;
+
+/// A unit of time or size as named in metric names, e.g. the
+/// `milliseconds` of `request_duration_milliseconds`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Unit {
+    Nanoseconds,
+    Microseconds,
+    Milliseconds,
+    Seconds,
+    Minutes,
+    Hours,
+    Days,
+    Bytes,
+    Kilobytes,
+    Megabytes,
+    Gigabytes,
+    Kibibytes,
+    Mebibytes,
+    Gibibytes,
+}
+
+const UNITS: [Unit; 14] = [
+    Unit::Nanoseconds,
+    Unit::Microseconds,
+    Unit::Milliseconds,
+    Unit::Seconds,
+    Unit::Minutes,
+    Unit::Hours,
+    Unit::Days,
+    Unit::Bytes,
+    Unit::Kilobytes,
+    Unit::Megabytes,
+    Unit::Gigabytes,
+    Unit::Kibibytes,
+    Unit::Mebibytes,
+    Unit::Gibibytes,
+];
+
+impl Unit {
+    /// The name used in metric names.
+    pub fn name(self) -> &'static str {
+        self.names()[0]
+    }
+
+    /// The name, then abbreviations exporters use in metric names.
+    fn names(self) -> &'static [&'static str] {
+        match self {
+            Unit::Nanoseconds => &["nanoseconds", "ns"],
+            Unit::Microseconds => &["microseconds", "us"],
+            Unit::Milliseconds => &["milliseconds", "ms", "millis"],
+            Unit::Seconds => &["seconds", "secs", "s"],
+            Unit::Minutes => &["minutes", "mins"],
+            Unit::Hours => &["hours"],
+            Unit::Days => &["days"],
+            Unit::Bytes => &["bytes"],
+            Unit::Kilobytes => &["kilobytes", "kb"],
+            Unit::Megabytes => &["megabytes", "mb"],
+            Unit::Gigabytes => &["gigabytes", "gb"],
+            Unit::Kibibytes => &["kibibytes", "kib"],
+            Unit::Mebibytes => &["mebibytes", "mib"],
+            Unit::Gibibytes => &["gibibytes", "gib"],
+        }
+    }
+
+    fn is_time(self) -> bool {
+        matches!(
+            self,
+            Unit::Nanoseconds
+                | Unit::Microseconds
+                | Unit::Milliseconds
+                | Unit::Seconds
+                | Unit::Minutes
+                | Unit::Hours
+                | Unit::Days
+        )
+    }
+
+    /// The size in nanoseconds or bytes. Sizes are integers so conversions
+    /// between them round once.
+    fn size(self) -> f64 {
+        match self {
+            Unit::Nanoseconds | Unit::Bytes => 1.0,
+            Unit::Microseconds | Unit::Kilobytes => 1e3,
+            Unit::Milliseconds | Unit::Megabytes => 1e6,
+            Unit::Seconds | Unit::Gigabytes => 1e9,
+            Unit::Minutes => 60e9,
+            Unit::Hours => 3600e9,
+            Unit::Days => 86400e9,
+            Unit::Kibibytes => 1024.0,
+            Unit::Mebibytes => 1024.0 * 1024.0,
+            Unit::Gibibytes => 1024.0 * 1024.0 * 1024.0,
+        }
+    }
+}
+
+impl FromStr for Unit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let lower = s.to_ascii_lowercase();
+        UNITS
+            .into_iter()
+            .find(|u| u.names().contains(&lower.as_str()))
+            .ok_or_else(|| format!("unknown unit {:?}", s))
+    }
+}
+
+/// A conversion of families from one unit to another, loaded from YAML as
+/// `{from: milliseconds, to: seconds}`.
+///
+/// It applies to the families whose name ends in the `from` unit, by name
+/// or abbreviation, before any `_total`, such as `latency_ms` or
+/// `cpu_milliseconds_total`, and to the `families` listed. Their values are
+/// converted, with histogram bucket bounds and `_sum` and summary quantiles
+/// and `_sum`, while counts stay as they are. The unit in the name becomes
+/// `to`, which is appended to listed names without one, e.g.
+/// `cpu_milliseconds_total` becomes `cpu_seconds_total`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(try_from = "RawConversion")]
+pub struct Conversion {
+    pub from: Unit,
+    pub to: Unit,
+    pub families: Vec<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawConversion {
+    from: String,
+    to: String,
+    #[serde(default)]
+    families: Vec<String>,
+}
+
+impl TryFrom<RawConversion> for Conversion {
+    type Error = String;
+
+    fn try_from(raw: RawConversion) -> Result<Self, Self::Error> {
+        Conversion::new(raw.from.parse()?, raw.to.parse()?, raw.families)
+    }
+}
+
+impl Conversion {
+    /// Fails if `from` and `to` measure different things.
+    pub fn new(from: Unit, to: Unit, families: Vec<String>) -> Result<Self, String> {
+        if from.is_time() != to.is_time() {
+            return Err(format!("cannot convert {} to {}", from.name(), to.name()));
+        }
+        Ok(Conversion { from, to, families })
+    }
+
+    /// The new name of the family `name` of type `metric_type`, if the
+    /// conversion applies to it.
+    pub fn rename(&self, name: &str, metric_type: MetricType) -> Option<String> {
+        let (base, total) = match name.strip_suffix("_total") {
+            Some(base) if metric_type == MetricType::COUNTER => (base, "_total"),
+            _ => (name, ""),
+        };
+        let unit = self
+            .from
+            .names()
+            .iter()
+            .find_map(|unit| base.strip_suffix(unit)?.strip_suffix('_'));
+        match unit {
+            Some(stem) => Some(format!("{}_{}{}", stem, self.to.name(), total)),
+            None if self.families.iter().any(|f| f == name) => {
+                let known = UNITS
+                    .iter()
+                    .flat_map(|u| u.names())
+                    .any(|unit| base.ends_with(&format!("_{}", unit)));
+                if known {
+                    Some(name.to_string())
+                } else {
+                    Some(format!("{}_{}{}", base, self.to.name(), total))
+                }
+            }
+            None => None,
+        }
+    }
+
+    /// Converts `v` from `from` to `to`.
+    pub fn convert(&self, v: f64) -> f64 {
+        v * self.from.size() / self.to.size()
+    }
+}
+
+/// Applies `conversion` to the families of `mfs` it names, leaving the
+/// others as they are.
+pub fn convert(mfs: &[MetricFamily], conversion: &Conversion) -> Vec<MetricFamily> {
+    mfs.iter()
+        .map(|mf| {
+            let mut mf = mf.clone();
+            let Some(name) = conversion.rename(mf.get_name(), mf.get_field_type()) else {
+                return mf;
+            };
+            mf.set_name(name);
+            let metric_type = mf.get_field_type();
+            let c = |v: f64| conversion.convert(v);
+            for m in mf.mut_metric().iter_mut() {
+                match metric_type {
+                    MetricType::COUNTER => {
+                        let v = c(m.get_counter().get_value());
+                        m.mut_counter().set_value(v);
+                    }
+                    MetricType::GAUGE => {
+                        let v = c(m.get_gauge().get_value());
+                        m.mut_gauge().set_value(v);
+                    }
+                    MetricType::UNTYPED => {
+                        let v = c(m.get_untyped().get_value());
+                        m.mut_untyped().set_value(v);
+                    }
+                    MetricType::HISTOGRAM => {
+                        let h = m.mut_histogram();
+                        h.set_sample_sum(c(h.get_sample_sum()));
+                        for b in h.mut_bucket().iter_mut() {
+                            b.set_upper_bound(c(b.get_upper_bound()));
+                        }
+                    }
+                    MetricType::SUMMARY => {
+                        let s = m.mut_summary();
+                        s.set_sample_sum(c(s.get_sample_sum()));
+                        for q in s.mut_quantile().iter_mut() {
+                            q.set_value(c(q.get_value()));
+                        }
+                    }
+                }
+            }
+            mf
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::{read_metric_families, Format};
+    use crate::text_encode::metric_families_to_text;
+
+    #[test]
+    fn test_convert() {
+        let text = r#"# TYPE cpu_milliseconds_total counter
+cpu_milliseconds_total 1500
+# TYPE latency_ms histogram
+latency_ms_bucket{le="100"} 1
+latency_ms_bucket{le="+Inf"} 2
+latency_ms_sum 250
+latency_ms_count 2
+# TYPE uptime gauge
+uptime 60000
+# TYPE heap_bytes gauge
+heap_bytes 1024
+"#;
+        let mfs = read_metric_families(Format::Text, text.as_bytes()).unwrap();
+        let conversion: Conversion =
+            serde_yaml::from_str("{from: ms, to: seconds, families: [uptime]}").unwrap();
+        let mut out = Vec::new();
+        metric_families_to_text(&mut out, &convert(&mfs, &conversion)).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            r#"# TYPE cpu_seconds_total counter
+cpu_seconds_total 1.5
+# TYPE heap_bytes gauge
+heap_bytes 1024
+# TYPE latency_seconds histogram
+latency_seconds_bucket{le="0.1"} 1
+latency_seconds_bucket{le="+Inf"} 2
+latency_seconds_sum 0.25
+latency_seconds_count 2
+# TYPE uptime_seconds gauge
+uptime_seconds 60
+"#
+        );
+
+        let mib = Conversion::new(Unit::Bytes, "MiB".parse().unwrap(), vec![]).unwrap();
+        assert_eq!(
+            mib.rename("heap_bytes", MetricType::GAUGE).as_deref(),
+            Some("heap_mebibytes")
+        );
+        assert_eq!(mib.convert(3.0 * 1024.0 * 1024.0), 3.0);
+        assert!(serde_yaml::from_str::<Conversion>("{from: bytes, to: seconds}").is_err());
+        assert!("fortnights".parse::<Unit>().is_err());
+    }
+}
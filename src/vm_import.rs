@@ -0,0 +1,215 @@
+//! The JSON lines of VictoriaMetrics' `/api/v1/import`: one object per
+//! series, with its labels under `metric` and its samples as parallel
+//! `values` and `timestamps` arrays, e.g.
+//!
+//! ```text
+//! {"metric":{"__name__":"up","job":"node"},"values":[1,0],"timestamps":[1000,2000]}
+//! ```
+//!
+//! Every sample of a series goes on its line, so a recorded history such as
+//! `pmv storage export` writes imports in one request. The format has no
+//! types or help, so families read from it are untyped. Values that JSON
+//! numbers cannot hold are written as the strings `NaN`, `+Inf` and `-Inf`.
+
+use prometheus::proto::{LabelPair, Metric, MetricFamily, MetricType, Untyped};
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::io::{BufRead, Write};
+
+use crate::flatten::flatten_family;
+use crate::text_encode::format_float;
+
+const METRIC_NAME_LABEL: &str = "__name__";
+
+struct Line {
+    metric: Map<String, Value>,
+    values: Vec<Value>,
+    timestamps: Vec<i64>,
+}
+
+#[derive(Debug)]
+pub enum VmImportError {
+    Syntax(usize, serde_json::Error),
+    Invalid(usize, String),
+}
+
+impl fmt::Display for VmImportError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VmImportError::Syntax(line, e) => write!(f, "line {}: invalid JSON: {}", line, e),
+            VmImportError::Invalid(line, msg) => {
+                write!(f, "line {}: invalid import line: {}", line, msg)
+            }
+        }
+    }
+}
+
+impl Error for VmImportError {}
+
+/// Writes one line per series. Samples without a timestamp get
+/// `default_timestamp_ms`, as the format requires one.
+pub fn write_vm_import<W: Write>(
+    writer: &mut W,
+    mfs: &[MetricFamily],
+    default_timestamp_ms: i64,
+) -> std::io::Result<()> {
+    // Series in the order they first appear, with their samples.
+    let mut lines: Vec<Line> = Vec::new();
+    let mut index: HashMap<String, usize> = HashMap::new();
+    for mf in mfs {
+        for sample in flatten_family(mf) {
+            let i = *index.entry(sample.series_key()).or_insert_with(|| {
+                let mut metric = Map::new();
+                metric.insert(METRIC_NAME_LABEL.to_string(), json!(sample.name));
+                for (name, value) in &sample.labels {
+                    metric.insert(name.clone(), json!(value));
+                }
+                lines.push(Line {
+                    metric,
+                    values: Vec::new(),
+                    timestamps: Vec::new(),
+                });
+                lines.len() - 1
+            });
+            let value = if sample.value.is_finite() {
+                json!(sample.value)
+            } else {
+                json!(format_float(sample.value))
+            };
+            lines[i].values.push(value);
+            lines[i]
+                .timestamps
+                .push(sample.timestamp_ms.unwrap_or(default_timestamp_ms));
+        }
+    }
+
+    for line in lines {
+        let line = json!({
+            "metric": line.metric,
+            "values": line.values,
+            "timestamps": line.timestamps,
+        });
+        writeln!(writer, "{}", line)?;
+    }
+    Ok(())
+}
+
+/// Reads import lines into untyped families, in the order their names
+/// first appear, with a metric per sample. Blank lines are skipped.
+pub fn read_vm_import<R: BufRead>(reader: R) -> Result<Vec<MetricFamily>, Box<dyn Error>> {
+    let mut mfs: Vec<MetricFamily> = Vec::new();
+    let mut index: HashMap<String, usize> = HashMap::new();
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        let lineno = i + 1;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let invalid = |msg: &str| VmImportError::Invalid(lineno, msg.to_string());
+        let value: Value =
+            serde_json::from_str(&line).map_err(|e| VmImportError::Syntax(lineno, e))?;
+
+        let metric = value["metric"]
+            .as_object()
+            .ok_or_else(|| invalid("no metric object"))?;
+        let mut name = None;
+        let mut labels = Vec::new();
+        for (label, value) in metric {
+            let value = value
+                .as_str()
+                .ok_or_else(|| invalid(&format!("label {} is not a string", label)))?;
+            if label == METRIC_NAME_LABEL {
+                name = Some(value);
+            } else {
+                let mut pair = LabelPair::default();
+                pair.set_name(label.clone());
+                pair.set_value(value.to_string());
+                labels.push(pair);
+            }
+        }
+        let name = name.ok_or_else(|| invalid("no __name__ label"))?;
+
+        let values = value["values"]
+            .as_array()
+            .ok_or_else(|| invalid("no values array"))?;
+        let timestamps = value["timestamps"]
+            .as_array()
+            .ok_or_else(|| invalid("no timestamps array"))?;
+        if values.len() != timestamps.len() {
+            return Err(invalid("values and timestamps differ in length").into());
+        }
+
+        let mf = *index.entry(name.to_string()).or_insert_with(|| {
+            let mut mf = MetricFamily::default();
+            mf.set_name(name.to_string());
+            mf.set_field_type(MetricType::UNTYPED);
+            mfs.push(mf);
+            mfs.len() - 1
+        });
+        for (value, timestamp) in values.iter().zip(timestamps) {
+            let value = match value {
+                Value::Number(n) => n.as_f64(),
+                Value::String(s) => s.parse::<f64>().ok(),
+                _ => None,
+            }
+            .ok_or_else(|| invalid(&format!("invalid value {}", value)))?;
+            let timestamp = timestamp
+                .as_i64()
+                .ok_or_else(|| invalid(&format!("invalid timestamp {}", timestamp)))?;
+
+            let mut untyped = Untyped::default();
+            untyped.set_value(value);
+            let mut m = Metric::default();
+            m.set_label(labels.clone().into());
+            m.set_untyped(untyped);
+            m.set_timestamp_ms(timestamp);
+            mfs[mf].mut_metric().push(m);
+        }
+    }
+    Ok(mfs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::{read_metric_families, write_metric_families, Format};
+
+    #[test]
+    fn test_vm_import() {
+        let text =
+            "# TYPE up gauge\nup{job=\"a\"} 1 1000\nup{job=\"b\"} NaN 1000\nup{job=\"a\"} 0 2000\n\
+                    temperature 21.5\n";
+        let mfs = read_metric_families(Format::Text, text.as_bytes()).unwrap();
+        let mut out = Vec::new();
+        write_vm_import(&mut out, &mfs, 5000).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert_eq!(
+            out,
+            "{\"metric\":{\"__name__\":\"temperature\"},\"values\":[21.5],\"timestamps\":[5000]}\n\
+             {\"metric\":{\"__name__\":\"up\",\"job\":\"a\"},\"values\":[1.0,0.0],\"timestamps\":[1000,2000]}\n\
+             {\"metric\":{\"__name__\":\"up\",\"job\":\"b\"},\"values\":[\"NaN\"],\"timestamps\":[1000]}\n"
+        );
+
+        let mfs = read_vm_import(out.as_bytes()).unwrap();
+        assert_eq!(mfs[1].get_name(), "up");
+        assert_eq!(mfs[1].get_metric().len(), 3);
+        assert_eq!(mfs[1].get_metric()[1].get_untyped().get_value(), 0.0);
+        assert_eq!(mfs[1].get_metric()[1].get_timestamp_ms(), 2000);
+        assert!(mfs[1].get_metric()[2].get_untyped().get_value().is_nan());
+
+        let mut text = Vec::new();
+        write_metric_families(Format::Text, &mut text, &mfs[..1]).unwrap();
+        assert_eq!(
+            String::from_utf8(text).unwrap(),
+            "# TYPE temperature untyped\ntemperature 21.5 5000\n"
+        );
+
+        let err = read_vm_import("{\"metric\":{}}\n".as_bytes()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "line 1: invalid import line: no __name__ label"
+        );
+    }
+}
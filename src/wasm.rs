@@ -0,0 +1,236 @@
+//! Transforms compiled to WebAssembly, run with wasmtime, so users can add
+//! their own logic to pipelines and relays without rebuilding pmv. Built
+//! with the `wasm` feature.
+//!
+//! A module exports its `memory` and two functions:
+//!
+//! ```text
+//! (func (export "pmv_alloc") (param $len i32) (result i32))
+//! (func (export "pmv_transform") (param $ptr i32) (param $len i32) (result i64))
+//! ```
+//!
+//! pmv calls `pmv_alloc` for a buffer of `len` bytes, writes the families
+//! there as the JSON format (`--format json`) and calls `pmv_transform`
+//! with it. The result packs the address of the output in its upper 32
+//! bits and its length in the lower ones. The output is the transformed
+//! families in the same format, or `{"error": "..."}` to fail the run.
+//!
+//! Every call runs in a fresh instance, so modules keep no state between
+//! runs, and may not import anything. A call may consume at most
+//! [`MAX_FUEL`] units of fuel, about one per instruction.
+
+use prometheus::proto::MetricFamily;
+use serde_json::Value;
+use std::error::Error;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use wasmtime::{Config, Engine, Instance, Module, Store, Trap};
+
+use crate::json_format::{json_to_metric_families, metric_families_to_json};
+use crate::pipeline::{Families, Transform, TransformError};
+
+/// How much fuel a module may consume per run before it is stopped, so a
+/// runaway loop fails the run instead of hanging it.
+pub const MAX_FUEL: u64 = 1_000_000_000;
+
+#[derive(Debug)]
+pub enum WasmError {
+    Load(PathBuf, wasmtime::Error),
+    Run(wasmtime::Error),
+    /// The module's output is not families in the JSON format.
+    Output(String),
+    /// The module reported an error.
+    Failed(String),
+    /// The module consumed all of its [`MAX_FUEL`].
+    OutOfFuel,
+}
+
+impl fmt::Display for WasmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WasmError::Load(path, e) => write!(f, "{}: {}", path.display(), e),
+            WasmError::Run(e) => write!(f, "wasm transform failed: {}", e),
+            WasmError::Output(msg) => write!(f, "invalid wasm transform output: {}", msg),
+            WasmError::Failed(msg) => write!(f, "wasm transform failed: {}", msg),
+            WasmError::OutOfFuel => write!(
+                f,
+                "wasm transform failed: consumed all of its {} units of fuel",
+                MAX_FUEL
+            ),
+        }
+    }
+}
+
+impl Error for WasmError {}
+
+/// A transform stage backed by a WebAssembly module.
+pub struct WasmTransform {
+    path: PathBuf,
+    engine: Engine,
+    module: Module,
+}
+
+impl fmt::Debug for WasmTransform {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("WasmTransform")
+            .field("path", &self.path)
+            .finish()
+    }
+}
+
+fn engine() -> Engine {
+    let mut config = Config::new();
+    config.consume_fuel(true);
+    Engine::new(&config).expect("fuel is supported on every target")
+}
+
+impl WasmTransform {
+    /// Compiles the module at `path`, binary or text.
+    pub fn load(path: &Path) -> Result<Self, WasmError> {
+        let engine = engine();
+        let module =
+            Module::from_file(&engine, path).map_err(|e| WasmError::Load(path.to_path_buf(), e))?;
+        Ok(WasmTransform {
+            path: path.to_path_buf(),
+            engine,
+            module,
+        })
+    }
+
+    /// Compiles a module from its bytes, binary or text.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, WasmError> {
+        let engine = engine();
+        let module = Module::new(&engine, bytes).map_err(|e| WasmError::Load(PathBuf::new(), e))?;
+        Ok(WasmTransform {
+            path: PathBuf::new(),
+            engine,
+            module,
+        })
+    }
+
+    /// Runs the module on `mfs`.
+    pub fn run(&self, mfs: &[MetricFamily]) -> Result<Vec<MetricFamily>, WasmError> {
+        let input = metric_families_to_json(mfs).to_string().into_bytes();
+        let len = u32::try_from(input.len())
+            .map_err(|_| WasmError::Output("input over 4 GiB".to_string()))?;
+
+        let mut store = Store::new(&self.engine, ());
+        store.set_fuel(MAX_FUEL).map_err(WasmError::Run)?;
+        let instance = Instance::new(&mut store, &self.module, &[]).map_err(WasmError::Run)?;
+        let memory = instance
+            .get_memory(&mut store, "memory")
+            .ok_or_else(|| WasmError::Output("module exports no memory".to_string()))?;
+        let alloc = instance
+            .get_typed_func::<u32, u32>(&mut store, "pmv_alloc")
+            .map_err(WasmError::Run)?;
+        let transform = instance
+            .get_typed_func::<(u32, u32), u64>(&mut store, "pmv_transform")
+            .map_err(WasmError::Run)?;
+
+        let ptr = alloc.call(&mut store, len).map_err(run_error)?;
+        memory
+            .write(&mut store, ptr as usize, &input)
+            .map_err(|e| WasmError::Run(e.into()))?;
+        let packed = transform.call(&mut store, (ptr, len)).map_err(run_error)?;
+
+        let (out_ptr, out_len) = ((packed >> 32) as usize, packed as u32 as usize);
+        if out_ptr + out_len > memory.data_size(&store) {
+            return Err(WasmError::Output(format!(
+                "{} bytes at {} are outside the module's memory",
+                out_len, out_ptr
+            )));
+        }
+        let mut output = vec![0; out_len];
+        memory
+            .read(&store, out_ptr, &mut output)
+            .map_err(|e| WasmError::Run(e.into()))?;
+
+        let value: Value =
+            serde_json::from_slice(&output).map_err(|e| WasmError::Output(e.to_string()))?;
+        if let Some(msg) = value.get("error") {
+            let msg = msg.as_str().map_or_else(|| msg.to_string(), str::to_string);
+            return Err(WasmError::Failed(msg));
+        }
+        json_to_metric_families(&value).map_err(|e| WasmError::Output(e.to_string()))
+    }
+}
+
+fn run_error(e: wasmtime::Error) -> WasmError {
+    match e.downcast_ref::<Trap>() {
+        Some(Trap::OutOfFuel) => WasmError::OutOfFuel,
+        _ => WasmError::Run(e),
+    }
+}
+
+impl Transform for WasmTransform {
+    fn name(&self) -> String {
+        format!("wasm {}", self.path.display())
+    }
+
+    fn apply(&self, families: &mut Families) -> Result<(), TransformError> {
+        *families = self.run(families)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::{read_metric_families, Format};
+
+    /// Returns its input, or an error when the input is `[]`.
+    const ECHO: &str = r#"
+(module
+  (memory (export "memory") 1)
+  (data (i32.const 0) "{\"error\":\"empty\"}")
+  (func (export "pmv_alloc") (param $len i32) (result i32)
+    (drop (memory.grow (i32.add (i32.shr_u (local.get $len) (i32.const 16)) (i32.const 1))))
+    (i32.const 1024))
+  (func (export "pmv_transform") (param $ptr i32) (param $len i32) (result i64)
+    (if (result i64) (i32.eq (local.get $len) (i32.const 2))
+      (then (i64.const 17))
+      (else (i64.or
+        (i64.shl (i64.extend_i32_u (local.get $ptr)) (i64.const 32))
+        (i64.extend_i32_u (local.get $len)))))))
+"#;
+
+    #[test]
+    fn test_wasm_transform() {
+        let echo = WasmTransform::from_bytes(ECHO.as_bytes()).unwrap();
+        let mfs = read_metric_families(
+            Format::Text,
+            "# HELP up Whether the target is up.\n# TYPE up gauge\nup{job=\"a\"} 1\n".as_bytes(),
+        )
+        .unwrap();
+        assert_eq!(echo.run(&mfs).unwrap(), mfs);
+
+        let err = echo.run(&[]).unwrap_err();
+        assert_eq!(err.to_string(), "wasm transform failed: empty");
+        assert!(WasmTransform::from_bytes(b"(module").is_err());
+    }
+
+    #[test]
+    fn test_wasm_transform_limits() {
+        let spin = WasmTransform::from_bytes(
+            br#"(module
+  (memory (export "memory") 1)
+  (func (export "pmv_alloc") (param $len i32) (result i32) (i32.const 0))
+  (func (export "pmv_transform") (param $ptr i32) (param $len i32) (result i64)
+    (loop $spin (br $spin))
+    (i64.const 0)))"#,
+        )
+        .unwrap();
+        assert!(matches!(spin.run(&[]), Err(WasmError::OutOfFuel)));
+
+        // Claims 4 GiB of output from a 64 KiB memory.
+        let huge = WasmTransform::from_bytes(
+            br#"(module
+  (memory (export "memory") 1)
+  (func (export "pmv_alloc") (param $len i32) (result i32) (i32.const 0))
+  (func (export "pmv_transform") (param $ptr i32) (param $len i32) (result i64)
+    (i64.const 0xffffffff)))"#,
+        )
+        .unwrap();
+        assert!(matches!(huge.run(&[]), Err(WasmError::Output(_))));
+    }
+}
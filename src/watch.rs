@@ -0,0 +1,155 @@
+use prometheus::proto::MetricFamily;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::diff::{self, Change};
+use crate::flatten::flatten;
+use crate::text_encode::type_name;
+
+/// Notices when a file is rewritten by polling its modification time and
+/// size. Textfile collectors usually write a temporary file and rename it
+/// over the old one, which this catches as well.
+#[derive(Debug)]
+pub struct FileWatcher {
+    path: PathBuf,
+    last: Option<(SystemTime, u64)>,
+}
+
+impl FileWatcher {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        FileWatcher {
+            path: path.into(),
+            last: None,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Whether the file changed since the last call; always true on the
+    /// first call.
+    pub fn changed(&mut self) -> io::Result<bool> {
+        let meta = fs::metadata(&self.path)?;
+        let current = (meta.modified()?, meta.len());
+        Ok(self.last.replace(current) != Some(current))
+    }
+}
+
+/// Counts of one parse of a watched file, and what changed since the
+/// previous one.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Summary {
+    pub families: usize,
+    pub series: usize,
+    pub samples: usize,
+    pub by_type: BTreeMap<&'static str, usize>,
+    pub families_added: usize,
+    pub families_removed: usize,
+    pub samples_added: usize,
+    pub samples_removed: usize,
+    pub values_changed: usize,
+}
+
+impl Summary {
+    /// Summarizes `new`, comparing it with `old` if there was an earlier
+    /// parse.
+    pub fn new(old: Option<&[MetricFamily]>, new: &[MetricFamily]) -> Self {
+        let mut summary = Summary {
+            families: new.len(),
+            series: new.iter().map(|mf| mf.get_metric().len()).sum(),
+            samples: flatten(new).len(),
+            ..Summary::default()
+        };
+        for mf in new {
+            *summary
+                .by_type
+                .entry(type_name(mf.get_field_type()))
+                .or_default() += 1;
+        }
+
+        for change in old.map(|old| diff::diff(old, new, 0.0)).unwrap_or_default() {
+            match change {
+                Change::FamilyAdded { .. } => summary.families_added += 1,
+                Change::FamilyRemoved { .. } => summary.families_removed += 1,
+                Change::SeriesAdded { .. } => summary.samples_added += 1,
+                Change::SeriesRemoved { .. } => summary.samples_removed += 1,
+                Change::ValueChanged { .. } => summary.values_changed += 1,
+                Change::TypeChanged { .. } | Change::HelpChanged { .. } => {}
+            }
+        }
+        summary
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{} families, {} series, {} samples",
+            self.families, self.series, self.samples
+        )?;
+        let types: Vec<_> = self
+            .by_type
+            .iter()
+            .map(|(name, n)| format!("{} {}", name, n))
+            .collect();
+        writeln!(f, "types: {}", types.join(", "))?;
+        write!(
+            f,
+            "changes: families +{} -{}, samples +{} -{}, {} values changed",
+            self.families_added,
+            self.families_removed,
+            self.samples_added,
+            self.samples_removed,
+            self.values_changed
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::{read_metric_families, Format};
+
+    fn parse(text: &str) -> Vec<MetricFamily> {
+        read_metric_families(Format::Text, text.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn test_summary() {
+        let old = parse("# TYPE a counter\na{x=\"1\"} 1\na{x=\"2\"} 2\nb 1\n");
+        let new = parse(
+            "# TYPE a counter\na{x=\"1\"} 1\na{x=\"2\"} 3\na{x=\"3\"} 1\n\
+             # TYPE h histogram\nh_bucket{le=\"+Inf\"} 1\nh_sum 2\nh_count 1\n",
+        );
+
+        let summary = Summary::new(Some(&old), &new);
+        assert_eq!(
+            summary.to_string(),
+            "2 families, 4 series, 6 samples\n\
+             types: counter 1, histogram 1\n\
+             changes: families +1 -1, samples +1 -0, 1 values changed"
+        );
+        assert_eq!(Summary::new(None, &new).values_changed, 0);
+    }
+
+    #[test]
+    fn test_file_watcher() {
+        let path = std::env::temp_dir().join(format!("pmv-watch-{}.prom", std::process::id()));
+        let mut watcher = FileWatcher::new(&path);
+        assert!(watcher.changed().is_err());
+
+        fs::write(&path, "a 1\n").unwrap();
+        assert!(watcher.changed().unwrap());
+        assert!(!watcher.changed().unwrap());
+
+        fs::write(&path, "a 10\n").unwrap();
+        assert!(watcher.changed().unwrap());
+        fs::remove_file(&path).unwrap();
+    }
+}